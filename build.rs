@@ -0,0 +1,113 @@
+use std::{
+    env, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+// Directory packed into the initramfs built into the kernel, unless INITRAMFS
+// names another
+const INITRAMFS: &str = "initramfs";
+
+// Whether rustc was told to keep frame pointers, looking at the flags cargo
+// passes on, or RUSTFLAGS for older versions that don't
+fn frame_pointers() -> bool {
+    let (flags, separator) = match env::var("CARGO_ENCODED_RUSTFLAGS") {
+        Ok(flags) => (flags, '\x1F'),
+        Err(_) => (env::var("RUSTFLAGS").unwrap_or_default(), ' '),
+    };
+
+    flags
+        .split(separator)
+        .filter_map(|flag| flag.trim_start_matches("-C").trim().strip_prefix("force-frame-pointers"))
+        .last()
+        .map_or(false, |value| matches!(value, "" | "=yes" | "=y" | "=on"))
+}
+
+// Appends a newc cpio entry. Modification times are left at zero so that the
+// same files always make the same archive.
+fn cpio_entry(archive: &mut Vec<u8>, ino: usize, name: &str, mode: u32, data: &[u8]) {
+    let nlink = if mode & 0o170_000 == 0o040_000 { 2 } else { 1 };
+    let fields = [ino, mode as usize, 0, 0, nlink, 0, data.len(), 0, 0, 0, 0, name.len() + 1, 0];
+    archive.extend_from_slice(b"070701");
+    for field in fields.iter() {
+        archive.extend_from_slice(format!("{:08X}", field).as_bytes());
+    }
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    archive.resize((archive.len() + 3) & !3, 0);
+    archive.extend_from_slice(data);
+    archive.resize((archive.len() + 3) & !3, 0);
+}
+
+#[cfg(unix)]
+fn permissions(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn permissions(metadata: &fs::Metadata) -> u32 {
+    match (metadata.is_dir(), metadata.permissions().readonly()) {
+        (true, _) => 0o755,
+        (false, false) => 0o644,
+        (false, true) => 0o444,
+    }
+}
+
+// Packs everything under dir, parents before their children and in name
+// order, with paths relative to root
+fn pack_dir(archive: &mut Vec<u8>, inodes: &mut usize, root: &Path, dir: &Path) -> io::Result<()> {
+    println!("cargo:rerun-if-changed={}", dir.display());
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let name = path.strip_prefix(root).unwrap().to_str().expect("non UTF-8 name in the initramfs");
+        let name = name.replace('\\', "/");
+        let metadata = fs::symlink_metadata(&path)?;
+        *inodes += 1;
+        let ino = *inodes;
+
+        if metadata.file_type().is_symlink() {
+            let target = fs::read_link(&path)?;
+            let target = target.to_str().expect("non UTF-8 symlink in the initramfs");
+            cpio_entry(archive, ino, &name, 0o120_777, target.as_bytes());
+        } else if metadata.is_dir() {
+            cpio_entry(archive, ino, &name, 0o040_000 | permissions(&metadata), b"");
+            pack_dir(archive, inodes, root, &path)?;
+        } else {
+            println!("cargo:rerun-if-changed={}", path.display());
+            cpio_entry(archive, ino, &name, 0o100_000 | permissions(&metadata), &fs::read(&path)?);
+        }
+    }
+    Ok(())
+}
+
+// Builds the initramfs archive the kernel includes, which is empty if there's
+// no directory to pack
+fn pack_initramfs() -> io::Result<()> {
+    println!("cargo:rerun-if-env-changed=INITRAMFS");
+    let root = PathBuf::from(env::var("INITRAMFS").unwrap_or_else(|_| String::from(INITRAMFS)));
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap()).join("initramfs.cpio");
+
+    let mut archive = Vec::new();
+    if root.is_dir() {
+        pack_dir(&mut archive, &mut 0, &root, &root)?;
+        cpio_entry(&mut archive, 0, "TRAILER!!!", 0, b"");
+    } else {
+        println!("cargo:rerun-if-changed={}", root.display());
+    }
+    fs::File::create(out)?.write_all(&archive)
+}
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=RUSTFLAGS");
+    pack_initramfs().expect("failed to pack the initramfs");
+
+    // Heap debugging records call sites by walking frame pointers, which
+    // other builds go without
+    if env::var_os("CARGO_FEATURE_HEAP_DEBUG").is_some() && !frame_pointers() {
+        panic!("heap-debug needs frame pointers, build with RUSTFLAGS=\"-C force-frame-pointers=yes\"");
+    }
+}
@@ -0,0 +1,71 @@
+#!/bin/sh
+# Runs the kernel through bootimage. Test kernels get fresh disk images
+# first, which the test-args in Cargo.toml attach, and the filesystems on them
+# are checked once the tests are done.
+set -e
+kernel="$1"
+shift
+case "$kernel" in
+*/deps/*) ;;
+*) exec bootimage runner "$kernel" "$@" ;;
+esac
+
+disks=target/disks
+mkdir -p $disks
+
+# Blank disks for drivers to write all over, marked so tests can tell them
+# from the disk the kernel booted from. The CD drive gets one too, to read.
+scratch() {
+    dd if=/dev/zero of=$disks/$1.img bs=1M count=4 2>/dev/null
+    printf 'SOLSTICE-SCRATCH' | dd of=$disks/$1.img conv=notrunc 2>/dev/null
+}
+for disk in virtio ahci ide cd nvme; do
+    scratch $disk
+done
+
+# FAT volumes as mkfs.fat makes them, with the same files copied onto each by
+# mtools
+files=$disks/fat-files
+rm -rf $files
+mkdir -p "$files/Sub Dir"
+echo 'hello from mtools' >"$files/Hello World.txt"
+echo 'nested' >"$files/Sub Dir/nested.txt"
+seq 1 20000 >$files/numbers.txt
+fat() {
+    rm -f $disks/$1.img
+    mkfs.fat -C -F $2 -s 1 $disks/$1.img $3 >/dev/null
+    MTOOLS_SKIP_CHECK=1 mcopy -s -i $disks/$1.img "$files"/* ::/
+}
+fat fat12 12 1440
+fat fat16 16 16384
+fat fat32 32 40960
+
+# An ext2 volume from mke2fs to boot into, holding the initramfs and some
+# files for the tests to find. -d needs e2fsprogs 1.43 or later.
+root=$disks/ext2-root
+rm -rf $root
+mkdir -p $root/ext2/sub/deeper
+cp -R initramfs/. $root/
+echo 'hello from mke2fs' >$root/ext2/hello.txt
+chmod 640 $root/ext2/hello.txt
+ln $root/ext2/hello.txt $root/ext2/link
+ln -s hello.txt $root/ext2/symlink
+echo 'deep' >$root/ext2/sub/deeper/file
+seq 1 100000 >$root/ext2/numbers.txt
+rm -f $disks/ext2.img
+mke2fs -q -t ext2 -b 1024 -d $root $disks/ext2.img 16M >/dev/null
+
+# The tests write to the volumes, which have to still be in order afterwards
+status=0
+bootimage runner "$kernel" "$@" || status=$?
+for image in fat12 fat16 fat32; do
+    fsck.fat -n $disks/$image.img >/dev/null || {
+        echo "fsck.fat: $image.img is damaged"
+        status=1
+    }
+done
+e2fsck -fn $disks/ext2.img >/dev/null || {
+    echo "e2fsck: ext2.img is damaged"
+    status=1
+}
+exit $status
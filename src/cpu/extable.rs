@@ -0,0 +1,76 @@
+// Exception table. Inline assembly that may fault emits (instruction, fixup)
+// address pairs into the __ex_table section. When a fault happens in kernel
+// mode, the exception handlers look up the faulting instruction here and resume
+// execution at the fixup instead of panicking.
+use core::{mem, slice};
+use x86_64::VirtAddr;
+
+#[repr(C)]
+struct Entry {
+    insn: usize,
+    fixup: usize,
+}
+
+extern "C" {
+    static __ex_table_start: Entry;
+    static __ex_table_end: Entry;
+}
+
+fn entries() -> &'static [Entry] {
+    unsafe {
+        let start = &__ex_table_start as *const Entry;
+        let end = &__ex_table_end as *const Entry;
+        let len = (end as usize - start as usize) / mem::size_of::<Entry>();
+        slice::from_raw_parts(start, len)
+    }
+}
+
+pub fn search(rip: VirtAddr) -> Option<VirtAddr> {
+    entries()
+        .iter()
+        .find(|entry| entry.insn == rip.as_usize())
+        .map(|entry| VirtAddr::new(entry.fixup))
+}
+
+// Writes a byte, returning an error instead of faulting if the address isn't
+// writable
+pub unsafe fn try_write_u8(ptr: *mut u8, val: u8) -> Result<(), ()> {
+    let err: u64;
+
+    asm!("
+        xor $0, $0
+    1:  movb $2, ($1)
+    2:
+        .pushsection .fixup, \"ax\"
+    3:  mov $$1, $0
+        jmp 2b
+        .popsection
+        .pushsection __ex_table, \"a\"
+        .balign 8
+        .quad 1b, 3b
+        .popsection"
+        : "=&r"(err)
+        : "r"(ptr), "r"(val)
+        : "memory"
+        : "volatile");
+
+    if err == 0 {
+        Ok(())
+    } else {
+        Err(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    test_case!(write_unmapped, {
+        let mut x = 0u8;
+        assert_eq!(unsafe { try_write_u8(&mut x, 42) }, Ok(()));
+        assert_eq!(x, 42);
+
+        // Nothing is mapped this high in the lower half
+        assert_eq!(unsafe { try_write_u8(0x7000_0000_0000 as *mut u8, 42) }, Err(()));
+    });
+}
@@ -0,0 +1,154 @@
+// CPU identification and feature flags, read once and kept for the rest of the
+// kernel to query
+use arrayvec::ArrayVec;
+use x86_64::instructions::cpuid::{
+    self,
+    AddressSizes,
+    BrandString,
+    CacheInfo,
+    CacheType,
+    ExtendedFeaturesEbx,
+    ExtendedFeaturesEcx,
+    ExtendedProcessorEdx,
+    FeatureInfoEcx,
+    FeatureInfoEdx,
+    Features,
+    TopologyLevel,
+    Vendor,
+    Version,
+};
+
+const MAX_CACHES: usize = 8;
+const MAX_TOPOLOGY_LEVELS: usize = 4;
+
+pub struct CpuInfo {
+    pub vendor: Vendor,
+    pub brand: Option<BrandString>,
+    pub version: Version,
+    pub features: Features,
+    pub caches: ArrayVec<[CacheInfo; MAX_CACHES]>,
+    pub topology: ArrayVec<[TopologyLevel; MAX_TOPOLOGY_LEVELS]>,
+    pub address_sizes: AddressSizes,
+}
+
+lazy_static! {
+    static ref CPU_INFO: CpuInfo = CpuInfo {
+        vendor: Vendor::read(),
+        brand: BrandString::read(),
+        version: Version::read(),
+        features: Features::read(),
+        caches: cpuid::caches().take(MAX_CACHES).collect(),
+        topology: cpuid::topology().take(MAX_TOPOLOGY_LEVELS).collect(),
+        address_sizes: AddressSizes::read(),
+    };
+}
+
+pub fn cpu_info() -> &'static CpuInfo {
+    &*CPU_INFO
+}
+
+pub fn features() -> &'static Features {
+    &cpu_info().features
+}
+
+pub fn has_apic() -> bool {
+    features().info_edx.contains(FeatureInfoEdx::APIC)
+}
+
+pub fn has_x2apic() -> bool {
+    features().info_ecx.contains(FeatureInfoEcx::X2APIC)
+}
+
+pub fn has_giant_pages() -> bool {
+    features().processor_edx.contains(ExtendedProcessorEdx::PDPE1GB)
+}
+
+pub fn has_nx() -> bool {
+    features().processor_edx.contains(ExtendedProcessorEdx::NX)
+}
+
+pub fn has_smep() -> bool {
+    features().extended_ebx.contains(ExtendedFeaturesEbx::SMEP)
+}
+
+pub fn has_smap() -> bool {
+    features().extended_ebx.contains(ExtendedFeaturesEbx::SMAP)
+}
+
+pub fn has_umip() -> bool {
+    features().extended_ecx.contains(ExtendedFeaturesEcx::UMIP)
+}
+
+pub fn has_rdrand() -> bool {
+    features().info_ecx.contains(FeatureInfoEcx::RDRAND)
+}
+
+pub fn has_syscall() -> bool {
+    features().processor_edx.contains(ExtendedProcessorEdx::SYSCALL)
+}
+
+pub fn report() {
+    let info = cpu_info();
+
+    info!(
+        "cpu: {:?} {}",
+        info.vendor,
+        info.brand.as_ref().map_or("", |brand| brand.as_str())
+    );
+    info!(
+        "cpu: family {:#x}, model {:#x}, stepping {}",
+        info.version.family, info.version.model, info.version.stepping
+    );
+    info!(
+        "cpu: {} bit physical, {} bit virtual addresses",
+        info.address_sizes.physical, info.address_sizes.linear
+    );
+
+    for cache in &info.caches {
+        let kind = match cache.cache_type {
+            CacheType::Data => "d",
+            CacheType::Instruction => "i",
+            CacheType::Unified => "",
+        };
+        debug!(
+            "cpu: L{}{} cache: {} KiB, {} way, {} byte lines, shared by {}",
+            cache.level,
+            kind,
+            cache.size() / 1024,
+            cache.ways,
+            cache.line_size,
+            cache.shared_by
+        );
+    }
+
+    for level in &info.topology {
+        debug!(
+            "cpu: topology {:?}: {} logical processors, shift {}",
+            level.level_type, level.logical_processors, level.shift
+        );
+    }
+
+    let features = &info.features;
+    debug!("cpu: leaf 1:          {:?} | {:?}", features.info_ecx, features.info_edx);
+    debug!(
+        "cpu: leaf 7:          {:?} | {:?} | {:?}",
+        features.extended_ebx, features.extended_ecx, features.extended_edx
+    );
+    debug!(
+        "cpu: leaf 8000_0001h: {:?} | {:?}",
+        features.processor_ecx, features.processor_edx
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    test_case!(long_mode_features, {
+        // Anything we're running on has these
+        assert!(features().info_edx.contains(FeatureInfoEdx::FPU | FeatureInfoEdx::MSR | FeatureInfoEdx::PAE));
+        assert!(features().processor_edx.contains(ExtendedProcessorEdx::LM));
+        assert!(has_nx());
+        assert!(has_syscall());
+    });
+}
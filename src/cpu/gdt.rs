@@ -2,67 +2,84 @@ use lazy_static::lazy_static;
 use x86_64::{
     instructions::tables::load_tss,
     structures::{
-        gdt::{Descriptor, GlobalDescriptorTable},
+        gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
         tss::TaskStateSegment,
     },
+    PrivilegeLevel,
     VirtAddr,
 };
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-
-            stack_start + STACK_SIZE
-        };
+// The order of the user segments is fixed by sysret, which loads ss from
+// STAR[63:48] + 8 and cs from STAR[63:48] + 16
+pub const KERNEL_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(1, PrivilegeLevel::Ring0);
+pub const KERNEL_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(2, PrivilegeLevel::Ring0);
+pub const USER_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(3, PrivilegeLevel::Ring3);
+pub const USER_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(4, PrivilegeLevel::Ring3);
+pub const TSS_SELECTOR: SegmentSelector = SegmentSelector::new(5, PrivilegeLevel::Ring0);
 
-        tss
-    };
-}
+// Mutable, since the ring 0 stack pointer changes with every thread switch
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
 
 lazy_static! {
     static ref GDT: GlobalDescriptorTable = {
         let mut gdt = GlobalDescriptorTable::new();
 
-        // Kernel code segment
         gdt.add_entry(Descriptor::kernel_code_segment());
-
-        // TSS segment
-        gdt.add_entry(Descriptor::tss_segment(&TSS));
+        gdt.add_entry(Descriptor::kernel_data_segment());
+        gdt.add_entry(Descriptor::user_data_segment());
+        gdt.add_entry(Descriptor::user_code_segment());
+        gdt.add_entry(Descriptor::tss_segment(unsafe { &TSS }));
 
         gdt
     };
 }
 
 pub fn load() {
+    unsafe {
+        let mut ist = TSS.interrupt_stack_table;
+        ist[DOUBLE_FAULT_IST_INDEX as usize] = {
+            const STACK_SIZE: usize = 4096;
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+            let stack_start = VirtAddr::from_ptr(&STACK);
+
+            stack_start + STACK_SIZE
+        };
+        TSS.interrupt_stack_table = ist;
+    }
+
     GDT.load();
 
     unsafe {
-        use x86_64::{
-            instructions::segmentation as seg,
-            structures::gdt::SegmentSelector,
-            PrivilegeLevel,
-        };
+        use x86_64::instructions::segmentation as seg;
 
         let null_segment = SegmentSelector::new(0, PrivilegeLevel::Ring0);
-        let code_segment = SegmentSelector::new(1, PrivilegeLevel::Ring0);
-        let tss_segment = SegmentSelector::new(2, PrivilegeLevel::Ring0);
 
         seg::load_ds(null_segment);
         seg::load_es(null_segment);
         seg::load_fs(null_segment);
         seg::load_gs(null_segment);
-        seg::load_ss(null_segment);
-        seg::set_cs(code_segment);
-        load_tss(tss_segment);
+        seg::load_ss(KERNEL_DATA_SELECTOR);
+        seg::set_cs(KERNEL_CODE_SELECTOR);
+        load_tss(TSS_SELECTOR);
     }
 
     debug!("gdt: loaded");
 }
+
+// Sets the stack the CPU switches to when an interrupt or exception arrives in
+// ring 3
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    // TSS is packed, so the array can't be borrowed in place
+    unsafe {
+        let mut pst = TSS.privilege_stack_table;
+        pst[0] = stack_top;
+        TSS.privilege_stack_table = pst;
+    }
+}
+
+pub fn kernel_stack() -> VirtAddr {
+    unsafe { TSS.privilege_stack_table[0] }
+}
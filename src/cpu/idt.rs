@@ -2,7 +2,14 @@
 use lazy_static::lazy_static;
 use x86_64::structures::idt;
 use x86_64::registers::control::Cr2;
-use crate::cpu::gdt::DOUBLE_FAULT_IST_INDEX;
+use crate::{
+    cpu::{extable, gdt::DOUBLE_FAULT_IST_INDEX, irq},
+    mm::uaccess::USER_END,
+    process::{self, signal::{self, SigInfo, Signal, BUS_ADRALN, FPE_INTDIV, ILL_ILLOPN, SEGV_ACCERR, SEGV_MAPERR, SI_KERNEL}},
+    syscall::entry,
+    task,
+};
+use x86_64::PrivilegeLevel;
 
 lazy_static! {
     static ref IDT: idt::InterruptDescriptorTable = {
@@ -10,7 +17,7 @@ lazy_static! {
         idt.divide_error.set_handler_fn(divide_error_handler);
         idt.debug.set_handler_fn(debug_handler);
         idt.non_maskable_interrupt.set_handler_fn(non_maskable_interrupt_handler);
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.breakpoint.set_handler_fn(breakpoint_handler).set_privilege_level(PrivilegeLevel::Ring3);
         idt.overflow.set_handler_fn(overflow_handler);
         idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
         idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
@@ -27,6 +34,7 @@ lazy_static! {
         idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
         idt.virtualization.set_handler_fn(virtualization_handler);
         idt.security_exception.set_handler_fn(security_exception_handler);
+        irq::install(&mut idt);
         idt
     };
 }
@@ -36,11 +44,40 @@ pub fn load() {
     debug!("idt: loaded");
 }
 
+// Exceptions raised by ring 3 are turned into a signal for the process rather
+// than taking down the kernel. Returns whether the handler should return.
+fn user_fault(name: &str, frame: &mut idt::InterruptStackFrame, signal: Signal, code: i32, addr: usize) -> bool {
+    if frame.code_segment & 3 != 3 {
+        return false;
+    }
+
+    let thread = task::current();
+    if thread.process().is_none() {
+        info!("idt: killing thread {} ({}) after {} at {:?}", thread.tid(), thread.name(), name, frame.instruction_pointer);
+        drop(thread);
+
+        // We never return to ring 3, so switch back to the kernel's gs base
+        unsafe { x86_64::instructions::segmentation::swap_gs() };
+        process::exit(-1);
+    }
+
+    trace!("idt: {} in thread {} at {:?}, raising {:?}", name, thread.tid(), frame.instruction_pointer, signal);
+    drop(thread);
+    signal::force(signal, SigInfo::new(signal, code, addr));
+    entry::redirect_trap(frame);
+    true
+}
+
 test_case!(int3_handler, {
     x86_64::instructions::interrupts::int3();
 });
 
 extern "x86-interrupt" fn divide_error_handler(frame: &mut idt::InterruptStackFrame) {
+    let rip = frame.instruction_pointer.as_usize();
+    if user_fault("zero division", frame, Signal::SIGFPE, FPE_INTDIV, rip) {
+        return;
+    }
+
     panic!("EXCEPTION: Zero Division\n{:#?}", frame);
 }
 
@@ -53,22 +90,44 @@ extern "x86-interrupt" fn non_maskable_interrupt_handler(frame: &mut idt::Interr
 }
 
 extern "x86-interrupt" fn breakpoint_handler(frame: &mut idt::InterruptStackFrame) {
+    if user_fault("breakpoint", frame, Signal::SIGTRAP, SI_KERNEL, 0) {
+        return;
+    }
+
     trace!("EXCEPTION: Breakpoint\n{:#?}", frame);
 }
 
 extern "x86-interrupt" fn overflow_handler(frame: &mut idt::InterruptStackFrame) {
+    if user_fault("overflow", frame, Signal::SIGSEGV, SI_KERNEL, 0) {
+        return;
+    }
+
     panic!("EXCEPTION: Overflow\n{:#?}", frame);
 }
 
 extern "x86-interrupt" fn bound_range_exceeded_handler(frame: &mut idt::InterruptStackFrame) {
+    if user_fault("bound range exceeded", frame, Signal::SIGSEGV, SI_KERNEL, 0) {
+        return;
+    }
+
     panic!("EXCEPTION: Bound Range Exceeded\n{:#?}", frame);
 }
 
 extern "x86-interrupt" fn invalid_opcode_handler(frame: &mut idt::InterruptStackFrame) {
+    let rip = frame.instruction_pointer.as_usize();
+    if user_fault("invalid opcode", frame, Signal::SIGILL, ILL_ILLOPN, rip) {
+        return;
+    }
+
     panic!("EXCEPTION: Invalid Opcode\n{:#?}", frame);
 }
 
 extern "x86-interrupt" fn device_not_available_handler(frame: &mut idt::InterruptStackFrame) {
+    let rip = frame.instruction_pointer.as_usize();
+    if user_fault("device not available", frame, Signal::SIGFPE, SI_KERNEL, rip) {
+        return;
+    }
+
     panic!("EXCEPTION: Device Not Available\n{:#?}", frame);
 }
 
@@ -85,22 +144,75 @@ extern "x86-interrupt" fn segment_not_present_handler(frame: &mut idt::Interrupt
 }
 
 extern "x86-interrupt" fn stack_segment_fault_handler(frame: &mut idt::InterruptStackFrame, error_code: u64) {
+    if user_fault("stack segment fault", frame, Signal::SIGBUS, BUS_ADRALN, 0) {
+        return;
+    }
+
     panic!("EXCEPTION: Stack Segment Fault with error code {}\n{:#?}", error_code, frame);
 }
 
 extern "x86-interrupt" fn general_protection_fault_handler(frame: &mut idt::InterruptStackFrame, error_code: u64) {
+    if user_fault("general protection fault", frame, Signal::SIGSEGV, SI_KERNEL, 0) {
+        return;
+    }
+
+    // Non-canonical addresses passed to the user copy helpers end up here
+    if frame.code_segment & 3 == 0 {
+        if let Some(fixup) = extable::search(frame.instruction_pointer) {
+            unsafe { frame.as_mut().instruction_pointer = fixup };
+            return;
+        }
+    }
+
     panic!("EXCEPTION: General Protection Fault with error code {}\n{:#?}", error_code, frame);
 }
 
 extern "x86-interrupt" fn page_fault_handler(frame: &mut idt::InterruptStackFrame, error_code: idt::PageFaultErrorCode) {
-    panic!("EXCEPTION: Page Fault with error code {:#?}\nAddress {:?}\n{:#?}", error_code, Cr2::read(), frame);
+    // Writes to copy-on-write user pages, from ring 3 or through uaccess
+    let write_protect = idt::PageFaultErrorCode::PROTECTION_VIOLATION | idt::PageFaultErrorCode::CAUSED_BY_WRITE;
+    let addr = Cr2::read();
+    if error_code.contains(write_protect) && addr.as_usize() < USER_END {
+        if let Some(space) = task::current().addr_space() {
+            if space.handle_cow_fault(addr) {
+                return;
+            }
+        }
+    }
+
+    let code = if error_code.contains(idt::PageFaultErrorCode::PROTECTION_VIOLATION) {
+        SEGV_ACCERR
+    } else {
+        SEGV_MAPERR
+    };
+    if user_fault("page fault", frame, Signal::SIGSEGV, code, addr.as_usize()) {
+        return;
+    }
+
+    if !error_code.contains(idt::PageFaultErrorCode::USER_MODE) {
+        if let Some(fixup) = extable::search(frame.instruction_pointer) {
+            unsafe { frame.as_mut().instruction_pointer = fixup };
+            return;
+        }
+    }
+
+    panic!("EXCEPTION: Page Fault with error code {:#?}\nAddress {:?}\n{:#?}", error_code, addr, frame);
 }
 
 extern "x86-interrupt" fn x87_floating_point_handler(frame: &mut idt::InterruptStackFrame) {
+    let rip = frame.instruction_pointer.as_usize();
+    if user_fault("x87 floating point", frame, Signal::SIGFPE, SI_KERNEL, rip) {
+        return;
+    }
+
     panic!("EXCEPTION: x87 Floating Point\n{:#?}", frame);
 }
 
 extern "x86-interrupt" fn alignment_check_handler(frame: &mut idt::InterruptStackFrame, error_code: u64) {
+    let rip = frame.instruction_pointer.as_usize();
+    if user_fault("alignment check", frame, Signal::SIGBUS, BUS_ADRALN, rip) {
+        return;
+    }
+
     panic!("EXCEPTION: Alignment Check with error code {}\n{:#?}", error_code, frame);
 }
 
@@ -109,6 +221,11 @@ extern "x86-interrupt" fn machine_check_handler(frame: &mut idt::InterruptStackF
 }
 
 extern "x86-interrupt" fn simd_floating_point_handler(frame: &mut idt::InterruptStackFrame) {
+    let rip = frame.instruction_pointer.as_usize();
+    if user_fault("simd floating point", frame, Signal::SIGFPE, SI_KERNEL, rip) {
+        return;
+    }
+
     panic!("EXCEPTION: SIMD Floating Point\n{:#?}", frame);
 }
 
@@ -0,0 +1,122 @@
+// Vectors for device interrupts. Drivers allocate a vector with a handler and
+// then point their device at it, through an I/O APIC pin or an MSI message.
+// Handlers run on whichever thread was interrupted, so anything they lock must
+// only be locked with interrupts off elsewhere.
+use super::lapic;
+use crate::ds::SpinLock;
+use alloc::{sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::{
+    instructions::interrupts,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
+};
+
+pub type Handler = Arc<dyn Fn() + Send + Sync>;
+
+// Vectors below 0x20 are exceptions, and the priority class of a vector is
+// its top nibble, so devices start a little way above them
+pub const VECTOR_START: u8 = 0x30;
+pub const VECTOR_COUNT: usize = 32;
+
+lazy_static! {
+    static ref HANDLERS: SpinLock<Vec<Option<Handler>>> = SpinLock::new(vec![None; VECTOR_COUNT]);
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+macro_rules! stubs {
+    ($($index:literal => $name:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_frame: &mut InterruptStackFrame) {
+                dispatch(VECTOR_START + $index);
+            }
+        )*
+
+        pub fn install(idt: &mut InterruptDescriptorTable) {
+            $(idt[usize::from(VECTOR_START + $index)].set_handler_fn($name);)*
+            idt[usize::from(lapic::SPURIOUS_VECTOR)].set_handler_fn(spurious_handler);
+        }
+    };
+}
+
+stubs! {
+    0 => irq_0, 1 => irq_1, 2 => irq_2, 3 => irq_3, 4 => irq_4, 5 => irq_5, 6 => irq_6, 7 => irq_7,
+    8 => irq_8, 9 => irq_9, 10 => irq_10, 11 => irq_11, 12 => irq_12, 13 => irq_13, 14 => irq_14,
+    15 => irq_15, 16 => irq_16, 17 => irq_17, 18 => irq_18, 19 => irq_19, 20 => irq_20, 21 => irq_21,
+    22 => irq_22, 23 => irq_23, 24 => irq_24, 25 => irq_25, 26 => irq_26, 27 => irq_27, 28 => irq_28,
+    29 => irq_29, 30 => irq_30, 31 => irq_31,
+}
+
+// Spurious interrupts aren't in service, so they mustn't be acknowledged
+extern "x86-interrupt" fn spurious_handler(_frame: &mut InterruptStackFrame) {
+    trace!("irq: spurious interrupt");
+}
+
+// Starts taking interrupts, once the interrupt controllers are set up. Threads
+// started from then on run with them on as well.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Release);
+    interrupts::enable();
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+// Returns a free vector that will call handler, if there are any left
+pub fn alloc_vector(handler: Handler) -> Option<u8> {
+    interrupts::without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let index = handlers.iter().position(Option::is_none)?;
+        handlers[index] = Some(handler);
+        Some(VECTOR_START + index as u8)
+    })
+}
+
+pub fn free_vector(vector: u8) {
+    let index = usize::from(vector - VECTOR_START);
+    let old = interrupts::without_interrupts(|| HANDLERS.lock()[index].take());
+    assert!(old.is_some(), "irq: freeing unallocated vector {:#x}", vector);
+}
+
+pub fn dispatch(vector: u8) {
+    // The handler may well allocate or free vectors itself. Tests call this
+    // with interrupts on.
+    let index = usize::from(vector - VECTOR_START);
+    let handler = interrupts::without_interrupts(|| HANDLERS.lock()[index].clone());
+    match handler {
+        Some(handler) => handler(),
+        None => warn!("irq: unexpected interrupt on vector {:#x}", vector),
+    }
+
+    lapic::eoi();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    test_case!(alloc_and_dispatch, {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let vector = alloc_vector(Arc::new(move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }))
+        .unwrap();
+        assert!(vector >= VECTOR_START && usize::from(vector - VECTOR_START) < VECTOR_COUNT);
+
+        dispatch(vector);
+        dispatch(vector);
+        assert_eq!(count.load(Ordering::Relaxed), 2);
+
+        let other = alloc_vector(Arc::new(|| {})).unwrap();
+        assert_ne!(other, vector);
+        free_vector(other);
+
+        free_vector(vector);
+        dispatch(vector);
+        assert_eq!(count.load(Ordering::Relaxed), 2);
+        assert_eq!(Arc::strong_count(&count), 1);
+    });
+}
@@ -0,0 +1,87 @@
+// The local APIC of the boot processor, in xAPIC mode. Device interrupts are
+// delivered through it, whether they come from the I/O APIC or as MSIs, and
+// each has to be acknowledged with an EOI.
+use crate::mm::mmio;
+use core::{
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use x86_64::{
+    instructions::port::Port,
+    registers::model_specific::Msr,
+    PhysAddr,
+    VirtAddr,
+};
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_MASK: u64 = 0xF_FFFF_F000;
+
+const REG_ID: usize = 0x20;
+const REG_VERSION: usize = 0x30;
+const REG_EOI: usize = 0xB0;
+const REG_SPURIOUS: usize = 0xF0;
+
+const SPURIOUS_ENABLE: u32 = 1 << 8;
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+// Where the registers are mapped, or zero before init
+static BASE: AtomicUsize = AtomicUsize::new(0);
+
+pub fn init() {
+    // Everything goes through the APICs, so keep the 8259s quiet
+    unsafe {
+        Port::<u8>::new(0x21).write(0xFF);
+        Port::<u8>::new(0xA1).write(0xFF);
+    }
+
+    let mut msr = Msr::new(IA32_APIC_BASE);
+    let value = unsafe { msr.read() };
+    unsafe { msr.write(value | APIC_BASE_ENABLE) };
+
+    let phys = PhysAddr::new((value & APIC_BASE_MASK) as usize);
+    let virt = mmio::map(phys, 0x1000).expect("lapic: failed to map registers");
+    BASE.store(virt.as_usize(), Ordering::Release);
+
+    write(REG_SPURIOUS, SPURIOUS_ENABLE | u32::from(SPURIOUS_VECTOR));
+    info!("lapic: id {} version {:#x} at {:?}", id(), read(REG_VERSION) & 0xFF, phys);
+}
+
+pub fn is_enabled() -> bool {
+    BASE.load(Ordering::Acquire) != 0
+}
+
+fn register(reg: usize) -> VirtAddr {
+    let base = BASE.load(Ordering::Acquire);
+    assert_ne!(base, 0, "lapic: not initialised");
+    VirtAddr::new(base + reg)
+}
+
+fn read(reg: usize) -> u32 {
+    unsafe { ptr::read_volatile(register(reg).as_ptr::<u32>()) }
+}
+
+fn write(reg: usize, value: u32) {
+    unsafe { ptr::write_volatile(register(reg).as_mut_ptr::<u32>(), value) }
+}
+
+// The APIC ID interrupts have to be addressed to for this CPU
+pub fn id() -> u8 {
+    (read(REG_ID) >> 24) as u8
+}
+
+pub fn eoi() {
+    write(REG_EOI, 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    test_case!(enabled, {
+        assert!(is_enabled());
+        assert_ne!(read(REG_SPURIOUS) & SPURIOUS_ENABLE, 0);
+        // The boot processor is always the first in q35
+        assert_eq!(id(), 0);
+    });
+}
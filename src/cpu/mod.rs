@@ -1,3 +1,9 @@
+pub mod extable;
+pub mod features;
 pub mod gdt;
 pub mod idt;
+pub mod irq;
+pub mod lapic;
 pub mod percpu;
+pub mod protect;
+pub mod user;
@@ -1,10 +1,22 @@
-use crate::mm::addr_space::AddrSpace;
+use crate::{cpu::gdt, mm::addr_space::AddrSpace};
 use arrayvec::ArrayVec;
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::{
+    cell::UnsafeCell,
+    mem,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use x86_64::{registers::model_specific::GsBase, VirtAddr};
 
+// The syscall entry stub finds this through the gs base, so the first two
+// fields must stay where they are (see syscall/entry.rs)
+#[repr(C)]
 pub struct PerCpu {
+    kernel_stack: UnsafeCell<usize>,
+    user_stack: UnsafeCell<usize>,
     addr_space: *const AddrSpace,
     preempt_count: AtomicUsize,
+    // Interrupt frame of an exception from ring 3 being turned into a signal
+    trap_frame: UnsafeCell<Option<[usize; 5]>>,
 }
 
 unsafe impl Send for PerCpu {}
@@ -17,19 +29,52 @@ lazy_static! {
         let mut cpus = ArrayVec::new();
 
         cpus.push(PerCpu {
+            kernel_stack: UnsafeCell::new(0),
+            user_stack: UnsafeCell::new(0),
             addr_space: AddrSpace::kernel(),
             preempt_count: AtomicUsize::new(0),
+            trap_frame: UnsafeCell::new(None),
         });
 
         cpus
     };
 }
 
+// Points the gs base at this CPU's PerCpu. Must come after the GDT is loaded,
+// since loading gs clears its base.
+pub fn init() {
+    unsafe { GsBase::write(VirtAddr::from_ptr(PerCpu::current())) };
+}
+
 impl PerCpu {
     pub fn current() -> &'static PerCpu {
         &CPUS[0] // TODO: SMP
     }
 
+    // Where this CPU is in CPUS, for whatever drivers keep for each CPU
+    pub fn index(&self) -> usize {
+        (self as *const PerCpu as usize - CPUS.as_ptr() as usize) / mem::size_of::<PerCpu>()
+    }
+
+    // Stack used for entries from ring 3, through both interrupts and syscalls
+    pub fn set_kernel_stack(&self, stack_top: VirtAddr) {
+        unsafe { *self.kernel_stack.get() = stack_top.as_usize() };
+        gdt::set_kernel_stack(stack_top);
+    }
+
+    pub fn kernel_stack(&self) -> VirtAddr {
+        VirtAddr::new(unsafe { *self.kernel_stack.get() })
+    }
+
+    // Interrupts must stay disabled until the frame is taken again
+    pub unsafe fn save_trap_frame(&self, frame: [usize; 5]) {
+        *self.trap_frame.get() = Some(frame);
+    }
+
+    pub unsafe fn take_trap_frame(&self) -> [usize; 5] {
+        (*self.trap_frame.get()).take().expect("percpu: no saved trap frame")
+    }
+
     pub unsafe fn preempt_inc(&self) {
         self.preempt_count.fetch_add(1, Ordering::Acquire);
     }
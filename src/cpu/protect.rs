@@ -0,0 +1,28 @@
+// Supervisor mode protections: SMEP stops the kernel from executing user pages,
+// SMAP stops it from touching user pages outside of the mm::uaccess helpers,
+// and UMIP stops user mode from reading descriptor table addresses.
+use super::features;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::control::{Cr4, Cr4Flags};
+
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn init() {
+    let smep = features::has_smep();
+    let smap = features::has_smap();
+    let umip = features::has_umip();
+
+    let mut enable = Cr4Flags::empty();
+    enable.set(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION, smep);
+    enable.set(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION, smap);
+    enable.set(Cr4Flags::USER_MODE_INSTRUCTION_PREVENTION, umip);
+
+    unsafe { Cr4::update(|flags| *flags |= enable) };
+    SMAP_ENABLED.store(smap, Ordering::Relaxed);
+
+    debug!("protect: smep = {}, smap = {}, umip = {}", smep, smap, umip);
+}
+
+pub fn smap_enabled() -> bool {
+    SMAP_ENABLED.load(Ordering::Relaxed)
+}
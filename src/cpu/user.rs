@@ -0,0 +1,124 @@
+// Transitions into ring 3
+use crate::cpu::{
+    gdt::{USER_CODE_SELECTOR, USER_DATA_SELECTOR},
+    irq,
+};
+use x86_64::{registers::rflags::RFlags, VirtAddr};
+
+// Reserved bit 1 is always set
+const USER_RFLAGS: u64 = 0x2;
+
+// Drops to ring 3 at entry with the given stack pointer. Traps from user mode
+// arrive on the current thread's kernel stack, from the top, so anything still
+// on this stack is lost: the caller must not own anything that needs dropping.
+pub unsafe fn enter_user(entry: VirtAddr, stack: VirtAddr) -> ! {
+    let data = u64::from(USER_DATA_SELECTOR.0);
+    let code = u64::from(USER_CODE_SELECTOR.0);
+    // Interrupts stay off until there is something to handle them
+    let rflags = if irq::is_enabled() {
+        USER_RFLAGS | RFlags::INTERRUPT_FLAG.bits()
+    } else {
+        USER_RFLAGS
+    };
+
+    // Build an interrupt frame (ss, rsp, rflags, cs, rip) and clear every
+    // register so nothing from the kernel leaks into user mode. The kernel's gs
+    // base goes back into KernelGsBase for the syscall entry stub to find.
+    asm!("
+        swapgs
+        mov $2, %ds
+        mov $2, %es
+        pushq $2
+        pushq $1
+        pushq $4
+        pushq $3
+        pushq $0
+        xor %rax, %rax
+        xor %rbx, %rbx
+        xor %rcx, %rcx
+        xor %rdx, %rdx
+        xor %rsi, %rsi
+        xor %rdi, %rdi
+        xor %rbp, %rbp
+        xor %r8, %r8
+        xor %r9, %r9
+        xor %r10, %r10
+        xor %r11, %r11
+        xor %r12, %r12
+        xor %r13, %r13
+        xor %r14, %r14
+        xor %r15, %r15
+        iretq"
+        :
+        : "r"(entry.as_usize()), "r"(stack.as_usize()), "r"(data), "r"(code), "r"(rflags)
+        : "memory"
+        : "volatile");
+
+    unreachable!();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mm::{self, addr_space::AddrSpace, pmm::PhysAllocator},
+        task,
+    };
+    use x86_64::structures::paging::PageTableFlags;
+
+    const CODE_ADDR: usize = 0x5000_0000_0000;
+    const STACK_ADDR: usize = CODE_ADDR + 0x1000;
+    const MAGIC: u64 = 0x1234_5678;
+
+    // push $0x12345678; int3; jmp .
+    const BLOB: [u8; 8] = [0x68, 0x78, 0x56, 0x34, 0x12, 0xCC, 0xEB, 0xFE];
+
+    fn run_blob(_: usize) {
+        unsafe {
+            enter_user(
+                VirtAddr::new(CODE_ADDR),
+                VirtAddr::new(STACK_ADDR + 0x1000),
+            )
+        }
+    }
+
+    test_case!(ring3_traps_back, {
+        let kernel = AddrSpace::kernel();
+        let code = PhysAllocator::alloc(0);
+        let stack = PhysAllocator::alloc(0);
+        let code_phys = code.start.start_address();
+        let stack_phys = stack.start.start_address();
+
+        // Written through the physical map, SMAP would stop us otherwise
+        unsafe {
+            let code_virt = mm::phys_to_kernel_virt(code_phys).as_mut_ptr::<u8>();
+            core::ptr::copy_nonoverlapping(BLOB.as_ptr(), code_virt, BLOB.len());
+        }
+
+        use PageTableFlags as F;
+        kernel
+            .map_to(VirtAddr::new(CODE_ADDR), code_phys, F::PRESENT | F::USER_ACCESSIBLE)
+            .unwrap()
+            .flush();
+        kernel
+            .map_to(
+                VirtAddr::new(STACK_ADDR),
+                stack_phys,
+                F::PRESENT | F::WRITABLE | F::USER_ACCESSIBLE | F::NO_EXECUTE,
+            )
+            .unwrap()
+            .flush();
+
+        // The breakpoint kills the thread, since nothing handles it
+        let thread = task::spawn("ring3", run_blob, 0);
+        assert_eq!(task::join(&thread), -1);
+
+        let pushed = unsafe { *mm::phys_to_kernel_virt(stack_phys + 0xFF8).as_ptr::<u64>() };
+        assert_eq!(pushed, MAGIC);
+
+        kernel.unmap(VirtAddr::new(CODE_ADDR)).unwrap().1.flush();
+        kernel.unmap(VirtAddr::new(STACK_ADDR)).unwrap().1.flush();
+        PhysAllocator::free(code);
+        PhysAllocator::free(stack);
+    });
+}
@@ -1,7 +1,20 @@
-use acpi::{Acpi, AcpiHandler, AmlTable, PhysicalMapping};
-use aml::{AmlContext, AmlError};
+use crate::{
+    drivers::{
+        ioapic::Polarity,
+        pci::config::{self, Address},
+    },
+    ds::SpinLock,
+};
+use acpi::{Acpi, AcpiHandler, AmlTable, InterruptModel, PhysicalMapping};
+use alloc::{boxed::Box, vec::Vec};
+use aml::{
+    pci_routing::{PciRoutingTable, Pin},
+    resource::InterruptPolarity,
+    value::Args,
+    AmlContext, AmlError, AmlName, AmlValue, DebugVerbosity, Handler,
+};
 use core::ptr::NonNull;
-use x86_64::{PhysAddr, VirtAddr};
+use x86_64::{instructions::port::Port, PhysAddr, VirtAddr};
 
 pub fn init() -> Acpi {
     let acpi = unsafe {
@@ -10,7 +23,7 @@ pub fn init() -> Acpi {
 
     debug!("acpi: found tables");
 
-    let mut ctx = AmlContext::new();
+    let mut ctx = AmlContext::new(Box::new(AmlHandler), DebugVerbosity::None);
 
     if let Some(dsdt) = &acpi.dsdt {
         parse_table(&mut ctx, dsdt).expect("AML DSDT parsing failed");
@@ -22,6 +35,11 @@ pub fn init() -> Acpi {
         debug!("acpi: parsed ssdt {}", i);
     }
 
+    if let Some(InterruptModel::Apic(_)) = acpi.interrupt_model {
+        set_apic_mode(&mut ctx);
+    }
+    *PCI_ROUTES.lock() = read_pci_routes(&mut ctx);
+
     debug!("acpi: done!");
 
     acpi
@@ -33,6 +51,75 @@ fn parse_table(ctx: &mut AmlContext, table: &AmlTable) -> Result<(), AmlError> {
     ctx.parse_table(unsafe { core::slice::from_raw_parts(virt.as_ptr(), table.length as usize) })
 }
 
+// Tells the firmware interrupts go through the I/O APIC, which firmware like
+// QEMU's q35 checks to pick between the _PRT for that and the one for the PIC
+fn set_apic_mode(ctx: &mut AmlContext) {
+    let name = AmlName::from_str("\\_PIC").expect("acpi: bad _PIC path");
+    let args = Args {
+        arg_0: Some(AmlValue::Integer(1)),
+        ..Default::default()
+    };
+
+    // It's optional, and without it there's only the one _PRT
+    if let Err(err) = ctx.invoke_method(&name, args) {
+        debug!("acpi: no _PIC: {:?}", err);
+    }
+}
+
+// Where an interrupt pin of a device on the root PCI bus is wired to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciRoute {
+    pub device: u8,
+    // 0 to 3 for INTA# to INTD#
+    pub pin: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+}
+
+lazy_static! {
+    static ref PCI_ROUTES: SpinLock<Vec<PciRoute>> = SpinLock::new(Vec::new());
+}
+
+pub fn pci_routes() -> Vec<PciRoute> {
+    PCI_ROUTES.lock().clone()
+}
+
+// Evaluates the root bridge's _PRT, which can be a package or a method
+// returning one. Its entries either give the GSI directly or name a link
+// device, whose current resources say which interrupt it's set to.
+fn read_pci_routes(ctx: &mut AmlContext) -> Vec<PciRoute> {
+    let mut routes = Vec::new();
+    let name = AmlName::from_str("\\_SB_.PCI0._PRT").expect("acpi: bad _PRT path");
+    let table = match PciRoutingTable::from_prt_path(&name, ctx) {
+        Ok(table) => table,
+        Err(err) => {
+            debug!("acpi: no usable _PRT ({:?}), leaving pci interrupts to firmware", err);
+            return routes;
+        }
+    };
+
+    let pins = [Pin::IntA, Pin::IntB, Pin::IntC, Pin::IntD];
+    for device in 0..32 {
+        for (pin, &aml_pin) in pins.iter().enumerate() {
+            // Missing entries are pins nothing is wired to
+            if let Ok(irq) = table.route(device, 0, aml_pin, ctx) {
+                routes.push(PciRoute {
+                    device: device as u8,
+                    pin: pin as u8,
+                    gsi: irq.irq,
+                    polarity: match irq.polarity {
+                        InterruptPolarity::ActiveHigh => Polarity::High,
+                        InterruptPolarity::ActiveLow => Polarity::Low,
+                    },
+                });
+            }
+        }
+    }
+
+    debug!("acpi: {} pci routes", routes.len());
+    routes
+}
+
 struct DummyAcpiHandler;
 
 impl AcpiHandler for DummyAcpiHandler {
@@ -53,3 +140,103 @@ impl AcpiHandler for DummyAcpiHandler {
 
     fn unmap_physical_region<T>(&mut self, _region: PhysicalMapping<T>) {}
 }
+
+// What AML methods use to reach memory, ports and PCI configuration space
+struct AmlHandler;
+
+impl AmlHandler {
+    fn memory<T>(address: usize) -> *mut T {
+        VirtAddr::from(PhysAddr::new(address as u64)).as_mut_ptr()
+    }
+}
+
+impl Handler for AmlHandler {
+    fn read_u8(&self, address: usize) -> u8 {
+        unsafe { Self::memory::<u8>(address).read_volatile() }
+    }
+
+    fn read_u16(&self, address: usize) -> u16 {
+        unsafe { Self::memory::<u16>(address).read_volatile() }
+    }
+
+    fn read_u32(&self, address: usize) -> u32 {
+        unsafe { Self::memory::<u32>(address).read_volatile() }
+    }
+
+    fn read_u64(&self, address: usize) -> u64 {
+        unsafe { Self::memory::<u64>(address).read_volatile() }
+    }
+
+    fn write_u8(&mut self, address: usize, value: u8) {
+        unsafe { Self::memory::<u8>(address).write_volatile(value) }
+    }
+
+    fn write_u16(&mut self, address: usize, value: u16) {
+        unsafe { Self::memory::<u16>(address).write_volatile(value) }
+    }
+
+    fn write_u32(&mut self, address: usize, value: u32) {
+        unsafe { Self::memory::<u32>(address).write_volatile(value) }
+    }
+
+    fn write_u64(&mut self, address: usize, value: u64) {
+        unsafe { Self::memory::<u64>(address).write_volatile(value) }
+    }
+
+    fn read_io_u8(&self, port: u16) -> u8 {
+        unsafe { Port::new(port).read() }
+    }
+
+    fn read_io_u16(&self, port: u16) -> u16 {
+        unsafe { Port::new(port).read() }
+    }
+
+    fn read_io_u32(&self, port: u16) -> u32 {
+        unsafe { Port::new(port).read() }
+    }
+
+    fn write_io_u8(&self, port: u16, value: u8) {
+        unsafe { Port::new(port).write(value) }
+    }
+
+    fn write_io_u16(&self, port: u16, value: u16) {
+        unsafe { Port::new(port).write(value) }
+    }
+
+    fn write_io_u32(&self, port: u16, value: u32) {
+        unsafe { Port::new(port).write(value) }
+    }
+
+    fn read_pci_u8(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u8 {
+        config::read_u8(pci_address(segment, bus, device, function), offset)
+    }
+
+    fn read_pci_u16(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u16 {
+        config::read_u16(pci_address(segment, bus, device, function), offset)
+    }
+
+    fn read_pci_u32(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+        config::read_u32(pci_address(segment, bus, device, function), offset)
+    }
+
+    fn write_pci_u8(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16, value: u8) {
+        config::write_u8(pci_address(segment, bus, device, function), offset, value)
+    }
+
+    fn write_pci_u16(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16, value: u16) {
+        config::write_u16(pci_address(segment, bus, device, function), offset, value)
+    }
+
+    fn write_pci_u32(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16, value: u32) {
+        config::write_u32(pci_address(segment, bus, device, function), offset, value)
+    }
+}
+
+fn pci_address(segment: u16, bus: u8, device: u8, function: u8) -> Address {
+    Address {
+        segment,
+        bus,
+        device,
+        function,
+    }
+}
@@ -0,0 +1,575 @@
+// AHCI controllers, which is where SATA disks are on q35. Each port has a
+// list of up to 32 command slots, and the controller works through the
+// commands in them by DMA. Disks with native command queueing get all their
+// reads and writes at once and finish them in whatever order suits them.
+//
+// Queued commands can't be outstanding alongside others, so flushes wait for
+// them to drain first. Ports that aren't an ATA disk are left alone.
+//
+// The interrupt handler completes requests with the port's slots locked, so
+// threads only lock them with interrupts off.
+use super::{
+    check,
+    Identity,
+    DEVICE_LBA,
+    IDENTIFY,
+    READ_DMA,
+    READ_DMA_EXT,
+    READ_FPDMA_QUEUED,
+    STATUS_BSY,
+    STATUS_DRQ,
+    WRITE_DMA,
+    WRITE_DMA_EXT,
+    WRITE_FPDMA_QUEUED,
+};
+use crate::{
+    drivers::{
+        block::{self, spin_for, wait_for, BlockDevice, Op, Request, SECTOR_SIZE},
+        pci::{
+            self,
+            device::{COMMAND_BUS_MASTER, COMMAND_MEMORY},
+            Bar,
+            Device,
+            Driver,
+            Irq,
+            Match,
+        },
+    },
+    ds::SpinLock,
+    mm::{dma::DmaBuffer, mmio},
+    syscall::Errno,
+    task,
+};
+use alloc::{
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{cmp, ptr};
+use x86_64::{instructions::interrupts, PhysAddr, VirtAddr};
+
+// Controller registers
+const CAP: usize = 0x00;
+const GHC: usize = 0x04;
+const IS: usize = 0x08;
+const PI: usize = 0x0C;
+const CAP2: usize = 0x24;
+const BOHC: usize = 0x28;
+
+const CAP_SSS: u32 = 1 << 27;
+const CAP_SNCQ: u32 = 1 << 30;
+const CAP_S64A: u32 = 1 << 31;
+const GHC_IE: u32 = 1 << 1;
+const GHC_AE: u32 = 1 << 31;
+const CAP2_BOH: u32 = 1 << 0;
+const BOHC_BOS: u32 = 1 << 0;
+const BOHC_OOS: u32 = 1 << 1;
+
+// Port registers, 0x80 bytes of them for each port
+const PORTS: usize = 0x100;
+const PORT_SIZE: usize = 0x80;
+const P_CLB: usize = 0x00;
+const P_FB: usize = 0x08;
+const P_IS: usize = 0x10;
+const P_IE: usize = 0x14;
+const P_CMD: usize = 0x18;
+const P_TFD: usize = 0x20;
+const P_SIG: usize = 0x24;
+const P_SSTS: usize = 0x28;
+const P_SERR: usize = 0x30;
+const P_SACT: usize = 0x34;
+const P_CI: usize = 0x38;
+
+const CMD_ST: u32 = 1 << 0;
+const CMD_SUD: u32 = 1 << 1;
+const CMD_POD: u32 = 1 << 2;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+
+// Port interrupts for a register FIS, a set device bits FIS finishing queued
+// commands, and the errors that stop the port
+const IS_DHRS: u32 = 1 << 0;
+const IS_SDBS: u32 = 1 << 3;
+const IS_ERRORS: u32 = 0xF << 27;
+
+// A device is there and the link is up
+const SSTS_PRESENT: u32 = 0x103;
+
+const SIG_ATA: u32 = 0x0000_0101;
+
+// How the memory shared with a port is laid out: the command list, the FISes
+// the device sends back, then a command table for each slot with room for a
+// single PRD entry, since request buffers are contiguous
+const COMMAND_LIST: usize = 0;
+const HEADER_SIZE: usize = 32;
+const RECEIVED_FIS: usize = 0x400;
+const TABLES: usize = 0x500;
+const TABLE_SIZE: usize = 0x100;
+const PRDT: usize = 0x80;
+const MAX_SLOTS: usize = 32;
+
+// A PRD entry's byte count has 22 bits
+const MAX_PRD_BYTES: usize = 4 << 20;
+
+const FIS_H2D: u8 = 0x27;
+
+pub static DRIVER: Driver = Driver {
+    name: "ahci",
+    matches: &[Match::Class {
+        class: 0x01,
+        subclass: 0x06,
+        prog_if: Some(0x01),
+    }],
+    probe,
+};
+
+#[derive(Clone, Copy)]
+struct Registers(VirtAddr);
+
+impl Registers {
+    fn read(self, offset: usize) -> u32 {
+        unsafe { ptr::read_volatile((self.0 + offset).as_ptr()) }
+    }
+
+    fn write(self, offset: usize, value: u32) {
+        unsafe { ptr::write_volatile((self.0 + offset).as_mut_ptr(), value) }
+    }
+
+    fn write_addr(self, offset: usize, addr: PhysAddr) {
+        self.write(offset, addr.as_usize() as u32);
+        self.write(offset + 4, (addr.as_usize() >> 32) as u32);
+    }
+
+    fn port(self, port: usize) -> Registers {
+        Registers(self.0 + PORTS + port * PORT_SIZE)
+    }
+}
+
+// A register FIS from host to device, which carries a command. Queued
+// commands have their sector count in the features and their tag in the
+// count.
+fn command_fis(command: u8, lba: u64, count: u16, features: u16, device: u8) -> [u8; 20] {
+    let lba = lba.to_le_bytes();
+    let mut fis = [0; 20];
+    fis[0] = FIS_H2D;
+    // A command rather than a write to the control register
+    fis[1] = 1 << 7;
+    fis[2] = command;
+    fis[3] = features as u8;
+    fis[4..7].copy_from_slice(&lba[..3]);
+    fis[7] = device;
+    fis[8..11].copy_from_slice(&lba[3..6]);
+    fis[11] = (features >> 8) as u8;
+    fis[12..14].copy_from_slice(&count.to_le_bytes());
+    fis
+}
+
+// The most sectors a request moves, in a single command with a single PRD
+// entry
+fn max_sectors(identity: &Identity) -> usize {
+    cmp::min(identity.max_sectors(), MAX_PRD_BYTES / SECTOR_SIZE)
+}
+
+// Without 64-bit addressing the controller only reaches the first 4GiB
+fn reachable(cap: u32, addr: PhysAddr, len: usize) -> bool {
+    cap & CAP_S64A != 0 || addr.as_usize() + len <= 1 << 32
+}
+
+// Stops the port running commands and taking FISes, dropping whatever
+// commands it had
+fn stop(port: Registers) -> Result<(), Errno> {
+    port.write(P_CMD, port.read(P_CMD) & !CMD_ST);
+    spin_for(|| port.read(P_CMD) & CMD_CR == 0)?;
+    port.write(P_CMD, port.read(P_CMD) & !CMD_FRE);
+    spin_for(|| port.read(P_CMD) & CMD_FR == 0)
+}
+
+// Points the port at its memory and starts it again
+fn start(port: Registers, memory: PhysAddr) -> Result<(), Errno> {
+    stop(port)?;
+    port.write_addr(P_CLB, memory + COMMAND_LIST);
+    port.write_addr(P_FB, memory + RECEIVED_FIS);
+    port.write(P_SERR, !0);
+    port.write(P_IS, !0);
+    port.write(P_CMD, port.read(P_CMD) | CMD_FRE);
+
+    spin_for(|| port.read(P_TFD) as u8 & (STATUS_BSY | STATUS_DRQ) == 0)?;
+    port.write(P_CMD, port.read(P_CMD) | CMD_ST);
+    Ok(())
+}
+
+struct Inflight {
+    memory: DmaBuffer,
+    requests: Vec<Option<Arc<Request>>>,
+    // Whether the requests are queued commands
+    queued: bool,
+    // Set when the port didn't come back after an error
+    failed: bool,
+}
+
+impl Inflight {
+    fn free_slot(&self, queued: bool) -> Option<usize> {
+        let busy = self.requests.iter().any(Option::is_some);
+        if busy && self.queued != queued {
+            return None;
+        }
+        self.requests.iter().position(Option::is_none)
+    }
+
+    // Fills in the slot's command header and table, with the data if there
+    // is any
+    fn prepare(&self, slot: usize, fis: &[u8; 20], data: Option<(PhysAddr, usize)>, write: bool) {
+        let table = TABLES + slot * TABLE_SIZE;
+        let header = self.memory.as_mut_ptr::<u32>(COMMAND_LIST + slot * HEADER_SIZE);
+        let prd_count = if data.is_some() { 1 } else { 0 };
+        let table_addr = (self.memory.phys() + table).as_usize();
+        unsafe {
+            let flags = (fis.len() / 4) as u32 | if write { 1 << 6 } else { 0 };
+            ptr::write_volatile(header, flags | prd_count << 16);
+            ptr::write_volatile(header.add(1), 0);
+            ptr::write_volatile(header.add(2), table_addr as u32);
+            ptr::write_volatile(header.add(3), (table_addr >> 32) as u32);
+
+            for (i, &byte) in fis.iter().enumerate() {
+                ptr::write_volatile(self.memory.as_mut_ptr::<u8>(table + i), byte);
+            }
+            if let Some((addr, len)) = data {
+                let prd = self.memory.as_mut_ptr::<u32>(table + PRDT);
+                ptr::write_volatile(prd, addr.as_usize() as u32);
+                ptr::write_volatile(prd.add(1), (addr.as_usize() >> 32) as u32);
+                ptr::write_volatile(prd.add(2), 0);
+                ptr::write_volatile(prd.add(3), (len - 1) as u32);
+            }
+        }
+    }
+}
+
+pub struct AhciDisk {
+    name: String,
+    port: Registers,
+    cap: u32,
+    identity: Identity,
+    ncq: bool,
+    inflight: SpinLock<Inflight>,
+    // The controller's interrupt, kept until its last disk goes
+    irq: Option<Arc<Irq>>,
+}
+
+impl AhciDisk {
+    // Completes every command the port has finished. An error stops the
+    // port, and everything outstanding on it fails.
+    fn complete(&self, inflight: &mut Inflight) {
+        let status = self.port.read(P_IS);
+        self.port.write(P_IS, status);
+
+        if status & IS_ERRORS != 0 {
+            warn!(
+                "ahci: {} failed a command, task file {:#x}",
+                self.name,
+                self.port.read(P_TFD)
+            );
+            for request in inflight.requests.iter_mut().filter_map(Option::take) {
+                request.complete(Err(Errno::EIO));
+            }
+            if start(self.port, inflight.memory.phys()).is_err() {
+                warn!("ahci: {} didn't restart", self.name);
+                inflight.failed = true;
+            }
+            return;
+        }
+
+        let active = self.port.read(P_CI) | self.port.read(P_SACT);
+        for (slot, entry) in inflight.requests.iter_mut().enumerate() {
+            if active & (1 << slot) == 0 {
+                if let Some(request) = entry.take() {
+                    request.complete(Ok(()));
+                }
+            }
+        }
+    }
+
+    fn interrupt(&self) {
+        self.complete(&mut self.inflight.lock());
+    }
+
+    // Puts the request's command in a free slot and starts it
+    fn issue(
+        &self,
+        inflight: &mut Inflight,
+        slot: usize,
+        request: &Arc<Request>,
+        command: u8,
+        queued: bool,
+        data: Option<(PhysAddr, usize)>,
+    ) {
+        let count = (request.buffer.len() / SECTOR_SIZE) as u16;
+        let fis = if queued {
+            command_fis(command, request.sector, (slot as u16) << 3, count, DEVICE_LBA)
+        } else if self.identity.lba48 || request.op == Op::Flush {
+            command_fis(command, request.sector, count, 0, DEVICE_LBA)
+        } else {
+            // 28-bit commands have the top of the LBA in the device register
+            let top = (request.sector >> 24) as u8 & 0xF;
+            command_fis(command, request.sector, count, 0, DEVICE_LBA | top)
+        };
+        inflight.prepare(slot, &fis, data, request.op == Op::Write);
+        inflight.requests[slot] = Some(request.clone());
+        inflight.queued = queued;
+
+        if queued {
+            self.port.write(P_SACT, 1 << slot);
+        }
+        self.port.write(P_CI, 1 << slot);
+    }
+}
+
+impl BlockDevice for AhciDisk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sectors(&self) -> u64 {
+        self.identity.sectors
+    }
+
+    fn submit(&self, request: Arc<Request>) -> Result<(), Errno> {
+        let queued = self.ncq && request.op != Op::Flush;
+        let command = match request.op {
+            Op::Flush if !self.identity.can_flush => {
+                request.complete(Ok(()));
+                return Ok(());
+            }
+            Op::Flush => self.identity.flush_command(),
+            Op::Read if queued => READ_FPDMA_QUEUED,
+            Op::Write if queued => WRITE_FPDMA_QUEUED,
+            Op::Read if self.identity.lba48 => READ_DMA_EXT,
+            Op::Read => READ_DMA,
+            Op::Write if self.identity.lba48 => WRITE_DMA_EXT,
+            Op::Write => WRITE_DMA,
+        };
+        let data = match request.op {
+            Op::Flush => None,
+            _ => {
+                check(&request, self.sectors(), SECTOR_SIZE)?;
+                if request.buffer.len() / SECTOR_SIZE > max_sectors(&self.identity) {
+                    return Err(Errno::EINVAL);
+                }
+                if !reachable(self.cap, request.buffer.phys(), request.buffer.len()) {
+                    return Err(Errno::ENOMEM);
+                }
+                Some((request.buffer.phys(), request.buffer.len()))
+            }
+        };
+
+        loop {
+            let issued = interrupts::without_interrupts(|| {
+                let mut inflight = self.inflight.lock();
+                if inflight.failed {
+                    return Err(Errno::EIO);
+                }
+                match inflight.free_slot(queued) {
+                    Some(slot) => {
+                        self.issue(&mut inflight, slot, &request, command, queued, data);
+                        Ok(true)
+                    }
+                    None => Ok(false),
+                }
+            })?;
+            if issued {
+                return Ok(());
+            }
+
+            // Wait for the disk to finish something
+            self.poll();
+            task::yield_now();
+        }
+    }
+
+    fn poll(&self) {
+        interrupts::without_interrupts(|| self.complete(&mut self.inflight.lock()));
+    }
+
+    fn has_interrupt(&self) -> bool {
+        self.irq.is_some()
+    }
+}
+
+fn probe(device: &Arc<Device>) -> Result<(), Errno> {
+    let (addr, size) = match device.bars[5] {
+        Some(Bar::Memory { addr, size, .. }) => (addr, size),
+        _ => return Err(Errno::ENODEV),
+    };
+    device.update_command(COMMAND_MEMORY | COMMAND_BUS_MASTER, 0);
+    let hba = Registers(mmio::map(PhysAddr::new(addr as usize), size as usize).map_err(|_| Errno::ENOMEM)?);
+
+    // Firmware that still uses the controller gets asked to let go of it
+    if hba.read(CAP2) & CAP2_BOH != 0 {
+        hba.write(BOHC, hba.read(BOHC) | BOHC_OOS);
+        if wait_for(|| hba.read(BOHC) & BOHC_BOS == 0).is_err() {
+            warn!("ahci: firmware didn't hand over {}", device.address);
+        }
+    }
+    hba.write(GHC, hba.read(GHC) | GHC_AE);
+
+    // Disks are added to what the handler looks at as they're found
+    let ports: Arc<SpinLock<Vec<(usize, Weak<AhciDisk>)>>> = Arc::new(SpinLock::new(Vec::new()));
+    let handled = ports.clone();
+    let handler = Arc::new(move || {
+        let pending = hba.read(IS);
+        for (i, disk) in handled.lock().iter() {
+            if pending & (1 << i) != 0 {
+                if let Some(disk) = disk.upgrade() {
+                    disk.interrupt();
+                }
+            }
+        }
+        hba.write(IS, pending);
+    });
+    let irq = match pci::request(device, handler) {
+        Ok(irq) => Some(Arc::new(irq)),
+        Err(errno) => {
+            warn!("ahci: no interrupt for {} ({:?}), polling only", device.address, errno);
+            None
+        }
+    };
+
+    let implemented = hba.read(PI);
+    let mut disks = Vec::new();
+    for i in (0..32).filter(|i| implemented & (1 << i) != 0) {
+        match add_port(hba, i, irq.clone()) {
+            Ok(Some(disk)) => {
+                // The handler may already run for another device on a shared pin
+                interrupts::without_interrupts(|| ports.lock().push((i, Arc::downgrade(&disk))));
+                disks.push(disk);
+            }
+            Ok(None) => {}
+            Err(errno) => warn!("ahci: port {} of {} failed ({:?})", i, device.address, errno),
+        }
+    }
+    if disks.is_empty() {
+        return Err(Errno::ENODEV);
+    }
+
+    if irq.is_some() {
+        for disk in &disks {
+            disk.port.write(P_IE, IS_DHRS | IS_SDBS | IS_ERRORS);
+        }
+        hba.write(GHC, hba.read(GHC) | GHC_IE);
+    }
+    for disk in disks {
+        block::register(disk);
+    }
+    Ok(())
+}
+
+// Starts a port and identifies its disk. None if there's no disk on it.
+fn add_port(hba: Registers, i: usize, irq: Option<Arc<Irq>>) -> Result<Option<Arc<AhciDisk>>, Errno> {
+    let cap = hba.read(CAP);
+    let port = hba.port(i);
+    if cap & CAP_SSS != 0 {
+        port.write(P_CMD, port.read(P_CMD) | CMD_SUD | CMD_POD);
+    }
+    if port.read(P_SSTS) & 0xF0F != SSTS_PRESENT {
+        return Ok(None);
+    }
+    let signature = port.read(P_SIG);
+    if signature != SIG_ATA {
+        info!("ahci: ignoring port {} with signature {:#x}", i, signature);
+        return Ok(None);
+    }
+
+    let memory = DmaBuffer::new(TABLES + MAX_SLOTS * TABLE_SIZE)?;
+    let buffer = DmaBuffer::new(SECTOR_SIZE)?;
+    if !reachable(cap, memory.phys(), memory.len()) || !reachable(cap, buffer.phys(), buffer.len()) {
+        return Err(Errno::ENOMEM);
+    }
+    let slots = ((cap >> 8) & 0x1F) as usize + 1;
+    let mut inflight = Inflight {
+        memory,
+        requests: (0..slots).map(|_| None).collect(),
+        queued: false,
+        failed: false,
+    };
+
+    // The port can't be left running on memory that's about to be freed
+    let identity = match start(port, inflight.memory.phys()).and_then(|()| identify(port, &inflight, &buffer)) {
+        Ok(identity) => identity,
+        Err(errno) => {
+            let _ = stop(port);
+            return Err(errno);
+        }
+    };
+
+    // Queued commands only go in as many slots as the disk has tags for
+    let ncq = cap & CAP_SNCQ != 0 && identity.queue_depth > 0;
+    if ncq {
+        inflight.requests.truncate(identity.queue_depth);
+    }
+
+    let name = block::next_name("sd");
+    info!(
+        "ahci: {} is {} on port {}, {} slots{}",
+        name,
+        identity.model,
+        i,
+        inflight.requests.len(),
+        if ncq { " with ncq" } else { "" }
+    );
+    Ok(Some(Arc::new(AhciDisk {
+        name,
+        port,
+        cap,
+        identity,
+        ncq,
+        inflight: SpinLock::new(inflight),
+        irq,
+    })))
+}
+
+// Asks the disk about itself, through the first slot
+fn identify(port: Registers, inflight: &Inflight, buffer: &DmaBuffer) -> Result<Identity, Errno> {
+    let fis = command_fis(IDENTIFY, 0, 0, 0, 0);
+    inflight.prepare(0, &fis, Some((buffer.phys(), buffer.len())), false);
+    port.write(P_CI, 1);
+    wait_for(|| port.read(P_CI) & 1 == 0 || port.read(P_IS) & IS_ERRORS != 0)?;
+    if port.read(P_IS) & IS_ERRORS != 0 {
+        return Err(Errno::EIO);
+    }
+    port.write(P_IS, !0);
+    Identity::parse(buffer.as_slice()).ok_or(Errno::ENODEV)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::block::testing;
+
+    // Against the scratch disk the test-args in Cargo.toml add beside the
+    // disk QEMU boots from
+    test_case!(read_write_disk, {
+        let disk = testing::scratch_disk("sd");
+        assert!(disk.has_interrupt());
+        testing::read_write(&disk, 8 * SECTOR_SIZE);
+    });
+
+    test_case!(transfer_limits, {
+        let mut identity = Identity {
+            model: String::new(),
+            sectors: 0,
+            lba48: false,
+            can_flush: false,
+            queue_depth: 0,
+        };
+        assert_eq!(max_sectors(&identity), 256);
+        identity.lba48 = true;
+        assert_eq!(max_sectors(&identity) * SECTOR_SIZE, MAX_PRD_BYTES);
+    });
+
+    test_case!(command_fis_layout, {
+        let fis = command_fis(READ_FPDMA_QUEUED, 0x0605_0403_0201, 5 << 3, 0x0102, DEVICE_LBA);
+        assert_eq!(fis[..4], [FIS_H2D, 0x80, READ_FPDMA_QUEUED, 0x02]);
+        assert_eq!(fis[4..12], [0x01, 0x02, 0x03, DEVICE_LBA, 0x04, 0x05, 0x06, 0x01]);
+        assert_eq!(fis[12..], [5 << 3, 0, 0, 0, 0, 0, 0, 0]);
+    });
+}
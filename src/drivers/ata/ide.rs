@@ -0,0 +1,484 @@
+// Legacy IDE controllers. Each has two channels of up to two drives, and the
+// CPU moves every word through a channel's data port, so requests are done by
+// the time they've been submitted. Drive interrupts are left off.
+//
+// ATAPI drives, which are mostly CD-ROMs, take SCSI commands wrapped in a
+// PACKET command instead. Only reading them is supported.
+use super::{
+    check,
+    Identity,
+    DEVICE_LBA,
+    IDENTIFY,
+    IDENTIFY_PACKET,
+    PACKET,
+    READ_SECTORS,
+    READ_SECTORS_EXT,
+    STATUS_BSY,
+    STATUS_DF,
+    STATUS_DRQ,
+    STATUS_ERR,
+    WRITE_SECTORS,
+    WRITE_SECTORS_EXT,
+};
+use crate::{
+    drivers::{
+        block::{self, wait_for, BlockDevice, Op, Request, SECTOR_SIZE},
+        pci::{device::COMMAND_IO, Bar, Device, Driver, Match},
+    },
+    ds::Mutex,
+    syscall::Errno,
+};
+use alloc::{string::String, sync::Arc};
+use core::{cmp, slice};
+use x86_64::instructions::port::{PortRead, PortWrite};
+
+// Command block registers
+const DATA: u16 = 0;
+const FEATURES: u16 = 1;
+const COUNT: u16 = 2;
+const LBA_LOW: u16 = 3;
+const LBA_MID: u16 = 4;
+const LBA_HIGH: u16 = 5;
+const DRIVE: u16 = 6;
+const COMMAND: u16 = 7;
+const STATUS: u16 = 7;
+
+// The control block register, at the third port of the block. Reading it
+// gives the status without acknowledging anything.
+const CONTROL: u16 = 2;
+const CONTROL_NIEN: u8 = 1 << 1;
+
+// Ports of channels in compatibility mode, where they ignore their BARs
+const LEGACY: [(u16, u16); 2] = [(0x1F0, 0x3F4), (0x170, 0x374)];
+
+// What a packet device leaves in the LBA registers when it aborts IDENTIFY
+const ATAPI_SIGNATURE: (u8, u8) = (0x14, 0xEB);
+
+const SCSI_READ_CAPACITY: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+
+pub static DRIVER: Driver = Driver {
+    name: "ide",
+    matches: &[Match::Class {
+        class: 0x01,
+        subclass: 0x01,
+        prog_if: None,
+    }],
+    probe,
+};
+
+struct Channel {
+    base: u16,
+    control: u16,
+}
+
+impl Channel {
+    fn read(&self, register: u16) -> u8 {
+        unsafe { PortRead::read_from_port(self.base + register) }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { PortWrite::write_to_port(self.base + register, value) }
+    }
+
+    fn alt_status(&self) -> u8 {
+        unsafe { PortRead::read_from_port(self.control + CONTROL) }
+    }
+
+    // Selects a drive, along with the top four bits of a 28-bit LBA. The
+    // drive takes 400ns to show its status, which four reads cover.
+    fn select(&self, drive: u8, lba_top: u8) {
+        self.write(DRIVE, 0xA0 | DEVICE_LBA | drive << 4 | lba_top);
+        unsafe { PortWrite::write_to_port(self.control + CONTROL, CONTROL_NIEN) };
+        for _ in 0..4 {
+            self.alt_status();
+        }
+    }
+
+    // Waits for the drive to stop being busy, returning its status
+    fn wait_idle(&self) -> Result<u8, Errno> {
+        wait_for(|| self.alt_status() & STATUS_BSY == 0)?;
+        Ok(self.read(STATUS))
+    }
+
+    // Waits for the drive to have data for us, or want some
+    fn wait_data(&self) -> Result<(), Errno> {
+        let status = self.wait_idle()?;
+        if status & (STATUS_ERR | STATUS_DF) != 0 || status & STATUS_DRQ == 0 {
+            return Err(Errno::EIO);
+        }
+        Ok(())
+    }
+
+    fn wait_done(&self) -> Result<(), Errno> {
+        if self.wait_idle()? & (STATUS_ERR | STATUS_DF) != 0 {
+            return Err(Errno::EIO);
+        }
+        Ok(())
+    }
+
+    fn read_data(&self, buf: &mut [u8]) {
+        for pair in buf.chunks_exact_mut(2) {
+            let word: u16 = unsafe { PortRead::read_from_port(self.base + DATA) };
+            pair.copy_from_slice(&word.to_le_bytes());
+        }
+    }
+
+    fn write_data(&self, buf: &[u8]) {
+        for pair in buf.chunks_exact(2) {
+            unsafe { PortWrite::write_to_port(self.base + DATA, u16::from_le_bytes([pair[0], pair[1]])) };
+        }
+    }
+
+    // What the drive says about itself, with the command that worked. None
+    // if there's no drive, or not one that speaks ATA or ATAPI.
+    fn identify(&self, drive: u8) -> Option<(u8, [u8; SECTOR_SIZE])> {
+        self.select(drive, 0);
+        for register in COUNT..=LBA_HIGH {
+            self.write(register, 0);
+        }
+        self.write(COMMAND, IDENTIFY);
+
+        // A channel without the drive reads as zero, one without any as all
+        // ones
+        let status = self.alt_status();
+        if status == 0 || status == 0xFF {
+            return None;
+        }
+        self.wait_idle().ok()?;
+
+        let command = if (self.read(LBA_MID), self.read(LBA_HIGH)) == ATAPI_SIGNATURE {
+            self.write(COMMAND, IDENTIFY_PACKET);
+            IDENTIFY_PACKET
+        } else {
+            IDENTIFY
+        };
+        self.wait_data().ok()?;
+        let mut data = [0; SECTOR_SIZE];
+        self.read_data(&mut data);
+        Some((command, data))
+    }
+
+    // Moves whole sectors, a sector for each time the drive asks. No more
+    // than the identity's max_sectors go in one command.
+    fn transfer(&self, drive: u8, identity: &Identity, op: Op, lba: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        let count = buf.len() / SECTOR_SIZE;
+        let command = match (op, identity.lba48) {
+            (Op::Write, true) => WRITE_SECTORS_EXT,
+            (Op::Write, false) => WRITE_SECTORS,
+            (_, true) => READ_SECTORS_EXT,
+            (_, false) => READ_SECTORS,
+        };
+
+        // The high bytes of 48-bit commands go first, through the same
+        // registers. A full count wraps to zero, which is what it's given as.
+        if identity.lba48 {
+            self.select(drive, 0);
+            self.write(COUNT, (count >> 8) as u8);
+            self.write(LBA_LOW, (lba >> 24) as u8);
+            self.write(LBA_MID, (lba >> 32) as u8);
+            self.write(LBA_HIGH, (lba >> 40) as u8);
+        } else {
+            self.select(drive, (lba >> 24) as u8 & 0xF);
+        }
+        self.write(COUNT, count as u8);
+        self.write(LBA_LOW, lba as u8);
+        self.write(LBA_MID, (lba >> 8) as u8);
+        self.write(LBA_HIGH, (lba >> 16) as u8);
+        self.write(COMMAND, command);
+
+        for sector in buf.chunks_exact_mut(SECTOR_SIZE) {
+            self.wait_data()?;
+            match op {
+                Op::Write => self.write_data(sector),
+                _ => self.read_data(sector),
+            }
+        }
+        self.wait_done()
+    }
+
+    // Runs a command without data, like a cache flush
+    fn command(&self, drive: u8, command: u8) -> Result<(), Errno> {
+        self.select(drive, 0);
+        self.write(COMMAND, command);
+        self.wait_done()
+    }
+
+    // Sends a SCSI command to a packet device, reading what comes back into
+    // buf. The drive says how much it has each time it asks.
+    fn packet(&self, drive: u8, cdb: &[u8; 12], buf: &mut [u8]) -> Result<(), Errno> {
+        let limit = cmp::min(buf.len(), 0xF800);
+        self.select(drive, 0);
+        self.write(FEATURES, 0);
+        self.write(LBA_MID, limit as u8);
+        self.write(LBA_HIGH, (limit >> 8) as u8);
+        self.write(COMMAND, PACKET);
+        self.wait_data()?;
+        self.write_data(cdb);
+
+        let mut done = 0;
+        loop {
+            let status = self.wait_idle()?;
+            if status & (STATUS_ERR | STATUS_DF) != 0 {
+                return Err(Errno::EIO);
+            }
+            if status & STATUS_DRQ == 0 {
+                break;
+            }
+
+            let len = usize::from(self.read(LBA_HIGH)) << 8 | usize::from(self.read(LBA_MID));
+            if len == 0 || len % 2 != 0 || done + len > buf.len() {
+                return Err(Errno::EIO);
+            }
+            self.read_data(&mut buf[done..done + len]);
+            done += len;
+        }
+
+        if done != buf.len() {
+            return Err(Errno::EIO);
+        }
+        Ok(())
+    }
+}
+
+// The request's data. The driver has it to itself until the request is
+// completed, and mustn't keep it past that.
+#[allow(clippy::mut_from_ref)]
+unsafe fn data(request: &Request) -> &mut [u8] {
+    slice::from_raw_parts_mut(request.buffer.as_mut_ptr(0), request.buffer.len())
+}
+
+pub struct AtaDisk {
+    name: String,
+    channel: Arc<Mutex<Channel>>,
+    drive: u8,
+    identity: Identity,
+}
+
+impl BlockDevice for AtaDisk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sectors(&self) -> u64 {
+        self.identity.sectors
+    }
+
+    fn submit(&self, request: Arc<Request>) -> Result<(), Errno> {
+        let result = match request.op {
+            Op::Flush if !self.identity.can_flush => Ok(()),
+            Op::Flush => self.channel.lock().command(self.drive, self.identity.flush_command()),
+            op => {
+                check(&request, self.sectors(), SECTOR_SIZE)?;
+                // Longer requests than a command moves take several
+                let per_command = self.identity.max_sectors();
+                let channel = self.channel.lock();
+                unsafe { data(&request) }
+                    .chunks_mut(per_command * SECTOR_SIZE)
+                    .enumerate()
+                    .try_for_each(|(i, chunk)| {
+                        let sector = request.sector + (i * per_command) as u64;
+                        channel.transfer(self.drive, &self.identity, op, sector, chunk)
+                    })
+            }
+        };
+        request.complete(result);
+        Ok(())
+    }
+
+    fn poll(&self) {}
+}
+
+pub struct AtapiDrive {
+    name: String,
+    channel: Arc<Mutex<Channel>>,
+    drive: u8,
+    sectors: u64,
+    sector_size: usize,
+}
+
+impl BlockDevice for AtapiDrive {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn is_read_only(&self) -> bool {
+        true
+    }
+
+    fn submit(&self, request: Arc<Request>) -> Result<(), Errno> {
+        let result = match request.op {
+            Op::Read => {
+                check(&request, self.sectors, self.sector_size)?;
+                // READ (10) has 16 bits for the count
+                let count = request.buffer.len() / self.sector_size;
+                if count > usize::from(u16::MAX) {
+                    return Err(Errno::EINVAL);
+                }
+                let lba = (request.sector as u32).to_be_bytes();
+                let count = (count as u16).to_be_bytes();
+                let cdb = [
+                    SCSI_READ_10,
+                    0,
+                    lba[0],
+                    lba[1],
+                    lba[2],
+                    lba[3],
+                    0,
+                    count[0],
+                    count[1],
+                    0,
+                    0,
+                    0,
+                ];
+                self.channel.lock().packet(self.drive, &cdb, unsafe { data(&request) })
+            }
+            Op::Write => return Err(Errno::EROFS),
+            // Nothing is ever written
+            Op::Flush => Ok(()),
+        };
+        request.complete(result);
+        Ok(())
+    }
+
+    fn poll(&self) {}
+}
+
+fn probe(device: &Arc<Device>) -> Result<(), Errno> {
+    device.update_command(COMMAND_IO, 0);
+
+    let mut found = 0;
+    for (i, &legacy) in LEGACY.iter().enumerate() {
+        // Channels in native mode have their ports in a pair of BARs
+        let (base, control) = if device.prog_if & (1 << (2 * i)) == 0 {
+            legacy
+        } else {
+            match (device.bars[2 * i], device.bars[2 * i + 1]) {
+                (Some(Bar::Io { port: base, .. }), Some(Bar::Io { port: control, .. })) => {
+                    (base as u16, control as u16)
+                }
+                _ => continue,
+            }
+        };
+
+        let channel = Arc::new(Mutex::new(Channel { base, control }));
+        for drive in 0..2 {
+            if add_drive(&channel, drive) {
+                found += 1;
+            }
+        }
+    }
+
+    if found == 0 {
+        return Err(Errno::ENODEV);
+    }
+    Ok(())
+}
+
+fn add_drive(channel: &Arc<Mutex<Channel>>, drive: u8) -> bool {
+    // Registering reads the partition table, so the channel can't stay locked
+    let identified = channel.lock().identify(drive);
+    let (command, data) = match identified {
+        Some(identified) => identified,
+        None => return false,
+    };
+
+    if command == IDENTIFY {
+        let identity = match Identity::parse(&data) {
+            Some(identity) => identity,
+            None => {
+                warn!("ide: ignoring a drive without lba addressing");
+                return false;
+            }
+        };
+        let name = block::next_name("hd");
+        info!(
+            "ide: {} is {}{}",
+            name,
+            identity.model,
+            if identity.lba48 { ", lba48" } else { "" }
+        );
+        block::register(Arc::new(AtaDisk {
+            name,
+            channel: channel.clone(),
+            drive,
+            identity,
+        }));
+        return true;
+    }
+
+    // A disc that was just put in fails the first command with a unit
+    // attention, so have a few goes
+    let cdb = [SCSI_READ_CAPACITY, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    let mut capacity = [0; 8];
+    if !(0..3).any(|_| channel.lock().packet(drive, &cdb, &mut capacity).is_ok()) {
+        info!("ide: no medium in atapi drive {}", drive);
+        return false;
+    }
+    let last = u32::from_be_bytes([capacity[0], capacity[1], capacity[2], capacity[3]]);
+    let sector_size = u32::from_be_bytes([capacity[4], capacity[5], capacity[6], capacity[7]]) as usize;
+    if !sector_size.is_power_of_two() || sector_size < SECTOR_SIZE || sector_size > 4096 {
+        warn!("ide: atapi drive {} has {} byte sectors", drive, sector_size);
+        return false;
+    }
+
+    let name = block::next_name("cd");
+    info!("ide: {} is an atapi drive", name);
+    block::register(Arc::new(AtapiDrive {
+        name,
+        channel: channel.clone(),
+        drive,
+        sectors: u64::from(last) + 1,
+        sector_size,
+    }));
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{drivers::block::testing, mm::dma::DmaBuffer};
+    use alloc::vec;
+
+    // Against the scratch disk on the IDE controller the test-args in
+    // Cargo.toml add, with the scratch image also in the drive beside it
+    test_case!(read_write_disk, {
+        let disk = testing::scratch_disk("hd");
+        testing::read_write(&disk, 8 * SECTOR_SIZE);
+
+        // More sectors than the low byte of the count holds, straight through
+        // the driver
+        let count = 300;
+        let start = disk.sectors() - count;
+        let mut buffer = DmaBuffer::new(count as usize * SECTOR_SIZE).unwrap();
+        for (i, byte) in buffer.as_mut_slice().iter_mut().enumerate() {
+            *byte = (i % 253) as u8;
+        }
+        let data = buffer.as_slice().to_vec();
+        let write = Request::new(Op::Write, start, buffer);
+        disk.submit(write.clone()).unwrap();
+        disk.wait(&write).unwrap();
+        let read = Request::new(Op::Read, start, DmaBuffer::new(data.len()).unwrap());
+        disk.submit(read.clone()).unwrap();
+        disk.wait(&read).unwrap();
+        assert!(read.buffer.as_slice() == &data[..]);
+
+        let drive = block::devices()
+            .into_iter()
+            .find(|drive| drive.name().starts_with("cd"))
+            .unwrap();
+        assert_eq!(drive.sector_size(), 2048);
+        let mut sector = vec![0; drive.sector_size()];
+        drive.read(0, &mut sector).unwrap();
+        assert!(sector.starts_with(b"SOLSTICE-SCRATCH"));
+        assert_eq!(drive.write(0, &sector), Err(Errno::EROFS));
+    });
+}
@@ -0,0 +1,175 @@
+// ATA disks, on legacy IDE controllers through programmed I/O or on AHCI
+// controllers through DMA. Both describe their drives the same way, with the
+// 256 words IDENTIFY returns, and take the same commands.
+use super::{block::Request, pci};
+use crate::syscall::Errno;
+use alloc::string::String;
+
+pub mod ahci;
+pub mod ide;
+
+pub const READ_SECTORS: u8 = 0x20;
+pub const READ_SECTORS_EXT: u8 = 0x24;
+pub const WRITE_SECTORS: u8 = 0x30;
+pub const WRITE_SECTORS_EXT: u8 = 0x34;
+pub const READ_DMA: u8 = 0xC8;
+pub const READ_DMA_EXT: u8 = 0x25;
+pub const WRITE_DMA: u8 = 0xCA;
+pub const WRITE_DMA_EXT: u8 = 0x35;
+pub const READ_FPDMA_QUEUED: u8 = 0x60;
+pub const WRITE_FPDMA_QUEUED: u8 = 0x61;
+pub const FLUSH_CACHE: u8 = 0xE7;
+pub const FLUSH_CACHE_EXT: u8 = 0xEA;
+pub const PACKET: u8 = 0xA0;
+pub const IDENTIFY_PACKET: u8 = 0xA1;
+pub const IDENTIFY: u8 = 0xEC;
+
+// Status register bits
+pub const STATUS_ERR: u8 = 1 << 0;
+pub const STATUS_DRQ: u8 = 1 << 3;
+pub const STATUS_DF: u8 = 1 << 5;
+pub const STATUS_BSY: u8 = 1 << 7;
+
+// Set in the device register for LBA addressing
+pub const DEVICE_LBA: u8 = 1 << 6;
+
+pub struct Identity {
+    pub model: String,
+    pub sectors: u64,
+    pub lba48: bool,
+    pub can_flush: bool,
+    // How many commands the drive queues, 0 without native command queueing
+    pub queue_depth: usize,
+}
+
+impl Identity {
+    // Decodes what IDENTIFY returned, None for drives that only take
+    // cylinders, heads and sectors
+    pub fn parse(data: &[u8]) -> Option<Identity> {
+        let word = |i: usize| u16::from_le_bytes([data[2 * i], data[2 * i + 1]]);
+        if word(49) & (1 << 9) == 0 {
+            return None;
+        }
+
+        // Each word of the model holds two characters, the first in the high byte
+        let mut model = String::new();
+        for i in 27..47 {
+            let [high, low] = word(i).to_be_bytes();
+            model.push(high as char);
+            model.push(low as char);
+        }
+        model.truncate(model.trim_end().len());
+
+        let lba48 = word(83) & (1 << 10) != 0;
+        let sectors = if lba48 {
+            (100..104)
+                .rev()
+                .fold(0, |sectors, i| sectors << 16 | u64::from(word(i)))
+        } else {
+            u64::from(word(61)) << 16 | u64::from(word(60))
+        };
+        let queue_depth = if word(76) & (1 << 8) != 0 {
+            usize::from(word(75) & 0x1F) + 1
+        } else {
+            0
+        };
+
+        Some(Identity {
+            model,
+            sectors,
+            lba48,
+            can_flush: word(83) & (1 << 12) != 0,
+            queue_depth,
+        })
+    }
+
+    // The most sectors a read or write command moves, which its count gives
+    // as zero
+    pub fn max_sectors(&self) -> usize {
+        if self.lba48 {
+            0x10000
+        } else {
+            0x100
+        }
+    }
+
+    pub fn flush_command(&self) -> u8 {
+        if self.lba48 {
+            FLUSH_CACHE_EXT
+        } else {
+            FLUSH_CACHE
+        }
+    }
+}
+
+// Checks that a read or write is whole sectors within the drive
+pub fn check(request: &Request, sectors: u64, sector_size: usize) -> Result<(), Errno> {
+    let len = request.buffer.len();
+    let end = request
+        .sector
+        .checked_add((len / sector_size) as u64)
+        .ok_or(Errno::EINVAL)?;
+    if request.buffer.is_empty() || len % sector_size != 0 || end > sectors {
+        return Err(Errno::EINVAL);
+    }
+    Ok(())
+}
+
+pub fn init() {
+    pci::register(&ide::DRIVER);
+    pci::register(&ahci::DRIVER);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        drivers::block::{Op, SECTOR_SIZE},
+        mm::dma::DmaBuffer,
+    };
+    use alloc::vec;
+
+    fn set(data: &mut [u8], i: usize, value: u16) {
+        data[2 * i..2 * i + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    test_case!(identify, {
+        let mut data = vec![0; SECTOR_SIZE];
+        for (i, pair) in b"QEMU HARDDISK   ".chunks(2).enumerate() {
+            set(&mut data, 27 + i, u16::from_be_bytes([pair[0], pair[1]]));
+        }
+        for i in 35..47 {
+            set(&mut data, i, 0x2020);
+        }
+        set(&mut data, 60, 0x5678);
+        set(&mut data, 61, 0x0234);
+        set(&mut data, 75, 31);
+        set(&mut data, 76, 1 << 8);
+        assert!(Identity::parse(&data).is_none());
+
+        set(&mut data, 49, 1 << 9);
+        let identity = Identity::parse(&data).unwrap();
+        assert_eq!(identity.model, "QEMU HARDDISK");
+        assert_eq!(
+            (identity.sectors, identity.lba48, identity.can_flush),
+            (0x0234_5678, false, false)
+        );
+        assert_eq!((identity.queue_depth, identity.flush_command()), (32, FLUSH_CACHE));
+        assert_eq!(identity.max_sectors(), 256);
+
+        // 48-bit drives give their size in words 100 to 103
+        set(&mut data, 83, 1 << 10 | 1 << 12);
+        set(&mut data, 100, 0x2222);
+        set(&mut data, 102, 0x0001);
+        set(&mut data, 76, 0);
+        let identity = Identity::parse(&data).unwrap();
+        assert_eq!((identity.sectors, identity.can_flush), (0x0001_0000_2222, true));
+        assert_eq!((identity.queue_depth, identity.flush_command()), (0, FLUSH_CACHE_EXT));
+        assert_eq!(identity.max_sectors(), 65536);
+
+        let request = Request::new(Op::Read, 6, DmaBuffer::new(2 * SECTOR_SIZE).unwrap());
+        assert_eq!(check(&request, 8, SECTOR_SIZE), Ok(()));
+        assert_eq!(check(&request, 7, SECTOR_SIZE), Err(Errno::EINVAL));
+        assert_eq!(check(&request, 8, 2048), Err(Errno::EINVAL));
+    });
+}
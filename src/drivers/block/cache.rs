@@ -0,0 +1,443 @@
+// The buffer cache. Devices are cached a page at a time, and writes stay in
+// the cache until their page is evicted or synced. Once the cache is full the
+// least recently used page goes first. Runs of consecutive pages are read and
+// written back with a single request when they fit in one.
+use super::{BlockDevice, Op, Request, MAX_TRANSFER};
+use crate::{
+    ds::SpinLock,
+    mm::{dma::DmaBuffer, PAGE_SIZE},
+    syscall::Errno,
+};
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec, vec::Vec};
+use core::cmp;
+
+const PAGE: usize = PAGE_SIZE as usize;
+const MAX_RUN: u64 = (MAX_TRANSFER / PAGE) as u64;
+
+// 4MiB
+const DEFAULT_CAPACITY: usize = 1024;
+
+// Device ID and page index
+type Key = (usize, u64);
+
+struct Page {
+    data: Box<[u8]>,
+    dirty: bool,
+    used: u64,
+}
+
+struct Inner {
+    capacity: usize,
+    tick: u64,
+    pages: BTreeMap<Key, Page>,
+    // Pages by when they were last used
+    lru: BTreeMap<u64, Key>,
+    // Dirty pages that were evicted and are on their way to the device, by
+    // when they were evicted. Reads still find them here meanwhile.
+    writeback: BTreeMap<Key, (u64, Box<[u8]>)>,
+    devices: BTreeMap<usize, Arc<dyn BlockDevice>>,
+}
+
+impl Inner {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn touch(&mut self, key: Key) -> Option<&mut Page> {
+        let tick = self.next_tick();
+        let Inner { pages, lru, .. } = self;
+        let page = pages.get_mut(&key)?;
+        lru.remove(&page.used);
+        page.used = tick;
+        lru.insert(tick, key);
+        Some(page)
+    }
+
+    fn contains(&self, key: Key) -> bool {
+        self.pages.contains_key(&key) || self.writeback.contains_key(&key)
+    }
+
+    fn lookup(&mut self, key: Key) -> Option<&[u8]> {
+        if self.pages.contains_key(&key) {
+            return self.touch(key).map(|page| &page.data[..]);
+        }
+        self.writeback.get(&key).map(|(_, data)| &data[..])
+    }
+
+    // Adds a page, returning the dirty pages that had to make room for it
+    fn insert(&mut self, key: Key, data: Box<[u8]>, dirty: bool) -> Vec<(Key, u64)> {
+        let used = self.next_tick();
+        self.pages.insert(key, Page { data, dirty, used });
+        self.lru.insert(used, key);
+
+        let mut evicted = Vec::new();
+        while self.pages.len() > self.capacity {
+            let (&tick, &victim) = self.lru.iter().next().unwrap();
+            self.lru.remove(&tick);
+            let page = self.pages.remove(&victim).unwrap();
+            if page.dirty {
+                let tick = self.next_tick();
+                self.writeback.insert(victim, (tick, page.data));
+                evicted.push((victim, tick));
+            }
+        }
+        evicted
+    }
+}
+
+pub struct BufferCache {
+    inner: SpinLock<Inner>,
+}
+
+lazy_static! {
+    static ref CACHE: BufferCache = BufferCache::new(DEFAULT_CAPACITY);
+}
+
+// Bytes of the page that are on the device, less than a page only at the end
+fn page_len(device: &dyn BlockDevice, page: u64) -> usize {
+    cmp::min(PAGE as u64, device.size() - page * PAGE as u64) as usize
+}
+
+impl BufferCache {
+    pub fn new(capacity: usize) -> BufferCache {
+        BufferCache {
+            inner: SpinLock::new(Inner {
+                capacity: cmp::max(capacity, 1),
+                tick: 0,
+                pages: BTreeMap::new(),
+                lru: BTreeMap::new(),
+                writeback: BTreeMap::new(),
+                devices: BTreeMap::new(),
+            }),
+        }
+    }
+
+    fn check(device: &Arc<dyn BlockDevice>, offset: u64, len: usize) -> Result<(), Errno> {
+        let end = offset.checked_add(len as u64).ok_or(Errno::EINVAL)?;
+        if end > device.size() || PAGE % device.sector_size() != 0 {
+            return Err(Errno::EINVAL);
+        }
+        Ok(())
+    }
+
+    // Reads count pages from first on with one request. They're always whole
+    // pages, padded with zeros past the end of the device.
+    fn read_pages(device: &Arc<dyn BlockDevice>, first: u64, count: u64) -> Result<Vec<Box<[u8]>>, Errno> {
+        let lens: Vec<usize> = (first..first + count).map(|page| page_len(&**device, page)).collect();
+        let sector = first * (PAGE / device.sector_size()) as u64;
+        let request = Request::new(Op::Read, sector, DmaBuffer::new(lens.iter().sum())?);
+        device.submit(request.clone())?;
+        device.wait(&request)?;
+
+        let mut pages = Vec::with_capacity(lens.len());
+        for (i, &len) in lens.iter().enumerate() {
+            let mut data = vec![0; PAGE].into_boxed_slice();
+            data[..len].copy_from_slice(&request.buffer.as_slice()[i * PAGE..i * PAGE + len]);
+            pages.push(data);
+        }
+        Ok(pages)
+    }
+
+    // Copies the part of buf that falls in page between the two
+    fn copy(page: u64, data: &[u8], offset: u64, buf: &mut [u8]) {
+        let (start, end) = span(page, offset, buf.len());
+        let base = page * PAGE as u64;
+        buf[(start - offset) as usize..(end - offset) as usize]
+            .copy_from_slice(&data[(start - base) as usize..(end - base) as usize]);
+    }
+
+    pub fn read(&self, device: &Arc<dyn BlockDevice>, offset: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        Self::check(device, offset, buf.len())?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        let id = device.id();
+        let (first, last) = (offset / PAGE as u64, (offset + buf.len() as u64 - 1) / PAGE as u64);
+        let mut page = first;
+        while page <= last {
+            let count = {
+                let mut inner = self.inner.lock();
+                if let Some(data) = inner.lookup((id, page)) {
+                    Self::copy(page, data, offset, buf);
+                    page += 1;
+                    continue;
+                }
+
+                // Fetch the whole run of missing pages in one go
+                let mut count = 1;
+                while page + count <= last && count < MAX_RUN && !inner.contains((id, page + count)) {
+                    count += 1;
+                }
+                count
+            };
+
+            let pages = Self::read_pages(device, page, count)?;
+            let mut evicted = Vec::new();
+            {
+                let mut inner = self.inner.lock();
+                inner.devices.entry(id).or_insert_with(|| device.clone());
+                for (i, data) in pages.into_iter().enumerate() {
+                    let index = page + i as u64;
+                    // Someone else may have read or written it meanwhile
+                    match inner.lookup((id, index)) {
+                        Some(cached) => Self::copy(index, cached, offset, buf),
+                        None => {
+                            Self::copy(index, &data, offset, buf);
+                            evicted.extend(inner.insert((id, index), data, false));
+                        }
+                    }
+                }
+            }
+            self.write_back(evicted)?;
+            page += count;
+        }
+        Ok(())
+    }
+
+    pub fn write(&self, device: &Arc<dyn BlockDevice>, offset: u64, buf: &[u8]) -> Result<(), Errno> {
+        if device.is_read_only() {
+            return Err(Errno::EROFS);
+        }
+        Self::check(device, offset, buf.len())?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        let id = device.id();
+        let (first, last) = (offset / PAGE as u64, (offset + buf.len() as u64 - 1) / PAGE as u64);
+        for page in first..=last {
+            let (start, end) = span(page, offset, buf.len());
+            let base = page * PAGE as u64;
+            let whole = start == base && end == base + page_len(&**device, page) as u64;
+
+            // Partly written pages need the rest of their contents first
+            let fetched = if whole || self.inner.lock().contains((id, page)) {
+                None
+            } else {
+                Some(Self::read_pages(device, page, 1)?.pop().unwrap())
+            };
+
+            let evicted = {
+                let mut inner = self.inner.lock();
+                inner.devices.entry(id).or_insert_with(|| device.clone());
+                let src = &buf[(start - offset) as usize..(end - offset) as usize];
+                let range = (start - base) as usize..(end - base) as usize;
+
+                if let Some(cached) = inner.touch((id, page)) {
+                    cached.data[range].copy_from_slice(src);
+                    cached.dirty = true;
+                    Vec::new()
+                } else {
+                    let mut data = match inner.writeback.get(&(id, page)) {
+                        Some((_, data)) => data.clone(),
+                        None => fetched.unwrap_or_else(|| vec![0; PAGE].into_boxed_slice()),
+                    };
+                    data[range].copy_from_slice(src);
+                    inner.insert((id, page), data, true)
+                }
+            };
+            self.write_back(evicted)?;
+        }
+        Ok(())
+    }
+
+    // Writes evicted pages out, one by one
+    fn write_back(&self, evicted: Vec<(Key, u64)>) -> Result<(), Errno> {
+        let mut result = Ok(());
+        for (key, tick) in evicted {
+            let (device, request) = {
+                let inner = self.inner.lock();
+                let device = inner.devices[&key.0].clone();
+                let len = page_len(&*device, key.1);
+                let mut buffer = DmaBuffer::new(len)?;
+                buffer.as_mut_slice().copy_from_slice(&inner.writeback[&key].1[..len]);
+                let sector = key.1 * (PAGE / device.sector_size()) as u64;
+                (device, Request::new(Op::Write, sector, buffer))
+            };
+
+            let written = device.submit(request.clone()).and_then(|_| device.wait(&request));
+            if let Err(errno) = written {
+                error!("block: lost a page of {} writing it back: {:?}", device.name(), errno);
+                result = Err(errno);
+            }
+
+            let mut inner = self.inner.lock();
+            if inner.writeback.get(&key).map_or(false, |&(other, _)| other == tick) {
+                inner.writeback.remove(&key);
+            }
+        }
+        result
+    }
+
+    // Writes every dirty page of the device back and flushes it, merging
+    // consecutive pages into single requests
+    pub fn sync(&self, device: &Arc<dyn BlockDevice>) -> Result<(), Errno> {
+        let id = device.id();
+        let mut requests = Vec::new();
+        {
+            let mut inner = self.inner.lock();
+            let dirty: Vec<u64> = inner
+                .pages
+                .range((id, 0)..=(id, !0))
+                .filter(|(_, page)| page.dirty)
+                .map(|(&(_, index), _)| index)
+                .collect();
+
+            let mut i = 0;
+            while i < dirty.len() {
+                let first = dirty[i];
+                let mut count = 1;
+                while i + count < dirty.len() && dirty[i + count] == first + count as u64 && (count as u64) < MAX_RUN {
+                    count += 1;
+                }
+
+                let lens: Vec<usize> = (first..first + count as u64).map(|page| page_len(&**device, page)).collect();
+                let mut buffer = DmaBuffer::new(lens.iter().sum())?;
+                let mut used = Vec::with_capacity(count);
+                for (n, &len) in lens.iter().enumerate() {
+                    let page = inner.pages.get_mut(&(id, first + n as u64)).unwrap();
+                    buffer.as_mut_slice()[n * PAGE..n * PAGE + len].copy_from_slice(&page.data[..len]);
+                    used.push(page.used);
+                }
+
+                let sector = first * (PAGE / device.sector_size()) as u64;
+                requests.push((first, used, Request::new(Op::Write, sector, buffer)));
+                i += count;
+            }
+        }
+
+        // Everything goes to the device before waiting on any of it
+        let mut result = Ok(());
+        for (_, _, request) in &requests {
+            if let Err(errno) = device.submit(request.clone()) {
+                request.complete(Err(errno));
+            }
+        }
+        for (first, used, request) in &requests {
+            if let Err(errno) = device.wait(request) {
+                // Leave them dirty for the next sync to try again
+                result = Err(errno);
+                continue;
+            }
+
+            // Pages only become clean once they're on the device. Until then
+            // they can't be evicted without being written back, or read from
+            // the device while the write is still on its way. Pages used
+            // since may have been written again, so they stay dirty.
+            let mut inner = self.inner.lock();
+            for (n, &used) in used.iter().enumerate() {
+                if let Some(page) = inner.pages.get_mut(&(id, first + n as u64)) {
+                    if page.used == used {
+                        page.dirty = false;
+                    }
+                }
+            }
+        }
+
+        result?;
+        device.flush()
+    }
+
+    // Syncs and then forgets everything cached for the device
+    pub fn invalidate(&self, device: &Arc<dyn BlockDevice>) -> Result<(), Errno> {
+        self.sync(device)?;
+        let id = device.id();
+        let mut inner = self.inner.lock();
+        let keys: Vec<Key> = inner.pages.range((id, 0)..=(id, !0)).map(|(&key, _)| key).collect();
+        for key in keys {
+            let page = inner.pages.remove(&key).unwrap();
+            inner.lru.remove(&page.used);
+        }
+        inner.devices.remove(&id);
+        Ok(())
+    }
+}
+
+// Where the transfer of len bytes at offset overlaps the page
+fn span(page: u64, offset: u64, len: usize) -> (u64, u64) {
+    let base = page * PAGE as u64;
+    (cmp::max(offset, base), cmp::min(offset + len as u64, base + PAGE as u64))
+}
+
+pub fn read(device: &Arc<dyn BlockDevice>, offset: u64, buf: &mut [u8]) -> Result<(), Errno> {
+    CACHE.read(device, offset, buf)
+}
+
+pub fn write(device: &Arc<dyn BlockDevice>, offset: u64, buf: &[u8]) -> Result<(), Errno> {
+    CACHE.write(device, offset, buf)
+}
+
+pub fn sync(device: &Arc<dyn BlockDevice>) -> Result<(), Errno> {
+    CACHE.sync(device)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::testing::MemDisk, *};
+    use core::sync::atomic::Ordering;
+
+    test_case!(merging_and_hits, {
+        let disk = MemDisk::new(64);
+        for (i, byte) in disk.data.lock().iter_mut().enumerate() {
+            *byte = (i / 512) as u8;
+        }
+        let device: Arc<dyn BlockDevice> = disk.clone();
+        let cache = BufferCache::new(16);
+
+        // Three pages, fetched with one request
+        let mut buf = vec![0; 2 * PAGE + 10];
+        cache.read(&device, 100, &mut buf).unwrap();
+        assert_eq!(disk.requests.load(Ordering::Relaxed), 1);
+        assert_eq!((buf[0], buf[411], buf[412]), (0, 0, 1));
+        assert_eq!(buf[buf.len() - 1], ((100 + buf.len() - 1) / 512) as u8);
+
+        cache.read(&device, 0, &mut buf[..PAGE]).unwrap();
+        assert_eq!(disk.requests.load(Ordering::Relaxed), 1);
+
+        // Writes stay in the cache until synced
+        cache.write(&device, 10, &[0xAA; 4]).unwrap();
+        cache.write(&device, PAGE as u64, &[0xBB; PAGE]).unwrap();
+        assert_eq!(disk.requests.load(Ordering::Relaxed), 1);
+        assert_eq!(disk.data.lock()[10], 0);
+        cache.read(&device, 8, &mut buf[..4]).unwrap();
+        assert_eq!(&buf[..4], &[0, 0, 0xAA, 0xAA]);
+
+        // Both dirty pages go out together, then a flush
+        cache.sync(&device).unwrap();
+        assert_eq!(disk.requests.load(Ordering::Relaxed), 3);
+        assert_eq!(disk.data.lock()[13], 0xAA);
+        assert_eq!(disk.data.lock()[PAGE + 100], 0xBB);
+        cache.sync(&device).unwrap();
+        assert_eq!(disk.requests.load(Ordering::Relaxed), 4);
+
+        assert_eq!(cache.read(&device, device.size() - 1, &mut buf[..2]), Err(Errno::EINVAL));
+    });
+
+    test_case!(eviction_writes_back, {
+        let disk = MemDisk::new(64);
+        let device: Arc<dyn BlockDevice> = disk.clone();
+        let cache = BufferCache::new(2);
+
+        cache.write(&device, 0, &[1; PAGE]).unwrap();
+        cache.write(&device, PAGE as u64, &[2; PAGE]).unwrap();
+        assert_eq!(disk.requests.load(Ordering::Relaxed), 0);
+
+        // Touch the first page so the second is the oldest
+        let mut byte = [0];
+        cache.read(&device, 0, &mut byte).unwrap();
+        cache.write(&device, 2 * PAGE as u64, &[3; PAGE]).unwrap();
+        assert_eq!(disk.requests.load(Ordering::Relaxed), 1);
+        assert_eq!(disk.data.lock()[PAGE], 2);
+        assert_eq!(disk.data.lock()[0], 0);
+
+        cache.read(&device, PAGE as u64, &mut byte).unwrap();
+        assert_eq!(byte[0], 2);
+
+        cache.invalidate(&device).unwrap();
+        assert_eq!(disk.data.lock()[0], 1);
+        assert_eq!(disk.data.lock()[2 * PAGE], 3);
+        assert!(cache.inner.lock().pages.is_empty());
+    });
+}
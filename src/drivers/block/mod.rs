@@ -0,0 +1,418 @@
+// Block devices. Drivers take requests for whole sectors and complete them
+// later, from their interrupt handler or when polled. Whoever waits on a
+// request sleeps until the handler completes it, or for devices without an
+// interrupt, polls in between yielding to other threads.
+//
+// Filesystems go through the buffer cache rather than straight to a device,
+// and find their device by name, which for partitions is the disk's name and
+// the partition number.
+use crate::{
+    ds::SpinLock,
+    mm::dma::DmaBuffer,
+    syscall::Errno,
+    task::{self, Thread},
+    time,
+};
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::{cmp, sync::atomic::spin_loop_hint, time::Duration};
+use x86_64::instructions::interrupts;
+
+pub mod cache;
+pub mod partition;
+
+pub const SECTOR_SIZE: usize = 512;
+
+// The most a single request moves, larger transfers are split up
+pub const MAX_TRANSFER: usize = 64 * 1024;
+
+// How long to poll a device before giving up on it
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Read,
+    Write,
+    Flush,
+}
+
+pub struct Request {
+    pub op: Op,
+    pub sector: u64,
+    // Whole sectors. Empty for flushes.
+    pub buffer: Arc<DmaBuffer>,
+    // Both are locked from interrupt handlers, so only with interrupts off
+    result: SpinLock<Option<Result<(), Errno>>>,
+    // The thread sleeping until it completes
+    waiter: SpinLock<Option<Arc<Thread>>>,
+    // Completed along with this one, for requests passed down from a
+    // partition to its disk
+    parent: Option<Arc<Request>>,
+}
+
+impl Request {
+    pub fn new(op: Op, sector: u64, buffer: DmaBuffer) -> Arc<Request> {
+        Arc::new(Request {
+            op,
+            sector,
+            buffer: Arc::new(buffer),
+            result: SpinLock::new(None),
+            waiter: SpinLock::new(None),
+            parent: None,
+        })
+    }
+
+    // The same transfer at another sector, completing this one when done
+    pub fn forward(self: &Arc<Self>, sector: u64) -> Arc<Request> {
+        Arc::new(Request {
+            op: self.op,
+            sector,
+            buffer: self.buffer.clone(),
+            result: SpinLock::new(None),
+            waiter: SpinLock::new(None),
+            parent: Some(self.clone()),
+        })
+    }
+
+    // Called by the driver once the device is done with it
+    pub fn complete(&self, result: Result<(), Errno>) {
+        let waiter = interrupts::without_interrupts(|| {
+            *self.result.lock() = Some(result);
+            self.waiter.lock().take()
+        });
+        if let Some(waiter) = waiter {
+            task::wake(&waiter);
+        }
+        if let Some(parent) = &self.parent {
+            parent.complete(result);
+        }
+    }
+
+    pub fn result(&self) -> Option<Result<(), Errno>> {
+        interrupts::without_interrupts(|| *self.result.lock())
+    }
+
+    // Blocks until the driver completes it. Interrupts stay off from checking
+    // the result to blocking, so the handler can't complete it in between
+    // and leave nobody to wake us.
+    fn sleep(&self) -> Result<(), Errno> {
+        let thread = task::current();
+        interrupts::without_interrupts(|| loop {
+            if let Some(result) = *self.result.lock() {
+                return result;
+            }
+            *self.waiter.lock() = Some(thread.clone());
+            task::block();
+        })
+    }
+}
+
+// Polls until done says the device is ready, yielding in between
+pub fn wait_for(done: impl FnMut() -> bool) -> Result<(), Errno> {
+    poll_until(done, task::yield_now)
+}
+
+// The same for waiting with a SpinLock held, when nothing else may run
+pub fn spin_for(done: impl FnMut() -> bool) -> Result<(), Errno> {
+    poll_until(done, spin_loop_hint)
+}
+
+fn poll_until(mut done: impl FnMut() -> bool, between: fn()) -> Result<(), Errno> {
+    let deadline = time::now() + TIMEOUT;
+    while !done() {
+        if time::now() >= deadline {
+            return Err(Errno::EIO);
+        }
+        between();
+    }
+    Ok(())
+}
+
+pub trait BlockDevice: Send + Sync {
+    fn name(&self) -> &str;
+
+    // Size in sectors of sector_size bytes
+    fn sectors(&self) -> u64;
+
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    // Queues a request, which the driver completes some time later
+    fn submit(&self, request: Arc<Request>) -> Result<(), Errno>;
+
+    // Completes whatever requests the device has finished
+    fn poll(&self);
+
+    // Whether the device interrupts when it finishes requests, so waiting
+    // for them needn't poll
+    fn has_interrupt(&self) -> bool {
+        false
+    }
+}
+
+impl dyn BlockDevice {
+    // Identifies the device for as long as it's alive
+    pub fn id(&self) -> usize {
+        self as *const dyn BlockDevice as *const u8 as usize
+    }
+
+    pub fn size(&self) -> u64 {
+        self.sectors() * self.sector_size() as u64
+    }
+
+    pub fn wait(&self, request: &Request) -> Result<(), Errno> {
+        if self.has_interrupt() {
+            return request.sleep();
+        }
+
+        loop {
+            self.poll();
+            if let Some(result) = request.result() {
+                return result;
+            }
+            task::yield_now();
+        }
+    }
+
+    // Checks that len bytes from sector are whole sectors on the device
+    pub fn check(&self, sector: u64, len: usize) -> Result<(), Errno> {
+        if len % self.sector_size() != 0 {
+            return Err(Errno::EINVAL);
+        }
+        let end = sector.checked_add((len / self.sector_size()) as u64).ok_or(Errno::EINVAL)?;
+        if end > self.sectors() {
+            return Err(Errno::EINVAL);
+        }
+        Ok(())
+    }
+
+    // Reads whole sectors starting at sector into buf, bypassing the cache
+    pub fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        self.check(sector, buf.len())?;
+        let per_chunk = (MAX_TRANSFER / self.sector_size()) as u64;
+        for (i, chunk) in buf.chunks_mut(MAX_TRANSFER).enumerate() {
+            let request = Request::new(Op::Read, sector + i as u64 * per_chunk, DmaBuffer::new(chunk.len())?);
+            self.submit(request.clone())?;
+            self.wait(&request)?;
+            chunk.copy_from_slice(request.buffer.as_slice());
+        }
+        Ok(())
+    }
+
+    pub fn write(&self, sector: u64, buf: &[u8]) -> Result<(), Errno> {
+        if self.is_read_only() {
+            return Err(Errno::EROFS);
+        }
+        self.check(sector, buf.len())?;
+        let per_chunk = (MAX_TRANSFER / self.sector_size()) as u64;
+        for (i, chunk) in buf.chunks(MAX_TRANSFER).enumerate() {
+            let mut buffer = DmaBuffer::new(chunk.len())?;
+            buffer.as_mut_slice().copy_from_slice(chunk);
+            let request = Request::new(Op::Write, sector + i as u64 * per_chunk, buffer);
+            self.submit(request.clone())?;
+            self.wait(&request)?;
+        }
+        Ok(())
+    }
+
+    // Waits for written data to reach the medium
+    pub fn flush(&self) -> Result<(), Errno> {
+        let request = Request::new(Op::Flush, 0, DmaBuffer::new(0)?);
+        self.submit(request.clone())?;
+        self.wait(&request)
+    }
+}
+
+lazy_static! {
+    static ref DEVICES: SpinLock<Vec<Arc<dyn BlockDevice>>> = SpinLock::new(Vec::new());
+}
+
+// Adds a disk, along with whatever partitions it has
+pub fn register(device: Arc<dyn BlockDevice>) {
+    add(device.clone());
+    partition::scan(&device);
+}
+
+fn add(device: Arc<dyn BlockDevice>) {
+    info!(
+        "block: {} is {} sectors of {} bytes ({} MiB){}",
+        device.name(),
+        device.sectors(),
+        device.sector_size(),
+        device.size() >> 20,
+        if device.is_read_only() { ", read only" } else { "" }
+    );
+    DEVICES.lock().push(device);
+}
+
+pub fn devices() -> Vec<Arc<dyn BlockDevice>> {
+    DEVICES.lock().clone()
+}
+
+pub fn find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES.lock().iter().find(|device| device.name() == name).cloned()
+}
+
+// The next free name with the given prefix, like vda, vdb and so on
+pub fn next_name(prefix: &str) -> String {
+    let devices = DEVICES.lock();
+    let count = devices
+        .iter()
+        .filter(|device| {
+            device.name().len() == prefix.len() + 1 && device.name().starts_with(prefix)
+        })
+        .count();
+    let mut name = String::from(prefix);
+    name.push((b'a' + cmp::min(count, 25) as u8) as char);
+    name
+}
+
+// For tests of drivers, which run against scratch disks that
+// scripts/runner.sh makes and the test-args in Cargo.toml attach, and of what
+// sits on top of block devices
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+    use alloc::{collections::VecDeque, vec};
+    use core::{
+        ptr,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    // What the scratch disks start with, so they aren't mistaken for the disk
+    // the kernel booted from
+    const SCRATCH_MARKER: &[u8] = b"SOLSTICE-SCRATCH";
+
+    // The scratch disk of the driver whose names start with prefix
+    pub fn scratch_disk(prefix: &str) -> Arc<dyn BlockDevice> {
+        devices()
+            .into_iter()
+            .find(|disk| {
+                let mut first = vec![0; disk.sector_size()];
+                disk.name().starts_with(prefix)
+                    && disk.read(0, &mut first).is_ok()
+                    && first.starts_with(SCRATCH_MARKER)
+            })
+            .unwrap_or_else(|| panic!("block: no {} scratch disk", prefix))
+    }
+
+    // Writes len bytes at the end of the disk and reads them back, all at
+    // once and then a sector at a time with the requests in flight together
+    pub fn read_write(disk: &Arc<dyn BlockDevice>, len: usize) {
+        let sector_size = disk.sector_size();
+        let count = (len / sector_size) as u64;
+        assert!(disk.sectors() > count);
+        let start = disk.sectors() - count;
+
+        let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        disk.write(start, &data).unwrap();
+        disk.flush().unwrap();
+        let mut back = vec![0; len];
+        disk.read(start, &mut back).unwrap();
+        assert!(back == data);
+
+        let requests: Vec<Arc<Request>> = (0..count)
+            .map(|i| Request::new(Op::Read, start + i, DmaBuffer::new(sector_size).unwrap()))
+            .collect();
+        for request in &requests {
+            disk.submit(request.clone()).unwrap();
+        }
+        for (request, expected) in requests.iter().zip(data.chunks(sector_size)) {
+            disk.wait(request).unwrap();
+            assert!(request.buffer.as_slice() == expected);
+        }
+
+        assert_eq!(disk.read(disk.sectors(), &mut back[..sector_size]), Err(Errno::EINVAL));
+    }
+
+    // A disk in memory, completing requests on the poll after they were
+    // submitted
+    pub struct MemDisk {
+        pub data: SpinLock<Vec<u8>>,
+        pub requests: AtomicUsize,
+        pending: SpinLock<VecDeque<Arc<Request>>>,
+    }
+
+    impl MemDisk {
+        pub fn new(sectors: usize) -> Arc<MemDisk> {
+            Arc::new(MemDisk {
+                data: SpinLock::new(vec![0; sectors * SECTOR_SIZE]),
+                requests: AtomicUsize::new(0),
+                pending: SpinLock::new(VecDeque::new()),
+            })
+        }
+    }
+
+    impl BlockDevice for MemDisk {
+        fn name(&self) -> &str {
+            "mem"
+        }
+
+        fn sectors(&self) -> u64 {
+            (self.data.lock().len() / SECTOR_SIZE) as u64
+        }
+
+        fn submit(&self, request: Arc<Request>) -> Result<(), Errno> {
+            self.requests.fetch_add(1, Ordering::Relaxed);
+            self.pending.lock().push_back(request);
+            Ok(())
+        }
+
+        fn poll(&self) {
+            while let Some(request) = self.pending.lock().pop_front() {
+                let start = request.sector as usize * SECTOR_SIZE;
+                let len = request.buffer.len();
+                let mut data = self.data.lock();
+                let buffer = request.buffer.as_mut_ptr::<u8>(0);
+                unsafe {
+                    match request.op {
+                        Op::Read => ptr::copy_nonoverlapping(data[start..].as_ptr(), buffer, len),
+                        Op::Write => ptr::copy_nonoverlapping(buffer, data[start..].as_mut_ptr(), len),
+                        Op::Flush => {}
+                    }
+                }
+                request.complete(Ok(()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{testing::MemDisk, *};
+    use alloc::vec;
+
+    test_case!(split_transfers, {
+        let disk: Arc<dyn BlockDevice> = MemDisk::new(2 * MAX_TRANSFER / SECTOR_SIZE + 4);
+
+        let data: Vec<u8> = (0..MAX_TRANSFER + 2 * SECTOR_SIZE).map(|i| (i % 251) as u8).collect();
+        disk.write(1, &data).unwrap();
+        let mut back = vec![0; data.len()];
+        disk.read(1, &mut back).unwrap();
+        assert!(back == data);
+        disk.flush().unwrap();
+
+        let mut sector = [0; SECTOR_SIZE];
+        disk.read(0, &mut sector).unwrap();
+        assert!(sector.iter().all(|&byte| byte == 0));
+
+        assert_eq!(disk.read(0, &mut sector[..100]), Err(Errno::EINVAL));
+        assert_eq!(disk.read(disk.sectors(), &mut sector), Err(Errno::EINVAL));
+        assert_eq!(disk.write(!0, &sector), Err(Errno::EINVAL));
+    });
+
+    test_case!(forwarded_requests, {
+        let disk: Arc<dyn BlockDevice> = MemDisk::new(8);
+        let request = Request::new(Op::Read, 1, DmaBuffer::new(SECTOR_SIZE).unwrap());
+        let forwarded = request.forward(5);
+        assert!(Arc::ptr_eq(&request.buffer, &forwarded.buffer));
+
+        disk.submit(forwarded.clone()).unwrap();
+        disk.wait(&forwarded).unwrap();
+        assert_eq!(request.result(), Some(Ok(())));
+    });
+}
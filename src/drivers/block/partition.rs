@@ -0,0 +1,359 @@
+// Partition tables. Each partition of a disk with an MBR or GPT becomes a
+// device of its own, named after the disk with the partition number on the
+// end, and a 'p' in between when the disk's name ends in a digit. Partitions
+// pass requests on to their disk at an offset.
+use super::{BlockDevice, Op, Request};
+use crate::syscall::Errno;
+use alloc::{format, string::String, sync::Arc, vec, vec::Vec};
+use core::{mem, ptr};
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_ENTRIES: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_GPT_PROTECTIVE: u8 = 0xEE;
+const MBR_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+// Bounds the walk of a looping chain of extended boot records
+const MAX_LOGICAL: usize = 64;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_MIN: usize = 92;
+const GPT_ENTRY_MIN: usize = 128;
+const GPT_ENTRIES_MAX: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    // The system ID byte
+    Mbr(u8),
+    // The partition type GUID, as stored on disk
+    Gpt([u8; 16]),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry {
+    // From 1. Logical MBR partitions start at 5.
+    pub number: usize,
+    pub start: u64,
+    pub sectors: u64,
+    pub kind: Kind,
+}
+
+pub struct Partition {
+    name: String,
+    disk: Arc<dyn BlockDevice>,
+    entry: Entry,
+}
+
+impl Partition {
+    pub fn new(disk: &Arc<dyn BlockDevice>, entry: Entry) -> Partition {
+        let separator = if disk.name().ends_with(|c: char| c.is_ascii_digit()) { "p" } else { "" };
+        Partition {
+            name: format!("{}{}{}", disk.name(), separator, entry.number),
+            disk: disk.clone(),
+            entry,
+        }
+    }
+}
+
+impl BlockDevice for Partition {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sectors(&self) -> u64 {
+        self.entry.sectors
+    }
+
+    fn sector_size(&self) -> usize {
+        self.disk.sector_size()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.disk.is_read_only()
+    }
+
+    fn submit(&self, request: Arc<Request>) -> Result<(), Errno> {
+        if request.op == Op::Flush {
+            return self.disk.submit(request.forward(0));
+        }
+
+        let sectors = (request.buffer.len() / self.sector_size()) as u64;
+        if request.sector.checked_add(sectors).map_or(true, |end| end > self.entry.sectors) {
+            return Err(Errno::EINVAL);
+        }
+        self.disk.submit(request.forward(self.entry.start + request.sector))
+    }
+
+    fn poll(&self) {
+        self.disk.poll();
+    }
+
+    fn has_interrupt(&self) -> bool {
+        self.disk.has_interrupt()
+    }
+}
+
+// Registers the partitions of a newly found disk
+pub fn scan(disk: &Arc<dyn BlockDevice>) {
+    let entries = match parse(&**disk) {
+        Ok(entries) => entries,
+        Err(errno) => {
+            warn!("block: can't read partition table of {}: {:?}", disk.name(), errno);
+            return;
+        }
+    };
+
+    for entry in entries {
+        let partition = Partition::new(disk, entry);
+        debug!("block: {} is {:?} at sector {}", partition.name, entry.kind, entry.start);
+        super::add(Arc::new(partition));
+    }
+}
+
+fn read<T: Copy>(data: &[u8], offset: usize) -> T {
+    assert!(offset + mem::size_of::<T>() <= data.len());
+    unsafe { ptr::read_unaligned(data[offset..].as_ptr() as *const T) }
+}
+
+fn read_sectors(disk: &dyn BlockDevice, sector: u64, count: usize) -> Result<Vec<u8>, Errno> {
+    let mut buf = vec![0; count * disk.sector_size()];
+    disk.read(sector, &mut buf)?;
+    Ok(buf)
+}
+
+// Finds the partitions on a disk, none if it has no partition table
+pub fn parse(disk: &dyn BlockDevice) -> Result<Vec<Entry>, Errno> {
+    if disk.sectors() == 0 || disk.sector_size() < 512 {
+        return Ok(Vec::new());
+    }
+    let mbr = read_sectors(disk, 0, 1)?;
+    if mbr[510..512] != MBR_SIGNATURE {
+        return Ok(Vec::new());
+    }
+
+    let primary: Vec<(u8, u64, u64)> = (0..4).map(|i| mbr_entry(&mbr, i)).collect();
+    if primary.iter().any(|&(kind, _, _)| kind == MBR_GPT_PROTECTIVE) {
+        return parse_gpt(disk);
+    }
+
+    let mut entries = Vec::new();
+    for (i, &(kind, start, sectors)) in primary.iter().enumerate() {
+        if kind == 0 || sectors == 0 {
+            continue;
+        }
+        if MBR_EXTENDED.contains(&kind) {
+            parse_logical(disk, start, &mut entries)?;
+            continue;
+        }
+        entries.push(Entry {
+            number: i + 1,
+            start,
+            sectors,
+            kind: Kind::Mbr(kind),
+        });
+    }
+    Ok(checked(disk, entries))
+}
+
+// System ID, first sector and sector count of an MBR entry
+fn mbr_entry(sector: &[u8], index: usize) -> (u8, u64, u64) {
+    let offset = MBR_ENTRIES + index * MBR_ENTRY_SIZE;
+    (
+        sector[offset + 4],
+        u64::from(read::<u32>(sector, offset + 8)),
+        u64::from(read::<u32>(sector, offset + 12)),
+    )
+}
+
+// Logical partitions are a chain of extended boot records. The first entry of
+// each is the partition, relative to that record, and the second points to
+// the next record, relative to the start of the extended partition.
+fn parse_logical(disk: &dyn BlockDevice, extended: u64, entries: &mut Vec<Entry>) -> Result<(), Errno> {
+    let mut record = extended;
+    for number in 5..5 + MAX_LOGICAL {
+        if record >= disk.sectors() {
+            break;
+        }
+        let ebr = read_sectors(disk, record, 1)?;
+        if ebr[510..512] != MBR_SIGNATURE {
+            break;
+        }
+
+        let (kind, start, sectors) = mbr_entry(&ebr, 0);
+        if kind != 0 && sectors != 0 {
+            entries.push(Entry {
+                number,
+                start: record + start,
+                sectors,
+                kind: Kind::Mbr(kind),
+            });
+        }
+
+        let (next_kind, next, _) = mbr_entry(&ebr, 1);
+        if next_kind == 0 || next == 0 {
+            break;
+        }
+        record = extended + next;
+    }
+    Ok(())
+}
+
+fn parse_gpt(disk: &dyn BlockDevice) -> Result<Vec<Entry>, Errno> {
+    let mut header = read_sectors(disk, 1, 1)?;
+    if &header[..8] != GPT_SIGNATURE {
+        return Err(Errno::EINVAL);
+    }
+
+    let header_size = read::<u32>(&header, 12) as usize;
+    if header_size < GPT_HEADER_MIN || header_size > header.len() {
+        return Err(Errno::EINVAL);
+    }
+    let header_crc = read::<u32>(&header, 16);
+    header[16..20].copy_from_slice(&[0; 4]);
+    if crc32(&header[..header_size]) != header_crc {
+        return Err(Errno::EINVAL);
+    }
+
+    let entries_lba = read::<u64>(&header, 72);
+    let count = read::<u32>(&header, 80) as usize;
+    let entry_size = read::<u32>(&header, 84) as usize;
+    let entries_crc = read::<u32>(&header, 88);
+    let len = count.checked_mul(entry_size).ok_or(Errno::EINVAL)?;
+    if entry_size < GPT_ENTRY_MIN || entry_size % 8 != 0 || len > GPT_ENTRIES_MAX {
+        return Err(Errno::EINVAL);
+    }
+
+    let sector_size = disk.sector_size();
+    let table = read_sectors(disk, entries_lba, (len + sector_size - 1) / sector_size)?;
+    if crc32(&table[..len]) != entries_crc {
+        return Err(Errno::EINVAL);
+    }
+
+    let mut entries = Vec::new();
+    for i in 0..count {
+        let entry = &table[i * entry_size..(i + 1) * entry_size];
+        let kind: [u8; 16] = read(entry, 0);
+        if kind == [0; 16] {
+            continue;
+        }
+
+        let (first, last) = (read::<u64>(entry, 32), read::<u64>(entry, 40));
+        if last < first {
+            continue;
+        }
+        entries.push(Entry {
+            number: i + 1,
+            start: first,
+            sectors: last - first + 1,
+            kind: Kind::Gpt(kind),
+        });
+    }
+    Ok(checked(disk, entries))
+}
+
+// Drops partitions that don't fit on the disk
+fn checked(disk: &dyn BlockDevice, entries: Vec<Entry>) -> Vec<Entry> {
+    entries
+        .into_iter()
+        .filter(|entry| {
+            let fits = entry.start.checked_add(entry.sectors).map_or(false, |end| end <= disk.sectors());
+            if !fits {
+                warn!("block: partition {} of {} is past the end of the disk", entry.number, disk.name());
+            }
+            fits
+        })
+        .collect()
+}
+
+// The CRC-32 used by GPT, as in zlib and Ethernet
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (!(crc & 1)).wrapping_add(1));
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::testing::MemDisk, *};
+
+    fn set_mbr_entry(sector: &mut [u8], index: usize, kind: u8, start: u32, sectors: u32) {
+        let offset = MBR_ENTRIES + index * MBR_ENTRY_SIZE;
+        sector[offset + 4] = kind;
+        sector[offset + 8..offset + 12].copy_from_slice(&start.to_le_bytes());
+        sector[offset + 12..offset + 16].copy_from_slice(&sectors.to_le_bytes());
+        sector[510..512].copy_from_slice(&MBR_SIGNATURE);
+    }
+
+    test_case!(crc, {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    });
+
+    test_case!(mbr_with_logical_partitions, {
+        let disk = MemDisk::new(256);
+        {
+            let mut data = disk.data.lock();
+            set_mbr_entry(&mut data[..512], 0, 0x83, 8, 32);
+            set_mbr_entry(&mut data[..512], 1, 0x05, 64, 128);
+            set_mbr_entry(&mut data[..512], 3, 0x0C, 200, 100);
+            // Logical 5 at 64+2, next record at 64+40 holding logical 6
+            set_mbr_entry(&mut data[64 * 512..65 * 512], 0, 0x83, 2, 30);
+            set_mbr_entry(&mut data[64 * 512..65 * 512], 1, 0x05, 40, 50);
+            set_mbr_entry(&mut data[104 * 512..105 * 512], 0, 0x82, 1, 20);
+        }
+
+        let entries = parse(&*disk).unwrap();
+        let summary: Vec<(usize, u64, u64)> = entries.iter().map(|e| (e.number, e.start, e.sectors)).collect();
+        // The last primary partition runs off the end of the disk
+        assert_eq!(summary, vec![(1, 8, 32), (5, 66, 30), (6, 105, 20)]);
+        assert_eq!(entries[2].kind, Kind::Mbr(0x82));
+
+        let blank = MemDisk::new(8);
+        assert!(parse(&*blank).unwrap().is_empty());
+    });
+
+    test_case!(gpt_and_forwarding, {
+        let disk = MemDisk::new(128);
+        {
+            let mut data = disk.data.lock();
+            set_mbr_entry(&mut data[..512], 0, MBR_GPT_PROTECTIVE, 1, 127);
+
+            let linux = [0xAF, 0x3D, 0xC6, 0x0F, 0x83, 0x84, 0x72, 0x47, 0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4];
+            let table = &mut data[2 * 512..6 * 512];
+            table[128..144].copy_from_slice(&linux);
+            table[128 + 32..128 + 40].copy_from_slice(&40u64.to_le_bytes());
+            table[128 + 40..128 + 48].copy_from_slice(&79u64.to_le_bytes());
+            let entries_crc = crc32(table);
+
+            let header = &mut data[512..1024];
+            header[..8].copy_from_slice(GPT_SIGNATURE);
+            header[12..16].copy_from_slice(&92u32.to_le_bytes());
+            header[24..32].copy_from_slice(&1u64.to_le_bytes());
+            header[72..80].copy_from_slice(&2u64.to_le_bytes());
+            header[80..84].copy_from_slice(&16u32.to_le_bytes());
+            header[84..88].copy_from_slice(&128u32.to_le_bytes());
+            header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+            let header_crc = crc32(&header[..92]);
+            header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+        }
+
+        let device: Arc<dyn BlockDevice> = disk.clone();
+        let entries = parse(&*device).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!((entries[0].number, entries[0].start, entries[0].sectors), (2, 40, 40));
+
+        let partition: Arc<dyn BlockDevice> = Arc::new(Partition::new(&device, entries[0]));
+        assert_eq!(partition.name(), "mem2");
+        partition.write(1, &[0x5A; 512]).unwrap();
+        assert_eq!(disk.data.lock()[41 * 512], 0x5A);
+        assert_eq!(partition.write(40, &[0; 512]), Err(Errno::EINVAL));
+
+        // A bad checksum means no partitions rather than wrong ones
+        disk.data.lock()[2 * 512 + 128 + 32] = 41;
+        assert_eq!(parse(&*device), Err(Errno::EINVAL));
+    });
+}
@@ -0,0 +1,132 @@
+// The i8042 PS/2 controller. Its first port has the keyboard and its second,
+// which is left disabled, the mouse. Bytes to and from the keyboard go
+// through the data port, and the controller's own commands through the
+// command port, which reads back as its status.
+use crate::{syscall::Errno, time};
+use core::time::Duration;
+use x86_64::instructions::port::{PortRead, PortWrite};
+
+const DATA: u16 = 0x60;
+const STATUS: u16 = 0x64;
+const COMMAND: u16 = 0x64;
+
+// There's a byte to read, or the last one written hasn't been taken yet
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+// What there is to read came from the mouse
+const STATUS_AUX: u8 = 1 << 5;
+
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const DISABLE_AUX: u8 = 0xA7;
+const SELF_TEST: u8 = 0xAA;
+const TEST_PORT: u8 = 0xAB;
+const DISABLE_PORT: u8 = 0xAD;
+const ENABLE_PORT: u8 = 0xAE;
+#[cfg(test)]
+const WRITE_OUTPUT: u8 = 0xD2;
+
+pub const CONFIG_IRQ: u8 = 1 << 0;
+const CONFIG_AUX_IRQ: u8 = 1 << 1;
+// Turns what the keyboard sends from scancode set 2 into set 1
+pub const CONFIG_TRANSLATE: u8 = 1 << 6;
+
+const SELF_TEST_OK: u8 = 0x55;
+
+// How long to wait on the status before giving up. Keyboards can take a
+// while to reset.
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+fn status() -> u8 {
+    unsafe { PortRead::read_from_port(STATUS) }
+}
+
+fn wait(done: impl Fn(u8) -> bool) -> Result<(), Errno> {
+    let deadline = time::now() + TIMEOUT;
+    while !done(status()) {
+        if time::now() >= deadline {
+            return Err(Errno::EIO);
+        }
+    }
+    Ok(())
+}
+
+fn command(command: u8) -> Result<(), Errno> {
+    wait(|status| status & STATUS_INPUT_FULL == 0)?;
+    unsafe { PortWrite::write_to_port(COMMAND, command) };
+    Ok(())
+}
+
+fn command_read(command_byte: u8) -> Result<u8, Errno> {
+    command(command_byte)?;
+    read_wait()
+}
+
+fn command_write(command_byte: u8, data: u8) -> Result<(), Errno> {
+    command(command_byte)?;
+    write(data)
+}
+
+// Sends a byte to the keyboard
+pub fn write(byte: u8) -> Result<(), Errno> {
+    wait(|status| status & STATUS_INPUT_FULL == 0)?;
+    unsafe { PortWrite::write_to_port(DATA, byte) };
+    Ok(())
+}
+
+// The next byte from the keyboard, if there is one. Anything from the mouse
+// is thrown away.
+pub fn read() -> Option<u8> {
+    loop {
+        let status = status();
+        if status & STATUS_OUTPUT_FULL == 0 {
+            return None;
+        }
+        let byte = unsafe { PortRead::read_from_port(DATA) };
+        if status & STATUS_AUX == 0 {
+            return Some(byte);
+        }
+    }
+}
+
+pub fn read_wait() -> Result<u8, Errno> {
+    wait(|status| status & STATUS_OUTPUT_FULL != 0)?;
+    Ok(unsafe { PortRead::read_from_port(DATA) })
+}
+
+// Sets and clears bits in the configuration byte
+pub fn update_config(set: u8, clear: u8) -> Result<(), Errno> {
+    let config = command_read(READ_CONFIG)?;
+    command_write(WRITE_CONFIG, (config | set) & !clear)
+}
+
+// Resets the controller and enables the keyboard's port, with interrupts
+// and translation off. Fails if there's no controller, where the status
+// reads as all ones and never says it's ready.
+pub fn init() -> Result<(), Errno> {
+    command(DISABLE_PORT)?;
+    command(DISABLE_AUX)?;
+    // Whatever was left over, which there's only so much room for
+    for _ in 0..16 {
+        read();
+    }
+
+    let quiet = CONFIG_IRQ | CONFIG_AUX_IRQ | CONFIG_TRANSLATE;
+    update_config(0, quiet)?;
+    if command_read(SELF_TEST)? != SELF_TEST_OK {
+        return Err(Errno::ENODEV);
+    }
+    // Some controllers reset their configuration as they test themselves
+    update_config(0, quiet)?;
+    if command_read(TEST_PORT)? != 0 {
+        return Err(Errno::ENODEV);
+    }
+    command(ENABLE_PORT)
+}
+
+// Makes the controller hand back a byte as if the keyboard had sent it
+#[cfg(test)]
+pub fn inject(byte: u8) {
+    command_write(WRITE_OUTPUT, byte).unwrap();
+    wait(|status| status & STATUS_OUTPUT_FULL != 0).unwrap();
+}
@@ -0,0 +1,121 @@
+// Input events, queued until someone reads them. Drivers push events from
+// their interrupt handlers or when polled, and readers with nothing to read
+// sleep until the next one. Without a keyboard interrupt, readers also wake
+// every so often to poll the keyboard themselves.
+use super::keyboard::{self, Key, Modifiers};
+use crate::{
+    ds::SpinLock,
+    task::{self, Thread},
+    time::{self, timer},
+};
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::{mem, time::Duration};
+use x86_64::instructions::interrupts;
+
+// Events past this many are dropped until some are read
+const CAPACITY: usize = 256;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub key: Key,
+    // Released otherwise
+    pub pressed: bool,
+    // As they were after this key
+    pub modifiers: Modifiers,
+    // What the keymap says the key types, for presses only
+    pub c: Option<char>,
+}
+
+// Both are taken from interrupt handlers, so elsewhere they're only held
+// with interrupts off
+lazy_static! {
+    static ref EVENTS: SpinLock<VecDeque<KeyEvent>> = SpinLock::new(VecDeque::new());
+    static ref WAITERS: SpinLock<Vec<Arc<Thread>>> = SpinLock::new(Vec::new());
+}
+
+// Queues an event and wakes everyone waiting for one
+pub fn push(event: KeyEvent) {
+    let waiters = interrupts::without_interrupts(|| {
+        let mut events = EVENTS.lock();
+        if events.len() < CAPACITY {
+            events.push_back(event);
+        }
+        mem::replace(&mut *WAITERS.lock(), Vec::new())
+    });
+
+    for thread in &waiters {
+        task::wake(thread);
+    }
+}
+
+// The oldest event, if there are any
+pub fn try_read() -> Option<KeyEvent> {
+    keyboard::poll();
+    interrupts::without_interrupts(|| EVENTS.lock().pop_front())
+}
+
+// Sleeps until there may be an event to read. Interrupts stay off from
+// checking the queue to blocking, so an event can't be pushed in between and
+// leave nobody to wake us.
+pub fn wait() {
+    let thread = task::current();
+    interrupts::without_interrupts(|| {
+        if !EVENTS.lock().is_empty() {
+            return;
+        }
+        WAITERS.lock().push(thread.clone());
+
+        if keyboard::has_interrupt() {
+            task::block();
+        } else {
+            let timer = timer::add(time::now() + POLL_INTERVAL, thread.clone());
+            task::block();
+            timer::cancel(timer);
+            WAITERS.lock().retain(|waiter| !Arc::ptr_eq(waiter, &thread));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn q_pressed() -> KeyEvent {
+        KeyEvent {
+            key: Key::Q,
+            pressed: true,
+            modifiers: Modifiers::default(),
+            c: Some('q'),
+        }
+    }
+
+    fn push_later(_: usize) {
+        task::yield_now();
+        push(q_pressed());
+    }
+
+    test_case!(queue, {
+        while try_read().is_some() {}
+        let event = q_pressed();
+        push(event);
+        push(KeyEvent {
+            pressed: false,
+            c: None,
+            ..event
+        });
+
+        // Nothing to wait for with events queued
+        wait();
+        assert_eq!(try_read(), Some(event));
+        assert_eq!(try_read().map(|event| event.pressed), Some(false));
+
+        // Otherwise it sleeps until the next one
+        let pusher = task::spawn("input-pusher", push_later, 0);
+        wait();
+        assert_eq!(try_read(), Some(event));
+        assert!(try_read().is_none());
+        task::join(&pusher);
+    });
+}
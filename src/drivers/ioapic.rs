@@ -0,0 +1,229 @@
+// I/O APICs, which turn interrupt pins into messages for the local APICs.
+// Each covers a range of global system interrupts (GSIs) starting at its base.
+// The 16 ISA IRQs are identity mapped onto the first GSIs unless the MADT has
+// an override for them.
+use crate::{cpu::lapic, ds::SpinLock, mm::mmio, syscall::Errno};
+use acpi::{Apic, Polarity as AcpiPolarity, TriggerMode as AcpiTrigger};
+use alloc::vec::Vec;
+use core::ptr;
+use x86_64::{PhysAddr, VirtAddr};
+
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+
+const REG_ID: u32 = 0x00;
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION: u32 = 0x10;
+
+const ENTRY_POLARITY_LOW: u64 = 1 << 13;
+const ENTRY_TRIGGER_LEVEL: u64 = 1 << 15;
+const ENTRY_MASKED: u64 = 1 << 16;
+
+const ISA_IRQS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    High,
+    Low,
+}
+
+struct IoApic {
+    id: u8,
+    base: VirtAddr,
+    gsi_base: u32,
+    count: u32,
+}
+
+impl IoApic {
+    // Register accesses are a select then a window access, so the caller has
+    // to hold the IO_APICS lock
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile((self.base + IOREGSEL).as_mut_ptr::<u32>(), reg);
+            ptr::read_volatile((self.base + IOWIN).as_ptr::<u32>())
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile((self.base + IOREGSEL).as_mut_ptr::<u32>(), reg);
+            ptr::write_volatile((self.base + IOWIN).as_mut_ptr::<u32>(), value);
+        }
+    }
+
+    fn read_entry(&self, pin: u32) -> u64 {
+        let low = self.read(REG_REDIRECTION + 2 * pin);
+        let high = self.read(REG_REDIRECTION + 2 * pin + 1);
+        u64::from(high) << 32 | u64::from(low)
+    }
+
+    // The low half holds the mask bit, so it goes last to unmask with the
+    // destination already in place
+    fn write_entry(&self, pin: u32, entry: u64) {
+        self.write(REG_REDIRECTION + 2 * pin, (entry as u32) | ENTRY_MASKED as u32);
+        self.write(REG_REDIRECTION + 2 * pin + 1, (entry >> 32) as u32);
+        self.write(REG_REDIRECTION + 2 * pin, entry as u32);
+    }
+}
+
+lazy_static! {
+    static ref IO_APICS: SpinLock<Vec<IoApic>> = SpinLock::new(Vec::new());
+    // GSI, trigger and polarity of each ISA IRQ
+    static ref ISA: SpinLock<[(u32, Trigger, Polarity); ISA_IRQS]> = {
+        let mut isa = [(0, Trigger::Edge, Polarity::High); ISA_IRQS];
+        for (irq, entry) in isa.iter_mut().enumerate() {
+            entry.0 = irq as u32;
+        }
+        SpinLock::new(isa)
+    };
+}
+
+pub fn init(apic: &Apic) {
+    let mut io_apics = IO_APICS.lock();
+    for info in &apic.io_apics {
+        let phys = PhysAddr::new(info.address as usize);
+        let base = match mmio::map(phys, 0x20) {
+            Ok(base) => base,
+            Err(err) => {
+                error!("ioapic: failed to map {:?}: {:?}", phys, err);
+                continue;
+            }
+        };
+
+        let mut io_apic = IoApic {
+            id: info.id,
+            base,
+            gsi_base: info.global_system_interrupt_base,
+            count: 0,
+        };
+        io_apic.count = ((io_apic.read(REG_VERSION) >> 16) & 0xFF) + 1;
+
+        // Nothing is routed until a driver asks for it
+        for pin in 0..io_apic.count {
+            io_apic.write_entry(pin, ENTRY_MASKED);
+        }
+
+        info!(
+            "ioapic: id {} at {:?}, gsis {}-{}",
+            (io_apic.read(REG_ID) >> 24) & 0xF,
+            phys,
+            io_apic.gsi_base,
+            io_apic.gsi_base + io_apic.count - 1
+        );
+        io_apics.push(io_apic);
+    }
+
+    let mut isa = ISA.lock();
+    for over in &apic.interrupt_source_overrides {
+        let irq = usize::from(over.isa_source);
+        if irq >= ISA_IRQS {
+            continue;
+        }
+
+        // ISA interrupts are edge triggered and active high by default
+        let trigger = match over.trigger_mode {
+            AcpiTrigger::Level => Trigger::Level,
+            _ => Trigger::Edge,
+        };
+        let polarity = match over.polarity {
+            AcpiPolarity::ActiveLow => Polarity::Low,
+            _ => Polarity::High,
+        };
+        isa[irq] = (over.global_system_interrupt, trigger, polarity);
+        debug!("ioapic: isa irq {} is gsi {} ({:?}, {:?})", irq, over.global_system_interrupt, trigger, polarity);
+    }
+}
+
+pub fn is_present() -> bool {
+    !IO_APICS.lock().is_empty()
+}
+
+// Where an ISA IRQ ends up, along with how it's signalled
+pub fn isa_irq(irq: u8) -> (u32, Trigger, Polarity) {
+    ISA.lock()[usize::from(irq)]
+}
+
+// Runs f with the I/O APIC and pin that gsi is on
+fn with_pin<T>(gsi: u32, f: impl FnOnce(&IoApic, u32) -> T) -> Result<T, Errno> {
+    let io_apics = IO_APICS.lock();
+    let io_apic = io_apics
+        .iter()
+        .find(|io_apic| gsi >= io_apic.gsi_base && gsi - io_apic.gsi_base < io_apic.count)
+        .ok_or(Errno::ENODEV)?;
+    Ok(f(io_apic, gsi - io_apic.gsi_base))
+}
+
+// Delivers gsi to vector on the local APIC with ID dest, and unmasks it
+pub fn route(gsi: u32, vector: u8, dest: u8, trigger: Trigger, polarity: Polarity) -> Result<(), Errno> {
+    let mut entry = u64::from(vector) | u64::from(dest) << 56;
+    if trigger == Trigger::Level {
+        entry |= ENTRY_TRIGGER_LEVEL;
+    }
+    if polarity == Polarity::Low {
+        entry |= ENTRY_POLARITY_LOW;
+    }
+
+    with_pin(gsi, |io_apic, pin| {
+        trace!("ioapic: gsi {} -> vector {:#x} on lapic {} (ioapic {})", gsi, vector, dest, io_apic.id);
+        io_apic.write_entry(pin, entry);
+    })
+}
+
+// Routes gsi to vector on this CPU
+pub fn route_here(gsi: u32, vector: u8, trigger: Trigger, polarity: Polarity) -> Result<(), Errno> {
+    route(gsi, vector, lapic::id(), trigger, polarity)
+}
+
+pub fn mask(gsi: u32) -> Result<(), Errno> {
+    with_pin(gsi, |io_apic, pin| {
+        io_apic.write_entry(pin, io_apic.read_entry(pin) | ENTRY_MASKED);
+    })
+}
+
+pub fn unmask(gsi: u32) -> Result<(), Errno> {
+    with_pin(gsi, |io_apic, pin| {
+        io_apic.write_entry(pin, io_apic.read_entry(pin) & !ENTRY_MASKED);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    test_case!(route_and_mask, {
+        assert!(is_present());
+
+        // The last pin of the last I/O APIC isn't wired to anything in q35
+        let gsi = {
+            let io_apics = IO_APICS.lock();
+            let last = io_apics.last().unwrap();
+            last.gsi_base + last.count - 1
+        };
+        let entry = || with_pin(gsi, |io_apic, pin| io_apic.read_entry(pin)).unwrap();
+        assert_ne!(entry() & ENTRY_MASKED, 0);
+
+        route(gsi, 0x42, 3, Trigger::Level, Polarity::Low).unwrap();
+        assert_eq!(entry() & 0xFF, 0x42);
+        assert_eq!(entry() >> 56, 3);
+        assert_eq!(entry() & (ENTRY_MASKED | ENTRY_TRIGGER_LEVEL | ENTRY_POLARITY_LOW), ENTRY_TRIGGER_LEVEL | ENTRY_POLARITY_LOW);
+
+        mask(gsi).unwrap();
+        assert_ne!(entry() & ENTRY_MASKED, 0);
+        assert_eq!(entry() & 0xFF, 0x42);
+        unmask(gsi).unwrap();
+        assert_eq!(entry() & ENTRY_MASKED, 0);
+        mask(gsi).unwrap();
+
+        assert_eq!(route(gsi + 1, 0x42, 0, Trigger::Edge, Polarity::High), Err(Errno::ENODEV));
+
+        // q35 moves the PIT from IRQ 0 to GSI 2
+        assert_eq!(isa_irq(0).0, 2);
+        assert_eq!(isa_irq(1), (1, Trigger::Edge, Polarity::High));
+    });
+}
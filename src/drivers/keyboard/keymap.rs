@@ -0,0 +1,262 @@
+// Keymaps, which say what each key types. Keys that type the same thing on
+// every layout, like Enter and the keypad, are handled here and layouts only
+// give the rest. Dead keys type their accent straight away.
+use super::{scancode::Key, Modifiers};
+
+pub trait Keymap: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // What the key types on its own and with shift
+    fn lookup(&self, key: Key) -> Option<(char, char)>;
+
+    // Layouts with AltGr have it on the right alt key, which is otherwise
+    // just alt
+    fn has_alt_gr(&self) -> bool {
+        false
+    }
+
+    fn alt_gr(&self, _key: Key) -> Option<char> {
+        None
+    }
+}
+
+static KEYMAPS: &[&dyn Keymap] = &[&Us, &De];
+
+pub fn by_name(name: &str) -> Option<&'static dyn Keymap> {
+    KEYMAPS.iter().copied().find(|keymap| keymap.name() == name)
+}
+
+// The character a key types with the given modifiers, if any
+pub fn translate(keymap: &dyn Keymap, key: Key, modifiers: Modifiers) -> Option<char> {
+    if modifiers.alt_gr {
+        return keymap.alt_gr(key);
+    }
+    if let Some(c) = fixed(key, modifiers.num_lock) {
+        return Some(c);
+    }
+
+    // Caps lock only goes for letters with a capital, and shift undoes it
+    let (plain, shifted) = keymap.lookup(key)?;
+    let letter = plain.is_alphabetic() && shifted.is_alphabetic();
+    let shift = modifiers.shift != (modifiers.caps_lock && letter);
+    let c = if shift { shifted } else { plain };
+
+    // Control characters are the letters and a few symbols around them
+    if modifiers.ctrl {
+        let upper = c.to_ascii_uppercase();
+        if ('@'..='_').contains(&upper) {
+            return Some((upper as u8 & 0x1F) as char);
+        }
+    }
+    Some(c)
+}
+
+fn fixed(key: Key, num_lock: bool) -> Option<char> {
+    use Key::*;
+    let digit = |n: u8| if num_lock { Some((b'0' + n) as char) } else { None };
+    match key {
+        Escape => Some('\x1B'),
+        Backspace => Some('\x08'),
+        Tab => Some('\t'),
+        Enter | KeypadEnter => Some('\n'),
+        Space => Some(' '),
+        KeypadSlash => Some('/'),
+        KeypadStar => Some('*'),
+        KeypadMinus => Some('-'),
+        KeypadPlus => Some('+'),
+        KeypadPeriod if num_lock => Some('.'),
+        Keypad0 => digit(0),
+        Keypad1 => digit(1),
+        Keypad2 => digit(2),
+        Keypad3 => digit(3),
+        Keypad4 => digit(4),
+        Keypad5 => digit(5),
+        Keypad6 => digit(6),
+        Keypad7 => digit(7),
+        Keypad8 => digit(8),
+        Keypad9 => digit(9),
+        _ => None,
+    }
+}
+
+// Letters where they are on a US keyboard
+fn letter(key: Key) -> Option<(char, char)> {
+    use Key::*;
+    let c = match key {
+        A => 'a',
+        B => 'b',
+        C => 'c',
+        D => 'd',
+        E => 'e',
+        F => 'f',
+        G => 'g',
+        H => 'h',
+        I => 'i',
+        J => 'j',
+        K => 'k',
+        L => 'l',
+        M => 'm',
+        N => 'n',
+        O => 'o',
+        P => 'p',
+        Q => 'q',
+        R => 'r',
+        S => 's',
+        T => 't',
+        U => 'u',
+        V => 'v',
+        W => 'w',
+        X => 'x',
+        Y => 'y',
+        Z => 'z',
+        _ => return None,
+    };
+    Some((c, c.to_ascii_uppercase()))
+}
+
+pub struct Us;
+
+impl Keymap for Us {
+    fn name(&self) -> &'static str {
+        "us"
+    }
+
+    fn lookup(&self, key: Key) -> Option<(char, char)> {
+        use Key::*;
+        Some(match key {
+            Backtick => ('`', '~'),
+            Num1 => ('1', '!'),
+            Num2 => ('2', '@'),
+            Num3 => ('3', '#'),
+            Num4 => ('4', '$'),
+            Num5 => ('5', '%'),
+            Num6 => ('6', '^'),
+            Num7 => ('7', '&'),
+            Num8 => ('8', '*'),
+            Num9 => ('9', '('),
+            Num0 => ('0', ')'),
+            Minus => ('-', '_'),
+            Equals => ('=', '+'),
+            LeftBracket => ('[', '{'),
+            RightBracket => (']', '}'),
+            Backslash | NonUsBackslash => ('\\', '|'),
+            Semicolon => (';', ':'),
+            Quote => ('\'', '"'),
+            Comma => (',', '<'),
+            Period => ('.', '>'),
+            Slash => ('/', '?'),
+            _ => return letter(key),
+        })
+    }
+}
+
+// German, with Y and Z swapped and umlauts where US has brackets and
+// punctuation
+pub struct De;
+
+impl Keymap for De {
+    fn name(&self) -> &'static str {
+        "de"
+    }
+
+    fn lookup(&self, key: Key) -> Option<(char, char)> {
+        use Key::*;
+        Some(match key {
+            Backtick => ('^', '°'),
+            Num1 => ('1', '!'),
+            Num2 => ('2', '"'),
+            Num3 => ('3', '§'),
+            Num4 => ('4', '$'),
+            Num5 => ('5', '%'),
+            Num6 => ('6', '&'),
+            Num7 => ('7', '/'),
+            Num8 => ('8', '('),
+            Num9 => ('9', ')'),
+            Num0 => ('0', '='),
+            Minus => ('ß', '?'),
+            Equals => ('´', '`'),
+            Y => ('z', 'Z'),
+            LeftBracket => ('ü', 'Ü'),
+            RightBracket => ('+', '*'),
+            Semicolon => ('ö', 'Ö'),
+            Quote => ('ä', 'Ä'),
+            Backslash => ('#', '\''),
+            NonUsBackslash => ('<', '>'),
+            Z => ('y', 'Y'),
+            Comma => (',', ';'),
+            Period => ('.', ':'),
+            Slash => ('-', '_'),
+            _ => return letter(key),
+        })
+    }
+
+    fn has_alt_gr(&self) -> bool {
+        true
+    }
+
+    fn alt_gr(&self, key: Key) -> Option<char> {
+        use Key::*;
+        Some(match key {
+            Num2 => '²',
+            Num3 => '³',
+            Num7 => '{',
+            Num8 => '[',
+            Num9 => ']',
+            Num0 => '}',
+            Minus => '\\',
+            Q => '@',
+            E => '€',
+            RightBracket => '~',
+            NonUsBackslash => '|',
+            M => 'µ',
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    test_case!(us_and_de, {
+        let none = Modifiers::default();
+        let shift = Modifiers { shift: true, ..none };
+        let caps = Modifiers {
+            caps_lock: true,
+            ..none
+        };
+        let ctrl = Modifiers { ctrl: true, ..none };
+
+        assert_eq!(translate(&Us, Key::A, none), Some('a'));
+        assert_eq!(translate(&Us, Key::A, shift), Some('A'));
+        assert_eq!(translate(&Us, Key::Num2, shift), Some('@'));
+        assert_eq!(translate(&Us, Key::A, caps), Some('A'));
+        assert_eq!(translate(&Us, Key::Num2, caps), Some('2'));
+        assert_eq!(translate(&Us, Key::A, Modifiers { shift: true, ..caps }), Some('a'));
+        assert_eq!(translate(&Us, Key::C, ctrl), Some('\x03'));
+        assert_eq!(translate(&Us, Key::LeftBracket, ctrl), Some('\x1B'));
+        assert_eq!(translate(&Us, Key::Enter, shift), Some('\n'));
+        assert_eq!(translate(&Us, Key::LeftShift, none), None);
+
+        // The keypad is digits with num lock on, otherwise it's for moving
+        assert_eq!(translate(&Us, Key::Keypad7, none), None);
+        assert_eq!(
+            translate(&Us, Key::Keypad7, Modifiers { num_lock: true, ..none }),
+            Some('7')
+        );
+        assert_eq!(translate(&Us, Key::KeypadPlus, none), Some('+'));
+
+        let alt_gr = Modifiers { alt_gr: true, ..none };
+        assert_eq!(translate(&De, Key::Y, none), Some('z'));
+        assert_eq!(translate(&De, Key::Z, shift), Some('Y'));
+        assert_eq!(translate(&De, Key::Quote, caps), Some('Ä'));
+        assert_eq!(translate(&De, Key::Minus, caps), Some('ß'));
+        assert_eq!(translate(&De, Key::Num7, shift), Some('/'));
+        assert_eq!(translate(&De, Key::Q, alt_gr), Some('@'));
+        assert_eq!(translate(&De, Key::A, alt_gr), None);
+        assert!(De.has_alt_gr() && !Us.has_alt_gr());
+
+        assert_eq!(by_name("de").map(|keymap| keymap.name()), Some("de"));
+        assert!(by_name("xx").is_none());
+    });
+}
@@ -0,0 +1,353 @@
+// The PS/2 keyboard on the i8042's first port. Bytes it sends are decoded
+// into key events for the input queue, as they come in on IRQ 1 or when
+// someone reading input polls for them. Its LEDs follow the lock keys, and
+// the commands for them go out a byte at a time as the keyboard acknowledges
+// each one, between the keys it's sending.
+//
+// The keymap is picked at build time, from the KEYMAP environment variable.
+use self::{
+    keymap::Keymap,
+    scancode::{Decoder, Set},
+};
+use super::{
+    i8042,
+    input::{self, KeyEvent},
+    ioapic,
+};
+use crate::{cpu::irq, ds::SpinLock, syscall::Errno};
+use alloc::{collections::VecDeque, sync::Arc};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts;
+
+pub mod keymap;
+pub mod scancode;
+
+pub use self::scancode::Key;
+
+const SET_LEDS: u8 = 0xED;
+const SCANCODE_SET: u8 = 0xF0;
+const ENABLE_SCANNING: u8 = 0xF4;
+const RESET: u8 = 0xFF;
+
+const ACK: u8 = 0xFA;
+const RESEND: u8 = 0xFE;
+const SELF_TEST_OK: u8 = 0xAA;
+
+const LED_SCROLL_LOCK: u8 = 1 << 0;
+const LED_NUM_LOCK: u8 = 1 << 1;
+const LED_CAPS_LOCK: u8 = 1 << 2;
+
+// Keys held down that matter for what the others do
+const HELD_LEFT_SHIFT: u16 = 1 << 0;
+const HELD_RIGHT_SHIFT: u16 = 1 << 1;
+const HELD_LEFT_CTRL: u16 = 1 << 2;
+const HELD_RIGHT_CTRL: u16 = 1 << 3;
+const HELD_LEFT_ALT: u16 = 1 << 4;
+const HELD_RIGHT_ALT: u16 = 1 << 5;
+// Lock keys only toggle as they go down, not as they repeat
+const HELD_CAPS_LOCK: u16 = 1 << 6;
+const HELD_NUM_LOCK: u16 = 1 << 7;
+const HELD_SCROLL_LOCK: u16 = 1 << 8;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+    // Right alt, on layouts that have it
+    pub alt_gr: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+}
+
+struct Keyboard {
+    decoder: Decoder,
+    keymap: &'static dyn Keymap,
+    held: u16,
+    leds: u8,
+    // Bytes for the keyboard, and the one it's yet to acknowledge
+    outgoing: VecDeque<u8>,
+    unacked: Option<u8>,
+}
+
+lazy_static! {
+    // Taken from the interrupt handler, so elsewhere only held with interrupts
+    // off
+    static ref KEYBOARD: SpinLock<Option<Keyboard>> = SpinLock::new(None);
+}
+
+static HAS_INTERRUPT: AtomicBool = AtomicBool::new(false);
+
+impl Keyboard {
+    fn new(set: Set, keymap: &'static dyn Keymap) -> Keyboard {
+        Keyboard {
+            decoder: Decoder::new(set),
+            keymap,
+            held: 0,
+            leds: 0,
+            outgoing: VecDeque::new(),
+            unacked: None,
+        }
+    }
+
+    fn modifiers(&self) -> Modifiers {
+        let held = |bits| self.held & bits != 0;
+        let alt_gr = held(HELD_RIGHT_ALT) && self.keymap.has_alt_gr();
+        Modifiers {
+            shift: held(HELD_LEFT_SHIFT | HELD_RIGHT_SHIFT),
+            ctrl: held(HELD_LEFT_CTRL | HELD_RIGHT_CTRL),
+            alt: held(HELD_LEFT_ALT) || (held(HELD_RIGHT_ALT) && !alt_gr),
+            alt_gr,
+            caps_lock: self.leds & LED_CAPS_LOCK != 0,
+            num_lock: self.leds & LED_NUM_LOCK != 0,
+        }
+    }
+
+    // Takes a byte from the keyboard, returning the event it finishes
+    fn receive(&mut self, byte: u8) -> Option<KeyEvent> {
+        match byte {
+            ACK => {
+                self.unacked = None;
+                return None;
+            }
+            RESEND => {
+                if let Some(byte) = self.unacked.take() {
+                    self.outgoing.push_front(byte);
+                }
+                return None;
+            }
+            _ => {}
+        }
+
+        let (key, pressed) = self.decoder.feed(byte)?;
+        let (bit, led) = match key {
+            Key::LeftShift => (HELD_LEFT_SHIFT, 0),
+            Key::RightShift => (HELD_RIGHT_SHIFT, 0),
+            Key::LeftCtrl => (HELD_LEFT_CTRL, 0),
+            Key::RightCtrl => (HELD_RIGHT_CTRL, 0),
+            Key::LeftAlt => (HELD_LEFT_ALT, 0),
+            Key::RightAlt => (HELD_RIGHT_ALT, 0),
+            Key::CapsLock => (HELD_CAPS_LOCK, LED_CAPS_LOCK),
+            Key::NumLock => (HELD_NUM_LOCK, LED_NUM_LOCK),
+            Key::ScrollLock => (HELD_SCROLL_LOCK, LED_SCROLL_LOCK),
+            _ => (0, 0),
+        };
+        if pressed && self.held & bit == 0 && led != 0 {
+            self.leds ^= led;
+            self.outgoing.extend(&[SET_LEDS, self.leds]);
+        }
+        if pressed {
+            self.held |= bit;
+        } else {
+            self.held &= !bit;
+        }
+
+        let modifiers = self.modifiers();
+        let c = if pressed {
+            keymap::translate(self.keymap, key, modifiers)
+        } else {
+            None
+        };
+        Some(KeyEvent {
+            key,
+            pressed,
+            modifiers,
+            c,
+        })
+    }
+
+    // The next byte to send, once the last has been acknowledged
+    fn next_byte(&mut self) -> Option<u8> {
+        if self.unacked.is_some() {
+            return None;
+        }
+        let byte = self.outgoing.pop_front()?;
+        self.unacked = Some(byte);
+        Some(byte)
+    }
+}
+
+// Sends a byte and waits for the keyboard to acknowledge it, for while
+// nothing else is coming in
+fn command(byte: u8) -> Result<(), Errno> {
+    for _ in 0..3 {
+        i8042::write(byte)?;
+        match i8042::read_wait()? {
+            ACK => return Ok(()),
+            RESEND => continue,
+            _ => return Err(Errno::EIO),
+        }
+    }
+    Err(Errno::EIO)
+}
+
+fn start() -> Result<(), Errno> {
+    i8042::init()?;
+    command(RESET)?;
+    if i8042::read_wait()? != SELF_TEST_OK {
+        return Err(Errno::EIO);
+    }
+
+    // Keyboards that won't switch to set 2 are likely in it already, and
+    // the controller can turn that into set 1 for us
+    let set = if command(SCANCODE_SET).and_then(|()| command(2)).is_ok() {
+        Set::Two
+    } else {
+        i8042::update_config(i8042::CONFIG_TRANSLATE, 0)?;
+        Set::One
+    };
+    command(SET_LEDS)?;
+    command(0)?;
+    command(ENABLE_SCANNING)?;
+
+    let keymap = option_env!("KEYMAP").and_then(keymap::by_name).unwrap_or(&keymap::Us);
+    let keyboard = Keyboard::new(set, keymap);
+    info!(
+        "keyboard: scancode set {:?}, {} keymap",
+        keyboard.decoder.set(),
+        keyboard.keymap.name()
+    );
+    interrupts::without_interrupts(|| *KEYBOARD.lock() = Some(keyboard));
+
+    if !ioapic::is_present() {
+        return Err(Errno::ENODEV);
+    }
+    let (gsi, trigger, polarity) = ioapic::isa_irq(1);
+    let vector = irq::alloc_vector(Arc::new(poll)).ok_or(Errno::ENOSPC)?;
+    if let Err(errno) = ioapic::route_here(gsi, vector, trigger, polarity) {
+        irq::free_vector(vector);
+        return Err(errno);
+    }
+    i8042::update_config(i8042::CONFIG_IRQ, 0)?;
+    HAS_INTERRUPT.store(true, Ordering::Release);
+    Ok(())
+}
+
+pub fn init() {
+    match start() {
+        Ok(()) => {}
+        Err(errno) if is_present() => warn!("keyboard: no interrupt ({:?}), polling only", errno),
+        Err(errno) => info!("keyboard: none found ({:?})", errno),
+    }
+}
+
+pub fn is_present() -> bool {
+    interrupts::without_interrupts(|| KEYBOARD.lock().is_some())
+}
+
+// Whether events arrive by themselves, rather than only when polled
+pub fn has_interrupt() -> bool {
+    HAS_INTERRUPT.load(Ordering::Acquire)
+}
+
+// Queues events for whatever the keyboard has sent, and sends it the next
+// byte it's waiting for
+pub fn poll() {
+    interrupts::without_interrupts(|| {
+        let mut keyboard = KEYBOARD.lock();
+        let keyboard = match keyboard.as_mut() {
+            Some(keyboard) => keyboard,
+            None => return,
+        };
+
+        loop {
+            if let Some(byte) = keyboard.next_byte() {
+                if i8042::write(byte).is_err() {
+                    warn!("keyboard: couldn't send {:#x}", byte);
+                    keyboard.unacked = None;
+                }
+            }
+            match i8042::read() {
+                Some(byte) => {
+                    if let Some(event) = keyboard.receive(byte) {
+                        input::push(event);
+                    }
+                }
+                None => break,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn receive(keyboard: &mut Keyboard, bytes: &[u8]) -> Vec<KeyEvent> {
+        bytes.iter().filter_map(|&byte| keyboard.receive(byte)).collect()
+    }
+
+    fn typed(events: &[KeyEvent]) -> Vec<char> {
+        events.iter().filter_map(|event| event.c).collect()
+    }
+
+    test_case!(modifiers_and_leds, {
+        let mut keyboard = Keyboard::new(Set::Two, &keymap::Us);
+
+        // Shift stays down until both are up
+        let events = receive(
+            &mut keyboard,
+            &[0x1C, 0x12, 0x59, 0x1C, 0xF0, 0x12, 0x1C, 0xF0, 0x59, 0x1C],
+        );
+        assert_eq!(typed(&events), ['a', 'A', 'A', 'a']);
+        assert!(events[1].modifiers.shift && !events[6].modifiers.shift);
+        assert!(!events[4].pressed && events[4].c.is_none());
+
+        // Caps lock toggles once however long it's held, and the LEDs
+        // follow one acknowledged byte at a time
+        let events = receive(&mut keyboard, &[0x58, 0x58, 0x58, 0xF0, 0x58, 0x1C]);
+        assert_eq!(typed(&events), ['A']);
+        assert!(events[4].modifiers.caps_lock);
+        assert_eq!(keyboard.next_byte(), Some(SET_LEDS));
+        assert_eq!(keyboard.next_byte(), None);
+        assert!(keyboard.receive(RESEND).is_none());
+        assert_eq!(keyboard.next_byte(), Some(SET_LEDS));
+        assert!(keyboard.receive(ACK).is_none());
+        assert_eq!(keyboard.next_byte(), Some(LED_CAPS_LOCK));
+        assert!(keyboard.receive(ACK).is_none());
+        assert_eq!(keyboard.next_byte(), None);
+
+        // Ctrl makes control characters, and right alt is just alt on US
+        let events = receive(
+            &mut keyboard,
+            &[0x58, 0xF0, 0x58, 0xE0, 0x14, 0x21, 0xE0, 0xF0, 0x14, 0xE0, 0x11],
+        );
+        assert_eq!(typed(&events), ['\x03']);
+        assert!(events[5].modifiers.alt && !events[5].modifiers.alt_gr);
+        assert_eq!(keyboard.leds, 0);
+
+        // Where it's AltGr on German
+        let mut keyboard = Keyboard::new(Set::One, &keymap::De);
+        let events = receive(&mut keyboard, &[0xE0, 0x38, 0x10, 0x90, 0xE0, 0xB8, 0x15, 0x2C]);
+        assert_eq!(typed(&events), ['@', 'z', 'y']);
+        assert!(events[0].modifiers.alt_gr && !events[0].modifiers.alt);
+    });
+
+    // Goes through the controller, with bytes it's told to hand back as if
+    // they were from the keyboard
+    test_case!(injected_keys, {
+        let set = interrupts::without_interrupts(|| {
+            KEYBOARD.lock().as_ref().map(|keyboard| keyboard.decoder.set())
+        });
+        if let Some(set) = set {
+            // q35 has an I/O APIC to route IRQ 1 through
+            assert!(has_interrupt());
+            while input::try_read().is_some() {}
+            let bytes: &[u8] = match set {
+                Set::One => &[0x2D, 0xAD],
+                Set::Two => &[0x22, 0xF0, 0x22],
+            };
+            for &byte in bytes {
+                i8042::inject(byte);
+                poll();
+            }
+
+            let down = input::try_read().unwrap();
+            assert_eq!((down.key, down.pressed, down.c), (Key::X, true, Some('x')));
+            let up = input::try_read().unwrap();
+            assert_eq!((up.key, up.pressed, up.c), (Key::X, false, None));
+            assert!(input::try_read().is_none());
+        }
+    });
+}
@@ -0,0 +1,523 @@
+// Scancodes, the bytes a keyboard sends as keys go down and up. Set 1 is the
+// original XT codes, with the top bit set on release. Set 2 is what AT and
+// PS/2 keyboards send by default, with an F0 byte before each release. Keys
+// added later have an E0 prefix in both, and Pause has a sequence of its own.
+//
+// Keys are named after where they are on a US keyboard, whatever the keymap.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Set {
+    One,
+    Two,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Escape,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    PrintScreen,
+    ScrollLock,
+    Pause,
+    Backtick,
+    Num1,
+    Num2,
+    Num3,
+    Num4,
+    Num5,
+    Num6,
+    Num7,
+    Num8,
+    Num9,
+    Num0,
+    Minus,
+    Equals,
+    Backspace,
+    Tab,
+    Q,
+    W,
+    E,
+    R,
+    T,
+    Y,
+    U,
+    I,
+    O,
+    P,
+    LeftBracket,
+    RightBracket,
+    Backslash,
+    CapsLock,
+    A,
+    S,
+    D,
+    F,
+    G,
+    H,
+    J,
+    K,
+    L,
+    Semicolon,
+    Quote,
+    Enter,
+    LeftShift,
+    // The extra key next to left shift on non-US keyboards
+    NonUsBackslash,
+    Z,
+    X,
+    C,
+    V,
+    B,
+    N,
+    M,
+    Comma,
+    Period,
+    Slash,
+    RightShift,
+    LeftCtrl,
+    LeftMeta,
+    LeftAlt,
+    Space,
+    RightAlt,
+    RightMeta,
+    Menu,
+    RightCtrl,
+    Insert,
+    Delete,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Up,
+    Down,
+    Left,
+    Right,
+    NumLock,
+    KeypadSlash,
+    KeypadStar,
+    KeypadMinus,
+    KeypadPlus,
+    KeypadEnter,
+    KeypadPeriod,
+    Keypad0,
+    Keypad1,
+    Keypad2,
+    Keypad3,
+    Keypad4,
+    Keypad5,
+    Keypad6,
+    Keypad7,
+    Keypad8,
+    Keypad9,
+}
+
+// Where Decoder is in a multi-byte sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Start,
+    Extended,
+    // Set 2 releases only
+    Release,
+    ExtendedRelease,
+    // Partway through Pause, and whether it's the release
+    Pause(bool),
+}
+
+// Turns scancodes back into keys, one byte at a time
+pub struct Decoder {
+    set: Set,
+    state: State,
+}
+
+impl Decoder {
+    pub fn new(set: Set) -> Decoder {
+        Decoder {
+            set,
+            state: State::Start,
+        }
+    }
+
+    pub fn set(&self) -> Set {
+        self.set
+    }
+
+    // The key and whether it went down, once a whole scancode is in. Codes
+    // for keys we don't know are dropped.
+    pub fn feed(&mut self, byte: u8) -> Option<(Key, bool)> {
+        match self.set {
+            Set::One => self.feed_set1(byte),
+            Set::Two => self.feed_set2(byte),
+        }
+    }
+
+    fn feed_set1(&mut self, byte: u8) -> Option<(Key, bool)> {
+        let pressed = byte & 0x80 == 0;
+        match (self.state, byte) {
+            (State::Start, 0xE0) => self.state = State::Extended,
+            (State::Start, 0xE1) => self.state = State::Pause(false),
+            (State::Start, _) => return set1(byte & 0x7F).map(|key| (key, pressed)),
+            (State::Extended, _) => {
+                self.state = State::Start;
+                return set1_extended(byte & 0x7F).map(|key| (key, pressed));
+            }
+            // E1 1D 45 as it goes down, E1 9D C5 as it comes up
+            (State::Pause(_), 0x9D) => self.state = State::Pause(true),
+            (State::Pause(released), 0x45) | (State::Pause(released), 0xC5) => {
+                self.state = State::Start;
+                return Some((Key::Pause, !released));
+            }
+            (State::Pause(_), _) => {}
+            (State::Release, _) | (State::ExtendedRelease, _) => unreachable!(),
+        }
+        None
+    }
+
+    fn feed_set2(&mut self, byte: u8) -> Option<(Key, bool)> {
+        match (self.state, byte) {
+            (State::Start, 0xE0) => self.state = State::Extended,
+            (State::Start, 0xE1) => self.state = State::Pause(false),
+            (State::Start, 0xF0) => self.state = State::Release,
+            (State::Start, _) => return set2(byte).map(|key| (key, true)),
+            (State::Extended, 0xF0) => self.state = State::ExtendedRelease,
+            (State::Extended, _) => {
+                self.state = State::Start;
+                return set2_extended(byte).map(|key| (key, true));
+            }
+            (State::Release, _) => {
+                self.state = State::Start;
+                return set2(byte).map(|key| (key, false));
+            }
+            (State::ExtendedRelease, _) => {
+                self.state = State::Start;
+                return set2_extended(byte).map(|key| (key, false));
+            }
+            // E1 14 77 as it goes down, E1 F0 14 F0 77 as it comes up
+            (State::Pause(_), 0xF0) => self.state = State::Pause(true),
+            (State::Pause(released), 0x77) => {
+                self.state = State::Start;
+                return Some((Key::Pause, !released));
+            }
+            (State::Pause(_), _) => {}
+        }
+        None
+    }
+}
+
+fn set1(code: u8) -> Option<Key> {
+    use Key::*;
+    Some(match code {
+        0x01 => Escape,
+        0x02 => Num1,
+        0x03 => Num2,
+        0x04 => Num3,
+        0x05 => Num4,
+        0x06 => Num5,
+        0x07 => Num6,
+        0x08 => Num7,
+        0x09 => Num8,
+        0x0A => Num9,
+        0x0B => Num0,
+        0x0C => Minus,
+        0x0D => Equals,
+        0x0E => Backspace,
+        0x0F => Tab,
+        0x10 => Q,
+        0x11 => W,
+        0x12 => E,
+        0x13 => R,
+        0x14 => T,
+        0x15 => Y,
+        0x16 => U,
+        0x17 => I,
+        0x18 => O,
+        0x19 => P,
+        0x1A => LeftBracket,
+        0x1B => RightBracket,
+        0x1C => Enter,
+        0x1D => LeftCtrl,
+        0x1E => A,
+        0x1F => S,
+        0x20 => D,
+        0x21 => F,
+        0x22 => G,
+        0x23 => H,
+        0x24 => J,
+        0x25 => K,
+        0x26 => L,
+        0x27 => Semicolon,
+        0x28 => Quote,
+        0x29 => Backtick,
+        0x2A => LeftShift,
+        0x2B => Backslash,
+        0x2C => Z,
+        0x2D => X,
+        0x2E => C,
+        0x2F => V,
+        0x30 => B,
+        0x31 => N,
+        0x32 => M,
+        0x33 => Comma,
+        0x34 => Period,
+        0x35 => Slash,
+        0x36 => RightShift,
+        0x37 => KeypadStar,
+        0x38 => LeftAlt,
+        0x39 => Space,
+        0x3A => CapsLock,
+        0x3B => F1,
+        0x3C => F2,
+        0x3D => F3,
+        0x3E => F4,
+        0x3F => F5,
+        0x40 => F6,
+        0x41 => F7,
+        0x42 => F8,
+        0x43 => F9,
+        0x44 => F10,
+        0x45 => NumLock,
+        0x46 => ScrollLock,
+        0x47 => Keypad7,
+        0x48 => Keypad8,
+        0x49 => Keypad9,
+        0x4A => KeypadMinus,
+        0x4B => Keypad4,
+        0x4C => Keypad5,
+        0x4D => Keypad6,
+        0x4E => KeypadPlus,
+        0x4F => Keypad1,
+        0x50 => Keypad2,
+        0x51 => Keypad3,
+        0x52 => Keypad0,
+        0x53 => KeypadPeriod,
+        0x56 => NonUsBackslash,
+        0x57 => F11,
+        0x58 => F12,
+        _ => return None,
+    })
+}
+
+// After E0. Print Screen also sends a fake shift around itself, which is
+// left out along with the others.
+fn set1_extended(code: u8) -> Option<Key> {
+    use Key::*;
+    Some(match code {
+        0x1C => KeypadEnter,
+        0x1D => RightCtrl,
+        0x35 => KeypadSlash,
+        0x37 => PrintScreen,
+        0x38 => RightAlt,
+        0x47 => Home,
+        0x48 => Up,
+        0x49 => PageUp,
+        0x4B => Left,
+        0x4D => Right,
+        0x4F => End,
+        0x50 => Down,
+        0x51 => PageDown,
+        0x52 => Insert,
+        0x53 => Delete,
+        0x5B => LeftMeta,
+        0x5C => RightMeta,
+        0x5D => Menu,
+        _ => return None,
+    })
+}
+
+fn set2(code: u8) -> Option<Key> {
+    use Key::*;
+    Some(match code {
+        0x01 => F9,
+        0x03 => F5,
+        0x04 => F3,
+        0x05 => F1,
+        0x06 => F2,
+        0x07 => F12,
+        0x09 => F10,
+        0x0A => F8,
+        0x0B => F6,
+        0x0C => F4,
+        0x0D => Tab,
+        0x0E => Backtick,
+        0x11 => LeftAlt,
+        0x12 => LeftShift,
+        0x14 => LeftCtrl,
+        0x15 => Q,
+        0x16 => Num1,
+        0x1A => Z,
+        0x1B => S,
+        0x1C => A,
+        0x1D => W,
+        0x1E => Num2,
+        0x21 => C,
+        0x22 => X,
+        0x23 => D,
+        0x24 => E,
+        0x25 => Num4,
+        0x26 => Num3,
+        0x29 => Space,
+        0x2A => V,
+        0x2B => F,
+        0x2C => T,
+        0x2D => R,
+        0x2E => Num5,
+        0x31 => N,
+        0x32 => B,
+        0x33 => H,
+        0x34 => G,
+        0x35 => Y,
+        0x36 => Num6,
+        0x3A => M,
+        0x3B => J,
+        0x3C => U,
+        0x3D => Num7,
+        0x3E => Num8,
+        0x41 => Comma,
+        0x42 => K,
+        0x43 => I,
+        0x44 => O,
+        0x45 => Num0,
+        0x46 => Num9,
+        0x49 => Period,
+        0x4A => Slash,
+        0x4B => L,
+        0x4C => Semicolon,
+        0x4D => P,
+        0x4E => Minus,
+        0x52 => Quote,
+        0x54 => LeftBracket,
+        0x55 => Equals,
+        0x58 => CapsLock,
+        0x59 => RightShift,
+        0x5A => Enter,
+        0x5B => RightBracket,
+        0x5D => Backslash,
+        0x61 => NonUsBackslash,
+        0x66 => Backspace,
+        0x69 => Keypad1,
+        0x6B => Keypad4,
+        0x6C => Keypad7,
+        0x70 => Keypad0,
+        0x71 => KeypadPeriod,
+        0x72 => Keypad2,
+        0x73 => Keypad5,
+        0x74 => Keypad6,
+        0x75 => Keypad8,
+        0x76 => Escape,
+        0x77 => NumLock,
+        0x78 => F11,
+        0x79 => KeypadPlus,
+        0x7A => Keypad3,
+        0x7B => KeypadMinus,
+        0x7C => KeypadStar,
+        0x7D => Keypad9,
+        0x7E => ScrollLock,
+        0x83 => F7,
+        _ => return None,
+    })
+}
+
+fn set2_extended(code: u8) -> Option<Key> {
+    use Key::*;
+    Some(match code {
+        0x11 => RightAlt,
+        0x14 => RightCtrl,
+        0x1F => LeftMeta,
+        0x27 => RightMeta,
+        0x2F => Menu,
+        0x4A => KeypadSlash,
+        0x5A => KeypadEnter,
+        0x69 => End,
+        0x6B => Left,
+        0x6C => Home,
+        0x70 => Insert,
+        0x71 => Delete,
+        0x72 => Down,
+        0x74 => Right,
+        0x75 => Up,
+        0x7A => PageDown,
+        0x7C => PrintScreen,
+        0x7D => PageUp,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn decode(set: Set, bytes: &[u8]) -> Vec<(Key, bool)> {
+        let mut decoder = Decoder::new(set);
+        bytes.iter().filter_map(|&byte| decoder.feed(byte)).collect()
+    }
+
+    test_case!(set_1, {
+        assert_eq!(decode(Set::One, &[0x1E, 0x9E]), [(Key::A, true), (Key::A, false)]);
+        assert_eq!(
+            decode(Set::One, &[0x2A, 0x10, 0x90, 0xAA]),
+            [
+                (Key::LeftShift, true),
+                (Key::Q, true),
+                (Key::Q, false),
+                (Key::LeftShift, false)
+            ]
+        );
+        assert_eq!(
+            decode(Set::One, &[0xE0, 0x48, 0xE0, 0xC8, 0xE0, 0x38]),
+            [(Key::Up, true), (Key::Up, false), (Key::RightAlt, true)]
+        );
+
+        // Print Screen's fake shifts, then Pause
+        assert_eq!(decode(Set::One, &[0xE0, 0x2A, 0xE0, 0x37]), [(Key::PrintScreen, true)]);
+        assert_eq!(
+            decode(Set::One, &[0xE1, 0x1D, 0x45, 0xE1, 0x9D, 0xC5, 0x01]),
+            [(Key::Pause, true), (Key::Pause, false), (Key::Escape, true)]
+        );
+        assert!(decode(Set::One, &[0x54, 0x5F]).is_empty());
+    });
+
+    test_case!(set_2, {
+        assert_eq!(decode(Set::Two, &[0x1C, 0xF0, 0x1C]), [(Key::A, true), (Key::A, false)]);
+        assert_eq!(
+            decode(Set::Two, &[0x83, 0x61, 0xF0, 0x61]),
+            [
+                (Key::F7, true),
+                (Key::NonUsBackslash, true),
+                (Key::NonUsBackslash, false)
+            ]
+        );
+        assert_eq!(
+            decode(Set::Two, &[0xE0, 0x75, 0xE0, 0xF0, 0x75, 0xE0, 0x14]),
+            [(Key::Up, true), (Key::Up, false), (Key::RightCtrl, true)]
+        );
+
+        // Keys next to each other aren't next to each other in set 2
+        assert_eq!(
+            decode(Set::Two, &[0x15, 0x1D, 0x24, 0x2D, 0x2C, 0x35]),
+            [
+                (Key::Q, true),
+                (Key::W, true),
+                (Key::E, true),
+                (Key::R, true),
+                (Key::T, true),
+                (Key::Y, true)
+            ]
+        );
+
+        assert_eq!(
+            decode(Set::Two, &[0xE1, 0x14, 0x77, 0xE1, 0xF0, 0x14, 0xF0, 0x77, 0x76]),
+            [(Key::Pause, true), (Key::Pause, false), (Key::Escape, true)]
+        );
+        assert!(decode(Set::Two, &[0x00, 0xF0, 0x02]).is_empty());
+    });
+}
@@ -2,4 +2,13 @@
 pub mod vga;
 
 pub mod acpi;
+pub mod ata;
+pub mod block;
+pub mod i8042;
+pub mod input;
+pub mod ioapic;
+pub mod keyboard;
+pub mod nvme;
+pub mod pci;
 pub mod serial;
+pub mod virtio;
@@ -0,0 +1,517 @@
+// NVMe controllers. Commands go through pairs of submission and completion
+// queues in memory, one pair for admin commands and one more for I/O on each
+// CPU, so CPUs don't contend for them. Each I/O queue interrupts through its
+// own MSI-X entry, and the admin queue is only used while setting up, so it's
+// polled. Handlers complete requests with their queue locked, so threads only
+// lock the I/O queues with interrupts off.
+//
+// Every active namespace is registered as a block device in its own right.
+use self::{
+    prp::Prps,
+    queue::{Command, Queue},
+};
+use crate::{
+    cpu::{
+        irq::Handler,
+        percpu::{PerCpu, CPUS},
+    },
+    drivers::{
+        block::{self, wait_for, BlockDevice, Op, Request},
+        pci::{
+            self,
+            device::{COMMAND_BUS_MASTER, COMMAND_MEMORY},
+            Bar,
+            Device,
+            Driver,
+            Irq,
+            Match,
+        },
+    },
+    ds::{Mutex, SpinLock},
+    mm::{dma::DmaBuffer, mmio, PAGE_SIZE},
+    syscall::Errno,
+    task,
+};
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::{cmp, convert::TryInto, ptr};
+use x86_64::{instructions::interrupts, PhysAddr, VirtAddr};
+
+pub mod prp;
+pub mod queue;
+
+// Controller registers
+const CAP: usize = 0x00;
+const VS: usize = 0x08;
+const CC: usize = 0x14;
+const CSTS: usize = 0x1C;
+const AQA: usize = 0x24;
+const ASQ: usize = 0x28;
+const ACQ: usize = 0x30;
+const DOORBELLS: usize = 0x1000;
+
+// Supports the NVM command set
+const CAP_NVM: u64 = 1 << 37;
+const CC_ENABLE: u32 = 1 << 0;
+// 64 byte commands and 16 byte completions
+const CC_ENTRY_SIZES: u32 = 6 << 16 | 4 << 20;
+const CSTS_READY: u32 = 1 << 0;
+const CSTS_FATAL: u32 = 1 << 1;
+
+// Admin commands
+const CREATE_SQ: u8 = 0x01;
+const CREATE_CQ: u8 = 0x05;
+const IDENTIFY: u8 = 0x06;
+const SET_FEATURES: u8 = 0x09;
+
+// What IDENTIFY describes
+const CNS_NAMESPACE: u32 = 0;
+const CNS_CONTROLLER: u32 = 1;
+const CNS_ACTIVE_NAMESPACES: u32 = 2;
+
+const FEATURE_QUEUES: u32 = 0x07;
+
+// Creating a queue
+const QUEUE_CONTIGUOUS: u32 = 1 << 0;
+const QUEUE_INTERRUPTS: u32 = 1 << 1;
+
+// NVM commands
+const FLUSH: u8 = 0x00;
+const WRITE: u8 = 0x01;
+const READ: u8 = 0x02;
+
+const ADMIN_QUEUE_SIZE: u16 = 32;
+const IO_QUEUE_SIZE: u16 = 128;
+
+const PAGE: usize = PAGE_SIZE as usize;
+
+pub static DRIVER: Driver = Driver {
+    name: "nvme",
+    matches: &[Match::Class {
+        class: 0x01,
+        subclass: 0x08,
+        prog_if: Some(0x02),
+    }],
+    probe,
+};
+
+#[derive(Clone, Copy)]
+struct Registers(VirtAddr);
+
+impl Registers {
+    fn read(self, offset: usize) -> u32 {
+        unsafe { ptr::read_volatile((self.0 + offset).as_ptr()) }
+    }
+
+    fn read_u64(self, offset: usize) -> u64 {
+        u64::from(self.read(offset + 4)) << 32 | u64::from(self.read(offset))
+    }
+
+    fn write(self, offset: usize, value: u32) {
+        unsafe { ptr::write_volatile((self.0 + offset).as_mut_ptr(), value) }
+    }
+
+    fn write_addr(self, offset: usize, addr: PhysAddr) {
+        self.write(offset, addr.as_usize() as u32);
+        self.write(offset + 4, (addr.as_usize() >> 32) as u32);
+    }
+
+    fn doorbells(self) -> VirtAddr {
+        self.0 + DOORBELLS
+    }
+}
+
+// The request and the PRP lists it needs until it's done
+type Pending = (Arc<Request>, Prps);
+type IoQueue = SpinLock<Queue<Pending>>;
+
+// Completes every request the controller has finished on a queue
+fn complete(queue: &mut Queue<Pending>) {
+    while let Some((completion, (request, _))) = queue.pop() {
+        let result = match completion.status() {
+            0 => Ok(()),
+            status => {
+                warn!("nvme: command failed with status {:#x}", status);
+                Err(Errno::EIO)
+            }
+        };
+        request.complete(result);
+    }
+}
+
+// Runs an admin command to completion, returning what it gave back
+fn run(queue: &mut Queue<()>, command: Command) -> Result<u32, Errno> {
+    queue.submit(command, ()).map_err(|()| Errno::EIO)?;
+    let mut completion = None;
+    wait_for(|| {
+        completion = queue.pop();
+        completion.is_some()
+    })?;
+
+    let (completion, ()) = completion.unwrap();
+    if completion.status() != 0 {
+        warn!(
+            "nvme: admin command {:#x} failed with status {:#x}",
+            command.opcode,
+            completion.status()
+        );
+        return Err(Errno::EIO);
+    }
+    Ok(completion.result)
+}
+
+fn identify(queue: &mut Queue<()>, cns: u32, nsid: u32) -> Result<DmaBuffer, Errno> {
+    let buffer = DmaBuffer::new(PAGE)?;
+    run(
+        queue,
+        Command {
+            opcode: IDENTIFY,
+            nsid,
+            prp1: buffer.phys().as_usize() as u64,
+            cdw10: cns,
+            ..Command::default()
+        },
+    )?;
+    Ok(buffer)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+struct Controller {
+    admin: Mutex<Queue<()>>,
+    // Indexed by CPU, and the queue with ID i is at i - 1
+    queues: Vec<Arc<IoQueue>>,
+    // Whether there's a write cache for flushes to write back
+    volatile_cache: bool,
+    // Empty if the queues are only polled
+    irqs: Vec<Irq>,
+}
+
+pub struct Namespace {
+    name: String,
+    controller: Arc<Controller>,
+    nsid: u32,
+    sectors: u64,
+    sector_size: usize,
+}
+
+fn probe(device: &Arc<Device>) -> Result<(), Errno> {
+    let (addr, size) = match device.bars[0] {
+        Some(Bar::Memory { addr, size, .. }) => (addr, size),
+        _ => return Err(Errno::ENODEV),
+    };
+    device.update_command(COMMAND_MEMORY | COMMAND_BUS_MASTER, 0);
+    let regs = Registers(mmio::map(PhysAddr::new(addr as usize), size as usize).map_err(|_| Errno::ENOMEM)?);
+
+    // Only controllers that take the NVM command set and 4 KiB pages
+    let cap = regs.read_u64(CAP);
+    if cap & CAP_NVM == 0 || (cap >> 48) & 0xF != 0 {
+        return Err(Errno::ENODEV);
+    }
+    let stride = 4 << ((cap >> 32) & 0xF) as usize;
+    let entries = cmp::min((cap & 0xFFFF) + 1, u64::from(IO_QUEUE_SIZE)) as u16;
+
+    disable(regs)?;
+    let mut admin = Queue::new(0, ADMIN_QUEUE_SIZE, regs.doorbells(), stride)?;
+    let last = u32::from(ADMIN_QUEUE_SIZE - 1);
+    regs.write(AQA, last << 16 | last);
+    regs.write_addr(ASQ, admin.submission_addr());
+    regs.write_addr(ACQ, admin.completion_addr());
+    regs.write(CC, CC_ENTRY_SIZES | CC_ENABLE);
+
+    // The admin queue can't be freed until the controller stops using it
+    let (controller, count) = match wait_for(|| regs.read(CSTS) & (CSTS_READY | CSTS_FATAL) != 0)
+        .and_then(|()| setup(device, regs, stride, entries, &mut admin))
+    {
+        Ok((queues, volatile_cache, irqs, count)) => {
+            let controller = Arc::new(Controller {
+                admin: Mutex::new(admin),
+                queues,
+                volatile_cache,
+                irqs,
+            });
+            (controller, count)
+        }
+        Err(errno) => {
+            let _ = disable(regs);
+            return Err(errno);
+        }
+    };
+
+    // Namespace IDs are listed from version 1.1, before that they're every
+    // one up to the number there are. Without a list there's nothing to add.
+    let version = regs.read(VS);
+    let nsids: Vec<u32> = if version >= 0x0001_0100 {
+        identify(&mut controller.admin.lock(), CNS_ACTIVE_NAMESPACES, 0)
+            .map(|list| {
+                (0..PAGE / 4)
+                    .map(|i| read_u32(list.as_slice(), 4 * i))
+                    .take_while(|&nsid| nsid != 0)
+                    .collect()
+            })
+            .unwrap_or_default()
+    } else {
+        (1..=count).collect()
+    };
+
+    let mut namespaces = Vec::new();
+    for nsid in nsids {
+        match add_namespace(&controller, nsid) {
+            Ok(Some(namespace)) => namespaces.push(namespace),
+            Ok(None) => {}
+            Err(errno) => warn!("nvme: namespace {} of {} failed ({:?})", nsid, device.address, errno),
+        }
+    }
+    if namespaces.is_empty() {
+        let _ = disable(regs);
+        return Err(Errno::ENODEV);
+    }
+    for namespace in namespaces {
+        block::register(namespace);
+    }
+    Ok(())
+}
+
+// Stops the controller, after which it no longer touches any queue
+fn disable(regs: Registers) -> Result<(), Errno> {
+    regs.write(CC, regs.read(CC) & !CC_ENABLE);
+    wait_for(|| regs.read(CSTS) & CSTS_READY == 0)
+}
+
+// Identifies the controller and creates its I/O queues, returning them,
+// whether it has a write cache, their interrupts and how many namespaces
+// there can be
+fn setup(
+    device: &Arc<Device>,
+    regs: Registers,
+    stride: usize,
+    entries: u16,
+    admin: &mut Queue<()>,
+) -> Result<(Vec<Arc<IoQueue>>, bool, Vec<Irq>, u32), Errno> {
+    if regs.read(CSTS) & CSTS_FATAL != 0 {
+        return Err(Errno::EIO);
+    }
+
+    let data = identify(admin, CNS_CONTROLLER, 0)?;
+    let data = data.as_slice();
+    let model = String::from_utf8_lossy(&data[24..64]);
+    // The largest transfer as a power of two of pages, with 0 for no limit
+    let mdts = data[77];
+    if mdts != 0 && (PAGE << mdts) < block::MAX_TRANSFER {
+        warn!("nvme: {} only transfers {} bytes at once", device.address, PAGE << mdts);
+        return Err(Errno::ENODEV);
+    }
+    let namespaces = read_u32(data, 516);
+    let volatile_cache = data[525] & 1 != 0;
+
+    // The controller may grant fewer queues than asked for, and the count
+    // for each kind is one less than the number
+    let wanted = CPUS.len() as u32 - 1;
+    let granted = run(
+        admin,
+        Command {
+            opcode: SET_FEATURES,
+            cdw10: FEATURE_QUEUES,
+            cdw11: wanted << 16 | wanted,
+            ..Command::default()
+        },
+    )?;
+    let count = cmp::min(wanted, cmp::min(granted & 0xFFFF, granted >> 16)) + 1;
+
+    let mut queues = Vec::new();
+    for id in 1..=count as u16 {
+        queues.push(Arc::new(SpinLock::new(Queue::new(
+            id,
+            entries,
+            regs.doorbells(),
+            stride,
+        )?)));
+    }
+
+    // The first MSI-X entry would be the admin queue's
+    let mut handlers: Vec<Handler> = Vec::new();
+    handlers.push(Arc::new(|| {}));
+    for queue in &queues {
+        let queue = queue.clone();
+        handlers.push(Arc::new(move || complete(&mut queue.lock())));
+    }
+    let irqs = pci::request_msix(device, handlers).unwrap_or_else(|errno| {
+        warn!("nvme: no interrupts for {} ({:?}), polling only", device.address, errno);
+        Vec::new()
+    });
+
+    for queue in &queues {
+        let (id, size, submissions, completions) = interrupts::without_interrupts(|| {
+            let queue = queue.lock();
+            (
+                queue.id(),
+                queue.size(),
+                queue.submission_addr(),
+                queue.completion_addr(),
+            )
+        });
+        let cdw10 = u32::from(size - 1) << 16 | u32::from(id);
+        let interrupts = if irqs.is_empty() {
+            0
+        } else {
+            u32::from(id) << 16 | QUEUE_INTERRUPTS
+        };
+        run(
+            admin,
+            Command {
+                opcode: CREATE_CQ,
+                prp1: completions.as_usize() as u64,
+                cdw10,
+                cdw11: interrupts | QUEUE_CONTIGUOUS,
+                ..Command::default()
+            },
+        )?;
+        run(
+            admin,
+            Command {
+                opcode: CREATE_SQ,
+                prp1: submissions.as_usize() as u64,
+                cdw10,
+                cdw11: u32::from(id) << 16 | QUEUE_CONTIGUOUS,
+                ..Command::default()
+            },
+        )?;
+    }
+
+    info!(
+        "nvme: {} is {}, {} queues of {}{}",
+        device.address,
+        model.trim_end(),
+        queues.len(),
+        entries,
+        if volatile_cache { " with a write cache" } else { "" }
+    );
+    Ok((queues, volatile_cache, irqs, namespaces))
+}
+
+// Identifies a namespace. None if it's inactive or formatted in a way we
+// can't use.
+fn add_namespace(controller: &Arc<Controller>, nsid: u32) -> Result<Option<Arc<Namespace>>, Errno> {
+    let data = identify(&mut controller.admin.lock(), CNS_NAMESPACE, nsid)?;
+    let data = data.as_slice();
+    let sectors = u64::from_le_bytes(data[0..8].try_into().unwrap());
+    if sectors == 0 {
+        return Ok(None);
+    }
+
+    // The format in use, which mustn't have metadata alongside the data
+    let format = read_u32(data, 128 + 4 * usize::from(data[26] & 0xF));
+    let sector_size = 1usize << ((format >> 16) & 0xFF);
+    if format & 0xFFFF != 0 || sector_size < block::SECTOR_SIZE || sector_size > PAGE {
+        warn!("nvme: namespace {} has an unsupported format {:#x}", nsid, format);
+        return Ok(None);
+    }
+
+    let name = block::next_name("nv");
+    info!(
+        "nvme: {} is namespace {}, {} sectors of {} bytes",
+        name, nsid, sectors, sector_size
+    );
+    Ok(Some(Arc::new(Namespace {
+        name,
+        controller: controller.clone(),
+        nsid,
+        sectors,
+        sector_size,
+    })))
+}
+
+impl BlockDevice for Namespace {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn submit(&self, request: Arc<Request>) -> Result<(), Errno> {
+        let opcode = match request.op {
+            Op::Read => READ,
+            Op::Write => WRITE,
+            // Without a write cache everything is already on the disk
+            Op::Flush if !self.controller.volatile_cache => {
+                request.complete(Ok(()));
+                return Ok(());
+            }
+            Op::Flush => FLUSH,
+        };
+
+        let mut command = Command {
+            opcode,
+            nsid: self.nsid,
+            ..Command::default()
+        };
+        let prps = if request.op == Op::Flush {
+            Prps::none()
+        } else {
+            let len = request.buffer.len();
+            let blocks = (len / self.sector_size) as u64;
+            let end = request.sector.checked_add(blocks).ok_or(Errno::EINVAL)?;
+            if request.buffer.is_empty() || len % self.sector_size != 0 || end > self.sectors {
+                return Err(Errno::EINVAL);
+            }
+            command.cdw10 = request.sector as u32;
+            command.cdw11 = (request.sector >> 32) as u32;
+            command.cdw12 = blocks as u32 - 1;
+            Prps::new(&[request.buffer.frames()], len)?
+        };
+        command.prp1 = prps.prp1;
+        command.prp2 = prps.prp2;
+
+        let queues = &self.controller.queues;
+        let queue = &queues[PerCpu::current().index() % queues.len()];
+        let mut pending = (request, prps);
+        loop {
+            match interrupts::without_interrupts(|| queue.lock().submit(command, pending)) {
+                Ok(()) => return Ok(()),
+                Err(back) => pending = back,
+            }
+
+            // Wait for the controller to finish something
+            self.poll();
+            task::yield_now();
+        }
+    }
+
+    fn poll(&self) {
+        for queue in &self.controller.queues {
+            interrupts::without_interrupts(|| complete(&mut queue.lock()));
+        }
+    }
+
+    fn has_interrupt(&self) -> bool {
+        !self.controller.irqs.is_empty()
+    }
+}
+
+pub fn init() {
+    pci::register(&DRIVER);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::block::testing;
+
+    // Against the scratch disk the test-args in Cargo.toml attach to an NVMe
+    // controller
+    test_case!(read_write_disk, {
+        let disk = testing::scratch_disk("nv");
+        assert!(disk.has_interrupt());
+
+        // Across several pages, so through a PRP list
+        let len = cmp::min(32 * disk.sector_size(), block::MAX_TRANSFER);
+        testing::read_write(&disk, len);
+    });
+}
@@ -0,0 +1,126 @@
+// Physical region pages, which is how commands point at their data. The first
+// entry has the start of the data and the second the page after it, or for
+// anything longer than two pages, a list of the rest. Lists take a page each,
+// and a full one goes on to the next through its last entry.
+use crate::{
+    mm::{dma::DmaBuffer, PAGE_SIZE},
+    syscall::Errno,
+};
+use alloc::vec::Vec;
+use core::ptr;
+use x86_64::structures::paging::frame::PhysFrameRange;
+
+const PAGE: usize = PAGE_SIZE as usize;
+const ENTRIES: usize = PAGE / 8;
+
+pub struct Prps {
+    pub prp1: u64,
+    pub prp2: u64,
+    // Kept until the controller is done with the command
+    lists: Vec<DmaBuffer>,
+}
+
+impl Prps {
+    // For commands without data
+    pub fn none() -> Prps {
+        Prps {
+            prp1: 0,
+            prp2: 0,
+            lists: Vec::new(),
+        }
+    }
+
+    // Describes len bytes spread over the frames of ranges, in order
+    pub fn new(ranges: &[PhysFrameRange], len: usize) -> Result<Prps, Errno> {
+        let count = (len + PAGE - 1) / PAGE;
+        let pages: Vec<u64> = ranges
+            .iter()
+            .flat_map(|&range| range)
+            .map(|frame| frame.start_address().as_usize() as u64)
+            .take(count)
+            .collect();
+        if count == 0 || pages.len() < count {
+            return Err(Errno::EINVAL);
+        }
+
+        let mut prps = Prps {
+            prp1: pages[0],
+            prp2: 0,
+            lists: Vec::new(),
+        };
+        match pages.len() {
+            1 => {}
+            2 => prps.prp2 = pages[1],
+            _ => {
+                let mut rest = &pages[1..];
+                while !rest.is_empty() {
+                    let list = DmaBuffer::new(PAGE)?;
+                    let take = if rest.len() <= ENTRIES { rest.len() } else { ENTRIES - 1 };
+                    for (i, &page) in rest[..take].iter().enumerate() {
+                        unsafe { ptr::write_volatile(list.as_mut_ptr(8 * i), page) };
+                    }
+
+                    let addr = list.phys().as_usize() as u64;
+                    match prps.lists.last() {
+                        Some(previous) => unsafe { ptr::write_volatile(previous.as_mut_ptr(PAGE - 8), addr) },
+                        None => prps.prp2 = addr,
+                    }
+                    prps.lists.push(list);
+                    rest = &rest[take..];
+                }
+            }
+        }
+        Ok(prps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mm::phys_to_kernel_virt;
+    use x86_64::{structures::paging::PhysFrame, PhysAddr};
+
+    // Frames that are never touched, only pointed at
+    fn frames(start: usize, count: usize) -> PhysFrameRange {
+        let start = PhysFrame::containing_address(PhysAddr::new(start));
+        PhysFrame::range(start, start + count)
+    }
+
+    fn entry(list: u64, index: usize) -> u64 {
+        let list = phys_to_kernel_virt(PhysAddr::new(list as usize));
+        unsafe { ptr::read_volatile((list + 8 * index).as_ptr()) }
+    }
+
+    test_case!(lists, {
+        let prps = Prps::new(&[frames(0x10000, 4)], 100).unwrap();
+        assert_eq!((prps.prp1, prps.prp2), (0x10000, 0));
+
+        let split = [frames(0x10000, 1), frames(0x80000, 8)];
+        let prps = Prps::new(&split, 2 * PAGE).unwrap();
+        assert_eq!((prps.prp1, prps.prp2), (0x10000, 0x80000));
+        assert!(prps.lists.is_empty());
+
+        // The rest of the pages go in a list
+        let prps = Prps::new(&split, 4 * PAGE + 1).unwrap();
+        assert_eq!(prps.prp1, 0x10000);
+        assert_eq!(prps.lists.len(), 1);
+        let pages: Vec<u64> = (0..5).map(|i| entry(prps.prp2, i)).collect();
+        assert_eq!(pages, [0x80000, 0x81000, 0x82000, 0x83000, 0]);
+
+        // Which runs on to another when it fills up
+        let prps = Prps::new(&[frames(0x100_0000, ENTRIES + 10)], (ENTRIES + 10) * PAGE).unwrap();
+        assert_eq!(prps.lists.len(), 2);
+        assert_eq!(entry(prps.prp2, 0), 0x100_1000);
+        assert_eq!(
+            entry(prps.prp2, ENTRIES - 2),
+            0x100_0000 + (ENTRIES - 1) as u64 * PAGE as u64
+        );
+        let next = entry(prps.prp2, ENTRIES - 1);
+        assert_eq!(next, prps.lists[1].phys().as_usize() as u64);
+        assert_eq!(entry(next, 0), 0x100_0000 + ENTRIES as u64 * PAGE as u64);
+        assert_eq!(entry(next, 9), 0x100_0000 + (ENTRIES + 9) as u64 * PAGE as u64);
+
+        assert_eq!(Prps::new(&split, 10 * PAGE).err(), Some(Errno::EINVAL));
+        assert_eq!(Prps::new(&split, 0).err(), Some(Errno::EINVAL));
+    });
+}
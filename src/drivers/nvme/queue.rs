@@ -0,0 +1,215 @@
+// A submission queue and the completion queue it reports to. Commands go in
+// at the tail of the submission queue, and a write of the new tail to its
+// doorbell tells the controller. Completions carry a phase bit that flips on
+// each pass around their queue, which tells new ones from those already seen.
+use crate::{mm::dma::DmaBuffer, syscall::Errno};
+use alloc::vec::Vec;
+use core::{
+    mem,
+    ptr,
+    sync::atomic::{fence, Ordering},
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Command {
+    pub opcode: u8,
+    pub flags: u8,
+    // Set by the queue
+    pub cid: u16,
+    pub nsid: u32,
+    pub reserved: u64,
+    pub metadata: u64,
+    pub prp1: u64,
+    pub prp2: u64,
+    pub cdw10: u32,
+    pub cdw11: u32,
+    pub cdw12: u32,
+    pub cdw13: u32,
+    pub cdw14: u32,
+    pub cdw15: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Completion {
+    pub result: u32,
+    pub reserved: u32,
+    pub sq_head: u16,
+    pub sq_id: u16,
+    pub cid: u16,
+    // The phase in the low bit, then the status, which is zero for success
+    pub status: u16,
+}
+
+impl Completion {
+    pub fn status(&self) -> u16 {
+        self.status >> 1
+    }
+
+    fn phase(&self) -> bool {
+        self.status & 1 != 0
+    }
+}
+
+pub struct Queue<T> {
+    id: u16,
+    size: u16,
+    submissions: DmaBuffer,
+    completions: DmaBuffer,
+    tail: u16,
+    head: u16,
+    phase: bool,
+    sq_doorbell: VirtAddr,
+    cq_doorbell: VirtAddr,
+    // What each outstanding command is for, by command ID. There's one less
+    // than the size of the queue, so the submission queue can't overflow.
+    pending: Vec<Option<T>>,
+}
+
+impl<T> Queue<T> {
+    // Doorbells is where the controller's doorbell registers start, stride
+    // bytes apart
+    pub fn new(id: u16, size: u16, doorbells: VirtAddr, stride: usize) -> Result<Queue<T>, Errno> {
+        assert!(size >= 2);
+        Ok(Queue {
+            id,
+            size,
+            submissions: DmaBuffer::new(usize::from(size) * mem::size_of::<Command>())?,
+            completions: DmaBuffer::new(usize::from(size) * mem::size_of::<Completion>())?,
+            tail: 0,
+            head: 0,
+            phase: true,
+            sq_doorbell: doorbells + 2 * usize::from(id) * stride,
+            cq_doorbell: doorbells + (2 * usize::from(id) + 1) * stride,
+            pending: (1..size).map(|_| None).collect(),
+        })
+    }
+
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn submission_addr(&self) -> PhysAddr {
+        self.submissions.phys()
+    }
+
+    pub fn completion_addr(&self) -> PhysAddr {
+        self.completions.phys()
+    }
+
+    // Hands the command to the controller, or gives pending back if there
+    // are already as many outstanding as the queue takes
+    pub fn submit(&mut self, mut command: Command, pending: T) -> Result<(), T> {
+        let cid = match self.pending.iter().position(Option::is_none) {
+            Some(cid) => cid,
+            None => return Err(pending),
+        };
+        command.cid = cid as u16;
+        self.pending[cid] = Some(pending);
+
+        let offset = usize::from(self.tail) * mem::size_of::<Command>();
+        unsafe { ptr::write_volatile(self.submissions.as_mut_ptr(offset), command) };
+        self.tail = (self.tail + 1) % self.size;
+
+        // The command must be there before the controller goes looking
+        fence(Ordering::SeqCst);
+        unsafe { ptr::write_volatile(self.sq_doorbell.as_mut_ptr(), u32::from(self.tail)) };
+        Ok(())
+    }
+
+    // The next completion the controller has posted, and what its command
+    // was for
+    pub fn pop(&mut self) -> Option<(Completion, T)> {
+        loop {
+            let offset = usize::from(self.head) * mem::size_of::<Completion>();
+            let completion: Completion = unsafe { ptr::read_volatile(self.completions.as_mut_ptr(offset)) };
+            if completion.phase() != self.phase {
+                return None;
+            }
+
+            self.head += 1;
+            if self.head == self.size {
+                self.head = 0;
+                self.phase = !self.phase;
+            }
+            unsafe { ptr::write_volatile(self.cq_doorbell.as_mut_ptr(), u32::from(self.head)) };
+
+            // IDs the queue didn't hand out are skipped
+            if let Some(pending) = self.pending.get_mut(usize::from(completion.cid)).and_then(Option::take) {
+                return Some((completion, pending));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mm::phys_to_kernel_virt;
+
+    // Plays the controller's part, posting a completion at the given slot
+    fn post(queue: &Queue<u32>, slot: u16, cid: u16, status: u16, phase: bool) {
+        let completion = Completion {
+            result: 0,
+            reserved: 0,
+            sq_head: 0,
+            sq_id: queue.id(),
+            cid,
+            status: status << 1 | phase as u16,
+        };
+        let offset = usize::from(slot) * mem::size_of::<Completion>();
+        unsafe { ptr::write_volatile(queue.completions.as_mut_ptr(offset), completion) };
+    }
+
+    fn doorbell(doorbells: VirtAddr, index: usize) -> u32 {
+        unsafe { ptr::read_volatile((doorbells + 4 * index).as_ptr()) }
+    }
+
+    test_case!(submissions_and_completions, {
+        let scratch = DmaBuffer::new(4096).unwrap();
+        let doorbells = phys_to_kernel_virt(scratch.phys());
+        let mut queue: Queue<u32> = Queue::new(1, 4, doorbells, 4).unwrap();
+
+        let command = Command {
+            opcode: 2,
+            nsid: 1,
+            ..Command::default()
+        };
+        assert_eq!(queue.submit(command, 10), Ok(()));
+        assert_eq!(queue.submit(command, 11), Ok(()));
+        assert_eq!(queue.submit(command, 12), Ok(()));
+        assert_eq!(queue.submit(command, 13), Err(13));
+        assert_eq!(doorbell(doorbells, 2), 3);
+        let second: Command = unsafe { ptr::read_volatile(queue.submissions.as_mut_ptr(mem::size_of::<Command>())) };
+        assert_eq!((second.opcode, second.cid, second.nsid), (2, 1, 1));
+
+        // Completions come back in any order
+        assert!(queue.pop().is_none());
+        post(&queue, 0, 2, 0, true);
+        post(&queue, 1, 0, 0x81, true);
+        let (completion, pending) = queue.pop().unwrap();
+        assert_eq!((completion.cid, completion.status(), pending), (2, 0, 12));
+        let (completion, pending) = queue.pop().unwrap();
+        assert_eq!((completion.status(), pending), (0x81, 10));
+        assert!(queue.pop().is_none());
+        assert_eq!(doorbell(doorbells, 3), 2);
+
+        // Around the end, where the phase flips. The entry after the one
+        // with an unknown ID is left from the last pass.
+        assert_eq!(queue.submit(command, 14), Ok(()));
+        post(&queue, 2, 1, 0, true);
+        post(&queue, 3, 0, 0, true);
+        post(&queue, 0, 5, 0, false);
+        assert_eq!(queue.pop().map(|(_, pending)| pending), Some(11));
+        assert_eq!(queue.pop().map(|(_, pending)| pending), Some(14));
+        assert!(queue.pop().is_none());
+        assert_eq!(doorbell(doorbells, 3), 1);
+        assert_eq!(doorbell(doorbells, 2), 0);
+    });
+}
@@ -0,0 +1,225 @@
+// Configuration space access. The legacy mechanism goes through a pair of I/O
+// ports and only reaches the first 256 bytes of each function. ECAM maps all
+// 4KiB of every function into memory, at addresses from the ACPI MCFG table.
+use crate::{
+    ds::{RwSpinLock, SpinLock},
+    mm::mmio,
+};
+use acpi::PciConfigRegions;
+use alloc::collections::BTreeMap;
+use core::{fmt, ptr};
+use x86_64::{instructions::port::Port, PhysAddr, VirtAddr};
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+// Size of each function's configuration space, with and without ECAM
+pub const CONFIG_SIZE: u16 = 0x1000;
+pub const LEGACY_CONFIG_SIZE: u16 = 0x100;
+
+// ECAM space for all 32 devices of a bus, with 8 functions each
+const BUS_SIZE: usize = 32 * 8 * CONFIG_SIZE as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Address {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Address {
+    pub fn new(bus: u8, device: u8, function: u8) -> Address {
+        Address {
+            segment: 0,
+            bus,
+            device,
+            function,
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04x}:{:02x}:{:02x}.{}", self.segment, self.bus, self.device, self.function)
+    }
+}
+
+// The two ports are a single address and data pair, so accesses through them
+// are serialised
+static PORTS: SpinLock<()> = SpinLock::new(());
+static ECAM: RwSpinLock<Option<PciConfigRegions>> = RwSpinLock::new(None);
+
+lazy_static! {
+    // Uncached mappings of ECAM space, made a bus at a time as they're first
+    // used, by segment and bus
+    static ref BUSES: RwSpinLock<BTreeMap<(u16, u8), VirtAddr>> = RwSpinLock::new(BTreeMap::new());
+}
+
+pub fn init(regions: Option<PciConfigRegions>) {
+    match regions {
+        Some(regions) => {
+            debug!("pci: using ecam");
+            *ECAM.write() = Some(regions);
+        }
+        None => debug!("pci: no mcfg table, using port i/o"),
+    }
+}
+
+pub fn has_ecam() -> bool {
+    ECAM.read().is_some()
+}
+
+// Where the function's space is mapped, if it has one
+fn ecam_address(address: Address, offset: u16) -> Option<VirtAddr> {
+    let function = usize::from(address.device) << 15 | usize::from(address.function) << 12;
+    let function_offset = function + usize::from(offset);
+    let key = (address.segment, address.bus);
+    let ecam = ECAM.read();
+    let regions = ecam.as_ref()?;
+    if let Some(&base) = BUSES.read().get(&key) {
+        return Some(base + function_offset);
+    }
+
+    let phys = regions.physical_address(address.segment, address.bus, 0, 0)?;
+    let mut buses = BUSES.write();
+    let base = match buses.get(&key) {
+        Some(&base) => base,
+        None => match mmio::map(PhysAddr::new(phys), BUS_SIZE) {
+            Ok(base) => {
+                buses.insert(key, base);
+                base
+            }
+            Err(err) => {
+                warn!("pci: can't map ecam for bus {:04x}:{:02x} ({:?})", address.segment, address.bus, err);
+                return None;
+            }
+        },
+    };
+    Some(base + function_offset)
+}
+
+fn select(address: Address, offset: u16) {
+    debug_assert_eq!(address.segment, 0);
+    let value = 0x8000_0000
+        | u32::from(address.bus) << 16
+        | u32::from(address.device) << 11
+        | u32::from(address.function) << 8
+        | u32::from(offset & 0xFC);
+    unsafe { Port::<u32>::new(CONFIG_ADDRESS).write(value) };
+}
+
+// Reads size bytes at offset, which must be aligned to size. Reads past what
+// can be reached give all ones, like a missing device.
+pub fn read(address: Address, offset: u16, size: u16) -> u32 {
+    debug_assert!(offset % size == 0 && offset < CONFIG_SIZE);
+
+    if let Some(virt) = ecam_address(address, offset) {
+        return unsafe {
+            match size {
+                1 => u32::from(ptr::read_volatile(virt.as_ptr::<u8>())),
+                2 => u32::from(ptr::read_volatile(virt.as_ptr::<u16>())),
+                _ => ptr::read_volatile(virt.as_ptr::<u32>()),
+            }
+        };
+    }
+    if offset >= LEGACY_CONFIG_SIZE || address.segment != 0 {
+        return !0 >> (32 - 8 * u32::from(size));
+    }
+
+    let _lock = PORTS.lock();
+    select(address, offset);
+    let port = CONFIG_DATA + (offset & 3);
+    unsafe {
+        match size {
+            1 => u32::from(Port::<u8>::new(port).read()),
+            2 => u32::from(Port::<u16>::new(port).read()),
+            _ => Port::<u32>::new(port).read(),
+        }
+    }
+}
+
+// Writes size bytes at offset. Writes that can't be reached are dropped.
+pub fn write(address: Address, offset: u16, size: u16, value: u32) {
+    debug_assert!(offset % size == 0 && offset < CONFIG_SIZE);
+
+    if let Some(virt) = ecam_address(address, offset) {
+        unsafe {
+            match size {
+                1 => ptr::write_volatile(virt.as_mut_ptr::<u8>(), value as u8),
+                2 => ptr::write_volatile(virt.as_mut_ptr::<u16>(), value as u16),
+                _ => ptr::write_volatile(virt.as_mut_ptr::<u32>(), value),
+            }
+        }
+        return;
+    }
+    if offset >= LEGACY_CONFIG_SIZE || address.segment != 0 {
+        return;
+    }
+
+    let _lock = PORTS.lock();
+    select(address, offset);
+    let port = CONFIG_DATA + (offset & 3);
+    unsafe {
+        match size {
+            1 => Port::<u8>::new(port).write(value as u8),
+            2 => Port::<u16>::new(port).write(value as u16),
+            _ => Port::<u32>::new(port).write(value),
+        }
+    }
+}
+
+pub fn read_u8(address: Address, offset: u16) -> u8 {
+    read(address, offset, 1) as u8
+}
+
+pub fn read_u16(address: Address, offset: u16) -> u16 {
+    read(address, offset, 2) as u16
+}
+
+pub fn read_u32(address: Address, offset: u16) -> u32 {
+    read(address, offset, 4)
+}
+
+pub fn write_u8(address: Address, offset: u16, value: u8) {
+    write(address, offset, 1, u32::from(value))
+}
+
+pub fn write_u16(address: Address, offset: u16, value: u16) {
+    write(address, offset, 2, u32::from(value))
+}
+
+pub fn write_u32(address: Address, offset: u16, value: u32) {
+    write(address, offset, 4, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mm::addr_space::AddrSpace;
+    use x86_64::structures::paging::PageTableFlags;
+
+    test_case!(ports_and_ecam_agree, {
+        let host = Address::new(0, 0, 0);
+        assert_ne!(read_u16(host, 0), 0xFFFF);
+        assert_eq!(read_u16(host, 0), read_u32(host, 0) as u16);
+        assert_eq!(read_u8(host, 3), (read_u32(host, 0) >> 24) as u8);
+
+        // q35 always has an MCFG table
+        assert!(has_ecam());
+        let ecam = read_u32(host, 0);
+
+        // Through an uncached mapping of the whole bus
+        let phys = ECAM.read().as_ref().unwrap().physical_address(0, 0, 1, 0).unwrap();
+        let virt = ecam_address(Address::new(0, 1, 0), 4).unwrap();
+        let (mapped, flags) = AddrSpace::kernel().translate_page(virt).unwrap();
+        assert_eq!(mapped, PhysAddr::new(phys + 4));
+        assert!(flags.contains(PageTableFlags::NO_CACHE));
+        assert_eq!(ecam_address(host, 0), Some(virt - 0x8004));
+
+        let regions = ECAM.write().take();
+        assert_eq!(read_u32(host, 0), ecam);
+        assert_eq!(read_u32(host, LEGACY_CONFIG_SIZE), !0);
+        *ECAM.write() = regions;
+    });
+}
@@ -0,0 +1,388 @@
+// Decoding of a function's configuration header: its identity, BARs and
+// capability lists.
+use super::{
+    config::{self, Address, LEGACY_CONFIG_SIZE},
+    driver::Driver,
+};
+use crate::ds::SpinLock;
+use alloc::vec::Vec;
+use core::fmt;
+
+// Header offsets shared by every header type
+pub const VENDOR_ID: u16 = 0x00;
+pub const DEVICE_ID: u16 = 0x02;
+pub const COMMAND: u16 = 0x04;
+pub const STATUS: u16 = 0x06;
+pub const REVISION: u16 = 0x08;
+pub const HEADER_TYPE: u16 = 0x0E;
+pub const BAR0: u16 = 0x10;
+pub const CAPABILITIES: u16 = 0x34;
+pub const INTERRUPT_LINE: u16 = 0x3C;
+pub const INTERRUPT_PIN: u16 = 0x3D;
+
+// Type 1 (bridge) header
+pub const SECONDARY_BUS: u16 = 0x19;
+pub const SUBORDINATE_BUS: u16 = 0x1A;
+
+pub const COMMAND_IO: u16 = 1 << 0;
+pub const COMMAND_MEMORY: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+const HEADER_MULTIFUNCTION: u8 = 0x80;
+
+// Extended capabilities start right after the legacy space
+const EXTENDED_CAPABILITIES: u16 = LEGACY_CONFIG_SIZE;
+// Bounds list walks on broken hardware
+const MAX_CAPABILITIES: usize = 48;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderType {
+    General,
+    PciBridge,
+    CardBusBridge,
+    Unknown(u8),
+}
+
+impl HeaderType {
+    fn from_raw(raw: u8) -> HeaderType {
+        match raw & !HEADER_MULTIFUNCTION {
+            0 => HeaderType::General,
+            1 => HeaderType::PciBridge,
+            2 => HeaderType::CardBusBridge,
+            other => HeaderType::Unknown(other),
+        }
+    }
+
+    fn bar_count(self) -> usize {
+        match self {
+            HeaderType::General => 6,
+            HeaderType::PciBridge => 2,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        addr: u64,
+        size: u64,
+        prefetchable: bool,
+        // Takes up two slots
+        wide: bool,
+    },
+    Io {
+        port: u32,
+        size: u32,
+    },
+}
+
+impl Bar {
+    // Decodes a BAR from its value and what reads back after writing all
+    // ones, with the upper half for 64-bit BARs. None if it's unimplemented.
+    pub fn decode(low: u32, low_mask: u32, high: Option<(u32, u32)>) -> Option<Bar> {
+        if low & 1 != 0 {
+            let mask = low_mask & !0x3;
+            if mask == 0 {
+                return None;
+            }
+
+            // The upper half of I/O BARs doesn't have to be implemented
+            let size = !(mask | 0xFFFF_0000) + 1;
+            return Some(Bar::Io {
+                port: low & !0x3,
+                size,
+            });
+        }
+
+        // A 64-bit BAR can have the whole low half free for sizes of 4GiB up
+        if low_mask & !0xF == 0 && high.map_or(true, |(_, high_mask)| high_mask == 0) {
+            return None;
+        }
+        let (high, high_mask) = high.unwrap_or((0, !0));
+        let mask = u64::from(high_mask) << 32 | u64::from(low_mask & !0xF);
+
+        Some(Bar::Memory {
+            addr: u64::from(high) << 32 | u64::from(low & !0xF),
+            size: (!mask).wrapping_add(1),
+            prefetchable: low & 0x8 != 0,
+            wide: (low >> 1) & 0x3 == 0x2,
+        })
+    }
+}
+
+impl fmt::Display for Bar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Bar::Memory {
+                addr,
+                size,
+                prefetchable,
+                wide,
+            } => write!(
+                f,
+                "mem {:#x} size {:#x}{}{}",
+                addr,
+                size,
+                if wide { " 64-bit" } else { "" },
+                if prefetchable { " prefetchable" } else { "" }
+            ),
+            Bar::Io { port, size } => write!(f, "i/o {:#x} size {:#x}", port, size),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u16,
+    // Where it starts in the configuration space
+    pub offset: u16,
+}
+
+pub struct Device {
+    pub address: Address,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: HeaderType,
+    pub multifunction: bool,
+    pub bars: [Option<Bar>; 6],
+    pub capabilities: Vec<Capability>,
+    // PCI Express extended capabilities, only reachable through ECAM
+    pub extended_capabilities: Vec<Capability>,
+    pub interrupt_line: u8,
+    // 1 to 4 for INTA# to INTD#, 0 for none
+    pub interrupt_pin: u8,
+    // For bridges, the range of buses behind it
+    pub buses: Option<(u8, u8)>,
+    // Bridges between this and the root bus
+    pub depth: usize,
+    pub(super) driver: SpinLock<Option<&'static Driver>>,
+}
+
+impl Device {
+    // Reads the header of a function, None if there isn't one
+    pub fn probe(address: Address, depth: usize) -> Option<Device> {
+        let vendor_id = config::read_u16(address, VENDOR_ID);
+        if vendor_id == 0xFFFF {
+            return None;
+        }
+
+        let class = config::read_u32(address, REVISION);
+        let raw_header = config::read_u8(address, HEADER_TYPE);
+        let header_type = HeaderType::from_raw(raw_header);
+        let buses = match header_type {
+            HeaderType::PciBridge => Some((
+                config::read_u8(address, SECONDARY_BUS),
+                config::read_u8(address, SUBORDINATE_BUS),
+            )),
+            _ => None,
+        };
+
+        let mut device = Device {
+            address,
+            vendor_id,
+            device_id: config::read_u16(address, DEVICE_ID),
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            revision: class as u8,
+            header_type,
+            multifunction: raw_header & HEADER_MULTIFUNCTION != 0,
+            bars: [None; 6],
+            capabilities: Vec::new(),
+            extended_capabilities: Vec::new(),
+            interrupt_line: config::read_u8(address, INTERRUPT_LINE),
+            interrupt_pin: config::read_u8(address, INTERRUPT_PIN),
+            buses,
+            depth,
+            driver: SpinLock::new(None),
+        };
+        device.bars = device.read_bars();
+        device.capabilities = device.read_capabilities();
+        device.extended_capabilities = device.read_extended_capabilities();
+        Some(device)
+    }
+
+    pub fn read_u8(&self, offset: u16) -> u8 {
+        config::read_u8(self.address, offset)
+    }
+
+    pub fn read_u16(&self, offset: u16) -> u16 {
+        config::read_u16(self.address, offset)
+    }
+
+    pub fn read_u32(&self, offset: u16) -> u32 {
+        config::read_u32(self.address, offset)
+    }
+
+    pub fn write_u8(&self, offset: u16, value: u8) {
+        config::write_u8(self.address, offset, value)
+    }
+
+    pub fn write_u16(&self, offset: u16, value: u16) {
+        config::write_u16(self.address, offset, value)
+    }
+
+    pub fn write_u32(&self, offset: u16, value: u32) {
+        config::write_u32(self.address, offset, value)
+    }
+
+    // Sets and clears bits of the command register
+    pub fn update_command(&self, set: u16, clear: u16) {
+        let command = self.read_u16(COMMAND);
+        self.write_u16(COMMAND, (command & !clear) | set);
+    }
+
+    pub fn is_bridge(&self) -> bool {
+        self.header_type == HeaderType::PciBridge
+    }
+
+    pub fn capability(&self, id: u8) -> Option<Capability> {
+        self.capabilities.iter().find(|cap| cap.id == u16::from(id)).copied()
+    }
+
+    pub fn extended_capability(&self, id: u16) -> Option<Capability> {
+        self.extended_capabilities.iter().find(|cap| cap.id == id).copied()
+    }
+
+    pub fn driver(&self) -> Option<&'static Driver> {
+        *self.driver.lock()
+    }
+
+    // Sizes the BARs by writing all ones and reading back the mask, with
+    // decoding turned off so the device doesn't answer at a bogus address
+    // meanwhile
+    fn read_bars(&self) -> [Option<Bar>; 6] {
+        let mut bars = [None; 6];
+        let count = self.header_type.bar_count();
+        if count == 0 {
+            return bars;
+        }
+
+        let command = self.read_u16(COMMAND);
+        self.write_u16(COMMAND, command & !(COMMAND_IO | COMMAND_MEMORY));
+
+        let mut i = 0;
+        while i < count {
+            let offset = BAR0 + 4 * i as u16;
+            let (low, low_mask) = self.size_bar(offset);
+            let wide = low & 1 == 0 && (low >> 1) & 0x3 == 0x2 && i + 1 < count;
+            let high = if wide { Some(self.size_bar(offset + 4)) } else { None };
+
+            bars[i] = Bar::decode(low, low_mask, high);
+            i += if wide { 2 } else { 1 };
+        }
+
+        self.write_u16(COMMAND, command);
+        bars
+    }
+
+    fn size_bar(&self, offset: u16) -> (u32, u32) {
+        let value = self.read_u32(offset);
+        self.write_u32(offset, !0);
+        let mask = self.read_u32(offset);
+        self.write_u32(offset, value);
+        (value, mask)
+    }
+
+    fn read_capabilities(&self) -> Vec<Capability> {
+        let mut capabilities = Vec::new();
+        if self.read_u16(STATUS) & STATUS_CAPABILITIES == 0 {
+            return capabilities;
+        }
+
+        let mut offset = u16::from(self.read_u8(CAPABILITIES) & 0xFC);
+        while offset != 0 && capabilities.len() < MAX_CAPABILITIES {
+            capabilities.push(Capability {
+                id: u16::from(self.read_u8(offset)),
+                offset,
+            });
+            offset = u16::from(self.read_u8(offset + 1) & 0xFC);
+        }
+        capabilities
+    }
+
+    // Each header is a 16 bit ID, a 4 bit version and a 12 bit next offset.
+    // Without ECAM or on plain PCI devices this reads all ones or zeros.
+    fn read_extended_capabilities(&self) -> Vec<Capability> {
+        let mut capabilities = Vec::new();
+        let mut offset = EXTENDED_CAPABILITIES;
+        while offset >= EXTENDED_CAPABILITIES && capabilities.len() < MAX_CAPABILITIES {
+            let header = self.read_u32(offset);
+            if header == 0 || header == !0 {
+                break;
+            }
+
+            capabilities.push(Capability {
+                id: header as u16,
+                offset,
+            });
+            offset = (header >> 20) as u16 & 0xFFC;
+        }
+        capabilities
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} [{:04x}:{:04x}] {} ({:02x}.{:02x}.{:02x})",
+            self.address,
+            self.vendor_id,
+            self.device_id,
+            super::class_name(self.class, self.subclass),
+            self.class,
+            self.subclass,
+            self.prog_if
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::devices, *};
+
+    test_case!(bar_decoding, {
+        assert_eq!(Bar::decode(0xFEBD_1000, 0xFFFF_F000, None), Some(Bar::Memory {
+            addr: 0xFEBD_1000,
+            size: 0x1000,
+            prefetchable: false,
+            wide: false,
+        }));
+        assert_eq!(Bar::decode(0xC00C, 0xFFFF_C00C, Some((0x8, 0xFFFF_FFFF))), Some(Bar::Memory {
+            addr: 0x8_0000_C000,
+            size: 0x4000,
+            prefetchable: true,
+            wide: true,
+        }));
+        assert_eq!(Bar::decode(0xC041, 0xFFE1, None), Some(Bar::Io {
+            port: 0xC040,
+            size: 0x20,
+        }));
+        assert_eq!(Bar::decode(0, 0, None), None);
+        assert_eq!(Bar::decode(0x4, 0x4, Some((0, 0))), None);
+    });
+
+    test_case!(q35_devices, {
+        let devices = devices();
+        let host = devices.iter().find(|device| device.address == Address::new(0, 0, 0)).unwrap();
+        assert_eq!((host.class, host.subclass), (0x06, 0x00));
+
+        // The ICH9 AHCI controller is always there, with ABAR in BAR5 and MSI
+        let ahci = devices.iter().find(|device| device.address == Address::new(0, 0x1F, 2)).unwrap();
+        assert_eq!((ahci.vendor_id, ahci.device_id), (0x8086, 0x2922));
+        assert_eq!((ahci.class, ahci.subclass, ahci.prog_if), (0x01, 0x06, 0x01));
+        match ahci.bars[5] {
+            Some(Bar::Memory { size, .. }) => assert_eq!(size, 0x1000),
+            bar => panic!("unexpected abar {:?}", bar),
+        }
+        assert!(ahci.capability(0x05).is_some());
+    });
+}
@@ -0,0 +1,127 @@
+// The driver registry. Drivers list the devices they handle, by ID or class,
+// and get probed for each matching device that isn't bound yet, whether it
+// was found before or after they registered.
+use super::{devices, Device};
+use crate::{ds::SpinLock, syscall::Errno};
+use alloc::{sync::Arc, vec::Vec};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Match {
+    Id { vendor: u16, device: u16 },
+    // Any programming interface if prog_if is None
+    Class { class: u8, subclass: u8, prog_if: Option<u8> },
+}
+
+impl Match {
+    pub fn matches(self, device: &Device) -> bool {
+        match self {
+            Match::Id { vendor, device: id } => device.vendor_id == vendor && device.device_id == id,
+            Match::Class {
+                class,
+                subclass,
+                prog_if,
+            } => {
+                device.class == class
+                    && device.subclass == subclass
+                    && prog_if.map_or(true, |prog_if| device.prog_if == prog_if)
+            }
+        }
+    }
+}
+
+pub struct Driver {
+    pub name: &'static str,
+    pub matches: &'static [Match],
+    // Takes the device over, or fails to leave it unbound
+    pub probe: fn(&Arc<Device>) -> Result<(), Errno>,
+}
+
+lazy_static! {
+    static ref DRIVERS: SpinLock<Vec<&'static Driver>> = SpinLock::new(Vec::new());
+}
+
+pub fn register(driver: &'static Driver) {
+    DRIVERS.lock().push(driver);
+    for device in devices() {
+        if device.driver().is_none() && driver.matches.iter().any(|m| m.matches(&device)) {
+            bind(driver, &device);
+        }
+    }
+}
+
+// Binds a newly found device to the first driver that takes it
+pub(super) fn probe(device: &Arc<Device>) {
+    let drivers = DRIVERS.lock().clone();
+    for driver in drivers {
+        if driver.matches.iter().any(|m| m.matches(device)) && bind(driver, device) {
+            return;
+        }
+    }
+}
+
+fn bind(driver: &'static Driver, device: &Arc<Device>) -> bool {
+    match (driver.probe)(device) {
+        Ok(()) => {
+            info!("pci: {} bound to {}", device.address, driver.name);
+            *device.driver.lock() = Some(driver);
+            true
+        }
+        Err(errno) => {
+            warn!("pci: {} failed to probe {}: {:?}", driver.name, device.address, errno);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::Address, *};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static PROBES: AtomicUsize = AtomicUsize::new(0);
+
+    fn refuse(_: &Arc<Device>) -> Result<(), Errno> {
+        PROBES.fetch_add(1, Ordering::Relaxed);
+        Err(Errno::ENODEV)
+    }
+
+    static REFUSING: Driver = Driver {
+        name: "refusing",
+        matches: &[Match::Class {
+            class: 0x06,
+            subclass: 0x00,
+            prog_if: None,
+        }],
+        probe: refuse,
+    };
+
+    test_case!(matching, {
+        let devices = devices();
+        let host = devices.iter().find(|device| device.address == Address::new(0, 0, 0)).unwrap();
+
+        let id = Match::Id {
+            vendor: host.vendor_id,
+            device: host.device_id,
+        };
+        assert!(id.matches(host));
+        assert!(!Match::Id { vendor: 0xFFFF, device: 0 }.matches(host));
+        assert!(Match::Class {
+            class: 0x06,
+            subclass: 0x00,
+            prog_if: Some(host.prog_if),
+        }
+        .matches(host));
+        assert!(!Match::Class {
+            class: 0x06,
+            subclass: 0x01,
+            prog_if: None,
+        }
+        .matches(host));
+
+        // A failed probe leaves the device free
+        let bound = host.driver().is_some();
+        register(&REFUSING);
+        assert_eq!(PROBES.load(Ordering::Relaxed), if bound { 0 } else { 1 });
+        assert!(host.driver().map_or(true, |driver| driver.name != "refusing"));
+    });
+}
@@ -0,0 +1,349 @@
+// Interrupts for PCI functions. MSI-X is preferred, then MSI, and functions
+// with neither get their pin routed through the I/O APIC. Pins are level
+// triggered and can be shared, so each one gets a single vector that runs
+// every handler on it.
+use super::{
+    device::{Device, COMMAND_INTX_DISABLE},
+    devices,
+    msi::{Msi, MsiX},
+};
+use crate::{
+    cpu::{
+        irq::{self, Handler},
+        lapic,
+    },
+    drivers::{
+        acpi,
+        ioapic::{self, Polarity, Trigger},
+    },
+    ds::SpinLock,
+    syscall::Errno,
+};
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+
+enum Kind {
+    // Shared by every entry in use, and with the index of this one
+    MsiX(Arc<MsiX>, usize),
+    Msi(Msi),
+    Intx { gsi: u32, id: usize },
+}
+
+// An interrupt handler installed for a device, removed again on drop
+pub struct Irq {
+    device: Arc<Device>,
+    vector: u8,
+    kind: Kind,
+}
+
+struct SharedPin {
+    vector: u8,
+    handlers: Vec<(usize, Handler)>,
+}
+
+lazy_static! {
+    static ref PINS: SpinLock<BTreeMap<u32, SharedPin>> = SpinLock::new(BTreeMap::new());
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+// Installs handler for the device's interrupts and turns them on
+pub fn request(device: &Arc<Device>, handler: Handler) -> Result<Irq, Errno> {
+    let dest = lapic::id();
+
+    if let Some(msix) = MsiX::new(device) {
+        let vector = irq::alloc_vector(handler).ok_or(Errno::ENOSPC)?;
+        msix.enable(device);
+        msix.set(0, vector, dest);
+        msix.unmask(0);
+        device.update_command(COMMAND_INTX_DISABLE, 0);
+        debug!("pci: {} using msi-x on vector {:#x}", device.address, vector);
+        return Ok(Irq::new(device, vector, Kind::MsiX(Arc::new(msix), 0)));
+    }
+
+    if let Some(msi) = Msi::new(device) {
+        let vector = irq::alloc_vector(handler).ok_or(Errno::ENOSPC)?;
+        if let Err(errno) = msi.enable(device, vector, dest) {
+            irq::free_vector(vector);
+            return Err(errno);
+        }
+        device.update_command(COMMAND_INTX_DISABLE, 0);
+        debug!("pci: {} using msi on vector {:#x}", device.address, vector);
+        return Ok(Irq::new(device, vector, Kind::Msi(msi)));
+    }
+
+    request_intx(device, handler)
+}
+
+fn request_intx(device: &Arc<Device>, handler: Handler) -> Result<Irq, Errno> {
+    let (gsi, polarity) = intx_gsi(device).ok_or(Errno::ENODEV)?;
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let vector = share_pin(gsi, polarity, id, handler)?;
+    device.update_command(0, COMMAND_INTX_DISABLE);
+    debug!("pci: {} using gsi {} on vector {:#x}", device.address, gsi, vector);
+    Ok(Irq::new(device, vector, Kind::Intx { gsi, id }))
+}
+
+// Installs a handler for each of the first MSI-X entries, for devices that
+// interrupt separately for each of their queues
+pub fn request_msix(device: &Arc<Device>, handlers: Vec<Handler>) -> Result<Vec<Irq>, Errno> {
+    let msix = Arc::new(MsiX::new(device).ok_or(Errno::ENODEV)?);
+    if handlers.len() > msix.table_size() {
+        return Err(Errno::ENOSPC);
+    }
+
+    msix.enable(device);
+    let mut irqs = Vec::new();
+    for (index, handler) in handlers.into_iter().enumerate() {
+        let vector = match irq::alloc_vector(handler) {
+            Some(vector) => vector,
+            None => {
+                // Dropping the others won't disable it while this is around
+                msix.disable(device);
+                return Err(Errno::ENOSPC);
+            }
+        };
+        msix.set(index, vector, lapic::id());
+        msix.unmask(index);
+        irqs.push(Irq::new(device, vector, Kind::MsiX(msix.clone(), index)));
+    }
+    device.update_command(COMMAND_INTX_DISABLE, 0);
+    debug!("pci: {} using {} msi-x entries", device.address, irqs.len());
+    Ok(irqs)
+}
+
+impl Irq {
+    fn new(device: &Arc<Device>, vector: u8, kind: Kind) -> Irq {
+        Irq {
+            device: device.clone(),
+            vector,
+            kind,
+        }
+    }
+
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+
+    pub fn is_msi(&self) -> bool {
+        !matches!(self.kind, Kind::Intx { .. })
+    }
+
+    // Stops the device from raising the interrupt. Shared pins stay unmasked
+    // at the I/O APIC and are silenced at the device instead.
+    pub fn mask(&self) {
+        match self.kind {
+            Kind::MsiX(ref msix, index) => msix.mask(index),
+            Kind::Msi(ref msi) if msi.can_mask() => msi.set_masked(&self.device, true),
+            Kind::Msi(ref msi) => msi.disable(&self.device),
+            Kind::Intx { .. } => self.device.update_command(COMMAND_INTX_DISABLE, 0),
+        }
+    }
+
+    pub fn unmask(&self) {
+        match self.kind {
+            Kind::MsiX(ref msix, index) => msix.unmask(index),
+            Kind::Msi(ref msi) if msi.can_mask() => msi.set_masked(&self.device, false),
+            Kind::Msi(ref msi) => {
+                // Was enabled with this vector before, so it can't fail
+                let _ = msi.enable(&self.device, self.vector, lapic::id());
+            }
+            Kind::Intx { .. } => self.device.update_command(0, COMMAND_INTX_DISABLE),
+        }
+    }
+}
+
+impl Drop for Irq {
+    fn drop(&mut self) {
+        match self.kind {
+            Kind::MsiX(ref msix, index) => {
+                msix.mask(index);
+                if Arc::strong_count(msix) == 1 {
+                    msix.disable(&self.device);
+                }
+                irq::free_vector(self.vector);
+            }
+            Kind::Msi(ref msi) => {
+                msi.disable(&self.device);
+                irq::free_vector(self.vector);
+            }
+            Kind::Intx { gsi, id } => {
+                self.device.update_command(COMMAND_INTX_DISABLE, 0);
+                unshare_pin(gsi, id);
+            }
+        }
+    }
+}
+
+// Finds the GSI the device's pin ends up on. Each bridge rotates the pins of
+// the devices behind it by their device number, until the root bus where the
+// _PRT says where they go.
+fn intx_gsi(device: &Device) -> Option<(u32, Polarity)> {
+    if device.interrupt_pin == 0 {
+        return None;
+    }
+
+    let devices = devices();
+    let mut pin = device.interrupt_pin - 1;
+    let mut address = device.address;
+    while let Some(bridge) = devices
+        .iter()
+        .find(|bridge| bridge.buses.map_or(false, |(secondary, _)| secondary == address.bus))
+    {
+        pin = (pin + address.device) % 4;
+        address = bridge.address;
+    }
+
+    let routes = acpi::pci_routes();
+    if let Some(route) = routes.iter().find(|route| route.device == address.device && route.pin == pin) {
+        return Some((route.gsi, route.polarity));
+    }
+
+    // Without a usable _PRT, go with the legacy IRQ firmware set up
+    match device.interrupt_line {
+        line if line < 16 => Some((ioapic::isa_irq(line).0, Polarity::Low)),
+        _ => None,
+    }
+}
+
+// Pins are looked up from their vector's handler, so they're only locked with
+// interrupts off
+fn share_pin(gsi: u32, polarity: Polarity, id: usize, handler: Handler) -> Result<u8, Errno> {
+    interrupts::without_interrupts(|| {
+        let mut pins = PINS.lock();
+        if let Some(pin) = pins.get_mut(&gsi) {
+            pin.handlers.push((id, handler));
+            return Ok(pin.vector);
+        }
+
+        let vector = irq::alloc_vector(Arc::new(move || run_pin(gsi))).ok_or(Errno::ENOSPC)?;
+        if let Err(errno) = ioapic::route_here(gsi, vector, Trigger::Level, polarity) {
+            irq::free_vector(vector);
+            return Err(errno);
+        }
+
+        pins.insert(gsi, SharedPin {
+            vector,
+            handlers: vec![(id, handler)],
+        });
+        Ok(vector)
+    })
+}
+
+fn unshare_pin(gsi: u32, id: usize) {
+    interrupts::without_interrupts(|| {
+        let mut pins = PINS.lock();
+        let pin = pins.get_mut(&gsi).expect("pci: unsharing unknown pin");
+        pin.handlers.retain(|&(other, _)| other != id);
+        if pin.handlers.is_empty() {
+            let _ = ioapic::mask(gsi);
+            irq::free_vector(pin.vector);
+            pins.remove(&gsi);
+        }
+    })
+}
+
+fn run_pin(gsi: u32) {
+    // Handlers can't tell if it was their device, so they all check
+    let handlers = interrupts::without_interrupts(|| PINS.lock().get(&gsi).map(|pin| pin.handlers.clone()));
+    for (_, handler) in handlers.iter().flatten() {
+        handler();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{
+            device::COMMAND,
+            testing::{wait_until, Edu},
+        },
+        *,
+    };
+
+    // Counts the edu interrupts it clears
+    fn counter(edu: &Arc<Edu>, count: &Arc<AtomicUsize>) -> Handler {
+        let (edu, count) = (edu.clone(), count.clone());
+        Arc::new(move || {
+            if edu.ack() {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        })
+    }
+
+    test_case!(msi_arrives, {
+        let edu = Arc::new(Edu::find());
+        let count = Arc::new(AtomicUsize::new(0));
+        let irq = request(&edu.device, counter(&edu, &count)).unwrap();
+        assert!(irq.is_msi());
+        assert_ne!(edu.device.read_u16(COMMAND) & COMMAND_INTX_DISABLE, 0);
+
+        edu.raise();
+        assert!(wait_until(|| count.load(Ordering::Relaxed) == 1));
+
+        // It has no mask bits, so masking turns MSI off and nothing is sent
+        irq.mask();
+        edu.raise();
+        assert!(!wait_until(|| count.load(Ordering::Relaxed) == 2));
+        irq.unmask();
+        assert!(edu.ack());
+
+        edu.raise();
+        assert!(wait_until(|| count.load(Ordering::Relaxed) == 2));
+        drop(irq);
+    });
+
+    test_case!(intx_arrives, {
+        let edu = Arc::new(Edu::find());
+
+        // q35 routes pins through link devices, onto GSIs 16 to 23
+        let (gsi, _) = intx_gsi(&edu.device).unwrap();
+        assert!(gsi >= 16 && gsi < 24);
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let irq = request_intx(&edu.device, counter(&edu, &count)).unwrap();
+        assert!(!irq.is_msi());
+
+        // The second only gets through if the first was acknowledged at the
+        // I/O APIC
+        edu.raise();
+        assert!(wait_until(|| count.load(Ordering::Relaxed) == 1));
+        edu.raise();
+        assert!(wait_until(|| count.load(Ordering::Relaxed) == 2));
+
+        irq.mask();
+        edu.raise();
+        assert!(!wait_until(|| count.load(Ordering::Relaxed) == 3));
+        irq.unmask();
+        assert!(wait_until(|| count.load(Ordering::Relaxed) == 3));
+        drop(irq);
+        assert!(interrupts::without_interrupts(|| PINS.lock().get(&gsi).is_none()));
+    });
+
+    test_case!(shared_pins, {
+        // No driver has routed the last GSI, so the test can have it
+        let gsi = 23;
+        let count = Arc::new(AtomicUsize::new(0));
+        let (first, second) = (count.clone(), count.clone());
+        let vector = share_pin(gsi, Polarity::Low, 1000, Arc::new(move || {
+            first.fetch_add(1, Ordering::Relaxed);
+        }))
+        .unwrap();
+        let same = share_pin(gsi, Polarity::Low, 1001, Arc::new(move || {
+            second.fetch_add(10, Ordering::Relaxed);
+        }))
+        .unwrap();
+        assert_eq!(vector, same);
+
+        irq::dispatch(vector);
+        assert_eq!(count.load(Ordering::Relaxed), 11);
+
+        unshare_pin(gsi, 1000);
+        irq::dispatch(vector);
+        assert_eq!(count.load(Ordering::Relaxed), 21);
+        unshare_pin(gsi, 1001);
+        assert!(interrupts::without_interrupts(|| PINS.lock().get(&gsi).is_none()));
+        assert_eq!(Arc::strong_count(&count), 1);
+    });
+}
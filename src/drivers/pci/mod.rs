@@ -0,0 +1,127 @@
+// PCI enumeration. Buses are walked from the root through bridges, and every
+// function found is kept in a list that drivers are matched against.
+use crate::ds::SpinLock;
+use acpi::PciConfigRegions;
+use alloc::{sync::Arc, vec::Vec};
+
+pub mod config;
+pub mod device;
+pub mod driver;
+pub mod irq;
+pub mod msi;
+#[cfg(test)]
+pub mod testing;
+
+pub use config::Address;
+pub use device::{Bar, Capability, Device};
+pub use driver::{register, Driver, Match};
+pub use irq::{request, request_msix, Irq};
+
+lazy_static! {
+    static ref DEVICES: SpinLock<Vec<Arc<Device>>> = SpinLock::new(Vec::new());
+}
+
+pub fn init(regions: Option<PciConfigRegions>) {
+    config::init(regions);
+
+    let mut found = Vec::new();
+    scan_root(&mut found);
+
+    info!("pci: found {} functions", found.len());
+    for device in &found {
+        info!("pci: {:indent$}{}", "", device, indent = 2 * device.depth);
+        for (i, bar) in device.bars.iter().enumerate() {
+            if let Some(bar) = bar {
+                debug!("pci: {:indent$}  bar{}: {}", "", i, bar, indent = 2 * device.depth);
+            }
+        }
+    }
+
+    let found: Vec<Arc<Device>> = found.into_iter().map(Arc::new).collect();
+    DEVICES.lock().extend(found.iter().cloned());
+    for device in &found {
+        driver::probe(device);
+    }
+}
+
+// Every function found, in the order of the tree
+pub fn devices() -> Vec<Arc<Device>> {
+    DEVICES.lock().clone()
+}
+
+// A multi-function host bridge at 00:00.0 means there are several host
+// controllers, each function being the root of the bus with its number
+fn scan_root(found: &mut Vec<Device>) {
+    let host = match Device::probe(Address::new(0, 0, 0), 0) {
+        Some(host) => host,
+        None => return,
+    };
+
+    if host.multifunction {
+        for function in 0..8 {
+            if Device::probe(Address::new(0, 0, function), 0).is_some() {
+                scan_bus(function, 0, found);
+            }
+        }
+    } else {
+        scan_bus(0, 0, found);
+    }
+}
+
+fn scan_bus(bus: u8, depth: usize, found: &mut Vec<Device>) {
+    for device in 0..32 {
+        let first = match Device::probe(Address::new(bus, device, 0), depth) {
+            Some(first) => first,
+            None => continue,
+        };
+
+        let functions = if first.multifunction { 8 } else { 1 };
+        scan_function(first, depth, found);
+        for function in 1..functions {
+            if let Some(next) = Device::probe(Address::new(bus, device, function), depth) {
+                scan_function(next, depth, found);
+            }
+        }
+    }
+}
+
+fn scan_function(device: Device, depth: usize, found: &mut Vec<Device>) {
+    // Firmware numbers the buses, so a secondary bus at or below this one
+    // would loop
+    let behind = match device.buses {
+        Some((secondary, _)) if secondary > device.address.bus => Some(secondary),
+        _ => None,
+    };
+
+    found.push(device);
+    if let Some(secondary) = behind {
+        scan_bus(secondary, depth + 1, found);
+    }
+}
+
+pub fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x01, 0x00) => "SCSI controller",
+        (0x01, 0x01) => "IDE controller",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "NVMe controller",
+        (0x01, _) => "storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _) => "network controller",
+        (0x03, 0x00) => "VGA controller",
+        (0x03, _) => "display controller",
+        (0x04, _) => "multimedia controller",
+        (0x05, _) => "memory controller",
+        (0x06, 0x00) => "host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "bridge",
+        (0x07, _) => "communication controller",
+        (0x08, _) => "system peripheral",
+        (0x09, _) => "input controller",
+        (0x0C, 0x03) => "USB controller",
+        (0x0C, 0x05) => "SMBus controller",
+        (0x0C, _) => "serial bus controller",
+        _ => "unknown device",
+    }
+}
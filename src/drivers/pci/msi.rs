@@ -0,0 +1,256 @@
+// Message signalled interrupts. Instead of asserting a pin, the device writes
+// a message to an address that the local APICs decode as an interrupt. MSI has
+// a single message for the function, MSI-X a table of them in a BAR, each
+// with its own mask.
+use super::device::{Bar, Device};
+use crate::{mm::mmio, syscall::Errno};
+use core::ptr;
+use x86_64::{PhysAddr, VirtAddr};
+
+pub const CAP_MSI: u8 = 0x05;
+pub const CAP_MSIX: u8 = 0x11;
+
+const MSI_CONTROL: u16 = 0x02;
+const MSI_ADDRESS: u16 = 0x04;
+const MSI_ENABLE: u16 = 1 << 0;
+const MSI_MULTIPLE_ENABLE: u16 = 0x7 << 4;
+const MSI_64BIT: u16 = 1 << 7;
+const MSI_PER_VECTOR_MASK: u16 = 1 << 8;
+
+const MSIX_CONTROL: u16 = 0x02;
+const MSIX_TABLE: u16 = 0x04;
+const MSIX_PBA: u16 = 0x08;
+const MSIX_TABLE_SIZE: u16 = 0x7FF;
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_ENABLE: u16 = 1 << 15;
+
+const MSIX_ENTRY_SIZE: usize = 16;
+const MSIX_ENTRY_ADDRESS: usize = 0x0;
+const MSIX_ENTRY_UPPER_ADDRESS: usize = 0x4;
+const MSIX_ENTRY_DATA: usize = 0x8;
+const MSIX_ENTRY_CONTROL: usize = 0xC;
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+// Fixed delivery, edge triggered, physical destination mode
+const MESSAGE_ADDRESS: u32 = 0xFEE0_0000;
+
+// The address and data that raise vector on the local APIC with ID dest
+pub fn message(vector: u8, dest: u8) -> (u64, u32) {
+    (u64::from(MESSAGE_ADDRESS | u32::from(dest) << 12), u32::from(vector))
+}
+
+pub struct Msi {
+    offset: u16,
+    wide: bool,
+    per_vector_mask: bool,
+}
+
+impl Msi {
+    pub fn new(device: &Device) -> Option<Msi> {
+        let offset = device.capability(CAP_MSI)?.offset;
+        let control = device.read_u16(offset + MSI_CONTROL);
+        Some(Msi {
+            offset,
+            wide: control & MSI_64BIT != 0,
+            per_vector_mask: control & MSI_PER_VECTOR_MASK != 0,
+        })
+    }
+
+    fn data_offset(&self) -> u16 {
+        self.offset + if self.wide { 0xC } else { 0x8 }
+    }
+
+    fn mask_offset(&self) -> u16 {
+        self.data_offset() + 4
+    }
+
+    // Points the function's one message at vector and turns MSI on
+    pub fn enable(&self, device: &Device, vector: u8, dest: u8) -> Result<(), Errno> {
+        let (address, data) = message(vector, dest);
+        if address >> 32 != 0 && !self.wide {
+            return Err(Errno::EINVAL);
+        }
+
+        let control = device.read_u16(self.offset + MSI_CONTROL);
+        device.write_u16(self.offset + MSI_CONTROL, control & !(MSI_ENABLE | MSI_MULTIPLE_ENABLE));
+
+        device.write_u32(self.offset + MSI_ADDRESS, address as u32);
+        if self.wide {
+            device.write_u32(self.offset + MSI_ADDRESS + 4, (address >> 32) as u32);
+        }
+        device.write_u16(self.data_offset(), data as u16);
+        if self.per_vector_mask {
+            device.write_u32(self.mask_offset(), 0);
+        }
+
+        device.write_u16(self.offset + MSI_CONTROL, (control & !MSI_MULTIPLE_ENABLE) | MSI_ENABLE);
+        Ok(())
+    }
+
+    pub fn disable(&self, device: &Device) {
+        let control = device.read_u16(self.offset + MSI_CONTROL);
+        device.write_u16(self.offset + MSI_CONTROL, control & !MSI_ENABLE);
+    }
+
+    // Without per-vector masking the only way to stop messages is to turn MSI
+    // off, which makes the device fall back to its pin
+    pub fn can_mask(&self) -> bool {
+        self.per_vector_mask
+    }
+
+    pub fn set_masked(&self, device: &Device, masked: bool) {
+        if self.per_vector_mask {
+            device.write_u32(self.mask_offset(), masked as u32);
+        }
+    }
+}
+
+pub struct MsiX {
+    offset: u16,
+    table: VirtAddr,
+    size: usize,
+}
+
+impl MsiX {
+    // Maps the table, which lives in one of the function's memory BARs
+    pub fn new(device: &Device) -> Option<MsiX> {
+        let offset = device.capability(CAP_MSIX)?.offset;
+        let control = device.read_u16(offset + MSIX_CONTROL);
+        let size = usize::from(control & MSIX_TABLE_SIZE) + 1;
+
+        let table = device.read_u32(offset + MSIX_TABLE);
+        let bar = match device.bars[(table & 0x7) as usize] {
+            Some(Bar::Memory { addr, .. }) => addr,
+            _ => {
+                warn!("pci: {} has an msi-x table outside a memory bar", device.address);
+                return None;
+            }
+        };
+
+        let phys = PhysAddr::new((bar + u64::from(table & !0x7)) as usize);
+        let table = match mmio::map(phys, size * MSIX_ENTRY_SIZE) {
+            Ok(table) => table,
+            Err(err) => {
+                warn!("pci: failed to map msi-x table of {}: {:?}", device.address, err);
+                return None;
+            }
+        };
+
+        Some(MsiX { offset, table, size })
+    }
+
+    pub fn table_size(&self) -> usize {
+        self.size
+    }
+
+    // Where the pending bits are, as the BAR index and offset into it
+    pub fn pba(&self, device: &Device) -> (usize, u32) {
+        let pba = device.read_u32(self.offset + MSIX_PBA);
+        ((pba & 0x7) as usize, pba & !0x7)
+    }
+
+    fn entry(&self, index: usize, field: usize) -> *mut u32 {
+        assert!(index < self.size, "msi-x entry {} out of range", index);
+        (self.table + index * MSIX_ENTRY_SIZE + field).as_mut_ptr()
+    }
+
+    // Turns MSI-X on with every entry masked, for them to be set up one by one
+    pub fn enable(&self, device: &Device) {
+        let control = device.read_u16(self.offset + MSIX_CONTROL);
+        device.write_u16(self.offset + MSIX_CONTROL, control | MSIX_FUNCTION_MASK | MSIX_ENABLE);
+        for index in 0..self.size {
+            self.mask(index);
+        }
+        device.write_u16(self.offset + MSIX_CONTROL, (control | MSIX_ENABLE) & !MSIX_FUNCTION_MASK);
+    }
+
+    pub fn disable(&self, device: &Device) {
+        let control = device.read_u16(self.offset + MSIX_CONTROL);
+        device.write_u16(self.offset + MSIX_CONTROL, control & !MSIX_ENABLE);
+    }
+
+    // Points an entry at vector. It keeps whatever mask it had.
+    pub fn set(&self, index: usize, vector: u8, dest: u8) {
+        let (address, data) = message(vector, dest);
+        let masked = self.is_masked(index);
+        self.mask(index);
+        unsafe {
+            ptr::write_volatile(self.entry(index, MSIX_ENTRY_ADDRESS), address as u32);
+            ptr::write_volatile(self.entry(index, MSIX_ENTRY_UPPER_ADDRESS), (address >> 32) as u32);
+            ptr::write_volatile(self.entry(index, MSIX_ENTRY_DATA), data);
+        }
+        if !masked {
+            self.unmask(index);
+        }
+    }
+
+    pub fn is_masked(&self, index: usize) -> bool {
+        unsafe { ptr::read_volatile(self.entry(index, MSIX_ENTRY_CONTROL)) & MSIX_ENTRY_MASKED != 0 }
+    }
+
+    pub fn mask(&self, index: usize) {
+        unsafe {
+            let control = ptr::read_volatile(self.entry(index, MSIX_ENTRY_CONTROL));
+            ptr::write_volatile(self.entry(index, MSIX_ENTRY_CONTROL), control | MSIX_ENTRY_MASKED);
+        }
+    }
+
+    pub fn unmask(&self, index: usize) {
+        unsafe {
+            let control = ptr::read_volatile(self.entry(index, MSIX_ENTRY_CONTROL));
+            ptr::write_volatile(self.entry(index, MSIX_ENTRY_CONTROL), control & !MSIX_ENTRY_MASKED);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{devices, testing::Edu},
+        *,
+    };
+
+    test_case!(message_encoding, {
+        assert_eq!(message(0x31, 0), (0xFEE0_0000, 0x31));
+        assert_eq!(message(0x40, 3), (0xFEE0_3000, 0x40));
+    });
+
+    // On the edu device, which no driver has an interrupt from
+    test_case!(msi_on_edu, {
+        let edu = Edu::find();
+        let device = &edu.device;
+        let msi = Msi::new(device).unwrap();
+
+        msi.enable(device, 0x45, 0).unwrap();
+        let control = device.read_u16(msi.offset + MSI_CONTROL);
+        assert_ne!(control & MSI_ENABLE, 0);
+        assert_eq!(device.read_u32(msi.offset + MSI_ADDRESS), 0xFEE0_0000);
+        assert_eq!(device.read_u16(msi.data_offset()), 0x45);
+
+        msi.disable(device);
+        assert_eq!(device.read_u16(msi.offset + MSI_CONTROL) & MSI_ENABLE, 0);
+    });
+
+    test_case!(msix_masking, {
+        // Only some machine configurations have an MSI-X device that no
+        // driver is using
+        let devices = devices();
+        let unbound = devices
+            .iter()
+            .find(|device| device.driver().is_none() && device.capability(CAP_MSIX).is_some());
+        if let Some(device) = unbound {
+            let msix = MsiX::new(device).unwrap();
+            assert!(msix.table_size() >= 1);
+
+            msix.enable(device);
+            assert!(msix.is_masked(0));
+            msix.set(0, 0x46, 0);
+            assert!(msix.is_masked(0));
+            msix.unmask(0);
+            assert!(!msix.is_masked(0));
+            msix.mask(0);
+            assert!(msix.is_masked(0));
+            msix.disable(device);
+        }
+    });
+}
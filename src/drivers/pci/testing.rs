@@ -0,0 +1,75 @@
+// QEMU's edu device, which the test-args in Cargo.toml attach and no driver
+// takes. It raises interrupts when asked to, so tests can watch real ones
+// arrive, and can use it without getting in the way of any driver.
+use super::{
+    device::{Bar, Device, COMMAND_BUS_MASTER, COMMAND_MEMORY},
+    devices,
+};
+use crate::{mm::mmio, time};
+use alloc::sync::Arc;
+use core::{ptr, sync::atomic::spin_loop_hint, time::Duration};
+use x86_64::{PhysAddr, VirtAddr};
+
+const VENDOR_ID: u16 = 0x1234;
+const DEVICE_ID: u16 = 0x11E8;
+
+// The interrupt status, and registers setting and clearing bits of it. The
+// device interrupts whenever bits get set, and holds its pin while any are.
+const STATUS: usize = 0x24;
+const RAISE: usize = 0x60;
+const ACK: usize = 0x64;
+
+pub struct Edu {
+    pub device: Arc<Device>,
+    regs: VirtAddr,
+}
+
+impl Edu {
+    pub fn find() -> Edu {
+        let device = devices()
+            .into_iter()
+            .find(|device| device.vendor_id == VENDOR_ID && device.device_id == DEVICE_ID)
+            .expect("pci: no edu device");
+        let addr = match device.bars[0] {
+            Some(Bar::Memory { addr, .. }) => addr,
+            _ => panic!("pci: edu has no registers"),
+        };
+
+        // MSIs are memory writes, so they need bus mastering
+        device.update_command(COMMAND_MEMORY | COMMAND_BUS_MASTER, 0);
+        let regs = mmio::map(PhysAddr::new(addr as usize), 0x100).expect("pci: can't map edu");
+        Edu { device, regs }
+    }
+
+    pub fn raise(&self) {
+        self.write(RAISE, 1);
+    }
+
+    // Clears the interrupt, and says if there was one
+    pub fn ack(&self) -> bool {
+        let status = self.read(STATUS);
+        self.write(ACK, status);
+        status != 0
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { ptr::read_volatile((self.regs + offset).as_ptr()) }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { ptr::write_volatile((self.regs + offset).as_mut_ptr(), value) }
+    }
+}
+
+// Spins with interrupts on until f holds, or gives up after a tenth of a
+// second
+pub fn wait_until(f: impl Fn() -> bool) -> bool {
+    let deadline = time::now() + Duration::from_millis(100);
+    while !f() {
+        if time::now() >= deadline {
+            return false;
+        }
+        spin_loop_hint();
+    }
+    true
+}
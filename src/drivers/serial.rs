@@ -32,7 +32,11 @@ fn write_byte(ch: u8) {
 }
 
 pub fn write_str(s: &str) {
-    for byte in s.bytes() {
+    write_bytes(s.as_bytes());
+}
+
+pub fn write_bytes(bytes: &[u8]) {
+    for &byte in bytes {
         write_byte(byte);
     }
 }
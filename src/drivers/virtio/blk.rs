@@ -0,0 +1,267 @@
+// Virtio block devices. Each request is a chain of a header for the device to
+// read, the data, and a status byte for it to write back. Headers and status
+// bytes live in a table indexed by the head descriptor of their chain.
+use super::{queue::Buffer, Transport, VirtQueue, VENDOR_ID};
+use crate::{
+    drivers::{
+        block::{self, BlockDevice, Op, Request, SECTOR_SIZE},
+        pci::{self, Device, Driver, Irq, Match},
+    },
+    ds::SpinLock,
+    mm::dma::DmaBuffer,
+    syscall::Errno,
+    task,
+};
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::{mem, ptr};
+use x86_64::instructions::interrupts;
+
+const DEVICE_TRANSITIONAL: u16 = 0x1001;
+const DEVICE_MODERN: u16 = 0x1042;
+
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+
+const CONFIG_CAPACITY: usize = 0;
+
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+
+const S_OK: u8 = 0;
+const S_UNSUPP: u8 = 2;
+
+const QUEUE_SIZE: u16 = 128;
+
+#[repr(C)]
+struct Header {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+pub static DRIVER: Driver = Driver {
+    name: "virtio-blk",
+    matches: &[
+        Match::Id {
+            vendor: VENDOR_ID,
+            device: DEVICE_TRANSITIONAL,
+        },
+        Match::Id {
+            vendor: VENDOR_ID,
+            device: DEVICE_MODERN,
+        },
+    ],
+    probe,
+};
+
+struct Inflight {
+    queue: VirtQueue,
+    requests: Vec<Option<Arc<Request>>>,
+    // Headers first, then a status byte for each
+    headers: DmaBuffer,
+}
+
+impl Inflight {
+    fn header_offset(&self, head: u16) -> usize {
+        usize::from(head) * mem::size_of::<Header>()
+    }
+
+    fn status_offset(&self, head: u16) -> usize {
+        usize::from(self.queue.size()) * mem::size_of::<Header>() + usize::from(head)
+    }
+
+    // Completes every request the device has handed back
+    fn complete_used(&mut self) {
+        while let Some((head, _)) = self.queue.pop_used() {
+            let status = unsafe { ptr::read_volatile(self.headers.as_mut_ptr::<u8>(self.status_offset(head))) };
+            let result = match status {
+                S_OK => Ok(()),
+                S_UNSUPP => Err(Errno::EINVAL),
+                _ => Err(Errno::EIO),
+            };
+            if let Some(request) = self.requests[usize::from(head)].take() {
+                request.complete(result);
+            }
+        }
+    }
+}
+
+pub struct VirtioBlk {
+    name: String,
+    transport: Transport,
+    sectors: u64,
+    read_only: bool,
+    can_flush: bool,
+    inflight: SpinLock<Inflight>,
+    irq: SpinLock<Option<Irq>>,
+}
+
+fn probe(device: &Arc<Device>) -> Result<(), Errno> {
+    let transport = Transport::new(device)?;
+    let features = transport.negotiate(F_RO | F_FLUSH)?;
+
+    // Queue interrupts use the first MSI-X entry, which is what pci::request
+    // sets up
+    let queue = match transport.setup_queue(0, QUEUE_SIZE, 0) {
+        Ok(queue) => queue,
+        Err(errno) => {
+            transport.fail();
+            return Err(errno);
+        }
+    };
+    let sectors = transport.read_config::<u64>(CONFIG_CAPACITY).ok_or(Errno::ENODEV)?;
+    let size = usize::from(queue.size());
+    let headers = DmaBuffer::new(size * (mem::size_of::<Header>() + 1))?;
+
+    let blk = Arc::new(VirtioBlk {
+        name: block::next_name("vd"),
+        transport,
+        sectors,
+        read_only: features & F_RO != 0,
+        can_flush: features & F_FLUSH != 0,
+        inflight: SpinLock::new(Inflight {
+            queue,
+            requests: (0..size).map(|_| None).collect(),
+            headers,
+        }),
+        irq: SpinLock::new(None),
+    });
+
+    let weak = Arc::downgrade(&blk);
+    let handler = Arc::new(move || {
+        if let Some(blk) = weak.upgrade() {
+            blk.interrupt();
+        }
+    });
+    match pci::request(device, handler) {
+        Ok(irq) => *blk.irq.lock() = Some(irq),
+        Err(errno) => warn!("virtio-blk: no interrupt for {} ({:?}), polling only", device.address, errno),
+    }
+
+    blk.transport.driver_ok();
+    block::register(blk);
+    Ok(())
+}
+
+impl VirtioBlk {
+    fn interrupt(&self) {
+        // Reading it acknowledges a pin interrupt. The pin may be shared, but
+        // if it wasn't ours there's nothing used to complete.
+        self.transport.read_isr();
+        self.inflight.lock().complete_used();
+    }
+
+    // Puts a request on the queue once there's room for it
+    fn add(&self, request: Arc<Request>, kind: u32, descriptors: u16) -> Result<(), Errno> {
+        let mut inflight = loop {
+            let inflight = self.inflight.lock();
+            if inflight.queue.free_descriptors() >= descriptors {
+                break inflight;
+            }
+
+            // Wait for the device to finish something
+            drop(inflight);
+            self.poll();
+            task::yield_now();
+        };
+
+        let head = inflight.queue.next_head().unwrap();
+        let header_offset = inflight.header_offset(head);
+        let status_offset = inflight.status_offset(head);
+        unsafe {
+            ptr::write_volatile(inflight.headers.as_mut_ptr(header_offset), Header {
+                kind,
+                reserved: 0,
+                sector: request.sector,
+            });
+            ptr::write_volatile(inflight.headers.as_mut_ptr::<u8>(status_offset), 0xFF);
+        }
+
+        let header = Buffer {
+            addr: inflight.headers.phys() + header_offset,
+            len: mem::size_of::<Header>() as u32,
+            device_writes: false,
+        };
+        let data = Buffer {
+            addr: request.buffer.phys(),
+            len: request.buffer.len() as u32,
+            device_writes: request.op == Op::Read,
+        };
+        let status = Buffer {
+            addr: inflight.headers.phys() + status_offset,
+            len: 1,
+            device_writes: true,
+        };
+        let added = if request.op == Op::Flush {
+            inflight.queue.add(&[header, status])
+        } else {
+            inflight.queue.add(&[header, data, status])
+        };
+        assert_eq!(added, Some(head));
+
+        inflight.requests[usize::from(head)] = Some(request);
+        inflight.queue.notify();
+        Ok(())
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn submit(&self, request: Arc<Request>) -> Result<(), Errno> {
+        let kind = match request.op {
+            Op::Read => T_IN,
+            Op::Write if self.read_only => return Err(Errno::EROFS),
+            Op::Write => T_OUT,
+            // Without the feature the device has no write cache to flush
+            Op::Flush if !self.can_flush => {
+                request.complete(Ok(()));
+                return Ok(());
+            }
+            Op::Flush => T_FLUSH,
+        };
+        if request.op != Op::Flush {
+            let len = request.buffer.len();
+            let end = request.sector + (len / SECTOR_SIZE) as u64;
+            if request.buffer.is_empty() || len % SECTOR_SIZE != 0 || end > self.sectors {
+                return Err(Errno::EINVAL);
+            }
+        }
+        let descriptors = if request.op == Op::Flush { 2 } else { 3 };
+
+        // The handler completes requests too, so the queue is only locked
+        // with interrupts off
+        interrupts::without_interrupts(|| self.add(request, kind, descriptors))
+    }
+
+    fn poll(&self) {
+        interrupts::without_interrupts(|| self.inflight.lock().complete_used());
+    }
+
+    fn has_interrupt(&self) -> bool {
+        self.irq.lock().is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::block::testing;
+
+    test_case!(read_write_disk, {
+        let disk = testing::scratch_disk("vd");
+        assert!(disk.has_interrupt());
+        testing::read_write(&disk, 8 * SECTOR_SIZE);
+    });
+}
@@ -0,0 +1,26 @@
+// Virtio 1.0 devices over PCI. The transport handles discovery, feature
+// negotiation and queue setup, and the split virtqueues carry the requests.
+use super::pci;
+
+pub mod blk;
+pub mod queue;
+pub mod transport;
+
+pub use queue::VirtQueue;
+pub use transport::Transport;
+
+pub const VENDOR_ID: u16 = 0x1AF4;
+
+// Device status bits, set by the driver as initialisation goes along
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_FAILED: u8 = 128;
+
+// Without it the device only speaks the legacy interface
+pub const F_VERSION_1: u64 = 1 << 32;
+
+pub fn init() {
+    pci::register(&blk::DRIVER);
+}
@@ -0,0 +1,266 @@
+// Split virtqueues. The driver puts chains of descriptors in the available
+// ring and the device hands them back through the used ring when done. All
+// three parts live in one DMA buffer and are only touched with volatile
+// accesses, with fences where the device must see things in order.
+use crate::{mm::dma::DmaBuffer, syscall::Errno};
+use core::{
+    mem,
+    ptr,
+    sync::atomic::{fence, Ordering},
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct UsedElem {
+    id: u32,
+    len: u32,
+}
+
+// Flags and index come before the ring in both the available and used rings
+const RING_HEADER: usize = 4;
+
+// A piece of memory for a request, which the device either reads or writes
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub addr: PhysAddr,
+    pub len: u32,
+    pub device_writes: bool,
+}
+
+pub struct VirtQueue {
+    index: u16,
+    size: u16,
+    memory: DmaBuffer,
+    avail_offset: usize,
+    used_offset: usize,
+    // Free descriptors are chained together through their next fields
+    free_head: u16,
+    free_count: u16,
+    avail_idx: u16,
+    last_used: u16,
+    notify: VirtAddr,
+}
+
+impl VirtQueue {
+    // Size must be a power of two. Notify is where the transport wants the
+    // queue index written to kick the device.
+    pub fn new(index: u16, size: u16, notify: VirtAddr) -> Result<VirtQueue, Errno> {
+        assert!(size.is_power_of_two());
+        let avail_offset = usize::from(size) * mem::size_of::<Descriptor>();
+        let avail_len = RING_HEADER + 2 * usize::from(size) + 2;
+        let used_offset = x86_64::align_up(avail_offset + avail_len, 4);
+        let used_len = RING_HEADER + mem::size_of::<UsedElem>() * usize::from(size) + 2;
+
+        let queue = VirtQueue {
+            index,
+            size,
+            memory: DmaBuffer::new(used_offset + used_len)?,
+            avail_offset,
+            used_offset,
+            free_head: 0,
+            free_count: size,
+            avail_idx: 0,
+            last_used: 0,
+            notify,
+        };
+        for i in 0..size {
+            unsafe { (*queue.desc(i)).next = i.wrapping_add(1) };
+        }
+        Ok(queue)
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn desc_addr(&self) -> PhysAddr {
+        self.memory.phys()
+    }
+
+    pub fn avail_addr(&self) -> PhysAddr {
+        self.memory.phys() + self.avail_offset
+    }
+
+    pub fn used_addr(&self) -> PhysAddr {
+        self.memory.phys() + self.used_offset
+    }
+
+    fn desc(&self, i: u16) -> *mut Descriptor {
+        self.memory.as_mut_ptr(usize::from(i) * mem::size_of::<Descriptor>())
+    }
+
+    fn avail(&self, offset: usize) -> *mut u16 {
+        self.memory.as_mut_ptr(self.avail_offset + offset)
+    }
+
+    fn used(&self, offset: usize) -> *mut u16 {
+        self.memory.as_mut_ptr(self.used_offset + offset)
+    }
+
+    pub fn free_descriptors(&self) -> u16 {
+        self.free_count
+    }
+
+    // The head the next chain added will get, for drivers that keep per
+    // request state indexed by it
+    pub fn next_head(&self) -> Option<u16> {
+        if self.free_count > 0 {
+            Some(self.free_head)
+        } else {
+            None
+        }
+    }
+
+    // Chains the buffers and makes them available, returning the head
+    // descriptor that identifies the chain once used. None if the queue is too
+    // full for them. The device isn't told until notify.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > usize::from(self.free_count) {
+            return None;
+        }
+
+        let head = self.free_head;
+        let mut i = head;
+        for (n, buffer) in buffers.iter().enumerate() {
+            let desc = self.desc(i);
+            let mut flags = if buffer.device_writes { DESC_F_WRITE } else { 0 };
+            // The next free descriptor is the next in the chain
+            if n + 1 < buffers.len() {
+                flags |= DESC_F_NEXT;
+            }
+            unsafe {
+                ptr::write_volatile(&mut (*desc).addr, buffer.addr.as_usize() as u64);
+                ptr::write_volatile(&mut (*desc).len, buffer.len);
+                ptr::write_volatile(&mut (*desc).flags, flags);
+            }
+
+            let next = unsafe { ptr::read_volatile(&(*desc).next) };
+            if n + 1 == buffers.len() {
+                self.free_head = next;
+            }
+            i = next;
+        }
+        self.free_count -= buffers.len() as u16;
+
+        let slot = usize::from(self.avail_idx % self.size);
+        unsafe { ptr::write_volatile(self.avail(RING_HEADER + 2 * slot), head) };
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        // The ring entry has to be there before the device sees the index
+        fence(Ordering::SeqCst);
+        unsafe { ptr::write_volatile(self.avail(2), self.avail_idx) };
+        Some(head)
+    }
+
+    pub fn notify(&self) {
+        fence(Ordering::SeqCst);
+        unsafe { ptr::write_volatile(self.notify.as_mut_ptr::<u16>(), self.index) };
+    }
+
+    // Takes the next chain the device is done with, returning its head and
+    // how many bytes the device wrote into it
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used_idx = unsafe { ptr::read_volatile(self.used(2)) };
+        if used_idx == self.last_used {
+            return None;
+        }
+        // Don't read the entry before the index that covers it
+        fence(Ordering::SeqCst);
+
+        let slot = usize::from(self.last_used % self.size);
+        let elem: *mut UsedElem = self
+            .memory
+            .as_mut_ptr(self.used_offset + RING_HEADER + slot * mem::size_of::<UsedElem>());
+        let (id, len) = unsafe { (ptr::read_volatile(&(*elem).id), ptr::read_volatile(&(*elem).len)) };
+        self.last_used = self.last_used.wrapping_add(1);
+
+        // Put the chain back at the front of the free list
+        let head = id as u16;
+        let mut last = head;
+        let mut count = 1;
+        loop {
+            let desc = self.desc(last);
+            let flags = unsafe { ptr::read_volatile(&(*desc).flags) };
+            if flags & DESC_F_NEXT == 0 {
+                unsafe { ptr::write_volatile(&mut (*desc).next, self.free_head) };
+                break;
+            }
+            last = unsafe { ptr::read_volatile(&(*desc).next) };
+            count += 1;
+        }
+        self.free_head = head;
+        self.free_count += count;
+
+        Some((head, len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mm::phys_to_kernel_virt;
+
+    // Plays the device's part, returning the chain at the given avail slot
+    fn device_use(queue: &VirtQueue, slot: u16, len: u32) {
+        unsafe {
+            let head = ptr::read_volatile(queue.avail(RING_HEADER + 2 * usize::from(slot)));
+            let used_idx = ptr::read_volatile(queue.used(2));
+            let elem: *mut UsedElem = queue
+                .memory
+                .as_mut_ptr(queue.used_offset + RING_HEADER + usize::from(used_idx % queue.size) * 8);
+            ptr::write_volatile(elem, UsedElem { id: u32::from(head), len });
+            ptr::write_volatile(queue.used(2), used_idx.wrapping_add(1));
+        }
+    }
+
+    test_case!(chains_and_recycling, {
+        let scratch = DmaBuffer::new(4096).unwrap();
+        let notify = phys_to_kernel_virt(scratch.phys());
+        let mut queue = VirtQueue::new(3, 4, notify).unwrap();
+        assert_eq!(queue.avail_addr() - queue.desc_addr(), 64);
+        assert_eq!(queue.used_addr().as_usize() % 4, 0);
+
+        let buffer = |device_writes| Buffer {
+            addr: scratch.phys(),
+            len: 16,
+            device_writes,
+        };
+        let next = queue.next_head();
+        let first = queue.add(&[buffer(false), buffer(true), buffer(true)]).unwrap();
+        assert_eq!(next, Some(first));
+        assert_eq!(queue.free_descriptors(), 1);
+        assert!(queue.add(&[buffer(false), buffer(true)]).is_none());
+        unsafe {
+            assert_eq!((*queue.desc(first)).flags, DESC_F_NEXT);
+            let second = (*queue.desc(first)).next;
+            assert_eq!((*queue.desc(second)).flags, DESC_F_NEXT | DESC_F_WRITE);
+            assert_eq!((*queue.desc((*queue.desc(second)).next)).flags, DESC_F_WRITE);
+        }
+
+        queue.notify();
+        assert_eq!(unsafe { ptr::read_volatile(notify.as_ptr::<u16>()) }, 3);
+
+        assert_eq!(queue.pop_used(), None);
+        device_use(&queue, 0, 7);
+        assert_eq!(queue.pop_used(), Some((first, 7)));
+        assert_eq!(queue.free_descriptors(), 4);
+
+        // Wrap both rings around a few times
+        for round in 1..10 {
+            let head = queue.add(&[buffer(false), buffer(true)]).unwrap();
+            device_use(&queue, round % 4, round.into());
+            assert_eq!(queue.pop_used(), Some((head, round.into())));
+        }
+        assert_eq!(queue.free_descriptors(), 4);
+    });
+}
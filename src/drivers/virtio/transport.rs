@@ -0,0 +1,221 @@
+// The virtio PCI transport. Vendor specific capabilities say which BAR holds
+// each register block: the common configuration, queue notifications, the
+// interrupt status and the device's own configuration.
+use super::{
+    queue::VirtQueue,
+    F_VERSION_1,
+    STATUS_ACKNOWLEDGE,
+    STATUS_DRIVER,
+    STATUS_DRIVER_OK,
+    STATUS_FAILED,
+    STATUS_FEATURES_OK,
+};
+use crate::{
+    drivers::pci::{
+        device::{COMMAND_BUS_MASTER, COMMAND_MEMORY},
+        Bar,
+        Capability,
+        Device,
+    },
+    mm::mmio,
+    syscall::Errno,
+};
+use core::{cmp, ptr, sync::atomic::spin_loop_hint};
+use x86_64::{PhysAddr, VirtAddr};
+
+const CAP_VENDOR: u8 = 0x09;
+
+const CFG_COMMON: u8 = 1;
+const CFG_NOTIFY: u8 = 2;
+const CFG_ISR: u8 = 3;
+const CFG_DEVICE: u8 = 4;
+
+// Capability fields
+const CAP_CFG_TYPE: u16 = 3;
+const CAP_BAR: u16 = 4;
+const CAP_OFFSET: u16 = 8;
+const CAP_LENGTH: u16 = 12;
+const CAP_NOTIFY_MULTIPLIER: u16 = 16;
+
+// Common configuration registers
+const DEVICE_FEATURE_SELECT: usize = 0x00;
+const DEVICE_FEATURE: usize = 0x04;
+const DRIVER_FEATURE_SELECT: usize = 0x08;
+const DRIVER_FEATURE: usize = 0x0C;
+const MSIX_CONFIG: usize = 0x10;
+const DEVICE_STATUS: usize = 0x14;
+const CONFIG_GENERATION: usize = 0x15;
+const QUEUE_SELECT: usize = 0x16;
+const QUEUE_SIZE: usize = 0x18;
+const QUEUE_MSIX_VECTOR: usize = 0x1A;
+const QUEUE_ENABLE: usize = 0x1C;
+const QUEUE_NOTIFY_OFF: usize = 0x1E;
+const QUEUE_DESC: usize = 0x20;
+const QUEUE_DRIVER: usize = 0x28;
+const QUEUE_DEVICE: usize = 0x30;
+
+pub const NO_VECTOR: u16 = 0xFFFF;
+
+pub struct Transport {
+    common: VirtAddr,
+    notify: VirtAddr,
+    notify_multiplier: u32,
+    isr: VirtAddr,
+    config: Option<VirtAddr>,
+}
+
+impl Transport {
+    pub fn new(device: &Device) -> Result<Transport, Errno> {
+        let find = |cfg_type| {
+            device
+                .capabilities
+                .iter()
+                .find(|cap| cap.id == u16::from(CAP_VENDOR) && device.read_u8(cap.offset + CAP_CFG_TYPE) == cfg_type)
+                .copied()
+        };
+
+        // Devices with only the legacy interface have none of these
+        let common = find(CFG_COMMON).ok_or(Errno::ENODEV)?;
+        let notify = find(CFG_NOTIFY).ok_or(Errno::ENODEV)?;
+        let isr = find(CFG_ISR).ok_or(Errno::ENODEV)?;
+
+        device.update_command(COMMAND_MEMORY | COMMAND_BUS_MASTER, 0);
+        Ok(Transport {
+            common: map_region(device, common)?,
+            notify: map_region(device, notify)?,
+            notify_multiplier: device.read_u32(notify.offset + CAP_NOTIFY_MULTIPLIER),
+            isr: map_region(device, isr)?,
+            config: match find(CFG_DEVICE) {
+                Some(cap) => Some(map_region(device, cap)?),
+                None => None,
+            },
+        })
+    }
+
+    fn common<T>(&self, offset: usize) -> *mut T {
+        (self.common + offset).as_mut_ptr()
+    }
+
+    fn read_common<T>(&self, offset: usize) -> T {
+        unsafe { ptr::read_volatile(self.common(offset)) }
+    }
+
+    fn write_common<T>(&self, offset: usize, value: T) {
+        unsafe { ptr::write_volatile(self.common(offset), value) }
+    }
+
+    pub fn status(&self) -> u8 {
+        self.read_common(DEVICE_STATUS)
+    }
+
+    fn add_status(&self, bits: u8) {
+        self.write_common(DEVICE_STATUS, self.status() | bits);
+    }
+
+    // Puts the device back to its initial state, dropping its queues
+    pub fn reset(&self) {
+        self.write_common(DEVICE_STATUS, 0u8);
+        while self.status() != 0 {
+            spin_loop_hint();
+        }
+    }
+
+    // Resets the device and agrees on the features both sides know, out of
+    // wanted. Modern devices are required.
+    pub fn negotiate(&self, wanted: u64) -> Result<u64, Errno> {
+        self.reset();
+        self.add_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        self.write_common(DEVICE_FEATURE_SELECT, 0u32);
+        let low: u32 = self.read_common(DEVICE_FEATURE);
+        self.write_common(DEVICE_FEATURE_SELECT, 1u32);
+        let high: u32 = self.read_common(DEVICE_FEATURE);
+        let offered = u64::from(high) << 32 | u64::from(low);
+
+        let features = offered & (wanted | F_VERSION_1);
+        if features & F_VERSION_1 == 0 {
+            self.fail();
+            return Err(Errno::ENODEV);
+        }
+
+        self.write_common(DRIVER_FEATURE_SELECT, 0u32);
+        self.write_common(DRIVER_FEATURE, features as u32);
+        self.write_common(DRIVER_FEATURE_SELECT, 1u32);
+        self.write_common(DRIVER_FEATURE, (features >> 32) as u32);
+
+        self.add_status(STATUS_FEATURES_OK);
+        if self.status() & STATUS_FEATURES_OK == 0 {
+            self.fail();
+            return Err(Errno::ENODEV);
+        }
+        Ok(features)
+    }
+
+    // Sets up a queue of at most max_size entries, raising MSI-X entry vector
+    // when it has used buffers, if MSI-X is in use
+    pub fn setup_queue(&self, index: u16, max_size: u16, vector: u16) -> Result<VirtQueue, Errno> {
+        self.write_common(QUEUE_SELECT, index);
+        let size: u16 = self.read_common(QUEUE_SIZE);
+        if size == 0 {
+            return Err(Errno::ENODEV);
+        }
+
+        // Sizes needn't be powers of two in 1.0, but ours are
+        let mut size = cmp::min(size, max_size);
+        if !size.is_power_of_two() {
+            size = size.next_power_of_two() >> 1;
+        }
+
+        let notify_off: u16 = self.read_common(QUEUE_NOTIFY_OFF);
+        let notify = self.notify + usize::from(notify_off) * self.notify_multiplier as usize;
+        let queue = VirtQueue::new(index, size, notify)?;
+
+        self.write_common(QUEUE_SIZE, size);
+        self.write_common(QUEUE_DESC, queue.desc_addr().as_usize() as u64);
+        self.write_common(QUEUE_DRIVER, queue.avail_addr().as_usize() as u64);
+        self.write_common(QUEUE_DEVICE, queue.used_addr().as_usize() as u64);
+        self.write_common(QUEUE_MSIX_VECTOR, vector);
+        self.write_common(MSIX_CONFIG, NO_VECTOR);
+        self.write_common(QUEUE_ENABLE, 1u16);
+        Ok(queue)
+    }
+
+    // The device can be used from here on
+    pub fn driver_ok(&self) {
+        self.add_status(STATUS_DRIVER_OK);
+    }
+
+    pub fn fail(&self) {
+        self.add_status(STATUS_FAILED);
+    }
+
+    // Reading the interrupt status also clears it, which matters for pins.
+    // Bit 0 means a queue has used buffers, bit 1 a configuration change.
+    pub fn read_isr(&self) -> u8 {
+        unsafe { ptr::read_volatile(self.isr.as_ptr::<u8>()) }
+    }
+
+    // Reads device configuration, retrying if it changes halfway through
+    pub fn read_config<T: Copy>(&self, offset: usize) -> Option<T> {
+        let config = self.config?;
+        loop {
+            let generation: u8 = self.read_common(CONFIG_GENERATION);
+            let value = unsafe { ptr::read_volatile((config + offset).as_ptr::<T>()) };
+            if self.read_common::<u8>(CONFIG_GENERATION) == generation {
+                return Some(value);
+            }
+        }
+    }
+}
+
+fn map_region(device: &Device, cap: Capability) -> Result<VirtAddr, Errno> {
+    let bar = usize::from(device.read_u8(cap.offset + CAP_BAR));
+    let base = match device.bars.get(bar) {
+        Some(Some(Bar::Memory { addr, .. })) => *addr,
+        _ => return Err(Errno::ENODEV),
+    };
+
+    let offset = u64::from(device.read_u32(cap.offset + CAP_OFFSET));
+    let length = device.read_u32(cap.offset + CAP_LENGTH) as usize;
+    mmio::map(PhysAddr::new((base + offset) as usize), length).map_err(|_| Errno::ENOMEM)
+}
@@ -1,2 +1,2 @@
 pub mod sync;
-pub use sync::{rwspinlock::RwSpinLock, spinlock::SpinLock};
+pub use sync::{mutex::Mutex, rwspinlock::RwSpinLock, spinlock::SpinLock};
@@ -1,2 +1,3 @@
+pub mod mutex;
 pub mod rwspinlock;
 pub mod spinlock;
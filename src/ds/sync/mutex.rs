@@ -0,0 +1,96 @@
+// A lock for data that's held across things that sleep, like block I/O.
+// Threads run until they yield, so a thread spinning on a SpinLock would never
+// let the holder finish. Waiting on a Mutex yields instead.
+use crate::task;
+use core::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+pub struct Mutex<T> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Send> Send for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<T> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            task::yield_now();
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        if self.locked.compare_and_swap(false, true, Ordering::Acquire) {
+            return None;
+        }
+
+        Some(MutexGuard {
+            locked: &self.locked,
+            data: unsafe { &mut *self.data.get() },
+        })
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    locked: &'a AtomicBool,
+    data: &'a mut T,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &*self.data
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.data
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::percpu::PerCpu;
+
+    test_case!(lock, {
+        let m = Mutex::new(1);
+        {
+            let mut l = m.lock();
+            *l += 1;
+            assert!(m.try_lock().is_none());
+
+            // Doesn't hold off preemption like a SpinLock
+            assert_eq!(PerCpu::current().preempt_count(Ordering::SeqCst), 0);
+        }
+        assert_eq!(*m.try_lock().unwrap(), 2);
+    });
+}
@@ -0,0 +1,146 @@
+// Assembles small ELF files for tests
+use super::*;
+use alloc::vec::Vec;
+
+struct Segment {
+    header: ProgramHeader,
+    data: Vec<u8>,
+}
+
+pub struct ElfBuilder {
+    header: Header,
+    segments: Vec<Segment>,
+    phoff: Option<u64>,
+}
+
+impl ElfBuilder {
+    pub fn new(entry: u64) -> ElfBuilder {
+        let mut ident = [0; 16];
+        ident[0..4].copy_from_slice(&MAGIC);
+        ident[4] = CLASS_64;
+        ident[5] = DATA_LSB;
+        ident[6] = VERSION_CURRENT;
+
+        ElfBuilder {
+            header: Header {
+                ident,
+                elf_type: ET_EXEC,
+                machine: EM_X86_64,
+                version: u32::from(VERSION_CURRENT),
+                entry,
+                phoff: 0,
+                shoff: 0,
+                flags: 0,
+                ehsize: mem::size_of::<Header>() as u16,
+                phentsize: mem::size_of::<ProgramHeader>() as u16,
+                phnum: 0,
+                shentsize: 0,
+                shnum: 0,
+                shstrndx: 0,
+            },
+            segments: Vec::new(),
+            phoff: None,
+        }
+    }
+
+    // A PT_LOAD segment with the given contents, placed in the file at an
+    // offset congruent to vaddr
+    pub fn segment(mut self, vaddr: u64, data: &[u8], memsz: u64, flags: u32) -> Self {
+        self.segments.push(Segment {
+            header: ProgramHeader {
+                p_type: PT_LOAD,
+                flags,
+                offset: 0,
+                vaddr,
+                paddr: vaddr,
+                filesz: data.len() as u64,
+                memsz,
+                align: PAGE_SIZE,
+            },
+            data: data.to_vec(),
+        });
+        self
+    }
+
+    // A program header written as is, without any data
+    pub fn raw_segment(
+        mut self,
+        p_type: u32,
+        vaddr: u64,
+        offset: u64,
+        filesz: u64,
+        memsz: u64,
+        flags: u32,
+    ) -> Self {
+        self.segments.push(Segment {
+            header: ProgramHeader {
+                p_type,
+                flags,
+                offset,
+                vaddr,
+                paddr: vaddr,
+                filesz,
+                memsz,
+                align: PAGE_SIZE,
+            },
+            data: Vec::new(),
+        });
+        self
+    }
+
+    pub fn entry(mut self, entry: u64) -> Self {
+        self.header.entry = entry;
+        self
+    }
+
+    pub fn elf_type(mut self, elf_type: u16) -> Self {
+        self.header.elf_type = elf_type;
+        self
+    }
+
+    pub fn machine(mut self, machine: u16) -> Self {
+        self.header.machine = machine;
+        self
+    }
+
+    pub fn phentsize(mut self, phentsize: u16) -> Self {
+        self.header.phentsize = phentsize;
+        self
+    }
+
+    pub fn phoff(mut self, phoff: u64) -> Self {
+        self.phoff = Some(phoff);
+        self
+    }
+
+    pub fn build(mut self) -> Vec<u8> {
+        let header_size = mem::size_of::<Header>();
+        let ph_size = mem::size_of::<ProgramHeader>();
+
+        self.header.phnum = self.segments.len() as u16;
+        self.header.phoff = self.phoff.unwrap_or(header_size as u64);
+
+        // Segment data goes after the headers, one page each at least
+        let mut file = Vec::new();
+        file.resize(PAGE_SIZE as usize, 0);
+        for segment in self.segments.iter_mut().filter(|s| !s.data.is_empty()) {
+            let page = (file.len() as u64 + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+            segment.header.offset = page + segment.header.vaddr % PAGE_SIZE;
+            file.resize(segment.header.offset as usize, 0);
+            file.extend_from_slice(&segment.data);
+        }
+
+        write(&mut file, 0, &self.header);
+        for (i, segment) in self.segments.iter().enumerate() {
+            write(&mut file, header_size + i * ph_size, &segment.header);
+        }
+
+        file
+    }
+}
+
+fn write<T: Copy>(file: &mut Vec<u8>, offset: usize, value: &T) {
+    let bytes =
+        unsafe { core::slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) };
+    file[offset..offset + bytes.len()].copy_from_slice(bytes);
+}
@@ -0,0 +1,333 @@
+// Loads a parsed ELF file into a user address space and builds the initial
+// stack as described by the System V x86_64 ABI
+use super::{Elf, ElfError, PF_W, PF_X};
+use crate::{
+    cpu::user::enter_user,
+    mm::{self, addr_space::AddrSpace},
+};
+use core::{cmp, mem};
+use x86_64::{
+    instructions::random::RdRand,
+    structures::paging::{PageSize, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+pub const USER_STACK_TOP: usize = 0x7FFF_FFFF_F000;
+pub const USER_STACK_PAGES: usize = 16;
+
+// Arguments and environment may use up to half of the initial stack
+pub const MAX_ARGS_SIZE: usize = USER_STACK_PAGES * Size4KiB::SIZE / 2;
+
+// Auxiliary vector entry types
+const AT_NULL: usize = 0;
+const AT_PHDR: usize = 3;
+const AT_PHENT: usize = 4;
+const AT_PHNUM: usize = 5;
+const AT_PAGESZ: usize = 6;
+const AT_ENTRY: usize = 9;
+const AT_RANDOM: usize = 25;
+
+#[derive(Debug, Clone, Copy)]
+pub struct LoadedImage {
+    pub entry: VirtAddr,
+    pub stack_pointer: VirtAddr,
+    // End of the highest segment, where the heap can start
+    pub brk: VirtAddr,
+}
+
+pub fn load(space: &AddrSpace, elf: &Elf, argv: &[&[u8]], envp: &[&[u8]]) -> Result<LoadedImage, ElfError> {
+    let mut brk = 0;
+
+    for ph in elf.segments() {
+        load_segment(space, ph.vaddr as usize, elf.segment_data(&ph), ph.memsz as usize, ph.flags)?;
+        brk = cmp::max(brk, (ph.vaddr + ph.memsz) as usize);
+    }
+
+    let stack_pointer = setup_stack(space, elf, argv, envp)?;
+
+    Ok(LoadedImage {
+        entry: VirtAddr::new(elf.entry() as usize),
+        stack_pointer,
+        brk: VirtAddr::new(brk).align_up(Size4KiB::SIZE),
+    })
+}
+
+// Jumps to the image, which must have been loaded into the current thread's
+// address space
+pub unsafe fn start(image: &LoadedImage) -> ! {
+    enter_user(image.entry, image.stack_pointer)
+}
+
+fn segment_flags(flags: u32) -> PageTableFlags {
+    let mut page_flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if flags & PF_W != 0 {
+        page_flags |= PageTableFlags::WRITABLE;
+    }
+    if flags & PF_X == 0 {
+        page_flags |= PageTableFlags::NO_EXECUTE;
+    }
+    page_flags
+}
+
+fn load_segment(
+    space: &AddrSpace,
+    vaddr: usize,
+    data: &[u8],
+    memsz: usize,
+    flags: u32,
+) -> Result<(), ElfError> {
+    let flags = segment_flags(flags);
+    let start = VirtAddr::new(vaddr).align_down(Size4KiB::SIZE);
+    let end = VirtAddr::new(vaddr + memsz).align_up(Size4KiB::SIZE);
+
+    let mut page = start;
+    while page < end {
+        // Segments which aren't page aligned can share a page with the
+        // previous one, in which case it gets the union of both permissions
+        match space.translate_page(page) {
+            Some((_, old)) => {
+                let mut merged = old | flags;
+                if !(old & flags).contains(PageTableFlags::NO_EXECUTE) {
+                    merged.remove(PageTableFlags::NO_EXECUTE);
+                }
+                space
+                    .update_flags_range(page, Size4KiB::SIZE, merged)
+                    .map_err(|_| ElfError::OutOfMemory)?;
+            }
+            None => {
+                space.map_zeroed(page, flags).map_err(|_| ElfError::OutOfMemory)?;
+            }
+        }
+
+        page += Size4KiB::SIZE;
+    }
+
+    write_to_space(space, VirtAddr::new(vaddr), data);
+    trace!("elf: loaded {:?}-{:?}, {:?}", start, end, flags);
+
+    Ok(())
+}
+
+// Copies into an address space which may not be active, through the physical
+// memory map. Every page must be mapped.
+fn write_to_space(space: &AddrSpace, mut addr: VirtAddr, mut data: &[u8]) {
+    while !data.is_empty() {
+        let (phys, _) = space.translate_page(addr).expect("writing to unmapped user memory");
+        let page_left = Size4KiB::SIZE - (addr.as_usize() % Size4KiB::SIZE);
+        let len = cmp::min(page_left, data.len());
+
+        unsafe {
+            let dst = mm::phys_to_kernel_virt(phys).as_mut_ptr::<u8>();
+            core::ptr::copy_nonoverlapping(data.as_ptr(), dst, len);
+        }
+
+        addr += len;
+        data = &data[len..];
+    }
+}
+
+fn random_bytes() -> [u8; 16] {
+    let mut bytes = [0; 16];
+
+    let (a, b) = match RdRand::new().and_then(|rand| Some((rand.get_u64()?, rand.get_u64()?))) {
+        Some(values) => values,
+        // Not random, but different for every exec
+        None => unsafe {
+            let tsc = core::arch::x86_64::_rdtsc();
+            (tsc, tsc.rotate_left(32) ^ 0x9E37_79B9_7F4A_7C15)
+        },
+    };
+
+    bytes[..8].copy_from_slice(&a.to_le_bytes());
+    bytes[8..].copy_from_slice(&b.to_le_bytes());
+    bytes
+}
+
+// Builds the initial stack. From the top: the AT_RANDOM bytes, the argument
+// and environment strings, then aligned to 16 bytes the auxiliary vector,
+// envp, argv and argc, which the stack pointer points at.
+fn setup_stack(space: &AddrSpace, elf: &Elf, argv: &[&[u8]], envp: &[&[u8]]) -> Result<VirtAddr, ElfError> {
+    let word = mem::size_of::<usize>();
+    let strings_size: usize = argv.iter().chain(envp.iter()).map(|s| s.len() + 1).sum();
+    let vectors_size = (argv.len() + envp.len() + 3 + 2 * 7) * word;
+    if strings_size + vectors_size > MAX_ARGS_SIZE {
+        return Err(ElfError::ArgumentsTooLarge);
+    }
+
+    let stack_flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::NO_EXECUTE;
+    for i in 1..=USER_STACK_PAGES {
+        space
+            .map_zeroed(VirtAddr::new(USER_STACK_TOP - i * Size4KiB::SIZE), stack_flags)
+            .map_err(|_| ElfError::OutOfMemory)?;
+    }
+
+    let mut sp = USER_STACK_TOP;
+    let mut push = |bytes: &[u8]| {
+        sp -= bytes.len();
+        write_to_space(space, VirtAddr::new(sp), bytes);
+        sp
+    };
+
+    let random = push(&random_bytes());
+
+    // Strings are pushed in reverse, so that they end up in order
+    let mut push_strings = |strings: &[&[u8]], out: &mut alloc::vec::Vec<usize>| {
+        for s in strings.iter().rev() {
+            push(&[0]);
+            out.push(push(s));
+        }
+        out.reverse();
+    };
+
+    let mut env_ptrs = alloc::vec::Vec::with_capacity(envp.len());
+    let mut arg_ptrs = alloc::vec::Vec::with_capacity(argv.len());
+    push_strings(envp, &mut env_ptrs);
+    push_strings(argv, &mut arg_ptrs);
+
+    let phdr = elf.phdr_addr().unwrap_or(0) as usize;
+    let auxv = [
+        (AT_PHDR, phdr),
+        (AT_PHENT, usize::from(elf.header().phentsize)),
+        (AT_PHNUM, usize::from(elf.header().phnum)),
+        (AT_PAGESZ, Size4KiB::SIZE),
+        (AT_ENTRY, elf.entry() as usize),
+        (AT_RANDOM, random),
+        (AT_NULL, 0),
+    ];
+
+    let mut words = alloc::vec::Vec::with_capacity(vectors_size / word);
+    words.push(argv.len());
+    words.extend_from_slice(&arg_ptrs);
+    words.push(0);
+    words.extend_from_slice(&env_ptrs);
+    words.push(0);
+    for &(key, value) in auxv.iter() {
+        words.push(key);
+        words.push(value);
+    }
+
+    // The stack pointer must be 16 byte aligned at the entry point
+    sp = (sp - words.len() * word) & !0xF;
+    for (i, value) in words.iter().enumerate() {
+        write_to_space(space, VirtAddr::new(sp + i * word), &value.to_le_bytes());
+    }
+
+    Ok(VirtAddr::new(sp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        elf::{builder::ElfBuilder, Elf, PF_R},
+        mm::pmm::PhysAllocator,
+        task,
+    };
+    use alloc::{boxed::Box, sync::Arc};
+
+    // mov (%rsp), %rdi; mov $60, %eax; syscall; jmp .
+    const EXIT_ARGC: [u8; 13] = [
+        0x48, 0x8B, 0x3C, 0x24, 0xB8, 0x3C, 0x00, 0x00, 0x00, 0x0F, 0x05, 0xEB, 0xFE,
+    ];
+
+    fn read_word(space: &AddrSpace, addr: usize) -> usize {
+        let (phys, _) = space.translate_page(VirtAddr::new(addr)).unwrap();
+        unsafe { *mm::phys_to_kernel_virt(phys).as_ptr::<usize>() }
+    }
+
+    fn read_byte(space: &AddrSpace, addr: usize) -> u8 {
+        let (phys, _) = space.translate_page(VirtAddr::new(addr)).unwrap();
+        unsafe { *mm::phys_to_kernel_virt(phys).as_ptr::<u8>() }
+    }
+
+    fn load_sample() -> (AddrSpace, LoadedImage) {
+        let data = ElfBuilder::new(0x40_0000)
+            .segment(0x40_0000, &EXIT_ARGC, 0x1000, PF_R | PF_X)
+            .segment(0x60_0010, b"data", 0x3000, PF_R | PF_W)
+            .build();
+        let elf = Elf::parse(&data).unwrap();
+
+        let space = AddrSpace::new_user().unwrap();
+        let image = load(&space, &elf, &[b"prog", b"arg"], &[b"HOME=/"]).unwrap();
+        (space, image)
+    }
+
+    test_case!(load_segments_and_stack, {
+        let (space, image) = load_sample();
+        assert_eq!(image.entry, VirtAddr::new(0x40_0000));
+        assert_eq!(image.brk, VirtAddr::new(0x60_4000));
+
+        let (_, code_flags) = space.translate_page(VirtAddr::new(0x40_0000)).unwrap();
+        assert!(code_flags.contains(PageTableFlags::USER_ACCESSIBLE));
+        assert!(!code_flags.contains(PageTableFlags::WRITABLE));
+        assert!(!code_flags.contains(PageTableFlags::NO_EXECUTE));
+
+        let (_, data_flags) = space.translate_page(VirtAddr::new(0x60_2000)).unwrap();
+        assert!(data_flags.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+
+        // File contents, then .bss
+        assert_eq!(read_byte(&space, 0x60_0010), b'd');
+        assert_eq!(read_byte(&space, 0x60_0013), b'a');
+        assert_eq!(read_word(&space, 0x60_0018), 0);
+        assert_eq!(read_word(&space, 0x60_2FF8), 0);
+
+        let sp = image.stack_pointer.as_usize();
+        assert_eq!(sp % 16, 0);
+        assert_eq!(read_word(&space, sp), 2);
+        let argv0 = read_word(&space, sp + 8);
+        assert_eq!(read_byte(&space, argv0), b'p');
+        assert_eq!(read_byte(&space, argv0 + 4), 0);
+        let argv1 = read_word(&space, sp + 16);
+        assert_eq!(read_byte(&space, argv1), b'a');
+        assert_eq!(read_word(&space, sp + 24), 0);
+        let envp0 = read_word(&space, sp + 32);
+        assert_eq!(read_byte(&space, envp0), b'H');
+        assert_eq!(read_word(&space, sp + 40), 0);
+
+        // auxv, after envp
+        assert_eq!(read_word(&space, sp + 48), AT_PHDR);
+    });
+
+    test_case!(dropping_frees_frames, {
+        // The first round may grow the heap
+        drop(load_sample());
+
+        let free = PhysAllocator::free_pages();
+        drop(load_sample());
+        assert_eq!(PhysAllocator::free_pages(), free);
+    });
+
+    test_case!(arguments_too_large, {
+        let data = ElfBuilder::new(0x40_0000)
+            .segment(0x40_0000, &EXIT_ARGC, 0x1000, PF_R | PF_X)
+            .build();
+        let elf = Elf::parse(&data).unwrap();
+
+        let big = [b'x'; MAX_ARGS_SIZE];
+        let space = AddrSpace::new_user().unwrap();
+        assert_eq!(load(&space, &elf, &[&big], &[]).err(), Some(ElfError::ArgumentsTooLarge));
+    });
+
+    fn run_image(arg: usize) {
+        let (space, image) = *unsafe { Box::from_raw(arg as *mut (Arc<AddrSpace>, LoadedImage)) };
+        task::set_addr_space(Some(space));
+        unsafe { start(&image) };
+    }
+
+    test_case!(run_program, {
+        let data = ElfBuilder::new(0x40_0000)
+            .segment(0x40_0000, &EXIT_ARGC, 0x1000, PF_R | PF_X)
+            .build();
+        let elf = Elf::parse(&data).unwrap();
+
+        let space = Arc::new(AddrSpace::new_user().unwrap());
+        let image = load(&space, &elf, &[b"a", b"b", b"c"], &[]).unwrap();
+
+        let arg = Box::into_raw(Box::new((space, image))) as usize;
+        let thread = task::spawn("elf", run_image, arg);
+        assert_eq!(task::join(&thread), 3);
+    });
+}
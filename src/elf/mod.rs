@@ -0,0 +1,108 @@
+// ELF executables, and loading them into user address spaces
+#[cfg(test)]
+pub mod builder;
+pub mod load;
+mod parse;
+
+pub use load::{load, LoadedImage};
+pub use parse::*;
+
+// End of the lower half, segments must stay below it
+const USER_END: u64 = crate::mm::uaccess::USER_END as u64;
+
+#[cfg(test)]
+mod tests {
+    use super::{builder::ElfBuilder, *};
+
+    const CODE: [u8; 2] = [0xEB, 0xFE];
+
+    fn valid() -> ElfBuilder {
+        ElfBuilder::new(0x40_0000)
+            .segment(0x40_0000, &CODE, 0x1000, PF_R | PF_X)
+            .segment(0x60_0000, b"data", 0x3000, PF_R | PF_W)
+    }
+
+    fn parse_err(data: &[u8]) -> ElfError {
+        match Elf::parse(data) {
+            Ok(_) => panic!("expected an error"),
+            Err(e) => e,
+        }
+    }
+
+    test_case!(parse_valid, {
+        let data = valid().build();
+        let elf = Elf::parse(&data).unwrap();
+        assert_eq!(elf.entry(), 0x40_0000);
+
+        let segments: alloc::vec::Vec<_> = elf.segments().collect();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].vaddr, 0x40_0000);
+        assert_eq!(elf.segment_data(&segments[0]), &CODE);
+        assert_eq!(segments[1].memsz, 0x3000);
+        assert_eq!(elf.segment_data(&segments[1]), b"data");
+    });
+
+    test_case!(reject_bad_identification, {
+        assert_eq!(parse_err(&[0x7F, b'E', b'L']), ElfError::TooShort);
+
+        let mut data = valid().build();
+        data[0] = 0;
+        assert_eq!(parse_err(&data), ElfError::BadMagic);
+
+        let mut data = valid().build();
+        data[4] = 1;
+        assert_eq!(parse_err(&data), ElfError::UnsupportedClass(1));
+
+        let mut data = valid().build();
+        data[5] = 2;
+        assert_eq!(parse_err(&data), ElfError::UnsupportedEndianness(2));
+
+        let data = valid().elf_type(ET_DYN).build();
+        assert_eq!(parse_err(&data), ElfError::UnsupportedType(ET_DYN));
+
+        let data = valid().machine(3).build();
+        assert_eq!(parse_err(&data), ElfError::UnsupportedMachine(3));
+    });
+
+    test_case!(reject_bad_program_headers, {
+        let data = valid().phoff(0x10_0000).build();
+        assert_eq!(parse_err(&data), ElfError::ProgramHeadersOutOfBounds);
+
+        let data = valid().phentsize(48).build();
+        assert_eq!(parse_err(&data), ElfError::BadProgramHeaderSize(48));
+    });
+
+    test_case!(reject_bad_segments, {
+        let data = valid().raw_segment(PT_LOAD, 0x80_0000, 0x1000, 0x10_0000, 0x10_0000, PF_R).build();
+        assert_eq!(parse_err(&data), ElfError::SegmentOutOfBounds(2));
+
+        let data = valid().raw_segment(PT_LOAD, 0x80_0000, 0x1000, 0x10, 0x8, PF_R).build();
+        assert_eq!(parse_err(&data), ElfError::FileSizeExceedsMemSize(2));
+
+        let data = valid().raw_segment(PT_LOAD, 0x80_0010, 0x1000, 0x10, 0x10, PF_R).build();
+        assert_eq!(parse_err(&data), ElfError::MisalignedSegment(2));
+
+        let data = valid().raw_segment(PT_LOAD, 0xFFFF_8000_0000_0000, 0x1000, 0x10, 0x10, PF_R).build();
+        assert_eq!(parse_err(&data), ElfError::SegmentNotInUserSpace(2));
+
+        let data = valid().raw_segment(PT_LOAD, 0x0, 0x1000, 0x10, 0x10, PF_R).build();
+        assert_eq!(parse_err(&data), ElfError::SegmentNotInUserSpace(2));
+
+        let data = valid().raw_segment(PT_LOAD, 0x60_2000, 0x1000, 0x10, 0x10, PF_R).build();
+        assert_eq!(parse_err(&data), ElfError::OverlappingSegments(1, 2));
+
+        let data = valid().raw_segment(PT_INTERP, 0, 0x1000, 0x10, 0x10, PF_R).build();
+        assert_eq!(parse_err(&data), ElfError::InterpreterNotSupported);
+    });
+
+    test_case!(reject_bad_entry, {
+        let data = ElfBuilder::new(0x40_0000).build();
+        assert_eq!(parse_err(&data), ElfError::NoLoadableSegments);
+
+        let data = valid().entry(0x60_0000).build();
+        assert_eq!(parse_err(&data), ElfError::EntryNotExecutable(0x60_0000));
+
+        let data = valid().entry(0x50_0000).build();
+        assert_eq!(parse_err(&data), ElfError::EntryNotExecutable(0x50_0000));
+    });
+}
@@ -0,0 +1,256 @@
+// ELF64 parsing. Only statically linked x86_64 executables are supported.
+// This doesn't use anything else from the kernel, so that it can be tested on
+// the host as well, see tools/elf-test.
+use super::USER_END;
+use core::{fmt, mem, ptr};
+
+pub const MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+pub const CLASS_64: u8 = 2;
+pub const DATA_LSB: u8 = 1;
+pub const VERSION_CURRENT: u8 = 1;
+
+pub const ET_EXEC: u16 = 2;
+pub const ET_DYN: u16 = 3;
+pub const EM_X86_64: u16 = 62;
+
+pub const PT_NULL: u32 = 0;
+pub const PT_LOAD: u32 = 1;
+pub const PT_DYNAMIC: u32 = 2;
+pub const PT_INTERP: u32 = 3;
+pub const PT_PHDR: u32 = 6;
+pub const PT_TLS: u32 = 7;
+
+pub const PF_X: u32 = 1 << 0;
+pub const PF_W: u32 = 1 << 1;
+pub const PF_R: u32 = 1 << 2;
+
+pub(super) const PAGE_SIZE: u64 = 4096;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Header {
+    pub ident: [u8; 16],
+    pub elf_type: u16,
+    pub machine: u16,
+    pub version: u32,
+    pub entry: u64,
+    pub phoff: u64,
+    pub shoff: u64,
+    pub flags: u32,
+    pub ehsize: u16,
+    pub phentsize: u16,
+    pub phnum: u16,
+    pub shentsize: u16,
+    pub shnum: u16,
+    pub shstrndx: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub p_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    // File layout
+    TooShort,
+    BadMagic,
+    UnsupportedClass(u8),
+    UnsupportedEndianness(u8),
+    UnsupportedVersion(u32),
+    UnsupportedType(u16),
+    UnsupportedMachine(u16),
+    BadHeaderSize(u16),
+    BadProgramHeaderSize(u16),
+    ProgramHeadersOutOfBounds,
+    // Segments, by program header index
+    SegmentOutOfBounds(usize),
+    FileSizeExceedsMemSize(usize),
+    MisalignedSegment(usize),
+    SegmentNotInUserSpace(usize),
+    OverlappingSegments(usize, usize),
+    InterpreterNotSupported,
+    NoLoadableSegments,
+    EntryNotExecutable(u64),
+    // Loading
+    OutOfMemory,
+    ArgumentsTooLarge,
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use ElfError::*;
+
+        match *self {
+            TooShort => write!(f, "file is shorter than the ELF header"),
+            BadMagic => write!(f, "bad magic number"),
+            UnsupportedClass(c) => write!(f, "unsupported class {}, expected 64-bit", c),
+            UnsupportedEndianness(e) => write!(f, "unsupported data encoding {}, expected little endian", e),
+            UnsupportedVersion(v) => write!(f, "unsupported ELF version {}", v),
+            UnsupportedType(t) => write!(f, "unsupported file type {}, expected ET_EXEC", t),
+            UnsupportedMachine(m) => write!(f, "unsupported machine {}, expected x86_64", m),
+            BadHeaderSize(s) => write!(f, "bad ELF header size {}", s),
+            BadProgramHeaderSize(s) => write!(f, "bad program header size {}", s),
+            ProgramHeadersOutOfBounds => write!(f, "program headers extend past the end of the file"),
+            SegmentOutOfBounds(i) => write!(f, "segment {} extends past the end of the file", i),
+            FileSizeExceedsMemSize(i) => write!(f, "segment {} has a file size larger than its memory size", i),
+            MisalignedSegment(i) => write!(f, "segment {} has a file offset and address that differ modulo the page size", i),
+            SegmentNotInUserSpace(i) => write!(f, "segment {} is not in user space", i),
+            OverlappingSegments(i, j) => write!(f, "segments {} and {} overlap", i, j),
+            InterpreterNotSupported => write!(f, "dynamically linked executables are not supported"),
+            NoLoadableSegments => write!(f, "no loadable segments"),
+            EntryNotExecutable(e) => write!(f, "entry point {:#x} is not in an executable segment", e),
+            OutOfMemory => write!(f, "out of memory"),
+            ArgumentsTooLarge => write!(f, "arguments and environment don't fit on the stack"),
+        }
+    }
+}
+
+// A validated ELF file
+pub struct Elf<'a> {
+    data: &'a [u8],
+    header: Header,
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Elf<'a>, ElfError> {
+        let header: Header = read(data, 0).ok_or(ElfError::TooShort)?;
+
+        if header.ident[0..4] != MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if header.ident[4] != CLASS_64 {
+            return Err(ElfError::UnsupportedClass(header.ident[4]));
+        }
+        if header.ident[5] != DATA_LSB {
+            return Err(ElfError::UnsupportedEndianness(header.ident[5]));
+        }
+        if header.ident[6] != VERSION_CURRENT || header.version != u32::from(VERSION_CURRENT) {
+            return Err(ElfError::UnsupportedVersion(header.version));
+        }
+        if header.elf_type != ET_EXEC {
+            return Err(ElfError::UnsupportedType(header.elf_type));
+        }
+        if header.machine != EM_X86_64 {
+            return Err(ElfError::UnsupportedMachine(header.machine));
+        }
+        if usize::from(header.ehsize) != mem::size_of::<Header>() {
+            return Err(ElfError::BadHeaderSize(header.ehsize));
+        }
+        if usize::from(header.phentsize) != mem::size_of::<ProgramHeader>() {
+            return Err(ElfError::BadProgramHeaderSize(header.phentsize));
+        }
+
+        let ph_size = u64::from(header.phnum) * u64::from(header.phentsize);
+        match header.phoff.checked_add(ph_size) {
+            Some(end) if end <= data.len() as u64 => {}
+            _ => return Err(ElfError::ProgramHeadersOutOfBounds),
+        }
+
+        let elf = Elf { data, header };
+        elf.validate_segments()?;
+        Ok(elf)
+    }
+
+    fn validate_segments(&self) -> Result<(), ElfError> {
+        let mut loadable = 0;
+        let mut entry_ok = false;
+
+        for (i, ph) in self.program_headers().enumerate() {
+            match ph.p_type {
+                PT_INTERP => return Err(ElfError::InterpreterNotSupported),
+                PT_LOAD => {}
+                _ => continue,
+            }
+
+            match ph.offset.checked_add(ph.filesz) {
+                Some(end) if end <= self.data.len() as u64 => {}
+                _ => return Err(ElfError::SegmentOutOfBounds(i)),
+            }
+            if ph.filesz > ph.memsz {
+                return Err(ElfError::FileSizeExceedsMemSize(i));
+            }
+            if ph.vaddr % PAGE_SIZE != ph.offset % PAGE_SIZE {
+                return Err(ElfError::MisalignedSegment(i));
+            }
+            match ph.vaddr.checked_add(ph.memsz) {
+                Some(end) if end <= USER_END && ph.vaddr >= PAGE_SIZE => {}
+                _ => return Err(ElfError::SegmentNotInUserSpace(i)),
+            }
+
+            for (j, other) in self.program_headers().enumerate().take(i) {
+                let overlaps = other.p_type == PT_LOAD
+                    && ph.vaddr < other.vaddr + other.memsz
+                    && other.vaddr < ph.vaddr + ph.memsz;
+                if overlaps {
+                    return Err(ElfError::OverlappingSegments(j, i));
+                }
+            }
+
+            let entry = self.header.entry;
+            if ph.flags & PF_X != 0 && entry >= ph.vaddr && entry < ph.vaddr + ph.memsz {
+                entry_ok = true;
+            }
+
+            loadable += 1;
+        }
+
+        if loadable == 0 {
+            Err(ElfError::NoLoadableSegments)
+        } else if !entry_ok {
+            Err(ElfError::EntryNotExecutable(self.header.entry))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn entry(&self) -> u64 {
+        self.header.entry
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        let (phoff, phentsize) = (self.header.phoff as usize, usize::from(self.header.phentsize));
+
+        (0..usize::from(self.header.phnum))
+            .map(move |i| read(self.data, phoff + i * phentsize).expect("program headers were checked"))
+    }
+
+    pub fn segments(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        self.program_headers().filter(|ph| ph.p_type == PT_LOAD)
+    }
+
+    // File contents of a segment
+    pub fn segment_data(&self, ph: &ProgramHeader) -> &'a [u8] {
+        &self.data[ph.offset as usize..(ph.offset + ph.filesz) as usize]
+    }
+
+    // Where the program headers end up in memory, if a segment covers them
+    pub fn phdr_addr(&self) -> Option<u64> {
+        let phoff = self.header.phoff;
+
+        self.segments()
+            .find(|ph| phoff >= ph.offset && phoff < ph.offset + ph.filesz)
+            .map(|ph| ph.vaddr + (phoff - ph.offset))
+    }
+}
+
+fn read<T: Copy>(data: &[u8], offset: usize) -> Option<T> {
+    let end = offset.checked_add(mem::size_of::<T>())?;
+    if end > data.len() {
+        return None;
+    }
+
+    Some(unsafe { ptr::read_unaligned(data[offset..].as_ptr() as *const T) })
+}
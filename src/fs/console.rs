@@ -0,0 +1,71 @@
+// The serial port as a file, which processes start with as stdin, stdout and
+// stderr. What's typed on the keyboard is read back as UTF-8, without any
+// line editing or echo. Without a keyboard, reads are always at the end.
+use super::{File, FileType, Metadata};
+use crate::{
+    drivers::{input, keyboard, serial},
+    ds::SpinLock,
+    process::signal,
+    syscall::Errno,
+};
+use alloc::{collections::VecDeque, sync::Arc};
+use core::cmp;
+
+pub struct Console;
+
+lazy_static! {
+    static ref CONSOLE: Arc<Console> = Arc::new(Console);
+    // Typed but not yet read, which is only ever part of a character
+    static ref PENDING: SpinLock<VecDeque<u8>> = SpinLock::new(VecDeque::new());
+}
+
+pub fn console() -> Arc<dyn File> {
+    CONSOLE.clone()
+}
+
+impl File for Console {
+    // Waits for at least one byte
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        if buf.is_empty() || !keyboard::is_present() {
+            return Ok(0);
+        }
+
+        loop {
+            {
+                let mut pending = PENDING.lock();
+                while pending.len() < buf.len() {
+                    match input::try_read() {
+                        Some(event) => {
+                            if let Some(c) = event.c {
+                                pending.extend(c.encode_utf8(&mut [0; 4]).as_bytes());
+                            }
+                        }
+                        None => break,
+                    }
+                }
+
+                if !pending.is_empty() {
+                    let len = cmp::min(pending.len(), buf.len());
+                    for (dest, byte) in buf.iter_mut().zip(pending.drain(..len)) {
+                        *dest = byte;
+                    }
+                    return Ok(len);
+                }
+            }
+
+            if signal::has_pending() {
+                return Err(Errno::EINTR);
+            }
+            input::wait();
+        }
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        serial::write_bytes(buf);
+        Ok(buf.len())
+    }
+
+    fn metadata(&self) -> Result<Metadata, Errno> {
+        Ok(Metadata::new(0, FileType::CharDevice, 0o620))
+    }
+}
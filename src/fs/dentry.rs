@@ -0,0 +1,168 @@
+// The dentry cache. Each directory remembers the inodes it has found for
+// names, and the names it found nothing for, so walking the same path again
+// stays out of the filesystem. A dentry also knows its parent, which is where
+// ".." goes.
+//
+// Every mount has a tree of its own, from the root of its superblock. Changes
+// to a directory go through Location, which has its dentry drop everything it
+// cached, since filesystems may match names loosely (FAT ignores case).
+// Filesystems changed some other way have to be mounted afterwards.
+use super::Inode;
+use crate::{ds::SpinLock, syscall::Errno};
+use alloc::{collections::BTreeMap, string::String, sync::Arc};
+use core::{
+    mem,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+// Directories forget everything once they've cached this many names
+const CACHED_MAX: usize = 256;
+
+pub struct Dentry {
+    inode: Arc<dyn Inode>,
+    // None for the root of a mount
+    parent: Option<Arc<Dentry>>,
+    // None for names that aren't there
+    children: SpinLock<BTreeMap<String, Option<Arc<Dentry>>>>,
+    // Bumped whenever the directory changes, so that a lookup which raced
+    // with the change doesn't cache what it found
+    generation: AtomicU64,
+}
+
+impl Dentry {
+    pub fn root(inode: Arc<dyn Inode>) -> Arc<Dentry> {
+        Dentry::new(inode, None)
+    }
+
+    fn new(inode: Arc<dyn Inode>, parent: Option<Arc<Dentry>>) -> Arc<Dentry> {
+        Arc::new(Dentry {
+            inode,
+            parent,
+            children: SpinLock::new(BTreeMap::new()),
+            generation: AtomicU64::new(0),
+        })
+    }
+
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    pub fn parent(&self) -> Option<&Arc<Dentry>> {
+        self.parent.as_ref()
+    }
+
+    // Finds a child by name, asking the filesystem only if it isn't cached. The
+    // lock isn't held across the filesystem's lookup, which may sleep.
+    pub fn lookup(self: &Arc<Self>, name: &str) -> Result<Arc<Dentry>, Errno> {
+        if let Some(cached) = self.children.lock().get(name) {
+            return cached.clone().ok_or(Errno::ENOENT);
+        }
+
+        let generation = self.generation.load(Ordering::Acquire);
+        let child = match self.inode.lookup(name) {
+            Ok(inode) => Some(Dentry::new(inode, Some(self.clone()))),
+            Err(Errno::ENOENT) => None,
+            Err(errno) => return Err(errno),
+        };
+
+        let mut children = self.children.lock();
+        if self.generation.load(Ordering::Acquire) != generation {
+            return child.ok_or(Errno::ENOENT);
+        }
+        if children.len() >= CACHED_MAX {
+            for old in mem::replace(&mut *children, BTreeMap::new()).values().flatten() {
+                old.prune();
+            }
+        }
+        // Someone else may have looked it up meanwhile
+        let child = children.entry(String::from(name)).or_insert(child).clone();
+        child.ok_or(Errno::ENOENT)
+    }
+
+    // Caches an inode that was just added to the directory as name
+    pub fn add(self: &Arc<Self>, name: &str, inode: Arc<dyn Inode>) -> Arc<Dentry> {
+        self.invalidate();
+        let child = Dentry::new(inode, Some(self.clone()));
+        self.children.lock().insert(String::from(name), Some(child.clone()));
+        child
+    }
+
+    // Drops what the directory has cached, once it changed
+    pub fn invalidate(&self) {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        self.prune();
+    }
+
+    // Empties the cache below this dentry. Children keep their parents alive,
+    // so trees that are dropped have to be taken apart.
+    pub fn prune(&self) {
+        let children = mem::replace(&mut *self.children.lock(), BTreeMap::new());
+        for child in children.values().flatten() {
+            child.prune();
+        }
+    }
+
+    #[cfg(test)]
+    fn cached(&self, name: &str) -> Option<Option<Arc<Dentry>>> {
+        self.children.lock().get(name).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{
+        mkdir,
+        mount,
+        open,
+        path::lookup,
+        rmdir,
+        stat,
+        symlink,
+        tmpfs::TmpFs,
+        unlink,
+        unmount,
+        FileType,
+        O_CREAT,
+        O_WRONLY,
+    };
+    use super::*;
+
+    test_case!(cache_follows_changes, {
+        mount("/", TmpFs::new(None)).unwrap();
+        let root = lookup("/").unwrap().dentry;
+
+        // Misses are cached too, so the filesystem isn't asked again
+        assert_eq!(stat("/hidden").err(), Some(Errno::ENOENT));
+        assert!(root.cached("hidden").unwrap().is_none());
+        root.inode().create("hidden", FileType::Regular, 0o644).unwrap();
+        assert_eq!(stat("/hidden").err(), Some(Errno::ENOENT));
+        root.invalidate();
+        assert!(stat("/hidden").is_ok());
+        root.inode().unlink("hidden").unwrap();
+        root.invalidate();
+
+        // Unless the change goes through the VFS
+        assert_eq!(stat("/file").err(), Some(Errno::ENOENT));
+        open("/file", O_WRONLY | O_CREAT, 0o644).unwrap();
+        let file = lookup("/file").unwrap().dentry;
+        assert!(Arc::ptr_eq(&file, &lookup("/file").unwrap().dentry));
+        assert!(Arc::ptr_eq(file.parent().unwrap(), &root));
+
+        symlink("file", "/sym").unwrap();
+        assert_eq!(stat("/sym").unwrap().ino, file.inode().metadata().ino);
+        unlink("/file").unwrap();
+        assert!(root.cached("file").is_none());
+        assert_eq!(stat("/file").err(), Some(Errno::ENOENT));
+        assert_eq!(stat("/sym").err(), Some(Errno::ENOENT));
+
+        mkdir("/dir", 0o755).unwrap();
+        mkdir("/dir/sub", 0o755).unwrap();
+        let up = lookup("/dir/sub/..").unwrap().dentry;
+        assert!(Arc::ptr_eq(&up, &lookup("/dir").unwrap().dentry));
+        rmdir("/dir/sub").unwrap();
+        assert_eq!(stat("/dir/sub").err(), Some(Errno::ENOENT));
+
+        unmount("/").unwrap();
+        assert!(root.cached("dir").is_none());
+    });
+}
@@ -0,0 +1,179 @@
+// Directory entries. A directory's blocks are each a list of entries: inode
+// number, record length, name length, file type and then the name, padded to
+// four bytes. Record lengths chain the entries together up to the end of the
+// block, so removing one just adds its length to the one before. An inode
+// number of zero marks an unused record.
+use crate::{fs::FileType, syscall::Errno};
+use alloc::vec::Vec;
+
+const HEADER_SIZE: usize = 8;
+
+// File types in entries, when the filesystem has them
+pub const FT_UNKNOWN: u8 = 0;
+pub const FT_REG_FILE: u8 = 1;
+pub const FT_DIR: u8 = 2;
+pub const FT_CHRDEV: u8 = 3;
+pub const FT_BLKDEV: u8 = 4;
+pub const FT_SYMLINK: u8 = 7;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub ino: u32,
+    pub name: Vec<u8>,
+    pub file_type: u8,
+    // Where the record is in its block, and its length
+    pub offset: usize,
+    pub rec_len: usize,
+}
+
+impl Entry {
+    // The space the entry needs, less than rec_len if there's slack after it
+    fn used(&self) -> usize {
+        if self.ino == 0 {
+            0
+        } else {
+            record_size(self.name.len())
+        }
+    }
+}
+
+pub fn record_size(name_len: usize) -> usize {
+    (HEADER_SIZE + name_len + 3) & !3
+}
+
+pub fn file_type_code(file_type: FileType) -> u8 {
+    match file_type {
+        FileType::Regular => FT_REG_FILE,
+        FileType::Directory => FT_DIR,
+        FileType::CharDevice => FT_CHRDEV,
+        FileType::BlockDevice => FT_BLKDEV,
+        FileType::Symlink => FT_SYMLINK,
+    }
+}
+
+// Every record of a block, unused ones too. Records that run off the end of
+// the block or aren't aligned mean the directory is corrupt.
+pub fn parse(block: &[u8]) -> Result<Vec<Entry>, Errno> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset < block.len() {
+        if offset + HEADER_SIZE > block.len() {
+            return Err(Errno::EIO);
+        }
+        let header = &block[offset..offset + HEADER_SIZE];
+        let ino = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let rec_len = usize::from(u16::from_le_bytes([header[4], header[5]]));
+        let name_len = usize::from(header[6]);
+        if rec_len < HEADER_SIZE || rec_len % 4 != 0 || offset + rec_len > block.len() {
+            return Err(Errno::EIO);
+        }
+        if ino != 0 && record_size(name_len) > rec_len {
+            return Err(Errno::EIO);
+        }
+
+        entries.push(Entry {
+            ino,
+            name: block[offset + HEADER_SIZE..offset + HEADER_SIZE + name_len].to_vec(),
+            file_type: header[7],
+            offset,
+            rec_len,
+        });
+        offset += rec_len;
+    }
+    Ok(entries)
+}
+
+// Writes a record into the block. Without the filetype feature the byte
+// holding the type is the top of a 16 bit name length instead, so zero.
+pub fn write(block: &mut [u8], offset: usize, rec_len: usize, ino: u32, name: &[u8], file_type: u8) {
+    let record = &mut block[offset..offset + rec_len];
+    record[..4].copy_from_slice(&ino.to_le_bytes());
+    record[4..6].copy_from_slice(&(rec_len as u16).to_le_bytes());
+    record[6] = name.len() as u8;
+    record[7] = file_type;
+    record[HEADER_SIZE..HEADER_SIZE + name.len()].copy_from_slice(name);
+}
+
+fn set_rec_len(block: &mut [u8], offset: usize, rec_len: usize) {
+    block[offset + 4..offset + 6].copy_from_slice(&(rec_len as u16).to_le_bytes());
+}
+
+// Adds an entry to the block if there's room, in an unused record or the
+// slack after a used one
+pub fn insert(block: &mut [u8], ino: u32, name: &[u8], file_type: u8) -> Result<bool, Errno> {
+    let needed = record_size(name.len());
+    for entry in parse(block)? {
+        let used = entry.used();
+        if entry.rec_len - used < needed {
+            continue;
+        }
+
+        if used != 0 {
+            set_rec_len(block, entry.offset, used);
+        }
+        write(block, entry.offset + used, entry.rec_len - used, ino, name, file_type);
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+// Removes the entry for name from the block, if it's there. The first record
+// of a block can't be merged into anything, so it's marked unused instead.
+pub fn remove(block: &mut [u8], name: &[u8]) -> Result<bool, Errno> {
+    let entries = parse(block)?;
+    let found = entries.iter().position(|entry| entry.ino != 0 && entry.name == name);
+    match found {
+        Some(0) => block[..4].copy_from_slice(&0u32.to_le_bytes()),
+        Some(i) => {
+            let previous = &entries[i - 1];
+            set_rec_len(block, previous.offset, previous.rec_len + entries[i].rec_len);
+        }
+        None => return Ok(false),
+    }
+    Ok(true)
+}
+
+// A block for a new directory, holding "." and ".."
+pub fn new_block(block_size: usize, ino: u32, parent: u32, file_types: bool) -> Vec<u8> {
+    let mut block = alloc::vec![0; block_size];
+    let file_type = if file_types { FT_DIR } else { FT_UNKNOWN };
+    let dot = record_size(1);
+    write(&mut block, 0, dot, ino, b".", file_type);
+    write(&mut block, dot, block_size - dot, parent, b"..", file_type);
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    test_case!(records, {
+        let mut block = new_block(1024, 12, 2, true);
+        let names: Vec<Vec<u8>> = parse(&block).unwrap().into_iter().map(|entry| entry.name).collect();
+        assert_eq!(names, [b".".to_vec(), b"..".to_vec()]);
+
+        // New entries go in the slack after ".."
+        assert_eq!(insert(&mut block, 13, b"first", FT_REG_FILE), Ok(true));
+        assert_eq!(insert(&mut block, 14, b"second", FT_DIR), Ok(true));
+        let entries = parse(&block).unwrap();
+        assert_eq!(entries.len(), 4);
+        assert_eq!((entries[1].rec_len, entries[2].offset, entries[2].rec_len), (12, 24, 16));
+        let last = &entries[3];
+        assert_eq!((last.ino, last.file_type, last.offset + last.rec_len), (14, FT_DIR, 1024));
+
+        // Removing merges into the record before, which can then be reused
+        assert_eq!(remove(&mut block, b"first"), Ok(true));
+        assert_eq!(remove(&mut block, b"first"), Ok(false));
+        assert_eq!(parse(&block).unwrap()[1].rec_len, 28);
+        assert_eq!(insert(&mut block, 15, b"third", FT_REG_FILE), Ok(true));
+        assert_eq!(parse(&block).unwrap()[2].offset, 24);
+
+        // No room for a name this long
+        let long = [b'x'; 255];
+        while insert(&mut block, 16, &long, FT_REG_FILE).unwrap() {}
+        assert_eq!(parse(&block).unwrap().len(), 7);
+
+        block[4] = 3;
+        assert_eq!(parse(&block), Err(Errno::EIO));
+    });
+}
@@ -0,0 +1,829 @@
+// Inodes, which hold everything about a file but its names. The first twelve
+// block pointers map the start of the file directly, the next three go through
+// one, two and three levels of indirect blocks. Pointers of zero are holes.
+use super::{dir, Volume};
+use crate::{
+    ds::SpinLock,
+    fs::{DirEntry, FileType, Inode, InodeNumber, Metadata, NAME_MAX},
+    syscall::Errno,
+};
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+use core::{cmp, ops::Range};
+
+// The part of an on-disk inode that's the same whatever its size
+pub const INODE_SIZE: usize = 128;
+
+const S_IFMT: u16 = 0o170_000;
+const S_IFREG: u16 = 0o100_000;
+const S_IFDIR: u16 = 0o040_000;
+const S_IFCHR: u16 = 0o020_000;
+const S_IFBLK: u16 = 0o060_000;
+const S_IFLNK: u16 = 0o120_000;
+
+const DIRECT_BLOCKS: usize = 12;
+
+// Set when Linux keeps a hashed index of a directory, which has to go once
+// entries are added without updating it
+const INDEX_FL: u32 = 0x1000;
+
+// Symlinks this short are kept in the block pointers instead of a block
+const FAST_SYMLINK_MAX: usize = 59;
+
+// As in Linux
+const LINK_MAX: u16 = 32000;
+
+#[derive(Clone, Copy)]
+pub struct DiskInode([u8; INODE_SIZE]);
+
+impl DiskInode {
+    pub fn new(file_type: FileType, mode: u16, now: u32) -> DiskInode {
+        let mut disk = DiskInode([0; INODE_SIZE]);
+        disk.set16(0, file_type.mode_bits() as u16 | (mode & 0o7777));
+        disk.set_links(1);
+        disk.touch(now);
+        disk.set32(8, now);
+        disk
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> DiskInode {
+        let mut disk = DiskInode([0; INODE_SIZE]);
+        disk.0.copy_from_slice(&bytes[..INODE_SIZE]);
+        disk
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    fn get16(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self.0[offset], self.0[offset + 1]])
+    }
+
+    fn get32(&self, offset: usize) -> u32 {
+        u32::from_le_bytes([
+            self.0[offset],
+            self.0[offset + 1],
+            self.0[offset + 2],
+            self.0[offset + 3],
+        ])
+    }
+
+    fn set16(&mut self, offset: usize, value: u16) {
+        self.0[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn set32(&mut self, offset: usize, value: u32) {
+        self.0[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn mode(&self) -> u16 {
+        self.get16(0)
+    }
+
+    // Sockets and FIFOs pass for regular files, there being nothing else to
+    // show them as
+    pub fn file_type(&self) -> FileType {
+        match self.mode() & S_IFMT {
+            S_IFDIR => FileType::Directory,
+            S_IFLNK => FileType::Symlink,
+            S_IFCHR => FileType::CharDevice,
+            S_IFBLK => FileType::BlockDevice,
+            _ => FileType::Regular,
+        }
+    }
+
+    // The top half of the size of regular files is where directories keep
+    // their ACL
+    pub fn size(&self) -> u64 {
+        let low = u64::from(self.get32(4));
+        match self.mode() & S_IFMT {
+            S_IFREG => low | u64::from(self.get32(108)) << 32,
+            _ => low,
+        }
+    }
+
+    fn set_size(&mut self, size: u64) {
+        self.set32(4, size as u32);
+        if self.mode() & S_IFMT == S_IFREG {
+            self.set32(108, (size >> 32) as u32);
+        }
+    }
+
+    pub fn links(&self) -> u16 {
+        self.get16(26)
+    }
+
+    fn set_links(&mut self, links: u16) {
+        self.set16(26, links);
+    }
+
+    // In 512 byte units, counting indirect blocks
+    fn sectors(&self) -> u32 {
+        self.get32(28)
+    }
+
+    fn set_sectors(&mut self, sectors: u32) {
+        self.set32(28, sectors);
+    }
+
+    fn block(&self, slot: usize) -> u32 {
+        self.get32(40 + slot * 4)
+    }
+
+    fn set_block(&mut self, slot: usize, block: u32) {
+        self.set32(40 + slot * 4, block);
+    }
+
+    // The block pointers, where fast symlinks keep their target
+    fn inline_data(&mut self) -> &mut [u8] {
+        &mut self.0[40..100]
+    }
+
+    fn file_acl(&self) -> u32 {
+        self.get32(104)
+    }
+
+    // Sets the modification and change times
+    fn touch(&mut self, now: u32) {
+        self.set32(12, now);
+        self.set32(16, now);
+    }
+
+    // A symlink with no blocks but an extended attribute one keeps its target
+    // inline
+    fn is_fast_symlink(&self, block_size: u32) -> bool {
+        let acl_sectors = if self.file_acl() != 0 { block_size / 512 } else { 0 };
+        self.file_type() == FileType::Symlink && self.sectors() == acl_sectors
+    }
+}
+
+struct InodeInner {
+    disk: DiskInode,
+    // No longer linked anywhere but still in use. Freed once it's not.
+    removed: bool,
+}
+
+pub struct Ext2Inode {
+    volume: Arc<Volume>,
+    ino: u32,
+    inner: SpinLock<InodeInner>,
+}
+
+impl Ext2Inode {
+    pub fn new(volume: Arc<Volume>, ino: u32, disk: DiskInode) -> Arc<Ext2Inode> {
+        Arc::new(Ext2Inode {
+            volume,
+            ino,
+            inner: SpinLock::new(InodeInner { disk, removed: false }),
+        })
+    }
+
+    fn disk(&self) -> DiskInode {
+        self.inner.lock().disk
+    }
+
+    pub fn file_type(&self) -> FileType {
+        self.disk().file_type()
+    }
+
+    // Keeps the changes and writes them to the inode table
+    fn store(&self, disk: DiskInode) -> Result<(), Errno> {
+        self.inner.lock().disk = disk;
+        self.volume.write_inode(self.ino, &disk)
+    }
+
+    fn check_dir(&self) -> Result<(), Errno> {
+        match self.file_type() {
+            FileType::Directory => Ok(()),
+            _ => Err(Errno::ENOTDIR),
+        }
+    }
+
+    // For reads and writes, which only go to regular files
+    fn check_file(&self) -> Result<(), Errno> {
+        match self.file_type() {
+            FileType::Regular => Ok(()),
+            FileType::Directory => Err(Errno::EISDIR),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+
+    fn sectors_per_block(&self) -> u32 {
+        self.volume.block_size / 512
+    }
+
+    // The i_block slot under which index is, and the entries to follow in
+    // each level of indirect block from there
+    fn path(&self, index: u64) -> Result<(usize, Vec<u64>), Errno> {
+        if index < DIRECT_BLOCKS as u64 {
+            return Ok((index as usize, Vec::new()));
+        }
+
+        let per_block = u64::from(self.volume.block_size / 4);
+        let (mut index, mut span) = (index - DIRECT_BLOCKS as u64, per_block);
+        for depth in 1..=3 {
+            if index < span {
+                let path = (0..depth)
+                    .rev()
+                    .map(|level| index / per_block.pow(level) % per_block)
+                    .collect();
+                return Ok((DIRECT_BLOCKS + depth as usize - 1, path));
+            }
+            index -= span;
+            span *= per_block;
+        }
+        Err(Errno::EFBIG)
+    }
+
+    // The block holding the index'th block of the file, zero for a hole.
+    // With create, holes get a block, and so do indirect blocks on the way.
+    fn bmap(&self, disk: &mut DiskInode, index: u64, create: bool) -> Result<u32, Errno> {
+        let (slot, path) = self.path(index)?;
+        let goal = self.volume.inode_group(self.ino);
+        let mut block = disk.block(slot);
+        if block == 0 {
+            if !create {
+                return Ok(0);
+            }
+            block = self.volume.alloc_block(goal)?;
+            disk.set_block(slot, block);
+            disk.set_sectors(disk.sectors() + self.sectors_per_block());
+        }
+
+        for &entry in &path {
+            let offset = self.volume.block_offset(block)? + entry * 4;
+            let mut next = self.volume.read_u32(offset)?;
+            if next == 0 {
+                if !create {
+                    return Ok(0);
+                }
+                next = self.volume.alloc_block(goal)?;
+                self.volume.write(offset, &next.to_le_bytes())?;
+                disk.set_sectors(disk.sectors() + self.sectors_per_block());
+            }
+            block = next;
+        }
+        Ok(block)
+    }
+
+    fn release_block(&self, disk: &mut DiskInode, block: u32) -> Result<(), Errno> {
+        self.volume.free_block(block)?;
+        disk.set_sectors(disk.sectors().saturating_sub(self.sectors_per_block()));
+        Ok(())
+    }
+
+    // Frees every block of the file from index keep on, and indirect blocks
+    // with nothing left under them
+    fn trim(&self, disk: &mut DiskInode, keep: u64) -> Result<(), Errno> {
+        for slot in cmp::min(keep, DIRECT_BLOCKS as u64) as usize..DIRECT_BLOCKS {
+            let block = disk.block(slot);
+            if block != 0 {
+                self.release_block(disk, block)?;
+                disk.set_block(slot, 0);
+            }
+        }
+
+        let per_block = u64::from(self.volume.block_size / 4);
+        let (mut start, mut span) = (DIRECT_BLOCKS as u64, per_block);
+        for depth in 1..=3 {
+            let slot = DIRECT_BLOCKS + depth as usize - 1;
+            let block = disk.block(slot);
+            if block != 0 && self.trim_tree(disk, block, depth, keep.saturating_sub(start))? {
+                disk.set_block(slot, 0);
+            }
+            start += span;
+            span *= per_block;
+        }
+        Ok(())
+    }
+
+    // Frees what's under an indirect block from keep on, counting from the
+    // first block it maps. Returns whether the indirect block went too.
+    fn trim_tree(&self, disk: &mut DiskInode, block: u32, depth: u32, keep: u64) -> Result<bool, Errno> {
+        let per_block = u64::from(self.volume.block_size / 4);
+        if keep >= per_block.pow(depth) {
+            return Ok(false);
+        }
+
+        let span = per_block.pow(depth - 1);
+        let mut entries = self.volume.read_block(block)?;
+        let mut changed = false;
+        for (i, raw) in entries.chunks_exact_mut(4).enumerate() {
+            let entry = u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]);
+            let start = i as u64 * span;
+            if entry == 0 || start + span <= keep {
+                continue;
+            }
+
+            let gone = match depth {
+                1 => self.release_block(disk, entry).map(|_| true)?,
+                _ => self.trim_tree(disk, entry, depth - 1, keep.saturating_sub(start))?,
+            };
+            if gone {
+                raw.copy_from_slice(&[0; 4]);
+                changed = true;
+            }
+        }
+
+        if keep == 0 {
+            self.release_block(disk, block)?;
+            return Ok(true);
+        }
+        if changed {
+            self.volume.write_block(block, &entries)?;
+        }
+        Ok(false)
+    }
+
+    fn blocks_for(&self, size: u64) -> u64 {
+        let block_size = u64::from(self.volume.block_size);
+        (size + block_size - 1) / block_size
+    }
+
+    // Calls f with the device offset of each piece of offset..offset + len,
+    // None for holes, and where that piece is in the range. With create,
+    // holes are filled, stopping short if the disk fills up after the first
+    // piece. Returns how much was done.
+    fn map<F>(&self, disk: &mut DiskInode, offset: u64, len: usize, create: bool, mut f: F) -> Result<usize, Errno>
+    where
+        F: FnMut(Option<u64>, Range<usize>) -> Result<(), Errno>,
+    {
+        let block_size = u64::from(self.volume.block_size);
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let within = position % block_size;
+            let chunk = cmp::min(len - done, (block_size - within) as usize);
+            let block = match self.bmap(disk, position / block_size, create) {
+                Ok(block) => block,
+                Err(Errno::ENOSPC) if done > 0 => break,
+                Err(errno) => return Err(errno),
+            };
+
+            let device = match block {
+                0 => None,
+                block => Some(self.volume.block_offset(block)? + within),
+            };
+            f(device, done..done + chunk)?;
+            done += chunk;
+        }
+        Ok(done)
+    }
+
+    // Every block of a directory and what's in it. Directories have no holes.
+    fn dir_blocks(&self, disk: &DiskInode) -> Result<Vec<(u32, Vec<u8>)>, Errno> {
+        let mut disk = *disk;
+        let count = disk.size() / u64::from(self.volume.block_size);
+        let mut blocks = Vec::new();
+        for index in 0..count {
+            match self.bmap(&mut disk, index, false)? {
+                0 => return Err(Errno::EIO),
+                block => blocks.push((block, self.volume.read_block(block)?)),
+            }
+        }
+        Ok(blocks)
+    }
+
+    // The entries in use, "." and ".." included
+    fn entries(&self, disk: &DiskInode) -> Result<Vec<dir::Entry>, Errno> {
+        let mut entries = Vec::new();
+        for (_, data) in self.dir_blocks(disk)? {
+            entries.extend(dir::parse(&data)?.into_iter().filter(|entry| entry.ino != 0));
+        }
+        Ok(entries)
+    }
+
+    fn find(&self, disk: &DiskInode, name: &str) -> Result<Option<dir::Entry>, Errno> {
+        Ok(self
+            .entries(disk)?
+            .into_iter()
+            .find(|entry| entry.name == name.as_bytes()))
+    }
+
+    // Puts the entry in the first block with room for it, or a new one at the
+    // end
+    fn add_entry(&self, disk: &mut DiskInode, name: &str, ino: u32, file_type: FileType) -> Result<(), Errno> {
+        let code = if self.volume.file_types {
+            dir::file_type_code(file_type)
+        } else {
+            dir::FT_UNKNOWN
+        };
+        disk.set32(32, disk.get32(32) & !INDEX_FL);
+
+        for (block, mut data) in self.dir_blocks(disk)? {
+            if dir::insert(&mut data, ino, name.as_bytes(), code)? {
+                return self.volume.write_block(block, &data);
+            }
+        }
+
+        let block_size = u64::from(self.volume.block_size);
+        let size = disk.size();
+        let block = self.bmap(disk, size / block_size, true)?;
+        let mut data = vec![0; block_size as usize];
+        dir::write(&mut data, 0, data.len(), ino, name.as_bytes(), code);
+        self.volume.write_block(block, &data)?;
+        disk.set_size(size + block_size);
+        Ok(())
+    }
+
+    fn remove_entry(&self, disk: &DiskInode, name: &str) -> Result<(), Errno> {
+        for (block, mut data) in self.dir_blocks(disk)? {
+            if dir::remove(&mut data, name.as_bytes())? {
+                return self.volume.write_block(block, &data);
+            }
+        }
+        Err(Errno::ENOENT)
+    }
+
+    // Frees the blocks and then the inode itself, once there are no links to
+    // it and nobody's using it
+    fn release(&self, disk: &mut DiskInode) -> Result<(), Errno> {
+        if !disk.is_fast_symlink(self.volume.block_size) {
+            self.trim(disk, 0)?;
+        }
+        if disk.file_acl() != 0 {
+            self.volume.release_xattr(disk.file_acl())?;
+            disk.set32(104, 0);
+            disk.set_sectors(0);
+        }
+
+        // e2fsck wants a deletion time on every free inode
+        disk.set_links(0);
+        disk.set32(20, cmp::max(self.volume.now(), 1));
+        self.store(*disk)?;
+        self.volume
+            .free_inode(self.ino, disk.file_type() == FileType::Directory)
+    }
+
+    // Makes a new inode and links it in as name, after init has filled it in
+    fn add_child<F>(&self, name: &str, file_type: FileType, mode: u16, init: F) -> Result<Arc<dyn Inode>, Errno>
+    where
+        F: FnOnce(&Ext2Inode, &mut DiskInode) -> Result<(), Errno>,
+    {
+        self.check_dir()?;
+        check_name(name)?;
+        self.volume.check_writable()?;
+
+        let _guard = self.volume.lock.lock();
+        let mut disk = self.disk();
+        if disk.links() == 0 {
+            return Err(Errno::ENOENT);
+        }
+        if self.find(&disk, name)?.is_some() {
+            return Err(Errno::EEXIST);
+        }
+        let is_dir = file_type == FileType::Directory;
+        if is_dir && disk.links() >= LINK_MAX {
+            return Err(Errno::EMLINK);
+        }
+
+        let ino = self.volume.alloc_inode(self.volume.inode_group(self.ino), is_dir)?;
+        let child = self.volume.inode(ino)?;
+        let mut child_disk = DiskInode::new(file_type, mode, self.volume.now());
+        let added = init(&child, &mut child_disk)
+            .and_then(|_| child.store(child_disk))
+            .and_then(|_| self.add_entry(&mut disk, name, ino, file_type));
+        if let Err(errno) = added {
+            child.release(&mut child_disk)?;
+            self.store(disk)?;
+            return Err(errno);
+        }
+
+        // The new directory's ".." links back
+        if is_dir {
+            disk.set_links(disk.links() + 1);
+        }
+        disk.touch(self.volume.now());
+        self.store(disk)?;
+        Ok(child)
+    }
+}
+
+impl Drop for Ext2Inode {
+    fn drop(&mut self) {
+        let (removed, mut disk) = {
+            let inner = self.inner.lock();
+            (inner.removed, inner.disk)
+        };
+        if removed {
+            let _guard = self.volume.lock.lock();
+            if let Err(errno) = self.release(&mut disk) {
+                error!("ext2: couldn't free removed inode {}: {:?}", self.ino, errno);
+            }
+        }
+
+        // Unless the number went to another inode since
+        let mut inodes = self.volume.inodes.lock();
+        if inodes.get(&self.ino).map_or(false, |inode| inode.strong_count() == 0) {
+            inodes.remove(&self.ino);
+        }
+    }
+}
+
+fn check_name(name: &str) -> Result<(), Errno> {
+    match name {
+        "" | "." | ".." => Err(Errno::EINVAL),
+        _ if name.len() > NAME_MAX => Err(Errno::ENAMETOOLONG),
+        _ => Ok(()),
+    }
+}
+
+fn entry_file_type(code: u8) -> FileType {
+    match code {
+        dir::FT_DIR => FileType::Directory,
+        dir::FT_CHRDEV => FileType::CharDevice,
+        dir::FT_BLKDEV => FileType::BlockDevice,
+        dir::FT_SYMLINK => FileType::Symlink,
+        _ => FileType::Regular,
+    }
+}
+
+impl Inode for Ext2Inode {
+    fn metadata(&self) -> Metadata {
+        let disk = self.disk();
+        let file_type = disk.file_type();
+        let mut metadata = Metadata::new(InodeNumber::from(self.ino), file_type, disk.mode() & 0o7777);
+        metadata.nlink = u32::from(disk.links());
+        metadata.size = disk.size();
+        metadata.blksize = self.volume.block_size;
+        metadata.blocks = u64::from(disk.sectors());
+        metadata.atime = u64::from(disk.get32(8));
+        metadata.ctime = u64::from(disk.get32(12));
+        metadata.mtime = u64::from(disk.get32(16));
+        // Old style numbers are in the first block pointer, new ones in the
+        // second, already laid out like Linux's dev_t
+        if let FileType::CharDevice | FileType::BlockDevice = file_type {
+            metadata.rdev = u64::from(match disk.block(0) {
+                0 => disk.block(1),
+                old => old & 0xFFFF,
+            });
+        }
+        metadata
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Errno> {
+        self.check_dir()?;
+        let _guard = self.volume.lock.lock();
+        let entry = self.find(&self.disk(), name)?.ok_or(Errno::ENOENT)?;
+        Ok(self.volume.inode(entry.ino)?)
+    }
+
+    fn create(&self, name: &str, file_type: FileType, mode: u16) -> Result<Arc<dyn Inode>, Errno> {
+        match file_type {
+            FileType::Regular => self.add_child(name, file_type, mode, |_, _| Ok(())),
+            FileType::Directory => self.add_child(name, file_type, mode, |child, disk| {
+                let block = child.bmap(disk, 0, true)?;
+                let block_size = self.volume.block_size as usize;
+                let data = dir::new_block(block_size, child.ino, self.ino, self.volume.file_types);
+                self.volume.write_block(block, &data)?;
+                disk.set_size(block_size as u64);
+                disk.set_links(2);
+                Ok(())
+            }),
+            _ => Err(Errno::EPERM),
+        }
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, Errno> {
+        if target.is_empty() {
+            return Err(Errno::ENOENT);
+        }
+        if target.len() >= self.volume.block_size as usize {
+            return Err(Errno::ENAMETOOLONG);
+        }
+
+        self.add_child(name, FileType::Symlink, 0o777, |child, disk| {
+            disk.set_size(target.len() as u64);
+            if target.len() <= FAST_SYMLINK_MAX {
+                disk.inline_data()[..target.len()].copy_from_slice(target.as_bytes());
+                return Ok(());
+            }
+            let block = child.bmap(disk, 0, true)?;
+            self.volume.write(self.volume.block_offset(block)?, target.as_bytes())
+        })
+    }
+
+    fn link(&self, name: &str, target: &Arc<dyn Inode>) -> Result<(), Errno> {
+        self.check_dir()?;
+        check_name(name)?;
+        self.volume.check_writable()?;
+        let metadata = target.metadata();
+        if metadata.is_dir() {
+            return Err(Errno::EPERM);
+        }
+
+        let _guard = self.volume.lock.lock();
+        let mut disk = self.disk();
+        if disk.links() == 0 {
+            return Err(Errno::ENOENT);
+        }
+        if self.find(&disk, name)?.is_some() {
+            return Err(Errno::EEXIST);
+        }
+        let child = self.volume.inode(metadata.ino as u32)?;
+        let mut child_disk = child.disk();
+        match child_disk.links() {
+            0 => return Err(Errno::ENOENT),
+            LINK_MAX..=u16::MAX => return Err(Errno::EMLINK),
+            _ => (),
+        }
+
+        self.add_entry(&mut disk, name, child.ino, child_disk.file_type())?;
+        disk.touch(self.volume.now());
+        self.store(disk)?;
+        child_disk.set_links(child_disk.links() + 1);
+        child_disk.set32(12, self.volume.now());
+        child.store(child_disk)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), Errno> {
+        self.check_dir()?;
+        if name == "." || name == ".." {
+            return Err(Errno::EINVAL);
+        }
+        self.volume.check_writable()?;
+
+        let _guard = self.volume.lock.lock();
+        let mut disk = self.disk();
+        let entry = self.find(&disk, name)?.ok_or(Errno::ENOENT)?;
+        let child = self.volume.inode(entry.ino)?;
+        let mut child_disk = child.disk();
+        let is_dir = child_disk.file_type() == FileType::Directory;
+        if is_dir
+            && child
+                .entries(&child_disk)?
+                .iter()
+                .any(|entry| entry.name != b"." && entry.name != b"..")
+        {
+            return Err(Errno::ENOTEMPTY);
+        }
+
+        self.remove_entry(&disk, name)?;
+        let now = self.volume.now();
+        if is_dir {
+            disk.set_links(disk.links().saturating_sub(1));
+            child_disk.set_links(0);
+        } else {
+            child_disk.set_links(child_disk.links().saturating_sub(1));
+        }
+        disk.touch(now);
+        self.store(disk)?;
+        child_disk.set32(12, now);
+
+        if child_disk.links() != 0 {
+            child.store(child_disk)
+        } else if Arc::strong_count(&child) > 1 {
+            // Whoever has it open can keep using its blocks meanwhile
+            child.inner.lock().removed = true;
+            child.store(child_disk)
+        } else {
+            child.release(&mut child_disk)
+        }
+    }
+
+    fn read_dir(&self, index: usize) -> Result<Option<DirEntry>, Errno> {
+        self.check_dir()?;
+        let _guard = self.volume.lock.lock();
+        let entries = self.entries(&self.disk())?;
+        let entry = |name: &str, ino: u32, file_type| DirEntry {
+            ino: InodeNumber::from(ino),
+            name: String::from(name),
+            file_type,
+        };
+
+        Ok(match index {
+            0 => Some(entry(".", self.ino, FileType::Directory)),
+            1 => {
+                let parent = entries
+                    .iter()
+                    .find(|entry| entry.name == b"..")
+                    .map_or(self.ino, |entry| entry.ino);
+                Some(entry("..", parent, FileType::Directory))
+            }
+            _ => {
+                let child = entries
+                    .iter()
+                    .filter(|entry| entry.name != b"." && entry.name != b"..")
+                    .nth(index - 2);
+                match child {
+                    Some(child) => {
+                        // Without types in the entries it takes a look at the inode
+                        let file_type = if self.volume.file_types {
+                            entry_file_type(child.file_type)
+                        } else {
+                            self.volume.inode(child.ino)?.file_type()
+                        };
+                        Some(entry(&String::from_utf8_lossy(&child.name), child.ino, file_type))
+                    }
+                    None => None,
+                }
+            }
+        })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        self.check_file()?;
+        let _guard = self.volume.lock.lock();
+        let mut disk = self.disk();
+        let size = disk.size();
+        if offset >= size {
+            return Ok(0);
+        }
+
+        let len = cmp::min(buf.len() as u64, size - offset) as usize;
+        self.map(&mut disk, offset, len, false, |device, range| match device {
+            Some(device) => self.volume.read(device, &mut buf[range]),
+            None => {
+                buf[range].iter_mut().for_each(|byte| *byte = 0);
+                Ok(())
+            }
+        })
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, Errno> {
+        self.check_file()?;
+        self.volume.check_writable()?;
+        match offset.checked_add(buf.len() as u64) {
+            Some(end) if end <= self.volume.max_file_size() => (),
+            _ => return Err(Errno::EFBIG),
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let _guard = self.volume.lock.lock();
+        let mut disk = self.disk();
+        let size = disk.size();
+        let written = self.map(&mut disk, offset, buf.len(), true, |device, range| {
+            self.volume.write(device.ok_or(Errno::EIO)?, &buf[range])
+        });
+        // Indirect blocks may have been taken on the way to a block that
+        // didn't fit
+        match written {
+            Ok(len) => {
+                disk.set_size(cmp::max(size, offset + len as u64));
+                disk.touch(self.volume.now());
+                if len < buf.len() {
+                    self.trim(&mut disk, self.blocks_for(disk.size()))?;
+                }
+            }
+            Err(_) => self.trim(&mut disk, self.blocks_for(size))?,
+        }
+        self.store(disk)?;
+        written
+    }
+
+    // Growing leaves a hole
+    fn truncate(&self, size: u64) -> Result<(), Errno> {
+        self.check_file()?;
+        self.volume.check_writable()?;
+        if size > self.volume.max_file_size() {
+            return Err(Errno::EFBIG);
+        }
+
+        let _guard = self.volume.lock.lock();
+        let mut disk = self.disk();
+        if size < disk.size() {
+            self.trim(&mut disk, self.blocks_for(size))?;
+            // What's left of the last block past the end is zeroed, so that
+            // growing the file again reads zeros there
+            let block_size = u64::from(self.volume.block_size);
+            let tail = size % block_size;
+            let block = self.bmap(&mut disk, size / block_size, false)?;
+            if tail != 0 && block != 0 {
+                let zeros = vec![0; (block_size - tail) as usize];
+                self.volume.write(self.volume.block_offset(block)? + tail, &zeros)?;
+            }
+        }
+
+        disk.set_size(size);
+        disk.touch(self.volume.now());
+        self.store(disk)
+    }
+
+    fn readlink(&self) -> Result<String, Errno> {
+        let _guard = self.volume.lock.lock();
+        let mut disk = self.disk();
+        if disk.file_type() != FileType::Symlink {
+            return Err(Errno::EINVAL);
+        }
+
+        let size = disk.size() as usize;
+        let target = if disk.is_fast_symlink(self.volume.block_size) {
+            disk.inline_data().get(..size).ok_or(Errno::EIO)?.to_vec()
+        } else {
+            if size >= self.volume.block_size as usize {
+                return Err(Errno::EIO);
+            }
+            let mut target = vec![0; size];
+            let block = self.bmap(&mut disk, 0, false)?;
+            self.volume.read(self.volume.block_offset(block)?, &mut target)?;
+            target
+        };
+        String::from_utf8(target).map_err(|_| Errno::EIO)
+    }
+
+    fn sync(&self) -> Result<(), Errno> {
+        self.volume.sync()
+    }
+}
@@ -0,0 +1,948 @@
+// ext2, as made by mke2fs. After the superblock come the group descriptors,
+// one for each block group the volume is split into. Each group has a bitmap
+// of its blocks in use, one of its inodes in use and its part of the inode
+// table. Everything goes through the buffer cache, so changes reach the disk
+// once it's synced.
+use super::{Inode, Superblock};
+use crate::{
+    drivers::block::{cache, BlockDevice},
+    ds::{Mutex, SpinLock},
+    syscall::Errno,
+};
+use alloc::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use core::{
+    cmp,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+mod dir;
+mod inode;
+
+use self::inode::{DiskInode, Ext2Inode};
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xEF53;
+const GROUP_DESC_SIZE: u64 = 32;
+
+pub const ROOT_INO: u32 = 2;
+
+// Revision 0 has fixed inode sizes and reserves the first ten inodes
+const GOOD_OLD_REV: u32 = 0;
+const GOOD_OLD_FIRST_INO: u32 = 11;
+
+// Features that change the layout are refused, ones that only matter to
+// writers make the volume read-only
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+const RO_COMPAT_LARGE_FILE: u32 = 0x2;
+const RO_COMPAT_BTREE_DIR: u32 = 0x4;
+
+const XATTR_MAGIC: u32 = 0xEA02_0000;
+
+fn field<T: Copy>(data: &[u8], offset: usize) -> T {
+    assert!(offset + core::mem::size_of::<T>() <= data.len());
+    unsafe { core::ptr::read_unaligned(data.as_ptr().add(offset) as *const T) }
+}
+
+struct Group {
+    block_bitmap: u32,
+    inode_bitmap: u32,
+    inode_table: u32,
+    free_blocks: u16,
+    free_inodes: u16,
+    used_dirs: u16,
+}
+
+// What inodes share: the layout and the allocators
+pub struct Volume {
+    device: Arc<dyn BlockDevice>,
+    block_size: u32,
+    blocks: u32,
+    first_data_block: u32,
+    blocks_per_group: u32,
+    inode_count: u32,
+    inodes_per_group: u32,
+    inode_size: u32,
+    first_ino: u32,
+    // Whether directory entries say what type the inode is
+    file_types: bool,
+    large_files: bool,
+    read_only: bool,
+    // There's no clock, so this stands in for the time
+    time: u32,
+    // Held across anything touching the bitmaps, inodes or directories,
+    // which may sleep on I/O, so a SpinLock won't do
+    lock: Mutex<()>,
+    groups: SpinLock<Vec<Group>>,
+    // The superblock's counts of free blocks and inodes, written back on sync
+    free: SpinLock<(u32, u32)>,
+    dirty: AtomicBool,
+    // Inodes in use by number, so that everyone sees the same one
+    inodes: SpinLock<BTreeMap<u32, Weak<Ext2Inode>>>,
+    this: Weak<Volume>,
+}
+
+impl Volume {
+    fn new(device: Arc<dyn BlockDevice>) -> Result<Arc<Volume>, Errno> {
+        let mut sb = [0; SUPERBLOCK_SIZE];
+        if device.size() < SUPERBLOCK_OFFSET + SUPERBLOCK_SIZE as u64 {
+            return Err(Errno::EINVAL);
+        }
+        cache::read(&device, SUPERBLOCK_OFFSET, &mut sb)?;
+        if field::<u16>(&sb, 56) != MAGIC {
+            return Err(Errno::EINVAL);
+        }
+
+        let log_block_size = field::<u32>(&sb, 24);
+        if log_block_size > 2 {
+            return Err(Errno::EINVAL);
+        }
+        let block_size = 1024 << log_block_size;
+        let (first_ino, inode_size, incompat, ro_compat) = match field::<u32>(&sb, 76) {
+            GOOD_OLD_REV => (GOOD_OLD_FIRST_INO, inode::INODE_SIZE as u32, 0, 0),
+            _ => (
+                field::<u32>(&sb, 84),
+                u32::from(field::<u16>(&sb, 88)),
+                field::<u32>(&sb, 96),
+                field::<u32>(&sb, 100),
+            ),
+        };
+        if incompat & !INCOMPAT_FILETYPE != 0 {
+            return Err(Errno::EINVAL);
+        }
+
+        let inode_count = field::<u32>(&sb, 0);
+        let blocks = field::<u32>(&sb, 4);
+        let first_data_block = field::<u32>(&sb, 20);
+        let blocks_per_group = field::<u32>(&sb, 32);
+        let inodes_per_group = field::<u32>(&sb, 40);
+        if blocks_per_group == 0
+            || blocks_per_group > block_size * 8
+            || inodes_per_group == 0
+            || inodes_per_group > block_size * 8
+            || inode_size < inode::INODE_SIZE as u32
+            || inode_size > block_size
+            || !inode_size.is_power_of_two()
+            || first_ino <= ROOT_INO
+            || first_data_block >= blocks
+            || u64::from(blocks) * u64::from(block_size) > device.size()
+        {
+            return Err(Errno::EINVAL);
+        }
+        let group_count = (blocks - first_data_block - 1) / blocks_per_group + 1;
+        if u64::from(inode_count) > u64::from(group_count) * u64::from(inodes_per_group) {
+            return Err(Errno::EINVAL);
+        }
+
+        let mut descriptors = vec![0; (u64::from(group_count) * GROUP_DESC_SIZE) as usize];
+        let gdt_offset = u64::from(first_data_block + 1) * u64::from(block_size);
+        cache::read(&device, gdt_offset, &mut descriptors)?;
+        let groups: Vec<Group> = descriptors
+            .chunks_exact(GROUP_DESC_SIZE as usize)
+            .map(|raw| Group {
+                block_bitmap: field(raw, 0),
+                inode_bitmap: field(raw, 4),
+                inode_table: field(raw, 8),
+                free_blocks: field(raw, 12),
+                free_inodes: field(raw, 14),
+                used_dirs: field(raw, 16),
+            })
+            .collect();
+        let table_blocks = (inodes_per_group * inode_size + block_size - 1) / block_size;
+        let inside =
+            |block: u32, len: u32| block > first_data_block && u64::from(block) + u64::from(len) <= u64::from(blocks);
+        if !groups.iter().all(|group| {
+            inside(group.block_bitmap, 1) && inside(group.inode_bitmap, 1) && inside(group.inode_table, table_blocks)
+        }) {
+            return Err(Errno::EINVAL);
+        }
+
+        let mut volume = Arc::new(Volume {
+            device,
+            block_size,
+            blocks,
+            first_data_block,
+            blocks_per_group,
+            inode_count,
+            inodes_per_group,
+            inode_size,
+            first_ino,
+            file_types: incompat & INCOMPAT_FILETYPE != 0,
+            large_files: ro_compat & RO_COMPAT_LARGE_FILE != 0,
+            read_only: ro_compat & !(RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE | RO_COMPAT_BTREE_DIR) != 0,
+            // Whenever it was last mounted or written to elsewhere
+            time: cmp::max(field::<u32>(&sb, 44), field::<u32>(&sb, 48)),
+            lock: Mutex::new(()),
+            groups: SpinLock::new(groups),
+            free: SpinLock::new((field(&sb, 12), field(&sb, 16))),
+            dirty: AtomicBool::new(false),
+            inodes: SpinLock::new(BTreeMap::new()),
+            this: Weak::new(),
+        });
+        let this = Arc::downgrade(&volume);
+        Arc::get_mut(&mut volume).unwrap().this = this;
+        Ok(volume)
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        cache::read(&self.device, offset, buf)
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) -> Result<(), Errno> {
+        cache::write(&self.device, offset, buf)
+    }
+
+    fn read_u32(&self, offset: u64) -> Result<u32, Errno> {
+        let mut raw = [0; 4];
+        self.read(offset, &mut raw)?;
+        Ok(u32::from_le_bytes(raw))
+    }
+
+    // Fails with EIO for blocks outside the volume, which come from a corrupt
+    // pointer
+    fn block_offset(&self, block: u32) -> Result<u64, Errno> {
+        if block <= self.first_data_block || block >= self.blocks {
+            return Err(Errno::EIO);
+        }
+        Ok(u64::from(block) * u64::from(self.block_size))
+    }
+
+    fn read_block(&self, block: u32) -> Result<Vec<u8>, Errno> {
+        let mut data = vec![0; self.block_size as usize];
+        self.read(self.block_offset(block)?, &mut data)?;
+        Ok(data)
+    }
+
+    fn write_block(&self, block: u32, data: &[u8]) -> Result<(), Errno> {
+        self.write(self.block_offset(block)?, data)
+    }
+
+    fn now(&self) -> u32 {
+        self.time
+    }
+
+    fn check_writable(&self) -> Result<(), Errno> {
+        if self.read_only {
+            return Err(Errno::EROFS);
+        }
+        Ok(())
+    }
+
+    // Without the large file feature sizes have to fit in 31 bits
+    fn max_file_size(&self) -> u64 {
+        let per_block = u64::from(self.block_size / 4);
+        let blocks = 12 + per_block + per_block.pow(2) + per_block.pow(3);
+        let limit = if self.large_files {
+            i64::max_value() as u64
+        } else {
+            i32::max_value() as u64
+        };
+        cmp::min(blocks * u64::from(self.block_size), limit)
+    }
+
+    fn blocks_in_group(&self, group: usize) -> u32 {
+        let start = group as u32 * self.blocks_per_group;
+        cmp::min(self.blocks_per_group, self.blocks - self.first_data_block - start)
+    }
+
+    fn inode_group(&self, ino: u32) -> usize {
+        ((ino - 1) / self.inodes_per_group) as usize
+    }
+
+    fn inode_offset(&self, ino: u32) -> Result<u64, Errno> {
+        if ino == 0 || ino > self.inode_count {
+            return Err(Errno::EIO);
+        }
+        let table = self.groups.lock()[self.inode_group(ino)].inode_table;
+        let index = u64::from((ino - 1) % self.inodes_per_group);
+        Ok(self.block_offset(table)? + index * u64::from(self.inode_size))
+    }
+
+    fn read_inode(&self, ino: u32) -> Result<DiskInode, Errno> {
+        let mut raw = [0; inode::INODE_SIZE];
+        self.read(self.inode_offset(ino)?, &mut raw)?;
+        Ok(DiskInode::from_bytes(&raw))
+    }
+
+    // Only the common part, anything after it is left alone
+    fn write_inode(&self, ino: u32, disk: &DiskInode) -> Result<(), Errno> {
+        self.write(self.inode_offset(ino)?, disk.as_bytes())
+    }
+
+    // The inode, shared by everyone using it
+    fn inode(&self, ino: u32) -> Result<Arc<Ext2Inode>, Errno> {
+        if let Some(inode) = self.inodes.lock().get(&ino).and_then(Weak::upgrade) {
+            return Ok(inode);
+        }
+
+        let inode = Ext2Inode::new(self.this.upgrade().unwrap(), ino, self.read_inode(ino)?);
+        self.inodes.lock().insert(ino, Arc::downgrade(&inode));
+        Ok(inode)
+    }
+
+    // Writes back a group's free counts
+    fn write_group(&self, index: usize) -> Result<(), Errno> {
+        let mut counts = [0; 6];
+        {
+            let groups = self.groups.lock();
+            let group = &groups[index];
+            counts[..2].copy_from_slice(&group.free_blocks.to_le_bytes());
+            counts[2..4].copy_from_slice(&group.free_inodes.to_le_bytes());
+            counts[4..].copy_from_slice(&group.used_dirs.to_le_bytes());
+        }
+        self.dirty.store(true, Ordering::Relaxed);
+        let offset = u64::from(self.first_data_block + 1) * u64::from(self.block_size);
+        self.write(offset + index as u64 * GROUP_DESC_SIZE + 12, &counts)
+    }
+
+    // Sets the first clear bit among the first count of a bitmap, returning
+    // which it was
+    fn take_bit(&self, bitmap: u32, count: u32) -> Result<Option<u32>, Errno> {
+        let data = self.read_block(bitmap)?;
+        for (i, &byte) in data.iter().enumerate() {
+            if byte == 0xFF {
+                continue;
+            }
+            let bit = (!byte).trailing_zeros();
+            let index = i as u32 * 8 + bit;
+            if index >= count {
+                break;
+            }
+
+            self.write(self.block_offset(bitmap)? + i as u64, &[byte | 1 << bit])?;
+            return Ok(Some(index));
+        }
+        Ok(None)
+    }
+
+    // Clearing a bit that's already clear means something was freed twice
+    fn clear_bit(&self, bitmap: u32, index: u32) -> Result<(), Errno> {
+        let offset = self.block_offset(bitmap)? + u64::from(index / 8);
+        let mut byte = [0];
+        self.read(offset, &mut byte)?;
+        let mask = 1 << (index % 8);
+        if byte[0] & mask == 0 {
+            return Err(Errno::EIO);
+        }
+        self.write(offset, &[byte[0] & !mask])
+    }
+
+    // Takes a free block, filled with zeros, from the goal group if it can so
+    // that files stay near their inodes
+    fn alloc_block(&self, goal: usize) -> Result<u32, Errno> {
+        let count = self.groups.lock().len();
+        for i in 0..count {
+            let group = (goal + i) % count;
+            let (free, bitmap) = {
+                let groups = self.groups.lock();
+                (groups[group].free_blocks, groups[group].block_bitmap)
+            };
+            if free == 0 {
+                continue;
+            }
+            let index = match self.take_bit(bitmap, self.blocks_in_group(group))? {
+                Some(index) => index,
+                None => continue,
+            };
+
+            self.groups.lock()[group].free_blocks -= 1;
+            {
+                let mut free = self.free.lock();
+                free.0 = free.0.saturating_sub(1);
+            }
+            self.write_group(group)?;
+            let block = self.first_data_block + group as u32 * self.blocks_per_group + index;
+            self.write_block(block, &vec![0; self.block_size as usize])?;
+            return Ok(block);
+        }
+        Err(Errno::ENOSPC)
+    }
+
+    fn free_block(&self, block: u32) -> Result<(), Errno> {
+        self.block_offset(block)?;
+        let group = ((block - self.first_data_block) / self.blocks_per_group) as usize;
+        let bitmap = self.groups.lock()[group].block_bitmap;
+        self.clear_bit(bitmap, (block - self.first_data_block) % self.blocks_per_group)?;
+
+        self.groups.lock()[group].free_blocks += 1;
+        self.free.lock().0 += 1;
+        self.write_group(group)
+    }
+
+    // Takes a free inode, zeroed, from the goal group if it can
+    fn alloc_inode(&self, goal: usize, dir: bool) -> Result<u32, Errno> {
+        let count = self.groups.lock().len();
+        for i in 0..count {
+            let group = (goal + i) % count;
+            let (free, bitmap) = {
+                let groups = self.groups.lock();
+                (groups[group].free_inodes, groups[group].inode_bitmap)
+            };
+            if free == 0 {
+                continue;
+            }
+            let index = match self.take_bit(bitmap, self.inodes_per_group)? {
+                Some(index) => index,
+                None => continue,
+            };
+            let ino = group as u32 * self.inodes_per_group + index + 1;
+            if ino < self.first_ino || ino > self.inode_count {
+                return Err(Errno::EIO);
+            }
+
+            {
+                let mut groups = self.groups.lock();
+                groups[group].free_inodes -= 1;
+                groups[group].used_dirs += u16::from(dir);
+            }
+            {
+                let mut free = self.free.lock();
+                free.1 = free.1.saturating_sub(1);
+            }
+            self.write_group(group)?;
+            self.write(self.inode_offset(ino)?, &vec![0; self.inode_size as usize])?;
+            return Ok(ino);
+        }
+        Err(Errno::ENOSPC)
+    }
+
+    fn free_inode(&self, ino: u32, dir: bool) -> Result<(), Errno> {
+        let group = self.inode_group(ino);
+        let bitmap = self.groups.lock()[group].inode_bitmap;
+        self.clear_bit(bitmap, (ino - 1) % self.inodes_per_group)?;
+
+        {
+            let mut groups = self.groups.lock();
+            groups[group].free_inodes += 1;
+            groups[group].used_dirs = groups[group].used_dirs.saturating_sub(u16::from(dir));
+        }
+        self.free.lock().1 += 1;
+        self.write_group(group)
+    }
+
+    // Extended attribute blocks can be shared between inodes, and are freed
+    // along with the last one
+    fn release_xattr(&self, block: u32) -> Result<(), Errno> {
+        let offset = self.block_offset(block)?;
+        let (magic, refcount) = (self.read_u32(offset)?, self.read_u32(offset + 4)?);
+        if magic == XATTR_MAGIC && refcount > 1 {
+            return self.write(offset + 4, &(refcount - 1).to_le_bytes());
+        }
+        self.free_block(block)
+    }
+
+    fn sync(&self) -> Result<(), Errno> {
+        let _guard = self.lock.lock();
+        if self.dirty.swap(false, Ordering::Relaxed) {
+            let (blocks, inodes) = *self.free.lock();
+            let mut counts = [0; 8];
+            counts[..4].copy_from_slice(&blocks.to_le_bytes());
+            counts[4..].copy_from_slice(&inodes.to_le_bytes());
+            self.write(SUPERBLOCK_OFFSET + 12, &counts)?;
+        }
+        cache::sync(&self.device)
+    }
+}
+
+pub struct Ext2Fs {
+    volume: Arc<Volume>,
+    // Kept loaded, root() can't do I/O
+    root: Arc<Ext2Inode>,
+}
+
+impl Ext2Fs {
+    // Fails with EINVAL if the device doesn't hold an ext2 filesystem this
+    // can use
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Arc<Ext2Fs>, Errno> {
+        let volume = Volume::new(device)?;
+        let root = {
+            let _guard = volume.lock.lock();
+            volume.inode(ROOT_INO)?
+        };
+        if !root.metadata().is_dir() {
+            return Err(Errno::EINVAL);
+        }
+        Ok(Arc::new(Ext2Fs { volume, root }))
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.volume.read_only
+    }
+}
+
+impl Superblock for Ext2Fs {
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+
+    fn sync(&self) -> Result<(), Errno> {
+        self.volume.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{
+            link, lstat, mkdir, mount, open, read_file, readlink, stat, symlink, truncate, unmount, FileType, O_CREAT,
+            O_RDWR,
+        },
+        *,
+    };
+    use crate::drivers::block::{testing::MemDisk, SECTOR_SIZE};
+    use alloc::{format, string::String};
+
+    const BLOCK_SIZE: usize = 1024;
+
+    fn get16(data: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([data[offset], data[offset + 1]])
+    }
+
+    fn get32(data: &[u8], offset: usize) -> u32 {
+        field(data, offset)
+    }
+
+    fn put16(data: &mut [u8], offset: usize, value: u16) {
+        data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put32(data: &mut [u8], offset: usize, value: u32) {
+        data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    // A group's block bitmap, which the inode bitmap and inode table follow.
+    // Group 0 has the superblock and descriptors first.
+    fn group_start(group: u32, blocks_per_group: u32) -> u32 {
+        1 + group * blocks_per_group + if group == 0 { 2 } else { 0 }
+    }
+
+    // How many blocks at the start of a group that leaves in use
+    fn metadata_blocks(group: u32, blocks_per_group: u32, table_blocks: u32) -> u32 {
+        group_start(group, blocks_per_group) + 2 + table_blocks - (1 + group * blocks_per_group)
+    }
+
+    // An empty filesystem in 1 KiB blocks laid out like mke2fs does it, with
+    // a root directory and lost+found. Revision 0 has 128 byte inodes and no
+    // types in directory entries, revision 1 256 byte ones and types.
+    fn format(blocks: u32, blocks_per_group: u32, inodes_per_group: u32, revision: u32) -> Arc<MemDisk> {
+        let disk = MemDisk::new(blocks as usize * BLOCK_SIZE / SECTOR_SIZE);
+        let mut data = disk.data.lock();
+        let inode_size = if revision == 0 { 128 } else { 256 };
+        let groups = (blocks - 2) / blocks_per_group + 1;
+        let table_blocks = inodes_per_group * inode_size / BLOCK_SIZE as u32;
+        let (root_block, lost_block) = {
+            let first = group_start(0, blocks_per_group) + 2 + table_blocks;
+            (first, first + 1)
+        };
+
+        let (mut free_blocks, mut free_inodes) = (0, 0);
+        for group in 0..groups {
+            let start = group_start(group, blocks_per_group);
+            let in_group = cmp::min(blocks_per_group, blocks - 1 - group * blocks_per_group);
+            let used = metadata_blocks(group, blocks_per_group, table_blocks) + if group == 0 { 2 } else { 0 };
+            let used_inodes = if group == 0 { 11 } else { 0 };
+
+            // Bits past the end of the group are set too
+            let set = |data: &mut [u8], bitmap: u32, bits: &mut dyn Iterator<Item = u32>| {
+                for bit in bits {
+                    data[bitmap as usize * BLOCK_SIZE + bit as usize / 8] |= 1 << (bit % 8);
+                }
+            };
+            set(&mut data, start, &mut (0..used).chain(in_group..BLOCK_SIZE as u32 * 8));
+            set(&mut data, start + 1, &mut (0..used_inodes).chain(inodes_per_group..BLOCK_SIZE as u32 * 8));
+
+            let desc = &mut data[2 * BLOCK_SIZE + group as usize * 32..][..32];
+            put32(desc, 0, start);
+            put32(desc, 4, start + 1);
+            put32(desc, 8, start + 2);
+            put16(desc, 12, (in_group - used) as u16);
+            put16(desc, 14, (inodes_per_group - used_inodes) as u16);
+            put16(desc, 16, if group == 0 { 2 } else { 0 });
+            free_blocks += in_group - used;
+            free_inodes += inodes_per_group - used_inodes;
+        }
+
+        let file_types = revision != 0;
+        let mut root = dir::new_block(BLOCK_SIZE, ROOT_INO, ROOT_INO, file_types);
+        let lost_type = if file_types { dir::FT_DIR } else { dir::FT_UNKNOWN };
+        assert_eq!(dir::insert(&mut root, 11, b"lost+found", lost_type), Ok(true));
+        let lost = dir::new_block(BLOCK_SIZE, 11, ROOT_INO, file_types);
+        data[root_block as usize * BLOCK_SIZE..][..BLOCK_SIZE].copy_from_slice(&root);
+        data[lost_block as usize * BLOCK_SIZE..][..BLOCK_SIZE].copy_from_slice(&lost);
+        let table = group_start(0, blocks_per_group) as usize + 2;
+        for &(ino, mode, links, block) in [(ROOT_INO, 0o40_755, 3, root_block), (11, 0o40_700, 2, lost_block)].iter() {
+            let raw = &mut data[table * BLOCK_SIZE + (ino as usize - 1) * inode_size as usize..][..128];
+            put16(raw, 0, mode);
+            put32(raw, 4, BLOCK_SIZE as u32);
+            put16(raw, 26, links);
+            put32(raw, 28, 2);
+            put32(raw, 40, block);
+        }
+
+        let sb = &mut data[SUPERBLOCK_OFFSET as usize..][..SUPERBLOCK_SIZE];
+        put32(sb, 0, groups * inodes_per_group);
+        put32(sb, 4, blocks);
+        put32(sb, 12, free_blocks);
+        put32(sb, 16, free_inodes);
+        put32(sb, 20, 1);
+        put32(sb, 32, blocks_per_group);
+        put32(sb, 36, blocks_per_group);
+        put32(sb, 40, inodes_per_group);
+        put32(sb, 48, 1_600_000_000);
+        put16(sb, 56, MAGIC);
+        put16(sb, 58, 1);
+        put32(sb, 76, revision);
+        if revision != 0 {
+            put32(sb, 84, 11);
+            put16(sb, 88, inode_size as u16);
+            put32(sb, 96, INCOMPAT_FILETYPE);
+        }
+
+        drop(data);
+        disk
+    }
+
+    // Marks the block and what's under it as the inode's, returning how many
+    // blocks that is
+    fn claim(data: &[u8], block: u32, depth: usize, ino: u32, owners: &mut BTreeMap<u32, u32>) -> u32 {
+        assert_eq!(owners.insert(block, ino), None, "block {} of inode {} is used twice", block, ino);
+        let mut count = 1;
+        if depth > 0 {
+            for i in 0..BLOCK_SIZE / 4 {
+                match get32(data, block as usize * BLOCK_SIZE + i * 4) {
+                    0 => (),
+                    next => count += claim(data, next, depth - 1, ino, owners),
+                }
+            }
+        }
+        count
+    }
+
+    // What e2fsck goes over, on the synced image: every block belongs to one
+    // inode or none, the bitmaps and free counts agree with that, and link
+    // counts agree with the directory entries
+    fn check(disk: &MemDisk) {
+        let data = disk.data.lock();
+        let sb = &data[SUPERBLOCK_OFFSET as usize..][..SUPERBLOCK_SIZE];
+        let (inode_count, blocks, blocks_per_group, inodes_per_group) =
+            (get32(sb, 0), get32(sb, 4), get32(sb, 32), get32(sb, 40));
+        let inode_size = if get32(sb, 76) == 0 { 128 } else { u32::from(get16(sb, 88)) };
+        let table_blocks = inodes_per_group * inode_size / BLOCK_SIZE as u32;
+        let groups = (blocks - 2) / blocks_per_group + 1;
+        let desc = |group: u32| &data[2 * BLOCK_SIZE + group as usize * 32..][..32];
+        let bit = |bitmap: u32, index: u32| data[bitmap as usize * BLOCK_SIZE + index as usize / 8] & 1 << (index % 8) != 0;
+
+        let mut owners = BTreeMap::new();
+        let mut references: BTreeMap<u32, u16> = BTreeMap::new();
+        let mut links = BTreeMap::new();
+        let mut dirs = vec![0; groups as usize];
+        for ino in 1..=inode_count {
+            let group = (ino - 1) / inodes_per_group;
+            let index = (ino - 1) % inodes_per_group;
+            let raw = &data[(get32(desc(group), 8) * BLOCK_SIZE as u32 + index * inode_size) as usize..][..128];
+            let used = bit(get32(desc(group), 4), index);
+            if ino < 11 && ino != ROOT_INO {
+                assert!(used);
+                continue;
+            }
+            assert_eq!(used, get16(raw, 26) != 0, "inode {}", ino);
+            if !used {
+                assert!(get16(raw, 0) == 0 || get32(raw, 20) != 0, "inode {} has no deletion time", ino);
+                continue;
+            }
+
+            let mode = get16(raw, 0);
+            let mut count = 0;
+            if mode & 0o170_000 != 0o120_000 || get32(raw, 28) != 0 {
+                for slot in 0..15 {
+                    match get32(raw, 40 + slot * 4) {
+                        0 => (),
+                        block => count += claim(&data, block, slot.saturating_sub(11), ino, &mut owners),
+                    }
+                }
+            }
+            assert_eq!(get32(raw, 28), count * 2, "inode {}", ino);
+
+            links.insert(ino, get16(raw, 26));
+            if mode & 0o170_000 == 0o040_000 {
+                dirs[group as usize] += 1;
+                let size = get32(raw, 4) as usize;
+                assert!(size % BLOCK_SIZE == 0 && size / BLOCK_SIZE <= 12);
+                for slot in 0..size / BLOCK_SIZE {
+                    let block = get32(raw, 40 + slot * 4) as usize;
+                    for entry in dir::parse(&data[block * BLOCK_SIZE..][..BLOCK_SIZE]).unwrap() {
+                        if entry.ino != 0 {
+                            *references.entry(entry.ino).or_default() += 1;
+                        }
+                    }
+                }
+            }
+        }
+        assert_eq!(links, references);
+
+        let (mut free_blocks, mut free_inodes) = (0, 0);
+        for group in 0..groups {
+            let in_group = cmp::min(blocks_per_group, blocks - 1 - group * blocks_per_group);
+            let metadata = metadata_blocks(group, blocks_per_group, table_blocks);
+            let mut free = 0;
+            for index in 0..in_group {
+                let block = 1 + group * blocks_per_group + index;
+                let wanted = index < metadata || owners.contains_key(&block);
+                assert_eq!(bit(get32(desc(group), 0), index), wanted, "block {}", block);
+                free += u32::from(!wanted);
+            }
+            let free_in_group = (0..inodes_per_group).filter(|&index| !bit(get32(desc(group), 4), index)).count();
+            assert_eq!(u32::from(get16(desc(group), 12)), free);
+            assert_eq!(usize::from(get16(desc(group), 14)), free_in_group);
+            assert_eq!(get16(desc(group), 16), dirs[group as usize]);
+            free_blocks += free;
+            free_inodes += free_in_group as u32;
+        }
+        assert_eq!((get32(sb, 12), get32(sb, 16)), (free_blocks, free_inodes));
+    }
+
+    fn contents(inode: &Arc<dyn Inode>) -> Vec<u8> {
+        let mut data = vec![0; inode.metadata().size as usize];
+        assert_eq!(inode.read_at(0, &mut data), Ok(data.len()));
+        data
+    }
+
+    fn names(dir: &Arc<dyn Inode>) -> Vec<String> {
+        let mut index = 0;
+        core::iter::from_fn(|| {
+            index += 1;
+            dir.read_dir(index - 1).unwrap()
+        })
+        .map(|entry| entry.name)
+        .collect()
+    }
+
+    fn free_blocks(fs: &Ext2Fs) -> u32 {
+        fs.volume.free.lock().0
+    }
+
+    test_case!(mke2fs_image, {
+        for &revision in [0, 1].iter() {
+            let disk = format(2048, 1024, 128, revision);
+            check(&disk);
+            let fs = Ext2Fs::new(disk.clone()).unwrap();
+            let root = fs.root();
+            assert_eq!(names(&root), [".", "..", "lost+found"]);
+            let metadata = root.metadata();
+            assert_eq!((metadata.ino, metadata.mode, metadata.nlink, metadata.size), (2, 0o755, 3, 1024));
+
+            let lost = root.lookup("lost+found").unwrap();
+            let metadata = lost.metadata();
+            assert_eq!((metadata.file_type, metadata.mode, metadata.nlink), (FileType::Directory, 0o700, 2));
+            assert_eq!(lost.lookup("..").unwrap().metadata().ino, 2);
+            assert_eq!(root.read_dir(2).unwrap().unwrap().file_type, FileType::Directory);
+            assert_eq!(root.lookup("missing").err(), Some(Errno::ENOENT));
+        }
+
+        // Unknown read-only features keep it from being written to, and
+        // unknown incompatible ones from being mounted
+        let changed = |offset: usize, bits: u8| {
+            let disk = format(2048, 1024, 128, 1);
+            disk.data.lock()[SUPERBLOCK_OFFSET as usize + offset] |= bits;
+            disk
+        };
+        let fs = Ext2Fs::new(changed(100, 0x40)).unwrap();
+        assert!(fs.read_only());
+        assert_eq!(fs.root().create("file", FileType::Regular, 0o644).err(), Some(Errno::EROFS));
+        assert_eq!(Ext2Fs::new(changed(96, 0x40)).err(), Some(Errno::EINVAL));
+        assert_eq!(Ext2Fs::new(changed(57, 0xFF)).err(), Some(Errno::EINVAL));
+    });
+
+    test_case!(files_and_directories, {
+        for &revision in [0, 1].iter() {
+            let disk = format(8192, 2048, 256, revision);
+            let fs = Ext2Fs::new(disk.clone()).unwrap();
+            let free = free_blocks(&fs);
+            let root = fs.root();
+
+            // Past the direct blocks and the single indirect ones
+            let data: Vec<u8> = (0..300 * 1024).map(|i| (i % 251) as u8).collect();
+            let file = root.create("file", FileType::Regular, 0o640).unwrap();
+            assert_eq!(file.write_at(0, &data), Ok(data.len()));
+            assert_eq!(contents(&file), data);
+            let metadata = file.metadata();
+            assert_eq!((metadata.mode, metadata.size), (0o640, 300 * 1024));
+            // With one indirect block, and a double one with one under it
+            assert_eq!(metadata.blocks, (300 + 3) * 2);
+
+            // Far enough to need the triple indirect block, leaving a hole
+            assert_eq!(file.write_at(70 << 20, b"far"), Ok(3));
+            let mut buf = [1; 8];
+            assert_eq!(file.read_at((70 << 20) - 5, &mut buf), Ok(8));
+            assert_eq!(buf, *b"\0\0\0\0\0far");
+            assert_eq!(file.metadata().blocks, (300 + 3 + 4) * 2);
+            assert_eq!(file.read_at(400 * 1024, &mut buf), Ok(8));
+            assert_eq!(buf, [0; 8]);
+
+            // Shrinking frees blocks and zeroes the rest of the last one
+            file.truncate(1000).unwrap();
+            assert_eq!(file.metadata().blocks, 2);
+            file.truncate(2000).unwrap();
+            let back = contents(&file);
+            assert!(back[..1000] == data[..1000] && back[1000..].iter().all(|&byte| byte == 0));
+
+            let sub = root.create("sub", FileType::Directory, 0o750).unwrap();
+            let nested = sub.create("nested", FileType::Directory, 0o755).unwrap();
+            nested.create("x", FileType::Regular, 0o644).unwrap().write_at(0, b"x").unwrap();
+            assert_eq!(nested.lookup("..").unwrap().metadata().ino, sub.metadata().ino);
+            assert_eq!((root.metadata().nlink, sub.metadata().nlink), (4, 3));
+            assert_eq!(root.create("sub", FileType::Regular, 0o644).err(), Some(Errno::EEXIST));
+
+            // Enough to take the directory over a few blocks
+            for i in 0..100 {
+                sub.create(&format!("file number {}", i), FileType::Regular, 0o644).unwrap();
+            }
+            let listed = names(&sub);
+            assert_eq!(listed.len(), 103);
+            assert_eq!((listed[2].as_str(), listed[102].as_str()), ("nested", "file number 99"));
+            assert!(sub.metadata().size >= 3 * 1024);
+            assert_eq!(root.unlink("sub"), Err(Errno::ENOTEMPTY));
+
+            // It's all on the disk once synced, and mounts again
+            fs.sync().unwrap();
+            check(&disk);
+            let again = Ext2Fs::new(disk.clone()).unwrap();
+            assert_eq!(contents(&again.root().lookup("file").unwrap())[..1000], data[..1000]);
+            assert_eq!(names(&again.root().lookup("sub").unwrap()).len(), 103);
+            drop(again);
+
+            // Removing it all gives every block back
+            for i in 0..100 {
+                sub.unlink(&format!("file number {}", i)).unwrap();
+            }
+            nested.unlink("x").unwrap();
+            sub.unlink("nested").unwrap();
+            root.unlink("sub").unwrap();
+            root.unlink("file").unwrap();
+            assert_eq!(names(&root), [".", "..", "lost+found"]);
+            assert_eq!(root.metadata().nlink, 3);
+            drop((file, sub, nested));
+            assert_eq!(free_blocks(&fs), free);
+            fs.sync().unwrap();
+            check(&disk);
+        }
+    });
+
+    test_case!(links_symlinks_and_full_volumes, {
+        // Under 500 free blocks
+        let disk = format(512, 512, 64, 1);
+        let fs = Ext2Fs::new(disk.clone()).unwrap();
+        let root = fs.root();
+
+        let file = root.create("file", FileType::Regular, 0o644).unwrap();
+        file.write_at(0, b"shared").unwrap();
+        root.link("other", &file).unwrap();
+        assert_eq!(file.metadata().nlink, 2);
+        assert_eq!(root.link("other", &file), Err(Errno::EEXIST));
+        let lost = root.lookup("lost+found").unwrap();
+        assert_eq!(root.link("dir", &lost), Err(Errno::EPERM));
+        root.unlink("file").unwrap();
+        let other = root.lookup("other").unwrap();
+        assert_eq!((other.metadata().ino, other.metadata().nlink), (file.metadata().ino, 1));
+        assert_eq!(contents(&other), b"shared");
+
+        // Short targets are kept in the inode
+        let target: String = core::iter::repeat('t').take(100).collect();
+        let fast = root.symlink("fast", "other").unwrap();
+        let slow = root.symlink("slow", &target).unwrap();
+        assert_eq!((fast.metadata().blocks, slow.metadata().blocks), (0, 2));
+        assert_eq!((fast.readlink(), slow.readlink()), (Ok(String::from("other")), Ok(target.clone())));
+        assert_eq!(root.read_dir(5).unwrap().unwrap().file_type, FileType::Symlink);
+        assert_eq!(fast.metadata().mode, 0o777);
+
+        // Writes as much as fits
+        let big = root.create("big", FileType::Regular, 0o644).unwrap();
+        let written = big.write_at(0, &vec![1; 600 * 1024]).unwrap();
+        assert!(written > 300 * 1024 && written < 500 * 1024);
+        assert_eq!(root.create("small", FileType::Regular, 0o644).unwrap().write_at(0, b"x"), Err(Errno::ENOSPC));
+        fs.sync().unwrap();
+        check(&disk);
+
+        // A removed file keeps its blocks while it's in use
+        let free = free_blocks(&fs);
+        root.unlink("big").unwrap();
+        assert_eq!(big.metadata().nlink, 0);
+        assert_eq!(free_blocks(&fs), free);
+        let mut buf = [0; 4];
+        assert_eq!(big.read_at(1000, &mut buf), Ok(4));
+        assert_eq!(buf, [1; 4]);
+        drop(big);
+        assert!(free_blocks(&fs) > free + 300);
+
+        root.unlink("slow").unwrap();
+        root.unlink("fast").unwrap();
+        root.unlink("small").unwrap();
+        drop((fast, slow));
+        fs.sync().unwrap();
+        check(&disk);
+    });
+
+    test_case!(root_through_the_vfs, {
+        let disk = format(4096, 4096, 256, 1);
+        mount("/", Ext2Fs::new(disk.clone()).unwrap()).unwrap();
+
+        mkdir("/home", 0o755).unwrap();
+        let file = open("/home/notes", O_RDWR | O_CREAT, 0o600).unwrap();
+        assert_eq!(file.write(b"written through the cache"), Ok(25));
+        let on_disk = || disk.data.lock().windows(25).any(|window| window == b"written through the cache");
+        assert!(!on_disk());
+        file.sync().unwrap();
+        assert!(on_disk());
+
+        link("/home/notes", "/notes").unwrap();
+        assert_eq!(stat("/notes").unwrap().nlink, 2);
+        assert_eq!(stat("/notes").unwrap().mode, 0o600);
+        assert_eq!(link("/home", "/dir"), Err(Errno::EPERM));
+        symlink("home/notes", "/link").unwrap();
+        assert_eq!(readlink("/link"), Ok(String::from("home/notes")));
+        assert_eq!(read_file("/link"), Ok(b"written through the cache".to_vec()));
+
+        drop(file);
+        unmount("/").unwrap();
+        check(&disk);
+        let fs = Ext2Fs::new(disk).unwrap();
+        assert_eq!(names(&fs.root()), [".", "..", "lost+found", "home", "notes", "link"]);
+    });
+
+    // The volume scripts/runner.sh makes with mke2fs, which mount_devices has
+    // put over the root before anything under /mnt. e2fsck checks what's
+    // written here once the tests are done.
+    test_case!(root_from_mke2fs, {
+        assert!(stat("/lost+found").unwrap().is_dir());
+        assert!(stat("/mnt").unwrap().is_dir());
+        assert_eq!(read_file("/ext2/hello.txt"), Ok(b"hello from mke2fs\n".to_vec()));
+        assert_eq!(read_file("/ext2/sub/deeper/file"), Ok(b"deep\n".to_vec()));
+
+        let metadata = stat("/ext2/hello.txt").unwrap();
+        assert_eq!((metadata.mode, metadata.nlink), (0o640, 2));
+        assert_eq!(stat("/ext2/link").unwrap().ino, metadata.ino);
+        assert_eq!(lstat("/ext2/symlink").unwrap().file_type, FileType::Symlink);
+        assert_eq!(readlink("/ext2/symlink"), Ok(String::from("hello.txt")));
+        assert_eq!(read_file("/ext2/symlink"), Ok(b"hello from mke2fs\n".to_vec()));
+
+        // Through the double indirect blocks
+        let numbers: String = (1..=100_000).map(|n| format!("{}\n", n)).collect();
+        assert!(read_file("/ext2/numbers.txt") == Ok(numbers.clone().into_bytes()));
+
+        mkdir("/ext2/written", 0o755).unwrap();
+        let data: Vec<u8> = (0..300 * 1024).map(|i| (i % 251) as u8).collect();
+        let file = open("/ext2/written/file", O_RDWR | O_CREAT, 0o644).unwrap();
+        assert_eq!(file.write(&data), Ok(data.len()));
+        truncate("/ext2/numbers.txt", 100).unwrap();
+        file.sync().unwrap();
+        assert!(read_file("/ext2/written/file") == Ok(data));
+        assert_eq!(read_file("/ext2/numbers.txt"), Ok(numbers.as_bytes()[..100].to_vec()));
+        assert_eq!(stat("/ext2").unwrap().nlink, 4);
+    });
+}
@@ -0,0 +1,447 @@
+// Directory entries. Each is 32 bytes: a short 8.3 name along with the
+// attributes, first cluster and size of the file. Long names are kept in extra
+// entries just before it, 13 UTF-16 units each, stored last part first and
+// tied to the short name by its checksum.
+use crate::syscall::Errno;
+use alloc::{format, string::String, vec, vec::Vec};
+use core::char;
+
+pub const ENTRY_SIZE: usize = 32;
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+// Read-only, hidden, system and volume ID all at once
+pub const ATTR_LONG_NAME: u8 = 0x0F;
+
+// First byte of a free entry, and of the one after the last used
+pub const FREE: u8 = 0xE5;
+pub const END: u8 = 0x00;
+
+// Set in the sequence number of the last part of a long name
+const LAST_LONG: u8 = 0x40;
+// Where the 13 units of a long name entry are
+const LONG_UNITS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const LONG_MAX: usize = 255;
+
+// Case flags, set by Windows NT for short names that are all lowercase
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+
+// 1980-01-01, the earliest date FAT can hold
+const EPOCH_DATE: u16 = 0x21;
+
+// Allowed in short names besides letters and digits
+const SHORT_SPECIAL: &[u8] = b"!#$%&'()-@^_`{}~";
+// Not allowed in long names either, along with control characters
+const INVALID: &str = "\"*/:<>?\\|";
+
+pub type Slot = [u8; ENTRY_SIZE];
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub name: String,
+    pub short_name: [u8; 11],
+    pub attr: u8,
+    pub cluster: u32,
+    pub size: u32,
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+    // Device offsets of its slots, any long name ones first
+    pub slots: Vec<u64>,
+}
+
+impl Entry {
+    // Where the short entry is
+    pub fn offset(&self) -> u64 {
+        *self.slots.last().unwrap()
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    // Names are matched ignoring case, and by the short alias too
+    pub fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || short_display(&self.short_name, 0).eq_ignore_ascii_case(name)
+    }
+}
+
+fn read_u16(slot: &Slot, offset: usize) -> u16 {
+    u16::from_le_bytes([slot[offset], slot[offset + 1]])
+}
+
+// Bits 28-31 of the cluster number are reserved on FAT32, so they're kept
+pub fn cluster(slot: &Slot) -> u32 {
+    (u32::from(read_u16(slot, 20)) << 16 | u32::from(read_u16(slot, 26))) & 0x0FFF_FFFF
+}
+
+pub fn set_cluster(slot: &mut Slot, cluster: u32) {
+    slot[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    slot[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+pub fn set_size(slot: &mut Slot, size: u32) {
+    slot[28..32].copy_from_slice(&size.to_le_bytes());
+}
+
+// Seconds since the Unix epoch for a FAT date and time, which are local time
+// really, zero if the date is unset
+pub fn to_unix(date: u16, time: u16) -> u64 {
+    let (year, month, day) = (1980 + i64::from(date >> 9), i64::from((date >> 5) & 0xF), i64::from(date & 0x1F));
+    if month == 0 || month > 12 || day == 0 {
+        return 0;
+    }
+
+    // Days from civil, counting years from March so leap days come last
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    let seconds = i64::from(time >> 11) * 3600 + i64::from((time >> 5) & 0x3F) * 60 + i64::from(time & 0x1F) * 2;
+    (days * 86400 + seconds) as u64
+}
+
+pub fn checksum(short_name: &[u8; 11]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, &byte| (sum >> 1).wrapping_add(sum << 7).wrapping_add(byte))
+}
+
+// The short name as shown, "NAME.EXT" or lowercased as the case flags say.
+// Bytes past ASCII are in some code page, which is taken to be Latin-1.
+pub fn short_display(short_name: &[u8; 11], case: u8) -> String {
+    let part = |bytes: &[u8], lower: bool| -> String {
+        let len = bytes.iter().rposition(|&byte| byte != b' ').map_or(0, |last| last + 1);
+        bytes[..len]
+            .iter()
+            .map(|&byte| match lower {
+                true => byte.to_ascii_lowercase() as char,
+                false => byte as char,
+            })
+            .collect()
+    };
+
+    let mut base = short_name[..8].to_vec();
+    // A name starting with 0xE5 is stored as 0x05, since 0xE5 means free
+    if base[0] == 0x05 {
+        base[0] = FREE;
+    }
+    let mut name = part(&base, case & CASE_LOWER_BASE != 0);
+    let ext = part(&short_name[8..], case & CASE_LOWER_EXT != 0);
+    if !ext.is_empty() {
+        name.push('.');
+        name.push_str(&ext);
+    }
+    name
+}
+
+// Gathers the parts of a long name until its short entry turns up
+#[derive(Default)]
+struct LongName {
+    units: Vec<[u16; 13]>,
+    slots: Vec<u64>,
+    checksum: u8,
+    // Sequence number of the next part, zero once all are here
+    next: u8,
+}
+
+impl LongName {
+    fn reset(&mut self) {
+        self.units.clear();
+        self.slots.clear();
+        self.next = 0;
+    }
+
+    fn push(&mut self, offset: u64, slot: &Slot) {
+        let sequence = slot[0] & !LAST_LONG;
+        if slot[0] & LAST_LONG != 0 {
+            self.reset();
+            self.checksum = slot[13];
+        } else if self.units.is_empty() || sequence != self.next || slot[13] != self.checksum {
+            // An orphaned part
+            self.reset();
+            return;
+        }
+        if sequence == 0 || sequence as usize > (LONG_MAX + 12) / 13 {
+            self.reset();
+            return;
+        }
+
+        let mut units = [0; 13];
+        for (unit, &at) in units.iter_mut().zip(LONG_UNITS.iter()) {
+            *unit = read_u16(slot, at);
+        }
+        self.units.push(units);
+        self.slots.push(offset);
+        self.next = sequence - 1;
+    }
+
+    // The name, if it's whole and belongs to the short entry
+    fn take(&mut self, checksum: u8) -> Option<(String, Vec<u64>)> {
+        let complete = !self.units.is_empty() && self.next == 0 && self.checksum == checksum;
+        let units: Vec<u16> = self.units.iter().rev().flat_map(|units| units.iter().copied()).collect();
+        let slots = self.slots.clone();
+        self.reset();
+        if !complete {
+            return None;
+        }
+
+        let len = units.iter().position(|&unit| unit == 0).unwrap_or_else(|| units.len());
+        let name = char::decode_utf16(units[..len].iter().copied())
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect();
+        Some((name, slots))
+    }
+}
+
+// The entries in a directory's slots, up to the end marker. "." and "..", as
+// well as volume labels, are left out.
+pub fn parse(slots: &[(u64, Slot)]) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut long = LongName::default();
+    for (offset, slot) in slots {
+        match slot[0] {
+            END => break,
+            FREE => {
+                long.reset();
+                continue;
+            }
+            _ => {}
+        }
+        if slot[11] & 0x3F == ATTR_LONG_NAME {
+            long.push(*offset, slot);
+            continue;
+        }
+        if slot[11] & ATTR_VOLUME_ID != 0 || slot[0] == b'.' {
+            long.reset();
+            continue;
+        }
+
+        let mut short_name = [0; 11];
+        short_name.copy_from_slice(&slot[..11]);
+        let (name, mut slots) = long
+            .take(checksum(&short_name))
+            .unwrap_or_else(|| (short_display(&short_name, slot[12]), Vec::new()));
+        slots.push(*offset);
+
+        entries.push(Entry {
+            name,
+            short_name,
+            attr: slot[11],
+            cluster: cluster(slot),
+            size: u32::from_le_bytes([slot[28], slot[29], slot[30], slot[31]]),
+            atime: to_unix(read_u16(slot, 18), 0),
+            mtime: to_unix(read_u16(slot, 24), read_u16(slot, 22)),
+            ctime: to_unix(read_u16(slot, 16), read_u16(slot, 14)),
+            slots,
+        });
+    }
+    entries
+}
+
+pub fn check_name(name: &str) -> Result<(), Errno> {
+    if name.encode_utf16().count() > LONG_MAX {
+        return Err(Errno::ENAMETOOLONG);
+    }
+    // Windows drops trailing dots and spaces, so such names can't be reached
+    // from there
+    if name.is_empty() || name.ends_with('.') || name.ends_with(' ') {
+        return Err(Errno::EINVAL);
+    }
+    if name.chars().any(|c| c.is_control() || INVALID.contains(c)) {
+        return Err(Errno::EINVAL);
+    }
+    Ok(())
+}
+
+fn short_char(byte: u8) -> bool {
+    byte.is_ascii_uppercase() || byte.is_ascii_digit() || SHORT_SPECIAL.contains(&byte)
+}
+
+// The short name and case flags for a name that fits in 8.3 as it is, so
+// needs no long name. A part in lowercase can still be stored through the
+// case flags, but mixed case can't.
+pub fn short_only(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || (name.contains('.') && ext.is_empty()) {
+        return None;
+    }
+
+    let mut short_name = [b' '; 11];
+    let mut case = 0;
+    for (part, start, len, flag) in [(base, 0, 8, CASE_LOWER_BASE), (ext, 8, 3, CASE_LOWER_EXT)].iter() {
+        let bytes = part.as_bytes();
+        let lower = bytes.iter().any(u8::is_ascii_lowercase);
+        if lower && bytes.iter().any(u8::is_ascii_uppercase) {
+            return None;
+        }
+        if lower {
+            case |= flag;
+        }
+        for (i, &byte) in bytes.iter().enumerate().take(*len) {
+            let byte = byte.to_ascii_uppercase();
+            if !short_char(byte) {
+                return None;
+            }
+            short_name[start + i] = byte;
+        }
+    }
+    Some((short_name, case))
+}
+
+// A short alias for a long name, made like Windows does: upper-cased, with
+// characters that aren't allowed replaced, cut down and given a ~n tail that
+// makes it unique
+pub fn alias(name: &str, taken: impl Fn(&[u8; 11]) -> bool) -> Option<[u8; 11]> {
+    let name = name.trim_start_matches('.');
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+    let convert = |part: &str, len: usize| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| match c.is_ascii() {
+                true if short_char(c.to_ascii_uppercase() as u8) => c.to_ascii_uppercase() as u8,
+                _ => b'_',
+            })
+            .take(len)
+            .collect()
+    };
+    let mut base = convert(base, 8);
+    if base.is_empty() {
+        base.push(b'_');
+    }
+    let ext = convert(ext, 3);
+
+    for n in 1..1_000_000 {
+        let tail = format!("~{}", n);
+        let mut short_name = [b' '; 11];
+        let kept = core::cmp::min(base.len(), 8 - tail.len());
+        short_name[..kept].copy_from_slice(&base[..kept]);
+        short_name[kept..kept + tail.len()].copy_from_slice(tail.as_bytes());
+        short_name[8..8 + ext.len()].copy_from_slice(&ext);
+        if !taken(&short_name) {
+            return Some(short_name);
+        }
+    }
+    None
+}
+
+// The long name slots for a name, in the order they're stored
+pub fn long_slots(name: &str, short_name: &[u8; 11]) -> Vec<Slot> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    // Terminated unless it fills the last part, then padded with 0xFFFF
+    if units.len() % 13 != 0 {
+        units.push(0);
+    }
+    while units.len() % 13 != 0 {
+        units.push(0xFFFF);
+    }
+
+    let count = units.len() / 13;
+    let sum = checksum(short_name);
+    (1..=count)
+        .rev()
+        .map(|sequence| {
+            let mut slot = [0; ENTRY_SIZE];
+            slot[0] = sequence as u8 | if sequence == count { LAST_LONG } else { 0 };
+            slot[11] = ATTR_LONG_NAME;
+            slot[13] = sum;
+            for (&unit, &at) in units[(sequence - 1) * 13..sequence * 13].iter().zip(LONG_UNITS.iter()) {
+                slot[at..at + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            slot
+        })
+        .collect()
+}
+
+// A short entry for a new file. There's no wall clock, so it's dated at the
+// FAT epoch.
+pub fn short_slot(short_name: &[u8; 11], case: u8, attr: u8, cluster: u32) -> Slot {
+    let mut slot = [0; ENTRY_SIZE];
+    slot[..11].copy_from_slice(short_name);
+    slot[11] = attr;
+    slot[12] = case;
+    for &at in [16, 18, 24].iter() {
+        slot[at..at + 2].copy_from_slice(&EPOCH_DATE.to_le_bytes());
+    }
+    set_cluster(&mut slot, cluster);
+    slot
+}
+
+// The "." and ".." entries that start a new directory. A parent that's the
+// root is cluster 0, even on FAT32.
+pub fn dot_slots(cluster: u32, parent: u32) -> Vec<Slot> {
+    let mut dot = [b' '; 11];
+    dot[0] = b'.';
+    let mut dot_dot = dot;
+    dot_dot[1] = b'.';
+    vec![
+        short_slot(&dot, 0, ATTR_DIRECTORY, cluster),
+        short_slot(&dot_dot, 0, ATTR_DIRECTORY, parent),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slots(slots: Vec<Slot>) -> Vec<(u64, Slot)> {
+        slots.into_iter().enumerate().map(|(i, slot)| ((i * ENTRY_SIZE) as u64, slot)).collect()
+    }
+
+    test_case!(long_names, {
+        let name = "A rather long file name.text";
+        let short_name = alias(name, |short_name| short_name == b"ARATHE~1TEX").unwrap();
+        assert_eq!(&short_name, b"ARATHE~2TEX");
+
+        let mut stored = long_slots(name, &short_name);
+        assert_eq!(stored.len(), 3);
+        assert_eq!((stored[0][0], stored[2][0]), (0x43, 0x01));
+        stored.push(short_slot(&short_name, 0, ATTR_ARCHIVE, 5));
+        stored.push(short_slot(b"PLAIN   TXT", CASE_LOWER_BASE, 0, 0));
+        let entries = parse(&slots(stored.clone()));
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, name);
+        assert_eq!(entries[0].slots, [0, 32, 64, 96]);
+        assert_eq!(entries[0].cluster, 5);
+        assert!(entries[0].matches("a RATHER long FILE name.TEXT") && entries[0].matches("arathe~2.tex"));
+        assert_eq!((entries[1].name.as_str(), entries[1].offset()), ("plain.TXT", 128));
+
+        // A long name whose checksum is off is dropped for the short one
+        stored[3][0] = b'B';
+        let entries = parse(&slots(stored));
+        assert_eq!(entries[0].name, "BRATHE~2.TEX");
+        assert_eq!(entries[0].slots, [96]);
+    });
+
+    test_case!(short_names, {
+        assert_eq!(short_only("README.TXT"), Some((*b"README  TXT", 0)));
+        assert_eq!(short_only("readme.TXT"), Some((*b"README  TXT", CASE_LOWER_BASE)));
+        assert_eq!(short_only("Makefile"), None);
+        assert_eq!(short_only("a.b.c"), None);
+        assert_eq!(short_only("toolongname"), None);
+        assert_eq!(short_only("x+y"), None);
+        assert_eq!(alias(".bashrc", |_| false), Some(*b"BASHRC~1   "));
+        assert_eq!(alias("my file+1.tar.gz", |_| false), Some(*b"MYFILE~1GZ "));
+
+        assert_eq!(check_name("fine name.txt"), Ok(()));
+        assert_eq!(check_name("what?"), Err(Errno::EINVAL));
+        assert_eq!(check_name("dot."), Err(Errno::EINVAL));
+        assert_eq!(check_name(&"x".repeat(256)), Err(Errno::ENAMETOOLONG));
+
+        // 2000-03-01 12:34:56
+        assert_eq!(to_unix((20 << 9) | (3 << 5) | 1, (12 << 11) | (34 << 5) | 28), 951_914_096);
+        assert_eq!(to_unix(0, 0), 0);
+    });
+}
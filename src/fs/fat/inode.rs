@@ -0,0 +1,496 @@
+// Files and directories. FAT has no inodes as such, everything there is to a
+// file is in its directory entry: attributes, first cluster and size.
+use super::{
+    dir::{self, Entry},
+    entry_ino,
+    FatFs,
+    ROOT_INO,
+};
+use crate::{
+    ds::SpinLock,
+    fs::{DirEntry, FileType, Inode, InodeNumber, Metadata, Superblock},
+    syscall::Errno,
+};
+use alloc::{
+    string::String,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use core::{cmp, iter, ops::Range};
+
+// Sizes are 32 bits
+pub const FILE_SIZE_MAX: u64 = u32::max_value() as u64;
+
+struct InodeInner {
+    attr: u8,
+    // Zero for an empty file
+    cluster: u32,
+    size: u32,
+    atime: u64,
+    mtime: u64,
+    ctime: u64,
+    // The last cluster found by walking the chain and its index, where the
+    // next walk starts if it can, so files read in order aren't walked from
+    // the start each time
+    cursor: Option<(u32, u32)>,
+    // Removed while in use. Its clusters are freed once it's no longer.
+    removed: bool,
+}
+
+pub struct FatInode {
+    fs: Arc<FatFs>,
+    ino: InodeNumber,
+    // For handing out as a parent
+    this: Weak<FatInode>,
+    // Where the short entry is, None for the root, which has none
+    entry: Option<u64>,
+    // None for the root, which is its own parent
+    parent: Option<Arc<FatInode>>,
+    inner: SpinLock<InodeInner>,
+}
+
+impl FatInode {
+    pub fn new(fs: Arc<FatFs>, parent: Arc<FatInode>, entry: &Entry) -> Arc<FatInode> {
+        let inner = InodeInner {
+            attr: entry.attr,
+            cluster: entry.cluster,
+            size: if entry.is_dir() { 0 } else { entry.size },
+            atime: entry.atime,
+            mtime: entry.mtime,
+            ctime: entry.ctime,
+            cursor: None,
+            removed: false,
+        };
+        Self::build(fs, entry_ino(entry), Some(entry.offset()), Some(parent), inner)
+    }
+
+    // The root is cluster 0 on FAT12 and FAT16, for the fixed root directory
+    pub fn root(fs: Arc<FatFs>, cluster: u32) -> Arc<FatInode> {
+        let inner = InodeInner {
+            attr: dir::ATTR_DIRECTORY,
+            cluster,
+            size: 0,
+            atime: 0,
+            mtime: 0,
+            ctime: 0,
+            cursor: None,
+            removed: false,
+        };
+        Self::build(fs, ROOT_INO, None, None, inner)
+    }
+
+    fn build(
+        fs: Arc<FatFs>,
+        ino: InodeNumber,
+        entry: Option<u64>,
+        parent: Option<Arc<FatInode>>,
+        inner: InodeInner,
+    ) -> Arc<FatInode> {
+        let mut inode = Arc::new(FatInode {
+            fs,
+            ino,
+            this: Weak::new(),
+            entry,
+            parent,
+            inner: SpinLock::new(inner),
+        });
+        let this = Arc::downgrade(&inode);
+        Arc::get_mut(&mut inode).unwrap().this = this;
+        inode
+    }
+
+    fn arc(&self) -> Arc<FatInode> {
+        self.this.upgrade().unwrap()
+    }
+
+    fn is_dir(&self) -> bool {
+        self.inner.lock().attr & dir::ATTR_DIRECTORY != 0
+    }
+
+    fn cluster(&self) -> u32 {
+        self.inner.lock().cluster
+    }
+
+    // Follows the chain towards index, returning where it got to, which is
+    // short of it if the chain ends first. None for an empty file.
+    fn walk(&self, index: u32) -> Result<Option<(u32, u32)>, Errno> {
+        let (first, cursor) = {
+            let inner = self.inner.lock();
+            (inner.cluster, inner.cursor)
+        };
+        let (mut at, mut cluster) = match cursor {
+            Some((at, cluster)) if at <= index => (at, cluster),
+            _ if first == 0 => return Ok(None),
+            _ => (0, first),
+        };
+        if !self.fs.is_cluster(cluster) {
+            return Err(Errno::EIO);
+        }
+
+        while at < index {
+            match self.fs.next_cluster(cluster)? {
+                Some(next) => cluster = next,
+                None => break,
+            }
+            at += 1;
+            // Longer than the volume means it loops
+            if at > self.fs.clusters {
+                return Err(Errno::EIO);
+            }
+        }
+        self.inner.lock().cursor = Some((at, cluster));
+        Ok(Some((at, cluster)))
+    }
+
+    // Calls f with the device offset of each piece of offset..offset + len,
+    // which must have clusters, and where that piece is in the range
+    fn map<F>(&self, offset: u64, len: usize, mut f: F) -> Result<(), Errno>
+    where
+        F: FnMut(u64, Range<usize>) -> Result<(), Errno>,
+    {
+        let cluster_size = u64::from(self.fs.cluster_size);
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let within = position % cluster_size;
+            let chunk = cmp::min(len - done, (cluster_size - within) as usize);
+            let index = (position / cluster_size) as u32;
+            let cluster = match self.walk(index)? {
+                Some((at, cluster)) if at == index => cluster,
+                _ => return Err(Errno::EIO),
+            };
+
+            f(self.fs.cluster_offset(cluster) + within, done..done + chunk)?;
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    // Makes the chain count clusters long if there's room, returning how long
+    // it got
+    fn grow(&self, count: u32) -> Result<u32, Errno> {
+        let mut last = self.walk(u32::max_value())?;
+        let mut len = last.map_or(0, |(at, _)| at + 1);
+        while len < count {
+            let cluster = match self.fs.alloc_cluster(last.map(|(_, cluster)| cluster)) {
+                Ok(cluster) => cluster,
+                Err(Errno::ENOSPC) => break,
+                Err(errno) => return Err(errno),
+            };
+
+            let mut inner = self.inner.lock();
+            if last.is_none() {
+                inner.cluster = cluster;
+            }
+            last = Some((len, cluster));
+            inner.cursor = last;
+            len += 1;
+        }
+        Ok(len)
+    }
+
+    // Frees every cluster past the first count
+    fn shrink(&self, count: u32) -> Result<(), Errno> {
+        let first = self.cluster();
+        if first == 0 {
+            return Ok(());
+        }
+        if count == 0 {
+            self.fs.free_chain(first)?;
+            let mut inner = self.inner.lock();
+            inner.cluster = 0;
+            inner.cursor = None;
+            return Ok(());
+        }
+
+        if let Some((at, last)) = self.walk(count - 1)? {
+            if at == count - 1 {
+                if let Some(next) = self.fs.next_cluster(last)? {
+                    self.fs.set_fat_entry(last, self.fs.end_of_chain() | 7)?;
+                    self.fs.free_chain(next)?;
+                }
+            }
+        }
+        self.inner.lock().cursor = None;
+        Ok(())
+    }
+
+    fn clusters_for(&self, size: u64) -> u32 {
+        let cluster_size = u64::from(self.fs.cluster_size);
+        ((size + cluster_size - 1) / cluster_size) as u32
+    }
+
+    fn zero(&self, from: u64, to: u64) -> Result<(), Errno> {
+        let zeros = vec![0; self.fs.cluster_size as usize];
+        self.map(from, (to - from) as usize, |device, range| self.fs.write(device, &zeros[..range.len()]))
+    }
+
+    // Writes the first cluster and size back to the directory entry
+    fn write_entry(&self) -> Result<(), Errno> {
+        let (cluster, size) = {
+            let inner = self.inner.lock();
+            match self.entry {
+                Some(_) if !inner.removed => (inner.cluster, inner.size),
+                _ => return Ok(()),
+            }
+        };
+
+        let offset = self.entry.unwrap();
+        let mut slot = [0; dir::ENTRY_SIZE];
+        self.fs.read(offset, &mut slot)?;
+        dir::set_cluster(&mut slot, cluster);
+        dir::set_size(&mut slot, size);
+        self.fs.write(offset, &slot)
+    }
+}
+
+impl Drop for FatInode {
+    fn drop(&mut self) {
+        let (removed, cluster) = {
+            let inner = self.inner.lock();
+            (inner.removed, inner.cluster)
+        };
+        if removed && cluster != 0 {
+            let _guard = self.fs.lock.lock();
+            if let Err(errno) = self.fs.free_chain(cluster) {
+                error!("fat: couldn't free the clusters of a removed file: {:?}", errno);
+            }
+        }
+
+        // Unless the number went to another entry since
+        let mut inodes = self.fs.inodes.lock();
+        if inodes.get(&self.ino).map_or(false, |inode| inode.strong_count() == 0) {
+            inodes.remove(&self.ino);
+        }
+    }
+}
+
+impl Inode for FatInode {
+    fn metadata(&self) -> Metadata {
+        let inner = self.inner.lock();
+        let cluster_size = u64::from(self.fs.cluster_size);
+        let (file_type, mode) = match inner.attr & dir::ATTR_DIRECTORY {
+            0 => (FileType::Regular, 0o644),
+            _ => (FileType::Directory, 0o755),
+        };
+        let mode = if inner.attr & dir::ATTR_READ_ONLY != 0 { mode & !0o222 } else { mode };
+
+        let mut metadata = Metadata::new(self.ino, file_type, mode);
+        metadata.nlink = match (inner.removed, file_type) {
+            (true, _) => 0,
+            (false, FileType::Directory) => 2,
+            _ => 1,
+        };
+        metadata.size = u64::from(inner.size);
+        metadata.blksize = self.fs.cluster_size;
+        metadata.blocks = (metadata.size + cluster_size - 1) / cluster_size * cluster_size / 512;
+        metadata.atime = inner.atime;
+        metadata.mtime = inner.mtime;
+        metadata.ctime = inner.ctime;
+        metadata
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Errno> {
+        if !self.is_dir() {
+            return Err(Errno::ENOTDIR);
+        }
+        if name == ".." {
+            return Ok(self.parent.clone().unwrap_or_else(|| self.arc()));
+        }
+
+        let _guard = self.fs.lock.lock();
+        let entry = self.fs.find(self.cluster(), name)?.ok_or(Errno::ENOENT)?;
+        Ok(self.fs.inode(&self.arc(), &entry))
+    }
+
+    // There are no permissions, so mode is ignored
+    fn create(&self, name: &str, file_type: FileType, _mode: u16) -> Result<Arc<dyn Inode>, Errno> {
+        if !self.is_dir() {
+            return Err(Errno::ENOTDIR);
+        }
+        let attr = match file_type {
+            FileType::Regular => dir::ATTR_ARCHIVE,
+            FileType::Directory => dir::ATTR_DIRECTORY,
+            _ => return Err(Errno::EPERM),
+        };
+        dir::check_name(name)?;
+
+        let _guard = self.fs.lock.lock();
+        if self.inner.lock().removed {
+            return Err(Errno::ENOENT);
+        }
+        let cluster = self.cluster();
+        let entries = self.fs.entries(cluster)?;
+        if entries.iter().any(|entry| entry.matches(name)) {
+            return Err(Errno::EEXIST);
+        }
+
+        // A long name is only added if the name doesn't fit in 8.3
+        let taken = |short_name: &[u8; 11]| entries.iter().any(|entry| &entry.short_name == short_name);
+        let (short_name, case, long) = match dir::short_only(name) {
+            Some((short_name, case)) if !taken(&short_name) => (short_name, case, Vec::new()),
+            _ => {
+                let short_name = dir::alias(name, taken).ok_or(Errno::EEXIST)?;
+                (short_name, 0, dir::long_slots(name, &short_name))
+            }
+        };
+
+        // Directories start out with "." and ".." in their first cluster
+        let first = match file_type {
+            FileType::Directory => {
+                let first = self.fs.alloc_cluster(None)?;
+                let parent = if self.entry.is_none() { 0 } else { cluster };
+                let dots: Vec<u8> = dir::dot_slots(first, parent).concat();
+                self.fs.write(self.fs.cluster_offset(first), &dots)?;
+                first
+            }
+            _ => 0,
+        };
+
+        let short = dir::short_slot(&short_name, case, attr, first);
+        let slots = match self.fs.free_slots(cluster, long.len() + 1) {
+            Ok(slots) => slots,
+            Err(errno) => {
+                self.fs.free_chain(first)?;
+                return Err(errno);
+            }
+        };
+        for (&offset, slot) in slots.iter().zip(long.iter().chain(iter::once(&short))) {
+            self.fs.write(offset, slot)?;
+        }
+
+        let mut entry = dir::parse(&[(*slots.last().unwrap(), short)]).remove(0);
+        entry.name = String::from(name);
+        entry.slots = slots;
+        Ok(self.fs.inode(&self.arc(), &entry))
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn Inode>, Errno> {
+        Err(Errno::EPERM)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), Errno> {
+        if !self.is_dir() {
+            return Err(Errno::ENOTDIR);
+        }
+
+        let _guard = self.fs.lock.lock();
+        let entry = self.fs.find(self.cluster(), name)?.ok_or(Errno::ENOENT)?;
+        if entry.is_dir() && (entry.cluster == 0 || !self.fs.entries(entry.cluster)?.is_empty()) {
+            return Err(Errno::ENOTEMPTY);
+        }
+
+        for &offset in &entry.slots {
+            self.fs.write(offset, &[dir::FREE])?;
+        }
+        match self.fs.forget(entry_ino(&entry)) {
+            // Whoever has it open can keep using its clusters meanwhile
+            Some(inode) => inode.inner.lock().removed = true,
+            None => self.fs.free_chain(entry.cluster)?,
+        }
+        Ok(())
+    }
+
+    fn read_dir(&self, index: usize) -> Result<Option<DirEntry>, Errno> {
+        if !self.is_dir() {
+            return Err(Errno::ENOTDIR);
+        }
+
+        let entry = |name: &str, ino, file_type| DirEntry {
+            ino,
+            name: String::from(name),
+            file_type,
+        };
+        Ok(match index {
+            0 => Some(entry(".", self.ino, FileType::Directory)),
+            1 => Some(entry("..", self.parent.as_ref().map_or(self.ino, |parent| parent.ino), FileType::Directory)),
+            _ => {
+                let _guard = self.fs.lock.lock();
+                self.fs.entries(self.cluster())?.get(index - 2).map(|child| {
+                    let file_type = if child.is_dir() { FileType::Directory } else { FileType::Regular };
+                    entry(&child.name, entry_ino(child), file_type)
+                })
+            }
+        })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        if self.is_dir() {
+            return Err(Errno::EISDIR);
+        }
+
+        let _guard = self.fs.lock.lock();
+        let size = u64::from(self.inner.lock().size);
+        if offset >= size {
+            return Ok(0);
+        }
+
+        let len = cmp::min(buf.len() as u64, size - offset) as usize;
+        self.map(offset, len, |device, range| self.fs.read(device, &mut buf[range]))?;
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, Errno> {
+        if self.is_dir() {
+            return Err(Errno::EISDIR);
+        }
+        let end = match offset.checked_add(buf.len() as u64) {
+            Some(end) if end <= FILE_SIZE_MAX => end,
+            _ => return Err(Errno::EFBIG),
+        };
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let _guard = self.fs.lock.lock();
+        let size = u64::from(self.inner.lock().size);
+        let room = u64::from(self.grow(self.clusters_for(end))?) * u64::from(self.fs.cluster_size);
+        if room <= offset {
+            self.shrink(self.clusters_for(size))?;
+            return Err(Errno::ENOSPC);
+        }
+
+        // There are no holes, the gap is filled with zeros
+        if offset > size {
+            self.zero(size, offset)?;
+        }
+        let len = (cmp::min(end, room) - offset) as usize;
+        self.map(offset, len, |device, range| self.fs.write(device, &buf[range]))?;
+
+        let mut inner = self.inner.lock();
+        inner.size = cmp::max(size, offset + len as u64) as u32;
+        drop(inner);
+        self.write_entry()?;
+        Ok(len)
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), Errno> {
+        if self.is_dir() {
+            return Err(Errno::EISDIR);
+        }
+        if size > FILE_SIZE_MAX {
+            return Err(Errno::EFBIG);
+        }
+
+        let _guard = self.fs.lock.lock();
+        let old = u64::from(self.inner.lock().size);
+        if size > old {
+            let count = self.clusters_for(size);
+            if self.grow(count)? < count {
+                self.shrink(self.clusters_for(old))?;
+                return Err(Errno::ENOSPC);
+            }
+            self.zero(old, size)?;
+        } else {
+            self.shrink(self.clusters_for(size))?;
+        }
+
+        self.inner.lock().size = size as u32;
+        self.write_entry()
+    }
+
+    fn sync(&self) -> Result<(), Errno> {
+        self.fs.sync()
+    }
+}
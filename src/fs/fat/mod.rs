@@ -0,0 +1,792 @@
+// FAT12, FAT16 and FAT32, as made by mkfs.fat and mtools or shown to the
+// guest by QEMU's vvfat. The volume is a boot sector describing its layout,
+// one or more copies of the FAT, which links each cluster of a file to the
+// next, and then the clusters. FAT12 and FAT16 keep the root directory in a
+// fixed area before the clusters. Everything goes through the buffer cache,
+// so changes reach the disk once it's synced.
+use super::{Inode, InodeNumber, Superblock};
+use crate::{
+    drivers::block::{cache, BlockDevice},
+    ds::{Mutex, SpinLock},
+    syscall::Errno,
+};
+use alloc::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+pub mod dir;
+mod inode;
+
+use self::{dir::Slot, inode::FatInode};
+
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+
+// FAT32 has an FSInfo sector with a hint of where free clusters are
+const FS_INFO_LEAD: u32 = 0x4161_5252;
+const FS_INFO_STRUCT: u32 = 0x6141_7272;
+const FS_INFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+// Counts of clusters past which FAT12 and FAT16 can't go
+const FAT12_MAX_CLUSTERS: u32 = 4084;
+const FAT16_MAX_CLUSTERS: u32 = 65524;
+
+// Directories hold at most 65536 entries
+const DIR_MAX_SIZE: u64 = 2 * 1024 * 1024;
+
+pub const ROOT_INO: InodeNumber = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+fn read<T: Copy>(sector: &[u8], offset: usize) -> T {
+    assert!(offset + core::mem::size_of::<T>() <= sector.len());
+    unsafe { core::ptr::read_unaligned(sector.as_ptr().add(offset) as *const T) }
+}
+
+// Inode numbers come from where the short entry is, which doesn't change
+// while it exists. The root has none and gets ROOT_INO.
+fn entry_ino(entry: &dir::Entry) -> InodeNumber {
+    entry.offset() / dir::ENTRY_SIZE as u64 + 2
+}
+
+pub struct FatFs {
+    device: Arc<dyn BlockDevice>,
+    fat_type: FatType,
+    cluster_size: u32,
+    // Offsets of the FATs that are kept up to date, the first being the one
+    // read. FAT32 can turn mirroring off and use a single one.
+    fats: Vec<u64>,
+    // The fixed root directory of FAT12 and FAT16, empty on FAT32
+    root_offset: u64,
+    root_size: u64,
+    root_cluster: u32,
+    data_offset: u64,
+    // Clusters are numbered from 2 to clusters + 1
+    clusters: u32,
+    fs_info: Option<u64>,
+    // Held across anything touching the FAT or directories, which may sleep
+    // on I/O, so a SpinLock won't do
+    lock: Mutex<()>,
+    // Where to start looking for a free cluster
+    next_free: AtomicU32,
+    // Set once the FAT changes, so FSInfo is out of date
+    dirty: AtomicBool,
+    // Inodes in use by inode number, so that everyone sees the same size
+    inodes: SpinLock<BTreeMap<InodeNumber, Weak<FatInode>>>,
+    this: Weak<FatFs>,
+}
+
+impl FatFs {
+    // Fails with EINVAL if the device doesn't hold a FAT filesystem
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Arc<FatFs>, Errno> {
+        let mut boot = [0; 512];
+        if device.size() < boot.len() as u64 {
+            return Err(Errno::EINVAL);
+        }
+        cache::read(&device, 0, &mut boot)?;
+        if boot[510..512] != BOOT_SIGNATURE || (boot[0] != 0xEB && boot[0] != 0xE9) {
+            return Err(Errno::EINVAL);
+        }
+
+        let sector_size = u64::from(read::<u16>(&boot, 0x0B));
+        let sectors_per_cluster = u64::from(boot[0x0D]);
+        let reserved = u64::from(read::<u16>(&boot, 0x0E));
+        let fat_count = u64::from(boot[0x10]);
+        let root_entries = u64::from(read::<u16>(&boot, 0x11));
+        let total = match read::<u16>(&boot, 0x13) {
+            0 => u64::from(read::<u32>(&boot, 0x20)),
+            total => u64::from(total),
+        };
+        let (fat_size, fat32) = match read::<u16>(&boot, 0x16) {
+            0 => (u64::from(read::<u32>(&boot, 0x24)), true),
+            size => (u64::from(size), false),
+        };
+
+        let cluster_size = sector_size * sectors_per_cluster;
+        if !(512..=4096).contains(&sector_size)
+            || !sector_size.is_power_of_two()
+            || !sectors_per_cluster.is_power_of_two()
+            || cluster_size > 64 * 1024
+            || reserved == 0
+            || fat_count == 0
+            || fat_size == 0
+            || (fat32 && root_entries != 0)
+            || total * sector_size > device.size()
+        {
+            return Err(Errno::EINVAL);
+        }
+
+        let root_sectors = (root_entries * dir::ENTRY_SIZE as u64 + sector_size - 1) / sector_size;
+        let data_start = reserved + fat_count * fat_size + root_sectors;
+        if data_start >= total {
+            return Err(Errno::EINVAL);
+        }
+        let clusters = ((total - data_start) / sectors_per_cluster) as u32;
+        let fat_type = match clusters {
+            _ if fat32 => FatType::Fat32,
+            0..=FAT12_MAX_CLUSTERS => FatType::Fat12,
+            _ => FatType::Fat16,
+        };
+        // The FAT must have room for every cluster
+        let fat_bits = match fat_type {
+            FatType::Fat12 => 12,
+            FatType::Fat16 => 16,
+            FatType::Fat32 => 32,
+        };
+        if (u64::from(clusters) + 2) * fat_bits > fat_size * sector_size * 8
+            || (fat_type == FatType::Fat16 && clusters > FAT16_MAX_CLUSTERS)
+        {
+            return Err(Errno::EINVAL);
+        }
+
+        let mut fats: Vec<u64> = (0..fat_count).map(|i| (reserved + i * fat_size) * sector_size).collect();
+        let (mut root_cluster, mut fs_info) = (0, None);
+        if fat32 {
+            let flags = read::<u16>(&boot, 0x28);
+            if flags & 0x80 != 0 {
+                let active = fats.get(usize::from(flags & 0xF)).copied().ok_or(Errno::EINVAL)?;
+                fats = vec![active];
+            }
+            root_cluster = read::<u32>(&boot, 0x2C);
+            if root_cluster < 2 || root_cluster >= clusters + 2 {
+                return Err(Errno::EINVAL);
+            }
+
+            let sector = u64::from(read::<u16>(&boot, 0x30));
+            if sector != 0 && sector < reserved {
+                let mut info = [0; 512];
+                cache::read(&device, sector * sector_size, &mut info)?;
+                if read::<u32>(&info, 0) == FS_INFO_LEAD && read::<u32>(&info, 0x1E4) == FS_INFO_STRUCT {
+                    fs_info = Some(sector * sector_size);
+                }
+            }
+        }
+
+        let mut fs = Arc::new(FatFs {
+            device,
+            fat_type,
+            cluster_size: cluster_size as u32,
+            fats,
+            root_offset: (reserved + fat_count * fat_size) * sector_size,
+            root_size: root_entries * dir::ENTRY_SIZE as u64,
+            root_cluster,
+            data_offset: data_start * sector_size,
+            clusters,
+            fs_info,
+            lock: Mutex::new(()),
+            next_free: AtomicU32::new(2),
+            dirty: AtomicBool::new(false),
+            inodes: SpinLock::new(BTreeMap::new()),
+            this: Weak::new(),
+        });
+        let this = Arc::downgrade(&fs);
+        Arc::get_mut(&mut fs).unwrap().this = this;
+        Ok(fs)
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        cache::read(&self.device, offset, buf)
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) -> Result<(), Errno> {
+        cache::write(&self.device, offset, buf)
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_offset + u64::from(cluster - 2) * u64::from(self.cluster_size)
+    }
+
+    // The smallest value marking the end of a chain. The one below is a bad
+    // cluster.
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat12 => 0xFF8,
+            FatType::Fat16 => 0xFFF8,
+            FatType::Fat32 => 0x0FFF_FFF8,
+        }
+    }
+
+    fn fat_entry(&self, cluster: u32) -> Result<u32, Errno> {
+        let mut raw = [0; 4];
+        Ok(match self.fat_type {
+            FatType::Fat12 => {
+                self.read(self.fats[0] + u64::from(cluster + cluster / 2), &mut raw[..2])?;
+                let pair = u32::from(u16::from_le_bytes([raw[0], raw[1]]));
+                if cluster & 1 == 1 {
+                    pair >> 4
+                } else {
+                    pair & 0xFFF
+                }
+            }
+            FatType::Fat16 => {
+                self.read(self.fats[0] + u64::from(cluster) * 2, &mut raw[..2])?;
+                u32::from(u16::from_le_bytes([raw[0], raw[1]]))
+            }
+            FatType::Fat32 => {
+                self.read(self.fats[0] + u64::from(cluster) * 4, &mut raw)?;
+                u32::from_le_bytes(raw) & 0x0FFF_FFFF
+            }
+        })
+    }
+
+    // Updates every copy of the FAT
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), Errno> {
+        self.dirty.store(true, Ordering::Relaxed);
+        for &fat in &self.fats {
+            match self.fat_type {
+                FatType::Fat12 => {
+                    let offset = fat + u64::from(cluster + cluster / 2);
+                    let mut raw = [0; 2];
+                    self.read(offset, &mut raw)?;
+                    let pair = u16::from_le_bytes(raw);
+                    let pair = if cluster & 1 == 1 {
+                        (pair & 0x000F) | (value as u16) << 4
+                    } else {
+                        (pair & 0xF000) | (value as u16 & 0xFFF)
+                    };
+                    self.write(offset, &pair.to_le_bytes())?;
+                }
+                FatType::Fat16 => self.write(fat + u64::from(cluster) * 2, &(value as u16).to_le_bytes())?,
+                FatType::Fat32 => {
+                    // The top four bits are reserved
+                    let offset = fat + u64::from(cluster) * 4;
+                    let mut raw = [0; 4];
+                    self.read(offset, &mut raw)?;
+                    let value = (u32::from_le_bytes(raw) & 0xF000_0000) | (value & 0x0FFF_FFFF);
+                    self.write(offset, &value.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn is_cluster(&self, value: u32) -> bool {
+        value >= 2 && value < self.clusters + 2
+    }
+
+    // The cluster after this one, None at the end of the chain. Anything
+    // else, like a free or bad cluster, means the chain is broken.
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, Errno> {
+        let next = self.fat_entry(cluster)?;
+        if next >= self.end_of_chain() {
+            Ok(None)
+        } else if self.is_cluster(next) {
+            Ok(Some(next))
+        } else {
+            Err(Errno::EIO)
+        }
+    }
+
+    // The whole chain starting at first, which is empty for cluster 0
+    fn chain(&self, first: u32) -> Result<Vec<u32>, Errno> {
+        let mut chain = Vec::new();
+        let mut next = Some(first).filter(|&first| first != 0);
+        while let Some(cluster) = next {
+            // Longer than the volume means it loops
+            if !self.is_cluster(cluster) || chain.len() > self.clusters as usize {
+                return Err(Errno::EIO);
+            }
+            chain.push(cluster);
+            next = self.next_cluster(cluster)?;
+        }
+        Ok(chain)
+    }
+
+    // Takes a free cluster, filled with zeros, and links it after prev if
+    // there is one
+    fn alloc_cluster(&self, prev: Option<u32>) -> Result<u32, Errno> {
+        let start = self.next_free.load(Ordering::Relaxed);
+        let start = if self.is_cluster(start) { start } else { 2 };
+        for i in 0..self.clusters {
+            let cluster = 2 + (start - 2 + i) % self.clusters;
+            if self.fat_entry(cluster)? != 0 {
+                continue;
+            }
+
+            self.write(self.cluster_offset(cluster), &vec![0; self.cluster_size as usize])?;
+            self.set_fat_entry(cluster, self.end_of_chain() | 7)?;
+            if let Some(prev) = prev {
+                self.set_fat_entry(prev, cluster)?;
+            }
+            self.next_free.store(cluster + 1, Ordering::Relaxed);
+            return Ok(cluster);
+        }
+        Err(Errno::ENOSPC)
+    }
+
+    fn free_chain(&self, first: u32) -> Result<(), Errno> {
+        for cluster in self.chain(first)? {
+            self.set_fat_entry(cluster, 0)?;
+        }
+        Ok(())
+    }
+
+    // Every slot of a directory along with its device offset. Cluster 0 is
+    // the fixed root directory.
+    fn dir_slots(&self, cluster: u32) -> Result<Vec<(u64, Slot)>, Errno> {
+        let extents: Vec<(u64, u64)> = if cluster == 0 {
+            vec![(self.root_offset, self.root_size)]
+        } else {
+            let size = u64::from(self.cluster_size);
+            self.chain(cluster)?.into_iter().map(|cluster| (self.cluster_offset(cluster), size)).collect()
+        };
+
+        let mut slots = Vec::new();
+        for (offset, size) in extents {
+            let mut data = vec![0; size as usize];
+            self.read(offset, &mut data)?;
+            for (i, chunk) in data.chunks_exact(dir::ENTRY_SIZE).enumerate() {
+                let mut slot = [0; dir::ENTRY_SIZE];
+                slot.copy_from_slice(chunk);
+                slots.push((offset + (i * dir::ENTRY_SIZE) as u64, slot));
+            }
+        }
+        Ok(slots)
+    }
+
+    fn entries(&self, cluster: u32) -> Result<Vec<dir::Entry>, Errno> {
+        Ok(dir::parse(&self.dir_slots(cluster)?))
+    }
+
+    fn find(&self, cluster: u32, name: &str) -> Result<Option<dir::Entry>, Errno> {
+        Ok(self.entries(cluster)?.into_iter().find(|entry| entry.matches(name)))
+    }
+
+    // Offsets of count free slots in a row, growing the directory if there
+    // aren't enough. The fixed root directory can't grow.
+    fn free_slots(&self, cluster: u32, count: usize) -> Result<Vec<u64>, Errno> {
+        let slots = self.dir_slots(cluster)?;
+        let mut run = Vec::new();
+        let mut end = false;
+        for (offset, slot) in &slots {
+            end |= slot[0] == dir::END;
+            if end || slot[0] == dir::FREE {
+                run.push(*offset);
+                if run.len() == count {
+                    return Ok(run);
+                }
+            } else {
+                run.clear();
+            }
+        }
+
+        if cluster == 0 {
+            return Err(Errno::ENOSPC);
+        }
+        let mut chain = self.chain(cluster)?;
+        while run.len() < count {
+            if (chain.len() as u64 + 1) * u64::from(self.cluster_size) > DIR_MAX_SIZE {
+                return Err(Errno::ENOSPC);
+            }
+            let new = self.alloc_cluster(chain.last().copied())?;
+            chain.push(new);
+            let offset = self.cluster_offset(new);
+            let fits = self.cluster_size as usize / dir::ENTRY_SIZE;
+            run.extend((0..fits).map(|i| offset + (i * dir::ENTRY_SIZE) as u64).take(count - run.len()));
+        }
+        Ok(run)
+    }
+
+    // The inode for an entry of parent, shared by everyone using it
+    fn inode(&self, parent: &Arc<FatInode>, entry: &dir::Entry) -> Arc<FatInode> {
+        let ino = entry_ino(entry);
+        let mut inodes = self.inodes.lock();
+        if let Some(inode) = inodes.get(&ino).and_then(Weak::upgrade) {
+            return inode;
+        }
+
+        let inode = FatInode::new(self.this.upgrade().unwrap(), parent.clone(), entry);
+        inodes.insert(ino, Arc::downgrade(&inode));
+        inode
+    }
+
+    // Called when an entry is removed, so its inode number can be reused
+    fn forget(&self, ino: InodeNumber) -> Option<Arc<FatInode>> {
+        self.inodes.lock().remove(&ino).and_then(|inode| inode.upgrade())
+    }
+
+    fn write_fs_info(&self) -> Result<(), Errno> {
+        if let Some(offset) = self.fs_info {
+            // The free count would take a scan of the whole FAT to get right,
+            // so it's marked unknown
+            let mut counts = [0; 8];
+            counts[..4].copy_from_slice(&FS_INFO_UNKNOWN.to_le_bytes());
+            counts[4..].copy_from_slice(&self.next_free.load(Ordering::Relaxed).to_le_bytes());
+            self.write(offset + 0x1E8, &counts)?;
+        }
+        Ok(())
+    }
+}
+
+impl Superblock for FatFs {
+    fn root(&self) -> Arc<dyn Inode> {
+        let mut inodes = self.inodes.lock();
+        if let Some(root) = inodes.get(&ROOT_INO).and_then(Weak::upgrade) {
+            return root;
+        }
+
+        let root = FatInode::root(self.this.upgrade().unwrap(), self.root_cluster);
+        inodes.insert(ROOT_INO, Arc::downgrade(&root));
+        root
+    }
+
+    fn sync(&self) -> Result<(), Errno> {
+        let _guard = self.lock.lock();
+        if self.dirty.swap(false, Ordering::Relaxed) {
+            self.write_fs_info()?;
+        }
+        cache::sync(&self.device)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{mkdir, mount, open, read_file, symlink, truncate, unmount, FileType, O_CREAT, O_RDWR},
+        *,
+    };
+    use crate::drivers::block::{self, testing::MemDisk, SECTOR_SIZE};
+    use alloc::{format, string::String};
+
+    fn put16(data: &mut [u8], offset: usize, value: u16) {
+        data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put32(data: &mut [u8], offset: usize, value: u32) {
+        data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    // An empty volume laid out like mkfs.fat does it, with two FATs and one
+    // sector clusters so that files span plenty of them. FAT32 has its root
+    // in cluster 2.
+    fn format(fat_type: FatType, sectors: usize, root_entries: u16) -> Arc<MemDisk> {
+        let disk = MemDisk::new(sectors);
+        let mut data = disk.data.lock();
+        let (bits, reserved, root_entries) = match fat_type {
+            FatType::Fat12 => (12, 1, root_entries),
+            FatType::Fat16 => (16, 1, root_entries),
+            FatType::Fat32 => (32, 32, 0),
+        };
+        let fat_size = ((sectors + 2) * bits + 4095) / 4096;
+
+        let boot = &mut data[..SECTOR_SIZE];
+        boot[..11].copy_from_slice(b"\xEB\x3C\x90mkfs.fat");
+        put16(boot, 0x0B, SECTOR_SIZE as u16);
+        boot[0x0D] = 1;
+        put16(boot, 0x0E, reserved as u16);
+        boot[0x10] = 2;
+        put16(boot, 0x11, root_entries);
+        put16(boot, 0x13, sectors as u16);
+        boot[0x15] = 0xF8;
+        if fat_type == FatType::Fat32 {
+            put32(boot, 0x24, fat_size as u32);
+            put32(boot, 0x2C, 2);
+            put16(boot, 0x30, 1);
+        } else {
+            put16(boot, 0x16, fat_size as u16);
+        }
+        boot[510..].copy_from_slice(&BOOT_SIGNATURE);
+
+        if fat_type == FatType::Fat32 {
+            let info = &mut data[SECTOR_SIZE..2 * SECTOR_SIZE];
+            put32(info, 0, FS_INFO_LEAD);
+            put32(info, 0x1E4, FS_INFO_STRUCT);
+            put32(info, 0x1E8, FS_INFO_UNKNOWN);
+            put32(info, 0x1EC, 3);
+            info[510..].copy_from_slice(&BOOT_SIGNATURE);
+        }
+
+        // The media byte and an end of chain mark, then the end of the FAT32
+        // root's chain
+        let head: &[u8] = match fat_type {
+            FatType::Fat12 => &[0xF8, 0xFF, 0xFF],
+            FatType::Fat16 => &[0xF8, 0xFF, 0xFF, 0xFF],
+            FatType::Fat32 => &[0xF8, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF, 0xFF, 0x0F],
+        };
+        for i in 0..2 {
+            let start = (reserved + i * fat_size) * SECTOR_SIZE;
+            data[start..start + head.len()].copy_from_slice(head);
+        }
+
+        drop(data);
+        disk
+    }
+
+    // A long name slot as the spec lays it out
+    fn long_slot(sequence: u8, checksum: u8, part: &str) -> Slot {
+        let mut units: Vec<u16> = part.encode_utf16().chain(core::iter::once(0)).collect();
+        units.resize(13, 0xFFFF);
+        let mut slot = [0; dir::ENTRY_SIZE];
+        slot[0] = sequence;
+        slot[11] = 0x0F;
+        slot[13] = checksum;
+        for (i, &unit) in units.iter().enumerate() {
+            let at = match i {
+                0..=4 => 1 + 2 * i,
+                5..=10 => 14 + 2 * (i - 5),
+                _ => 28 + 2 * (i - 11),
+            };
+            put16(&mut slot, at, unit);
+        }
+        slot
+    }
+
+    fn short_slot(name: &[u8; 11], attr: u8, case: u8, cluster: u16, size: u32) -> Slot {
+        let mut slot = [0; dir::ENTRY_SIZE];
+        slot[..11].copy_from_slice(name);
+        slot[11] = attr;
+        slot[12] = case;
+        put16(&mut slot, 26, cluster);
+        put32(&mut slot, 28, size);
+        slot
+    }
+
+    fn contents(inode: &Arc<dyn Inode>) -> Vec<u8> {
+        let mut data = vec![0; inode.metadata().size as usize];
+        assert_eq!(inode.read_at(0, &mut data), Ok(data.len()));
+        data
+    }
+
+    fn names(dir: &Arc<dyn Inode>) -> Vec<String> {
+        let mut index = 0;
+        core::iter::from_fn(|| {
+            index += 1;
+            dir.read_dir(index - 1).unwrap()
+        })
+        .map(|entry| entry.name)
+        .collect()
+    }
+
+    fn free_clusters(fs: &FatFs) -> usize {
+        (2..fs.clusters + 2).filter(|&cluster| fs.fat_entry(cluster).unwrap() == 0).count()
+    }
+
+    // What mtools leaves after copying in a file with a long name and a
+    // directory with a file in it, put together by hand
+    test_case!(reference_image, {
+        let disk = format(FatType::Fat12, 2880, 224);
+        {
+            let mut data = disk.data.lock();
+            // The file is in clusters 2 to 4, the directory in 5 and its file
+            // in 6. FAT12 packs two entries into three bytes.
+            let fat = [0xF8, 0xFF, 0xFF, 0x03, 0x40, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0x0F, 0x00];
+            for &start in [512, 10 * 512].iter() {
+                data[start..start + fat.len()].copy_from_slice(&fat);
+            }
+
+            let mut file = short_slot(b"HELLOW~1TXT", 0x20, 0, 2, 1100);
+            // 2000-03-01 12:34:56
+            put16(&mut file, 22, 0x645C);
+            put16(&mut file, 24, 0x2861);
+            let root = [
+                short_slot(b"TESTDISK   ", 0x08, 0, 0, 0),
+                long_slot(0x42, 0x1B, "xt"),
+                long_slot(0x01, 0x1B, "Hello World.t"),
+                file,
+                short_slot(b"\xE5LD     TXT", 0x20, 0, 7, 10),
+                short_slot(b"SUB        ", 0x10, 0, 5, 0),
+            ];
+            let sub = [
+                short_slot(b".          ", 0x10, 0, 5, 0),
+                short_slot(b"..         ", 0x10, 0, 0, 0),
+                short_slot(b"A       TXT", 0x20, 0x18, 6, 3),
+            ];
+            for (i, slot) in root.iter().enumerate() {
+                data[19 * 512 + i * 32..][..32].copy_from_slice(slot);
+            }
+            for (i, slot) in sub.iter().enumerate() {
+                data[36 * 512 + i * 32..][..32].copy_from_slice(slot);
+            }
+            for i in 0..1100 {
+                data[33 * 512 + i] = (i % 251) as u8;
+            }
+            data[37 * 512..37 * 512 + 3].copy_from_slice(b"abc");
+        }
+
+        let fs = FatFs::new(disk).unwrap();
+        assert_eq!(fs.fat_type(), FatType::Fat12);
+        let root = fs.root();
+        assert_eq!(names(&root), [".", "..", "Hello World.txt", "SUB"]);
+
+        let file = root.lookup("HELLO WORLD.TXT").unwrap();
+        assert_eq!(file.metadata().ino, root.lookup("hellow~1.txt").unwrap().metadata().ino);
+        let metadata = file.metadata();
+        assert_eq!((metadata.size, metadata.blocks, metadata.mtime), (1100, 3, 951_914_096));
+        let data = contents(&file);
+        assert!(data.iter().enumerate().all(|(i, &byte)| byte == (i % 251) as u8));
+        let mut buf = [0; 200];
+        assert_eq!(file.read_at(1000, &mut buf), Ok(100));
+        assert_eq!(root.lookup("old.txt").err(), Some(Errno::ENOENT));
+
+        let sub = root.lookup("sub").unwrap();
+        assert!(sub.metadata().is_dir());
+        assert_eq!(names(&sub), [".", "..", "a.txt"]);
+        assert_eq!(contents(&sub.lookup("A.txt").unwrap()), b"abc");
+        assert_eq!(sub.lookup("..").unwrap().metadata().ino, ROOT_INO);
+        assert_eq!(root.lookup("..").unwrap().metadata().ino, ROOT_INO);
+    });
+
+    test_case!(files_and_directories, {
+        for &(fat_type, sectors) in [(FatType::Fat12, 2880), (FatType::Fat16, 8192), (FatType::Fat32, 2048)].iter() {
+            let disk = format(fat_type, sectors, 64);
+            let fs = FatFs::new(disk.clone()).unwrap();
+            assert_eq!(fs.fat_type(), fat_type);
+            let free = free_clusters(&fs);
+            let root = fs.root();
+
+            // Across clusters, with a gap that reads back as zeros
+            let data: Vec<u8> = (0..3000).map(|i| (i % 253) as u8).collect();
+            let file = root.create("A long name.data", FileType::Regular, 0o644).unwrap();
+            assert_eq!(file.write_at(0, &data), Ok(data.len()));
+            assert_eq!(file.write_at(5000, b"end"), Ok(3));
+            let back = contents(&file);
+            assert_eq!(back.len(), 5003);
+            assert!(back[..3000] == data[..] && back[3000..5000].iter().all(|&byte| byte == 0));
+            assert_eq!(&back[5000..], b"end");
+            assert_eq!(root.create("A LONG NAME.DATA", FileType::Regular, 0o644).err(), Some(Errno::EEXIST));
+            assert_eq!(root.create("bad:name", FileType::Regular, 0o644).err(), Some(Errno::EINVAL));
+
+            // Shrinking and growing again clears what was past the end
+            file.truncate(100).unwrap();
+            file.truncate(600).unwrap();
+            let back = contents(&file);
+            assert!(back[..100] == data[..100] && back[100..].iter().all(|&byte| byte == 0));
+
+            let sub = root.create("sub", FileType::Directory, 0o755).unwrap();
+            let nested = sub.create("nested", FileType::Directory, 0o755).unwrap();
+            nested.create("x", FileType::Regular, 0o644).unwrap().write_at(0, b"x").unwrap();
+            assert_eq!(nested.lookup("..").unwrap().metadata().ino, sub.metadata().ino);
+            assert_eq!(sub.lookup("..").unwrap().metadata().ino, ROOT_INO);
+
+            // Enough long names to take the directory past one cluster
+            for i in 0..40 {
+                sub.create(&format!("file number {}", i), FileType::Regular, 0o644).unwrap();
+            }
+            let listed = names(&sub);
+            assert_eq!(listed.len(), 43);
+            assert_eq!((listed[2].as_str(), listed[42].as_str()), ("nested", "file number 39"));
+            assert_eq!(root.unlink("sub"), Err(Errno::ENOTEMPTY));
+
+            // Synced, it's all on the disk, with both FATs the same
+            fs.sync().unwrap();
+            {
+                let raw = disk.data.lock();
+                assert!(raw.windows(11).any(|name| name == b"ALONGN~1DAT"));
+                assert!(raw.windows(101).any(|window| window[..100] == data[..100] && window[100] == 0));
+                let fat_size = (fs.fats[1] - fs.fats[0]) as usize;
+                let (first, second) = (fs.fats[0] as usize, fs.fats[1] as usize);
+                assert!(raw[first..first + fat_size] == raw[second..second + fat_size]);
+            }
+            let again = FatFs::new(disk.clone()).unwrap();
+            assert_eq!(contents(&again.root().lookup("a long name.data").unwrap()).len(), 600);
+
+            // Removing it all gives every cluster back
+            for i in 0..40 {
+                sub.unlink(&format!("FILE NUMBER {}", i)).unwrap();
+            }
+            nested.unlink("x").unwrap();
+            sub.unlink("nested").unwrap();
+            root.unlink("sub").unwrap();
+            root.unlink("a long name.data").unwrap();
+            assert_eq!(names(&root), [".", ".."]);
+            drop((file, sub, nested));
+            assert_eq!(free_clusters(&fs), free);
+        }
+    });
+
+    test_case!(open_files_and_full_volumes, {
+        // 124 clusters and 16 root entries
+        let fs = FatFs::new(format(FatType::Fat12, 128, 16)).unwrap();
+        let root = fs.root();
+
+        // Writes as much as fits
+        let big = root.create("big", FileType::Regular, 0o644).unwrap();
+        assert_eq!(big.write_at(0, &vec![1; 200 * 512]), Ok(124 * 512));
+        let other = root.create("other", FileType::Regular, 0o644).unwrap();
+        assert_eq!(other.write_at(0, b"x"), Err(Errno::ENOSPC));
+
+        // A removed file keeps its clusters while it's open
+        root.unlink("big").unwrap();
+        assert_eq!(big.metadata().nlink, 0);
+        assert_eq!(other.write_at(0, b"x"), Err(Errno::ENOSPC));
+        let mut buf = [0; 4];
+        assert_eq!(big.read_at(1000, &mut buf), Ok(4));
+        assert_eq!(buf, [1; 4]);
+        drop(big);
+        assert_eq!(other.write_at(0, b"x"), Ok(1));
+
+        // The FAT12 root directory doesn't grow
+        for i in 0..15 {
+            root.create(&format!("F{}", i), FileType::Regular, 0o644).unwrap();
+        }
+        assert_eq!(root.create("F15", FileType::Regular, 0o644).err(), Some(Errno::ENOSPC));
+        assert_eq!(root.create("link", FileType::Symlink, 0o777).err(), Some(Errno::EPERM));
+    });
+
+    test_case!(fsync_through_the_vfs, {
+        let disk = format(FatType::Fat16, 8192, 64);
+        mount("/", FatFs::new(disk.clone()).unwrap()).unwrap();
+
+        mkdir("/docs", 0o755).unwrap();
+        let file = open("/docs/Notes for later.txt", O_RDWR | O_CREAT, 0o644).unwrap();
+        assert_eq!(file.write(b"written through the cache"), Ok(25));
+        let on_disk = || disk.data.lock().windows(25).any(|window| window == b"written through the cache");
+        assert!(!on_disk());
+        file.sync().unwrap();
+        assert!(on_disk());
+
+        assert_eq!(read_file("/DOCS/notes FOR later.TXT"), Ok(b"written through the cache".to_vec()));
+        assert_eq!(symlink("docs", "/link"), Err(Errno::EPERM));
+        unmount("/").unwrap();
+    });
+
+    // The volumes scripts/runner.sh makes with mkfs.fat and fills with mcopy,
+    // which mount_devices has put under /mnt. fsck.fat checks what's written
+    // here once the tests are done.
+    test_case!(volumes_from_mkfs, {
+        let numbers: String = (1..=20000).map(|n| format!("{}\n", n)).collect();
+        let mut found = Vec::new();
+        for device in block::devices() {
+            let fat_type = match FatFs::new(device.clone()) {
+                Ok(fs) => fs.fat_type(),
+                Err(_) => continue,
+            };
+            let dir = format!("/mnt/{}", device.name());
+            let path = |name: &str| format!("{}/{}", dir, name);
+
+            assert_eq!(read_file(&path("Hello World.txt")), Ok(b"hello from mtools\n".to_vec()));
+            assert_eq!(read_file(&path("HELLOW~1.TXT")), Ok(b"hello from mtools\n".to_vec()));
+            assert_eq!(read_file(&path("Sub Dir/nested.txt")), Ok(b"nested\n".to_vec()));
+            assert!(read_file(&path("numbers.txt")) == Ok(numbers.clone().into_bytes()));
+
+            // Across plenty of clusters, and in a new directory
+            mkdir(&path("New Dir"), 0o755).unwrap();
+            let data: Vec<u8> = (0..20_000).map(|i| (i % 251) as u8).collect();
+            let file = open(&path("New Dir/Written by solstice.bin"), O_RDWR | O_CREAT, 0o644).unwrap();
+            assert_eq!(file.write(&data), Ok(data.len()));
+
+            // Freeing most of a chain
+            truncate(&path("numbers.txt"), 100).unwrap();
+            file.sync().unwrap();
+            assert!(read_file(&path("New Dir/Written by solstice.bin")) == Ok(data));
+            assert_eq!(read_file(&path("numbers.txt")), Ok(numbers.as_bytes()[..100].to_vec()));
+            found.push(fat_type);
+        }
+
+        for &fat_type in [FatType::Fat12, FatType::Fat16, FatType::Fat32].iter() {
+            assert!(found.contains(&fat_type), "no {:?} volume", fat_type);
+        }
+    });
+}
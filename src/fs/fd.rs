@@ -0,0 +1,133 @@
+// File descriptor tables. Every process has one, which fork copies: the copy
+// refers to the same files and so shares their positions.
+use super::{console::console, File};
+use crate::{process, syscall::Errno};
+use alloc::{sync::Arc, vec::Vec};
+
+pub const MAX_FDS: usize = 256;
+
+#[derive(Clone)]
+struct Descriptor {
+    file: Arc<dyn File>,
+    // Closed by exec
+    cloexec: bool,
+}
+
+#[derive(Clone, Default)]
+pub struct FdTable {
+    fds: Vec<Option<Descriptor>>,
+}
+
+impl FdTable {
+    pub fn new() -> FdTable {
+        FdTable::default()
+    }
+
+    // stdin, stdout and stderr on the console
+    pub fn with_console() -> FdTable {
+        let mut table = FdTable::new();
+        for _ in 0..3 {
+            table.insert(console(), false).unwrap();
+        }
+        table
+    }
+
+    pub fn get(&self, fd: i32) -> Result<Arc<dyn File>, Errno> {
+        self.descriptor(fd).map(|descriptor| descriptor.file.clone())
+    }
+
+    fn descriptor(&self, fd: i32) -> Result<&Descriptor, Errno> {
+        if fd < 0 {
+            return Err(Errno::EBADF);
+        }
+        self.fds.get(fd as usize).and_then(Option::as_ref).ok_or(Errno::EBADF)
+    }
+
+    // Uses the lowest free descriptor
+    pub fn insert(&mut self, file: Arc<dyn File>, cloexec: bool) -> Result<i32, Errno> {
+        let descriptor = Some(Descriptor { file, cloexec });
+        match self.fds.iter().position(Option::is_none) {
+            Some(fd) => {
+                self.fds[fd] = descriptor;
+                Ok(fd as i32)
+            }
+            None if self.fds.len() < MAX_FDS => {
+                self.fds.push(descriptor);
+                Ok(self.fds.len() as i32 - 1)
+            }
+            None => Err(Errno::EMFILE),
+        }
+    }
+
+    pub fn remove(&mut self, fd: i32) -> Result<Arc<dyn File>, Errno> {
+        self.descriptor(fd)?;
+        let descriptor = self.fds[fd as usize].take().unwrap();
+
+        while let Some(None) = self.fds.last() {
+            self.fds.pop();
+        }
+        Ok(descriptor.file)
+    }
+
+    pub fn close_on_exec(&mut self) {
+        for fd in self.fds.iter_mut() {
+            if fd.as_ref().map_or(false, |descriptor| descriptor.cloexec) {
+                *fd = None;
+            }
+        }
+    }
+}
+
+// Looks up a descriptor of the current process. Kernel threads don't have a
+// table, but can write to the console through 0 to 2.
+pub fn get(fd: i32) -> Result<Arc<dyn File>, Errno> {
+    match process::current() {
+        Some(process) => process.files().lock().get(fd),
+        None if fd >= 0 && fd < 3 => Ok(console()),
+        None => Err(Errno::EBADF),
+    }
+}
+
+pub fn insert(file: Arc<dyn File>, cloexec: bool) -> Result<i32, Errno> {
+    let process = process::current().ok_or(Errno::EMFILE)?;
+    let mut files = process.files().lock();
+    files.insert(file, cloexec)
+}
+
+pub fn close(fd: i32) -> Result<(), Errno> {
+    let process = process::current().ok_or(Errno::EBADF)?;
+    let file = process.files().lock().remove(fd)?;
+
+    // Dropping the last reference may call into the filesystem
+    drop(file);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    test_case!(lowest_free_descriptor, {
+        let mut table = FdTable::with_console();
+        assert_eq!(table.insert(console(), true), Ok(3));
+        assert_eq!(table.insert(console(), false), Ok(4));
+        assert!(table.remove(1).is_ok());
+        assert_eq!(table.remove(1).err(), Some(Errno::EBADF));
+        assert_eq!(table.get(-1).err(), Some(Errno::EBADF));
+        assert_eq!(table.insert(console(), false), Ok(1));
+
+        table.close_on_exec();
+        assert!(table.get(3).is_err());
+        assert!(table.get(4).is_ok());
+    });
+
+    test_case!(table_is_limited, {
+        let mut table = FdTable::new();
+        for fd in 0..MAX_FDS {
+            assert_eq!(table.insert(console(), false), Ok(fd as i32));
+        }
+        assert_eq!(table.insert(console(), false).err(), Some(Errno::EMFILE));
+        assert!(table.remove(MAX_FDS as i32 - 1).is_ok());
+        assert!(table.insert(console(), false).is_ok());
+    });
+}
@@ -0,0 +1,146 @@
+// Open files. A File is shared by every descriptor that refers to it, along
+// with its position.
+use super::{DirEntry, Location, Metadata, O_ACCMODE, O_APPEND, O_RDWR, O_WRONLY};
+use crate::syscall::Errno;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+pub trait File: Send + Sync {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Errno>;
+
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno>;
+
+    // Returns the new position. Files without one fail with ESPIPE.
+    fn seek(&self, _pos: SeekFrom) -> Result<u64, Errno> {
+        Err(Errno::ESPIPE)
+    }
+
+    fn metadata(&self) -> Result<Metadata, Errno>;
+
+    // The next directory entry, or None at the end
+    fn read_dir(&self) -> Result<Option<DirEntry>, Errno> {
+        Err(Errno::ENOTDIR)
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), Errno> {
+        Err(Errno::EINVAL)
+    }
+
+    // Returns once everything written has reached the device
+    fn sync(&self) -> Result<(), Errno> {
+        Ok(())
+    }
+}
+
+// A file or directory opened through its inode. For directories the position
+// counts entries.
+pub struct InodeFile {
+    location: Location,
+    flags: u32,
+    // Not locked across filesystem calls, which may sleep
+    position: AtomicU64,
+}
+
+impl InodeFile {
+    pub fn new(location: Location, flags: u32) -> InodeFile {
+        InodeFile {
+            location,
+            flags,
+            position: AtomicU64::new(0),
+        }
+    }
+
+    pub fn location(&self) -> &Location {
+        &self.location
+    }
+
+    fn readable(&self) -> bool {
+        self.flags & O_ACCMODE != O_WRONLY
+    }
+
+    fn writable(&self) -> bool {
+        let mode = self.flags & O_ACCMODE;
+        mode == O_WRONLY || mode == O_RDWR
+    }
+}
+
+impl File for InodeFile {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        if !self.readable() {
+            return Err(Errno::EBADF);
+        }
+
+        let position = self.position.load(Ordering::Acquire);
+        let len = self.location.inode().read_at(position, buf)?;
+        self.position.store(position + len as u64, Ordering::Release);
+        Ok(len)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        if !self.writable() {
+            return Err(Errno::EBADF);
+        }
+
+        let position = if self.flags & O_APPEND != 0 {
+            self.location.metadata().size
+        } else {
+            self.position.load(Ordering::Acquire)
+        };
+        let len = self.location.inode().write_at(position, buf)?;
+        self.position.store(position + len as u64, Ordering::Release);
+        Ok(len)
+    }
+
+    fn seek(&self, pos: SeekFrom) -> Result<u64, Errno> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => (offset, 0),
+            SeekFrom::Current(delta) => (self.position.load(Ordering::Acquire), delta),
+            SeekFrom::End(delta) => (self.location.metadata().size, delta),
+        };
+
+        let position = if delta < 0 {
+            base.checked_sub(delta.wrapping_neg() as u64)
+        } else {
+            base.checked_add(delta as u64)
+        };
+
+        // Positions are signed for lseek
+        match position {
+            Some(position) if position <= i64::max_value() as u64 => {
+                self.position.store(position, Ordering::Release);
+                Ok(position)
+            }
+            _ => Err(Errno::EINVAL),
+        }
+    }
+
+    fn metadata(&self) -> Result<Metadata, Errno> {
+        Ok(self.location.metadata())
+    }
+
+    fn read_dir(&self) -> Result<Option<DirEntry>, Errno> {
+        let position = self.position.load(Ordering::Acquire);
+        let entry = self.location.inode().read_dir(position as usize)?;
+        if entry.is_some() {
+            self.position.store(position + 1, Ordering::Release);
+        }
+        Ok(entry)
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), Errno> {
+        if !self.writable() {
+            return Err(Errno::EINVAL);
+        }
+        self.location.inode().truncate(size)
+    }
+
+    fn sync(&self) -> Result<(), Errno> {
+        self.location.inode().sync()
+    }
+}
@@ -0,0 +1,472 @@
+// The initial ramdisk, an archive unpacked into the root filesystem before
+// anything runs. build.rs packs the initramfs/ directory into one that's built
+// into the kernel, and one loaded with the kernel as the boot package is
+// unpacked over it. Both newc cpio and ustar tar archives work, told apart by
+// their magic.
+use super::{mkdir, open, symlink, O_CREAT, O_TRUNC, O_WRONLY};
+use crate::{
+    mm::{
+        self,
+        addr_space::AddrSpace,
+        map::{self, Region},
+        pmm::PhysAllocator,
+    },
+    syscall::Errno,
+};
+use alloc::{collections::BTreeMap, format, string::String, vec::Vec};
+use core::{slice, str};
+use x86_64::{
+    structures::paging::{PageSize, Size4KiB},
+    VirtAddr,
+};
+
+const CPIO_MAGIC: &[u8] = b"070701";
+// Same layout, with checksums we don't check
+const CPIO_CRC_MAGIC: &[u8] = b"070702";
+const CPIO_HEADER_SIZE: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+const TAR_BLOCK_SIZE: usize = 512;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+// S_IFMT and the types we unpack
+const S_IFMT: u32 = 0o170_000;
+const S_IFREG: u32 = 0o100_000;
+const S_IFDIR: u32 = 0o040_000;
+const S_IFLNK: u32 = 0o120_000;
+
+// The archive from build.rs, empty if there was nothing to pack
+const BUILTIN_SIZE: usize = include_bytes!(concat!(env!("OUT_DIR"), "/initramfs.cpio")).len();
+#[link_section = ".initramfs"]
+static BUILTIN: [u8; BUILTIN_SIZE] = *include_bytes!(concat!(env!("OUT_DIR"), "/initramfs.cpio"));
+
+// Exported by linker.ld, around BUILTIN
+extern "C" {
+    static __initramfs_start: u8;
+    static __initramfs_end: u8;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveError {
+    UnknownFormat,
+    Truncated,
+    BadHeader,
+    BadChecksum,
+    BadName,
+    Fs(Errno),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind<'a> {
+    File(&'a [u8]),
+    Directory,
+    Symlink(&'a str),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry<'a> {
+    // Relative to the root, without leading or trailing slashes
+    pub path: String,
+    pub mode: u16,
+    pub kind: EntryKind<'a>,
+}
+
+// Unpacks the built in archive and then the boot package, if there is one,
+// freeing their memory afterwards
+pub fn init() {
+    if !BUILTIN.is_empty() {
+        match unpack(&BUILTIN) {
+            Ok(count) => info!("initramfs: unpacked {} built in entries", count),
+            Err(err) => error!("initramfs: can't unpack the built in archive: {:?}", err),
+        }
+    }
+    free_builtin();
+
+    for region in map::package_regions() {
+        let archive = unsafe {
+            slice::from_raw_parts(mm::phys_to_kernel_virt(region.addr).as_ptr::<u8>(), region.size)
+        };
+        match unpack(archive) {
+            Ok(count) => info!("initramfs: unpacked {} entries", count),
+            Err(err) => error!("initramfs: can't unpack: {:?}", err),
+        }
+
+        PhysAllocator::add_region(region);
+    }
+}
+
+// Unmaps the pages of the built in archive and gives their frames to the PMM,
+// a run of contiguous ones at a time
+fn free_builtin() {
+    let kernel = AddrSpace::kernel();
+    let (start, end) = unsafe { (VirtAddr::from_ptr(&__initramfs_start), VirtAddr::from_ptr(&__initramfs_end)) };
+
+    let mut run: Option<Region> = None;
+    let mut addr = start;
+    while addr < end {
+        let (frame, flush) = kernel.unmap(addr).expect("initramfs: built in archive isn't mapped");
+        flush.flush();
+
+        let frame_addr = frame.start_address();
+        match run.as_mut() {
+            Some(rg) if rg.addr + rg.size == frame_addr => rg.size += Size4KiB::SIZE,
+            _ => {
+                if let Some(rg) = run.replace(Region {
+                    addr: frame_addr,
+                    size: Size4KiB::SIZE,
+                }) {
+                    PhysAllocator::add_region(rg);
+                }
+            }
+        }
+        addr += Size4KiB::SIZE;
+    }
+
+    if let Some(rg) = run {
+        PhysAllocator::add_region(rg);
+    }
+}
+
+// Unpacks an archive into the root, returning the number of entries. A
+// malformed archive is rejected before anything is written, but the
+// filesystem failing part of the way through leaves what was unpacked so far.
+pub fn unpack(archive: &[u8]) -> Result<usize, ArchiveError> {
+    let entries = parse(archive)?;
+
+    // Parents made before they're listed get their mode from the archive
+    let dirs: BTreeMap<&str, u16> = entries
+        .iter()
+        .filter(|entry| entry.kind == EntryKind::Directory)
+        .map(|entry| (&entry.path[..], entry.mode))
+        .collect();
+    for entry in &entries {
+        unpack_entry(entry, &dirs).map_err(ArchiveError::Fs)?;
+    }
+    Ok(entries.len())
+}
+
+pub fn parse(archive: &[u8]) -> Result<Vec<Entry>, ArchiveError> {
+    if archive.starts_with(CPIO_MAGIC) || archive.starts_with(CPIO_CRC_MAGIC) {
+        parse_cpio(archive)
+    } else if archive.len() >= TAR_BLOCK_SIZE && archive[257..262] == *TAR_MAGIC {
+        parse_tar(archive)
+    } else {
+        Err(ArchiveError::UnknownFormat)
+    }
+}
+
+fn unpack_entry(entry: &Entry, dirs: &BTreeMap<&str, u16>) -> Result<(), Errno> {
+    let path = format!("/{}", entry.path);
+
+    // Archives don't always list parents first, or at all
+    for (i, _) in path.match_indices('/').skip(1) {
+        let mode = dirs.get(&path[1..i]).copied().unwrap_or(0o755);
+        match mkdir(&path[..i], mode) {
+            Ok(()) | Err(Errno::EEXIST) => {}
+            Err(errno) => return Err(errno),
+        }
+    }
+
+    match entry.kind {
+        EntryKind::File(data) => {
+            let file = open(&path, O_WRONLY | O_CREAT | O_TRUNC, entry.mode)?;
+            let mut written = 0;
+            while written < data.len() {
+                match file.write(&data[written..])? {
+                    0 => return Err(Errno::ENOSPC),
+                    len => written += len,
+                }
+            }
+            Ok(())
+        }
+        EntryKind::Directory => match mkdir(&path, entry.mode) {
+            Ok(()) | Err(Errno::EEXIST) => Ok(()),
+            Err(errno) => Err(errno),
+        },
+        EntryKind::Symlink(target) => symlink(target, &path),
+    }
+}
+
+// Makes a path relative to the root, returning None for the root itself
+fn clean_path(path: &str) -> Result<Option<String>, ArchiveError> {
+    let mut clean = String::new();
+    for name in path.split('/').filter(|name| !name.is_empty() && *name != ".") {
+        if name == ".." {
+            return Err(ArchiveError::BadName);
+        }
+        if !clean.is_empty() {
+            clean.push('/');
+        }
+        clean.push_str(name);
+    }
+
+    Ok(if clean.is_empty() { None } else { Some(clean) })
+}
+
+fn entry_kind(mode: u32, data: &[u8]) -> Result<Option<EntryKind>, ArchiveError> {
+    Ok(match mode & S_IFMT {
+        S_IFREG => Some(EntryKind::File(data)),
+        S_IFDIR => Some(EntryKind::Directory),
+        S_IFLNK => Some(EntryKind::Symlink(str::from_utf8(data).map_err(|_| ArchiveError::BadName)?)),
+        _ => None,
+    })
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+fn get(archive: &[u8], start: usize, len: usize) -> Result<&[u8], ArchiveError> {
+    let end = start.checked_add(len).ok_or(ArchiveError::Truncated)?;
+    archive.get(start..end).ok_or(ArchiveError::Truncated)
+}
+
+// newc: a header of 13 eight digit hex fields after the magic, then the name
+// and the data, each padded to 4 bytes. Hard links aren't kept, only the last
+// name of each gets the data and the others come out empty. Device nodes are
+// skipped.
+fn parse_cpio(archive: &[u8]) -> Result<Vec<Entry>, ArchiveError> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+
+    loop {
+        let header = get(archive, offset, CPIO_HEADER_SIZE)?;
+        if !header.starts_with(CPIO_MAGIC) && !header.starts_with(CPIO_CRC_MAGIC) {
+            return Err(ArchiveError::BadHeader);
+        }
+
+        let field = |index: usize| {
+            let digits = &header[6 + index * 8..14 + index * 8];
+            let digits = str::from_utf8(digits).map_err(|_| ArchiveError::BadHeader)?;
+            u32::from_str_radix(digits, 16).map_err(|_| ArchiveError::BadHeader)
+        };
+        let mode = field(1)?;
+        let file_size = field(6)? as usize;
+        let name_size = field(11)? as usize;
+
+        // The name includes its terminator
+        let name_start = offset + CPIO_HEADER_SIZE;
+        let name = match get(archive, name_start, name_size)?.split_last() {
+            Some((&0, name)) => str::from_utf8(name).map_err(|_| ArchiveError::BadName)?,
+            _ => return Err(ArchiveError::BadName),
+        };
+        let data_start = align_up(name_start + name_size, 4);
+        let data = get(archive, data_start, file_size)?;
+        offset = align_up(data_start + file_size, 4);
+
+        if name == CPIO_TRAILER {
+            return Ok(entries);
+        }
+
+        if let (Some(path), Some(kind)) = (clean_path(name)?, entry_kind(mode, data)?) {
+            entries.push(Entry {
+                path,
+                mode: (mode & 0o7777) as u16,
+                kind,
+            });
+        }
+    }
+}
+
+// A NUL padded string field
+fn tar_string(field: &[u8]) -> Result<&str, ArchiveError> {
+    let len = field.iter().position(|&byte| byte == 0).unwrap_or(field.len());
+    str::from_utf8(&field[..len]).map_err(|_| ArchiveError::BadName)
+}
+
+// An octal number, padded with spaces or NULs
+fn tar_number(field: &[u8]) -> Result<usize, ArchiveError> {
+    let mut value: usize = 0;
+    for &byte in field.iter().skip_while(|&&byte| byte == b' ') {
+        match byte {
+            b'0'..=b'7' => {
+                value = value
+                    .checked_mul(8)
+                    .and_then(|value| value.checked_add(usize::from(byte - b'0')))
+                    .ok_or(ArchiveError::BadHeader)?
+            }
+            b' ' | 0 => break,
+            _ => return Err(ArchiveError::BadHeader),
+        }
+    }
+    Ok(value)
+}
+
+// ustar: 512 byte header blocks followed by the data, padded to a block. Two
+// zeroed blocks end the archive. Hard links, devices and GNU extensions are
+// skipped.
+fn parse_tar(archive: &[u8]) -> Result<Vec<Entry>, ArchiveError> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+
+    loop {
+        let header = get(archive, offset, TAR_BLOCK_SIZE)?;
+        if header.iter().all(|&byte| byte == 0) {
+            return Ok(entries);
+        }
+        if header[257..262] != *TAR_MAGIC {
+            return Err(ArchiveError::BadHeader);
+        }
+
+        // Summed with the checksum field itself as spaces
+        let checksum = tar_number(&header[148..156])?;
+        let sum: usize = header
+            .iter()
+            .enumerate()
+            .map(|(i, &byte)| if (148..156).contains(&i) { usize::from(b' ') } else { usize::from(byte) })
+            .sum();
+        if sum != checksum {
+            return Err(ArchiveError::BadChecksum);
+        }
+
+        let mode = tar_number(&header[100..108])? as u32;
+        let size = tar_number(&header[124..136])?;
+        let data = get(archive, offset + TAR_BLOCK_SIZE, size)?;
+        offset += TAR_BLOCK_SIZE + align_up(size, TAR_BLOCK_SIZE);
+
+        let name = tar_string(&header[0..100])?;
+        let prefix = tar_string(&header[345..500])?;
+        let name = if prefix.is_empty() {
+            String::from(name)
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+
+        let kind = match header[156] {
+            b'0' | 0 => EntryKind::File(data),
+            b'5' => EntryKind::Directory,
+            b'2' => EntryKind::Symlink(tar_string(&header[157..257])?),
+            _ => continue,
+        };
+        if let Some(path) = clean_path(&name)? {
+            entries.push(Entry {
+                path,
+                mode: (mode & 0o7777) as u16,
+                kind,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{lstat, mount, read_file, readlink, stat, tmpfs::TmpFs, unmount, FileType},
+        *,
+    };
+
+    fn cpio_entry(archive: &mut Vec<u8>, name: &str, mode: u32, data: &[u8]) {
+        let fields = [0, mode, 0, 0, 1, 0, data.len() as u32, 0, 0, 0, 0, name.len() as u32 + 1, 0];
+        archive.extend_from_slice(CPIO_MAGIC);
+        for field in fields.iter() {
+            archive.extend_from_slice(format!("{:08X}", field).as_bytes());
+        }
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        archive.resize(align_up(archive.len(), 4), 0);
+        archive.extend_from_slice(data);
+        archive.resize(align_up(archive.len(), 4), 0);
+    }
+
+    fn tar_entry(archive: &mut Vec<u8>, name: &str, mode: u32, typeflag: u8, data: &[u8], link: &str) {
+        let mut header = [0; TAR_BLOCK_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..107].copy_from_slice(format!("{:07o}", mode).as_bytes());
+        header[124..135].copy_from_slice(format!("{:011o}", data.len()).as_bytes());
+        header[156] = typeflag;
+        header[157..157 + link.len()].copy_from_slice(link.as_bytes());
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+
+        let sum = header.iter().map(|&byte| usize::from(byte)).sum::<usize>() + 8 * usize::from(b' ');
+        header[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+        header[155] = b' ';
+
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(data);
+        archive.resize(align_up(archive.len(), TAR_BLOCK_SIZE), 0);
+    }
+
+    test_case!(parse_cpio_archive, {
+        let mut archive = Vec::new();
+        cpio_entry(&mut archive, ".", S_IFDIR | 0o755, b"");
+        cpio_entry(&mut archive, "bin", S_IFDIR | 0o755, b"");
+        cpio_entry(&mut archive, "bin/init", S_IFREG | 0o755, b"\x7FELF");
+        cpio_entry(&mut archive, "./sh", S_IFLNK | 0o777, b"bin/init");
+        cpio_entry(&mut archive, "dev/null", 0o020_000 | 0o666, b"");
+        cpio_entry(&mut archive, CPIO_TRAILER, 0, b"");
+
+        let entries = parse(&archive).unwrap();
+        let summary: Vec<(&str, u16, EntryKind)> =
+            entries.iter().map(|entry| (&entry.path[..], entry.mode, entry.kind)).collect();
+        assert_eq!(summary, [
+            ("bin", 0o755, EntryKind::Directory),
+            ("bin/init", 0o755, EntryKind::File(b"\x7FELF")),
+            ("sh", 0o777, EntryKind::Symlink("bin/init")),
+        ]);
+
+        assert_eq!(parse(&archive[..archive.len() - 8]), Err(ArchiveError::Truncated));
+        archive[CPIO_HEADER_SIZE + 4] = b'x';
+        assert_eq!(parse(&archive), Err(ArchiveError::BadHeader));
+        assert_eq!(parse(b"hello"), Err(ArchiveError::UnknownFormat));
+
+        let mut archive = Vec::new();
+        cpio_entry(&mut archive, "../escape", S_IFREG | 0o644, b"");
+        assert_eq!(parse(&archive), Err(ArchiveError::BadName));
+    });
+
+    test_case!(parse_tar_archive, {
+        let mut archive = Vec::new();
+        tar_entry(&mut archive, "./etc/", 0o755, b'5', b"", "");
+        tar_entry(&mut archive, "./etc/motd", 0o644, b'0', &[b'x'; 600], "");
+        tar_entry(&mut archive, "./etc/hard", 0o644, b'1', b"", "etc/motd");
+        tar_entry(&mut archive, "./motd", 0o777, b'2', b"", "etc/motd");
+        archive.resize(archive.len() + 2 * TAR_BLOCK_SIZE, 0);
+
+        let entries = parse(&archive).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].path, "etc");
+        assert_eq!(entries[1].kind, EntryKind::File(&[b'x'; 600]));
+        assert_eq!((&entries[2].path[..], entries[2].kind), ("motd", EntryKind::Symlink("etc/motd")));
+
+        archive[200] = 1;
+        assert_eq!(parse(&archive), Err(ArchiveError::BadChecksum));
+    });
+
+    test_case!(unpack_into_root, {
+        mount("/", TmpFs::new(None)).unwrap();
+
+        let mut archive = Vec::new();
+        cpio_entry(&mut archive, "usr/bin/true", S_IFREG | 0o755, b"true");
+        cpio_entry(&mut archive, "usr", S_IFDIR | 0o700, b"");
+        cpio_entry(&mut archive, "true", S_IFLNK | 0o777, b"usr/bin/true");
+        cpio_entry(&mut archive, CPIO_TRAILER, 0, b"");
+
+        assert_eq!(unpack(&archive), Ok(3));
+        assert_eq!(read_file("/true"), Ok(b"true".to_vec()));
+        assert_eq!(stat("/usr/bin/true").unwrap().mode, 0o755);
+        assert_eq!(lstat("/true").unwrap().file_type, FileType::Symlink);
+        assert_eq!(readlink("/true"), Ok(String::from("usr/bin/true")));
+
+        // Made as a parent before being listed, with the mode it's listed with
+        assert_eq!(stat("/usr").unwrap().mode, 0o700);
+        assert_eq!(stat("/usr/bin").unwrap().mode, 0o755);
+        assert_eq!(unpack(&archive), Err(ArchiveError::Fs(Errno::EEXIST)));
+
+        unmount("/").unwrap();
+    });
+
+    // What build.rs packed from initramfs/, looked up at the bottom of the
+    // tree in case a disk is mounted over it
+    test_case!(builtin_archive, {
+        let hostname = mount::root().and_then(|root| root.child("etc")?.child("hostname")).unwrap();
+        let mut buf = [0; 16];
+        let len = hostname.inode().read_at(0, &mut buf).unwrap();
+        assert_eq!(&buf[..len], b"solstice\n");
+
+        // Its pages were freed
+        let start = unsafe { VirtAddr::from_ptr(&__initramfs_start) };
+        assert!(BUILTIN_SIZE > 0);
+        assert_eq!(AddrSpace::kernel().translate_addr(start), None);
+    });
+}
@@ -0,0 +1,573 @@
+// The virtual filesystem. Filesystems implement Superblock and Inode and are
+// attached to the tree with mount, and what path lookups find in them is kept
+// in dentries. Opening an inode gives a File, which is what file descriptors
+// refer to.
+use crate::{drivers::block, syscall::Errno};
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+
+pub mod console;
+pub mod dentry;
+pub mod ext2;
+pub mod fat;
+pub mod fd;
+pub mod file;
+pub mod initramfs;
+pub mod mount;
+pub mod path;
+pub mod tmpfs;
+
+pub use file::{File, InodeFile, SeekFrom};
+pub use mount::{mount, unmount};
+pub use path::Location;
+
+pub type InodeNumber = u64;
+
+// Where mount_devices puts things
+const MNT: &str = "/mnt";
+
+// Longest name of a single path component
+pub const NAME_MAX: usize = 255;
+
+// open flags, as in Linux
+pub const O_RDONLY: u32 = 0;
+pub const O_WRONLY: u32 = 1;
+pub const O_RDWR: u32 = 2;
+pub const O_ACCMODE: u32 = 3;
+pub const O_CREAT: u32 = 0x40;
+pub const O_EXCL: u32 = 0x80;
+pub const O_TRUNC: u32 = 0x200;
+pub const O_APPEND: u32 = 0x400;
+pub const O_DIRECTORY: u32 = 0x1_0000;
+pub const O_NOFOLLOW: u32 = 0x2_0000;
+pub const O_CLOEXEC: u32 = 0x8_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    CharDevice,
+    BlockDevice,
+    Symlink,
+}
+
+impl FileType {
+    // The S_IFMT bits of st_mode
+    pub fn mode_bits(self) -> u32 {
+        match self {
+            FileType::Regular => 0o100_000,
+            FileType::Directory => 0o040_000,
+            FileType::CharDevice => 0o020_000,
+            FileType::BlockDevice => 0o060_000,
+            FileType::Symlink => 0o120_000,
+        }
+    }
+
+    // d_type in directory entries
+    pub fn dirent_type(self) -> u8 {
+        match self {
+            FileType::Regular => 8,
+            FileType::Directory => 4,
+            FileType::CharDevice => 2,
+            FileType::BlockDevice => 6,
+            FileType::Symlink => 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub ino: InodeNumber,
+    pub file_type: FileType,
+    // Permission bits
+    pub mode: u16,
+    pub nlink: u32,
+    pub size: u64,
+    pub blksize: u32,
+    pub blocks: u64,
+    // Device number, for device files
+    pub rdev: u64,
+    // Seconds since the epoch
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+}
+
+impl Metadata {
+    // Metadata with everything but the type and permissions zeroed
+    pub fn new(ino: InodeNumber, file_type: FileType, mode: u16) -> Metadata {
+        Metadata {
+            ino,
+            file_type,
+            mode,
+            nlink: 1,
+            size: 0,
+            blksize: 4096,
+            blocks: 0,
+            rdev: 0,
+            atime: 0,
+            mtime: 0,
+            ctime: 0,
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.file_type == FileType::Directory
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub ino: InodeNumber,
+    pub name: String,
+    pub file_type: FileType,
+}
+
+// A file or directory of a mounted filesystem. Directory operations fail with
+// ENOTDIR and file operations with EISDIR unless implemented.
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Metadata;
+
+    // Finds a child by name. ".." must be handled too, the root is its own
+    // parent. "." never gets here.
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, Errno> {
+        Err(Errno::ENOTDIR)
+    }
+
+    // Adds an empty file or directory, failing with EEXIST if the name is taken
+    fn create(&self, _name: &str, _file_type: FileType, _mode: u16) -> Result<Arc<dyn Inode>, Errno> {
+        Err(Errno::ENOTDIR)
+    }
+
+    // Adds a symlink pointing at target
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn Inode>, Errno> {
+        Err(Errno::ENOTDIR)
+    }
+
+    // Adds name as another link to inode, which is a file on the same
+    // filesystem. Filesystems without hard links fail with EPERM.
+    fn link(&self, _name: &str, _inode: &Arc<dyn Inode>) -> Result<(), Errno> {
+        Err(Errno::EPERM)
+    }
+
+    // Removes a child, which must not be a non-empty directory
+    fn unlink(&self, _name: &str) -> Result<(), Errno> {
+        Err(Errno::ENOTDIR)
+    }
+
+    // The entry at index, counting "." and "..", or None past the end
+    fn read_dir(&self, _index: usize) -> Result<Option<DirEntry>, Errno> {
+        Err(Errno::ENOTDIR)
+    }
+
+    // Reads at offset, returning 0 at the end of the file
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, Errno> {
+        Err(Errno::EISDIR)
+    }
+
+    // Writes at offset, growing the file as needed. As much as fits is
+    // written, failing with ENOSPC only if nothing does.
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, Errno> {
+        Err(Errno::EISDIR)
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), Errno> {
+        Err(Errno::EISDIR)
+    }
+
+    // The target of a symlink
+    fn readlink(&self) -> Result<String, Errno> {
+        Err(Errno::EINVAL)
+    }
+
+    // Devices return their own File here, everything else is opened as an
+    // InodeFile
+    fn open(&self, _flags: u32) -> Result<Option<Arc<dyn File>>, Errno> {
+        Ok(None)
+    }
+
+    // Writes back whatever is cached of the file, for fsync
+    fn sync(&self) -> Result<(), Errno> {
+        Ok(())
+    }
+}
+
+// A mounted instance of a filesystem
+pub trait Superblock: Send + Sync {
+    fn root(&self) -> Arc<dyn Inode>;
+
+    // Writes back anything cached, called on unmount
+    fn sync(&self) -> Result<(), Errno> {
+        Ok(())
+    }
+}
+
+// Mounts a tmpfs as the root and fills it from the initramfs
+pub fn init() {
+    mount("/", tmpfs::TmpFs::new(None)).expect("failed to mount the root filesystem");
+    initramfs::init();
+}
+
+// Mounts each block device with a filesystem on it. The first ext2 one,
+// preferring one that can be written to, goes over the root and hides the
+// initramfs, then the rest go at /mnt/<device> on top of it. Called once the
+// drivers have found them.
+pub fn mount_devices() {
+    let mut found = Vec::new();
+    for device in block::devices() {
+        let (fs, kind, root): (Arc<dyn Superblock>, String, Option<bool>) = match ext2::Ext2Fs::new(device.clone()) {
+            Ok(fs) if fs.read_only() => (fs, String::from("ext2, read-only"), Some(false)),
+            Ok(fs) => (fs, String::from("ext2"), Some(true)),
+            Err(_) => match fat::FatFs::new(device.clone()) {
+                Ok(fs) => {
+                    let kind = format!("{:?}", fs.fat_type());
+                    (fs, kind, None)
+                }
+                Err(_) => continue,
+            },
+        };
+        found.push((device, fs, kind, root));
+    }
+
+    let root = found
+        .iter()
+        .position(|&(_, _, _, root)| root == Some(true))
+        .or_else(|| found.iter().position(|&(_, _, _, root)| root.is_some()));
+    if let Some(index) = root {
+        let (device, fs, kind, _) = found.remove(index);
+        match mount("/", fs) {
+            Ok(()) => info!("fs: mounted {} ({}) on /", device.name(), kind),
+            Err(errno) => error!("fs: couldn't mount {}: {:?}", device.name(), errno),
+        }
+    }
+
+    let make_dir = |dir: &str| match mkdir(dir, 0o755) {
+        Err(Errno::EEXIST) => Ok(()),
+        result => result,
+    };
+    for (device, fs, kind, _) in found {
+        let path = format!("{}/{}", MNT, device.name());
+        match make_dir(MNT).and_then(|_| make_dir(&path)).and_then(|_| mount(&path, fs)) {
+            Ok(()) => info!("fs: mounted {} ({}) on {}", device.name(), kind, path),
+            Err(errno) => error!("fs: couldn't mount {}: {:?}", device.name(), errno),
+        }
+    }
+}
+
+pub fn open(path: &str, flags: u32, mode: u16) -> Result<Arc<dyn File>, Errno> {
+    let location = if flags & O_CREAT != 0 {
+        let (parent, name) = path::lookup_parent(path)?;
+        match parent.child(name) {
+            Ok(_) if flags & O_EXCL != 0 => return Err(Errno::EEXIST),
+            Ok(location) if location.metadata().file_type == FileType::Symlink => {
+                lookup_for_open(path, flags)?
+            }
+            Ok(location) => location,
+            Err(Errno::ENOENT) => parent.create(name, FileType::Regular, mode)?,
+            Err(errno) => return Err(errno),
+        }
+    } else {
+        lookup_for_open(path, flags)?
+    };
+
+    let metadata = location.metadata();
+    if flags & O_DIRECTORY != 0 && !metadata.is_dir() {
+        return Err(Errno::ENOTDIR);
+    }
+    if metadata.is_dir() && (flags & O_ACCMODE != O_RDONLY || flags & O_TRUNC != 0) {
+        return Err(Errno::EISDIR);
+    }
+
+    if let Some(file) = location.inode().open(flags)? {
+        return Ok(file);
+    }
+    if flags & O_TRUNC != 0 && flags & O_ACCMODE != O_RDONLY && metadata.file_type == FileType::Regular {
+        location.inode().truncate(0)?;
+    }
+    Ok(Arc::new(InodeFile::new(location, flags)))
+}
+
+fn lookup_for_open(path: &str, flags: u32) -> Result<Location, Errno> {
+    if flags & O_NOFOLLOW == 0 {
+        return path::lookup(path);
+    }
+
+    let location = path::lookup_nofollow(path)?;
+    if location.metadata().file_type == FileType::Symlink {
+        return Err(Errno::ELOOP);
+    }
+    Ok(location)
+}
+
+pub fn stat(path: &str) -> Result<Metadata, Errno> {
+    Ok(path::lookup(path)?.metadata())
+}
+
+// Doesn't follow a symlink in the last component
+pub fn lstat(path: &str) -> Result<Metadata, Errno> {
+    Ok(path::lookup_nofollow(path)?.metadata())
+}
+
+pub fn mkdir(path: &str, mode: u16) -> Result<(), Errno> {
+    let (parent, name) = path::lookup_parent(path)?;
+    parent.create(name, FileType::Directory, mode)?;
+    Ok(())
+}
+
+// Only removes files, like the syscall
+pub fn unlink(path: &str) -> Result<(), Errno> {
+    let (parent, name) = path::lookup_parent(path)?;
+    let child = parent.child(name)?;
+    if child.metadata().is_dir() {
+        return Err(Errno::EISDIR);
+    }
+
+    parent.unlink(name)
+}
+
+pub fn rmdir(path: &str) -> Result<(), Errno> {
+    let (parent, name) = path::lookup_parent(path)?;
+    let child = parent.child(name)?;
+    if !child.metadata().is_dir() {
+        return Err(Errno::ENOTDIR);
+    }
+    if child.mount.id() != parent.mount.id() {
+        return Err(Errno::EBUSY);
+    }
+
+    parent.unlink(name)
+}
+
+pub fn symlink(target: &str, path: &str) -> Result<(), Errno> {
+    let (parent, name) = path::lookup_parent(path)?;
+    parent.symlink(name, target)
+}
+
+// Doesn't follow a symlink at old, like link(2)
+pub fn link(old: &str, new: &str) -> Result<(), Errno> {
+    let target = path::lookup_nofollow(old)?;
+    if target.metadata().is_dir() {
+        return Err(Errno::EPERM);
+    }
+    let (parent, name) = path::lookup_parent(new)?;
+    if parent.mount.id() != target.mount.id() {
+        return Err(Errno::EXDEV);
+    }
+
+    parent.link(name, target.inode())
+}
+
+pub fn readlink(path: &str) -> Result<String, Errno> {
+    path::lookup_nofollow(path)?.inode().readlink()
+}
+
+pub fn truncate(path: &str, size: u64) -> Result<(), Errno> {
+    let location = path::lookup(path)?;
+    match location.metadata().file_type {
+        FileType::Regular => location.inode().truncate(size),
+        FileType::Directory => Err(Errno::EISDIR),
+        _ => Err(Errno::EINVAL),
+    }
+}
+
+// Reads a whole file, for things like exec
+pub fn read_file(path: &str) -> Result<Vec<u8>, Errno> {
+    let file = open(path, O_RDONLY, 0)?;
+    let mut data = Vec::new();
+    let mut chunk = [0; 4096];
+    loop {
+        let len = file.read(&mut chunk)?;
+        if len == 0 {
+            return Ok(data);
+        }
+        data.extend_from_slice(&chunk[..len]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    // A read-only filesystem built from a list of nodes, the inode number being
+    // the index. Node 0 is the root.
+    struct Node {
+        parent: InodeNumber,
+        name: &'static str,
+        // None for directories
+        data: Option<&'static [u8]>,
+    }
+
+    pub struct StaticFs {
+        nodes: Arc<Vec<Node>>,
+    }
+
+    struct StaticInode {
+        nodes: Arc<Vec<Node>>,
+        ino: InodeNumber,
+    }
+
+    impl StaticFs {
+        // /hello, /dir/nested and /dir/sub
+        pub fn sample() -> Arc<StaticFs> {
+            let dir = |parent, name| Node { parent, name, data: None };
+            let file = |parent, name, data| Node { parent, name, data: Some(data) };
+            Arc::new(StaticFs {
+                nodes: Arc::new(vec![
+                    dir(0, ""),
+                    file(0, "hello", b"hello"),
+                    dir(0, "dir"),
+                    file(2, "nested", b"nested file"),
+                    dir(2, "sub"),
+                ]),
+            })
+        }
+    }
+
+    impl Superblock for StaticFs {
+        fn root(&self) -> Arc<dyn Inode> {
+            Arc::new(StaticInode {
+                nodes: self.nodes.clone(),
+                ino: 0,
+            })
+        }
+    }
+
+    impl StaticInode {
+        fn node(&self) -> &Node {
+            &self.nodes[self.ino as usize]
+        }
+
+        fn get(&self, ino: InodeNumber) -> Arc<dyn Inode> {
+            Arc::new(StaticInode {
+                nodes: self.nodes.clone(),
+                ino,
+            })
+        }
+
+        fn children(&self) -> impl Iterator<Item = (InodeNumber, &Node)> {
+            let ino = self.ino;
+            (1..self.nodes.len() as InodeNumber)
+                .map(move |child| (child, &self.nodes[child as usize]))
+                .filter(move |(_, node)| node.parent == ino)
+        }
+
+        fn dir(&self) -> Result<(), Errno> {
+            match self.node().data {
+                None => Ok(()),
+                Some(_) => Err(Errno::ENOTDIR),
+            }
+        }
+    }
+
+    impl Inode for StaticInode {
+        fn metadata(&self) -> Metadata {
+            match self.node().data {
+                None => Metadata::new(self.ino, FileType::Directory, 0o755),
+                Some(data) => Metadata {
+                    size: data.len() as u64,
+                    ..Metadata::new(self.ino, FileType::Regular, 0o644)
+                },
+            }
+        }
+
+        fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Errno> {
+            self.dir()?;
+            if name == ".." {
+                return Ok(self.get(self.node().parent));
+            }
+
+            let (ino, _) = self.children().find(|(_, node)| node.name == name).ok_or(Errno::ENOENT)?;
+            Ok(self.get(ino))
+        }
+
+        fn create(&self, _name: &str, _file_type: FileType, _mode: u16) -> Result<Arc<dyn Inode>, Errno> {
+            self.dir()?;
+            Err(Errno::EROFS)
+        }
+
+        fn unlink(&self, _name: &str) -> Result<(), Errno> {
+            self.dir()?;
+            Err(Errno::EROFS)
+        }
+
+        fn read_dir(&self, index: usize) -> Result<Option<DirEntry>, Errno> {
+            self.dir()?;
+            let entry = |ino, name: &str| DirEntry {
+                ino,
+                name: String::from(name),
+                file_type: self.get(ino).metadata().file_type,
+            };
+
+            Ok(match index {
+                0 => Some(entry(self.ino, ".")),
+                1 => Some(entry(self.node().parent, "..")),
+                _ => self.children().nth(index - 2).map(|(ino, node)| entry(ino, node.name)),
+            })
+        }
+
+        fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+            let data = self.node().data.ok_or(Errno::EISDIR)?;
+            let start = core::cmp::min(offset, data.len() as u64) as usize;
+            let len = core::cmp::min(buf.len(), data.len() - start);
+            buf[..len].copy_from_slice(&data[start..start + len]);
+            Ok(len)
+        }
+
+        fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, Errno> {
+            self.node().data.ok_or(Errno::EISDIR)?;
+            Err(Errno::EROFS)
+        }
+    }
+
+    // Runs f with a sample filesystem mounted over the root
+    pub fn with_sample<F: FnOnce()>(f: F) {
+        mount("/", StaticFs::sample()).unwrap();
+        f();
+        unmount("/").unwrap();
+    }
+
+    test_case!(open_read_and_seek, {
+        with_sample(|| {
+            let file = open("/hello", O_RDONLY, 0).unwrap();
+            let mut buf = [0; 16];
+            assert_eq!(file.read(&mut buf), Ok(5));
+            assert_eq!(&buf[..5], b"hello");
+            assert_eq!(file.read(&mut buf), Ok(0));
+            assert_eq!(file.seek(SeekFrom::Start(1)), Ok(1));
+            assert_eq!(file.seek(SeekFrom::Current(1)), Ok(2));
+            assert_eq!(file.read(&mut buf), Ok(3));
+            assert_eq!(file.seek(SeekFrom::End(-1)), Ok(4));
+            assert_eq!(file.seek(SeekFrom::Current(-5)), Err(Errno::EINVAL));
+            assert_eq!(file.write(b"x"), Err(Errno::EBADF));
+            assert_eq!(read_file("/dir/nested"), Ok(b"nested file".to_vec()));
+        });
+    });
+
+    test_case!(open_checks_types, {
+        with_sample(|| {
+            assert_eq!(open("/missing", O_RDONLY, 0).err(), Some(Errno::ENOENT));
+            assert_eq!(open("/dir", O_WRONLY, 0).err(), Some(Errno::EISDIR));
+            assert_eq!(open("/hello", O_RDONLY | O_DIRECTORY, 0).err(), Some(Errno::ENOTDIR));
+            assert_eq!(open("/hello", O_RDONLY | O_CREAT | O_EXCL, 0o644).err(), Some(Errno::EEXIST));
+            assert_eq!(open("/new", O_RDWR | O_CREAT, 0o644).err(), Some(Errno::EROFS));
+            assert_eq!(mkdir("/hello/dir", 0o755), Err(Errno::ENOTDIR));
+            assert_eq!(unlink("/dir"), Err(Errno::EISDIR));
+            assert_eq!(unlink("/hello"), Err(Errno::EROFS));
+
+            let dir = open("/dir", O_RDONLY, 0).unwrap();
+            let mut buf = [0; 4];
+            assert_eq!(dir.read(&mut buf), Err(Errno::EISDIR));
+            let names: Vec<String> = core::iter::from_fn(|| dir.read_dir().unwrap()).map(|entry| entry.name).collect();
+            assert_eq!(names, [".", "..", "nested", "sub"]);
+        });
+    });
+
+    test_case!(stat_paths, {
+        with_sample(|| {
+            let hello = stat("/hello").unwrap();
+            assert_eq!((hello.ino, hello.file_type, hello.size), (1, FileType::Regular, 5));
+            assert!(stat("/dir/sub").unwrap().is_dir());
+            assert_eq!(stat("/dir/nested/x").err(), Some(Errno::ENOTDIR));
+        });
+    });
+}
@@ -0,0 +1,129 @@
+// The mount table. Each mount attaches the root of a superblock over a
+// directory of another mount, its mount point. The first mount on "/" becomes
+// the root of the tree, later ones stack on top of it.
+use super::{dentry::Dentry, path, InodeNumber, Location, Superblock};
+use crate::{ds::SpinLock, syscall::Errno};
+use alloc::{sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub struct Mount {
+    id: usize,
+    superblock: Arc<dyn Superblock>,
+    // Where the mount's dentries start
+    root: Arc<Dentry>,
+    // Where it's attached and that directory's inode number, None for the root
+    mountpoint: Option<(Location, InodeNumber)>,
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+lazy_static! {
+    static ref MOUNTS: SpinLock<Vec<Arc<Mount>>> = SpinLock::new(Vec::new());
+}
+
+impl Mount {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn superblock(&self) -> &Arc<dyn Superblock> {
+        &self.superblock
+    }
+
+    pub fn root(self: &Arc<Self>) -> Location {
+        Location {
+            mount: self.clone(),
+            dentry: self.root.clone(),
+        }
+    }
+
+    pub fn is_root(&self, dentry: &Arc<Dentry>) -> bool {
+        Arc::ptr_eq(dentry, &self.root)
+    }
+
+    pub fn mountpoint(&self) -> Option<&Location> {
+        self.mountpoint.as_ref().map(|(location, _)| location)
+    }
+}
+
+// The bottom of the tree, without whatever is mounted over it
+pub fn root() -> Result<Location, Errno> {
+    let mounts = MOUNTS.lock();
+    let root = mounts.iter().find(|mount| mount.mountpoint.is_none()).ok_or(Errno::ENOENT)?;
+    Ok(root.root())
+}
+
+// The mount attached to a directory, if any
+pub fn mounted_on(location: &Location) -> Option<Arc<Mount>> {
+    let ino = location.metadata().ino;
+    MOUNTS
+        .lock()
+        .iter()
+        .find(|mount| match &mount.mountpoint {
+            Some((point, point_ino)) => point.mount.id == location.mount.id && *point_ino == ino,
+            None => false,
+        })
+        .cloned()
+}
+
+pub fn mount(path: &str, superblock: Arc<dyn Superblock>) -> Result<(), Errno> {
+    let mountpoint = match path::lookup(path) {
+        Ok(location) => {
+            let metadata = location.metadata();
+            if !metadata.is_dir() {
+                return Err(Errno::ENOTDIR);
+            }
+            Some((location, metadata.ino))
+        }
+        Err(Errno::ENOENT) if path == "/" => None,
+        Err(errno) => return Err(errno),
+    };
+
+    let mount = Arc::new(Mount {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        root: Dentry::root(superblock.root()),
+        superblock,
+        mountpoint,
+    });
+    debug!("fs: mounted {} on {}", mount.id, path);
+
+    MOUNTS.lock().push(mount);
+    Ok(())
+}
+
+// Detaches the mount whose root is at path. Files that are still open keep
+// it alive until they're closed.
+pub fn unmount(path: &str) -> Result<(), Errno> {
+    let location = path::lookup(path)?;
+    if !location.mount.is_root(&location.dentry) {
+        return Err(Errno::EINVAL);
+    }
+
+    {
+        let mut mounts = MOUNTS.lock();
+        let busy = mounts.iter().any(|mount| match &mount.mountpoint {
+            Some((point, _)) => point.mount.id == location.mount.id,
+            None => false,
+        });
+        if busy {
+            return Err(Errno::EBUSY);
+        }
+        mounts.retain(|mount| mount.id != location.mount.id);
+    }
+
+    debug!("fs: unmounted {} from {}", location.mount.id, path);
+    location.mount.root.prune();
+    location.mount.superblock.sync()
+}
+
+// Writes back every mounted filesystem, so the disks are in order once the
+// tests are done
+#[cfg(test)]
+pub fn sync_all() {
+    let mounts = MOUNTS.lock().clone();
+    for mount in mounts {
+        if let Err(errno) = mount.superblock.sync() {
+            error!("fs: couldn't sync mount {}: {:?}", mount.id, errno);
+        }
+    }
+}
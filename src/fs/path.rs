@@ -0,0 +1,239 @@
+// Path lookup. There are no working directories yet, so every path is walked
+// from the root, one component at a time, through the dentry cache. "." stays
+// put and ".." goes up, leaving mounts through their mount point. Symlinks are
+// followed everywhere but in the last component, where it's up to the caller.
+use super::{
+    dentry::Dentry,
+    mount::{self, Mount},
+    FileType, Inode, Metadata, NAME_MAX,
+};
+use crate::syscall::Errno;
+use alloc::sync::Arc;
+
+// A dentry together with the mount it was reached through
+#[derive(Clone)]
+pub struct Location {
+    pub mount: Arc<Mount>,
+    pub dentry: Arc<Dentry>,
+}
+
+impl Location {
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        self.dentry.inode()
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        self.inode().metadata()
+    }
+
+    // Looks up a single path component
+    pub fn child(&self, name: &str) -> Result<Location, Errno> {
+        match name {
+            "" | "." => Ok(self.clone()),
+            _ => {
+                if name.len() > NAME_MAX {
+                    return Err(Errno::ENAMETOOLONG);
+                }
+                if !self.metadata().is_dir() {
+                    return Err(Errno::ENOTDIR);
+                }
+                if name == ".." {
+                    return Ok(self.parent());
+                }
+
+                let dentry = self.dentry.lookup(name)?;
+                Ok(Location {
+                    mount: self.mount.clone(),
+                    dentry,
+                }
+                .cross_mounts())
+            }
+        }
+    }
+
+    fn parent(&self) -> Location {
+        let mut location = self.clone();
+        loop {
+            if let Some(parent) = location.dentry.parent() {
+                return Location {
+                    mount: location.mount.clone(),
+                    dentry: parent.clone(),
+                };
+            }
+
+            location = match location.mount.mountpoint() {
+                Some(mountpoint) => mountpoint.clone(),
+                // The root is its own parent
+                None => return location.cross_mounts(),
+            };
+        }
+    }
+
+    // Directories are changed through these, which keep the dentry cache in
+    // step
+    pub fn create(&self, name: &str, file_type: FileType, mode: u16) -> Result<Location, Errno> {
+        let inode = self.inode().create(name, file_type, mode)?;
+        Ok(Location {
+            mount: self.mount.clone(),
+            dentry: self.dentry.add(name, inode),
+        })
+    }
+
+    pub fn symlink(&self, name: &str, target: &str) -> Result<(), Errno> {
+        let inode = self.inode().symlink(name, target)?;
+        self.dentry.add(name, inode);
+        Ok(())
+    }
+
+    pub fn link(&self, name: &str, inode: &Arc<dyn Inode>) -> Result<(), Errno> {
+        self.inode().link(name, inode)?;
+        self.dentry.invalidate();
+        Ok(())
+    }
+
+    pub fn unlink(&self, name: &str) -> Result<(), Errno> {
+        self.inode().unlink(name)?;
+        self.dentry.invalidate();
+        Ok(())
+    }
+
+    // Follows mounts on top of this directory
+    fn cross_mounts(mut self) -> Location {
+        while let Some(mount) = mount::mounted_on(&self) {
+            self = mount.root();
+        }
+        self
+    }
+}
+
+// Most symlinks followed in one lookup, as in Linux
+pub const SYMLINK_MAX: usize = 40;
+
+// Walks path from dir, or from the root if it's absolute. links counts the
+// symlinks followed so far.
+fn walk(dir: Location, path: &str, follow: bool, links: &mut usize) -> Result<Location, Errno> {
+    let mut location = if path.starts_with('/') {
+        mount::root()?.cross_mounts()
+    } else {
+        dir
+    };
+
+    let mut names = path.split('/').peekable();
+    while let Some(name) = names.next() {
+        let child = location.child(name)?;
+        let last = names.peek().is_none();
+        if child.metadata().file_type != FileType::Symlink || (last && !follow) {
+            location = child;
+            continue;
+        }
+
+        *links += 1;
+        if *links > SYMLINK_MAX {
+            return Err(Errno::ELOOP);
+        }
+        let target = child.inode().readlink()?;
+        if target.is_empty() {
+            return Err(Errno::ENOENT);
+        }
+
+        // Relative targets start from the directory holding the link
+        location = walk(location, &target, true, links)?;
+    }
+    Ok(location)
+}
+
+fn walk_from_root(path: &str, follow: bool) -> Result<Location, Errno> {
+    if path.is_empty() {
+        return Err(Errno::ENOENT);
+    }
+
+    let location = walk(mount::root()?.cross_mounts(), path, follow, &mut 0)?;
+    if path.ends_with('/') && !location.metadata().is_dir() {
+        return Err(Errno::ENOTDIR);
+    }
+    Ok(location)
+}
+
+pub fn lookup(path: &str) -> Result<Location, Errno> {
+    walk_from_root(path, true)
+}
+
+// Like lookup, but gives the symlink itself if the last component is one
+pub fn lookup_nofollow(path: &str) -> Result<Location, Errno> {
+    walk_from_root(path, false)
+}
+
+// Looks up the directory holding the last component of path, for creating or
+// removing it. Returns the directory and the name.
+pub fn lookup_parent(path: &str) -> Result<(Location, &str), Errno> {
+    if path.is_empty() {
+        return Err(Errno::ENOENT);
+    }
+
+    let trimmed = path.trim_end_matches('/');
+    let (dir, name) = match trimmed.rfind('/') {
+        Some(i) => (&trimmed[..i], &trimmed[i + 1..]),
+        None => ("", trimmed),
+    };
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(Errno::EEXIST);
+    }
+    if name.len() > NAME_MAX {
+        return Err(Errno::ENAMETOOLONG);
+    }
+
+    let parent = walk(mount::root()?.cross_mounts(), dir, true, &mut 0)?;
+    if !parent.metadata().is_dir() {
+        return Err(Errno::ENOTDIR);
+    }
+    Ok((parent, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{mount, tests::*, unmount};
+    use super::*;
+
+    fn ino(path: &str) -> Result<u64, Errno> {
+        lookup(path).map(|location| location.metadata().ino)
+    }
+
+    test_case!(dots_and_slashes, {
+        with_sample(|| {
+            assert_eq!(ino("/"), Ok(0));
+            assert_eq!(ino("/.."), Ok(0));
+            assert_eq!(ino("//dir/./sub/"), Ok(4));
+            assert_eq!(ino("/dir/sub/../../hello"), Ok(1));
+            assert_eq!(ino("/dir/nested/.."), Err(Errno::ENOTDIR));
+            assert_eq!(ino("/hello/"), Err(Errno::ENOTDIR));
+            assert_eq!(ino(""), Err(Errno::ENOENT));
+
+            let (parent, name) = lookup_parent("/dir/sub/new/").unwrap();
+            assert_eq!((parent.metadata().ino, name), (4, "new"));
+            assert_eq!(lookup_parent("/dir/..").err(), Some(Errno::EEXIST));
+            assert_eq!(lookup_parent("/hello/x").err(), Some(Errno::ENOTDIR));
+        });
+    });
+
+    test_case!(walks_across_mounts, {
+        with_sample(|| {
+            let below = lookup("/dir").unwrap().mount.id();
+            mount("/dir/sub", StaticFs::sample()).unwrap();
+            assert_eq!(mount("/hello", StaticFs::sample()), Err(Errno::ENOTDIR));
+
+            let hello = lookup("/dir/sub/hello").unwrap();
+            assert_eq!(hello.metadata().ino, 1);
+            assert_ne!(hello.mount.id(), below);
+
+            // Out through the mount point
+            let up = lookup("/dir/sub/..").unwrap();
+            assert_eq!((up.metadata().ino, up.mount.id()), (2, below));
+            assert_eq!(ino("/dir/sub/dir/../../nested"), Ok(3));
+
+            assert_eq!(unmount("/dir"), Err(Errno::EINVAL));
+            assert_eq!(unmount("/"), Err(Errno::EBUSY));
+            unmount("/dir/sub").unwrap();
+            assert_eq!(ino("/dir/sub/hello"), Err(Errno::ENOENT));
+        });
+    });
+}
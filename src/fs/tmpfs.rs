@@ -0,0 +1,485 @@
+// An in-memory filesystem. File data lives in whole pages from the PMM, which
+// are only allocated when written to, so holes read back as zeros without
+// taking any memory. Each mount can be limited to a number of pages.
+use super::{DirEntry, FileType, Inode, Metadata, Superblock};
+use crate::{
+    ds::SpinLock,
+    mm::{self, pmm::PhysAllocator, PAGE_SIZE},
+    syscall::{Errno, PATH_MAX},
+};
+use alloc::{
+    collections::BTreeMap,
+    string::String,
+    sync::{Arc, Weak},
+};
+use core::{
+    cmp,
+    ptr,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+use x86_64::structures::paging::PhysFrame;
+
+// Largest file size, so that positions fit in an off_t
+pub const FILE_SIZE_MAX: u64 = i64::max_value() as u64;
+
+// Shared by a mount and all of its inodes, which can outlive it while open
+struct Usage {
+    next_ino: AtomicU64,
+    pages: AtomicUsize,
+    // None for no limit
+    page_limit: Option<usize>,
+}
+
+impl Usage {
+    fn alloc_page(&self) -> Result<PhysFrame, Errno> {
+        let used = self.pages.fetch_add(1, Ordering::AcqRel);
+        if self.page_limit.map_or(false, |limit| used >= limit) {
+            self.pages.fetch_sub(1, Ordering::AcqRel);
+            return Err(Errno::ENOSPC);
+        }
+
+        match PhysAllocator::try_alloc(0) {
+            Some(range) => {
+                unsafe { ptr::write_bytes(page_ptr(range.start), 0, PAGE_SIZE as usize) };
+                Ok(range.start)
+            }
+            None => {
+                self.pages.fetch_sub(1, Ordering::AcqRel);
+                Err(Errno::ENOSPC)
+            }
+        }
+    }
+
+    fn free_page(&self, frame: PhysFrame) {
+        self.pages.fetch_sub(1, Ordering::AcqRel);
+        if mm::page_info(frame).unshare() {
+            PhysAllocator::free(PhysFrame::range(frame, frame + 1));
+        }
+    }
+}
+
+fn page_ptr(frame: PhysFrame) -> *mut u8 {
+    mm::phys_to_kernel_virt(frame.start_address()).as_mut_ptr()
+}
+
+pub struct TmpFs {
+    root: Arc<TmpInode>,
+    usage: Arc<Usage>,
+}
+
+impl TmpFs {
+    // page_limit caps the file data of the whole mount
+    pub fn new(page_limit: Option<usize>) -> Arc<TmpFs> {
+        let usage = Arc::new(Usage {
+            next_ino: AtomicU64::new(1),
+            pages: AtomicUsize::new(0),
+            page_limit,
+        });
+
+        let root = TmpInode::new(&usage, FileType::Directory, 0o755, Content::Directory(BTreeMap::new()));
+        root.inner.lock().parent = root.this.clone();
+        Arc::new(TmpFs { root, usage })
+    }
+
+    pub fn used_pages(&self) -> usize {
+        self.usage.pages.load(Ordering::Acquire)
+    }
+}
+
+impl Superblock for TmpFs {
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+enum Content {
+    // Pages by index, missing ones are holes
+    File(BTreeMap<u64, PhysFrame>),
+    Directory(BTreeMap<String, Arc<TmpInode>>),
+    Symlink(String),
+}
+
+struct InodeInner {
+    metadata: Metadata,
+    // Only set for directories. The root is its own parent.
+    parent: Weak<TmpInode>,
+    content: Content,
+}
+
+pub struct TmpInode {
+    // For handing out as a parent
+    this: Weak<TmpInode>,
+    usage: Arc<Usage>,
+    inner: SpinLock<InodeInner>,
+}
+
+impl TmpInode {
+    fn new(usage: &Arc<Usage>, file_type: FileType, mode: u16, content: Content) -> Arc<TmpInode> {
+        let ino = usage.next_ino.fetch_add(1, Ordering::Relaxed);
+        let mut metadata = Metadata::new(ino, file_type, mode);
+        if let Content::Directory(_) = content {
+            metadata.nlink = 2;
+        }
+
+        let mut inode = Arc::new(TmpInode {
+            this: Weak::new(),
+            usage: usage.clone(),
+            inner: SpinLock::new(InodeInner {
+                metadata,
+                parent: Weak::new(),
+                content,
+            }),
+        });
+        let this = Arc::downgrade(&inode);
+        Arc::get_mut(&mut inode).unwrap().this = this;
+        inode
+    }
+
+    fn add_child(&self, name: &str, child: Arc<TmpInode>) -> Result<Arc<dyn Inode>, Errno> {
+        let mut inner = self.inner.lock();
+        let is_dir = child.metadata().is_dir();
+        let children = match &mut inner.content {
+            Content::Directory(children) => children,
+            _ => return Err(Errno::ENOTDIR),
+        };
+        if children.contains_key(name) {
+            return Err(Errno::EEXIST);
+        }
+
+        children.insert(String::from(name), child.clone());
+        if is_dir {
+            inner.metadata.nlink += 1;
+        }
+        Ok(child)
+    }
+}
+
+impl Drop for TmpInode {
+    fn drop(&mut self) {
+        if let Content::File(pages) = &self.inner.lock().content {
+            for frame in pages.values() {
+                self.usage.free_page(*frame);
+            }
+        }
+    }
+}
+
+impl Inode for TmpInode {
+    fn metadata(&self) -> Metadata {
+        let inner = self.inner.lock();
+        let mut metadata = inner.metadata;
+        if let Content::File(pages) = &inner.content {
+            metadata.blocks = pages.len() as u64 * (PAGE_SIZE / 512);
+        }
+        metadata
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Errno> {
+        let inner = self.inner.lock();
+        let children = match &inner.content {
+            Content::Directory(children) => children,
+            _ => return Err(Errno::ENOTDIR),
+        };
+
+        if name == ".." {
+            // Gone if this directory was removed while in use
+            return match inner.parent.upgrade() {
+                Some(parent) => Ok(parent),
+                None => Err(Errno::ENOENT),
+            };
+        }
+        match children.get(name) {
+            Some(child) => Ok(child.clone()),
+            None => Err(Errno::ENOENT),
+        }
+    }
+
+    fn create(&self, name: &str, file_type: FileType, mode: u16) -> Result<Arc<dyn Inode>, Errno> {
+        let content = match file_type {
+            FileType::Regular => Content::File(BTreeMap::new()),
+            FileType::Directory => Content::Directory(BTreeMap::new()),
+            _ => return Err(Errno::EINVAL),
+        };
+
+        let child = TmpInode::new(&self.usage, file_type, mode, content);
+        if file_type == FileType::Directory {
+            child.inner.lock().parent = self.this.clone();
+        }
+        self.add_child(name, child)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>, Errno> {
+        if target.len() >= PATH_MAX {
+            return Err(Errno::ENAMETOOLONG);
+        }
+
+        let child = TmpInode::new(&self.usage, FileType::Symlink, 0o777, Content::Symlink(String::from(target)));
+        child.inner.lock().metadata.size = target.len() as u64;
+        self.add_child(name, child)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), Errno> {
+        let mut inner = self.inner.lock();
+        let children = match &mut inner.content {
+            Content::Directory(children) => children,
+            _ => return Err(Errno::ENOTDIR),
+        };
+
+        let child = children.get(name).ok_or(Errno::ENOENT)?.clone();
+        let mut child_inner = child.inner.lock();
+        let is_dir = match &child_inner.content {
+            Content::Directory(grandchildren) if !grandchildren.is_empty() => return Err(Errno::ENOTEMPTY),
+            Content::Directory(_) => true,
+            _ => false,
+        };
+
+        children.remove(name);
+        child_inner.metadata.nlink = 0;
+        if is_dir {
+            child_inner.parent = Weak::new();
+            inner.metadata.nlink -= 1;
+        }
+        Ok(())
+    }
+
+    fn read_dir(&self, index: usize) -> Result<Option<DirEntry>, Errno> {
+        let inner = self.inner.lock();
+        let children = match &inner.content {
+            Content::Directory(children) => children,
+            _ => return Err(Errno::ENOTDIR),
+        };
+
+        let entry = |name: &str, ino, file_type| DirEntry {
+            ino,
+            name: String::from(name),
+            file_type,
+        };
+        Ok(match index {
+            0 => Some(entry(".", inner.metadata.ino, FileType::Directory)),
+            1 => {
+                // Locks are only taken parent first, so drop this one. A
+                // removed directory lists itself as its parent.
+                let (ino, parent) = (inner.metadata.ino, inner.parent.upgrade());
+                drop(inner);
+                let parent = parent.map_or(ino, |parent| parent.metadata().ino);
+                Some(entry("..", parent, FileType::Directory))
+            }
+            _ => children.iter().nth(index - 2).map(|(name, child)| {
+                let metadata = child.metadata();
+                entry(name, metadata.ino, metadata.file_type)
+            }),
+        })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        let inner = self.inner.lock();
+        let pages = match &inner.content {
+            Content::File(pages) => pages,
+            Content::Directory(_) => return Err(Errno::EISDIR),
+            Content::Symlink(_) => return Err(Errno::EINVAL),
+        };
+
+        let size = inner.metadata.size;
+        if offset >= size {
+            return Ok(0);
+        }
+
+        let len = cmp::min(buf.len() as u64, size - offset) as usize;
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let start = (position % PAGE_SIZE) as usize;
+            let chunk = cmp::min(len - done, PAGE_SIZE as usize - start);
+            let dst = &mut buf[done..done + chunk];
+            match pages.get(&(position / PAGE_SIZE)) {
+                Some(frame) => unsafe {
+                    ptr::copy_nonoverlapping(page_ptr(*frame).add(start), dst.as_mut_ptr(), chunk);
+                },
+                None => {
+                    for byte in dst.iter_mut() {
+                        *byte = 0;
+                    }
+                }
+            }
+            done += chunk;
+        }
+
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, Errno> {
+        let mut inner = self.inner.lock();
+        let pages = match &mut inner.content {
+            Content::File(pages) => pages,
+            Content::Directory(_) => return Err(Errno::EISDIR),
+            Content::Symlink(_) => return Err(Errno::EINVAL),
+        };
+
+        match offset.checked_add(buf.len() as u64) {
+            Some(end) if end <= FILE_SIZE_MAX => {}
+            _ => return Err(Errno::EFBIG),
+        }
+
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let start = (position % PAGE_SIZE) as usize;
+            let chunk = cmp::min(buf.len() - done, PAGE_SIZE as usize - start);
+            let index = position / PAGE_SIZE;
+            let frame = match pages.get(&index) {
+                Some(frame) => *frame,
+                None => match self.usage.alloc_page() {
+                    Ok(frame) => *pages.entry(index).or_insert(frame),
+                    Err(_) if done > 0 => break,
+                    Err(errno) => return Err(errno),
+                },
+            };
+
+            unsafe { ptr::copy_nonoverlapping(buf[done..].as_ptr(), page_ptr(frame).add(start), chunk) };
+            done += chunk;
+        }
+
+        let end = offset + done as u64;
+        inner.metadata.size = cmp::max(inner.metadata.size, end);
+        Ok(done)
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), Errno> {
+        let mut inner = self.inner.lock();
+        let pages = match &mut inner.content {
+            Content::File(pages) => pages,
+            Content::Directory(_) => return Err(Errno::EISDIR),
+            Content::Symlink(_) => return Err(Errno::EINVAL),
+        };
+        if size > FILE_SIZE_MAX {
+            return Err(Errno::EFBIG);
+        }
+
+        // Drop whole pages past the end, and clear the tail of the last one
+        // so growing the file again reads zeros
+        let first_unused = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+        for (_, frame) in pages.split_off(&first_unused) {
+            self.usage.free_page(frame);
+        }
+        let start = (size % PAGE_SIZE) as usize;
+        if let (true, Some(frame)) = (start != 0, pages.get(&(size / PAGE_SIZE))) {
+            unsafe { ptr::write_bytes(page_ptr(*frame).add(start), 0, PAGE_SIZE as usize - start) };
+        }
+
+        inner.metadata.size = size;
+        Ok(())
+    }
+
+    fn readlink(&self) -> Result<String, Errno> {
+        match &self.inner.lock().content {
+            Content::Symlink(target) => Ok(target.clone()),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{
+            lstat, mkdir, mount, open, read_file, readlink, rmdir, stat, symlink, unlink, unmount, SeekFrom,
+            O_CREAT, O_RDONLY, O_RDWR,
+        },
+        *,
+    };
+    use alloc::vec::Vec;
+
+    test_case!(sparse_files, {
+        let fs = TmpFs::new(None);
+        let file = fs.root().create("file", FileType::Regular, 0o644).unwrap();
+
+        // Only the written page is allocated
+        let offset = 3 * PAGE_SIZE - 2;
+        assert_eq!(file.write_at(offset, b"abcd"), Ok(4));
+        assert_eq!(fs.used_pages(), 2);
+        let metadata = file.metadata();
+        assert_eq!((metadata.size, metadata.blocks), (offset + 4, 16));
+
+        let mut buf = [0xFF; 8];
+        assert_eq!(file.read_at(offset - 4, &mut buf), Ok(8));
+        assert_eq!(&buf, b"\0\0\0\0abcd");
+        assert_eq!(file.read_at(PAGE_SIZE, &mut buf), Ok(8));
+        assert_eq!(buf, [0; 8]);
+        assert_eq!(file.read_at(offset + 4, &mut buf), Ok(0));
+
+        // Shrinking frees pages and clears what's past the end
+        file.truncate(offset + 1).unwrap();
+        assert_eq!(fs.used_pages(), 1);
+        file.truncate(offset + 4).unwrap();
+        assert_eq!(file.read_at(offset, &mut buf), Ok(4));
+        assert_eq!(&buf[..4], b"a\0\0\0");
+        assert_eq!(file.write_at(FILE_SIZE_MAX, b"x"), Err(Errno::EFBIG));
+
+        // Pages go back when the last reference does
+        fs.root().unlink("file").unwrap();
+        assert_eq!(file.metadata().nlink, 0);
+        assert_eq!(fs.used_pages(), 1);
+        drop(file);
+        assert_eq!(fs.used_pages(), 0);
+    });
+
+    test_case!(size_limit, {
+        let fs = TmpFs::new(Some(2));
+        let file = fs.root().create("file", FileType::Regular, 0o644).unwrap();
+
+        let data = [1; PAGE_SIZE as usize];
+        assert_eq!(file.write_at(0, &data), Ok(data.len()));
+        assert_eq!(file.write_at(PAGE_SIZE + 1, &data), Ok(data.len() - 1));
+        assert_eq!(file.write_at(2 * PAGE_SIZE, &data), Err(Errno::ENOSPC));
+
+        // Holes don't count
+        file.truncate(16 * PAGE_SIZE).unwrap();
+        assert_eq!(fs.used_pages(), 2);
+        file.truncate(0).unwrap();
+        assert_eq!(file.write_at(5 * PAGE_SIZE, &data), Ok(data.len()));
+    });
+
+    test_case!(directories_and_symlinks, {
+        mount("/", TmpFs::new(None)).unwrap();
+
+        mkdir("/a", 0o755).unwrap();
+        mkdir("/a/b", 0o700).unwrap();
+        assert_eq!(mkdir("/a", 0o755), Err(Errno::EEXIST));
+        assert_eq!(stat("/a").unwrap().nlink, 3);
+        assert_eq!(stat("/a/b").unwrap().mode, 0o700);
+
+        let file = open("/a/b/file", O_RDWR | O_CREAT, 0o644).unwrap();
+        assert_eq!(file.write(b"contents"), Ok(8));
+        symlink("b/file", "/a/rel").unwrap();
+        symlink("/a/b", "/abs").unwrap();
+        symlink("/loop", "/loop").unwrap();
+
+        assert_eq!(read_file("/a/rel"), Ok(b"contents".to_vec()));
+        assert_eq!(read_file("/abs/file"), Ok(b"contents".to_vec()));
+        assert_eq!(readlink("/a/rel"), Ok(String::from("b/file")));
+        assert_eq!(readlink("/a/b/file"), Err(Errno::EINVAL));
+        assert_eq!(lstat("/abs").unwrap().file_type, FileType::Symlink);
+        assert!(stat("/abs").unwrap().is_dir());
+        assert_eq!(stat("/abs/..").unwrap().ino, stat("/a").unwrap().ino);
+        assert_eq!(stat("/loop").err(), Some(Errno::ELOOP));
+
+        let dir = open("/a", O_RDONLY, 0).unwrap();
+        let names: Vec<String> = core::iter::from_fn(|| dir.read_dir().unwrap()).map(|entry| entry.name).collect();
+        assert_eq!(names, [".", "..", "b", "rel"]);
+
+        assert_eq!(rmdir("/a/b"), Err(Errno::ENOTEMPTY));
+        assert_eq!(rmdir("/abs"), Err(Errno::ENOTDIR));
+        unlink("/a/b/file").unwrap();
+        unlink("/abs").unwrap();
+        rmdir("/a/b").unwrap();
+        assert_eq!(stat("/a").unwrap().nlink, 2);
+        assert_eq!(read_file("/a/rel"), Err(Errno::ENOENT));
+
+        // Still readable while open
+        assert_eq!(file.seek(SeekFrom::Start(0)), Ok(0));
+        let mut buf = [0; 8];
+        assert_eq!(file.read(&mut buf), Ok(8));
+
+        unmount("/").unwrap();
+    });
+}
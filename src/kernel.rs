@@ -1,10 +1,19 @@
 use crate::{
     cpu,
     drivers,
-    mm::{map::MemoryMap, pmm::PhysAllocator},
+    fs,
+    mm::{
+        self,
+        addr_space::AddrSpace,
+        map::{self, MemoryMap},
+        pmm::PhysAllocator,
+    },
+    process,
+    syscall,
+    task,
+    time,
 };
 use acpi::InterruptModel;
-use apic::{LocalApic, XApic};
 use bootloader::bootinfo::BootInfo;
 
 pub fn kernel_main(info: &BootInfo) {
@@ -22,24 +31,45 @@ pub fn kernel_main(info: &BootInfo) {
         println!();
     };
 
+    cpu::features::report();
     cpu::gdt::load();
     cpu::idt::load();
+    cpu::percpu::init();
 
+    map::save_boot_map(&info.memory_map);
     let map = MemoryMap::new(&info.memory_map);
 
     PhysAllocator::init(map);
+    AddrSpace::init_kernel_half();
+    mm::kernel_image::protect();
+    cpu::protect::init();
+    time::init();
+    task::init();
+    process::init();
+    fs::init();
+    syscall::init();
 
-    let acpi = drivers::acpi::init();
+    let mut acpi = drivers::acpi::init();
 
     match acpi.interrupt_model {
         None => panic!("unknown interrupt model"),
         Some(InterruptModel::Pic { .. }) => panic!("unsupported acpi interrupt model"),
-        Some(InterruptModel::Apic { .. }) => {
-            if !XApic::is_supported() {
+        Some(InterruptModel::Apic(ref apic)) => {
+            if !cpu::features::has_apic() {
                 error!("apic: xapic is not supported");
             } else {
                 info!("apic: detected xapic support");
+                cpu::lapic::init();
+                drivers::ioapic::init(apic);
+                cpu::irq::enable();
             }
         }
     };
+
+    drivers::keyboard::init();
+    drivers::pci::init(acpi.pci_config_regions.take());
+    drivers::virtio::init();
+    drivers::ata::init();
+    drivers::nvme::init();
+    fs::mount_devices();
 }
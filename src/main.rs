@@ -7,6 +7,7 @@
 #![feature(custom_inner_attributes)]
 #![feature(core_intrinsics)]
 #![feature(asm)]
+#![feature(global_asm)]
 #![feature(alloc_layout_extra)]
 #![feature(alloc_error_handler)]
 
@@ -24,9 +25,15 @@ mod macros;
 mod cpu;
 mod drivers;
 mod ds;
+mod elf;
+mod fs;
 mod kernel;
 mod mm;
+mod process;
+mod syscall;
+mod task;
 mod testing;
+mod time;
 
 use bootloader::BootInfo;
 
@@ -56,7 +63,7 @@ fn panic(info: &PanicInfo) -> ! {
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
-    panic!("allocation error: {:?}", layout)
+    mm::oom::out_of_memory(format_args!("heap allocation failed for {:?}", layout))
 }
 
 fn halt_loop() -> ! {
@@ -1,15 +1,20 @@
 use crate::{ds::RwSpinLock, mm::pmm::PhysAllocator};
 use x86_64::{
-    registers::control::Cr3,
+    instructions::tlb,
+    registers::control::{Cr3, Cr3Flags},
     structures::paging::{
         UnusedPhysFrame,
-        mapper::{MapToError, MapperAllSizes, MapperFlush},
-        page::Size4KiB,
+        mapper::{FlagUpdateError, MapToError, UnmapError, MapperAllSizes, MapperFlush, TranslateResult},
+        frame::PhysFrameRange,
+        page::{Size1GiB, Size2MiB, Size4KiB},
+        page_table::{PageTable, PageTableEntry},
         FrameAllocator,
         Mapper,
         OffsetPageTable,
         Page,
+        PageSize,
         PageTableFlags,
+        PhysFrame,
     },
     PhysAddr,
     VirtAddr,
@@ -17,8 +22,17 @@ use x86_64::{
 
 pub struct AddrSpace {
     table: RwSpinLock<OffsetPageTable<'static>>,
+    p4_frame: PhysFrame,
+    user: bool,
 }
 
+// The upper half of the P4 table is shared by every address space
+const KERNEL_P4_START: usize = 256;
+
+// Marks read-only user pages which are writable, but shared after a fork. The
+// first write copies the frame, unless nobody else maps it anymore.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
 unsafe impl Send for AddrSpace {}
 unsafe impl Sync for AddrSpace {}
 
@@ -31,28 +45,101 @@ lazy_static! {
             table: RwSpinLock::new(unsafe {
                 OffsetPageTable::new(&mut *table_virt.as_mut_ptr(), VirtAddr::new(super::PHYS_OFFSET))
             }),
+            p4_frame: table_frame,
+            user: false,
         }
     };
 }
 
+struct PhysAllocatorProxy;
+
+unsafe impl FrameAllocator<Size4KiB> for PhysAllocatorProxy {
+    fn allocate_frame(&mut self) -> Option<UnusedPhysFrame<Size4KiB>> {
+        PhysAllocator::try_alloc(0).map(|range| unsafe { UnusedPhysFrame::new(range.start) })
+    }
+}
+
 impl AddrSpace {
     pub fn kernel() -> &'static AddrSpace {
         &*KERNEL
     }
 
+    // Creates an empty user address space which shares the kernel's upper half
+    pub fn new_user() -> Option<AddrSpace> {
+        let frame = PhysAllocator::try_alloc(0)?.start;
+        let table = unsafe { &mut *Self::table_ptr(frame) };
+        let kernel = unsafe { &*Self::table_ptr(Self::kernel().p4_frame) };
+
+        for (i, entry) in table.iter_mut().enumerate() {
+            if i < KERNEL_P4_START {
+                entry.set_unused();
+            } else {
+                *entry = kernel[i].clone();
+            }
+        }
+
+        Some(AddrSpace {
+            table: RwSpinLock::new(unsafe {
+                OffsetPageTable::new(table, VirtAddr::new(super::PHYS_OFFSET))
+            }),
+            p4_frame: frame,
+            user: true,
+        })
+    }
+
+    // Allocates every P3 table of the kernel's upper half up front, so that
+    // kernel mappings created later show up in all user address spaces
+    pub fn init_kernel_half() {
+        let kernel = Self::kernel();
+        let _table = kernel.table.write();
+        let p4 = unsafe { &mut *Self::table_ptr(kernel.p4_frame) };
+
+        let mut count = 0;
+        for entry in p4.iter_mut().skip(KERNEL_P4_START) {
+            if entry.is_unused() {
+                let frame = PhysAllocator::alloc(0).start;
+                unsafe { (*Self::table_ptr(frame)).zero() };
+                entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+                count += 1;
+            }
+        }
+
+        debug!("addr_space: preallocated {} kernel p3 tables", count);
+    }
+
+    fn table_ptr(frame: PhysFrame) -> *mut PageTable {
+        super::phys_to_kernel_virt(frame.start_address()).as_mut_ptr()
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.p4_frame
+    }
+
+    pub unsafe fn activate(&self) {
+        if !self.is_active() {
+            Cr3::write(self.p4_frame, Cr3Flags::empty());
+        }
+    }
+
+    pub fn supports_giant_pages() -> bool {
+        crate::cpu::features::has_giant_pages()
+    }
+
+    // Without NX support the bit is reserved, so mappings go without it
+    fn supported_flags(flags: PageTableFlags) -> PageTableFlags {
+        if crate::cpu::features::has_nx() {
+            flags
+        } else {
+            flags - PageTableFlags::NO_EXECUTE
+        }
+    }
+
     pub fn map_to(
         &self,
         virt: VirtAddr,
         phys: PhysAddr,
         flags: PageTableFlags,
     ) -> Result<MapperFlush<Size4KiB>, MapToError> {
-        struct PhysAllocatorProxy;
-        unsafe impl FrameAllocator<Size4KiB> for PhysAllocatorProxy {
-            fn allocate_frame(&mut self) -> Option<UnusedPhysFrame<Size4KiB>> {
-                Some(PhysAllocator::alloc(0).start)
-            }
-        }
-
         self.map_to_with_allocator(virt, phys, flags, &mut PhysAllocatorProxy)
     }
 
@@ -69,13 +156,577 @@ impl AddrSpace {
             self.table.write().map_to(
                 Page::containing_address(virt),
                 UnusedPhysFrame::containing_address(phys),
-                flags,
+                Self::supported_flags(flags),
+                alloc,
+            )
+        }
+    }
+
+    pub fn unmap(&self, virt: VirtAddr) -> Result<(PhysFrame, MapperFlush<Size4KiB>), UnmapError> {
+        self.table.write().unmap(Page::containing_address(virt))
+    }
+
+    // Maps a single page of any size. Both addresses must be aligned to S::SIZE.
+    pub fn map_page_with_allocator<S, A>(
+        &self,
+        virt: VirtAddr,
+        phys: PhysAddr,
+        flags: PageTableFlags,
+        alloc: &mut A,
+    ) -> Result<MapperFlush<S>, MapToError>
+    where
+        S: PageSize,
+        A: FrameAllocator<Size4KiB>,
+        OffsetPageTable<'static>: Mapper<S>,
+    {
+        debug_assert!(virt.is_aligned(S::SIZE) && phys.is_aligned(S::SIZE));
+
+        unsafe {
+            self.table.write().map_to(
+                Page::<S>::containing_address(virt),
+                UnusedPhysFrame::<S>::containing_address(phys),
+                Self::supported_flags(flags),
                 alloc,
             )
         }
     }
 
+    // Maps a physically contiguous range, using the largest page size that the
+    // alignment of both addresses and the remaining length allow.
+    pub fn map_range(
+        &self,
+        virt: VirtAddr,
+        phys: PhysAddr,
+        size: usize,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError> {
+        debug_assert!(virt.is_aligned(Size4KiB::SIZE) && phys.is_aligned(Size4KiB::SIZE));
+
+        let mut offset = 0;
+        while offset < size {
+            let (v, p, remaining) = (virt + offset, phys + offset, size - offset);
+            let fits = |page_size: usize| {
+                v.is_aligned(page_size) && p.is_aligned(page_size) && remaining >= page_size
+            };
+
+            offset += if Self::supports_giant_pages() && fits(Size1GiB::SIZE) {
+                self.map_page_with_allocator::<Size1GiB, _>(v, p, flags, &mut PhysAllocatorProxy)?
+                    .flush();
+                Size1GiB::SIZE
+            } else if fits(Size2MiB::SIZE) {
+                self.map_page_with_allocator::<Size2MiB, _>(v, p, flags, &mut PhysAllocatorProxy)?
+                    .flush();
+                Size2MiB::SIZE
+            } else {
+                self.map_to(v, p, flags)?.flush();
+                Size4KiB::SIZE
+            };
+        }
+
+        Ok(())
+    }
+
+    pub fn map_frame_range(
+        &self,
+        virt: VirtAddr,
+        range: x86_64::structures::paging::frame::PhysFrameRange,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError> {
+        self.map_range(
+            virt,
+            range.start.start_address(),
+            range.len() * Size4KiB::SIZE,
+            flags,
+        )
+    }
+
+    // Changes the flags of every page in the range. Huge pages which are only
+    // partially covered by the range are split first, so that pages outside of
+    // the range keep their old protection.
+    pub fn update_flags_range(
+        &self,
+        virt: VirtAddr,
+        size: usize,
+        flags: PageTableFlags,
+    ) -> Result<(), FlagUpdateError> {
+        let flags = Self::supported_flags(flags);
+        let end = virt + size;
+        let mut addr = virt.align_down(Size4KiB::SIZE);
+
+        while addr < end {
+            let covers = |page_size: usize| addr.is_aligned(page_size) && end - addr >= page_size;
+
+            let mut table = self.table.write();
+            addr += match table.translate(addr) {
+                TranslateResult::PageNotMapped | TranslateResult::InvalidFrameAddress(_) => {
+                    return Err(FlagUpdateError::PageNotMapped)
+                }
+                TranslateResult::Frame1GiB { .. } if covers(Size1GiB::SIZE) => {
+                    Mapper::<Size1GiB>::update_flags(&mut *table, Page::containing_address(addr), flags)?
+                        .flush();
+                    Size1GiB::SIZE
+                }
+                TranslateResult::Frame2MiB { .. } if covers(Size2MiB::SIZE) => {
+                    Mapper::<Size2MiB>::update_flags(&mut *table, Page::containing_address(addr), flags)?
+                        .flush();
+                    Size2MiB::SIZE
+                }
+                TranslateResult::Frame1GiB { .. } | TranslateResult::Frame2MiB { .. } => {
+                    // Split, then go around again with the smaller pages
+                    drop(table);
+                    self.split_huge_page(addr)
+                        .map_err(|_| FlagUpdateError::ParentEntryHugePage)?;
+                    0
+                }
+                TranslateResult::Frame4KiB { .. } => {
+                    Mapper::<Size4KiB>::update_flags(&mut *table, Page::containing_address(addr), flags)?
+                        .flush();
+                    Size4KiB::SIZE
+                }
+            };
+        }
+
+        Ok(())
+    }
+
+    // Replaces the huge page containing addr with a table of pages of the next
+    // size down, preserving the mapping and its flags.
+    pub fn split_huge_page(&self, addr: VirtAddr) -> Result<(), MapToError> {
+        let _table = self.table.write();
+
+        let table_at = |entry: &PageTableEntry| unsafe {
+            &mut *super::phys_to_kernel_virt(entry.addr()).as_mut_ptr::<PageTable>()
+        };
+
+        let p4 = unsafe {
+            &mut *super::phys_to_kernel_virt(self.p4_frame.start_address()).as_mut_ptr::<PageTable>()
+        };
+
+        let p4_entry = &mut p4[addr.p4_index()];
+        if !p4_entry.flags().contains(PageTableFlags::PRESENT) {
+            return Ok(());
+        }
+
+        let p3_entry = &mut table_at(p4_entry)[addr.p3_index()];
+        let present_huge = PageTableFlags::PRESENT | PageTableFlags::HUGE_PAGE;
+        if p3_entry.flags().contains(present_huge) {
+            return Self::split_entry(p3_entry, addr.align_down(Size1GiB::SIZE), Size2MiB::SIZE, true);
+        } else if !p3_entry.flags().contains(PageTableFlags::PRESENT) {
+            return Ok(());
+        }
+
+        let p2_entry = &mut table_at(p3_entry)[addr.p2_index()];
+        if p2_entry.flags().contains(present_huge) {
+            Self::split_entry(p2_entry, addr.align_down(Size2MiB::SIZE), Size4KiB::SIZE, false)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn split_entry(
+        entry: &mut PageTableEntry,
+        start: VirtAddr,
+        sub_size: usize,
+        sub_huge: bool,
+    ) -> Result<(), MapToError> {
+        let frame = PhysAllocator::try_alloc(0).ok_or(MapToError::FrameAllocationFailed)?.start;
+        let table = unsafe {
+            &mut *super::phys_to_kernel_virt(frame.start_address()).as_mut_ptr::<PageTable>()
+        };
+
+        let flags = entry.flags();
+        let base = entry.addr();
+        let sub_flags = if sub_huge {
+            flags
+        } else {
+            flags - PageTableFlags::HUGE_PAGE
+        };
+
+        for (i, sub_entry) in table.iter_mut().enumerate() {
+            sub_entry.set_addr(base + i * sub_size, sub_flags);
+        }
+
+        // The new table inherits the access rights of the old mapping
+        entry.set_addr(
+            frame.start_address(),
+            flags - PageTableFlags::HUGE_PAGE - PageTableFlags::GLOBAL - PageTableFlags::DIRTY,
+        );
+
+        // Reloading CR3 would leave global entries behind, and the huge page
+        // may be cached in pieces, so each part of it is flushed
+        for i in 0..512 {
+            tlb::flush(start + i * sub_size);
+        }
+
+        Ok(())
+    }
+
     pub fn translate_addr(&self, addr: VirtAddr) -> Option<PhysAddr> {
         self.table.read().translate_addr(addr)
     }
+
+    // Returns the frame and flags of the page containing addr, if it's mapped
+    pub fn translate_page(&self, addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+        let table = self.table.read();
+        let (frame, offset) = match table.translate(addr) {
+            TranslateResult::Frame4KiB { frame, offset } => (frame.start_address(), offset),
+            TranslateResult::Frame2MiB { frame, offset } => (frame.start_address(), offset),
+            TranslateResult::Frame1GiB { frame, offset } => (frame.start_address(), offset),
+            _ => return None,
+        };
+        drop(table);
+
+        // translate() doesn't report flags, so walk down to the leaf entry
+        let mut table = unsafe { &*Self::table_ptr(self.p4_frame) };
+        let indices = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+        for (level, &index) in indices.iter().enumerate() {
+            let entry = &table[index];
+            if level == 3 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                return Some((frame + offset, entry.flags()));
+            }
+            table = unsafe { &*Self::table_ptr(PhysFrame::containing_address(entry.addr())) };
+        }
+
+        unreachable!()
+    }
+
+    // Maps a freshly allocated, zeroed frame at virt
+    pub fn map_zeroed(&self, virt: VirtAddr, flags: PageTableFlags) -> Result<PhysFrame, MapToError> {
+        let frame = PhysAllocator::try_alloc(0).ok_or(MapToError::FrameAllocationFailed)?.start;
+        unsafe {
+            core::ptr::write_bytes(
+                super::phys_to_kernel_virt(frame.start_address()).as_mut_ptr::<u8>(),
+                0,
+                Size4KiB::SIZE,
+            )
+        };
+
+        match self.map_to(virt, frame.start_address(), flags) {
+            Ok(flush) => {
+                flush.flush();
+                Ok(frame)
+            }
+            Err(e) => {
+                PhysAllocator::free(PhysFrame::range(frame, frame + 1));
+                Err(e)
+            }
+        }
+    }
+
+    // Duplicates the lower half into a new address space. Frames are shared
+    // rather than copied, with private writable pages turned copy-on-write in
+    // both, so only the page tables are allocated. User pages are always 4KiB.
+    pub fn fork(&self) -> Option<AddrSpace> {
+        self.fork_with_allocator(&mut PhysAllocatorProxy)
+    }
+
+    // Page tables come from alloc. If it runs out, whatever was copied so far
+    // is released again and self is left as if it had been forked, with its
+    // writable pages copy-on-write.
+    pub fn fork_with_allocator<A: FrameAllocator<Size4KiB>>(&self, alloc: &mut A) -> Option<AddrSpace> {
+        assert!(self.user, "forking the kernel address space");
+
+        let child = AddrSpace::new_user()?;
+        let table = self.table.write();
+        let src = unsafe { &mut *Self::table_ptr(self.p4_frame) };
+        let dst = unsafe { &mut *Self::table_ptr(child.p4_frame) };
+
+        let mut result = Some(());
+        for i in 0..KERNEL_P4_START {
+            if !src[i].is_unused() {
+                result = Self::fork_table(&mut src[i], &mut dst[i], 4, alloc);
+                if result.is_none() {
+                    break;
+                }
+            }
+        }
+
+        // Writable pages have just become read-only
+        if self.is_active() {
+            tlb::flush_all();
+        }
+        drop(table);
+
+        // Dropping child releases its partial copy
+        result.map(|_| child)
+    }
+
+    // level is that of the tables containing the entries, as for free_table
+    fn fork_table<A: FrameAllocator<Size4KiB>>(
+        src: &mut PageTableEntry,
+        dst: &mut PageTableEntry,
+        level: u8,
+        alloc: &mut A,
+    ) -> Option<()> {
+        let flags = src.flags();
+        assert!(
+            level == 1 || !flags.contains(PageTableFlags::HUGE_PAGE),
+            "addr_space: huge page in the user half"
+        );
+
+        if level == 1 {
+            let mut flags = flags;
+            if flags.contains(PageTableFlags::WRITABLE) {
+                flags.remove(PageTableFlags::WRITABLE);
+                flags.insert(COPY_ON_WRITE);
+                src.set_flags(flags);
+            }
+
+            super::page_info(PhysFrame::containing_address(src.addr())).share();
+            dst.set_addr(src.addr(), flags);
+            return Some(());
+        }
+
+        let frame = alloc.allocate_frame()?.frame();
+        let dst_table = unsafe { &mut *Self::table_ptr(frame) };
+        dst_table.zero();
+        dst.set_frame(frame, flags);
+
+        let src_table = unsafe { &mut *Self::table_ptr(PhysFrame::containing_address(src.addr())) };
+        for (src_entry, dst_entry) in src_table.iter_mut().zip(dst_table.iter_mut()) {
+            if !src_entry.is_unused() {
+                Self::fork_table(src_entry, dst_entry, level - 1, alloc)?;
+            }
+        }
+
+        Some(())
+    }
+
+    // Resolves a write fault on a copy-on-write page, returning false if addr
+    // isn't one or there's no memory for the copy
+    pub fn handle_cow_fault(&self, addr: VirtAddr) -> bool {
+        let _table = self.table.write();
+        let entry = match unsafe { self.leaf_entry(addr) } {
+            Some(entry) if entry.flags().contains(COPY_ON_WRITE) => entry,
+            _ => return false,
+        };
+
+        let old = PhysFrame::containing_address(entry.addr());
+        let flags = (entry.flags() - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+
+        if super::page_info(old).is_shared() {
+            let new = match PhysAllocator::try_alloc(0) {
+                Some(range) => range.start,
+                None => return false,
+            };
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    super::phys_to_kernel_virt(old.start_address()).as_ptr::<u8>(),
+                    super::phys_to_kernel_virt(new.start_address()).as_mut_ptr::<u8>(),
+                    Size4KiB::SIZE,
+                );
+            }
+            entry.set_addr(new.start_address(), flags);
+
+            // Whoever shared it may have let go in the meantime
+            if super::page_info(old).unshare() {
+                PhysAllocator::free(PhysFrame::range(old, old + 1));
+            }
+        } else {
+            entry.set_flags(flags);
+        }
+
+        tlb::flush(addr);
+        true
+    }
+
+    // The 4KiB page table entry mapping addr. The table lock must be held.
+    unsafe fn leaf_entry(&self, addr: VirtAddr) -> Option<&mut PageTableEntry> {
+        let mut table = &mut *Self::table_ptr(self.p4_frame);
+        let indices = [addr.p4_index(), addr.p3_index(), addr.p2_index()];
+        for &index in indices.iter() {
+            let entry = &table[index];
+            if entry.is_unused() || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                return None;
+            }
+            table = &mut *Self::table_ptr(PhysFrame::containing_address(entry.addr()));
+        }
+
+        let entry = &mut table[addr.p1_index()];
+        if entry.flags().contains(PageTableFlags::PRESENT) {
+            Some(entry)
+        } else {
+            None
+        }
+    }
+
+    // Frees the page tables of the lower half and every frame mapped there
+    fn free_user_half(&mut self) {
+        let p4 = unsafe { &mut *Self::table_ptr(self.p4_frame) };
+
+        for p4_entry in p4.iter_mut().take(KERNEL_P4_START) {
+            if !p4_entry.is_unused() {
+                Self::free_table(p4_entry, 4);
+            }
+        }
+    }
+
+    // level is that of the table containing entry, 4 for the P4
+    fn free_table(entry: &mut PageTableEntry, level: u8) {
+        let frame = PhysFrame::containing_address(entry.addr());
+        let huge = level <= 3 && entry.flags().contains(PageTableFlags::HUGE_PAGE);
+
+        if level == 1 || huge {
+            // A 4KiB, 2MiB or 1GiB page, unless another address space still
+            // maps it
+            let order = 9 * (level - 1);
+            if super::page_info(frame).unshare() {
+                PhysAllocator::free(PhysFrameRange {
+                    start: frame,
+                    end: frame + (1 << order),
+                });
+            }
+        } else {
+            let table = unsafe { &mut *Self::table_ptr(frame) };
+            for sub_entry in table.iter_mut().filter(|e| !e.is_unused()) {
+                Self::free_table(sub_entry, level - 1);
+            }
+            PhysAllocator::free(PhysFrame::range(frame, frame + 1));
+        }
+
+        entry.set_unused();
+    }
+}
+
+impl Drop for AddrSpace {
+    fn drop(&mut self) {
+        assert!(self.user, "dropping the kernel address space");
+        assert!(!self.is_active(), "dropping the active address space");
+
+        self.free_user_half();
+        PhysAllocator::free(PhysFrame::range(self.p4_frame, self.p4_frame + 1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Unused virtual address range, 1 GiB aligned
+    const TEST_VIRT: usize = 0xFFFF_A000_0000_0000;
+
+    test_case!(map_huge_and_split, {
+        let kernel = AddrSpace::kernel();
+        // The PMM only guarantees alignment relative to the start of a zone,
+        // so take twice as much and use the aligned half
+        let range = PhysAllocator::alloc(10);
+        let phys = range.start.start_address().align_up(Size2MiB::SIZE);
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+        let virt = VirtAddr::new(TEST_VIRT);
+        kernel.map_range(virt, phys, Size2MiB::SIZE, flags).unwrap();
+
+        match kernel.table.read().translate(virt + 0x1234) {
+            TranslateResult::Frame2MiB { frame, offset } => {
+                assert_eq!(frame.start_address(), phys);
+                assert_eq!(offset, 0x1234);
+            }
+            other => panic!("expected a 2MiB mapping, got {:?}", other),
+        }
+
+        // Make the second 4KiB page read-only, which requires a split
+        kernel
+            .update_flags_range(virt + Size4KiB::SIZE, Size4KiB::SIZE, PageTableFlags::PRESENT)
+            .unwrap();
+
+        match kernel.table.read().translate(virt + Size4KiB::SIZE) {
+            TranslateResult::Frame4KiB { frame, .. } => {
+                assert_eq!(frame.start_address(), phys + Size4KiB::SIZE)
+            }
+            other => panic!("expected a 4KiB mapping, got {:?}", other),
+        }
+        assert_eq!(kernel.translate_addr(virt + 0x1F_FFFF), Some(phys + 0x1F_FFFF));
+
+        for i in 0..512 {
+            let (frame, flush) = kernel.unmap(virt + i * Size4KiB::SIZE).unwrap();
+            flush.flush();
+            assert_eq!(frame.start_address(), phys + i * Size4KiB::SIZE);
+        }
+        assert_eq!(kernel.translate_addr(virt), None);
+        PhysAllocator::free(range);
+    });
+
+    fn user_flags() -> PageTableFlags {
+        use PageTableFlags as F;
+        F::PRESENT | F::WRITABLE | F::USER_ACCESSIBLE | F::NO_EXECUTE
+    }
+
+    // Far enough apart that each needs its own P3, P2 and P1 tables
+    const LOW_PAGE: usize = 0x40_0000;
+    const HIGH_PAGE: usize = 0x7FFF_FFFF_0000;
+
+    fn page_ptr(space: &AddrSpace, addr: usize) -> *mut u8 {
+        let (phys, _) = space.translate_page(VirtAddr::new(addr)).unwrap();
+        super::super::phys_to_kernel_virt(phys).as_mut_ptr()
+    }
+
+    // Hands out a limited number of frames
+    struct Budget(usize);
+
+    unsafe impl FrameAllocator<Size4KiB> for Budget {
+        fn allocate_frame(&mut self) -> Option<UnusedPhysFrame<Size4KiB>> {
+            if self.0 == 0 {
+                return None;
+            }
+            self.0 -= 1;
+            PhysAllocatorProxy.allocate_frame()
+        }
+    }
+
+    test_case!(fork_is_copy_on_write, {
+        let free = PhysAllocator::free_pages();
+        let parent = AddrSpace::new_user().unwrap();
+        parent.map_zeroed(VirtAddr::new(LOW_PAGE), user_flags()).unwrap();
+        unsafe { *page_ptr(&parent, LOW_PAGE) = 0x11 };
+
+        let child = parent.fork().unwrap();
+        let (parent_phys, parent_flags) = parent.translate_page(VirtAddr::new(LOW_PAGE)).unwrap();
+        let (child_phys, child_flags) = child.translate_page(VirtAddr::new(LOW_PAGE)).unwrap();
+        assert_eq!(parent_phys, child_phys);
+        assert_eq!(parent_flags, child_flags);
+        assert!(child_flags.contains(COPY_ON_WRITE));
+        assert!(!child_flags.contains(PageTableFlags::WRITABLE));
+
+        // The first writer gets a copy, the second one takes the frame back
+        assert!(child.handle_cow_fault(VirtAddr::new(LOW_PAGE)));
+        let (child_phys, child_flags) = child.translate_page(VirtAddr::new(LOW_PAGE)).unwrap();
+        assert_ne!(child_phys, parent_phys);
+        assert!(child_flags.contains(PageTableFlags::WRITABLE));
+        assert_eq!(unsafe { *page_ptr(&child, LOW_PAGE) }, 0x11);
+
+        assert!(parent.handle_cow_fault(VirtAddr::new(LOW_PAGE)));
+        let (phys, flags) = parent.translate_page(VirtAddr::new(LOW_PAGE)).unwrap();
+        assert_eq!(phys, parent_phys);
+        assert_eq!(flags, user_flags());
+
+        assert!(!parent.handle_cow_fault(VirtAddr::new(LOW_PAGE)));
+        assert!(!parent.handle_cow_fault(VirtAddr::new(HIGH_PAGE)));
+
+        drop(child);
+        drop(parent);
+        assert_eq!(PhysAllocator::free_pages(), free);
+    });
+
+    test_case!(fork_rolls_back_without_memory, {
+        let parent = AddrSpace::new_user().unwrap();
+        parent.map_zeroed(VirtAddr::new(LOW_PAGE), user_flags()).unwrap();
+        parent.map_zeroed(VirtAddr::new(HIGH_PAGE), user_flags()).unwrap();
+        unsafe { *page_ptr(&parent, HIGH_PAGE) = 0x22 };
+
+        // Both pages need three tables each
+        for budget in 0..6 {
+            let free = PhysAllocator::free_pages();
+            assert!(parent.fork_with_allocator(&mut Budget(budget)).is_none());
+            assert_eq!(PhysAllocator::free_pages(), free);
+        }
+
+        for &addr in &[LOW_PAGE, HIGH_PAGE] {
+            let (phys, _) = parent.translate_page(VirtAddr::new(addr)).unwrap();
+            assert!(!super::super::page_info(PhysFrame::containing_address(phys)).is_shared());
+        }
+        assert_eq!(unsafe { *page_ptr(&parent, HIGH_PAGE) }, 0x22);
+
+        let child = parent.fork_with_allocator(&mut Budget(6)).unwrap();
+        assert_eq!(unsafe { *page_ptr(&child, HIGH_PAGE) }, 0x22);
+    });
 }
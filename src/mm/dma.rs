@@ -0,0 +1,85 @@
+// Physically contiguous memory for devices to read and write. The CPU goes
+// through the physical memory map, which is cached, relying on DMA being
+// coherent with the caches as it is on x86.
+use super::{
+    phys_to_kernel_virt,
+    pmm::{PhysAllocator, MAX_ORDER},
+    PAGE_SIZE,
+};
+use crate::syscall::Errno;
+use core::{ptr, slice};
+use x86_64::{structures::paging::frame::PhysFrameRange, PhysAddr};
+
+pub struct DmaBuffer {
+    range: PhysFrameRange,
+    len: usize,
+}
+
+impl DmaBuffer {
+    // Allocates len bytes of zeroed memory, rounded up to a power of two pages
+    pub fn new(len: usize) -> Result<DmaBuffer, Errno> {
+        let pages = x86_64::align_up(len.max(1), PAGE_SIZE as usize) / PAGE_SIZE as usize;
+        let order = pages.next_power_of_two().trailing_zeros();
+        if u64::from(order) > MAX_ORDER {
+            return Err(Errno::ENOMEM);
+        }
+
+        let range = PhysAllocator::try_alloc(order as u8).ok_or(Errno::ENOMEM)?;
+        let buffer = DmaBuffer { range, len };
+        unsafe { ptr::write_bytes(buffer.as_mut_ptr::<u8>(0), 0, (1 << order) * PAGE_SIZE as usize) };
+        Ok(buffer)
+    }
+
+    pub fn phys(&self) -> PhysAddr {
+        self.range.start.start_address()
+    }
+
+    // All of the frames, which may go past len
+    pub fn frames(&self) -> PhysFrameRange {
+        self.range
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // A pointer to what's at offset, for structures shared with the device.
+    // Accesses to those should be volatile.
+    pub fn as_mut_ptr<T>(&self, offset: usize) -> *mut T {
+        (phys_to_kernel_virt(self.phys()) + offset).as_mut_ptr()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.as_mut_ptr(0), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.as_mut_ptr(0), self.len) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        PhysAllocator::free(self.range);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    test_case!(contiguous_and_zeroed, {
+        let mut buffer = DmaBuffer::new(3 * PAGE_SIZE as usize + 1).unwrap();
+        assert_eq!(buffer.len(), 3 * PAGE_SIZE as usize + 1);
+        assert!(buffer.as_slice().iter().all(|&byte| byte == 0));
+
+        buffer.as_mut_slice()[PAGE_SIZE as usize] = 0xAA;
+        assert_eq!(unsafe { ptr::read_volatile(buffer.as_mut_ptr::<u8>(PAGE_SIZE as usize)) }, 0xAA);
+
+        assert_eq!(DmaBuffer::new((MAX_ORDER as usize + 1) << 20).err(), Some(Errno::ENOMEM));
+    });
+}
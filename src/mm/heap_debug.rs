@@ -0,0 +1,280 @@
+// Heap debugging support, enabled with the `heap-debug` cargo feature.
+//
+// Every allocation is laid out as
+//
+//   | Header | redzone ... | user data | redzone |
+//
+// The redzones are filled with REDZONE_BYTE and checked when the allocation is
+// freed. Freed memory is filled with POISON_BYTE by the SLOB allocator and
+// checked again before it is handed out, which catches writes through dangling
+// pointers. Live allocations are kept on a linked list threaded through the
+// headers so that they can be reported (grouped by call site) at any time.
+use crate::ds::SpinLock;
+use alloc::alloc::Layout;
+use arrayvec::ArrayVec;
+use core::{
+    mem,
+    ptr::{self, NonNull},
+};
+
+pub const REDZONE_SIZE: usize = 16;
+pub const REDZONE_BYTE: u8 = 0xFD;
+pub const POISON_BYTE: u8 = 0x6B;
+
+// Number of return addresses recorded per allocation
+const BACKTRACE_DEPTH: usize = 4;
+
+// Frames belonging to GlobalAlloc::alloc and the __rust_alloc shims, which
+// would otherwise be identical for every allocation
+const BACKTRACE_SKIP: usize = 2;
+
+// Maximum number of distinct call sites shown by heap_leak_report()
+const MAX_REPORT_SITES: usize = 32;
+
+type Backtrace = [usize; BACKTRACE_DEPTH];
+
+#[repr(C)]
+struct Header {
+    size: usize,
+    align: usize,
+    backtrace: Backtrace,
+    prev: Option<NonNull<Header>>,
+    next: Option<NonNull<Header>>,
+}
+
+struct LiveList {
+    head: Option<NonNull<Header>>,
+    count: usize,
+    bytes: usize,
+}
+
+unsafe impl Send for LiveList {}
+
+static LIVE: SpinLock<LiveList> = SpinLock::new(LiveList {
+    head: None,
+    count: 0,
+    bytes: 0,
+});
+
+// Returns the padded layout along with the offset of the user data within it
+fn padded_layout(layout: Layout) -> (Layout, usize) {
+    let align = layout.align().max(mem::align_of::<Header>());
+    let offset = x86_64::align_up(mem::size_of::<Header>() + REDZONE_SIZE, align);
+    let padded = Layout::from_size_align(offset + layout.size() + REDZONE_SIZE, align)
+        .expect("heap-debug: padded layout creation failed");
+
+    (padded, offset)
+}
+
+pub unsafe fn alloc<F>(layout: Layout, inner: F) -> *mut u8
+where
+    F: FnOnce(Layout) -> *mut u8,
+{
+    let backtrace = backtrace();
+    let (padded, offset) = padded_layout(layout);
+
+    let base = inner(padded);
+    if base.is_null() {
+        return base;
+    }
+
+    let header = base as *mut Header;
+    ptr::write(
+        header,
+        Header {
+            size: layout.size(),
+            align: layout.align(),
+            backtrace,
+            prev: None,
+            next: None,
+        },
+    );
+
+    // Front redzone also covers any padding between the header and the data
+    let front = base.add(mem::size_of::<Header>());
+    ptr::write_bytes(front, REDZONE_BYTE, offset - mem::size_of::<Header>());
+    ptr::write_bytes(base.add(offset + layout.size()), REDZONE_BYTE, REDZONE_SIZE);
+
+    let mut live = LIVE.lock();
+    (*header).next = live.head;
+    if let Some(mut next) = live.head {
+        next.as_mut().prev = NonNull::new(header);
+    }
+    live.head = NonNull::new(header);
+    live.count += 1;
+    live.bytes += layout.size();
+
+    base.add(offset)
+}
+
+pub unsafe fn dealloc<F>(ptr: *mut u8, layout: Layout, inner: F)
+where
+    F: FnOnce(*mut u8, Layout),
+{
+    let (padded, offset) = padded_layout(layout);
+    let base = ptr.sub(offset);
+    let header = &mut *(base as *mut Header);
+
+    assert!(
+        header.size == layout.size() && header.align == layout.align(),
+        "heap-debug: dealloc of {:p} with layout {:?}, but it was allocated with size {} align \
+         {} from {:x?}",
+        ptr,
+        layout,
+        header.size,
+        header.align,
+        header.backtrace
+    );
+
+    let front = base.add(mem::size_of::<Header>());
+    if let Some(idx) = find_not(front, offset - mem::size_of::<Header>(), REDZONE_BYTE) {
+        panic!(
+            "heap-debug: buffer underflow {} bytes before {:p} ({} bytes allocated from {:x?})",
+            offset - mem::size_of::<Header>() - idx,
+            ptr,
+            header.size,
+            header.backtrace
+        );
+    }
+
+    if let Some(idx) = find_not(ptr.add(layout.size()), REDZONE_SIZE, REDZONE_BYTE) {
+        panic!(
+            "heap-debug: buffer overflow {} bytes past the end of {:p} ({} bytes allocated from \
+             {:x?})",
+            idx,
+            ptr,
+            header.size,
+            header.backtrace
+        );
+    }
+
+    {
+        let mut live = LIVE.lock();
+        match header.prev {
+            Some(mut prev) => prev.as_mut().next = header.next,
+            None => live.head = header.next,
+        }
+        if let Some(mut next) = header.next {
+            next.as_mut().prev = header.prev;
+        }
+        live.count -= 1;
+        live.bytes -= layout.size();
+    }
+
+    inner(base, padded);
+}
+
+// Called by the SLOB allocator for memory that is about to be handed out
+pub unsafe fn check_poison(ptr: *mut u8, len: usize) {
+    if let Some(idx) = find_not(ptr, len, POISON_BYTE) {
+        panic!(
+            "heap-debug: use after free detected, freed memory at {:p} was overwritten with {:#x}",
+            ptr.add(idx),
+            *ptr.add(idx)
+        );
+    }
+}
+
+pub unsafe fn poison(ptr: *mut u8, len: usize) {
+    ptr::write_bytes(ptr, POISON_BYTE, len);
+}
+
+unsafe fn find_not(ptr: *const u8, len: usize, byte: u8) -> Option<usize> {
+    (0..len).find(|&i| ptr::read_volatile(ptr.add(i)) != byte)
+}
+
+// Walks the frame pointer chain. The build script won't build the feature
+// without frame pointers.
+#[inline(always)]
+fn backtrace() -> Backtrace {
+    let mut out = [0; BACKTRACE_DEPTH];
+    let mut rbp: usize;
+
+    unsafe {
+        asm!("mov %rbp, $0" : "=r"(rbp) ::: "volatile");
+    }
+
+    for i in 0..BACKTRACE_SKIP + BACKTRACE_DEPTH {
+        // Stop once we walk off the kernel's stacks
+        if rbp < super::PHYS_OFFSET as usize || rbp % mem::align_of::<usize>() != 0 {
+            break;
+        }
+
+        let frame = rbp as *const usize;
+        unsafe {
+            if i >= BACKTRACE_SKIP {
+                out[i - BACKTRACE_SKIP] = *frame.offset(1);
+            }
+            rbp = *frame;
+        }
+    }
+
+    out
+}
+
+// Returns the number and total size of live allocations
+pub fn live_allocations() -> (usize, usize) {
+    let live = LIVE.lock();
+    (live.count, live.bytes)
+}
+
+struct Site {
+    backtrace: Backtrace,
+    count: usize,
+    bytes: usize,
+}
+
+// Dumps every live allocation, grouped by the call site that allocated it. This
+// can't allocate, so only the MAX_REPORT_SITES largest sites are shown.
+pub fn heap_leak_report() {
+    let mut sites: ArrayVec<[Site; MAX_REPORT_SITES]> = ArrayVec::new();
+    let mut dropped = 0;
+
+    let (count, bytes) = {
+        let live = LIVE.lock();
+        let mut curr_opt = live.head;
+        while let Some(curr) = curr_opt {
+            let header = unsafe { curr.as_ref() };
+
+            match sites.iter_mut().find(|s| s.backtrace == header.backtrace) {
+                Some(site) => {
+                    site.count += 1;
+                    site.bytes += header.size;
+                }
+                None => {
+                    let site = Site {
+                        backtrace: header.backtrace,
+                        count: 1,
+                        bytes: header.size,
+                    };
+
+                    if sites.try_push(site).is_err() {
+                        dropped += 1;
+                    }
+                }
+            }
+
+            curr_opt = header.next;
+        }
+
+        (live.count, live.bytes)
+    };
+
+    sites.sort_unstable_by(|a, b| b.bytes.cmp(&a.bytes));
+
+    info!(
+        "heap: {} live allocations ({} bytes) from {} call sites",
+        count,
+        bytes,
+        sites.len()
+    );
+    for site in &sites {
+        info!(
+            "  {:>6} bytes in {:>4} allocations from {:x?}",
+            site.bytes, site.count, site.backtrace
+        );
+    }
+    if dropped > 0 {
+        info!("  ({} allocations from other call sites not shown)", dropped);
+    }
+}
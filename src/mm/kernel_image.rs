@@ -0,0 +1,111 @@
+// Remaps the kernel image so that no page is both writable and executable
+use crate::{cpu::features, mm::addr_space::AddrSpace};
+use x86_64::{
+    registers::control::{Cr0, Cr0Flags, Efer, EferFlags},
+    structures::paging::{PageSize, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+// Exported by linker.ld
+extern "C" {
+    static __text_start: u8;
+    static __text_end: u8;
+    static __data_start: u8;
+    static __data_end: u8;
+    static __got_start: u8;
+    static __got_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __initramfs_start: u8;
+    static __initramfs_end: u8;
+    static __bss_start: u8;
+    static __bss_end: u8;
+}
+
+struct Section {
+    name: &'static str,
+    start: VirtAddr,
+    end: VirtAddr,
+    flags: PageTableFlags,
+}
+
+fn sections() -> [Section; 6] {
+    use PageTableFlags as F;
+
+    let section = |name, start: &u8, end: &u8, flags| Section {
+        name,
+        start: VirtAddr::from_ptr(start),
+        end: VirtAddr::from_ptr(end).align_up(Size4KiB::SIZE),
+        flags: flags | F::PRESENT | F::GLOBAL,
+    };
+
+    unsafe {
+        [
+            section(".text", &__text_start, &__text_end, F::empty()),
+            section(".data", &__data_start, &__data_end, F::WRITABLE | F::NO_EXECUTE),
+            section(".got", &__got_start, &__got_end, F::NO_EXECUTE),
+            section(".rodata", &__rodata_start, &__rodata_end, F::NO_EXECUTE),
+            section(".initramfs", &__initramfs_start, &__initramfs_end, F::NO_EXECUTE),
+            section(".bss", &__bss_start, &__bss_end, F::WRITABLE | F::NO_EXECUTE),
+        ]
+    }
+}
+
+pub fn protect() {
+    unsafe {
+        // The NX bit is reserved until this is set, so it must come first.
+        // Without it, AddrSpace leaves the bit out and .data stays executable.
+        if features::has_nx() {
+            Efer::update(|flags| *flags |= EferFlags::NO_EXECUTE_ENABLE);
+        } else {
+            warn!("kernel_image: no NX support, data stays executable");
+        }
+
+        // Otherwise ring 0 ignores the writable bit
+        Cr0::update(|flags| *flags |= Cr0Flags::WRITE_PROTECT);
+    }
+
+    let kernel = AddrSpace::kernel();
+    for section in sections().iter().filter(|s| s.start < s.end) {
+        kernel
+            .update_flags_range(section.start, section.end - section.start, section.flags)
+            .unwrap_or_else(|e| panic!("failed to protect kernel {}: {:?}", section.name, e));
+
+        trace!(
+            "kernel_image: {:<10} {:?}-{:?} {:?}",
+            section.name,
+            section.start,
+            section.end,
+            section.flags
+        );
+    }
+
+    debug!("kernel_image: protected");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::extable::try_write_u8;
+
+    // Non-zero, so that they don't end up in .bss
+    static RODATA: u8 = 0x5A;
+    static mut DATA: u8 = 0x5A;
+
+    test_case!(text_is_read_only, {
+        let text = protect as *const () as *mut u8;
+        assert_eq!(unsafe { try_write_u8(text, 0xCC) }, Err(()));
+    });
+
+    test_case!(rodata_is_read_only, {
+        let rodata = &RODATA as *const u8 as *mut u8;
+        assert_eq!(unsafe { try_write_u8(rodata, 1) }, Err(()));
+    });
+
+    test_case!(data_is_writable, {
+        unsafe {
+            assert_eq!(try_write_u8(&mut DATA, 0xA5), Ok(()));
+            assert_eq!(core::ptr::read_volatile(&DATA), 0xA5);
+        }
+    });
+}
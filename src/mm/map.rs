@@ -1,13 +1,25 @@
 // TODO: This should all be implemented in the bootloader, ideally
-use crate::mm::{self, addr_space::AddrSpace};
+use crate::{
+    ds::SpinLock,
+    mm::{self, addr_space::AddrSpace},
+};
 use arrayvec::ArrayVec;
 use bootloader::bootinfo::{MemoryRegion, MemoryRegionType};
 use core::{
     alloc::Layout,
+    mem,
     ptr::{self, NonNull},
 };
 use x86_64::{
-    structures::paging::{FrameAllocator, PageSize, PageTableFlags, UnusedPhysFrame, PhysFrame, Size4KiB},
+    structures::paging::{
+        FrameAllocator,
+        PageSize,
+        PageTableFlags,
+        PhysFrame,
+        Size2MiB,
+        Size4KiB,
+        UnusedPhysFrame,
+    },
     PhysAddr,
     VirtAddr,
 };
@@ -37,6 +49,53 @@ impl Region {
 // 64 is the number used in the bootloader crate
 const MAX_REGIONS: usize = 64;
 
+// Copy of the bootloader's memory map, kept around for diagnostics. This is an
+// Option because ArrayVec doesn't have a const constructor.
+static BOOT_MAP: SpinLock<Option<ArrayVec<[MemoryRegion; MAX_REGIONS]>>> = SpinLock::new(None);
+
+pub fn save_boot_map(memory_map: &[MemoryRegion]) {
+    *BOOT_MAP.lock() = Some(memory_map.iter().cloned().take(MAX_REGIONS).collect());
+}
+
+// Regions holding the boot package, such as an initramfs appended to the boot
+// image. They stay reserved until given to the PMM with
+// PhysAllocator::add_region.
+pub fn package_regions() -> ArrayVec<[Region; MAX_REGIONS]> {
+    let map = BOOT_MAP.lock();
+    map.iter()
+        .flatten()
+        .filter(|reg| reg.region_type == MemoryRegionType::Package)
+        .map(Region::from)
+        .collect()
+}
+
+impl From<&MemoryRegion> for Region {
+    fn from(reg: &MemoryRegion) -> Region {
+        Region {
+            addr: PhysAddr::new(reg.range.start_addr()),
+            size: reg.range.end_addr() - reg.range.start_addr(),
+        }
+    }
+}
+
+pub fn dump_boot_map() {
+    let map = BOOT_MAP.try_lock();
+
+    match map.as_ref().and_then(|map| map.as_ref()) {
+        Some(map) => {
+            for reg in map {
+                info!(
+                    "  [{:#012x}-{:#012x}) {:?}",
+                    reg.range.start_addr(),
+                    reg.range.end_addr(),
+                    reg.region_type
+                );
+            }
+        }
+        None => info!("  <memory map unavailable>"),
+    }
+}
+
 // TODO: Reference the memory map from bootloader crate instead
 #[derive(Debug, Clone, Default)]
 pub struct MemoryMap {
@@ -51,14 +110,14 @@ impl MemoryMap {
             num_pages: 0,
         };
 
+        // Package regions aren't allocated from, but get PageInfo entries so
+        // they can be freed later
+        let mut packages = ArrayVec::<[Region; MAX_REGIONS]>::new();
         for reg in memory_map.iter() {
-            if reg.region_type == MemoryRegionType::Usable
-                || reg.region_type == MemoryRegionType::Bootloader
-            {
-                bump.push(Region {
-                    addr: PhysAddr::new(reg.range.start_addr()),
-                    size: reg.range.end_addr() - reg.range.start_addr(),
-                });
+            match reg.region_type {
+                MemoryRegionType::Usable | MemoryRegionType::Bootloader => bump.push(Region::from(reg)),
+                MemoryRegionType::Package => packages.push(Region::from(reg)),
+                _ => {}
             }
         }
 
@@ -66,38 +125,69 @@ impl MemoryMap {
             panic!("no physical usable memory regions found");
         }
 
-        // Create PageInfo array
+        // Create PageInfo array, using 2MiB pages where possible to cut down on
+        // TLB pressure
         let kernel = AddrSpace::kernel();
-        for rg in bump.clone().regions {
+        for rg in bump.clone().regions.into_iter().chain(packages) {
             let start = PhysFrame::containing_address(rg.addr);
             let end = PhysFrame::containing_address(rg.addr + rg.size);
+            let info_start = VirtAddr::from_ptr(mm::phys_to_page_info(start));
+            let info_end =
+                VirtAddr::from_ptr(mm::phys_to_page_info(end)) + mem::size_of::<mm::PageInfo>();
+
+            let mut va = info_start.align_down(Size4KiB::SIZE);
+            while va < info_end {
+                va += bump.map_page_info(kernel, va, info_end);
+            }
+
             for page in PhysFrame::range_inclusive(start, end) {
-                let va = VirtAddr::from_ptr(mm::phys_to_page_info(page));
+                unsafe {
+                    ptr::write(
+                        mm::phys_to_page_info(page) as *mut mm::PageInfo,
+                        mm::PageInfo::default(),
+                    );
+                }
+            }
+        }
+
+        bump
+    }
+
+    // Makes sure that the part of the PageInfo array at va is mapped, returning
+    // the number of bytes that are now known to be mapped
+    fn map_page_info(&mut self, kernel: &AddrSpace, va: VirtAddr, end: VirtAddr) -> usize {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::GLOBAL;
 
-                // If this page is mapped already, just write
-                if kernel.translate_addr(va).is_some() {
-                    unsafe {
-                        ptr::write(va.as_mut_ptr(), mm::PageInfo::default());
+        if kernel.translate_addr(va).is_some() {
+            return Size4KiB::SIZE;
+        }
+
+        if va.is_aligned(Size2MiB::SIZE) && end - va >= Size2MiB::SIZE {
+            if let Some(frame) = FrameAllocator::<Size2MiB>::allocate_frame(self) {
+                let phys = frame.start_address();
+                match kernel.map_page_with_allocator::<Size2MiB, _>(va, phys, flags, self) {
+                    Ok(flush) => {
+                        flush.flush();
+                        return Size2MiB::SIZE;
                     }
-                } else {
-                    // Otherwise, allocate and map
-                    let phys_page = bump.allocate_frame().unwrap();
-                    kernel
-                        .map_to_with_allocator(
-                            va,
-                            phys_page.start_address(),
-                            PageTableFlags::PRESENT
-                                | PageTableFlags::WRITABLE
-                                | PageTableFlags::GLOBAL,
-                            &mut bump,
-                        )
-                        .expect("failed to create PageInfo array")
-                        .flush();
+                    // Part of this 2MiB range is mapped already, so give the frame
+                    // back and fall back to 4KiB pages
+                    Err(_) => self.push(Region {
+                        addr: phys,
+                        size: Size2MiB::SIZE,
+                    }),
                 }
             }
         }
 
-        bump
+        let phys_page = FrameAllocator::<Size4KiB>::allocate_frame(self)
+            .expect("out of memory while creating PageInfo array");
+        kernel
+            .map_to_with_allocator(va, phys_page.start_address(), flags, self)
+            .expect("failed to create PageInfo array")
+            .flush();
+
+        Size4KiB::SIZE
     }
 
     fn push(&mut self, rg: Region) {
@@ -106,14 +196,48 @@ impl MemoryMap {
     }
 }
 
+// Carves a 2MiB aligned frame out of the middle of a region. The frame isn't
+// cleared, unlike 4KiB frames.
+unsafe impl FrameAllocator<Size2MiB> for MemoryMap {
+    fn allocate_frame(&mut self) -> Option<UnusedPhysFrame<Size2MiB>> {
+        let idx = self.regions.iter().position(|rg| {
+            let start = rg.addr.align_up(Size2MiB::SIZE);
+            start + Size2MiB::SIZE <= rg.addr + rg.size
+        })?;
+
+        let rg = self.regions[idx];
+        let start = rg.addr.align_up(Size2MiB::SIZE);
+        let head = Region {
+            addr: rg.addr,
+            size: start - rg.addr,
+        };
+        let tail = Region {
+            addr: start + Size2MiB::SIZE,
+            size: rg.size - head.size - Size2MiB::SIZE,
+        };
+
+        // Splitting the region in two needs a free slot
+        if head.size > 0 && tail.size > 0 && self.regions.is_full() {
+            return None;
+        }
+
+        self.regions.remove(idx);
+        for part in [tail, head].iter().filter(|part| part.size > 0) {
+            self.regions.insert(idx, *part);
+        }
+        self.num_pages -= Size2MiB::SIZE / Size4KiB::SIZE;
+
+        unsafe { Some(UnusedPhysFrame::new(PhysFrame::containing_address(start))) }
+    }
+}
+
 unsafe impl FrameAllocator<Size4KiB> for MemoryMap {
     fn allocate_frame(&mut self) -> Option<UnusedPhysFrame> {
         let (idx, found_region) = self
             .regions
             .iter_mut()
             .enumerate()
-            .find(|(_, rg)| rg.size >= Size4KiB::SIZE)
-            .expect("bump allocator - out of memory");
+            .find(|(_, rg)| rg.size >= Size4KiB::SIZE)?;
 
         let out = PhysFrame::containing_address(found_region.addr);
 
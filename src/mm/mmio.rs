@@ -0,0 +1,60 @@
+// Mappings of device memory. The physical memory map is cached and may not
+// reach devices at all, so registers get their own uncached window instead.
+// Mappings are never torn down, drivers keep them for as long as the device.
+use super::addr_space::AddrSpace;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::{
+    structures::paging::{mapper::MapToError, PageSize, PageTableFlags, Size4KiB},
+    PhysAddr,
+    VirtAddr,
+};
+
+pub const MMIO_START: usize = 0xFFFF_B000_0000_0000;
+pub const MMIO_END: usize = MMIO_START + (1 << 40);
+
+static NEXT: AtomicUsize = AtomicUsize::new(MMIO_START);
+
+// Maps size bytes of registers at phys, which needn't be page aligned
+pub fn map(phys: PhysAddr, size: usize) -> Result<VirtAddr, MapToError> {
+    let start = phys.align_down(Size4KiB::SIZE);
+    let offset = phys - start;
+    let len = x86_64::align_up(offset + size, Size4KiB::SIZE);
+
+    let virt = NEXT.fetch_add(len, Ordering::Relaxed);
+    if virt + len > MMIO_END {
+        return Err(MapToError::FrameAllocationFailed);
+    }
+
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::GLOBAL
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_CACHE;
+    let kernel = AddrSpace::kernel();
+    for page in (0..len).step_by(Size4KiB::SIZE) {
+        kernel.map_to(VirtAddr::new(virt + page), start + page, flags)?.flush();
+    }
+
+    Ok(VirtAddr::new(virt + offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mm::{phys_to_kernel_virt, pmm::PhysAllocator};
+    use core::ptr;
+
+    test_case!(uncached_alias, {
+        // The alias is never unmapped, so the frame is leaked
+        let range = PhysAllocator::alloc(0);
+        let phys = range.start.start_address() + 0x10;
+        let virt = map(phys, 8).unwrap();
+        assert_eq!(virt.as_usize() & 0xFFF, 0x10);
+        assert_eq!(AddrSpace::kernel().translate_addr(virt), Some(phys));
+
+        unsafe {
+            ptr::write_volatile(virt.as_mut_ptr::<u64>(), 0x1234_5678);
+            assert_eq!(ptr::read_volatile(phys_to_kernel_virt(phys).as_ptr::<u64>()), 0x1234_5678);
+        }
+    });
+}
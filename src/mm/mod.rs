@@ -2,23 +2,67 @@ pub const PHYS_OFFSET: u64 = 0xFFFF8000_00000000;
 pub const PAGE_INFO_OFFSET: u64 = 0xFFFF9000_00000000;
 pub const PAGE_SIZE: u64 = 0x1000;
 
-use crate::ds::RwSpinLock;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use x86_64::{VirtAddr, PhysAddr};
 use x86_64::structures::paging::PhysFrame;
 
 pub mod addr_space;
+pub mod dma;
+pub mod kernel_image;
 pub mod map;
+pub mod mmio;
+pub mod oom;
 pub mod pmm;
 pub mod slob;
+pub mod uaccess;
+
+#[cfg(feature = "heap-debug")]
+pub mod heap_debug;
+#[cfg(feature = "heap-debug")]
+pub use heap_debug::heap_leak_report;
 
 #[derive(Default)]
 pub struct PageInfo {
-    _dummy: i64,
+    // Mappings of the frame besides the first, from address spaces sharing it
+    // after a fork
+    shares: AtomicUsize,
+}
+
+impl PageInfo {
+    // Records another mapping of a frame that is already mapped
+    pub fn share(&self) {
+        self.shares.fetch_add(1, Ordering::AcqRel);
+    }
+
+    // Drops a mapping, returning true if it was the last one, in which case the
+    // frame can be freed
+    pub fn unshare(&self) -> bool {
+        let mut shares = self.shares.load(Ordering::Acquire);
+        loop {
+            if shares == 0 {
+                return true;
+            }
+
+            match self.shares.compare_exchange(shares, shares - 1, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return false,
+                Err(current) => shares = current,
+            }
+        }
+    }
+
+    pub fn is_shared(&self) -> bool {
+        self.shares.load(Ordering::Acquire) != 0
+    }
+}
+
+// Only valid for frames managed by the PMM
+pub fn page_info(frame: PhysFrame) -> &'static PageInfo {
+    unsafe { &*phys_to_page_info(frame) }
 }
 
 pub fn phys_to_page_info(frame: PhysFrame) -> *const PageInfo {
     let idx = frame.start_address().as_usize() / PAGE_SIZE;
-    let out_addr = PAGE_INFO_OFFSET + idx * core::mem::size_of::<RwSpinLock<PageInfo>>();
+    let out_addr = PAGE_INFO_OFFSET + idx * core::mem::size_of::<PageInfo>();
 
     // Check that it's not too large
     debug_assert!(out_addr < PAGE_INFO_OFFSET + 0x0000100000000000);
@@ -27,10 +71,10 @@ pub fn phys_to_page_info(frame: PhysFrame) -> *const PageInfo {
 }
 
 pub fn kernel_virt_to_phys(virt: VirtAddr) -> PhysAddr {
-    debug_assert!(virt.start_address() >= PHYS_OFFSET);
-    PhysAddr::new(virt.start_address() - PHYS_OFFSET);
+    debug_assert!(virt.as_usize() >= PHYS_OFFSET as usize);
+    PhysAddr::from(virt)
 }
 
 pub fn phys_to_kernel_virt(phys: PhysAddr) -> VirtAddr {
-    VirtAddr::new(phys.as_u64())
+    VirtAddr::from(phys)
 }
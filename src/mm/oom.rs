@@ -0,0 +1,47 @@
+use crate::mm::{
+    map,
+    pmm::PhysAllocator,
+    slob::SlobAllocator,
+};
+use core::fmt;
+
+// Prints everything we know about the state of memory, then panics. All of the
+// statistics are gathered with try_lock, since the allocation that failed may
+// have been made with one of the allocator locks held.
+pub fn out_of_memory(args: fmt::Arguments) -> ! {
+    error!("out of memory: {}", args);
+    report();
+    panic!("out of memory: {}", args);
+}
+
+pub fn report() {
+    info!("memory map:");
+    map::dump_boot_map();
+
+    info!("physical memory zones:");
+    let zones = PhysAllocator::zone_stats();
+    if zones.is_empty() {
+        info!("  <zones unavailable>");
+    }
+    for zone in &zones {
+        info!(
+            "  [{:#012x}-{:#012x}) {:>8} pages, {:>8} free, largest free order {:?}",
+            zone.pages.start.start_address().as_usize(),
+            zone.pages.end.start_address().as_usize(),
+            zone.num_pages,
+            zone.free_pages,
+            zone.largest_free_order
+        );
+    }
+
+    match SlobAllocator::stats() {
+        Some(stats) => info!(
+            "heap: {} bytes claimed, {} bytes free in {} blocks (largest {} bytes)",
+            stats.claimed_bytes, stats.free_bytes, stats.free_blocks, stats.largest_free_block
+        ),
+        None => info!("heap: <locked>"),
+    }
+
+    #[cfg(feature = "heap-debug")]
+    super::heap_leak_report();
+}
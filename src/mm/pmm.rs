@@ -1,14 +1,22 @@
 use crate::{
     ds::{RwSpinLock, SpinLock},
     mm::{
+        addr_space::AddrSpace,
         map::{MemoryMap, Region, RegionBumpAllocator},
         PageInfo,
     },
 };
 use arrayvec::ArrayVec;
-use core::{alloc::Layout, mem, num::NonZeroU8, slice};
+use core::{alloc::Layout, cmp, mem, num::NonZeroU8, ptr, slice};
 use x86_64::{
-    structures::paging::frame::{PhysFrame, PhysFrameRange},
+    instructions::interrupts,
+    structures::paging::{
+        frame::{PhysFrame, PhysFrameRange},
+        mapper::MapToError,
+        PageSize,
+        PageTableFlags,
+        Size4KiB,
+    },
     PhysAddr,
     VirtAddr,
 };
@@ -21,9 +29,18 @@ pub const MAX_ORDER_PAGES: u64 = 1 << 11;
 struct Zone {
     pages: PhysFrameRange,
     num_pages: u64,
+    free_pages: u64,
     order_list: [&'static mut [Block]; MAX_ORDER as usize + 1],
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct ZoneStats {
+    pub pages: PhysFrameRange,
+    pub num_pages: u64,
+    pub free_pages: u64,
+    pub largest_free_order: Option<u8>,
+}
+
 impl Zone {
     pub fn new(addr: PhysAddr, size: usize, blocks: &'static mut [Block]) -> Self {
         let num_pages = (size / super::PAGE_SIZE as usize) as u64;
@@ -51,6 +68,7 @@ impl Zone {
         Zone {
             pages: PhysFrame::range(start_frame, end_frame),
             num_pages,
+            free_pages: num_pages,
             order_list,
         }
     }
@@ -110,6 +128,7 @@ impl Zone {
 
         self.order_list[order as usize][idx as usize] = Block::Used;
         self.update_tree(order, idx);
+        self.free_pages -= 2u64.pow(order as u32);
 
         let start_frame = self.pages.start + 2u64.pow(order as u32) * idx;
         let end_frame = self.pages.start + 2u64.pow(order as u32) * (idx + 1);
@@ -137,6 +156,24 @@ impl Zone {
 
         self.order_list[order as usize][idx as usize] = Block::from_order(order as u8);
         self.update_tree(order as u8, idx);
+        self.free_pages += range.len() as u64;
+    }
+
+    fn stats(&self) -> ZoneStats {
+        let largest_free_order = self.order_list[MAX_ORDER as usize]
+            .iter()
+            .filter_map(|blk| match blk {
+                Block::LargestFreeOrder(o) => Some(o.get() - 1),
+                Block::Used => None,
+            })
+            .max();
+
+        ZoneStats {
+            pages: self.pages,
+            num_pages: self.num_pages,
+            free_pages: self.free_pages,
+            largest_free_order,
+        }
     }
 }
 
@@ -236,54 +273,90 @@ impl PhysAllocator {
     }
 
     pub fn init(map: MemoryMap) {
-        let mut zones = ArrayVec::new();
-
+        *PMM.zones.write() = Some(ArrayVec::new());
         for rg in map {
-            let pages_in_rg = rg.size as u64 / super::PAGE_SIZE;
-            let usable_pages = usable_pages(pages_in_rg);
-            if usable_pages <= 1 {
-                continue;
-            }
+            Self::add_region(rg);
+        }
+
+        debug!("pmm: initialised");
+    }
 
-            let (reserved, usable) = rg.split_at(((pages_in_rg - usable_pages) * super::PAGE_SIZE) as usize);
-            let zone = Zone::new(
-                usable.addr.into(),
-                x86_64::align_down(usable.size as u64, super::PAGE_SIZE) as usize,
-                Block::new_blocks_for_region(reserved, usable_pages),
-            );
+    // Hands a region over to the allocator, mapping PageInfo entries for it if
+    // they aren't already
+    pub fn add_region(rg: Region) {
+        if let Err(err) = map_page_info(rg) {
+            warn!("pmm: can't map page info for {:?} ({:?}), leaking it", rg, err);
+            return;
+        }
 
-            zones.push(SpinLock::new(zone));
+        if let Some(zone) = Self::new_zone(rg) {
+            let full = interrupts::without_interrupts(|| {
+                let mut zones = PMM.zones.write();
+                zones.as_mut().unwrap().try_push(SpinLock::new(zone)).is_err()
+            });
+            if full {
+                warn!("pmm: no zone left for {:?}, leaking it", rg);
+            }
+        }
+    }
 
-            assert_eq!(usable.addr.as_u64() & (super::PAGE_SIZE - 1), 0); // Make sure it's aligned
+    fn new_zone(rg: Region) -> Option<Zone> {
+        let pages_in_rg = rg.size as u64 / super::PAGE_SIZE;
+        let usable_pages = usable_pages(pages_in_rg);
+        if usable_pages <= 1 {
+            return None;
         }
 
-        *PMM.zones.write() = Some(zones);
-        debug!("pmm: initialised");
+        let (reserved, usable) = rg.split_at(((pages_in_rg - usable_pages) * super::PAGE_SIZE) as usize);
+        assert_eq!(usable.addr.as_u64() & (super::PAGE_SIZE - 1), 0); // Make sure it's aligned
+
+        Some(Zone::new(
+            usable.addr.into(),
+            x86_64::align_down(usable.size as u64, super::PAGE_SIZE) as usize,
+            Block::new_blocks_for_region(reserved, usable_pages),
+        ))
     }
 
     pub fn alloc(order: u8) -> PhysFrameRange {
+        match Self::try_alloc(order) {
+            Some(range) => range,
+            None => super::oom::out_of_memory(format_args!(
+                "physical memory allocator failed to fulfill order {} alloc",
+                order
+            )),
+        }
+    }
+
+    // The heap allocates from here, interrupt handlers included, so zones are
+    // only locked with interrupts off
+    pub fn try_alloc(order: u8) -> Option<PhysFrameRange> {
         debug_assert!(order <= MAX_ORDER as u8);
 
-        for zone in PMM.zones.read().as_ref().unwrap() {
-            let mut zone = zone.lock();
-            if let Some(range) = zone.alloc(order) {
-                return range;
+        interrupts::without_interrupts(|| {
+            for zone in PMM.zones.read().as_ref().unwrap() {
+                let mut zone = zone.lock();
+                if let Some(range) = zone.alloc(order) {
+                    return Some(range);
+                }
             }
-        }
 
-        panic!(
-            "physical memory allocator: out of memory (failed to fulfill order {} alloc)",
-            order
-        );
+            None
+        })
     }
 
     pub fn free(range: PhysFrameRange) {
-        for zone in PMM.zones.read().as_ref().unwrap() {
-            let mut zone = zone.lock();
-            if zone.pages.contains_range(range) {
-                zone.free(range);
-                return;
+        let freed = interrupts::without_interrupts(|| {
+            for zone in PMM.zones.read().as_ref().unwrap() {
+                let mut zone = zone.lock();
+                if zone.pages.contains_range(range) {
+                    zone.free(range);
+                    return true;
+                }
             }
+            false
+        });
+        if freed {
+            return;
         }
 
         panic!(
@@ -291,6 +364,67 @@ impl PhysAllocator {
             range
         );
     }
+
+    pub fn zone_stats() -> ArrayVec<[ZoneStats; MAX_ZONES as usize]> {
+        let mut out = ArrayVec::new();
+
+        // Use try_lock, since this is called when reporting out of memory errors
+        if let Some(zones) = PMM.zones.try_read() {
+            for zone in zones.iter().flatten() {
+                if let Some(zone) = zone.try_lock() {
+                    out.push(zone.stats());
+                }
+            }
+        }
+
+        out
+    }
+
+    pub fn free_pages() -> u64 {
+        PMM.zones
+            .read()
+            .iter()
+            .flatten()
+            .map(|zone| zone.lock().free_pages)
+            .sum()
+    }
+}
+
+// Maps the part of the PageInfo array covering a region that isn't mapped yet
+// and resets the region's entries. Memory for it comes from the allocator in
+// the largest blocks that fit, so that they can be mapped with huge pages.
+fn map_page_info(rg: Region) -> Result<(), MapToError> {
+    let first = PhysFrame::containing_address(rg.addr);
+    let last = PhysFrame::containing_address(rg.addr + (rg.size - 1));
+    let info_end = VirtAddr::from_ptr(super::phys_to_page_info(last)) + mem::size_of::<PageInfo>();
+    let end = info_end.align_up(Size4KiB::SIZE);
+
+    let kernel = AddrSpace::kernel();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::GLOBAL;
+    let mut va = VirtAddr::from_ptr(super::phys_to_page_info(first)).align_down(Size4KiB::SIZE);
+    while va < end {
+        if kernel.translate_addr(va).is_some() {
+            va += Size4KiB::SIZE;
+            continue;
+        }
+
+        let mut unmapped_end = va + Size4KiB::SIZE;
+        while unmapped_end < end && kernel.translate_addr(unmapped_end).is_none() {
+            unmapped_end += Size4KiB::SIZE;
+        }
+        while va < unmapped_end {
+            let pages = (unmapped_end - va) / Size4KiB::SIZE;
+            let order = cmp::min(63 - pages.leading_zeros() as u64, MAX_ORDER) as u8;
+            let range = PhysAllocator::try_alloc(order).ok_or(MapToError::FrameAllocationFailed)?;
+            kernel.map_frame_range(va, range, flags)?;
+            va += range.len() * Size4KiB::SIZE;
+        }
+    }
+
+    for frame in PhysFrame::range_inclusive(first, last) {
+        unsafe { ptr::write(super::phys_to_page_info(frame) as *mut PageInfo, PageInfo::default()) };
+    }
+    Ok(())
 }
 
 // Each page of memory has a constant memory overhead of size_of::<PageInfo>(),
@@ -309,9 +443,9 @@ impl PhysAllocator {
 // TODO: should really be blocks_in_region(usable_pages), but this hugely
 // complicates the math
 fn usable_pages(total_pages: u64) -> u64 {
-    (4096 * total_pages - blocks_in_region(total_pages))
-        / (mem::size_of::<PageInfo>() as u64 + 4096)
-        - 2
+    ((4096 * total_pages - blocks_in_region(total_pages))
+        / (mem::size_of::<PageInfo>() as u64 + 4096))
+        .saturating_sub(2)
 }
 
 fn blocks_in_region(pages: u64) -> u64 {
@@ -336,4 +470,15 @@ mod tests {
         let block = &b as *const u8 as *const Block;
         assert_eq!(unsafe { *block }, Block::Used);
     });
+
+    test_case!(free_page_accounting, {
+        let before = PhysAllocator::free_pages();
+
+        let range = PhysAllocator::try_alloc(2).expect("order 2 alloc failed");
+        assert_eq!(range.len(), 4);
+        assert_eq!(PhysAllocator::free_pages(), before - 4);
+
+        PhysAllocator::free(range);
+        assert_eq!(PhysAllocator::free_pages(), before);
+    });
 }
@@ -1,7 +1,12 @@
+#[cfg(feature = "heap-debug")]
+use super::heap_debug;
 use crate::{ds::SpinLock, mm::pmm::PhysAllocator};
 use alloc::alloc::{GlobalAlloc, Layout};
-use core::ptr::NonNull;
-use x86_64::VirtAddr;
+use core::{
+    ptr::NonNull,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use x86_64::{instructions::interrupts, VirtAddr};
 
 // TODO: Use iterators. Could do with a general cleanup
 
@@ -21,11 +26,44 @@ unsafe impl Send for Block {}
 #[global_allocator]
 static HEAP: SlobAllocator = SlobAllocator::new();
 
+// Number of pages claimed from the PMM by morecore()
+static CLAIMED_PAGES: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeapStats {
+    pub claimed_bytes: u64,
+    pub free_bytes: usize,
+    pub free_blocks: usize,
+    pub largest_free_block: usize,
+}
+
 impl SlobAllocator {
     const fn new() -> Self {
         Self(SpinLock::new(None))
     }
 
+    // Returns None if the heap is currently locked
+    pub fn stats() -> Option<HeapStats> {
+        let list = HEAP.0.try_lock()?;
+        let mut stats = HeapStats {
+            claimed_bytes: CLAIMED_PAGES.load(Ordering::Relaxed) * super::PAGE_SIZE,
+            ..HeapStats::default()
+        };
+
+        let mut curr_opt = *list;
+        while let Some(curr) = curr_opt {
+            unsafe {
+                let size = curr.as_ref().size;
+                stats.free_bytes += size;
+                stats.free_blocks += 1;
+                stats.largest_free_block = stats.largest_free_block.max(size);
+                curr_opt = curr.as_ref().next;
+            }
+        }
+
+        Some(stats)
+    }
+
     #[allow(unused)]
     pub fn debug() {
         let list = HEAP.0.lock();
@@ -52,13 +90,30 @@ impl SlobAllocator {
     }
 }
 
+// Interrupt handlers allocate too, so the heap is only locked with them off
 unsafe impl GlobalAlloc for SlobAllocator {
+    #[cfg(not(feature = "heap-debug"))]
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        alloc_inner(&mut *self.0.lock(), layout)
+        interrupts::without_interrupts(|| alloc_inner(&mut *self.0.lock(), layout))
     }
 
+    #[cfg(not(feature = "heap-debug"))]
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        dealloc_inner(&mut *self.0.lock(), ptr, layout);
+        interrupts::without_interrupts(|| dealloc_inner(&mut *self.0.lock(), ptr, layout));
+    }
+
+    #[cfg(feature = "heap-debug")]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        interrupts::without_interrupts(|| heap_debug::alloc(layout, |l| alloc_inner(&mut *self.0.lock(), l)))
+    }
+
+    #[cfg(feature = "heap-debug")]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        interrupts::without_interrupts(|| {
+            heap_debug::dealloc(ptr, layout, |p, l| {
+                dealloc_inner(&mut *self.0.lock(), p, l)
+            })
+        });
     }
 }
 
@@ -91,6 +146,9 @@ unsafe fn alloc_inner(head: &mut Option<NonNull<Block>>, layout: Layout) -> *mut
                     None => *head = curr.as_mut().next,
                 }
 
+                #[cfg(feature = "heap-debug")]
+                heap_debug::check_poison(Block::allocation(curr), alloc_len);
+
                 return Block::allocation(curr);
             } else if curr.as_mut().size > layout.size() {
                 let (left, right) = Block::split_at(curr, alloc_len);
@@ -99,6 +157,9 @@ unsafe fn alloc_inner(head: &mut Option<NonNull<Block>>, layout: Layout) -> *mut
                     None => *head = Some(right),
                 }
 
+                #[cfg(feature = "heap-debug")]
+                heap_debug::check_poison(Block::allocation(left), alloc_len);
+
                 return Block::allocation(left);
             }
 
@@ -106,7 +167,9 @@ unsafe fn alloc_inner(head: &mut Option<NonNull<Block>>, layout: Layout) -> *mut
             curr_opt = curr.as_mut().next;
         }
 
-        morecore(head, (layout.size() as u64 + super::PAGE_SIZE) / super::PAGE_SIZE);
+        if !morecore(head, (layout.size() as u64 + super::PAGE_SIZE) / super::PAGE_SIZE) {
+            return core::ptr::null_mut();
+        }
     }
 
     unreachable!();
@@ -114,6 +177,10 @@ unsafe fn alloc_inner(head: &mut Option<NonNull<Block>>, layout: Layout) -> *mut
 
 unsafe fn dealloc_inner(head: &mut Option<NonNull<Block>>, ptr: *mut u8, layout: Layout) {
     let mut block = Block::from_allocation(ptr);
+
+    #[cfg(feature = "heap-debug")]
+    heap_debug::poison(ptr, block.as_mut().size);
+
     let mut prev: Option<NonNull<Block>> = None;
     let mut curr_opt = *head;
     while let Some(mut curr) = curr_opt {
@@ -152,11 +219,18 @@ unsafe fn dealloc_inner(head: &mut Option<NonNull<Block>>, ptr: *mut u8, layout:
     }
 }
 
-fn morecore(head: &mut Option<NonNull<Block>>, num_pages: u64) {
+// Returns false if the PMM is out of memory
+fn morecore(head: &mut Option<NonNull<Block>>, num_pages: u64) -> bool {
+    let order = num_pages.next_power_of_two().trailing_zeros() as u8;
+    let range = match PhysAllocator::try_alloc(order) {
+        Some(range) => range,
+        None => return false,
+    };
+
+    CLAIMED_PAGES.fetch_add(1 << order, Ordering::Relaxed);
+
     unsafe {
-        let addr =
-            super::phys_to_kernel_virt(PhysAllocator::alloc(num_pages.next_power_of_two().trailing_zeros() as u8)
-                .start.start_address());
+        let addr = super::phys_to_kernel_virt(range.start.start_address());
         let p_block = addr.as_mut_ptr::<Block>();
         let size = (num_pages * super::PAGE_SIZE) as usize - core::mem::size_of::<Block>();
         (*p_block).size = size;
@@ -169,6 +243,8 @@ fn morecore(head: &mut Option<NonNull<Block>>, num_pages: u64) {
             Layout::from_size_align(size, 1).unwrap(),
         );
     }
+
+    true
 }
 
 impl Block {
@@ -206,6 +282,11 @@ impl Block {
             // Merge
             left.as_mut().size += right.as_mut().size + core::mem::size_of::<Block>();
             left.as_mut().next = right.as_mut().next;
+
+            // The right header is now part of the left block's free space
+            #[cfg(feature = "heap-debug")]
+            heap_debug::poison(right.as_ptr() as *mut u8, core::mem::size_of::<Block>());
+
             // TODO: Occasionally walk the list and free a physical page?
             true
         } else {
@@ -239,4 +320,33 @@ mod tests {
             *x += 2;
         }
     });
+
+    #[cfg(feature = "heap-debug")]
+    test_case!(live_tracking, {
+        use alloc::boxed::Box;
+        let (count, bytes) = heap_debug::live_allocations();
+
+        let x = Box::new([0u8; 100]);
+        assert_eq!(heap_debug::live_allocations(), (count + 1, bytes + 100));
+
+        drop(x);
+        assert_eq!(heap_debug::live_allocations(), (count, bytes));
+    });
+
+    #[cfg(feature = "heap-debug")]
+    test_case!(poison_on_free, {
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        unsafe {
+            let ptr = alloc::alloc::alloc(layout);
+            core::ptr::write_bytes(ptr, 0, 64);
+            alloc::alloc::dealloc(ptr, layout);
+
+            for i in 0..64 {
+                assert_eq!(
+                    core::ptr::read_volatile(ptr.add(i)),
+                    heap_debug::POISON_BYTE
+                );
+            }
+        }
+    });
 }
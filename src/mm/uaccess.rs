@@ -0,0 +1,225 @@
+// Helpers for accessing user memory from the kernel. Addresses are checked
+// against the user half of the address space, and any fault while copying is
+// recovered through the exception table (see cpu::extable) and reported as an
+// error instead of a kernel panic.
+use crate::cpu::protect;
+use core::mem;
+use x86_64::{
+    instructions::smap::{clac, stac},
+    VirtAddr,
+};
+
+// End of the lower (user) half of the address space, exclusive
+pub const USER_END: usize = 0x0000_8000_0000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault;
+
+pub fn access_ok(addr: VirtAddr, len: usize) -> bool {
+    match addr.as_usize().checked_add(len) {
+        Some(end) => end <= USER_END,
+        None => false,
+    }
+}
+
+// Lifts SMAP for the duration of f
+fn with_user_access<T, F>(f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    let smap = protect::smap_enabled();
+
+    if smap {
+        unsafe { stac() };
+    }
+    let rv = f();
+    if smap {
+        unsafe { clac() };
+    }
+
+    rv
+}
+
+// Returns the number of bytes that were not copied
+unsafe fn copy_raw(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    let remaining: usize;
+    let _dst: usize;
+    let _src: usize;
+
+    // If rep movsb faults, rcx holds the number of bytes left to copy
+    asm!("
+    1:  rep movsb
+    2:
+        .pushsection .fixup, \"ax\"
+    3:  jmp 2b
+        .popsection
+        .pushsection __ex_table, \"a\"
+        .balign 8
+        .quad 1b, 3b
+        .popsection"
+        : "={rcx}"(remaining), "={rdi}"(_dst), "={rsi}"(_src)
+        : "0"(len), "1"(dst), "2"(src)
+        : "memory"
+        : "volatile");
+
+    remaining
+}
+
+unsafe fn read_u8_raw(src: *const u8) -> Result<u8, Fault> {
+    let val: u8;
+    let err: u64;
+
+    asm!("
+        xor $1, $1
+    1:  movb ($2), $0
+    2:
+        .pushsection .fixup, \"ax\"
+    3:  mov $$1, $1
+        jmp 2b
+        .popsection
+        .pushsection __ex_table, \"a\"
+        .balign 8
+        .quad 1b, 3b
+        .popsection"
+        : "=&r"(val), "=&r"(err)
+        : "r"(src)
+        : "memory"
+        : "volatile");
+
+    if err == 0 {
+        Ok(val)
+    } else {
+        Err(Fault)
+    }
+}
+
+pub fn copy_from_user(dst: &mut [u8], src: VirtAddr) -> Result<(), Fault> {
+    if !access_ok(src, dst.len()) {
+        return Err(Fault);
+    }
+
+    let remaining =
+        with_user_access(|| unsafe { copy_raw(dst.as_mut_ptr(), src.as_ptr(), dst.len()) });
+
+    if remaining == 0 {
+        Ok(())
+    } else {
+        Err(Fault)
+    }
+}
+
+pub fn copy_to_user(dst: VirtAddr, src: &[u8]) -> Result<(), Fault> {
+    if !access_ok(dst, src.len()) {
+        return Err(Fault);
+    }
+
+    let remaining =
+        with_user_access(|| unsafe { copy_raw(dst.as_mut_ptr(), src.as_ptr(), src.len()) });
+
+    if remaining == 0 {
+        Ok(())
+    } else {
+        Err(Fault)
+    }
+}
+
+// T must be plain data without padding, which would leak kernel memory
+pub unsafe fn write_user<T: Copy>(dst: VirtAddr, value: &T) -> Result<(), Fault> {
+    let bytes = core::slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>());
+    copy_to_user(dst, bytes)
+}
+
+// T must be valid for any bit pattern
+pub unsafe fn read_user<T: Copy>(src: VirtAddr) -> Result<T, Fault> {
+    let mut value = mem::MaybeUninit::<T>::uninit();
+    let bytes = core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, mem::size_of::<T>());
+    copy_from_user(bytes, src)?;
+    Ok(value.assume_init())
+}
+
+// Copies a NUL terminated string into dst, returning its length excluding the
+// terminator. If there is no terminator within dst.len() bytes, dst.len() is
+// returned and dst is not terminated.
+pub fn strncpy_from_user(dst: &mut [u8], src: VirtAddr) -> Result<usize, Fault> {
+    with_user_access(|| {
+        for (i, out) in dst.iter_mut().enumerate() {
+            let addr = src.as_usize().checked_add(i).ok_or(Fault)?;
+            if addr >= USER_END {
+                return Err(Fault);
+            }
+
+            *out = unsafe { read_u8_raw(addr as *const u8)? };
+            if *out == 0 {
+                return Ok(i);
+            }
+        }
+
+        Ok(dst.len())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mm::{addr_space::AddrSpace, pmm::PhysAllocator};
+    use x86_64::structures::paging::PageTableFlags;
+
+    // Unused user address, well away from anything the bootloader maps
+    const TEST_ADDR: usize = 0x6000_0000_0000;
+
+    test_case!(rejects_kernel_addresses, {
+        let mut buf = [0u8; 4];
+        let kernel_addr = VirtAddr::from_ptr(&buf);
+        assert_eq!(copy_from_user(&mut buf, kernel_addr), Err(Fault));
+        assert_eq!(copy_to_user(kernel_addr, &[1, 2, 3, 4]), Err(Fault));
+        assert_eq!(strncpy_from_user(&mut buf, kernel_addr), Err(Fault));
+
+        // Straddling the end of user space
+        let end = VirtAddr::new(USER_END - 2);
+        assert_eq!(copy_from_user(&mut buf, end), Err(Fault));
+    });
+
+    test_case!(unmapped_faults, {
+        let mut buf = [0u8; 16];
+        let addr = VirtAddr::new(TEST_ADDR - 0x1000);
+        assert_eq!(copy_from_user(&mut buf, addr), Err(Fault));
+        assert_eq!(copy_to_user(addr, &buf), Err(Fault));
+        assert_eq!(strncpy_from_user(&mut buf, addr), Err(Fault));
+    });
+
+    test_case!(copy_roundtrip, {
+        let kernel = AddrSpace::kernel();
+        let range = PhysAllocator::alloc(0);
+        let frame = range.start;
+        let addr = VirtAddr::new(TEST_ADDR);
+        kernel
+            .map_to(
+                addr,
+                frame.start_address(),
+                PageTableFlags::PRESENT
+                    | PageTableFlags::WRITABLE
+                    | PageTableFlags::USER_ACCESSIBLE
+                    | PageTableFlags::NO_EXECUTE,
+            )
+            .unwrap()
+            .flush();
+
+        assert_eq!(copy_to_user(addr, b"hello\0world"), Ok(()));
+
+        let mut buf = [0u8; 11];
+        assert_eq!(copy_from_user(&mut buf, addr), Ok(()));
+        assert_eq!(&buf, b"hello\0world");
+
+        let mut s = [0u8; 16];
+        assert_eq!(strncpy_from_user(&mut s, addr), Ok(5));
+        assert_eq!(&s[..6], b"hello\0");
+        assert_eq!(strncpy_from_user(&mut s[..3], addr), Ok(3));
+
+        // A copy running off the end of the mapping fails part way through
+        let near_end = addr + 0x1000 - 4;
+        assert_eq!(copy_from_user(&mut buf, near_end), Err(Fault));
+
+        kernel.unmap(addr).unwrap().1.flush();
+        PhysAllocator::free(range);
+    });
+}
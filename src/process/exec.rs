@@ -0,0 +1,108 @@
+// exec: replaces the program running in the calling process
+use super::current;
+use crate::{
+    elf::{self, load::LoadedImage, Elf},
+    fs::{self, FileType},
+    mm::addr_space::AddrSpace,
+    syscall::Errno,
+    task,
+};
+use alloc::{string::String, sync::Arc, vec::Vec};
+
+// Loads the program into a new address space and switches the calling thread
+// over to it, which frees the old user half once nothing else uses it. On
+// error the process is left as it was. The caller jumps to the image with
+// elf::load::start.
+pub fn exec(name: &str, data: &[u8], argv: &[&[u8]], envp: &[&[u8]]) -> Result<LoadedImage, Errno> {
+    let process = current().ok_or(Errno::EINVAL)?;
+
+    // Other threads would carry on running the old program
+    if process.inner.lock().threads > 1 {
+        return Err(Errno::EBUSY);
+    }
+
+    let elf = Elf::parse(data)?;
+    let space = Arc::new(AddrSpace::new_user().ok_or(Errno::ENOMEM)?);
+    let image = elf::load::load(&space, &elf, argv, envp)?;
+
+    process.inner.lock().addr_space = Some(space.clone());
+    *process.name.lock() = String::from(name);
+    process.signals.lock().exec();
+    process.files.lock().close_on_exec();
+    task::set_addr_space(Some(space));
+
+    debug!("process: {} is now running {}", process.pid, name);
+    Ok(image)
+}
+
+// Reads the executable at path
+pub fn read_executable(path: &[u8]) -> Result<Vec<u8>, Errno> {
+    let path = core::str::from_utf8(path).map_err(|_| Errno::ENOENT)?;
+    if fs::stat(path)?.file_type != FileType::Regular {
+        return Err(Errno::EACCES);
+    }
+    fs::read_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+    use crate::{
+        elf::{builder::ElfBuilder, PF_R, PF_X},
+        mm::pmm::PhysAllocator,
+    };
+    use alloc::vec::Vec;
+
+    // mov $60, %eax; mov (%rsp), %rdi; syscall; jmp .
+    const EXIT_ARGC: [u8; 13] = [
+        0xB8, 0x3C, 0x00, 0x00, 0x00, 0x48, 0x8B, 0x3C, 0x24, 0x0F, 0x05, 0xEB, 0xFE,
+    ];
+
+    fn program() -> Vec<u8> {
+        ElfBuilder::new(0x40_0000)
+            .segment(0x40_0000, &EXIT_ARGC, 0x1000, PF_R | PF_X)
+            .build()
+    }
+
+    fn exec_twice(_: usize) {
+        let data = program();
+        let first = exec("first", &data, &[b"a"], &[]).unwrap();
+        let image = exec("second", &data, &[b"a", b"b", b"c", b"d"], &[]).unwrap();
+        assert_eq!(first.entry, image.entry);
+        drop(data);
+
+        unsafe { elf::load::start(&image) };
+    }
+
+    fn exec_garbage(_: usize) {
+        let process = current().unwrap();
+        assert_eq!(exec("garbage", &[0; 64], &[], &[]).err(), Some(Errno::ENOEXEC));
+        assert!(process.addr_space().is_none());
+        assert_eq!(process.name(), "garbage-host");
+        drop(process);
+        task::exit(11);
+    }
+
+    fn run_exec_twice() {
+        let process = spawn("exec", exec_twice, 0, None).unwrap();
+        assert_eq!(wait(Some(process.pid())), Ok((process.pid(), ExitStatus::Exited(4))));
+        assert_eq!(process.name(), "second");
+        drop(process);
+
+        // The exited thread is left in the dead list until the next switch
+        task::yield_now();
+    }
+
+    test_case!(exec_replaces_the_image, {
+        run_exec_twice();
+
+        let free = PhysAllocator::free_pages();
+        run_exec_twice();
+        assert_eq!(PhysAllocator::free_pages(), free);
+    });
+
+    test_case!(exec_failure_keeps_the_process, {
+        let process = spawn("garbage-host", exec_garbage, 0, None).unwrap();
+        assert_eq!(wait(Some(process.pid())), Ok((process.pid(), ExitStatus::Exited(11))));
+    });
+}
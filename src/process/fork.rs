@@ -0,0 +1,103 @@
+// fork: duplicates the calling process, sharing its memory copy-on-write. Only
+// the calling thread is copied into the child.
+use super::{current, spawn, Process};
+use crate::syscall::{
+    entry::{self, UserFrame},
+    Errno,
+};
+use alloc::{boxed::Box, sync::Arc};
+
+// Must be called from a syscall made by a user process. The child returns from
+// that same syscall with 0.
+pub fn fork() -> Result<Arc<Process>, Errno> {
+    let parent = current().ok_or(Errno::EINVAL)?;
+    let space = parent.addr_space().ok_or(Errno::EINVAL)?;
+    let child_space = space.fork().ok_or(Errno::ENOMEM)?;
+
+    let mut frame = *unsafe { entry::current_frame() };
+    frame.rax = 0;
+
+    let arg = Box::into_raw(Box::new(frame));
+    let child = spawn(&parent.name(), start_child, arg as usize, Some(Arc::new(child_space))).map_err(|errno| {
+        drop(unsafe { Box::from_raw(arg) });
+        errno
+    })?;
+
+    // The child can't have run yet
+    *child.signals.lock() = parent.signals.lock().fork();
+    *child.files.lock() = parent.files.lock().clone();
+    Ok(child)
+}
+
+fn start_child(arg: usize) {
+    let frame = *unsafe { Box::from_raw(arg as *mut UserFrame) };
+    unsafe { entry::return_to_user(&frame) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+    use crate::{
+        elf::{builder::ElfBuilder, PF_R, PF_X},
+        mm::pmm::PhysAllocator,
+    };
+
+    // Forks 200 times. Each child writes to its stack and exits, the parent
+    // writes to its own stack and waits for it, exiting with 1 if anything
+    // goes wrong.
+    #[rustfmt::skip]
+    const FORK_LOOP: [u8; 75] = [
+        0x41, 0xBC, 0xC8, 0x00, 0x00, 0x00,     // mov $200, %r12d
+        0xB8, 0x39, 0x00, 0x00, 0x00,           // loop: mov $57, %eax
+        0x0F, 0x05,                             // syscall
+        0x48, 0x85, 0xC0,                       // test %rax, %rax
+        0x74, 0x28,                             // jz child
+        0x78, 0x2B,                             // js fail
+        0x48, 0x89, 0xC3,                       // mov %rax, %rbx
+        0x41, 0x54,                             // push %r12
+        0x41, 0x5C,                             // pop %r12
+        0x48, 0x89, 0xC7,                       // mov %rax, %rdi
+        0x31, 0xF6,                             // xor %esi, %esi
+        0x31, 0xD2,                             // xor %edx, %edx
+        0x45, 0x31, 0xD2,                       // xor %r10d, %r10d
+        0xB8, 0x3D, 0x00, 0x00, 0x00,           // mov $61, %eax
+        0x0F, 0x05,                             // syscall
+        0x48, 0x39, 0xD8,                       // cmp %rbx, %rax
+        0x75, 0x0E,                             // jne fail
+        0x41, 0xFF, 0xCC,                       // dec %r12d
+        0x75, 0xD0,                             // jnz loop
+        0x31, 0xFF,                             // xor %edi, %edi
+        0xEB, 0x0A,                             // jmp exit
+        0x53,                                   // child: push %rbx
+        0x31, 0xFF,                             // xor %edi, %edi
+        0xEB, 0x05,                             // jmp exit
+        0xBF, 0x01, 0x00, 0x00, 0x00,           // fail: mov $1, %edi
+        0xB8, 0x3C, 0x00, 0x00, 0x00,           // exit: mov $60, %eax
+        0x0F, 0x05,                             // syscall
+    ];
+
+    fn run_fork_loop() {
+        let data = ElfBuilder::new(0x40_0000)
+            .segment(0x40_0000, &FORK_LOOP, 0x1000, PF_R | PF_X)
+            .build();
+
+        let process = spawn_elf("forker", &data, &[b"forker"], &[]).unwrap();
+        assert_eq!(wait(Some(process.pid())), Ok((process.pid(), ExitStatus::Exited(0))));
+        drop(process);
+
+        // The exited thread is left in the dead list until the next switch
+        task::yield_now();
+    }
+
+    test_case!(fork_in_a_loop, {
+        run_fork_loop();
+
+        let free = PhysAllocator::free_pages();
+        run_fork_loop();
+        assert_eq!(PhysAllocator::free_pages(), free);
+    });
+
+    test_case!(fork_needs_a_user_process, {
+        assert_eq!(fork().err(), Some(Errno::EINVAL));
+    });
+}
@@ -0,0 +1,287 @@
+// Futexes: user space waits for a word in memory to change. Waiters are keyed
+// by the physical address of the word, so they meet across processes sharing
+// the frame, and kept in a fixed set of hashed wait queues.
+use super::{current, signal, Process};
+use crate::{
+    ds::SpinLock,
+    mm::{addr_space::COPY_ON_WRITE, uaccess},
+    syscall::Errno,
+    task::{self, Thread},
+    time::{self, timer},
+};
+use alloc::{sync::Arc, vec::Vec};
+use core::{mem, time::Duration};
+use x86_64::{PhysAddr, VirtAddr};
+
+const QUEUES: usize = 64;
+
+struct Waiter {
+    key: PhysAddr,
+    thread: Arc<Thread>,
+}
+
+lazy_static! {
+    static ref WAIT_QUEUES: Vec<SpinLock<Vec<Waiter>>> = (0..QUEUES).map(|_| SpinLock::new(Vec::new())).collect();
+}
+
+fn wait_queue(key: PhysAddr) -> &'static SpinLock<Vec<Waiter>> {
+    let hash = (key.as_usize() as u64 >> 2).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    &WAIT_QUEUES[(hash >> 58) as usize % QUEUES]
+}
+
+// Reads the futex word and finds its key. Copy-on-write pages are copied
+// first, since the word would move to another frame on the next write.
+fn lookup(addr: VirtAddr) -> Result<(u32, PhysAddr), Errno> {
+    if addr.as_usize() % mem::align_of::<u32>() != 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    let space = task::current().addr_space().ok_or(Errno::EFAULT)?;
+    let value = unsafe { uaccess::read_user::<u32>(addr)? };
+
+    let (_, flags) = space.translate_page(addr).ok_or(Errno::EFAULT)?;
+    if flags.contains(COPY_ON_WRITE) && !space.handle_cow_fault(addr) {
+        return Err(Errno::ENOMEM);
+    }
+
+    let (key, _) = space.translate_page(addr).ok_or(Errno::EFAULT)?;
+    Ok((value, key))
+}
+
+// Sleeps until woken through the same word, as long as it still holds
+// expected. Fails with ETIMEDOUT once timeout passes and EINTR when a signal
+// arrives or the process exits.
+pub fn wait(addr: VirtAddr, expected: u32, timeout: Option<Duration>) -> Result<(), Errno> {
+    let (value, key) = lookup(addr)?;
+    if value != expected {
+        return Err(Errno::EAGAIN);
+    }
+
+    // Nothing else runs until we block, so no wake can be missed in between
+    let thread = task::current();
+    let queue = wait_queue(key);
+    queue.lock().push(Waiter {
+        key,
+        thread: thread.clone(),
+    });
+
+    let deadline = timeout.map(|timeout| time::now() + timeout);
+    let timer = deadline.map(|deadline| timer::add(deadline, thread.clone()));
+
+    let result = loop {
+        // wake takes waiters off the queue
+        if !queue.lock().iter().any(|waiter| Arc::ptr_eq(&waiter.thread, &thread)) {
+            break Ok(());
+        }
+        if deadline.map_or(false, |deadline| time::now() >= deadline) {
+            break Err(Errno::ETIMEDOUT);
+        }
+        // The process may be exiting instead
+        if signal::has_pending() || current().map_or(false, |process| process.exit_status().is_some()) {
+            break Err(Errno::EINTR);
+        }
+
+        task::block();
+    };
+
+    if result.is_err() {
+        queue.lock().retain(|waiter| !Arc::ptr_eq(&waiter.thread, &thread));
+    }
+    if let Some(timer) = timer {
+        timer::cancel(timer);
+    }
+    result
+}
+
+// Wakes up to count threads waiting on the word, oldest first, and returns how
+// many there were
+pub fn wake(addr: VirtAddr, count: usize) -> Result<usize, Errno> {
+    let (_, key) = lookup(addr)?;
+
+    let mut woken = Vec::new();
+    wait_queue(key).lock().retain(|waiter| {
+        if waiter.key == key && woken.len() < count {
+            woken.push(waiter.thread.clone());
+            false
+        } else {
+            true
+        }
+    });
+
+    for thread in &woken {
+        task::wake(thread);
+    }
+    Ok(woken.len())
+}
+
+// Wakes the process's waiters so they can see a new signal. They go back to
+// sleep if it's blocked.
+pub fn interrupt(process: &Process) {
+    for queue in WAIT_QUEUES.iter() {
+        let threads: Vec<Arc<Thread>> = queue
+            .lock()
+            .iter()
+            .filter(|waiter| waiter.thread.process().map_or(false, |owner| owner.pid() == process.pid()))
+            .map(|waiter| waiter.thread.clone())
+            .collect();
+
+        for thread in &threads {
+            task::wake(thread);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cpu::user::enter_user,
+        elf::{builder::ElfBuilder, PF_R, PF_W, PF_X},
+        mm::{self, addr_space::AddrSpace},
+        process::{self, spawn, spawn_elf, spawn_thread, ExitStatus},
+    };
+    use x86_64::structures::paging::{PageTableFlags, PhysFrame};
+
+    const WORD: usize = 0x40_0000;
+    const OTHER_WORD: usize = 0x60_0000;
+
+    fn user_flags() -> PageTableFlags {
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::NO_EXECUTE
+    }
+
+    fn space_with_word() -> (Arc<AddrSpace>, PhysFrame) {
+        let space = AddrSpace::new_user().unwrap();
+        let frame = space.map_zeroed(VirtAddr::new(WORD), user_flags()).unwrap();
+        (Arc::new(space), frame)
+    }
+
+    fn check_errors(_: usize) {
+        let word = VirtAddr::new(WORD);
+        assert_eq!(wait(word + 2, 0, None), Err(Errno::EINVAL));
+        assert_eq!(wait(VirtAddr::new(OTHER_WORD), 0, None), Err(Errno::EFAULT));
+        assert_eq!(wait(word, 1, None), Err(Errno::EAGAIN));
+        assert_eq!(wake(word, 1), Ok(0));
+
+        let start = time::now();
+        assert_eq!(wait(word, 0, Some(Duration::from_millis(10))), Err(Errno::ETIMEDOUT));
+        assert!(time::now() - start >= Duration::from_millis(10));
+        assert_eq!(wait(word, 0, Some(Duration::from_secs(0))), Err(Errno::ETIMEDOUT));
+    }
+
+    test_case!(wait_errors_and_timeout, {
+        let (space, _) = space_with_word();
+        let process = spawn("futex-errors", check_errors, 0, Some(space)).unwrap();
+        assert_eq!(wait_pid(&process), ExitStatus::Exited(0));
+    });
+
+    fn wait_for_wake(_: usize) {
+        assert_eq!(wait(VirtAddr::new(WORD), 0, Some(Duration::from_secs(1))), Ok(()));
+    }
+
+    fn wake_other(_: usize) {
+        while wake(VirtAddr::new(OTHER_WORD), 1) == Ok(0) {
+            task::yield_now();
+        }
+    }
+
+    fn wait_pid(child: &Process) -> ExitStatus {
+        process::wait(Some(child.pid())).unwrap().1
+    }
+
+    // The same frame at different addresses in two processes
+    test_case!(wake_across_shared_mappings, {
+        let (space, frame) = space_with_word();
+        let other = AddrSpace::new_user().unwrap();
+        other.map_to(VirtAddr::new(OTHER_WORD), frame.start_address(), user_flags()).unwrap().flush();
+        mm::page_info(frame).share();
+
+        let waiter = spawn("futex-waiter", wait_for_wake, 0, Some(space)).unwrap();
+        let waker = spawn("futex-waker", wake_other, 0, Some(Arc::new(other))).unwrap();
+        assert_eq!(wait_pid(&waiter), ExitStatus::Exited(0));
+        assert_eq!(wait_pid(&waker), ExitStatus::Exited(0));
+    });
+
+    // Four threads take a futex based mutex 100 times each, yielding while
+    // holding it to force contention, then exit with the counter it protects.
+    // MUTEX, COUNTER and DONE are the words at 0x401000, 0x401004 and 0x401008.
+    // The first thread also waits for DONE to count every thread.
+    #[rustfmt::skip]
+    const CONTENDED_MUTEX: [u8; 240] = [
+        0x41, 0xBD, 0x01, 0x00, 0x00, 0x00,     // mov $1, %r13d
+        0x41, 0xBC, 0x64, 0x00, 0x00, 0x00,     // worker: mov $100, %r12d
+        0x31, 0xC0,                             // loop: xor %eax, %eax
+        0xB9, 0x01, 0x00, 0x00, 0x00,           // mov $1, %ecx
+        0xF0, 0x0F, 0xB1, 0x0C, 0x25, 0x00, 0x10, 0x40, 0x00, // lock cmpxchg %ecx, MUTEX
+        0x74, 0x2B,                             // jz locked
+        0xB8, 0x02, 0x00, 0x00, 0x00,           // contended: mov $2, %eax
+        0x87, 0x04, 0x25, 0x00, 0x10, 0x40, 0x00, // xchg %eax, MUTEX
+        0x85, 0xC0,                             // test %eax, %eax
+        0x74, 0x1B,                             // jz locked
+        0xBF, 0x00, 0x10, 0x40, 0x00,           // mov $MUTEX, %edi
+        0xBE, 0x80, 0x00, 0x00, 0x00,           // mov $128, %esi
+        0xBA, 0x02, 0x00, 0x00, 0x00,           // mov $2, %edx
+        0x45, 0x31, 0xD2,                       // xor %r10d, %r10d
+        0xB8, 0xCA, 0x00, 0x00, 0x00,           // mov $202, %eax
+        0x0F, 0x05,                             // syscall
+        0xEB, 0xD5,                             // jmp contended
+        0x8B, 0x1C, 0x25, 0x04, 0x10, 0x40, 0x00, // locked: mov COUNTER, %ebx
+        0xB8, 0x18, 0x00, 0x00, 0x00,           // mov $24, %eax
+        0x0F, 0x05,                             // syscall
+        0xFF, 0xC3,                             // inc %ebx
+        0x89, 0x1C, 0x25, 0x04, 0x10, 0x40, 0x00, // mov %ebx, COUNTER
+        0xF0, 0xFF, 0x0C, 0x25, 0x00, 0x10, 0x40, 0x00, // lock decl MUTEX
+        0x74, 0x21,                             // jz unlocked
+        0xC7, 0x04, 0x25, 0x00, 0x10, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, // movl $0, MUTEX
+        0xBF, 0x00, 0x10, 0x40, 0x00,           // mov $MUTEX, %edi
+        0xBE, 0x81, 0x00, 0x00, 0x00,           // mov $129, %esi
+        0xBA, 0x01, 0x00, 0x00, 0x00,           // mov $1, %edx
+        0xB8, 0xCA, 0x00, 0x00, 0x00,           // mov $202, %eax
+        0x0F, 0x05,                             // syscall
+        0x41, 0xFF, 0xCC,                       // unlocked: dec %r12d
+        0x0F, 0x85, 0x78, 0xFF, 0xFF, 0xFF,     // jnz loop
+        0xF0, 0xFF, 0x04, 0x25, 0x08, 0x10, 0x40, 0x00, // lock incl DONE
+        0xBF, 0x08, 0x10, 0x40, 0x00,           // mov $DONE, %edi
+        0xBE, 0x81, 0x00, 0x00, 0x00,           // mov $129, %esi
+        0xBA, 0x01, 0x00, 0x00, 0x00,           // mov $1, %edx
+        0xB8, 0xCA, 0x00, 0x00, 0x00,           // mov $202, %eax
+        0x0F, 0x05,                             // syscall
+        0x45, 0x85, 0xED,                       // test %r13d, %r13d
+        0x75, 0x09,                             // jnz wait_done
+        0x31, 0xFF,                             // xor %edi, %edi
+        0xB8, 0x3C, 0x00, 0x00, 0x00,           // mov $60, %eax
+        0x0F, 0x05,                             // syscall
+        0x8B, 0x14, 0x25, 0x08, 0x10, 0x40, 0x00, // wait_done: mov DONE, %edx
+        0x83, 0xFA, 0x04,                       // cmp $4, %edx
+        0x74, 0x16,                             // je finish
+        0xBF, 0x08, 0x10, 0x40, 0x00,           // mov $DONE, %edi
+        0xBE, 0x80, 0x00, 0x00, 0x00,           // mov $128, %esi
+        0x45, 0x31, 0xD2,                       // xor %r10d, %r10d
+        0xB8, 0xCA, 0x00, 0x00, 0x00,           // mov $202, %eax
+        0x0F, 0x05,                             // syscall
+        0xEB, 0xDE,                             // jmp wait_done
+        0x8B, 0x3C, 0x25, 0x04, 0x10, 0x40, 0x00, // finish: mov COUNTER, %edi
+        0xB8, 0xE7, 0x00, 0x00, 0x00,           // mov $231, %eax
+        0x0F, 0x05,                             // syscall
+    ];
+
+    const WORKER_ENTRY: usize = 0x40_0006;
+    const STACKS: usize = 0x41_0000;
+
+    fn start_worker(stack: usize) {
+        unsafe { enter_user(VirtAddr::new(WORKER_ENTRY), VirtAddr::new(stack)) }
+    }
+
+    test_case!(contended_mutex, {
+        let data = ElfBuilder::new(0x40_0000)
+            .segment(0x40_0000, &CONTENDED_MUTEX, 0x1000, PF_R | PF_X)
+            .segment(0x40_1000, &[], 0x1000, PF_R | PF_W)
+            .segment(STACKS as u64, &[], 0x3000, PF_R | PF_W)
+            .build();
+
+        let process = spawn_elf("mutex", &data, &[b"mutex"], &[]).unwrap();
+        for i in 1..=3 {
+            spawn_thread(&process, "mutex-worker", start_worker, STACKS + i * 0x1000);
+        }
+        assert_eq!(wait_pid(&process), ExitStatus::Exited(400));
+    });
+}
@@ -0,0 +1,412 @@
+// Processes: an address space shared by one or more threads, with a PID, a
+// parent and an exit status. Exited processes stay around as zombies until
+// their parent reaps them with wait. Orphans are handed to init, PID 1, which
+// is the boot thread.
+use self::signal::{Signal, SignalState};
+use crate::{
+    ds::SpinLock,
+    elf::{self, Elf},
+    fs::fd::FdTable,
+    mm::addr_space::AddrSpace,
+    syscall::Errno,
+    task::{self, Thread},
+};
+use alloc::{boxed::Box, collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use core::mem;
+
+pub mod exec;
+pub mod fork;
+pub mod futex;
+pub mod signal;
+
+pub use exec::exec;
+pub use fork::fork;
+
+pub type Pid = u32;
+
+pub const INIT_PID: Pid = 1;
+
+// PIDs are allocated from 1 up to this, then wrap around
+pub const PID_MAX: Pid = 32768;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    Exited(i32),
+    // Killed by a signal, true if it dumped core
+    Signaled(Signal, bool),
+}
+
+impl ExitStatus {
+    // Encoded like Linux does for wait4
+    pub fn wait_status(self) -> u32 {
+        match self {
+            ExitStatus::Exited(code) => ((code & 0xFF) << 8) as u32,
+            ExitStatus::Signaled(signal, core) => signal as u32 | if core { 0x80 } else { 0 },
+        }
+    }
+
+    // Exit code for the threads, shells report signals the same way
+    fn thread_code(self) -> i32 {
+        match self {
+            ExitStatus::Exited(code) => code,
+            ExitStatus::Signaled(signal, _) => 128 + signal as i32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    Zombie(ExitStatus),
+}
+
+struct Inner {
+    state: State,
+    // Threads which haven't exited yet
+    threads: usize,
+    // Set by exit, which makes the remaining threads leave on their way back
+    // to user mode
+    exit_status: Option<ExitStatus>,
+    children: Vec<Arc<Process>>,
+    addr_space: Option<Arc<AddrSpace>>,
+}
+
+pub struct Process {
+    pid: Pid,
+    // Changed by exec
+    name: SpinLock<String>,
+    parent: SpinLock<Pid>,
+    inner: SpinLock<Inner>,
+    signals: SpinLock<SignalState>,
+    files: SpinLock<FdTable>,
+}
+
+struct Table {
+    processes: BTreeMap<Pid, Arc<Process>>,
+    next_pid: Pid,
+}
+
+lazy_static! {
+    static ref TABLE: SpinLock<Table> = SpinLock::new(Table {
+        processes: BTreeMap::new(),
+        next_pid: INIT_PID,
+    });
+}
+
+impl Table {
+    fn alloc_pid(&mut self) -> Option<Pid> {
+        for _ in INIT_PID..PID_MAX {
+            let pid = self.next_pid;
+            self.next_pid = if pid + 1 == PID_MAX { INIT_PID + 1 } else { pid + 1 };
+
+            if !self.processes.contains_key(&pid) {
+                return Some(pid);
+            }
+        }
+
+        None
+    }
+}
+
+impl Process {
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn name(&self) -> String {
+        self.name.lock().clone()
+    }
+
+    pub fn parent(&self) -> Pid {
+        *self.parent.lock()
+    }
+
+    pub fn state(&self) -> State {
+        self.inner.lock().state
+    }
+
+    pub fn addr_space(&self) -> Option<Arc<AddrSpace>> {
+        self.inner.lock().addr_space.clone()
+    }
+
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        self.inner.lock().exit_status
+    }
+
+    pub fn files(&self) -> &SpinLock<FdTable> {
+        &self.files
+    }
+
+    // Called by task::exit for each of the process's threads
+    pub(crate) fn thread_exited(&self, code: i32) {
+        let (status, children, space) = {
+            let mut inner = self.inner.lock();
+            inner.threads -= 1;
+            if inner.threads > 0 {
+                return;
+            }
+
+            let status = inner.exit_status.unwrap_or(ExitStatus::Exited(code));
+            inner.state = State::Zombie(status);
+            (status, mem::replace(&mut inner.children, Vec::new()), inner.addr_space.take())
+        };
+
+        if self.pid == INIT_PID {
+            panic!("process: init exited with {:?}", status);
+        }
+        debug!("process: {} ({}) exited with {:?}", self.pid, self.name(), status);
+
+        // The exiting thread keeps its own reference until it's switched out
+        drop(space);
+        drop(mem::replace(&mut *self.files.lock(), FdTable::new()));
+
+        if !children.is_empty() {
+            let init = get(INIT_PID).expect("process: init is missing");
+            for child in &children {
+                *child.parent.lock() = INIT_PID;
+            }
+            init.inner.lock().children.extend(children);
+        }
+
+        if let Some(parent) = get(self.parent()) {
+            signal::send(&parent, Signal::SIGCHLD);
+        }
+    }
+}
+
+pub fn init() {
+    let thread = task::current();
+    let init = create(INIT_PID, "init", None).expect("process: can't create init");
+    init.inner.lock().threads += 1;
+    thread.set_process(init);
+
+    debug!("process: initialized");
+}
+
+pub fn get(pid: Pid) -> Option<Arc<Process>> {
+    TABLE.lock().processes.get(&pid).cloned()
+}
+
+// The process of the current thread, None for kernel threads
+pub fn current() -> Option<Arc<Process>> {
+    task::current().process()
+}
+
+fn create(parent: Pid, name: &str, space: Option<Arc<AddrSpace>>) -> Result<Arc<Process>, Errno> {
+    let mut table = TABLE.lock();
+    let pid = table.alloc_pid().ok_or(Errno::EAGAIN)?;
+
+    let process = Arc::new(Process {
+        pid,
+        name: SpinLock::new(String::from(name)),
+        parent: SpinLock::new(parent),
+        inner: SpinLock::new(Inner {
+            state: State::Running,
+            threads: 0,
+            exit_status: None,
+            children: Vec::new(),
+            addr_space: space,
+        }),
+        signals: SpinLock::new(SignalState::new()),
+        files: SpinLock::new(FdTable::with_console()),
+    });
+    table.processes.insert(pid, process.clone());
+
+    Ok(process)
+}
+
+// Starts a new process as a child of the current one with a single thread
+// running entry, in the given address space or the kernel's
+pub fn spawn(
+    name: &str,
+    entry: fn(usize),
+    arg: usize,
+    space: Option<Arc<AddrSpace>>,
+) -> Result<Arc<Process>, Errno> {
+    // Processes started from kernel threads belong to init
+    let parent = current().or_else(|| get(INIT_PID)).expect("process: init is missing");
+    let process = create(parent.pid, name, space)?;
+    parent.inner.lock().children.push(process.clone());
+
+    spawn_thread(&process, "main", entry, arg);
+    trace!("process: spawned {} ({})", process.pid, name);
+
+    Ok(process)
+}
+
+// Adds another thread to a running process
+pub fn spawn_thread(process: &Arc<Process>, name: &'static str, entry: fn(usize), arg: usize) -> Arc<Thread> {
+    process.inner.lock().threads += 1;
+    task::spawn_in(process.clone(), name, entry, arg)
+}
+
+fn start_user(arg: usize) {
+    let image = *unsafe { Box::from_raw(arg as *mut elf::load::LoadedImage) };
+    unsafe { elf::load::start(&image) };
+}
+
+// Loads an ELF executable into a fresh address space and runs it in a new
+// process
+pub fn spawn_elf(name: &str, data: &[u8], argv: &[&[u8]], envp: &[&[u8]]) -> Result<Arc<Process>, Errno> {
+    let elf = Elf::parse(data)?;
+    let space = Arc::new(AddrSpace::new_user().ok_or(Errno::ENOMEM)?);
+    let image = elf::load::load(&space, &elf, argv, envp)?;
+
+    let arg = Box::into_raw(Box::new(image));
+    spawn(name, start_user, arg as usize, Some(space)).map_err(|errno| {
+        drop(unsafe { Box::from_raw(arg) });
+        errno
+    })
+}
+
+// Exits every thread of the current process. The others leave the next time
+// they would return to user mode.
+pub fn exit(code: i32) -> ! {
+    exit_with(ExitStatus::Exited(code))
+}
+
+pub fn exit_with(status: ExitStatus) -> ! {
+    if let Some(process) = current() {
+        let mut inner = process.inner.lock();
+        if inner.exit_status.is_none() {
+            inner.exit_status = Some(status);
+        }
+        drop(inner);
+
+        // Threads sleeping on a futex would never get to check_exit
+        futex::interrupt(&process);
+    }
+
+    task::exit(status.thread_code())
+}
+
+// Exits the current thread if its process is exiting
+pub fn check_exit() {
+    if let Some(status) = current().and_then(|process| process.exit_status()) {
+        task::exit(status.thread_code());
+    }
+}
+
+// Reaps an exited child, any child if pid is None. Returns Ok(None) if there
+// are matching children but none have exited yet.
+pub fn try_wait(pid: Option<Pid>) -> Result<Option<(Pid, ExitStatus)>, Errno> {
+    let process = current().ok_or(Errno::ECHILD)?;
+    let matches = |child: &Arc<Process>| pid.map_or(true, |pid| child.pid == pid);
+
+    let child = {
+        let mut inner = process.inner.lock();
+        if !inner.children.iter().any(matches) {
+            return Err(Errno::ECHILD);
+        }
+
+        let zombie = inner
+            .children
+            .iter()
+            .position(|child| matches(child) && child.state() != State::Running);
+        match zombie {
+            Some(index) => inner.children.swap_remove(index),
+            None => return Ok(None),
+        }
+    };
+
+    TABLE.lock().processes.remove(&child.pid);
+
+    match child.state() {
+        State::Zombie(status) => {
+            trace!("process: reaped {} ({})", child.pid, child.name());
+            Ok(Some((child.pid, status)))
+        }
+        State::Running => unreachable!(),
+    }
+}
+
+// Blocks until a child exits, or a signal arrives
+pub fn wait(pid: Option<Pid>) -> Result<(Pid, ExitStatus), Errno> {
+    loop {
+        if let Some(result) = try_wait(pid)? {
+            return Ok(result);
+        }
+        if signal::has_pending() {
+            return Err(Errno::EINTR);
+        }
+        task::yield_now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        elf::{builder::ElfBuilder, PF_R, PF_X},
+        mm::pmm::PhysAllocator,
+    };
+
+    // mov $60, %eax; mov (%rsp), %rdi; syscall; jmp .
+    const EXIT_ARGC: [u8; 13] = [
+        0xB8, 0x3C, 0x00, 0x00, 0x00, 0x48, 0x8B, 0x3C, 0x24, 0x0F, 0x05, 0xEB, 0xFE,
+    ];
+
+    fn exit_with(code: usize) {
+        task::exit(code as i32);
+    }
+
+    fn spawn_grandchild(code: usize) {
+        spawn("grandchild", exit_with, code, None).unwrap();
+    }
+
+    test_case!(spawn_and_wait, {
+        let a = spawn("a", exit_with, 3, None).unwrap();
+        let b = spawn("b", exit_with, 4, None).unwrap();
+        assert_eq!(a.parent(), INIT_PID);
+        assert_ne!(a.pid(), b.pid());
+
+        assert_eq!(wait(Some(b.pid())), Ok((b.pid(), ExitStatus::Exited(4))));
+        assert_eq!(wait(None), Ok((a.pid(), ExitStatus::Exited(3))));
+        assert!(get(a.pid()).is_none());
+        assert_eq!(try_wait(None), Err(Errno::ECHILD));
+        assert_eq!(try_wait(Some(a.pid())), Err(Errno::ECHILD));
+    });
+
+    test_case!(zombies_until_reaped, {
+        let child = spawn("zombie", exit_with, 9, None).unwrap();
+        assert_eq!(try_wait(Some(child.pid())), Ok(None));
+
+        task::yield_now();
+        assert_eq!(child.state(), State::Zombie(ExitStatus::Exited(9)));
+        assert!(get(child.pid()).is_some());
+        assert_eq!(try_wait(Some(child.pid())), Ok(Some((child.pid(), ExitStatus::Exited(9)))));
+    });
+
+    test_case!(orphans_go_to_init, {
+        let child = spawn("parent", spawn_grandchild, 5, None).unwrap();
+        assert_eq!(wait(Some(child.pid())), Ok((child.pid(), ExitStatus::Exited(0))));
+
+        // The grandchild was handed to init, which is this thread
+        let (pid, status) = wait(None).unwrap();
+        assert_eq!(status, ExitStatus::Exited(5));
+        assert_ne!(pid, child.pid());
+    });
+
+    fn spawn_and_reap() {
+        let data = ElfBuilder::new(0x40_0000)
+            .segment(0x40_0000, &EXIT_ARGC, 0x1000, PF_R | PF_X)
+            .build();
+
+        let process = spawn_elf("prog", &data, &[b"prog", b"x"], &[]).unwrap();
+        assert_eq!(wait(Some(process.pid())), Ok((process.pid(), ExitStatus::Exited(2))));
+        drop(process);
+
+        // The exited thread is left in the dead list until the next switch
+        task::yield_now();
+    }
+
+    test_case!(processes_dont_leak_frames, {
+        spawn_and_reap();
+
+        let free = PhysAllocator::free_pages();
+        for _ in 0..16 {
+            spawn_and_reap();
+        }
+        assert_eq!(PhysAllocator::free_pages(), free);
+    });
+}
@@ -0,0 +1,718 @@
+// POSIX signals. Each process has a table of actions and a pending and a
+// blocked set. Signals are delivered on the way back to user mode, either by
+// running the default action or by building a signal frame on the user stack
+// and entering the handler, which returns through rt_sigreturn.
+use super::{current, exit_with, get, ExitStatus, Pid, Process, State};
+use crate::{
+    mm::uaccess::{self, USER_END},
+    syscall::{entry::UserFrame, Errno},
+};
+use core::mem;
+use x86_64::VirtAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Signal {
+    SIGHUP = 1,
+    SIGINT = 2,
+    SIGQUIT = 3,
+    SIGILL = 4,
+    SIGTRAP = 5,
+    SIGABRT = 6,
+    SIGBUS = 7,
+    SIGFPE = 8,
+    SIGKILL = 9,
+    SIGUSR1 = 10,
+    SIGSEGV = 11,
+    SIGUSR2 = 12,
+    SIGPIPE = 13,
+    SIGALRM = 14,
+    SIGTERM = 15,
+    SIGSTKFLT = 16,
+    SIGCHLD = 17,
+    SIGCONT = 18,
+    SIGSTOP = 19,
+    SIGTSTP = 20,
+    SIGTTIN = 21,
+    SIGTTOU = 22,
+    SIGURG = 23,
+    SIGXCPU = 24,
+    SIGXFSZ = 25,
+    SIGVTALRM = 26,
+    SIGPROF = 27,
+    SIGWINCH = 28,
+    SIGIO = 29,
+    SIGPWR = 30,
+    SIGSYS = 31,
+}
+
+pub const NSIG: usize = 32;
+
+// Special handler values
+pub const SIG_DFL: usize = 0;
+pub const SIG_IGN: usize = 1;
+
+// SigAction flags
+pub const SA_SIGINFO: u64 = 0x4;
+pub const SA_RESTORER: u64 = 0x0400_0000;
+pub const SA_NODEFER: u64 = 0x4000_0000;
+pub const SA_RESETHAND: u64 = 0x8000_0000;
+
+// si_code values
+pub const SI_USER: i32 = 0;
+pub const SI_KERNEL: i32 = 0x80;
+pub const ILL_ILLOPN: i32 = 2;
+pub const FPE_INTDIV: i32 = 1;
+pub const SEGV_MAPERR: i32 = 1;
+pub const SEGV_ACCERR: i32 = 2;
+pub const BUS_ADRALN: i32 = 1;
+
+// Flags a signal handler may change, plus the reserved bit 1
+const USER_RFLAGS_MASK: usize = 0xDD5;
+const INTERRUPT_FLAG: usize = 0x200;
+
+impl Signal {
+    pub fn from_number(number: u32) -> Option<Signal> {
+        use Signal::*;
+        const ALL: [Signal; NSIG - 1] = [
+            SIGHUP, SIGINT, SIGQUIT, SIGILL, SIGTRAP, SIGABRT, SIGBUS, SIGFPE, SIGKILL, SIGUSR1,
+            SIGSEGV, SIGUSR2, SIGPIPE, SIGALRM, SIGTERM, SIGSTKFLT, SIGCHLD, SIGCONT, SIGSTOP,
+            SIGTSTP, SIGTTIN, SIGTTOU, SIGURG, SIGXCPU, SIGXFSZ, SIGVTALRM, SIGPROF, SIGWINCH,
+            SIGIO, SIGPWR, SIGSYS,
+        ];
+
+        ALL.get((number as usize).wrapping_sub(1)).copied()
+    }
+
+    // SIGKILL and SIGSTOP can't be caught, blocked or ignored
+    pub fn is_catchable(self) -> bool {
+        self != Signal::SIGKILL && self != Signal::SIGSTOP
+    }
+
+    pub fn default_action(self) -> DefaultAction {
+        use Signal::*;
+        match self {
+            SIGQUIT | SIGILL | SIGTRAP | SIGABRT | SIGBUS | SIGFPE | SIGSEGV | SIGXCPU | SIGXFSZ
+            | SIGSYS => DefaultAction::Core,
+            // There's no job control, so stopping is ignored as well
+            SIGCHLD | SIGCONT | SIGURG | SIGWINCH | SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => {
+                DefaultAction::Ignore
+            }
+            _ => DefaultAction::Terminate,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefaultAction {
+    Terminate,
+    // Terminate after dumping the registers to the log
+    Core,
+    Ignore,
+}
+
+// A set of signals, bit n - 1 standing for signal n as in Linux's sigset_t
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct SigSet(pub u64);
+
+impl SigSet {
+    pub const fn empty() -> SigSet {
+        SigSet(0)
+    }
+
+    fn bit(signal: Signal) -> u64 {
+        1 << (signal as u64 - 1)
+    }
+
+    pub fn contains(self, signal: Signal) -> bool {
+        self.0 & Self::bit(signal) != 0
+    }
+
+    pub fn insert(&mut self, signal: Signal) {
+        self.0 |= Self::bit(signal);
+    }
+
+    pub fn remove(&mut self, signal: Signal) {
+        self.0 &= !Self::bit(signal);
+    }
+
+    // The set with SIGKILL and SIGSTOP taken out
+    pub fn catchable(self) -> SigSet {
+        let mut set = self;
+        set.remove(Signal::SIGKILL);
+        set.remove(Signal::SIGSTOP);
+        set
+    }
+
+    // The lowest numbered signal in the set
+    fn first(self) -> Option<Signal> {
+        if self.0 == 0 {
+            None
+        } else {
+            Signal::from_number(self.0.trailing_zeros() + 1)
+        }
+    }
+}
+
+// struct sigaction as passed to rt_sigaction
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SigAction {
+    pub handler: usize,
+    pub flags: u64,
+    pub restorer: usize,
+    pub mask: SigSet,
+}
+
+// The start of Linux's siginfo_t, which is all that we fill in
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SigInfo {
+    pub signo: i32,
+    pub errno: i32,
+    pub code: i32,
+    _pad: i32,
+    // Faulting address for SIGSEGV, SIGBUS, SIGILL and SIGFPE
+    pub addr: usize,
+}
+
+impl SigInfo {
+    pub fn new(signal: Signal, code: i32, addr: usize) -> SigInfo {
+        SigInfo {
+            signo: signal as i32,
+            errno: 0,
+            code,
+            _pad: 0,
+            addr,
+        }
+    }
+}
+
+// Saved by delivery and restored by rt_sigreturn. rdx points here when the
+// handler runs.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SignalContext {
+    pub regs: UserFrame,
+    pub blocked: SigSet,
+}
+
+// Built on the user stack below the red zone. The handler starts with rsp
+// pointing at restorer, as if it had been called from there.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct SignalFrame {
+    restorer: usize,
+    info: SigInfo,
+    context: SignalContext,
+}
+
+pub struct SignalState {
+    actions: [SigAction; NSIG],
+    pending: SigSet,
+    blocked: SigSet,
+    info: [SigInfo; NSIG],
+}
+
+impl SignalState {
+    pub fn new() -> SignalState {
+        SignalState {
+            actions: [SigAction::default(); NSIG],
+            pending: SigSet::empty(),
+            blocked: SigSet::empty(),
+            info: [SigInfo::default(); NSIG],
+        }
+    }
+
+    // A forked child keeps the actions and the mask, but nothing is pending
+    pub fn fork(&self) -> SignalState {
+        SignalState {
+            actions: self.actions,
+            blocked: self.blocked,
+            ..SignalState::new()
+        }
+    }
+
+    // exec resets caught signals, since the handlers are gone
+    pub fn exec(&mut self) {
+        for action in self.actions.iter_mut() {
+            if action.handler != SIG_IGN {
+                *action = SigAction::default();
+            }
+        }
+    }
+
+    pub fn action(&self, signal: Signal) -> SigAction {
+        self.actions[signal as usize]
+    }
+
+    pub fn set_action(&mut self, signal: Signal, action: SigAction) -> Result<(), Errno> {
+        if !signal.is_catchable() {
+            return Err(Errno::EINVAL);
+        }
+        // setup_frame sends the user to both, like sigreturn does to regs.rip
+        if action.handler >= USER_END || action.restorer >= USER_END {
+            return Err(Errno::EFAULT);
+        }
+
+        self.actions[signal as usize] = SigAction {
+            mask: action.mask.catchable(),
+            ..action
+        };
+
+        // Pending signals which are now ignored are dropped
+        if self.is_ignored(signal) {
+            self.pending.remove(signal);
+        }
+        Ok(())
+    }
+
+    pub fn blocked(&self) -> SigSet {
+        self.blocked
+    }
+
+    pub fn set_blocked(&mut self, blocked: SigSet) {
+        self.blocked = blocked.catchable();
+    }
+
+    pub fn pending(&self) -> SigSet {
+        self.pending
+    }
+
+    fn is_ignored(&self, signal: Signal) -> bool {
+        match self.action(signal).handler {
+            SIG_IGN => true,
+            SIG_DFL => signal.default_action() == DefaultAction::Ignore,
+            _ => false,
+        }
+    }
+
+    fn queue(&mut self, signal: Signal, info: SigInfo) {
+        if self.is_ignored(signal) && !self.blocked.contains(signal) {
+            return;
+        }
+
+        // Standard signals don't queue up, the first one's info is kept
+        if !self.pending.contains(signal) {
+            self.pending.insert(signal);
+            self.info[signal as usize] = info;
+        }
+    }
+
+    // Takes the lowest numbered pending signal that isn't blocked
+    fn dequeue(&mut self) -> Option<(Signal, SigInfo)> {
+        let signal = SigSet(self.pending.0 & !self.blocked.0).first()?;
+        self.pending.remove(signal);
+        Some((signal, self.info[signal as usize]))
+    }
+}
+
+// Changes the action for a signal, returning the old one
+pub fn sigaction(signal: Signal, action: Option<SigAction>) -> Result<SigAction, Errno> {
+    let process = current().ok_or(Errno::EINVAL)?;
+    let mut state = process.signals.lock();
+
+    let old = state.action(signal);
+    if let Some(action) = action {
+        state.set_action(signal, action)?;
+    }
+    Ok(old)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaskHow {
+    Block,
+    Unblock,
+    SetMask,
+}
+
+// Changes the blocked set, returning the old one
+pub fn sigprocmask(how: MaskHow, set: Option<SigSet>) -> Result<SigSet, Errno> {
+    let process = current().ok_or(Errno::EINVAL)?;
+    let mut state = process.signals.lock();
+
+    let old = state.blocked();
+    if let Some(set) = set {
+        state.set_blocked(match how {
+            MaskHow::Block => SigSet(old.0 | set.0),
+            MaskHow::Unblock => SigSet(old.0 & !set.0),
+            MaskHow::SetMask => set,
+        });
+    }
+    Ok(old)
+}
+
+// Sends a signal to another process. Without a signal, only checks that the
+// process exists.
+pub fn kill(pid: Pid, signal: Option<Signal>) -> Result<(), Errno> {
+    let process = get(pid).ok_or(Errno::ESRCH)?;
+    if let Some(signal) = signal {
+        if process.state() == State::Running {
+            send(&process, signal);
+        }
+    }
+    Ok(())
+}
+
+pub fn send(process: &Process, signal: Signal) {
+    send_info(process, signal, SigInfo::new(signal, SI_USER, 0));
+}
+
+pub fn send_info(process: &Process, signal: Signal, info: SigInfo) {
+    trace!("signal: sending {:?} to {}", signal, process.pid());
+    process.signals.lock().queue(signal, info);
+    super::futex::interrupt(process);
+}
+
+// Raises a signal caused by the current thread, like a fault. It can't be
+// blocked or ignored: if it is, the default action is restored.
+pub fn force(signal: Signal, info: SigInfo) {
+    let process = current().expect("signal: forcing a signal on a kernel thread");
+    let mut state = process.signals.lock();
+
+    if state.blocked.contains(signal) || state.action(signal).handler == SIG_IGN {
+        state.blocked.remove(signal);
+        state.actions[signal as usize] = SigAction::default();
+    }
+    state.queue(signal, info);
+}
+
+// Whether the current process has a signal to handle
+pub fn has_pending() -> bool {
+    match current() {
+        Some(process) => {
+            let state = process.signals.lock();
+            state.pending.0 & !state.blocked.0 != 0
+        }
+        None => false,
+    }
+}
+
+// Handles pending signals before frame is returned to. Runs default actions
+// until a signal is caught, in which case frame is changed to enter its
+// handler, or there are none left.
+pub fn deliver(frame: &mut UserFrame) {
+    let process = match current() {
+        Some(process) => process,
+        None => return,
+    };
+
+    loop {
+        let (signal, info, action, blocked) = {
+            let mut state = process.signals.lock();
+            let (signal, info) = match state.dequeue() {
+                Some(next) => next,
+                None => return,
+            };
+
+            let action = state.action(signal);
+            (signal, info, action, state.blocked)
+        };
+
+        match action.handler {
+            SIG_IGN => continue,
+            SIG_DFL => match signal.default_action() {
+                DefaultAction::Ignore => continue,
+                DefaultAction::Terminate => {
+                    drop(process);
+                    exit_with(ExitStatus::Signaled(signal, false));
+                }
+                DefaultAction::Core => {
+                    dump_core(&process, signal, frame);
+                    drop(process);
+                    exit_with(ExitStatus::Signaled(signal, true));
+                }
+            },
+            _ => {
+                if setup_frame(frame, signal, info, &action, blocked).is_err() {
+                    // The stack is unusable, so the handler can't run
+                    info!("signal: process {} has no stack for {:?}", process.pid(), signal);
+                    drop(process);
+                    exit_with(ExitStatus::Signaled(Signal::SIGSEGV, false));
+                }
+
+                let mut state = process.signals.lock();
+                let mut mask = SigSet(state.blocked.0 | action.mask.0);
+                if action.flags & SA_NODEFER == 0 {
+                    mask.insert(signal);
+                }
+                state.set_blocked(mask);
+
+                if action.flags & SA_RESETHAND != 0 {
+                    state.actions[signal as usize] = SigAction::default();
+                }
+                return;
+            }
+        }
+    }
+}
+
+fn setup_frame(
+    frame: &mut UserFrame,
+    signal: Signal,
+    info: SigInfo,
+    action: &SigAction,
+    blocked: SigSet,
+) -> Result<(), uaccess::Fault> {
+    // Skip the red zone, then align as if restorer had been pushed by a call
+    let size = mem::size_of::<SignalFrame>();
+    let addr = frame.rsp.checked_sub(128 + size).ok_or(uaccess::Fault)?;
+    let addr = (addr & !0xF).wrapping_sub(8);
+
+    let signal_frame = SignalFrame {
+        restorer: action.restorer,
+        info,
+        context: SignalContext {
+            regs: *frame,
+            blocked,
+        },
+    };
+    unsafe { uaccess::write_user(VirtAddr::new(addr), &signal_frame)? };
+
+    frame.rip = action.handler;
+    frame.rsp = addr;
+    frame.rdi = signal as usize;
+    frame.rsi = addr + offset_of_info();
+    frame.rdx = addr + offset_of_context();
+    frame.rax = 0;
+    // sysret takes rip and rflags from rcx and r11
+    frame.rflags &= !0x500;
+    frame.rcx = frame.rip;
+    frame.r11 = frame.rflags;
+
+    Ok(())
+}
+
+fn offset_of_info() -> usize {
+    mem::size_of::<usize>()
+}
+
+fn offset_of_context() -> usize {
+    offset_of_info() + mem::size_of::<SigInfo>()
+}
+
+// Restores the context saved by setup_frame. The handler has returned into the
+// restorer, which popped the return address.
+pub fn sigreturn(frame: &mut UserFrame) -> Result<usize, Errno> {
+    let process = current().ok_or(Errno::EINVAL)?;
+    let addr = frame.rsp.wrapping_sub(mem::size_of::<usize>()).wrapping_add(offset_of_context());
+    let context: SignalContext = unsafe { uaccess::read_user(VirtAddr::new(addr))? };
+
+    // Nothing that would fault in the kernel on the way out
+    let regs = context.regs;
+    if regs.rip >= USER_END || regs.rsp >= USER_END {
+        return Err(Errno::EFAULT);
+    }
+
+    *frame = UserFrame {
+        cs: frame.cs,
+        ss: frame.ss,
+        // Interrupts stay as they were when the process made the syscall
+        rflags: (regs.rflags & USER_RFLAGS_MASK) | (frame.rflags & INTERRUPT_FLAG) | 0x2,
+        ..regs
+    };
+    process.signals.lock().set_blocked(context.blocked);
+
+    // The result goes into rax
+    Ok(frame.rax)
+}
+
+// Logs the state of a process killed by a signal with a core action
+fn dump_core(process: &Process, signal: Signal, frame: &UserFrame) {
+    info!("signal: process {} ({}) killed by {:?}, dumping core", process.pid(), process.name(), signal);
+    info!("  rip {:#018x}  rsp {:#018x}  rflags {:#x}", frame.rip, frame.rsp, frame.rflags);
+    info!("  rax {:#018x}  rbx {:#018x}  rcx {:#018x}", frame.rax, frame.rbx, frame.rcx);
+    info!("  rdx {:#018x}  rsi {:#018x}  rdi {:#018x}", frame.rdx, frame.rsi, frame.rdi);
+    info!("  rbp {:#018x}  r8  {:#018x}  r9  {:#018x}", frame.rbp, frame.r8, frame.r9);
+    info!("  r10 {:#018x}  r11 {:#018x}  r12 {:#018x}", frame.r10, frame.r11, frame.r12);
+    info!("  r13 {:#018x}  r14 {:#018x}  r15 {:#018x}", frame.r13, frame.r14, frame.r15);
+
+    let mut stack = [0u8; 64];
+    if uaccess::copy_from_user(&mut stack, VirtAddr::new(frame.rsp)).is_ok() {
+        for (i, line) in stack.chunks(16).enumerate() {
+            info!("  stack+{:02x}: {:02x?}", i * 16, line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+    use super::*;
+    use crate::elf::{builder::ElfBuilder, PF_R, PF_X};
+
+    // Catches SIGUSR1 with a handler which sets the interrupted rbx to 42
+    // through the saved context. The signal is sent while blocked, so it only
+    // arrives once unblocked. Exits with rbx, or 1 if anything goes wrong.
+    #[rustfmt::skip]
+    const CATCH_USR1: [u8; 200] = [
+        0x48, 0x8D, 0x35, 0x99, 0x00, 0x00, 0x00, // lea act(%rip), %rsi
+        0xBF, 0x0A, 0x00, 0x00, 0x00,           // mov $10, %edi
+        0x31, 0xD2,                             // xor %edx, %edx
+        0x41, 0xBA, 0x08, 0x00, 0x00, 0x00,     // mov $8, %r10d
+        0xB8, 0x0D, 0x00, 0x00, 0x00,           // mov $13, %eax
+        0x0F, 0x05,                             // syscall
+        0x48, 0x85, 0xC0,                       // test %rax, %rax
+        0x75, 0x58,                             // jnz fail
+        0x31, 0xFF,                             // xor %edi, %edi
+        0x48, 0x8D, 0x35, 0x97, 0x00, 0x00, 0x00, // lea usr1(%rip), %rsi
+        0x31, 0xD2,                             // xor %edx, %edx
+        0x41, 0xBA, 0x08, 0x00, 0x00, 0x00,     // mov $8, %r10d
+        0xB8, 0x0E, 0x00, 0x00, 0x00,           // mov $14, %eax
+        0x0F, 0x05,                             // syscall
+        0xB8, 0x27, 0x00, 0x00, 0x00,           // mov $39, %eax
+        0x0F, 0x05,                             // syscall
+        0x48, 0x89, 0xC7,                       // mov %rax, %rdi
+        0xBE, 0x0A, 0x00, 0x00, 0x00,           // mov $10, %esi
+        0xB8, 0x3E, 0x00, 0x00, 0x00,           // mov $62, %eax
+        0x0F, 0x05,                             // syscall
+        0x48, 0x85, 0xC0,                       // test %rax, %rax
+        0x75, 0x25,                             // jnz fail
+        0x48, 0x85, 0xDB,                       // test %rbx, %rbx
+        0x75, 0x20,                             // jnz fail
+        0xBF, 0x01, 0x00, 0x00, 0x00,           // mov $1, %edi
+        0x48, 0x8D, 0x35, 0x5C, 0x00, 0x00, 0x00, // lea usr1(%rip), %rsi
+        0x31, 0xD2,                             // xor %edx, %edx
+        0x41, 0xBA, 0x08, 0x00, 0x00, 0x00,     // mov $8, %r10d
+        0xB8, 0x0E, 0x00, 0x00, 0x00,           // mov $14, %eax
+        0x0F, 0x05,                             // syscall
+        0x48, 0x89, 0xDF,                       // mov %rbx, %rdi
+        0xEB, 0x05,                             // jmp exit
+        0xBF, 0x01, 0x00, 0x00, 0x00,           // fail: mov $1, %edi
+        0xB8, 0x3C, 0x00, 0x00, 0x00,           // exit: mov $60, %eax
+        0x0F, 0x05,                             // syscall
+        0x83, 0xFF, 0x0A,                       // handler: cmp $10, %edi
+        0x75, 0x08,                             // jnz 1f
+        0x48, 0xC7, 0x42, 0x28, 0x2A, 0x00, 0x00, 0x00, // movq $42, 40(%rdx)
+        0xC3,                                   // 1: ret
+        0xB8, 0x0F, 0x00, 0x00, 0x00,           // restorer: mov $15, %eax
+        0x0F, 0x05,                             // syscall
+        0x0F, 0x1F, 0x80, 0x00, 0x00, 0x00, 0x00, // nop
+        0x84, 0x00, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, // act: handler
+        0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, // SA_RESTORER
+        0x92, 0x00, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, // restorer
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mask
+        0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // usr1: SIGUSR1
+    ];
+
+    // Reads from 0x1000 with a SIGSEGV handler, which exits with 7 if the
+    // fault address is right and 1 otherwise
+    #[rustfmt::skip]
+    const CATCH_SEGV: [u8; 96] = [
+        0x48, 0x8D, 0x35, 0x39, 0x00, 0x00, 0x00, // lea act(%rip), %rsi
+        0xBF, 0x0B, 0x00, 0x00, 0x00,           // mov $11, %edi
+        0x31, 0xD2,                             // xor %edx, %edx
+        0x41, 0xBA, 0x08, 0x00, 0x00, 0x00,     // mov $8, %r10d
+        0xB8, 0x0D, 0x00, 0x00, 0x00,           // mov $13, %eax
+        0x0F, 0x05,                             // syscall
+        0x48, 0x8B, 0x04, 0x25, 0x00, 0x10, 0x00, 0x00, // mov 0x1000, %rax
+        0xEB, 0xFE,                             // jmp .
+        0xBF, 0x07, 0x00, 0x00, 0x00,           // handler: mov $7, %edi
+        0x48, 0x81, 0x7E, 0x10, 0x00, 0x10, 0x00, 0x00, // cmpq $0x1000, 16(%rsi)
+        0x74, 0x05,                             // je exit
+        0xBF, 0x01, 0x00, 0x00, 0x00,           // mov $1, %edi
+        0xB8, 0x3C, 0x00, 0x00, 0x00,           // exit: mov $60, %eax
+        0x0F, 0x05,                             // syscall
+        0x25, 0x00, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, // act: handler
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // flags
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // restorer
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mask
+    ];
+
+    // movl $1, 0; jmp .
+    const WRITE_NULL: [u8; 13] = [
+        0xC7, 0x04, 0x25, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0xEB, 0xFE,
+    ];
+
+    fn run(code: &[u8]) -> ExitStatus {
+        let data = ElfBuilder::new(0x40_0000)
+            .segment(0x40_0000, code, 0x1000, PF_R | PF_X)
+            .build();
+
+        let process = spawn_elf("signals", &data, &[b"signals"], &[]).unwrap();
+        let (pid, status) = wait(Some(process.pid())).unwrap();
+        assert_eq!(pid, process.pid());
+        status
+    }
+
+    fn handler(addr: usize) -> SigAction {
+        SigAction {
+            handler: addr,
+            ..SigAction::default()
+        }
+    }
+
+    test_case!(sigset_bits, {
+        let mut set = SigSet::empty();
+        assert_eq!(set.first(), None);
+        set.insert(Signal::SIGTERM);
+        set.insert(Signal::SIGKILL);
+        assert!(set.contains(Signal::SIGKILL));
+        assert_eq!(set.first(), Some(Signal::SIGKILL));
+        assert_eq!(set.catchable(), SigSet(1 << (Signal::SIGTERM as u64 - 1)));
+        assert_eq!(Signal::from_number(0), None);
+        assert_eq!(Signal::from_number(32), None);
+    });
+
+    test_case!(queue_and_mask, {
+        let mut state = SignalState::new();
+        state.queue(Signal::SIGUSR2, SigInfo::new(Signal::SIGUSR2, SI_USER, 0));
+        state.queue(Signal::SIGUSR1, SigInfo::new(Signal::SIGUSR1, SI_USER, 1));
+        state.queue(Signal::SIGUSR1, SigInfo::new(Signal::SIGUSR1, SI_USER, 2));
+
+        // Ignored by default, so dropped unless blocked
+        state.queue(Signal::SIGCHLD, SigInfo::new(Signal::SIGCHLD, SI_USER, 0));
+        assert!(!state.pending().contains(Signal::SIGCHLD));
+
+        state.set_blocked(SigSet(!0));
+        assert_eq!(state.blocked(), SigSet(!0).catchable());
+        assert_eq!(state.dequeue(), None);
+
+        state.set_blocked(SigSet::empty());
+        let (signal, info) = state.dequeue().unwrap();
+        assert_eq!((signal, info.addr), (Signal::SIGUSR1, 1));
+        assert_eq!(state.dequeue().map(|(signal, _)| signal), Some(Signal::SIGUSR2));
+        assert_eq!(state.dequeue(), None);
+    });
+
+    test_case!(actions_across_fork_and_exec, {
+        let mut state = SignalState::new();
+        assert_eq!(state.set_action(Signal::SIGKILL, handler(0x1000)), Err(Errno::EINVAL));
+        assert_eq!(state.set_action(Signal::SIGSTOP, handler(SIG_IGN)), Err(Errno::EINVAL));
+        assert_eq!(state.set_action(Signal::SIGUSR1, handler(USER_END)), Err(Errno::EFAULT));
+        let kernel_restorer = SigAction {
+            restorer: !0,
+            ..handler(0x1000)
+        };
+        assert_eq!(state.set_action(Signal::SIGUSR1, kernel_restorer), Err(Errno::EFAULT));
+
+        state.set_action(Signal::SIGUSR1, handler(0x1000)).unwrap();
+        state.set_action(Signal::SIGINT, handler(SIG_IGN)).unwrap();
+        state.set_blocked(SigSet(1 << (Signal::SIGUSR2 as u64 - 1)));
+        state.queue(Signal::SIGUSR2, SigInfo::new(Signal::SIGUSR2, SI_USER, 0));
+
+        let mut child = state.fork();
+        assert_eq!(child.action(Signal::SIGUSR1).handler, 0x1000);
+        assert_eq!(child.blocked(), state.blocked());
+        assert_eq!(child.pending(), SigSet::empty());
+
+        child.exec();
+        assert_eq!(child.action(Signal::SIGUSR1).handler, SIG_DFL);
+        assert_eq!(child.action(Signal::SIGINT).handler, SIG_IGN);
+    });
+
+    test_case!(handler_runs_and_returns, {
+        assert_eq!(run(&CATCH_USR1), ExitStatus::Exited(42));
+    });
+
+    test_case!(fault_handler_gets_address, {
+        assert_eq!(run(&CATCH_SEGV), ExitStatus::Exited(7));
+    });
+
+    test_case!(fault_dumps_core, {
+        let status = run(&WRITE_NULL);
+        assert_eq!(status, ExitStatus::Signaled(Signal::SIGSEGV, true));
+        assert_eq!(status.wait_status(), 0x8B);
+    });
+}
@@ -0,0 +1,296 @@
+// The syscall instruction lands here. It leaves the user stack in place, so
+// the stub swaps in the kernel's gs base to find the current thread's kernel
+// stack (see PerCpu), saves the user state there and calls into Rust.
+//
+// Exceptions from ring 3 which raise a signal come through here as well, see
+// redirect_trap, so that every way back to user mode goes past the signal code
+// with the complete user state at hand.
+use super::dispatch;
+use crate::{
+    cpu::{
+        gdt::{KERNEL_CODE_SELECTOR, KERNEL_DATA_SELECTOR, USER_CODE_SELECTOR, USER_DATA_SELECTOR},
+        percpu::PerCpu,
+    },
+    mm::uaccess::USER_END,
+    process::{self, signal},
+    task,
+};
+use core::mem;
+use x86_64::{
+    registers::{
+        model_specific::{Efer, EferFlags, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    structures::idt::InterruptStackFrame,
+    VirtAddr,
+};
+
+// Saved user state, in the order the stubs push it (last pushed first). The
+// last five fields form an interrupt frame, which iretq returns through.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct UserFrame {
+    pub r15: usize,
+    pub r14: usize,
+    pub r13: usize,
+    pub r12: usize,
+    pub rbp: usize,
+    pub rbx: usize,
+    pub r11: usize,
+    pub r10: usize,
+    pub r9: usize,
+    pub r8: usize,
+    pub rdi: usize,
+    pub rsi: usize,
+    pub rdx: usize,
+    pub rcx: usize,
+    pub rax: usize,
+    pub rip: usize,
+    pub cs: usize,
+    pub rflags: usize,
+    pub rsp: usize,
+    pub ss: usize,
+}
+
+// gs:0 is PerCpu::kernel_stack, gs:8 is PerCpu::user_stack. Interrupts stay
+// disabled throughout through SFMASK. 0x23 and 0x1B are the user code and data
+// selectors.
+//
+// Most syscalls return with sysret, which takes rip and rflags from rcx and
+// r11. When the handler leaves different values in those (after sigreturn),
+// user_return restores everything with iretq instead. So does a return to the
+// end of user space, such as after a syscall in the last bytes of a segment:
+// sysret faults in ring 0 on the user stack if rip isn't canonical, while iretq
+// faults in ring 3.
+//
+// user_trap_entry is entered from an exception handler, on an empty kernel
+// stack and with the user's registers and gs base still live. The interrupt
+// frame is filled in by user_trap_handler.
+global_asm!("
+    .global syscall_entry
+syscall_entry:
+    swapgs
+    mov %rsp, %gs:8
+    mov %gs:0, %rsp
+
+    pushq $0x1B
+    pushq %gs:8
+    push %r11
+    pushq $0x23
+    push %rcx
+    push %rax
+    push %rcx
+    push %rdx
+    push %rsi
+    push %rdi
+    push %r8
+    push %r9
+    push %r10
+    push %r11
+    push %rbx
+    push %rbp
+    push %r12
+    push %r13
+    push %r14
+    push %r15
+
+    mov %rsp, %rdi
+    call syscall_handler
+    test %al, %al
+    jnz user_return
+
+    .global syscall_return
+syscall_return:
+    pop %r15
+    pop %r14
+    pop %r13
+    pop %r12
+    pop %rbp
+    pop %rbx
+    pop %r11
+    pop %r10
+    pop %r9
+    pop %r8
+    pop %rdi
+    pop %rsi
+    pop %rdx
+    pop %rcx
+    pop %rax
+
+    mov (%rsp), %rcx
+    mov 16(%rsp), %r11
+    mov 24(%rsp), %rsp
+    swapgs
+    sysretq
+
+    .global user_return
+user_return:
+    pop %r15
+    pop %r14
+    pop %r13
+    pop %r12
+    pop %rbp
+    pop %rbx
+    pop %r11
+    pop %r10
+    pop %r9
+    pop %r8
+    pop %rdi
+    pop %rsi
+    pop %rdx
+    pop %rcx
+    pop %rax
+
+    swapgs
+    iretq
+
+    .global user_trap_entry
+user_trap_entry:
+    sub $40, %rsp
+    push %rax
+    push %rcx
+    push %rdx
+    push %rsi
+    push %rdi
+    push %r8
+    push %r9
+    push %r10
+    push %r11
+    push %rbx
+    push %rbp
+    push %r12
+    push %r13
+    push %r14
+    push %r15
+
+    swapgs
+    mov %rsp, %rdi
+    call user_trap_handler
+    jmp user_return
+");
+
+extern "C" {
+    fn syscall_entry();
+    fn user_return();
+    fn user_trap_entry();
+}
+
+// The frame of the syscall the current thread is in, which is always at the top
+// of its kernel stack. Only meaningful while handling a syscall from ring 3.
+pub unsafe fn current_frame() -> &'static mut UserFrame {
+    let top = task::current().kernel_stack_top().expect("syscall: boot thread has no frame");
+    &mut *(top - mem::size_of::<UserFrame>()).as_mut_ptr()
+}
+
+// Leaves for ring 3 with the given state. Like enter_user, nothing on the
+// current stack gets dropped.
+pub unsafe fn return_to_user(frame: &UserFrame) -> ! {
+    let frame = *frame;
+    asm!("
+        mov $0, %rsp
+        jmp *$1"
+        :
+        : "r"(&frame), "r"(user_return as usize)
+        : "memory"
+        : "volatile");
+
+    unreachable!();
+}
+
+// Whether returning to the frame needs iretq
+fn needs_iret(frame: &UserFrame) -> bool {
+    frame.rcx != frame.rip || frame.r11 != frame.rflags || frame.rip >= USER_END
+}
+
+#[no_mangle]
+extern "C" fn syscall_handler(frame: &mut UserFrame) -> bool {
+    let args = [frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9];
+    frame.rax = dispatch(frame.rax, args) as usize;
+
+    // Another thread may have exited the process meanwhile
+    process::check_exit();
+    signal::deliver(frame);
+
+    needs_iret(frame)
+}
+
+// Makes an exception handler return into user_trap_entry rather than to ring
+// 3, once the exception was turned into a signal. The user's interrupt frame
+// is kept in PerCpu until then, interrupts stay off in between.
+pub fn redirect_trap(frame: &mut InterruptStackFrame) {
+    let cpu = PerCpu::current();
+    let stack = cpu.kernel_stack();
+
+    unsafe {
+        let user = frame.as_mut();
+        cpu.save_trap_frame([
+            user.instruction_pointer.as_usize(),
+            user.code_segment as usize,
+            user.cpu_flags as usize,
+            user.stack_pointer.as_usize(),
+            user.stack_segment as usize,
+        ]);
+
+        user.instruction_pointer = VirtAddr::new(user_trap_entry as usize);
+        user.code_segment = u64::from(KERNEL_CODE_SELECTOR.0);
+        user.cpu_flags = 0x2;
+        user.stack_pointer = stack;
+        user.stack_segment = u64::from(KERNEL_DATA_SELECTOR.0);
+    }
+}
+
+#[no_mangle]
+extern "C" fn user_trap_handler(frame: &mut UserFrame) {
+    let [rip, cs, rflags, rsp, ss] = unsafe { PerCpu::current().take_trap_frame() };
+    frame.rip = rip;
+    frame.cs = cs;
+    frame.rflags = rflags;
+    frame.rsp = rsp;
+    frame.ss = ss;
+
+    process::check_exit();
+    signal::deliver(frame);
+}
+
+pub fn init() {
+    unsafe {
+        Star::write(USER_CODE_SELECTOR, USER_DATA_SELECTOR, KERNEL_CODE_SELECTOR, KERNEL_DATA_SELECTOR)
+            .expect("syscall: bad GDT layout");
+        LStar::write(VirtAddr::new(syscall_entry as usize));
+
+        // Start the handler with interrupts off and a clean direction flag. AC
+        // is cleared too, since the user setting it would turn off SMAP.
+        SFMask::write(
+            RFlags::INTERRUPT_FLAG
+                | RFlags::DIRECTION_FLAG
+                | RFlags::TRAP_FLAG
+                | RFlags::ALIGNMENT_CHECK,
+        );
+
+        Efer::update(|flags| *flags |= EferFlags::SYSTEM_CALL_EXTENSIONS);
+    }
+
+    debug!("syscall: initialized");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    test_case!(stub_selectors, {
+        assert_eq!(USER_CODE_SELECTOR.0, 0x23);
+        assert_eq!(USER_DATA_SELECTOR.0, 0x1B);
+    });
+
+    test_case!(iret_past_user_end, {
+        let mut frame: UserFrame = unsafe { mem::zeroed() };
+        frame.rip = 0x40_0000;
+        frame.rcx = frame.rip;
+        assert!(!needs_iret(&frame));
+
+        // A syscall in the last two bytes of user space
+        frame.rip = USER_END;
+        frame.rcx = frame.rip;
+        assert!(needs_iret(&frame));
+    });
+}
@@ -0,0 +1,321 @@
+// File syscalls, on top of the VFS and the current process's descriptors
+use super::{copy_path, Errno, SyscallResult, UserPtr};
+use crate::{
+    fs::{self, fd, DirEntry, Metadata, SeekFrom, O_CLOEXEC},
+    mm::uaccess,
+};
+use alloc::{string::String, vec};
+use core::{cmp, mem};
+
+// lseek whence
+pub const SEEK_SET: i32 = 0;
+pub const SEEK_CUR: i32 = 1;
+pub const SEEK_END: i32 = 2;
+
+// Largest buffer getdents fills in one go
+const DIRENTS_MAX: usize = 32 * 1024;
+
+// struct stat
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stat {
+    pub st_dev: u64,
+    pub st_ino: u64,
+    pub st_nlink: u64,
+    pub st_mode: u32,
+    pub st_uid: u32,
+    pub st_gid: u32,
+    _pad0: u32,
+    pub st_rdev: u64,
+    pub st_size: i64,
+    pub st_blksize: i64,
+    pub st_blocks: i64,
+    pub st_atime: u64,
+    pub st_atime_nsec: u64,
+    pub st_mtime: u64,
+    pub st_mtime_nsec: u64,
+    pub st_ctime: u64,
+    pub st_ctime_nsec: u64,
+    _unused: [i64; 3],
+}
+
+impl From<Metadata> for Stat {
+    fn from(metadata: Metadata) -> Stat {
+        Stat {
+            st_ino: metadata.ino,
+            st_nlink: u64::from(metadata.nlink),
+            st_mode: metadata.file_type.mode_bits() | u32::from(metadata.mode),
+            st_rdev: metadata.rdev,
+            st_size: metadata.size as i64,
+            st_blksize: i64::from(metadata.blksize),
+            st_blocks: metadata.blocks as i64,
+            st_atime: metadata.atime,
+            st_mtime: metadata.mtime,
+            st_ctime: metadata.ctime,
+            ..Stat::default()
+        }
+    }
+}
+
+fn copy_path_str(ptr: UserPtr) -> Result<String, Errno> {
+    String::from_utf8(copy_path(ptr)?).map_err(|_| Errno::ENOENT)
+}
+
+pub fn sys_read(fd: i32, buf: UserPtr, count: usize) -> SyscallResult {
+    let file = fd::get(fd)?;
+    let mut chunk = [0u8; 512];
+    let mut done = 0;
+    while done < count {
+        let want = cmp::min(chunk.len(), count - done);
+        let len = file.read(&mut chunk[..want])?;
+        uaccess::copy_to_user(buf.offset(done)?.addr(), &chunk[..len])?;
+        done += len;
+
+        if len < want {
+            break;
+        }
+    }
+
+    Ok(done)
+}
+
+pub fn sys_write(fd: i32, buf: UserPtr, count: usize) -> SyscallResult {
+    let file = fd::get(fd)?;
+    let mut chunk = [0u8; 512];
+    let mut done = 0;
+    while done < count {
+        let want = cmp::min(chunk.len(), count - done);
+        uaccess::copy_from_user(&mut chunk[..want], buf.offset(done)?.addr())?;
+        let len = file.write(&chunk[..want])?;
+        done += len;
+
+        if len < want {
+            break;
+        }
+    }
+
+    Ok(done)
+}
+
+pub fn sys_open(path: UserPtr, flags: u32, mode: u32) -> SyscallResult {
+    let path = copy_path_str(path)?;
+    let file = fs::open(&path, flags, (mode & 0o7777) as u16)?;
+    Ok(fd::insert(file, flags & O_CLOEXEC != 0)? as usize)
+}
+
+pub fn sys_close(fd: i32) -> SyscallResult {
+    fd::close(fd)?;
+    Ok(0)
+}
+
+pub fn sys_stat(path: UserPtr, statbuf: UserPtr) -> SyscallResult {
+    let path = copy_path_str(path)?;
+    let stat = Stat::from(fs::stat(&path)?);
+    unsafe { uaccess::write_user(statbuf.addr(), &stat)? };
+    Ok(0)
+}
+
+pub fn sys_lstat(path: UserPtr, statbuf: UserPtr) -> SyscallResult {
+    let path = copy_path_str(path)?;
+    let stat = Stat::from(fs::lstat(&path)?);
+    unsafe { uaccess::write_user(statbuf.addr(), &stat)? };
+    Ok(0)
+}
+
+pub fn sys_fstat(fd: i32, statbuf: UserPtr) -> SyscallResult {
+    let stat = Stat::from(fd::get(fd)?.metadata()?);
+    unsafe { uaccess::write_user(statbuf.addr(), &stat)? };
+    Ok(0)
+}
+
+pub fn sys_lseek(fd: i32, offset: isize, whence: i32) -> SyscallResult {
+    let file = fd::get(fd)?;
+    let pos = match whence {
+        SEEK_SET if offset >= 0 => SeekFrom::Start(offset as u64),
+        SEEK_CUR => SeekFrom::Current(offset as i64),
+        SEEK_END => SeekFrom::End(offset as i64),
+        _ => return Err(Errno::EINVAL),
+    };
+    Ok(file.seek(pos)? as usize)
+}
+
+pub fn sys_mkdir(path: UserPtr, mode: u32) -> SyscallResult {
+    let path = copy_path_str(path)?;
+    fs::mkdir(&path, (mode & 0o7777) as u16)?;
+    Ok(0)
+}
+
+pub fn sys_rmdir(path: UserPtr) -> SyscallResult {
+    let path = copy_path_str(path)?;
+    fs::rmdir(&path)?;
+    Ok(0)
+}
+
+pub fn sys_link(old: UserPtr, new: UserPtr) -> SyscallResult {
+    let old = copy_path_str(old)?;
+    let new = copy_path_str(new)?;
+    fs::link(&old, &new)?;
+    Ok(0)
+}
+
+pub fn sys_unlink(path: UserPtr) -> SyscallResult {
+    let path = copy_path_str(path)?;
+    fs::unlink(&path)?;
+    Ok(0)
+}
+
+pub fn sys_symlink(target: UserPtr, path: UserPtr) -> SyscallResult {
+    let target = copy_path_str(target)?;
+    let path = copy_path_str(path)?;
+    fs::symlink(&target, &path)?;
+    Ok(0)
+}
+
+// Like Linux, the target isn't terminated and is cut off at bufsiz
+pub fn sys_readlink(path: UserPtr, buf: UserPtr, bufsiz: usize) -> SyscallResult {
+    if bufsiz == 0 || bufsiz > isize::max_value() as usize {
+        return Err(Errno::EINVAL);
+    }
+
+    let path = copy_path_str(path)?;
+    let target = fs::readlink(&path)?;
+    let len = cmp::min(target.len(), bufsiz);
+    uaccess::copy_to_user(buf.addr(), &target.as_bytes()[..len])?;
+    Ok(len)
+}
+
+pub fn sys_fsync(fd: i32) -> SyscallResult {
+    fd::get(fd)?.sync()?;
+    Ok(0)
+}
+
+// Metadata goes out through the same cache as data, so there's no cheaper way
+pub fn sys_fdatasync(fd: i32) -> SyscallResult {
+    sys_fsync(fd)
+}
+
+pub fn sys_truncate(path: UserPtr, length: isize) -> SyscallResult {
+    if length < 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    let path = copy_path_str(path)?;
+    fs::truncate(&path, length as u64)?;
+    Ok(0)
+}
+
+pub fn sys_ftruncate(fd: i32, length: isize) -> SyscallResult {
+    if length < 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    fd::get(fd)?.truncate(length as u64)?;
+    Ok(0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DirentLayout {
+    // struct linux_dirent, with d_type in the last byte
+    Old,
+    // struct linux_dirent64
+    New,
+}
+
+// Encodes an entry into buf, returning its length or None if it doesn't fit.
+// next is the d_off to continue from.
+pub fn encode_dirent(entry: &DirEntry, next: u64, layout: DirentLayout, buf: &mut [u8]) -> Option<usize> {
+    // Both start with d_ino, d_off and d_reclen
+    let header = 2 * mem::size_of::<u64>() + mem::size_of::<u16>();
+    let name = entry.name.as_bytes();
+    let len = match layout {
+        DirentLayout::Old => header + name.len() + 2,
+        DirentLayout::New => header + 1 + name.len() + 1,
+    };
+    let len = (len + 7) & !7;
+    if len > buf.len() || len > usize::from(u16::max_value()) {
+        return None;
+    }
+
+    let buf = &mut buf[..len];
+    for byte in buf.iter_mut() {
+        *byte = 0;
+    }
+    buf[0..8].copy_from_slice(&entry.ino.to_ne_bytes());
+    buf[8..16].copy_from_slice(&next.to_ne_bytes());
+    buf[16..18].copy_from_slice(&(len as u16).to_ne_bytes());
+    match layout {
+        DirentLayout::Old => {
+            buf[header..header + name.len()].copy_from_slice(name);
+            buf[len - 1] = entry.file_type.dirent_type();
+        }
+        DirentLayout::New => {
+            buf[header] = entry.file_type.dirent_type();
+            buf[header + 1..header + 1 + name.len()].copy_from_slice(name);
+        }
+    }
+    Some(len)
+}
+
+fn getdents(fd: i32, dirp: UserPtr, count: usize, layout: DirentLayout) -> SyscallResult {
+    let file = fd::get(fd)?;
+    let mut buf = vec![0; cmp::min(count, DIRENTS_MAX)];
+    let mut used = 0;
+
+    loop {
+        let position = file.seek(SeekFrom::Current(0))?;
+        let entry = match file.read_dir()? {
+            Some(entry) => entry,
+            None => break,
+        };
+
+        match encode_dirent(&entry, position + 1, layout, &mut buf[used..]) {
+            Some(len) => used += len,
+            None => {
+                // Put it back for next time
+                file.seek(SeekFrom::Start(position))?;
+                if used == 0 {
+                    return Err(Errno::EINVAL);
+                }
+                break;
+            }
+        }
+    }
+
+    uaccess::copy_to_user(dirp.addr(), &buf[..used])?;
+    Ok(used)
+}
+
+pub fn sys_getdents(fd: i32, dirp: UserPtr, count: usize) -> SyscallResult {
+    getdents(fd, dirp, count, DirentLayout::Old)
+}
+
+pub fn sys_getdents64(fd: i32, dirp: UserPtr, count: usize) -> SyscallResult {
+    getdents(fd, dirp, count, DirentLayout::New)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::FileType;
+
+    test_case!(dirent_layouts, {
+        let entry = DirEntry {
+            ino: 7,
+            name: String::from("hello"),
+            file_type: FileType::Regular,
+        };
+
+        let mut buf = [0xFF; 32];
+        assert_eq!(encode_dirent(&entry, 3, DirentLayout::New, &mut buf), Some(32));
+        assert_eq!(&buf[..8], &7u64.to_ne_bytes());
+        assert_eq!(&buf[8..16], &3u64.to_ne_bytes());
+        assert_eq!(&buf[16..19], &[32, 0, 8]);
+        assert_eq!(&buf[19..25], b"hello\0");
+
+        assert_eq!(encode_dirent(&entry, 3, DirentLayout::Old, &mut buf), Some(32));
+        assert_eq!(&buf[18..24], b"hello\0");
+        assert_eq!(buf[31], 8);
+
+        assert_eq!(encode_dirent(&entry, 3, DirentLayout::New, &mut buf[..31]), None);
+    });
+}
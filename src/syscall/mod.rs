@@ -0,0 +1,585 @@
+// System calls. Numbers and calling convention follow Linux on x86_64: the
+// number goes in rax, arguments in rdi, rsi, rdx, r10, r8 and r9, and the
+// result comes back in rax, with errors returned as -errno.
+use crate::{
+    elf::{self, load::MAX_ARGS_SIZE, ElfError},
+    mm::uaccess::{self, USER_END},
+    process::{
+        self,
+        signal::{self, MaskHow, SigAction, SigSet, Signal},
+        Pid,
+    },
+    task,
+};
+use alloc::{vec, vec::Vec};
+use core::{mem, time::Duration};
+use x86_64::VirtAddr;
+
+pub mod entry;
+pub mod errno;
+pub mod fs;
+
+pub use entry::init;
+pub use errno::Errno;
+
+use self::fs::{
+    sys_close, sys_fdatasync, sys_fstat, sys_fsync, sys_ftruncate, sys_getdents, sys_getdents64, sys_link,
+    sys_lseek, sys_lstat, sys_mkdir, sys_open, sys_read, sys_readlink, sys_rmdir, sys_stat, sys_symlink,
+    sys_truncate, sys_unlink, sys_write,
+};
+
+pub type SyscallResult = Result<usize, Errno>;
+
+pub const SYS_READ: usize = 0;
+pub const SYS_WRITE: usize = 1;
+pub const SYS_OPEN: usize = 2;
+pub const SYS_CLOSE: usize = 3;
+pub const SYS_STAT: usize = 4;
+pub const SYS_FSTAT: usize = 5;
+pub const SYS_LSTAT: usize = 6;
+pub const SYS_LSEEK: usize = 8;
+pub const SYS_RT_SIGACTION: usize = 13;
+pub const SYS_RT_SIGPROCMASK: usize = 14;
+pub const SYS_RT_SIGRETURN: usize = 15;
+pub const SYS_SCHED_YIELD: usize = 24;
+pub const SYS_GETPID: usize = 39;
+pub const SYS_FORK: usize = 57;
+pub const SYS_EXECVE: usize = 59;
+pub const SYS_EXIT: usize = 60;
+pub const SYS_WAIT4: usize = 61;
+pub const SYS_KILL: usize = 62;
+pub const SYS_FSYNC: usize = 74;
+pub const SYS_FDATASYNC: usize = 75;
+pub const SYS_TRUNCATE: usize = 76;
+pub const SYS_FTRUNCATE: usize = 77;
+pub const SYS_GETDENTS: usize = 78;
+pub const SYS_MKDIR: usize = 83;
+pub const SYS_RMDIR: usize = 84;
+pub const SYS_LINK: usize = 86;
+pub const SYS_UNLINK: usize = 87;
+pub const SYS_SYMLINK: usize = 88;
+pub const SYS_READLINK: usize = 89;
+pub const SYS_GETPPID: usize = 110;
+pub const SYS_FUTEX: usize = 202;
+pub const SYS_GETDENTS64: usize = 217;
+pub const SYS_EXIT_GROUP: usize = 231;
+
+// wait4 options
+pub const WNOHANG: u32 = 1;
+
+// rt_sigprocmask operations
+pub const SIG_BLOCK: i32 = 0;
+pub const SIG_UNBLOCK: i32 = 1;
+pub const SIG_SETMASK: i32 = 2;
+
+// futex operations. Private futexes are keyed like shared ones, and timeouts
+// are always measured on the monotonic clock.
+pub const FUTEX_WAIT: i32 = 0;
+pub const FUTEX_WAKE: i32 = 1;
+pub const FUTEX_PRIVATE_FLAG: i32 = 128;
+
+// Longest path accepted from user space, including the terminator
+pub const PATH_MAX: usize = 4096;
+
+// Decodes a raw register value into a typed argument
+pub trait FromArg: Sized {
+    fn from_arg(arg: usize) -> Result<Self, Errno>;
+}
+
+impl FromArg for usize {
+    fn from_arg(arg: usize) -> Result<Self, Errno> {
+        Ok(arg)
+    }
+}
+
+impl FromArg for isize {
+    fn from_arg(arg: usize) -> Result<Self, Errno> {
+        Ok(arg as isize)
+    }
+}
+
+// Like C int arguments, only the low 32 bits count
+impl FromArg for i32 {
+    fn from_arg(arg: usize) -> Result<Self, Errno> {
+        Ok(arg as i32)
+    }
+}
+
+impl FromArg for u32 {
+    fn from_arg(arg: usize) -> Result<Self, Errno> {
+        Ok(arg as u32)
+    }
+}
+
+// A pointer into user memory. Only the range is checked here, accesses must
+// still go through mm::uaccess.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserPtr(VirtAddr);
+
+impl UserPtr {
+    pub fn addr(self) -> VirtAddr {
+        self.0
+    }
+
+    pub fn is_null(self) -> bool {
+        self.0.as_usize() == 0
+    }
+
+    pub fn offset(self, bytes: usize) -> Result<UserPtr, Errno> {
+        Self::from_arg(self.0.as_usize().checked_add(bytes).ok_or(Errno::EFAULT)?)
+    }
+}
+
+impl FromArg for UserPtr {
+    fn from_arg(arg: usize) -> Result<Self, Errno> {
+        if arg < USER_END {
+            Ok(UserPtr(VirtAddr::new(arg)))
+        } else {
+            Err(Errno::EFAULT)
+        }
+    }
+}
+
+// struct timespec
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+impl Timespec {
+    pub fn to_duration(self) -> Result<Duration, Errno> {
+        if self.tv_sec < 0 || self.tv_nsec < 0 || self.tv_nsec >= 1_000_000_000 {
+            return Err(Errno::EINVAL);
+        }
+        Ok(Duration::new(self.tv_sec as u64, self.tv_nsec as u32))
+    }
+}
+
+impl From<uaccess::Fault> for Errno {
+    fn from(_: uaccess::Fault) -> Errno {
+        Errno::EFAULT
+    }
+}
+
+impl From<ElfError> for Errno {
+    fn from(err: ElfError) -> Errno {
+        match err {
+            ElfError::OutOfMemory => Errno::ENOMEM,
+            ElfError::ArgumentsTooLarge => Errno::E2BIG,
+            _ => Errno::ENOEXEC,
+        }
+    }
+}
+
+fn call0(f: fn() -> SyscallResult, _: &[usize; 6]) -> SyscallResult {
+    f()
+}
+
+fn call1<A: FromArg>(f: fn(A) -> SyscallResult, args: &[usize; 6]) -> SyscallResult {
+    f(A::from_arg(args[0])?)
+}
+
+fn call2<A: FromArg, B: FromArg>(f: fn(A, B) -> SyscallResult, args: &[usize; 6]) -> SyscallResult {
+    f(A::from_arg(args[0])?, B::from_arg(args[1])?)
+}
+
+fn call3<A: FromArg, B: FromArg, C: FromArg>(
+    f: fn(A, B, C) -> SyscallResult,
+    args: &[usize; 6],
+) -> SyscallResult {
+    f(A::from_arg(args[0])?, B::from_arg(args[1])?, C::from_arg(args[2])?)
+}
+
+fn call4<A: FromArg, B: FromArg, C: FromArg, D: FromArg>(
+    f: fn(A, B, C, D) -> SyscallResult,
+    args: &[usize; 6],
+) -> SyscallResult {
+    f(A::from_arg(args[0])?, B::from_arg(args[1])?, C::from_arg(args[2])?, D::from_arg(args[3])?)
+}
+
+pub fn dispatch(nr: usize, args: [usize; 6]) -> isize {
+    let result = match nr {
+        SYS_READ => call3(sys_read, &args),
+        SYS_WRITE => call3(sys_write, &args),
+        SYS_OPEN => call3(sys_open, &args),
+        SYS_CLOSE => call1(sys_close, &args),
+        SYS_STAT => call2(sys_stat, &args),
+        SYS_FSTAT => call2(sys_fstat, &args),
+        SYS_LSTAT => call2(sys_lstat, &args),
+        SYS_LSEEK => call3(sys_lseek, &args),
+        SYS_RT_SIGACTION => call4(sys_rt_sigaction, &args),
+        SYS_RT_SIGPROCMASK => call4(sys_rt_sigprocmask, &args),
+        SYS_RT_SIGRETURN => call0(sys_rt_sigreturn, &args),
+        SYS_SCHED_YIELD => call0(sys_sched_yield, &args),
+        SYS_GETPID => call0(sys_getpid, &args),
+        SYS_FORK => call0(sys_fork, &args),
+        SYS_EXECVE => call3(sys_execve, &args),
+        SYS_EXIT => call1(sys_exit, &args),
+        SYS_WAIT4 => call4(sys_wait4, &args),
+        SYS_KILL => call2(sys_kill, &args),
+        SYS_FSYNC => call1(sys_fsync, &args),
+        SYS_FDATASYNC => call1(sys_fdatasync, &args),
+        SYS_TRUNCATE => call2(sys_truncate, &args),
+        SYS_FTRUNCATE => call2(sys_ftruncate, &args),
+        SYS_GETDENTS => call3(sys_getdents, &args),
+        SYS_MKDIR => call2(sys_mkdir, &args),
+        SYS_RMDIR => call1(sys_rmdir, &args),
+        SYS_LINK => call2(sys_link, &args),
+        SYS_UNLINK => call1(sys_unlink, &args),
+        SYS_SYMLINK => call2(sys_symlink, &args),
+        SYS_READLINK => call3(sys_readlink, &args),
+        SYS_GETPPID => call0(sys_getppid, &args),
+        SYS_FUTEX => call4(sys_futex, &args),
+        SYS_GETDENTS64 => call3(sys_getdents64, &args),
+        SYS_EXIT_GROUP => call1(sys_exit_group, &args),
+        _ => Err(Errno::ENOSYS),
+    };
+
+    trace!("syscall: {}({:#x?}) = {:?}", nr, args, result);
+
+    match result {
+        Ok(value) => value as isize,
+        Err(errno) => errno.as_return(),
+    }
+}
+
+// Signal sets are a single word, so sigsetsize must be 8
+fn sys_rt_sigaction(sig: i32, act: UserPtr, oldact: UserPtr, sigsetsize: usize) -> SyscallResult {
+    if sigsetsize != mem::size_of::<SigSet>() {
+        return Err(Errno::EINVAL);
+    }
+
+    let signal = Signal::from_number(sig as u32).ok_or(Errno::EINVAL)?;
+    let action = if act.is_null() {
+        None
+    } else {
+        Some(unsafe { uaccess::read_user::<SigAction>(act.addr())? })
+    };
+
+    let old = signal::sigaction(signal, action)?;
+    if !oldact.is_null() {
+        unsafe { uaccess::write_user(oldact.addr(), &old)? };
+    }
+    Ok(0)
+}
+
+fn sys_rt_sigprocmask(how: i32, set: UserPtr, oldset: UserPtr, sigsetsize: usize) -> SyscallResult {
+    if sigsetsize != mem::size_of::<SigSet>() {
+        return Err(Errno::EINVAL);
+    }
+
+    let set = if set.is_null() {
+        None
+    } else {
+        Some(unsafe { uaccess::read_user::<SigSet>(set.addr())? })
+    };
+    let how = match how {
+        SIG_BLOCK => MaskHow::Block,
+        SIG_UNBLOCK => MaskHow::Unblock,
+        SIG_SETMASK => MaskHow::SetMask,
+        _ => return Err(Errno::EINVAL),
+    };
+
+    let old = signal::sigprocmask(how, set)?;
+    if !oldset.is_null() {
+        unsafe { uaccess::write_user(oldset.addr(), &old)? };
+    }
+    Ok(0)
+}
+
+// Returns to wherever the signal interrupted, with rax as it was then
+fn sys_rt_sigreturn() -> SyscallResult {
+    // Kernel threads don't have a user frame
+    if process::current().is_none() {
+        return Err(Errno::EINVAL);
+    }
+
+    signal::sigreturn(unsafe { entry::current_frame() })
+}
+
+// Process groups aren't supported, so pid must be positive. Signal 0 only
+// checks that the process exists.
+fn sys_kill(pid: i32, sig: i32) -> SyscallResult {
+    if pid <= 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    let signal = match sig {
+        0 => None,
+        sig => Some(Signal::from_number(sig as u32).ok_or(Errno::EINVAL)?),
+    };
+    signal::kill(pid as Pid, signal)?;
+    Ok(0)
+}
+
+// The timeout is relative. Only waiting and waking are supported.
+fn sys_futex(uaddr: UserPtr, op: i32, val: u32, timeout: UserPtr) -> SyscallResult {
+    match op & !FUTEX_PRIVATE_FLAG {
+        FUTEX_WAIT => {
+            let timeout = if timeout.is_null() {
+                None
+            } else {
+                Some(unsafe { uaccess::read_user::<Timespec>(timeout.addr())? }.to_duration()?)
+            };
+            process::futex::wait(uaddr.addr(), val, timeout)?;
+            Ok(0)
+        }
+        FUTEX_WAKE => process::futex::wake(uaddr.addr(), val as usize),
+        _ => Err(Errno::ENOSYS),
+    }
+}
+
+fn sys_sched_yield() -> SyscallResult {
+    task::yield_now();
+    Ok(0)
+}
+
+// Kernel threads don't belong to a process and report PID 0
+fn sys_getpid() -> SyscallResult {
+    Ok(process::current().map_or(0, |process| process.pid()) as usize)
+}
+
+fn sys_getppid() -> SyscallResult {
+    Ok(process::current().map_or(0, |process| process.parent()) as usize)
+}
+
+fn sys_fork() -> SyscallResult {
+    Ok(process::fork()?.pid() as usize)
+}
+
+// Only returns on error
+fn sys_execve(path: UserPtr, argv: UserPtr, envp: UserPtr) -> SyscallResult {
+    // Everything is dropped by the time we leave for the new program
+    let image = {
+        let path = copy_path(path)?;
+        let mut size = 0;
+        let argv = copy_string_array(argv, &mut size)?;
+        let envp = copy_string_array(envp, &mut size)?;
+        let data = process::exec::read_executable(&path)?;
+
+        let name = path.rsplit(|&c| c == b'/').next().unwrap_or(&path);
+        let name = core::str::from_utf8(name).unwrap_or("?");
+        let argv: Vec<&[u8]> = argv.iter().map(|arg| &arg[..]).collect();
+        let envp: Vec<&[u8]> = envp.iter().map(|env| &env[..]).collect();
+        process::exec(name, &data, &argv, &envp)?
+    };
+
+    unsafe { elf::load::start(&image) }
+}
+
+// Exits the calling thread only, the process goes once its last thread does
+fn sys_exit(code: i32) -> SyscallResult {
+    task::exit(code)
+}
+
+fn sys_exit_group(code: i32) -> SyscallResult {
+    process::exit(code)
+}
+
+// Process groups and resource usage aren't supported, so pid must be -1 or a
+// child's PID and rusage must be null
+fn sys_wait4(pid: i32, status: UserPtr, options: u32, rusage: UserPtr) -> SyscallResult {
+    if options & !WNOHANG != 0 || !rusage.is_null() {
+        return Err(Errno::EINVAL);
+    }
+
+    let pid = match pid {
+        -1 => None,
+        pid if pid > 0 => Some(pid as Pid),
+        _ => return Err(Errno::EINVAL),
+    };
+
+    let result = if options & WNOHANG != 0 {
+        process::try_wait(pid)?
+    } else {
+        Some(process::wait(pid)?)
+    };
+
+    match result {
+        Some((pid, exit_status)) => {
+            if !status.is_null() {
+                let wstatus = exit_status.wait_status();
+                uaccess::copy_to_user(status.addr(), &wstatus.to_ne_bytes())?;
+            }
+            Ok(pid as usize)
+        }
+        None => Ok(0),
+    }
+}
+
+// Copies a path, without its terminator
+fn copy_path(ptr: UserPtr) -> Result<Vec<u8>, Errno> {
+    let mut path = vec![0; PATH_MAX];
+    let len = uaccess::strncpy_from_user(&mut path, ptr.addr())?;
+    if len == PATH_MAX {
+        return Err(Errno::ENAMETOOLONG);
+    }
+
+    path.truncate(len);
+    Ok(path)
+}
+
+// Copies a NULL terminated array of strings such as argv, adding their size to
+// total. The strings are copied without their terminators.
+fn copy_string_array(ptr: UserPtr, total: &mut usize) -> Result<Vec<Vec<u8>>, Errno> {
+    let mut strings = Vec::new();
+    if ptr.is_null() {
+        return Ok(strings);
+    }
+
+    let mut buf = vec![0; MAX_ARGS_SIZE];
+    for i in 0.. {
+        let mut bytes = [0; mem::size_of::<usize>()];
+        uaccess::copy_from_user(&mut bytes, ptr.offset(i * bytes.len())?.addr())?;
+        let string = UserPtr::from_arg(usize::from_ne_bytes(bytes))?;
+        if string.is_null() {
+            break;
+        }
+
+        let room = MAX_ARGS_SIZE.saturating_sub(*total);
+        let len = uaccess::strncpy_from_user(&mut buf[..room], string.addr())?;
+        if len == room {
+            return Err(Errno::E2BIG);
+        }
+
+        strings.push(buf[..len].to_vec());
+        *total += len + 1 + bytes.len();
+    }
+
+    Ok(strings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cpu::user::enter_user,
+        mm::{self, addr_space::AddrSpace, pmm::PhysAllocator},
+    };
+    use x86_64::structures::paging::PageTableFlags;
+
+    const CODE_ADDR: usize = 0x5000_0001_0000;
+    const STACK_ADDR: usize = CODE_ADDR + 0x1000;
+
+    // write(1, msg, 6) + unknown syscall + write(1, <kernel address>, 1),
+    // then exit with the sum of the results: 6 - ENOSYS - EFAULT = -46
+    #[rustfmt::skip]
+    const BLOB: [u8; 85] = [
+        0xB8, 0x01, 0x00, 0x00, 0x00,             // mov $1, %eax
+        0xBF, 0x01, 0x00, 0x00, 0x00,             // mov $1, %edi
+        0x48, 0x8D, 0x35, 0x3E, 0x00, 0x00, 0x00, // lea msg(%rip), %rsi
+        0xBA, 0x06, 0x00, 0x00, 0x00,             // mov $6, %edx
+        0x0F, 0x05,                               // syscall
+        0x48, 0x89, 0xC3,                         // mov %rax, %rbx
+        0xB8, 0xE7, 0x03, 0x00, 0x00,             // mov $999, %eax
+        0x0F, 0x05,                               // syscall
+        0x48, 0x01, 0xC3,                         // add %rax, %rbx
+        0xB8, 0x01, 0x00, 0x00, 0x00,             // mov $1, %eax
+        0xBF, 0x01, 0x00, 0x00, 0x00,             // mov $1, %edi
+        0x48, 0xBE, 0x00, 0x00, 0x00, 0x00,       // mov $0xFFFF800000000000, %rsi
+        0x00, 0x80, 0xFF, 0xFF,
+        0xBA, 0x01, 0x00, 0x00, 0x00,             // mov $1, %edx
+        0x0F, 0x05,                               // syscall
+        0x48, 0x01, 0xC3,                         // add %rax, %rbx
+        0x48, 0x89, 0xDF,                         // mov %rbx, %rdi
+        0xB8, 0x3C, 0x00, 0x00, 0x00,             // mov $60, %eax
+        0x0F, 0x05,                               // syscall
+        0xEB, 0xFE,                               // jmp .
+        b'h', b'e', b'l', b'l', b'o', b'\n',      // msg
+    ];
+
+    fn run_blob(_: usize) {
+        unsafe { enter_user(VirtAddr::new(CODE_ADDR), VirtAddr::new(STACK_ADDR + 0x1000)) }
+    }
+
+    test_case!(dispatch_errors, {
+        assert_eq!(dispatch(999, [0; 6]), Errno::ENOSYS.as_return());
+        assert_eq!(dispatch(SYS_WRITE, [5, 0, 0, 0, 0, 0]), Errno::EBADF.as_return());
+        assert_eq!(dispatch(SYS_WRITE, [1, USER_END, 1, 0, 0, 0]), Errno::EFAULT.as_return());
+        assert_eq!(dispatch(SYS_WRITE, [1, 0x1000, 0, 0, 0, 0]), 0);
+        assert_eq!(dispatch(SYS_READ, [7, 0x1000, 1, 0, 0, 0]), Errno::EBADF.as_return());
+        assert_eq!(dispatch(SYS_OPEN, [USER_END, 0, 0, 0, 0, 0]), Errno::EFAULT.as_return());
+        assert_eq!(dispatch(SYS_CLOSE, [100, 0, 0, 0, 0, 0]), Errno::EBADF.as_return());
+        assert_eq!(dispatch(SYS_FSTAT, [9, 0x1000, 0, 0, 0, 0]), Errno::EBADF.as_return());
+        assert_eq!(dispatch(SYS_LSEEK, [1, 0, 7, 0, 0, 0]), Errno::EINVAL.as_return());
+        assert_eq!(dispatch(SYS_GETDENTS64, [8, 0x1000, 64, 0, 0, 0]), Errno::EBADF.as_return());
+        assert_eq!(dispatch(SYS_FTRUNCATE, [1, -1isize as usize, 0, 0, 0, 0]), Errno::EINVAL.as_return());
+        assert_eq!(dispatch(SYS_READLINK, [0x1000, 0x1000, 0, 0, 0, 0]), Errno::EINVAL.as_return());
+        assert_eq!(dispatch(SYS_WAIT4, [-1isize as usize, 0, 0, 0, 0, 0]), Errno::ECHILD.as_return());
+        assert_eq!(dispatch(SYS_WAIT4, [-1isize as usize, 0, 2, 0, 0, 0]), Errno::EINVAL.as_return());
+        assert_eq!(dispatch(SYS_WAIT4, [0, 0, 0, 0, 0, 0]), Errno::EINVAL.as_return());
+        assert_eq!(dispatch(SYS_FORK, [0; 6]), Errno::EINVAL.as_return());
+        assert_eq!(dispatch(SYS_EXECVE, [USER_END, 0, 0, 0, 0, 0]), Errno::EFAULT.as_return());
+        assert_eq!(dispatch(SYS_KILL, [0, 0, 0, 0, 0, 0]), Errno::EINVAL.as_return());
+        assert_eq!(dispatch(SYS_KILL, [1, 64, 0, 0, 0, 0]), Errno::EINVAL.as_return());
+        assert_eq!(dispatch(SYS_KILL, [process::PID_MAX as usize, 0, 0, 0, 0, 0]), Errno::ESRCH.as_return());
+        assert_eq!(dispatch(SYS_KILL, [1, 0, 0, 0, 0, 0]), 0);
+        assert_eq!(dispatch(SYS_RT_SIGACTION, [10, 0, 0, 4, 0, 0]), Errno::EINVAL.as_return());
+        assert_eq!(dispatch(SYS_RT_SIGACTION, [9, 0x1000, 0, 8, 0, 0]), Errno::EFAULT.as_return());
+        assert_eq!(dispatch(SYS_RT_SIGPROCMASK, [3, 0, 0, 8, 0, 0]), Errno::EINVAL.as_return());
+        assert_eq!(dispatch(SYS_FUTEX, [0x1000, 9, 0, 0, 0, 0]), Errno::ENOSYS.as_return());
+        assert_eq!(dispatch(SYS_FUTEX, [USER_END, FUTEX_WAKE as usize, 1, 0, 0, 0]), Errno::EFAULT.as_return());
+    });
+
+    test_case!(syscalls_from_ring3, {
+        let kernel = AddrSpace::kernel();
+        let code = PhysAllocator::alloc(0);
+        let stack = PhysAllocator::alloc(0);
+
+        unsafe {
+            let code_virt = mm::phys_to_kernel_virt(code.start.start_address()).as_mut_ptr::<u8>();
+            core::ptr::copy_nonoverlapping(BLOB.as_ptr(), code_virt, BLOB.len());
+        }
+
+        use PageTableFlags as F;
+        kernel
+            .map_to(VirtAddr::new(CODE_ADDR), code.start.start_address(), F::PRESENT | F::USER_ACCESSIBLE)
+            .unwrap()
+            .flush();
+        kernel
+            .map_to(
+                VirtAddr::new(STACK_ADDR),
+                stack.start.start_address(),
+                F::PRESENT | F::WRITABLE | F::USER_ACCESSIBLE | F::NO_EXECUTE,
+            )
+            .unwrap()
+            .flush();
+
+        let thread = task::spawn("syscalls", run_blob, 0);
+        assert_eq!(task::join(&thread), -46);
+
+        kernel.unmap(VirtAddr::new(CODE_ADDR)).unwrap().1.flush();
+        kernel.unmap(VirtAddr::new(STACK_ADDR)).unwrap().1.flush();
+        PhysAllocator::free(code);
+        PhysAllocator::free(stack);
+    });
+
+    test_case!(string_arrays, {
+        let kernel = AddrSpace::kernel();
+        let page = PhysAllocator::alloc(0);
+        let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+
+        // Two pointers and a NULL, then the strings they point to
+        let words = [STACK_ADDR + 0x100, STACK_ADDR + 0x103, 0];
+        unsafe {
+            let virt = mm::phys_to_kernel_virt(page.start.start_address()).as_mut_ptr::<u8>();
+            core::ptr::write_bytes(virt, 0, 0x1000);
+            core::ptr::copy_nonoverlapping(words.as_ptr() as *const u8, virt, mem::size_of_val(&words));
+            core::ptr::copy_nonoverlapping(b"ab\0c\0".as_ptr(), virt.add(0x100), 5);
+        }
+        kernel.map_to(VirtAddr::new(STACK_ADDR), page.start.start_address(), flags).unwrap().flush();
+
+        let mut total = 0;
+        let strings = copy_string_array(UserPtr::from_arg(STACK_ADDR).unwrap(), &mut total).unwrap();
+        assert_eq!(strings, [&b"ab"[..], &b"c"[..]]);
+        assert_eq!(total, 5 + 2 * mem::size_of::<usize>());
+
+        let empty = copy_string_array(UserPtr::from_arg(STACK_ADDR + 0x10).unwrap(), &mut total).unwrap();
+        assert!(empty.is_empty());
+
+        kernel.unmap(VirtAddr::new(STACK_ADDR)).unwrap().1.flush();
+        PhysAllocator::free(page);
+    });
+}
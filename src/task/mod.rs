@@ -0,0 +1,242 @@
+// Kernel threads with a simple round-robin, cooperative scheduler. Threads run
+// until they yield, block or exit. Interrupts don't preempt them, but their
+// handlers can wake blocked ones.
+use crate::{
+    cpu::{irq, percpu::PerCpu},
+    ds::SpinLock,
+    mm::addr_space::AddrSpace,
+    process::Process,
+    time::timer,
+};
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::sync::atomic::spin_loop_hint;
+use x86_64::instructions::{self, interrupts};
+
+pub mod thread;
+
+pub use thread::{State, Thread, Tid};
+
+struct Scheduler {
+    current: Arc<Thread>,
+    run_queue: VecDeque<Arc<Thread>>,
+    // Threads which have exited but may still be running on their stack
+    dead: Vec<Arc<Thread>>,
+}
+
+lazy_static! {
+    static ref SCHEDULER: SpinLock<Scheduler> = SpinLock::new(Scheduler {
+        current: Arc::new(Thread::bootstrap()),
+        run_queue: VecDeque::new(),
+        dead: Vec::new(),
+    });
+}
+
+pub fn init() {
+    lazy_static::initialize(&SCHEDULER);
+    debug!("task: initialized");
+}
+
+pub fn current() -> Arc<Thread> {
+    // Interrupt handlers wake threads, which takes the lock too
+    interrupts::without_interrupts(|| SCHEDULER.lock().current.clone())
+}
+
+pub fn spawn(name: &'static str, entry: fn(usize), arg: usize) -> Arc<Thread> {
+    enqueue(Thread::new(name, entry, arg, None))
+}
+
+// Spawns a thread belonging to a process, running in its address space. Use
+// process::spawn_thread, which also counts it.
+pub(crate) fn spawn_in(process: Arc<Process>, name: &'static str, entry: fn(usize), arg: usize) -> Arc<Thread> {
+    enqueue(Thread::new(name, entry, arg, Some(process)))
+}
+
+fn enqueue(thread: Thread) -> Arc<Thread> {
+    let thread = Arc::new(thread);
+    trace!("task: spawned {} ({})", thread.tid(), thread.name());
+
+    interrupts::without_interrupts(|| SCHEDULER.lock().run_queue.push_back(thread.clone()));
+    thread
+}
+
+// Switches the current thread to a different address space, returning the old
+// one. None means the kernel's.
+pub fn set_addr_space(space: Option<Arc<AddrSpace>>) -> Option<Arc<AddrSpace>> {
+    interrupts::without_interrupts(|| {
+        let thread = current();
+        let old = thread.set_addr_space(space);
+        thread.activate_addr_space();
+        old
+    })
+}
+
+pub fn yield_now() {
+    schedule();
+}
+
+// Puts the current thread to sleep until someone calls wake on it. Whoever
+// does must hold a reference to it meanwhile, the scheduler doesn't.
+pub fn block() {
+    current().set_state(State::Blocked);
+    schedule();
+}
+
+// Makes a blocked thread runnable again, does nothing otherwise
+pub fn wake(thread: &Arc<Thread>) {
+    interrupts::without_interrupts(|| {
+        let mut sched = SCHEDULER.lock();
+        if thread.state() != State::Blocked {
+            return;
+        }
+
+        thread.set_state(State::Runnable);
+        // It may not have switched out yet
+        if !Arc::ptr_eq(&sched.current, thread) {
+            sched.run_queue.push_back(thread.clone());
+        }
+    });
+}
+
+pub fn exit(code: i32) -> ! {
+    let thread = current();
+    trace!("task: {} ({}) exited with {}", thread.tid(), thread.name(), code);
+    thread.set_state(State::Exited(code));
+    if let Some(process) = thread.process() {
+        process.thread_exited(code);
+    }
+    drop(thread);
+
+    schedule();
+    unreachable!("exited thread was scheduled again");
+}
+
+// Waits for the thread to exit and returns its exit code
+pub fn join(thread: &Thread) -> i32 {
+    loop {
+        if let State::Exited(code) = thread.state() {
+            return code;
+        }
+        yield_now();
+    }
+}
+
+fn schedule() {
+    interrupts::without_interrupts(|| {
+        timer::run_expired();
+
+        let (prev, next) = loop {
+            let mut sched = SCHEDULER.lock();
+
+            // Nothing in here is current, so their stacks are free to go
+            sched.dead.clear();
+
+            let next = match sched.run_queue.pop_front() {
+                Some(next) => next,
+                None if sched.current.state() == State::Runnable => return,
+                None => {
+                    // Everything is blocked, until a timer expires or an
+                    // interrupt handler wakes something. sti holds interrupts
+                    // off for one more instruction, so hlt can't miss one.
+                    drop(sched);
+                    if timer::is_pending() {
+                        interrupts::enable();
+                        spin_loop_hint();
+                    } else if irq::is_enabled() {
+                        interrupts::enable();
+                        instructions::hlt();
+                    } else {
+                        panic!("task: no runnable threads left");
+                    }
+                    interrupts::disable();
+                    timer::run_expired();
+                    continue;
+                }
+            };
+
+            let prev = core::mem::replace(&mut sched.current, next.clone());
+            match prev.state() {
+                State::Runnable => sched.run_queue.push_back(prev.clone()),
+                State::Blocked => {}
+                State::Exited(_) => sched.dead.push(prev.clone()),
+            }
+
+            break (prev, next);
+        };
+
+        if let Some(stack) = next.kernel_stack_top() {
+            PerCpu::current().set_kernel_stack(stack);
+        }
+        next.activate_addr_space();
+
+        // Both are kept alive by the scheduler across the switch, and prev
+        // might never get back here to drop its references
+        let (prev_ptr, next_ptr) = (&*prev as *const Thread, &*next as *const Thread);
+        drop(prev);
+        drop(next);
+
+        unsafe { Thread::switch(&*prev_ptr, &*next_ptr) };
+    });
+}
+
+extern "C" fn thread_start() -> ! {
+    // Switched to from inside schedule, with interrupts off
+    if irq::is_enabled() {
+        interrupts::enable();
+    }
+
+    let (entry, arg) = current().entry().expect("thread without an entry point");
+    entry(arg);
+
+    exit(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mm::pmm::PhysAllocator;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    fn count(n: usize) {
+        for _ in 0..n {
+            COUNTER.fetch_add(1, Ordering::SeqCst);
+            yield_now();
+        }
+    }
+
+    fn exit_early(code: usize) {
+        exit(code as i32);
+    }
+
+    test_case!(spawn_and_join, {
+        COUNTER.store(0, Ordering::SeqCst);
+
+        let a = spawn("count-a", count, 10);
+        let b = spawn("count-b", count, 5);
+        assert_eq!(join(&a), 0);
+        assert_eq!(join(&b), 0);
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 15);
+
+        let c = spawn("exit-early", exit_early, 7);
+        assert_eq!(join(&c), 7);
+    });
+
+    fn spawn_and_reap() {
+        let thread = spawn("noop", |_| {}, 0);
+        join(&thread);
+        drop(thread);
+
+        // The thread is left in the dead list until the next switch
+        yield_now();
+    }
+
+    test_case!(stacks_are_freed, {
+        // Let the scheduler's queues reach their steady state size first
+        spawn_and_reap();
+
+        let free = PhysAllocator::free_pages();
+        spawn_and_reap();
+        assert_eq!(PhysAllocator::free_pages(), free);
+    });
+}
@@ -0,0 +1,181 @@
+use crate::{
+    ds::SpinLock,
+    mm::{self, addr_space::AddrSpace, pmm::PhysAllocator},
+    process::Process,
+};
+use alloc::sync::Arc;
+use core::{
+    cell::UnsafeCell,
+    mem,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use x86_64::{structures::paging::frame::PhysFrameRange, VirtAddr};
+
+pub type Tid = u64;
+
+// 16KiB kernel stacks
+const STACK_ORDER: u8 = 2;
+
+static NEXT_TID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Runnable,
+    // Waiting for task::wake, off the run queue
+    Blocked,
+    Exited(i32),
+}
+
+pub struct Thread {
+    tid: Tid,
+    name: &'static str,
+    state: SpinLock<State>,
+    // Saved stack pointer while the thread is switched out
+    rsp: UnsafeCell<usize>,
+    // None for the boot thread, which runs on the bootloader's stack
+    stack: Option<PhysFrameRange>,
+    entry: Option<(fn(usize), usize)>,
+    // None for threads that only run in the kernel's address space
+    addr_space: SpinLock<Option<Arc<AddrSpace>>>,
+    // None for kernel threads
+    process: SpinLock<Option<Arc<Process>>>,
+}
+
+// rsp is only touched by the scheduler, with interrupts disabled
+unsafe impl Send for Thread {}
+unsafe impl Sync for Thread {}
+
+// Saves the callee-saved registers on the current stack, stores the stack
+// pointer in *prev, then switches to the stack next and restores the registers
+// that were saved there. New threads start with a frame that "returns" into
+// thread_start.
+global_asm!("
+    .global task_switch
+task_switch:
+    push %rbp
+    push %rbx
+    push %r12
+    push %r13
+    push %r14
+    push %r15
+    mov %rsp, (%rdi)
+    mov %rsi, %rsp
+    pop %r15
+    pop %r14
+    pop %r13
+    pop %r12
+    pop %rbx
+    pop %rbp
+    ret
+");
+
+extern "C" {
+    fn task_switch(prev: *mut usize, next: usize);
+}
+
+impl Thread {
+    pub(super) fn bootstrap() -> Thread {
+        Thread {
+            tid: 0,
+            name: "boot",
+            state: SpinLock::new(State::Runnable),
+            rsp: UnsafeCell::new(0),
+            stack: None,
+            entry: None,
+            addr_space: SpinLock::new(None),
+            process: SpinLock::new(None),
+        }
+    }
+
+    pub(super) fn new(
+        name: &'static str,
+        entry: fn(usize),
+        arg: usize,
+        process: Option<Arc<Process>>,
+    ) -> Thread {
+        let stack = PhysAllocator::alloc(STACK_ORDER);
+        let top = mm::phys_to_kernel_virt(stack.end.start_address()).as_usize();
+
+        // Initial frame for task_switch: 6 zeroed registers, the return address
+        // and a dummy return address for thread_start, which keeps the stack
+        // aligned as if thread_start had been called
+        let frame: [usize; 8] = [0, 0, 0, 0, 0, 0, super::thread_start as usize, 0];
+        let rsp = top - mem::size_of_val(&frame);
+        unsafe { (rsp as *mut [usize; 8]).write(frame) };
+
+        Thread {
+            tid: NEXT_TID.fetch_add(1, Ordering::Relaxed),
+            name,
+            state: SpinLock::new(State::Runnable),
+            rsp: UnsafeCell::new(rsp),
+            stack: Some(stack),
+            entry: Some((entry, arg)),
+            addr_space: SpinLock::new(process.as_ref().and_then(|process| process.addr_space())),
+            process: SpinLock::new(process),
+        }
+    }
+
+    pub fn tid(&self) -> Tid {
+        self.tid
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn state(&self) -> State {
+        *self.state.lock()
+    }
+
+    pub(super) fn set_state(&self, state: State) {
+        *self.state.lock() = state;
+    }
+
+    pub(super) fn entry(&self) -> Option<(fn(usize), usize)> {
+        self.entry
+    }
+
+    pub fn addr_space(&self) -> Option<Arc<AddrSpace>> {
+        self.addr_space.lock().clone()
+    }
+
+    pub(super) fn set_addr_space(&self, space: Option<Arc<AddrSpace>>) -> Option<Arc<AddrSpace>> {
+        mem::replace(&mut *self.addr_space.lock(), space)
+    }
+
+    pub fn process(&self) -> Option<Arc<Process>> {
+        self.process.lock().clone()
+    }
+
+    // Only used to make the boot thread init
+    pub(crate) fn set_process(&self, process: Arc<Process>) {
+        let old = mem::replace(&mut *self.process.lock(), Some(process));
+        assert!(old.is_none(), "task: thread already belongs to a process");
+    }
+
+    pub(super) fn activate_addr_space(&self) {
+        match &*self.addr_space.lock() {
+            Some(space) => unsafe { space.activate() },
+            None => unsafe { AddrSpace::kernel().activate() },
+        }
+    }
+
+    // Top of the kernel stack, loaded into the TSS while the thread runs
+    pub fn kernel_stack_top(&self) -> Option<VirtAddr> {
+        self.stack
+            .as_ref()
+            .map(|stack| mm::phys_to_kernel_virt(stack.end.start_address()))
+    }
+
+    pub(super) unsafe fn switch(prev: &Thread, next: &Thread) {
+        task_switch(prev.rsp.get(), *next.rsp.get());
+    }
+}
+
+impl Drop for Thread {
+    fn drop(&mut self) {
+        if let Some(stack) = self.stack.take() {
+            PhysAllocator::free(stack);
+        }
+    }
+}
@@ -36,6 +36,7 @@ pub fn test_runner(tests: &[&dyn Fn()]) {
         test();
     }
 
+    crate::fs::mount::sync_all();
     exit_qemu(ExitCode::Success);
 }
 
@@ -0,0 +1,78 @@
+// Monotonic time since boot, read from the TSC. Its rate is measured against
+// the PIT at boot, since there's no timer interrupt to count ticks with.
+use core::{
+    arch::x86_64::_rdtsc,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use x86_64::instructions::port::Port;
+
+pub mod timer;
+
+const PIT_HZ: u64 = 1_193_182;
+const CALIBRATION_MS: u64 = 10;
+
+static BOOT_TSC: AtomicU64 = AtomicU64::new(0);
+static TSC_PER_MS: AtomicU64 = AtomicU64::new(0);
+
+pub fn init() {
+    let per_ms = calibrate();
+    BOOT_TSC.store(unsafe { _rdtsc() }, Ordering::Relaxed);
+    TSC_PER_MS.store(per_ms, Ordering::Relaxed);
+
+    debug!("time: tsc runs at {}.{:03} MHz", per_ms / 1000, per_ms % 1000);
+}
+
+// Counts TSC ticks while PIT channel 2 counts down CALIBRATION_MS in mode 0,
+// which raises its output once done. Channel 2 is polled through port 0x61,
+// with the speaker kept off.
+fn calibrate() -> u64 {
+    let mut control = Port::<u8>::new(0x61);
+    let mut command = Port::<u8>::new(0x43);
+    let mut channel2 = Port::<u8>::new(0x42);
+    let count = PIT_HZ * CALIBRATION_MS / 1000;
+
+    unsafe {
+        let gate = (control.read() & !0x02) | 0x01;
+        control.write(gate & !0x01);
+
+        // Channel 2, low then high byte, mode 0
+        command.write(0b1011_0000);
+        channel2.write(count as u8);
+        channel2.write((count >> 8) as u8);
+
+        // Raising the gate starts the count
+        control.write(gate);
+        let start = _rdtsc();
+        while control.read() & 0x20 == 0 {}
+        let end = _rdtsc();
+
+        ((end - start) / CALIBRATION_MS).max(1)
+    }
+}
+
+// Time since init, zero before it
+pub fn now() -> Duration {
+    let per_ms = TSC_PER_MS.load(Ordering::Relaxed);
+    if per_ms == 0 {
+        return Duration::from_secs(0);
+    }
+
+    let ticks = unsafe { _rdtsc() } - BOOT_TSC.load(Ordering::Relaxed);
+    Duration::from_nanos((u128::from(ticks) * 1_000_000 / u128::from(per_ms)) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    test_case!(clock_is_monotonic, {
+        let start = now();
+        let mut last = start;
+        while last - start < Duration::from_millis(5) {
+            let next = now();
+            assert!(next >= last);
+            last = next;
+        }
+    });
+}
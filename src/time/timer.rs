@@ -0,0 +1,73 @@
+// One-shot timers which wake a blocked thread. Without a timer interrupt they
+// are run by the scheduler, which spins while one is pending and there's
+// nothing else to run.
+use crate::{
+    ds::SpinLock,
+    task::{self, Thread},
+};
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+// Identifies a timer for cancel. Ordered by deadline, then creation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimerId(Duration, u64);
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref TIMERS: SpinLock<BTreeMap<TimerId, Arc<Thread>>> = SpinLock::new(BTreeMap::new());
+}
+
+// Wakes thread once time::now() reaches deadline
+pub fn add(deadline: Duration, thread: Arc<Thread>) -> TimerId {
+    let id = TimerId(deadline, NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    TIMERS.lock().insert(id, thread);
+    id
+}
+
+// Returns false if the timer already ran
+pub fn cancel(id: TimerId) -> bool {
+    TIMERS.lock().remove(&id).is_some()
+}
+
+// Wakes the threads of every expired timer
+pub fn run_expired() {
+    let now = super::now();
+    let expired: Vec<Arc<Thread>> = {
+        let mut timers = TIMERS.lock();
+        let later = timers.split_off(&TimerId(now, u64::max_value()));
+        core::mem::replace(&mut *timers, later).into_iter().map(|(_, thread)| thread).collect()
+    };
+
+    // The lock is dropped first, waking takes the scheduler's
+    for thread in expired {
+        task::wake(&thread);
+    }
+}
+
+// Whether any thread is waiting on a timer
+pub fn is_pending() -> bool {
+    !TIMERS.lock().is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    test_case!(timers_run_in_order, {
+        let thread = task::current();
+        let base = super::super::now() + Duration::from_secs(3600);
+        let a = add(base + Duration::from_millis(2), thread.clone());
+        let b = add(base + Duration::from_millis(1), thread.clone());
+        let c = add(base + Duration::from_millis(1), thread);
+        assert!(b < c && c < a);
+
+        assert!(cancel(b));
+        assert!(!cancel(b));
+        assert!(cancel(a));
+        assert!(cancel(c));
+    });
+}
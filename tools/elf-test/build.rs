@@ -0,0 +1,27 @@
+use std::{env, path::PathBuf, process::Command};
+
+// Sample programs, and the compiler flags to build them with
+const SAMPLES: &[(&str, &str, &[&str])] = &[
+    ("static", "static.S", &["-nostdlib", "-static", "-no-pie"]),
+    ("static-pie", "static.S", &["-nostdlib", "-static-pie", "-fPIE"]),
+    ("dynamic", "hello.c", &["-no-pie"]),
+];
+
+fn main() {
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let cc = env::var("CC").unwrap_or_else(|_| "cc".into());
+
+    for (name, source, flags) in SAMPLES {
+        let source = format!("samples/{}", source);
+        println!("cargo:rerun-if-changed={}", source);
+
+        let status = Command::new(&cc)
+            .args(*flags)
+            .arg("-o")
+            .arg(out.join(name))
+            .arg(&source)
+            .status()
+            .unwrap_or_else(|err| panic!("couldn't run {}: {}", cc, err));
+        assert!(status.success(), "{} failed to build {}", cc, source);
+    }
+}
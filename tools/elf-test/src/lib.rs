@@ -0,0 +1,7 @@
+// The kernel's ELF parser, built for the host so that it can be tested on
+// files from a real toolchain
+#[path = "../../../src/elf/parse.rs"]
+pub mod parse;
+
+// Where user space ends in the kernel, see mm::uaccess
+const USER_END: u64 = 0x0000_8000_0000_0000;
@@ -0,0 +1,109 @@
+use elf_test::parse::*;
+use std::mem;
+
+static STATIC: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/static"));
+static STATIC_PIE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/static-pie"));
+static DYNAMIC: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/dynamic"));
+
+fn parse_err(data: &[u8]) -> ElfError {
+    match Elf::parse(data) {
+        Ok(_) => panic!("expected an error"),
+        Err(e) => e,
+    }
+}
+
+#[test]
+fn static_executable() {
+    let elf = Elf::parse(STATIC).unwrap();
+    assert_eq!(elf.header().elf_type, ET_EXEC);
+
+    // The entry point is in the only executable segment, which isn't writable
+    let code: Vec<_> = elf.segments().filter(|ph| ph.flags & PF_X != 0).collect();
+    assert_eq!(code.len(), 1);
+    assert_eq!(code[0].flags & PF_W, 0);
+    assert!(elf.entry() >= code[0].vaddr && elf.entry() < code[0].vaddr + code[0].memsz);
+    assert_eq!(elf.segment_data(&code[0]).len() as u64, code[0].filesz);
+
+    // .data holds the counter and .bss isn't in the file
+    let data = elf.segments().find(|ph| ph.flags & PF_W != 0).unwrap();
+    assert_eq!(data.flags & PF_X, 0);
+    assert!(data.memsz >= data.filesz + 4096);
+    assert_eq!(&elf.segment_data(&data)[..4], &42u32.to_le_bytes());
+
+    for ph in elf.segments() {
+        assert_eq!(ph.vaddr % 4096, ph.offset % 4096);
+        assert!(ph.vaddr + ph.memsz <= 0x0000_8000_0000_0000);
+    }
+}
+
+#[test]
+fn phdr_address() {
+    let elf = Elf::parse(STATIC).unwrap();
+    let first = elf.segments().next().unwrap();
+
+    // The linker puts the headers at the start of the first segment
+    assert_eq!(first.offset, 0);
+    assert_eq!(elf.phdr_addr(), Some(first.vaddr + elf.header().phoff));
+}
+
+#[test]
+fn reject_pie() {
+    assert_eq!(parse_err(STATIC_PIE), ElfError::UnsupportedType(ET_DYN));
+}
+
+#[test]
+fn reject_dynamically_linked() {
+    assert_eq!(parse_err(DYNAMIC), ElfError::InterpreterNotSupported);
+}
+
+// Cutting a file short anywhere is caught before anything is read past it
+#[test]
+fn reject_truncated() {
+    let elf = Elf::parse(STATIC).unwrap();
+    let header_size = mem::size_of::<Header>();
+    let ph_end = elf.header().phoff as usize + usize::from(elf.header().phnum) * mem::size_of::<ProgramHeader>();
+    let file_end = elf.segments().map(|ph| ph.offset + ph.filesz).max().unwrap() as usize;
+
+    for len in 0..file_end {
+        let err = parse_err(&STATIC[..len]);
+        let expected = if len < header_size {
+            ElfError::TooShort
+        } else if len < ph_end {
+            ElfError::ProgramHeadersOutOfBounds
+        } else {
+            match err {
+                ElfError::SegmentOutOfBounds(_) => err,
+                _ => panic!("unexpected {:?} for a file cut at {:#x}", err, len),
+            }
+        };
+        assert_eq!(err, expected, "file cut at {:#x}", len);
+    }
+    assert!(Elf::parse(&STATIC[..file_end]).is_ok());
+}
+
+// Changing single fields of a real file
+#[test]
+fn reject_corrupted() {
+    let patched = |offset: usize, bytes: &[u8]| {
+        let mut data = STATIC.to_vec();
+        data[offset..offset + bytes.len()].copy_from_slice(bytes);
+        data
+    };
+
+    assert_eq!(parse_err(&patched(1, b"X")), ElfError::BadMagic);
+    assert_eq!(parse_err(&patched(4, &[1])), ElfError::UnsupportedClass(1));
+    assert_eq!(parse_err(&patched(5, &[2])), ElfError::UnsupportedEndianness(2));
+    assert_eq!(parse_err(&patched(18, &3u16.to_le_bytes())), ElfError::UnsupportedMachine(3));
+    assert_eq!(parse_err(&patched(54, &48u16.to_le_bytes())), ElfError::BadProgramHeaderSize(48));
+
+    let entry = 0x10u64;
+    assert_eq!(parse_err(&patched(24, &entry.to_le_bytes())), ElfError::EntryNotExecutable(entry));
+
+    // Moving the first loadable segment into the kernel's half
+    let elf = Elf::parse(STATIC).unwrap();
+    let phoff = elf.header().phoff as usize;
+    let index = elf.program_headers().position(|ph| ph.p_type == PT_LOAD).unwrap();
+    let vaddr = phoff + index * mem::size_of::<ProgramHeader>() + 16;
+    let data = patched(vaddr, &0xFFFF_8000_0040_0000u64.to_le_bytes());
+    assert_eq!(parse_err(&data), ElfError::SegmentNotInUserSpace(index));
+}
@@ -0,0 +1,624 @@
+//! Processor identification and feature detection using the `cpuid` instruction.
+
+use bitflags::bitflags;
+use core::{fmt, str};
+
+pub use core::arch::x86_64::CpuidResult;
+
+/// Executes `cpuid` for the given leaf and sub-leaf.
+///
+/// Leaves above the maximum supported standard or extended leaf return
+/// unspecified data, so check [`max_leaf`] and [`max_extended_leaf`] first.
+#[inline]
+pub fn cpuid(leaf: u32, sub_leaf: u32) -> CpuidResult {
+    unsafe { core::arch::x86_64::__cpuid_count(leaf, sub_leaf) }
+}
+
+/// Returns the highest supported standard leaf.
+#[inline]
+pub fn max_leaf() -> u32 {
+    cpuid(0, 0).eax
+}
+
+/// Returns the highest supported extended leaf (`0x8000_0000` and up).
+#[inline]
+pub fn max_extended_leaf() -> u32 {
+    cpuid(0x8000_0000, 0).eax
+}
+
+/// Like [`cpuid`], but returns all zeroes if the leaf is not supported.
+pub fn cpuid_checked(leaf: u32, sub_leaf: u32) -> CpuidResult {
+    let max = if leaf >= 0x8000_0000 {
+        max_extended_leaf()
+    } else {
+        max_leaf()
+    };
+
+    if leaf <= max {
+        cpuid(leaf, sub_leaf)
+    } else {
+        CpuidResult {
+            eax: 0,
+            ebx: 0,
+            ecx: 0,
+            edx: 0,
+        }
+    }
+}
+
+/// The 12 byte vendor identification string, e.g. `GenuineIntel`.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct Vendor([u8; 12]);
+
+impl Vendor {
+    /// Reads the vendor string from leaf 0.
+    pub fn read() -> Self {
+        let res = cpuid(0, 0);
+        let mut bytes = [0; 12];
+        bytes[0..4].copy_from_slice(&res.ebx.to_le_bytes());
+        bytes[4..8].copy_from_slice(&res.edx.to_le_bytes());
+        bytes[8..12].copy_from_slice(&res.ecx.to_le_bytes());
+        Vendor(bytes)
+    }
+
+    /// Returns the vendor string, or `"unknown"` if it is not valid UTF-8.
+    pub fn as_str(&self) -> &str {
+        str::from_utf8(&self.0).unwrap_or("unknown")
+    }
+
+    /// Whether this is an Intel processor.
+    pub fn is_intel(&self) -> bool {
+        &self.0 == b"GenuineIntel"
+    }
+
+    /// Whether this is an AMD processor.
+    pub fn is_amd(&self) -> bool {
+        &self.0 == b"AuthenticAMD"
+    }
+}
+
+impl fmt::Debug for Vendor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The 48 byte processor brand string from leaves `0x8000_0002` to `0x8000_0004`.
+#[derive(Copy, Clone)]
+pub struct BrandString([u8; 48]);
+
+impl BrandString {
+    /// Reads the brand string, if the processor provides one.
+    pub fn read() -> Option<Self> {
+        if max_extended_leaf() < 0x8000_0004 {
+            return None;
+        }
+
+        let mut bytes = [0; 48];
+        for (i, chunk) in bytes.chunks_mut(16).enumerate() {
+            let res = cpuid(0x8000_0002 + i as u32, 0);
+            chunk[0..4].copy_from_slice(&res.eax.to_le_bytes());
+            chunk[4..8].copy_from_slice(&res.ebx.to_le_bytes());
+            chunk[8..12].copy_from_slice(&res.ecx.to_le_bytes());
+            chunk[12..16].copy_from_slice(&res.edx.to_le_bytes());
+        }
+
+        Some(BrandString(bytes))
+    }
+
+    /// Returns the brand string without NUL padding and surrounding spaces.
+    pub fn as_str(&self) -> &str {
+        let len = self.0.iter().position(|&b| b == 0).unwrap_or(self.0.len());
+        str::from_utf8(&self.0[..len]).unwrap_or("unknown").trim()
+    }
+}
+
+impl fmt::Debug for BrandString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Processor family, model and stepping from leaf 1, with the extended family
+/// and model fields already folded in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Version {
+    /// The display family.
+    pub family: u32,
+    /// The display model.
+    pub model: u32,
+    /// The stepping ID.
+    pub stepping: u32,
+}
+
+impl Version {
+    /// Reads the version information from leaf 1.
+    pub fn read() -> Self {
+        let eax = cpuid(1, 0).eax;
+
+        let stepping = eax & 0xF;
+        let base_model = (eax >> 4) & 0xF;
+        let base_family = (eax >> 8) & 0xF;
+        let ext_model = (eax >> 16) & 0xF;
+        let ext_family = (eax >> 20) & 0xFF;
+
+        let family = if base_family == 0xF {
+            base_family + ext_family
+        } else {
+            base_family
+        };
+        let model = if base_family == 0x6 || base_family == 0xF {
+            (ext_model << 4) | base_model
+        } else {
+            base_model
+        };
+
+        Version {
+            family,
+            model,
+            stepping,
+        }
+    }
+}
+
+bitflags! {
+    /// Feature flags in `ecx` of leaf 1.
+    pub struct FeatureInfoEcx: u32 {
+        /// SSE3 instructions.
+        const SSE3 = 1 << 0;
+        /// `pclmulqdq` instruction.
+        const PCLMULQDQ = 1 << 1;
+        /// `monitor` and `mwait` instructions.
+        const MONITOR = 1 << 3;
+        /// Virtual machine extensions.
+        const VMX = 1 << 5;
+        /// Supplemental SSE3 instructions.
+        const SSSE3 = 1 << 9;
+        /// Fused multiply-add.
+        const FMA = 1 << 12;
+        /// `cmpxchg16b` instruction.
+        const CMPXCHG16B = 1 << 13;
+        /// Process-context identifiers.
+        const PCID = 1 << 17;
+        /// SSE4.1 instructions.
+        const SSE4_1 = 1 << 19;
+        /// SSE4.2 instructions.
+        const SSE4_2 = 1 << 20;
+        /// x2APIC mode.
+        const X2APIC = 1 << 21;
+        /// `movbe` instruction.
+        const MOVBE = 1 << 22;
+        /// `popcnt` instruction.
+        const POPCNT = 1 << 23;
+        /// The local APIC timer supports TSC deadline mode.
+        const TSC_DEADLINE = 1 << 24;
+        /// AES instructions.
+        const AES = 1 << 25;
+        /// `xsave` family of instructions.
+        const XSAVE = 1 << 26;
+        /// The OS has enabled `xsave` through Cr4.
+        const OSXSAVE = 1 << 27;
+        /// AVX instructions.
+        const AVX = 1 << 28;
+        /// 16-bit floating point conversion instructions.
+        const F16C = 1 << 29;
+        /// `rdrand` instruction.
+        const RDRAND = 1 << 30;
+        /// Always zero on real hardware, set by most hypervisors.
+        const HYPERVISOR = 1 << 31;
+    }
+}
+
+bitflags! {
+    /// Feature flags in `edx` of leaf 1.
+    pub struct FeatureInfoEdx: u32 {
+        /// x87 floating point unit.
+        const FPU = 1 << 0;
+        /// Virtual 8086 mode enhancements.
+        const VME = 1 << 1;
+        /// Debugging extensions.
+        const DE = 1 << 2;
+        /// Page size extensions.
+        const PSE = 1 << 3;
+        /// Time stamp counter.
+        const TSC = 1 << 4;
+        /// Model-specific registers.
+        const MSR = 1 << 5;
+        /// Physical address extensions.
+        const PAE = 1 << 6;
+        /// Machine check exception.
+        const MCE = 1 << 7;
+        /// `cmpxchg8b` instruction.
+        const CX8 = 1 << 8;
+        /// On-chip local APIC.
+        const APIC = 1 << 9;
+        /// `sysenter` and `sysexit` instructions.
+        const SEP = 1 << 11;
+        /// Memory type range registers.
+        const MTRR = 1 << 12;
+        /// Global pages.
+        const PGE = 1 << 13;
+        /// Machine check architecture.
+        const MCA = 1 << 14;
+        /// Conditional move instructions.
+        const CMOV = 1 << 15;
+        /// Page attribute table.
+        const PAT = 1 << 16;
+        /// 36-bit page size extensions.
+        const PSE36 = 1 << 17;
+        /// `clflush` instruction.
+        const CLFLUSH = 1 << 19;
+        /// MMX instructions.
+        const MMX = 1 << 23;
+        /// `fxsave` and `fxrstor` instructions.
+        const FXSR = 1 << 24;
+        /// SSE instructions.
+        const SSE = 1 << 25;
+        /// SSE2 instructions.
+        const SSE2 = 1 << 26;
+        /// Hyper-threading.
+        const HTT = 1 << 28;
+    }
+}
+
+bitflags! {
+    /// Structured extended feature flags in `ebx` of leaf 7, sub-leaf 0.
+    pub struct ExtendedFeaturesEbx: u32 {
+        /// `rdfsbase` and friends.
+        const FSGSBASE = 1 << 0;
+        /// Bit manipulation instruction set 1.
+        const BMI1 = 1 << 3;
+        /// AVX2 instructions.
+        const AVX2 = 1 << 5;
+        /// Supervisor mode execution prevention.
+        const SMEP = 1 << 7;
+        /// Bit manipulation instruction set 2.
+        const BMI2 = 1 << 8;
+        /// Enhanced `rep movsb` and `rep stosb`.
+        const ERMS = 1 << 9;
+        /// `invpcid` instruction.
+        const INVPCID = 1 << 10;
+        /// AVX-512 foundation.
+        const AVX512F = 1 << 16;
+        /// `rdseed` instruction.
+        const RDSEED = 1 << 18;
+        /// Supervisor mode access prevention.
+        const SMAP = 1 << 20;
+        /// `clflushopt` instruction.
+        const CLFLUSHOPT = 1 << 23;
+        /// `clwb` instruction.
+        const CLWB = 1 << 24;
+        /// SHA extensions.
+        const SHA = 1 << 29;
+    }
+}
+
+bitflags! {
+    /// Structured extended feature flags in `ecx` of leaf 7, sub-leaf 0.
+    pub struct ExtendedFeaturesEcx: u32 {
+        /// User-mode instruction prevention.
+        const UMIP = 1 << 2;
+        /// Memory protection keys for user pages.
+        const PKU = 1 << 3;
+        /// 5-level paging.
+        const LA57 = 1 << 16;
+        /// `rdpid` instruction.
+        const RDPID = 1 << 22;
+    }
+}
+
+bitflags! {
+    /// Structured extended feature flags in `edx` of leaf 7, sub-leaf 0.
+    pub struct ExtendedFeaturesEdx: u32 {
+        /// Fast short `rep movsb`.
+        const FSRM = 1 << 4;
+        /// Hybrid processor with different core types.
+        const HYBRID = 1 << 15;
+        /// Indirect branch restricted speculation.
+        const IBRS_IBPB = 1 << 26;
+        /// Single thread indirect branch predictors.
+        const STIBP = 1 << 27;
+        /// `IA32_ARCH_CAPABILITIES` MSR.
+        const ARCH_CAPABILITIES = 1 << 29;
+        /// Speculative store bypass disable.
+        const SSBD = 1 << 31;
+    }
+}
+
+bitflags! {
+    /// Extended processor feature flags in `ecx` of leaf `0x8000_0001`.
+    pub struct ExtendedProcessorEcx: u32 {
+        /// `lahf` and `sahf` in 64-bit mode.
+        const LAHF_LM = 1 << 0;
+        /// Secure virtual machine.
+        const SVM = 1 << 2;
+        /// `lzcnt` instruction.
+        const LZCNT = 1 << 5;
+        /// `prefetchw` instruction.
+        const PREFETCHW = 1 << 8;
+    }
+}
+
+bitflags! {
+    /// Extended processor feature flags in `edx` of leaf `0x8000_0001`.
+    pub struct ExtendedProcessorEdx: u32 {
+        /// `syscall` and `sysret` instructions.
+        const SYSCALL = 1 << 11;
+        /// No-execute page protection.
+        const NX = 1 << 20;
+        /// 1GiB pages.
+        const PDPE1GB = 1 << 26;
+        /// `rdtscp` instruction.
+        const RDTSCP = 1 << 27;
+        /// Long mode.
+        const LM = 1 << 29;
+    }
+}
+
+/// The feature flag leaves, read in one go.
+#[derive(Debug, Copy, Clone)]
+pub struct Features {
+    /// Leaf 1, `ecx`.
+    pub info_ecx: FeatureInfoEcx,
+    /// Leaf 1, `edx`.
+    pub info_edx: FeatureInfoEdx,
+    /// Leaf 7, `ebx`.
+    pub extended_ebx: ExtendedFeaturesEbx,
+    /// Leaf 7, `ecx`.
+    pub extended_ecx: ExtendedFeaturesEcx,
+    /// Leaf 7, `edx`.
+    pub extended_edx: ExtendedFeaturesEdx,
+    /// Leaf `0x8000_0001`, `ecx`.
+    pub processor_ecx: ExtendedProcessorEcx,
+    /// Leaf `0x8000_0001`, `edx`.
+    pub processor_edx: ExtendedProcessorEdx,
+}
+
+impl Features {
+    /// Reads the feature flags. Leaves which are not supported read as empty.
+    pub fn read() -> Self {
+        let leaf1 = cpuid_checked(1, 0);
+        let leaf7 = cpuid_checked(7, 0);
+        let ext = cpuid_checked(0x8000_0001, 0);
+
+        Features {
+            info_ecx: FeatureInfoEcx::from_bits_truncate(leaf1.ecx),
+            info_edx: FeatureInfoEdx::from_bits_truncate(leaf1.edx),
+            extended_ebx: ExtendedFeaturesEbx::from_bits_truncate(leaf7.ebx),
+            extended_ecx: ExtendedFeaturesEcx::from_bits_truncate(leaf7.ecx),
+            extended_edx: ExtendedFeaturesEdx::from_bits_truncate(leaf7.edx),
+            processor_ecx: ExtendedProcessorEcx::from_bits_truncate(ext.ecx),
+            processor_edx: ExtendedProcessorEdx::from_bits_truncate(ext.edx),
+        }
+    }
+}
+
+/// The kind of data a cache holds.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CacheType {
+    /// Data cache.
+    Data,
+    /// Instruction cache.
+    Instruction,
+    /// Unified data and instruction cache.
+    Unified,
+}
+
+/// Description of one cache level, from leaf 4 on Intel or `0x8000_001D` on AMD.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CacheInfo {
+    /// The cache level, starting at 1.
+    pub level: u8,
+    /// What the cache holds.
+    pub cache_type: CacheType,
+    /// Size of a cache line in bytes.
+    pub line_size: u32,
+    /// Number of ways of associativity.
+    pub ways: u32,
+    /// Number of sets.
+    pub sets: u32,
+    /// Maximum number of logical processors sharing this cache.
+    pub shared_by: u32,
+    /// Whether the cache is fully associative.
+    pub fully_associative: bool,
+}
+
+impl CacheInfo {
+    /// Total size of the cache in bytes.
+    pub fn size(&self) -> u32 {
+        self.line_size * self.ways * self.sets
+    }
+}
+
+/// Iterator over the deterministic cache parameters.
+#[derive(Debug, Clone)]
+pub struct Caches {
+    leaf: u32,
+    index: u32,
+}
+
+/// Returns an iterator over the processor's caches, or an empty iterator if
+/// neither the Intel nor the AMD deterministic cache leaf is available.
+pub fn caches() -> Caches {
+    let leaf = if Vendor::read().is_amd() {
+        if max_extended_leaf() >= 0x8000_001D
+            && cpuid(0x8000_0001, 0).ecx & (1 << 22) != 0
+        {
+            0x8000_001D
+        } else {
+            0
+        }
+    } else if max_leaf() >= 4 {
+        4
+    } else {
+        0
+    };
+
+    Caches { leaf, index: 0 }
+}
+
+impl Iterator for Caches {
+    type Item = CacheInfo;
+
+    fn next(&mut self) -> Option<CacheInfo> {
+        if self.leaf == 0 {
+            return None;
+        }
+
+        let res = cpuid(self.leaf, self.index);
+        let cache_type = match res.eax & 0x1F {
+            1 => CacheType::Data,
+            2 => CacheType::Instruction,
+            3 => CacheType::Unified,
+            // 0 means there are no more caches
+            _ => return None,
+        };
+        self.index += 1;
+
+        Some(CacheInfo {
+            level: ((res.eax >> 5) & 0x7) as u8,
+            cache_type,
+            line_size: (res.ebx & 0xFFF) + 1,
+            ways: ((res.ebx >> 22) & 0x3FF) + 1,
+            sets: res.ecx + 1,
+            shared_by: ((res.eax >> 14) & 0xFFF) + 1,
+            fully_associative: res.eax & (1 << 9) != 0,
+        })
+    }
+}
+
+/// The kind of a level in the processor topology.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TopologyLevelType {
+    /// Hardware threads within a core.
+    Thread,
+    /// Cores within a package.
+    Core,
+    /// A level this crate doesn't know about.
+    Other(u8),
+}
+
+/// One level of the extended topology enumeration in leaf `0xB`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TopologyLevel {
+    /// What this level counts.
+    pub level_type: TopologyLevelType,
+    /// Number of bits to shift the x2APIC ID right by to get the ID of the
+    /// next level up.
+    pub shift: u8,
+    /// Number of logical processors at this level.
+    pub logical_processors: u16,
+    /// x2APIC ID of the current logical processor.
+    pub x2apic_id: u32,
+}
+
+/// Iterator over the levels of the extended topology enumeration.
+#[derive(Debug, Clone)]
+pub struct Topology {
+    supported: bool,
+    index: u32,
+}
+
+/// Returns an iterator over the processor topology levels, or an empty
+/// iterator if leaf `0xB` is not available.
+pub fn topology() -> Topology {
+    Topology {
+        supported: max_leaf() >= 0xB && cpuid(0xB, 0).ebx != 0,
+        index: 0,
+    }
+}
+
+impl Iterator for Topology {
+    type Item = TopologyLevel;
+
+    fn next(&mut self) -> Option<TopologyLevel> {
+        if !self.supported {
+            return None;
+        }
+
+        let res = cpuid(0xB, self.index);
+        let level_type = match ((res.ecx >> 8) & 0xFF) as u8 {
+            // 0 means there are no more levels
+            0 => return None,
+            1 => TopologyLevelType::Thread,
+            2 => TopologyLevelType::Core,
+            other => TopologyLevelType::Other(other),
+        };
+        self.index += 1;
+
+        Some(TopologyLevel {
+            level_type,
+            shift: (res.eax & 0x1F) as u8,
+            logical_processors: (res.ebx & 0xFFFF) as u16,
+            x2apic_id: res.edx,
+        })
+    }
+}
+
+/// The initial APIC ID of the current processor, from leaf 1.
+pub fn initial_apic_id() -> u8 {
+    (cpuid(1, 0).ebx >> 24) as u8
+}
+
+/// Physical and linear address sizes, from leaf `0x8000_0008`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AddressSizes {
+    /// Number of physical address bits.
+    pub physical: u8,
+    /// Number of linear (virtual) address bits.
+    pub linear: u8,
+}
+
+impl AddressSizes {
+    /// Reads the address sizes, falling back to the architectural minimum of
+    /// 36 physical and 48 linear bits if the leaf is not available.
+    pub fn read() -> Self {
+        if max_extended_leaf() >= 0x8000_0008 {
+            let eax = cpuid(0x8000_0008, 0).eax;
+            AddressSizes {
+                physical: (eax & 0xFF) as u8,
+                linear: ((eax >> 8) & 0xFF) as u8,
+            }
+        } else {
+            AddressSizes {
+                physical: 36,
+                linear: 48,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_features_match_std() {
+        let features = Features::read();
+        assert_eq!(
+            features.info_edx.contains(FeatureInfoEdx::SSE2),
+            is_x86_feature_detected!("sse2")
+        );
+        assert_eq!(
+            features.info_ecx.contains(FeatureInfoEcx::POPCNT),
+            is_x86_feature_detected!("popcnt")
+        );
+        assert!(features.processor_edx.contains(ExtendedProcessorEdx::LM));
+    }
+
+    #[test]
+    pub fn test_caches() {
+        for cache in caches() {
+            assert!(cache.level >= 1);
+            assert!(cache.line_size.is_power_of_two());
+            assert!(cache.size() > 0);
+        }
+    }
+
+    #[test]
+    pub fn test_address_sizes() {
+        let sizes = AddressSizes::read();
+        assert!(sizes.physical >= 36 && sizes.physical <= 52);
+        assert!(sizes.linear >= 48);
+    }
+}
@@ -2,10 +2,12 @@
 
 //! Special x86_64 instructions.
 
+pub mod cpuid;
 pub mod interrupts;
 pub mod port;
 pub mod random;
 pub mod segmentation;
+pub mod smap;
 pub mod tables;
 pub mod tlb;
 
@@ -8,10 +8,9 @@ pub struct RdRand(());
 impl RdRand {
     /// Creates Some(RdRand) if RDRAND is supported, None otherwise
     pub fn new() -> Option<Self> {
-        // RDRAND support indicated by CPUID page 01h, ecx bit 30
-        // https://en.wikipedia.org/wiki/RdRand#Overview
-        let cpuid = unsafe { core::arch::x86_64::__cpuid(0x1) };
-        if cpuid.ecx & (1 << 30) != 0 {
+        use super::cpuid::{Features, FeatureInfoEcx};
+
+        if Features::read().info_ecx.contains(FeatureInfoEcx::RDRAND) {
             Some(RdRand(()))
         } else {
             None
@@ -0,0 +1,19 @@
+//! Instructions for temporarily lifting supervisor mode access prevention (SMAP).
+
+/// Sets the AC flag in the RFLAGS register using the `stac` instruction, allowing
+/// supervisor-mode accesses to user-mode pages while SMAP is enabled.
+///
+/// Causes an invalid opcode exception on CPUs that don't support SMAP.
+#[inline]
+pub unsafe fn stac() {
+    asm!("stac" ::: "memory" : "volatile");
+}
+
+/// Clears the AC flag in the RFLAGS register using the `clac` instruction, so that
+/// supervisor-mode accesses to user-mode pages fault again while SMAP is enabled.
+///
+/// Causes an invalid opcode exception on CPUs that don't support SMAP.
+#[inline]
+pub unsafe fn clac() {
+    asm!("clac" ::: "memory" : "volatile");
+}
@@ -61,6 +61,68 @@ bitflags! {
     }
 }
 
+/// Contains various flags to control operations in protected mode.
+#[derive(Debug)]
+pub struct Cr4;
+
+bitflags! {
+    /// Configuration flags of the Cr4 register.
+    pub struct Cr4Flags: u64 {
+        /// Enables hardware-supported performance enhancements for software running in
+        /// virtual-8086 mode.
+        const VIRTUAL_8086_MODE_EXTENSIONS = 1 << 0;
+        /// Enables support for protected-mode virtual interrupts.
+        const PROTECTED_MODE_VIRTUAL_INTERRUPTS = 1 << 1;
+        /// When set, only privilege-level 0 can execute the `rdtsc` or `rdtscp` instructions.
+        const TIMESTAMP_DISABLE = 1 << 2;
+        /// Enables I/O breakpoint capability and enforces treatment of `dr4` and `dr5` debug
+        /// registers as reserved.
+        const DEBUGGING_EXTENSIONS = 1 << 3;
+        /// Enables the use of 4MB physical frames; ignored in long mode.
+        const PAGE_SIZE_EXTENSION = 1 << 4;
+        /// Enables physical address extension and 2MB physical frames; required in long mode.
+        const PHYSICAL_ADDRESS_EXTENSION = 1 << 5;
+        /// Enables the machine-check exception mechanism.
+        const MACHINE_CHECK_EXCEPTION = 1 << 6;
+        /// Enables the global page feature, allowing some page translations to be marked as
+        /// global (see `PageTableFlags::GLOBAL`).
+        const PAGE_GLOBAL = 1 << 7;
+        /// Allows software running at any privilege level to use the `rdpmc` instruction.
+        const PERFORMANCE_MONITOR_COUNTER = 1 << 8;
+        /// Enables the use of legacy SSE instructions; allows using `fxsave` and `fxrstor` for
+        /// saving processor state of 128-bit media instructions.
+        const OSFXSR = 1 << 9;
+        /// Enables the SIMD floating-point exception (`#XF`) for handling unmasked 256-bit and
+        /// 128-bit media floating-point errors.
+        const OSXMMEXCPT_ENABLE = 1 << 10;
+        /// Prevents the execution of the `sgdt`, `sidt`, `sldt`, `smsw`, and `str` instructions
+        /// by user-mode software.
+        const USER_MODE_INSTRUCTION_PREVENTION = 1 << 11;
+        /// Enables 5-level paging on supported CPUs.
+        const L5_PAGING = 1 << 12;
+        /// Enables VMX instructions.
+        const VIRTUAL_MACHINE_EXTENSIONS = 1 << 13;
+        /// Enables SMX instructions.
+        const SAFER_MODE_EXTENSIONS = 1 << 14;
+        /// Enables software running in 64-bit mode at any privilege level to read and write
+        /// the FS.base and GS.base hidden segment register state.
+        const FSGSBASE = 1 << 16;
+        /// Enables process-context identifiers (PCIDs).
+        const PCID = 1 << 17;
+        /// Enables extended processor state management instructions, including `xgetbv` and
+        /// `xsave`.
+        const OSXSAVE = 1 << 18;
+        /// Prevents the execution of instructions that reside in pages accessible by user-mode
+        /// software when the processor is in supervisor-mode.
+        const SUPERVISOR_MODE_EXECUTION_PROTECTION = 1 << 20;
+        /// Enables restrictions for supervisor-mode software when reading data from user-mode
+        /// pages.
+        const SUPERVISOR_MODE_ACCESS_PREVENTION = 1 << 21;
+        /// Enables 4-level paging to associate each linear address with a protection key.
+        const PROTECTION_KEY = 1 << 22;
+    }
+}
+
 #[cfg(target_arch = "x86_64")]
 mod x86_64 {
     use super::*;
@@ -116,6 +178,55 @@ mod x86_64 {
         }
     }
 
+    impl Cr4 {
+        /// Read the current set of CR4 flags.
+        pub fn read() -> Cr4Flags {
+            Cr4Flags::from_bits_truncate(Self::read_raw())
+        }
+
+        /// Read the current raw CR4 value.
+        pub fn read_raw() -> u64 {
+            let value: u64;
+            unsafe {
+                asm!("mov %cr4, $0" : "=r" (value));
+            }
+            value
+        }
+
+        /// Write CR4 flags.
+        ///
+        /// Preserves the value of reserved fields. Unsafe because it's possible to violate memory
+        /// safety by e.g. disabling physical address extension.
+        pub unsafe fn write(flags: Cr4Flags) {
+            let old_value = Self::read_raw();
+            let reserved = old_value & !(Cr4Flags::all().bits());
+            let new_value = reserved | flags.bits();
+
+            Self::write_raw(new_value);
+        }
+
+        /// Write raw CR4 flags.
+        ///
+        /// Does _not_ preserve any values, including reserved fields. Unsafe because it's possible to violate memory
+        /// safety by e.g. disabling physical address extension.
+        pub unsafe fn write_raw(value: u64) {
+            asm!("mov $0, %cr4" :: "r" (value) : "memory")
+        }
+
+        /// Updates CR4 flags.
+        ///
+        /// Preserves the value of reserved fields. Unsafe because it's possible to violate memory
+        /// safety by e.g. disabling physical address extension.
+        pub unsafe fn update<F>(f: F)
+        where
+            F: FnOnce(&mut Cr4Flags),
+        {
+            let mut flags = Self::read();
+            f(&mut flags);
+            Self::write(flags);
+        }
+    }
+
     impl Cr2 {
         /// Read the current page fault linear address from the CR3 register.
         pub fn read() -> VirtAddr {
@@ -22,6 +22,60 @@ impl Efer {
     pub const MSR: Msr = Msr(0xC0000080);
 }
 
+/// FS.Base Model Specific Register.
+#[derive(Debug)]
+pub struct FsBase;
+
+/// GS.Base Model Specific Register.
+#[derive(Debug)]
+pub struct GsBase;
+
+/// KernelGsBase Model Specific Register, swapped with GS.Base by `swapgs`.
+#[derive(Debug)]
+pub struct KernelGsBase;
+
+/// Syscall Register: STAR
+#[derive(Debug)]
+pub struct Star;
+
+/// Syscall Register: LSTAR
+#[derive(Debug)]
+pub struct LStar;
+
+/// Syscall Register: SFMASK
+#[derive(Debug)]
+pub struct SFMask;
+
+impl FsBase {
+    /// The underlying model specific register.
+    pub const MSR: Msr = Msr(0xC000_0100);
+}
+
+impl GsBase {
+    /// The underlying model specific register.
+    pub const MSR: Msr = Msr(0xC000_0101);
+}
+
+impl KernelGsBase {
+    /// The underlying model specific register.
+    pub const MSR: Msr = Msr(0xC000_0102);
+}
+
+impl Star {
+    /// The underlying model specific register.
+    pub const MSR: Msr = Msr(0xC000_0081);
+}
+
+impl LStar {
+    /// The underlying model specific register.
+    pub const MSR: Msr = Msr(0xC000_0082);
+}
+
+impl SFMask {
+    /// The underlying model specific register.
+    pub const MSR: Msr = Msr(0xC000_0084);
+}
+
 bitflags! {
     /// Flags of the Extended Feature Enable Register.
     pub struct EferFlags: u64 {
@@ -47,6 +101,7 @@ bitflags! {
 #[cfg(target_arch = "x86_64")]
 mod x86_64 {
     use super::*;
+    use crate::{registers::rflags::RFlags, structures::gdt::SegmentSelector, PrivilegeLevel, VirtAddr};
 
     impl Msr {
         /// Read 64 bits msr register.
@@ -108,4 +163,102 @@ mod x86_64 {
             Self::write(flags);
         }
     }
+
+    macro_rules! impl_base_msr {
+        ($name:ident) => {
+            impl $name {
+                /// Read the current base address.
+                pub fn read() -> VirtAddr {
+                    VirtAddr::new(unsafe { Self::MSR.read() } as usize)
+                }
+
+                /// Write a given virtual address to the base register.
+                pub unsafe fn write(address: VirtAddr) {
+                    Self::MSR.write(address.as_usize() as u64);
+                }
+            }
+        };
+    }
+
+    impl_base_msr!(FsBase);
+    impl_base_msr!(GsBase);
+    impl_base_msr!(KernelGsBase);
+
+    impl Star {
+        /// Read the raw STAR value, returning the selector bases for
+        /// `(sysret, syscall)` from bits 48..64 and 32..48.
+        pub fn read_raw() -> (u16, u16) {
+            let value = unsafe { Self::MSR.read() };
+            ((value >> 48) as u16, (value >> 32) as u16)
+        }
+
+        /// Write the raw selector bases for `sysret` and `syscall`.
+        ///
+        /// `syscall` loads CS from `syscall_base` and SS from `syscall_base + 8`.
+        /// 64-bit `sysret` loads SS from `sysret_base + 8` and CS from `sysret_base + 16`,
+        /// both with RPL 3.
+        pub unsafe fn write_raw(sysret_base: u16, syscall_base: u16) {
+            let value = (u64::from(sysret_base) << 48) | (u64::from(syscall_base) << 32);
+            Self::MSR.write(value);
+        }
+
+        /// Write the selectors used by `syscall` and `sysret`, checking that they are laid out
+        /// in the GDT the way the instructions expect:
+        ///
+        /// - `kernel_data` must directly follow `kernel_code`,
+        /// - `user_code` must directly follow `user_data`,
+        /// - the user selectors must have RPL 3 and the kernel selectors RPL 0.
+        pub unsafe fn write(
+            user_code: SegmentSelector,
+            user_data: SegmentSelector,
+            kernel_code: SegmentSelector,
+            kernel_data: SegmentSelector,
+        ) -> Result<(), &'static str> {
+            if kernel_data.index() != kernel_code.index() + 1 {
+                return Err("kernel data segment must follow the kernel code segment");
+            }
+            if user_code.index() != user_data.index() + 1 {
+                return Err("user code segment must follow the user data segment");
+            }
+            if user_code.rpl() != PrivilegeLevel::Ring3 || user_data.rpl() != PrivilegeLevel::Ring3
+            {
+                return Err("user segments must have RPL 3");
+            }
+            if kernel_code.rpl() != PrivilegeLevel::Ring0
+                || kernel_data.rpl() != PrivilegeLevel::Ring0
+            {
+                return Err("kernel segments must have RPL 0");
+            }
+
+            // sysret adds 8 to get SS and 16 to get CS, on top of RPL 3
+            let sysret_base = (user_data.index() - 1) << 3 | 3;
+            let syscall_base = kernel_code.index() << 3;
+            Self::write_raw(sysret_base, syscall_base);
+            Ok(())
+        }
+    }
+
+    impl LStar {
+        /// Read the 64-bit `syscall` entry point.
+        pub fn read() -> VirtAddr {
+            VirtAddr::new(unsafe { Self::MSR.read() } as usize)
+        }
+
+        /// Write the 64-bit `syscall` entry point.
+        pub unsafe fn write(address: VirtAddr) {
+            Self::MSR.write(address.as_usize() as u64);
+        }
+    }
+
+    impl SFMask {
+        /// Read the RFLAGS bits that are cleared on `syscall`.
+        pub fn read() -> RFlags {
+            RFlags::from_bits_truncate(unsafe { Self::MSR.read() })
+        }
+
+        /// Write the RFLAGS bits to clear on `syscall`.
+        pub unsafe fn write(value: RFlags) {
+            Self::MSR.write(value.bits());
+        }
+    }
 }
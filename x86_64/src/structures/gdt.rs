@@ -186,6 +186,14 @@ impl Descriptor {
         Descriptor::UserSegment(flags.bits())
     }
 
+    /// Creates a segment descriptor for a long mode kernel data segment.
+    pub fn kernel_data_segment() -> Descriptor {
+        use self::DescriptorFlags as Flags;
+
+        let flags = Flags::USER_SEGMENT | Flags::PRESENT | Flags::WRITABLE;
+        Descriptor::UserSegment(flags.bits())
+    }
+
     /// Creates a segment descriptor for a long mode ring 3 data segment.
     pub fn user_data_segment() -> Descriptor {
         use self::DescriptorFlags as Flags;
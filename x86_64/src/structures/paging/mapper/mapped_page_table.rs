@@ -49,7 +49,7 @@ impl<'a, P: PhysToVirt> MappedPageTable<'a, P> {
         let p4 = &mut self.level_4_table;
         let p3 = self
             .page_table_walker
-            .create_next_table(&mut p4[page.p4_index()], allocator)?;
+            .create_next_table(&mut p4[page.p4_index()], flags, allocator)?;
 
         if !p3[page.p3_index()].is_unused() {
             return Err(MapToError::PageAlreadyMapped);
@@ -74,10 +74,10 @@ impl<'a, P: PhysToVirt> MappedPageTable<'a, P> {
         let p4 = &mut self.level_4_table;
         let p3 = self
             .page_table_walker
-            .create_next_table(&mut p4[page.p4_index()], allocator)?;
+            .create_next_table(&mut p4[page.p4_index()], flags, allocator)?;
         let p2 = self
             .page_table_walker
-            .create_next_table(&mut p3[page.p3_index()], allocator)?;
+            .create_next_table(&mut p3[page.p3_index()], flags, allocator)?;
 
         if !p2[page.p2_index()].is_unused() {
             return Err(MapToError::PageAlreadyMapped);
@@ -102,13 +102,13 @@ impl<'a, P: PhysToVirt> MappedPageTable<'a, P> {
         let p4 = &mut self.level_4_table;
         let p3 = self
             .page_table_walker
-            .create_next_table(&mut p4[page.p4_index()], allocator)?;
+            .create_next_table(&mut p4[page.p4_index()], flags, allocator)?;
         let p2 = self
             .page_table_walker
-            .create_next_table(&mut p3[page.p3_index()], allocator)?;
+            .create_next_table(&mut p3[page.p3_index()], flags, allocator)?;
         let p1 = self
             .page_table_walker
-            .create_next_table(&mut p2[page.p2_index()], allocator)?;
+            .create_next_table(&mut p2[page.p2_index()], flags, allocator)?;
 
         if !p1[page.p1_index()].is_unused() {
             return Err(MapToError::PageAlreadyMapped);
@@ -448,24 +448,36 @@ impl<P: PhysToVirt> PageTableWalker<P> {
     /// Returns `MapToError::FrameAllocationFailed` if the entry is unused and the allocator
     /// returned `None`. Returns `MapToError::ParentEntryHugePage` if the `HUGE_PAGE` flag is set
     /// in the passed entry.
+    ///
+    /// If `leaf_flags` contains `USER_ACCESSIBLE`, the flag is also set on the passed entry,
+    /// since the processor only allows user mode accesses if it is set at every level.
     fn create_next_table<'b, A>(
         &self,
         entry: &'b mut PageTableEntry,
+        leaf_flags: PageTableFlags,
         allocator: &mut A,
     ) -> Result<&'b mut PageTable, PageTableCreateError>
     where
         A: FrameAllocator<Size4KiB>,
     {
         let created;
+        let parent_flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | (leaf_flags & PageTableFlags::USER_ACCESSIBLE);
 
         if entry.is_unused() {
             if let Some(frame) = allocator.allocate_frame() {
-                entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+                entry.set_frame(frame, parent_flags);
                 created = true;
             } else {
                 return Err(PageTableCreateError::FrameAllocationFailed);
             }
         } else {
+            if !entry.flags().contains(PageTableFlags::HUGE_PAGE)
+                && !entry.flags().contains(parent_flags)
+            {
+                entry.set_flags(entry.flags() | parent_flags);
+            }
             created = false;
         }
 